@@ -17,6 +17,7 @@
 use std::convert::{Into, TryFrom};
 use std::error::Error as StdError;
 use std::fmt;
+use std::net::IpAddr;
 use std::str::FromStr;
 
 #[cfg(feature = "serde")]
@@ -56,7 +57,63 @@ impl fmt::Display for JidParseError {
     }
 }
 
+/// An error that signifies that the domain of a `Jid` isn’t a valid internationalized domain
+/// name, so it can’t be converted to or from its ASCII form.
+#[cfg(any(feature = "idna", feature = "icu"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdnaError {
+    domain: String,
+}
+
+#[cfg(any(feature = "idna", feature = "icu"))]
+impl StdError for IdnaError {}
+
+#[cfg(any(feature = "idna", feature = "icu"))]
+impl fmt::Display for IdnaError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{:?} isn’t a valid internationalized domain name",
+            self.domain
+        )
+    }
+}
+
+/// Whether `domain` is an IP address, which IDNA doesn’t apply to.
+fn is_ip_literal(domain: &str) -> bool {
+    domain.starts_with('[') || domain.parse::<IpAddr>().is_ok()
+}
+
+#[cfg(feature = "icu")]
+use idna_icu as idna;
+
+#[cfg(any(feature = "idna", feature = "icu"))]
+fn domain_to_ascii(domain: &str) -> Result<String, IdnaError> {
+    if is_ip_literal(domain) {
+        return Ok(domain.to_owned());
+    }
+    idna::domain_to_ascii(domain).map_err(|_| IdnaError {
+        domain: domain.to_owned(),
+    })
+}
+
+#[cfg(any(feature = "idna", feature = "icu"))]
+fn domain_to_unicode(domain: &str) -> Result<String, IdnaError> {
+    if is_ip_literal(domain) {
+        return Ok(domain.to_owned());
+    }
+    match idna::domain_to_unicode(domain) {
+        (unicode, Ok(())) => Ok(unicode),
+        (_, Err(_)) => Err(IdnaError {
+            domain: domain.to_owned(),
+        }),
+    }
+}
+
 /// An enum representing a Jabber ID. It can be either a `FullJid` or a `BareJid`.
+///
+/// Equality and hashing operate on the stored form, without any conversion of the domain, so
+/// `bücher.example` and `xn--bcher-kva.example` are different domains to them.
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Jid {
@@ -86,6 +143,22 @@ impl FromStr for Jid {
     }
 }
 
+impl TryFrom<&str> for Jid {
+    type Error = JidParseError;
+
+    fn try_from(s: &str) -> Result<Jid, JidParseError> {
+        Jid::from_str(s)
+    }
+}
+
+impl TryFrom<String> for Jid {
+    type Error = JidParseError;
+
+    fn try_from(s: String) -> Result<Jid, JidParseError> {
+        Jid::from_str(&s)
+    }
+}
+
 impl From<Jid> for String {
     fn from(jid: Jid) -> String {
         match jid {
@@ -127,6 +200,28 @@ impl Jid {
             Jid::Bare(BareJid { domain, .. }) | Jid::Full(FullJid { domain, .. }) => domain,
         }
     }
+
+    /// The same Jabber ID with its domain in ASCII form, punycode-encoded if it is
+    /// internationalized, as needed for SRV lookups and matching certificate names.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jid::Jid;
+    /// use std::str::FromStr;
+    ///
+    /// let jid = Jid::from_str("juliet@bücher.example/balcony").unwrap();
+    /// let ascii = jid.to_ascii_domain_form().unwrap();
+    ///
+    /// assert_eq!(ascii.to_string(), "juliet@xn--bcher-kva.example/balcony");
+    /// ```
+    #[cfg(any(feature = "idna", feature = "icu"))]
+    pub fn to_ascii_domain_form(&self) -> Result<Jid, IdnaError> {
+        Ok(match self {
+            Jid::Bare(jid) => Jid::Bare(jid.with_domain(domain_to_ascii(&jid.domain)?)),
+            Jid::Full(jid) => Jid::Full(jid.with_domain(domain_to_ascii(&jid.domain)?)),
+        })
+    }
 }
 
 impl From<Jid> for BareJid {
@@ -195,6 +290,8 @@ impl PartialEq<BareJid> for Jid {
 ///
 /// Unlike a `BareJid`, it always contains a resource, and should only be used when you are certain
 /// there is no case where a resource can be missing.  Otherwise, use a `Jid` enum.
+///
+/// Equality and hashing operate on the stored form, without any conversion of the domain.
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct FullJid {
     /// The node part of the Jabber ID, if it exists, else None.
@@ -214,6 +311,9 @@ pub struct FullJid {
 ///
 /// Unlike a `FullJid`, it can’t contain a resource, and should only be used when you are certain
 /// there is no case where a resource can be set.  Otherwise, use a `Jid` enum.
+///
+/// Equality and hashing operate on the stored form, see `domain_to_ascii` to compare
+/// internationalized domains.
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct BareJid {
     /// The node part of the Jabber ID, if it exists, else None.
@@ -404,6 +504,22 @@ impl FromStr for FullJid {
     }
 }
 
+impl TryFrom<&str> for FullJid {
+    type Error = JidParseError;
+
+    fn try_from(s: &str) -> Result<FullJid, JidParseError> {
+        FullJid::from_str(s)
+    }
+}
+
+impl TryFrom<String> for FullJid {
+    type Error = JidParseError;
+
+    fn try_from(s: String) -> Result<FullJid, JidParseError> {
+        FullJid::from_str(&s)
+    }
+}
+
 #[cfg(feature = "serde")]
 impl<'de> Deserialize<'de> for FullJid {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
@@ -546,6 +662,22 @@ impl FromStr for BareJid {
     }
 }
 
+impl TryFrom<&str> for BareJid {
+    type Error = JidParseError;
+
+    fn try_from(s: &str) -> Result<BareJid, JidParseError> {
+        BareJid::from_str(s)
+    }
+}
+
+impl TryFrom<String> for BareJid {
+    type Error = JidParseError;
+
+    fn try_from(s: String) -> Result<BareJid, JidParseError> {
+        BareJid::from_str(&s)
+    }
+}
+
 impl BareJid {
     /// Constructs a bare Jabber ID, containing two components.
     ///
@@ -670,6 +802,76 @@ impl BareJid {
             resource: resource.into(),
         }
     }
+
+    /// Checks whether the domain of this Jabber ID is `other` or one of its subdomains.
+    ///
+    /// Domains are compared label by label from the end, ignoring ASCII case and a trailing
+    /// dot, so that `ample.com` doesn’t match `example.com`.  IP addresses are only equal to
+    /// themselves.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jid::BareJid;
+    ///
+    /// let jid = BareJid::domain("conference.example.com");
+    ///
+    /// assert!(jid.domain_is_subdomain_of("example.com"));
+    /// assert!(jid.domain_is_subdomain_of("conference.example.com"));
+    /// assert!(!jid.domain_is_subdomain_of("ample.com"));
+    /// ```
+    pub fn domain_is_subdomain_of(&self, other: &str) -> bool {
+        let domain = self.domain.strip_suffix('.').unwrap_or(&self.domain);
+        let other = other.strip_suffix('.').unwrap_or(other);
+        if other.is_empty() {
+            return false;
+        }
+        if is_ip_literal(domain) {
+            return domain.eq_ignore_ascii_case(other);
+        }
+        let mut labels = domain.rsplit('.');
+        other.rsplit('.').all(|label| match labels.next() {
+            Some(own) => own.eq_ignore_ascii_case(label),
+            None => false,
+        })
+    }
+
+    /// The domain of this Jabber ID in ASCII form, punycode-encoded if it is internationalized,
+    /// as needed to resolve it.
+    ///
+    /// This applies the UTS #46 processing of IDNA2008, with the ContextJ rules too when the
+    /// `icu` feature is enabled.  IP addresses are returned unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jid::BareJid;
+    ///
+    /// let jid = BareJid::new("juliet", "Bücher.example");
+    ///
+    /// assert_eq!(jid.domain_to_ascii().unwrap(), "xn--bcher-kva.example");
+    /// ```
+    #[cfg(any(feature = "idna", feature = "icu"))]
+    pub fn domain_to_ascii(&self) -> Result<String, IdnaError> {
+        domain_to_ascii(&self.domain)
+    }
+
+    /// The domain of this Jabber ID in Unicode form, decoding its punycode labels, to be
+    /// displayed to users.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jid::BareJid;
+    ///
+    /// let jid = BareJid::new("juliet", "xn--bcher-kva.example");
+    ///
+    /// assert_eq!(jid.domain_to_unicode().unwrap(), "bücher.example");
+    /// ```
+    #[cfg(any(feature = "idna", feature = "icu"))]
+    pub fn domain_to_unicode(&self) -> Result<String, IdnaError> {
+        domain_to_unicode(&self.domain)
+    }
 }
 
 #[cfg(feature = "minidom")]
@@ -724,6 +926,31 @@ mod tests {
     use std::collections::HashMap;
     use std::str::FromStr;
 
+    #[test]
+    fn try_from_strings() {
+        assert_eq!(
+            Jid::try_from("a@b.c"),
+            Ok(Jid::Bare(BareJid::new("a", "b.c")))
+        );
+        assert_eq!(
+            Jid::try_from(String::from("a@b.c/d")),
+            Ok(Jid::Full(FullJid::new("a", "b.c", "d")))
+        );
+        assert_eq!(
+            FullJid::try_from("a@b.c/d"),
+            Ok(FullJid::new("a", "b.c", "d"))
+        );
+        assert_eq!(
+            FullJid::try_from(String::from("a@b.c")),
+            Err(JidParseError::NoResource)
+        );
+        assert_eq!(BareJid::try_from("a@b.c/d"), Ok(BareJid::new("a", "b.c")));
+        assert_eq!(
+            BareJid::try_from(String::from("@b.c")),
+            Err(JidParseError::EmptyNode)
+        );
+    }
+
     #[test]
     fn can_parse_full_jids() {
         assert_eq!(
@@ -864,6 +1091,81 @@ mod tests {
         );
     }
 
+    #[test]
+    fn subdomains() {
+        let jid = BareJid::new("room", "conference.example.com");
+        assert!(jid.domain_is_subdomain_of("example.com"));
+        assert!(jid.domain_is_subdomain_of("com"));
+        assert!(jid.domain_is_subdomain_of("conference.example.com"));
+        assert!(jid.domain_is_subdomain_of("Example.COM."));
+
+        // Not on a label boundary, which a plain ends_with() would accept.
+        assert!(!jid.domain_is_subdomain_of("ample.com"));
+        assert!(!jid.domain_is_subdomain_of("e.com"));
+        assert!(!BareJid::domain("example.com").domain_is_subdomain_of("ample.com"));
+        assert!(!BareJid::domain("notexample.com").domain_is_subdomain_of("example.com"));
+
+        // A parent isn’t a subdomain of its children.
+        assert!(!BareJid::domain("example.com").domain_is_subdomain_of("conference.example.com"));
+        assert!(!jid.domain_is_subdomain_of(""));
+        assert!(!jid.domain_is_subdomain_of("."));
+
+        // Addresses have no subdomains.
+        let ip = BareJid::domain("192.0.2.1");
+        assert!(ip.domain_is_subdomain_of("192.0.2.1"));
+        assert!(!ip.domain_is_subdomain_of("2.1"));
+        let ip = BareJid::domain("[2001:db8::1]");
+        assert!(ip.domain_is_subdomain_of("[2001:DB8::1]"));
+        assert!(!ip.domain_is_subdomain_of("1]"));
+    }
+
+    #[cfg(any(feature = "idna", feature = "icu"))]
+    #[test]
+    fn idna() {
+        let jid = BareJid::new("pile", "💩.la");
+        assert_eq!(jid.domain_to_ascii().unwrap(), "xn--ls8h.la");
+        let ascii = BareJid::new("pile", "xn--ls8h.la");
+        assert_eq!(ascii.domain_to_unicode().unwrap(), "💩.la");
+        // Only the stored form is compared.
+        assert_ne!(jid, ascii);
+
+        // Both ways, Unicode being normalised on the way.
+        let jid = BareJid::domain("BÜCHER.example");
+        let ascii = jid.domain_to_ascii().unwrap();
+        assert_eq!(ascii, "xn--bcher-kva.example");
+        let unicode = BareJid::domain(ascii.clone()).domain_to_unicode().unwrap();
+        assert_eq!(unicode, "bücher.example");
+        assert_eq!(BareJid::domain(unicode).domain_to_ascii().unwrap(), ascii);
+
+        let jid = Jid::from_str("juliet@bücher.example/balcony").unwrap();
+        assert_eq!(
+            jid.to_ascii_domain_form().unwrap(),
+            Jid::Full(FullJid::new("juliet", "xn--bcher-kva.example", "balcony"))
+        );
+        let jid = Jid::from_str("192.0.2.1").unwrap();
+        assert_eq!(jid.to_ascii_domain_form().unwrap(), jid);
+        let jid = BareJid::domain("[2001:db8::1]");
+        assert_eq!(jid.domain_to_ascii().unwrap(), "[2001:db8::1]");
+
+        // Starting with a combining mark, a disallowed character, and broken punycode.
+        for domain in &["\u{300}a.example", "a\u{2028}b.example", "xn--a.example"] {
+            let jid = BareJid::domain(*domain);
+            assert!(jid.domain_to_ascii().is_err(), "{}", domain);
+            assert!(Jid::Bare(jid).to_ascii_domain_form().is_err());
+        }
+        assert!(BareJid::domain("xn--a.example")
+            .domain_to_unicode()
+            .is_err());
+    }
+
+    #[cfg(feature = "icu")]
+    #[test]
+    fn idna_context_j() {
+        // A joiner is only allowed after a virama, which ICU checks.
+        let jid = BareJid::domain("a\u{200d}b.example");
+        assert!(jid.domain_to_ascii().is_err());
+    }
+
     #[cfg(feature = "minidom")]
     #[test]
     fn minidom() {
@@ -24,7 +24,7 @@ use std::io::Write;
 use std::borrow::Cow;
 use std::str;
 
-use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, Event};
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
 use quick_xml::Reader as EventReader;
 use quick_xml::Writer as EventWriter;
 
@@ -34,46 +34,89 @@ use std::str::FromStr;
 
 use std::slice;
 
+#[cfg(feature = "async")]
+use futures_io::AsyncWrite;
+#[cfg(feature = "async")]
+use std::future::poll_fn;
+#[cfg(feature = "async")]
+use std::io;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// The namespace of the defined conditions and text of stanza errors (RFC 6120 §8.3).
+const STANZA_ERRORS_NS: &str = "urn:ietf:params:xml:ns:xmpp-stanzas";
+
 /// helper function to escape a `&[u8]` and replace all
 /// xml special characters (<, >, &, ', ") with their corresponding
 /// xml escaped value.
+#[deprecated(note = "use escape_text or escape_attribute, which only escape what is needed")]
 pub fn escape(raw: &[u8]) -> Cow<[u8]> {
-    let mut escapes: Vec<(usize, &'static [u8])> = Vec::new();
-    let mut bytes = raw.iter();
-    fn to_escape(b: u8) -> bool {
-        matches!(b, b'<' | b'>' | b'\'' | b'&' | b'"')
-    }
-
-    let mut loc = 0;
-    while let Some(i) = bytes.position(|&b| to_escape(b)) {
-        loc += i;
-        match raw[loc] {
-            b'<' => escapes.push((loc, b"&lt;")),
-            b'>' => escapes.push((loc, b"&gt;")),
-            b'\'' => escapes.push((loc, b"&apos;")),
-            b'&' => escapes.push((loc, b"&amp;")),
-            b'"' => escapes.push((loc, b"&quot;")),
-            _ => unreachable!("Only '<', '>','\', '&' and '\"' are escaped"),
-        }
-        loc += 1;
-    }
+    escape_with(raw, |raw, i| match raw[i] {
+        b'<' => Some(b"&lt;"),
+        b'>' => Some(b"&gt;"),
+        b'\'' => Some(b"&apos;"),
+        b'&' => Some(b"&amp;"),
+        b'"' => Some(b"&quot;"),
+        _ => None,
+    })
+}
+
+/// Escapes a `&[u8]` to be written as the text content of an element.
+///
+/// Only `<` and `&` have to be, along with the `>` of a `]]>` sequence, and
+/// carriage returns which parsers would otherwise turn into line feeds.
+pub fn escape_text(raw: &[u8]) -> Cow<'_, [u8]> {
+    escape_with(raw, |raw, i| match raw[i] {
+        b'<' => Some(b"&lt;"),
+        b'&' => Some(b"&amp;"),
+        b'>' if raw[..i].ends_with(b"]]") => Some(b"&gt;"),
+        b'\r' => Some(b"&#13;"),
+        _ => None,
+    })
+}
 
-    if escapes.is_empty() {
-        Cow::Borrowed(raw)
-    } else {
-        let len = raw.len();
-        let mut v = Vec::with_capacity(len);
-        let mut start = 0;
-        for (i, r) in escapes {
+/// Escapes a `&[u8]` to be written as an attribute value, between double
+/// quotes.
+///
+/// Besides `<`, `&` and `"`, tabs and line breaks have to be, as parsers
+/// would otherwise turn them into spaces.
+pub fn escape_attribute(raw: &[u8]) -> Cow<'_, [u8]> {
+    escape_with(raw, |raw, i| match raw[i] {
+        b'<' => Some(b"&lt;"),
+        b'&' => Some(b"&amp;"),
+        b'"' => Some(b"&quot;"),
+        b'\t' => Some(b"&#9;"),
+        b'\n' => Some(b"&#10;"),
+        b'\r' => Some(b"&#13;"),
+        _ => None,
+    })
+}
+
+/// Replaces every byte of `raw` for which `escape` returns a replacement,
+/// only allocating if there is one.
+fn escape_with<F>(raw: &[u8], escape: F) -> Cow<'_, [u8]>
+where
+    F: Fn(&[u8], usize) -> Option<&'static [u8]>,
+{
+    let mut escaped: Option<Vec<u8>> = None;
+    let mut start = 0;
+    for i in 0..raw.len() {
+        if let Some(replacement) = escape(raw, i) {
+            let v = escaped.get_or_insert_with(|| Vec::with_capacity(raw.len() + 8));
             v.extend_from_slice(&raw[start..i]);
-            v.extend_from_slice(r);
+            v.extend_from_slice(replacement);
             start = i + 1;
         }
-
-        if start < len {
+    }
+    match escaped {
+        Some(mut v) => {
             v.extend_from_slice(&raw[start..]);
+            Cow::Owned(v)
         }
-        Cow::Owned(v)
+        None => Cow::Borrowed(raw),
     }
 }
 
@@ -88,6 +131,19 @@ pub struct Element {
     prefixes: Prefixes,
     attributes: BTreeMap<String, String>,
     children: Vec<Node>,
+    /// Only recorded by `Element::from_reader_with_spans`, boxed so that it costs a single
+    /// pointer otherwise.
+    span: Option<Box<Span>>,
+}
+
+/// Where an element was in the document it was parsed from, see [`Element::source_span`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    /// The offset in bytes of the `<` of its start tag.
+    pub start: usize,
+    /// The offset in bytes just after the `>` of its end tag, or of its start tag if it is
+    /// empty.
+    pub end: usize,
 }
 
 impl<'a> From<&'a Element> for String {
@@ -98,12 +154,116 @@ impl<'a> From<&'a Element> for String {
     }
 }
 
+/// Bounds on the size of the tree built while parsing, so that untrusted input can't exhaust
+/// memory or time, nor overflow the stack of the recursive functions working on `Element`s.
+///
+/// A hostile peer can nest elements very deeply, or send a huge number of tiny ones, which
+/// stay under any reasonable limit on the size in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLimits {
+    /// How deep elements may be nested, the root element being at depth 1.
+    pub max_depth: usize,
+    /// How many nodes, elements and text nodes included, a document may contain.
+    pub max_nodes: usize,
+}
+
+impl Default for ParseLimits {
+    /// Enough for any XMPP stanza, even when it embeds another one.
+    fn default() -> ParseLimits {
+        ParseLimits {
+            max_depth: 32,
+            max_nodes: 100_000,
+        }
+    }
+}
+
+impl ParseLimits {
+    /// No bounds at all, only for trusted input.
+    pub fn unlimited() -> ParseLimits {
+        ParseLimits {
+            max_depth: usize::MAX,
+            max_nodes: usize::MAX,
+        }
+    }
+
+    /// Fails if a node at `depth` would exceed these limits, `nodes` counting it already.
+    fn check(&self, depth: usize, nodes: usize) -> Result<()> {
+        if depth > self.max_depth {
+            Err(Error::TooDeep)
+        } else if nodes > self.max_nodes {
+            Err(Error::TooManyNodes)
+        } else {
+            Ok(())
+        }
+    }
+}
+
 impl FromStr for Element {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Element> {
         let mut reader = EventReader::from_str(s);
-        Element::from_reader(&mut reader)
+        Element::from_reader(&mut reader).map_err(|error| error.locate(s.as_bytes()))
+    }
+}
+
+/// The structured representation of an `Element` used by the `serde` feature.
+///
+/// Namespace prefixes are an artifact of the XML serialisation and are not kept, only the
+/// namespace of each element is.
+#[cfg(feature = "serde")]
+#[derive(Serialize)]
+struct SerializedElement<'a> {
+    name: &'a str,
+    ns: &'a str,
+    attrs: &'a BTreeMap<String, String>,
+    children: &'a [Node],
+}
+
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct DeserializedElement {
+    name: String,
+    ns: String,
+    #[serde(default)]
+    attrs: BTreeMap<String, String>,
+    #[serde(default)]
+    children: Vec<Node>,
+}
+
+/// Serializes as `{"name": …, "ns": …, "attrs": {…}, "children": […]}`.
+#[cfg(feature = "serde")]
+impl Serialize for Element {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        SerializedElement {
+            name: &self.name,
+            ns: &self.namespace,
+            attrs: &self.attributes,
+            children: &self.children,
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Element {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let elem = DeserializedElement::deserialize(deserializer)?;
+        ensure_no_prefix(&elem.name).map_err(de::Error::custom)?;
+        Ok(Element::new(
+            elem.name,
+            elem.ns,
+            None,
+            None,
+            elem.attrs,
+            elem.children,
+        ))
     }
 }
 
@@ -120,6 +280,89 @@ impl PartialEq for Element {
     }
 }
 
+/// The events serialising an element, produced one tag or text node at a time, which both
+/// the synchronous and asynchronous writers go through.
+struct Events<'a> {
+    /// The start tag of the root element, before anything else
+    root: Option<Event<'a>>,
+    /// The elements started but not ended yet, innermost last
+    open: Vec<OpenElement<'a>>,
+}
+
+struct OpenElement<'a> {
+    /// The name the element got started with, prefix included
+    name: String,
+    /// The children still to write
+    children: slice::Iter<'a, Node>,
+    /// The prefixes declared for the children
+    prefixes: BTreeMap<Prefix, Namespace>,
+}
+
+impl<'a> Events<'a> {
+    fn new(root: &'a Element, all_prefixes: &mut BTreeMap<Prefix, Namespace>) -> Events<'a> {
+        let mut events = Events {
+            root: None,
+            open: Vec::new(),
+        };
+        events.root = Some(events.start(root, all_prefixes));
+        events
+    }
+
+    fn start(
+        &mut self,
+        element: &'a Element,
+        all_prefixes: &mut BTreeMap<Prefix, Namespace>,
+    ) -> Event<'a> {
+        let (name, start) = element.start_tag(all_prefixes);
+        if element.children.is_empty() {
+            return Event::Empty(start);
+        }
+        self.open.push(OpenElement {
+            name,
+            children: element.children.iter(),
+            prefixes: all_prefixes.clone(),
+        });
+        Event::Start(start)
+    }
+}
+
+impl<'a> Iterator for Events<'a> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        if let Some(root) = self.root.take() {
+            return Some(root);
+        }
+        let parent = self.open.last_mut()?;
+        match parent.children.next() {
+            Some(Node::Element(child)) => {
+                let mut prefixes = parent.prefixes.clone();
+                Some(self.start(child, &mut prefixes))
+            }
+            Some(Node::Text(text)) => Some(Event::Text(BytesText::from_escaped(escape_text(
+                text.as_bytes(),
+            )))),
+            None => {
+                let parent = self.open.pop().unwrap();
+                Some(Event::End(BytesEnd::owned(parent.name.into_bytes())))
+            }
+        }
+    }
+}
+
+/// Writes the whole of `buf` to `writer`.
+#[cfg(feature = "async")]
+async fn write_all<W: AsyncWrite + Unpin>(writer: &mut W, mut buf: &[u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        let written = poll_fn(|cx| Pin::new(&mut *writer).poll_write(cx, buf)).await?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        buf = &buf[written..];
+    }
+    Ok(())
+}
+
 fn ensure_no_prefix<S: AsRef<str>>(s: &S) -> Result<()> {
     match s.as_ref().split(':').count() {
         1 => Ok(()),
@@ -145,6 +388,7 @@ impl Element {
             prefixes: prefixes.into(),
             attributes,
             children,
+            span: None,
         }
     }
 
@@ -204,6 +448,69 @@ impl Element {
         )
     }
 
+    /// Returns the `<error/>` of an XMPP stanza error, in the namespace `ns` of the stanza
+    /// carrying it, with `condition` and the optional `text` in the stanza errors namespace.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minidom::Element;
+    ///
+    /// let error = Element::error("jabber:client", "cancel", "item-not-found", Some("No such item."));
+    ///
+    /// assert_eq!(error.attr("type"), Some("cancel"));
+    /// assert!(error.has_child("item-not-found", "urn:ietf:params:xml:ns:xmpp-stanzas"));
+    /// assert_eq!(
+    ///     error.get_child("text", "urn:ietf:params:xml:ns:xmpp-stanzas").unwrap().text(),
+    ///     "No such item."
+    /// );
+    /// ```
+    pub fn error<NS: Into<String>>(
+        ns: NS,
+        type_: &str,
+        condition: &str,
+        text: Option<&str>,
+    ) -> Element {
+        let text = text.map(|text| Element::builder("text", STANZA_ERRORS_NS).append(text));
+        Element::builder("error", ns)
+            .attr("type", type_)
+            .append(Element::bare(condition, STANZA_ERRORS_NS))
+            .append_all(text)
+            .build()
+    }
+
+    /// Returns the error reply to this stanza: the same element, with its `id` but addressed
+    /// back to its sender, of type `error`, and containing only [`Element::error`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minidom::Element;
+    ///
+    /// let iq: Element = "<iq xmlns='jabber:client' from='a@b/c' to='b' id='1' type='get'/>"
+    ///     .parse()
+    ///     .unwrap();
+    /// let reply = iq.error_reply("cancel", "service-unavailable", None);
+    ///
+    /// assert_eq!(
+    ///     String::from(&reply),
+    ///     "<iq xmlns=\"jabber:client\" from=\"b\" id=\"1\" to=\"a@b/c\" type=\"error\">\
+    ///      <error type=\"cancel\">\
+    ///      <service-unavailable xmlns=\"urn:ietf:params:xml:ns:xmpp-stanzas\"/>\
+    ///      </error></iq>"
+    /// );
+    /// ```
+    pub fn error_reply(&self, type_: &str, condition: &str, text: Option<&str>) -> Element {
+        let mut reply = Element::builder(self.name(), self.ns())
+            .attr("id", self.attr("id"))
+            .attr("from", self.attr("to"))
+            .attr("to", self.attr("from"))
+            .attr("type", "error")
+            .build();
+        reply.append_child(Element::error(self.ns(), type_, condition, text));
+        reply
+    }
+
     /// Returns a reference to the local name of this element (that is, without a possible prefix).
     pub fn name(&self) -> &str {
         &self.name
@@ -214,6 +521,66 @@ impl Element {
         self.namespace.clone()
     }
 
+    /// Moves this element to another namespace, its children are left untouched.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minidom::Element;
+    ///
+    /// let mut elem: Element = "<message xmlns='jabber:client'/>".parse().unwrap();
+    /// elem.set_ns("jabber:component:accept");
+    ///
+    /// assert_eq!(elem.ns(), "jabber:component:accept");
+    /// assert_eq!(String::from(&elem), "<message xmlns=\"jabber:component:accept\"/>");
+    /// ```
+    pub fn set_ns<NS: Into<String>>(&mut self, namespace: NS) {
+        let namespace = namespace.into();
+        // Keep the default namespace declaration in sync, or serialising would need a prefix.
+        if self.prefixes.get(&None) == Some(&self.namespace) {
+            self.prefixes.insert(None, namespace.clone());
+        }
+        self.namespace = namespace;
+    }
+
+    /// Forgets the prefixes and namespace declarations this element and its descendants were
+    /// parsed with, so that it serialises as if it had been built with `Element::builder`, only
+    /// using default namespace declarations.
+    ///
+    /// This is useful when moving an element from one document to another, where the prefixes
+    /// of the original document might not be declared.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minidom::Element;
+    ///
+    /// let elem: Element = "<a:message xmlns:a='jabber:client'><a:body>Hello</a:body></a:message>"
+    ///     .parse()
+    ///     .unwrap();
+    /// let elem = elem.into_prefixless();
+    ///
+    /// assert_eq!(
+    ///     String::from(&elem),
+    ///     "<message xmlns=\"jabber:client\"><body>Hello</body></message>"
+    /// );
+    /// ```
+    pub fn into_prefixless(mut self) -> Element {
+        self.prefix = None;
+        self.prefixes = Prefixes::default();
+        self.attributes
+            .retain(|name, _| name != "xmlns" && !name.starts_with("xmlns:"));
+        self.children = self
+            .children
+            .into_iter()
+            .map(|node| match node {
+                Node::Element(child) => Node::Element(child.into_prefixless()),
+                node => node,
+            })
+            .collect();
+        self
+    }
+
     /// Returns a reference to the value of the given attribute, if it exists, else `None`.
     pub fn attr(&self, name: &str) -> Option<&str> {
         if let Some(value) = self.attributes.get(name) {
@@ -310,16 +677,150 @@ impl Element {
         namespace.into().compare(self.namespace.as_ref())
     }
 
-    /// Parse a document from an `EventReader`.
+    /// Compares two elements node by node, unlike `==` which stops at the last child of the
+    /// element with the fewest, so that elements only equal once trailing children or text get
+    /// dropped are told apart.  Text, whitespace included, must be identical.
+    ///
+    /// Attributes are kept sorted, so their order still doesn’t matter, and neither do the
+    /// prefixes used to serialise namespaces.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minidom::Element;
+    ///
+    /// let elem1: Element = "<a xmlns='ns1'><b/><c/></a>".parse().unwrap();
+    /// let elem2: Element = "<a xmlns='ns1'><b/></a>".parse().unwrap();
+    /// assert!(!elem1.strict_eq(&elem2));
+    /// assert!(elem1.strict_eq(&elem1.clone()));
+    /// ```
+    pub fn strict_eq(&self, other: &Element) -> bool {
+        self.name == other.name
+            && self.namespace == other.namespace
+            && self.attributes == other.attributes
+            && self.children.len() == other.children.len()
+            && self
+                .children
+                .iter()
+                .zip(other.children.iter())
+                .all(|nodes| match nodes {
+                    (Node::Element(elem1), Node::Element(elem2)) => elem1.strict_eq(elem2),
+                    (Node::Text(text1), Node::Text(text2)) => text1 == text2,
+                    _ => false,
+                })
+    }
+
+    /// Parse a document from an `EventReader`, within the default [`ParseLimits`].
+    ///
+    /// The reader is used as configured by the caller.  `expand_empty_elements` makes no
+    /// difference to the resulting tree.  `trim_text` drops the whitespace between elements,
+    /// but also trims the text inside them, such as message bodies where XMPP considers it
+    /// significant, so it should only be enabled for documents where it isn’t.  Parsing from a
+    /// string keeps the defaults of quick-xml, which leave all text untouched.  tokio-xmpp
+    /// doesn’t depend on any of these, as it parses streams without quick-xml.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minidom::Element;
+    /// use minidom::quick_xml::Reader;
+    ///
+    /// let mut reader = Reader::from_str("<a xmlns='ns'>\n  <b/>\n</a>");
+    /// reader.trim_text(true).expand_empty_elements(true);
+    /// let elem = Element::from_reader(&mut reader).unwrap();
+    /// assert_eq!(elem.nodes().count(), 1);
+    /// ```
     pub fn from_reader<R: BufRead>(reader: &mut EventReader<R>) -> Result<Element> {
+        Element::from_reader_with_limits(reader, ParseLimits::default())
+    }
+
+    /// Parse a document from an `EventReader`, failing with [`Error::TooDeep`] or
+    /// [`Error::TooManyNodes`] as soon as it exceeds `limits`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minidom::{Element, Error, ParseLimits};
+    /// use minidom::quick_xml::Reader;
+    ///
+    /// let limits = ParseLimits { max_depth: 2, ..ParseLimits::default() };
+    /// let mut reader = Reader::from_str("<a xmlns='ns'><b><c/></b></a>");
+    /// match Element::from_reader_with_limits(&mut reader, limits) {
+    ///     Err(error) => assert!(matches!(error.kind(), Error::TooDeep)),
+    ///     result => panic!("{:?}", result),
+    /// }
+    /// ```
+    ///
+    /// Errors come as [`Error::Positioned`], at the offset where the markup causing them starts,
+    /// including any whitespace before it the reader trims.  Only parsing from a string counts
+    /// lines and columns too, otherwise see [`Error::locate`].
+    pub fn from_reader_with_limits<R: BufRead>(
+        reader: &mut EventReader<R>,
+        limits: ParseLimits,
+    ) -> Result<Element> {
+        Element::parse(reader, limits, false)
+    }
+
+    /// Parse a document like [`Element::from_reader_with_limits`], also recording where each
+    /// element was in it, see [`Element::source_span`].
+    ///
+    /// The reader shouldn’t trim text, or the starts would include the whitespace before them.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minidom::{Element, ParseLimits};
+    /// use minidom::quick_xml::Reader;
+    ///
+    /// let xml = "<a xmlns='ns'>\n  <b/>\n</a>";
+    /// let mut reader = Reader::from_str(xml);
+    /// let elem = Element::from_reader_with_spans(&mut reader, ParseLimits::default()).unwrap();
+    /// let span = elem.get_child("b", "ns").unwrap().source_span().unwrap();
+    /// assert_eq!(&xml[span.start..span.end], "<b/>");
+    /// ```
+    pub fn from_reader_with_spans<R: BufRead>(
+        reader: &mut EventReader<R>,
+        limits: ParseLimits,
+    ) -> Result<Element> {
+        Element::parse(reader, limits, true)
+    }
+
+    /// Where this element was in the document it was parsed from, if it was parsed with
+    /// [`Element::from_reader_with_spans`].
+    pub fn source_span(&self) -> Option<&Span> {
+        self.span.as_deref()
+    }
+
+    fn parse<R: BufRead>(
+        reader: &mut EventReader<R>,
+        limits: ParseLimits,
+        spans: bool,
+    ) -> Result<Element> {
+        let mut start = reader.buffer_position();
+        Element::parse_events(reader, limits, spans, &mut start).map_err(|error| error.at(start))
+    }
+
+    /// Builds the tree, keeping `start` at the offset of the event being read for errors.
+    fn parse_events<R: BufRead>(
+        reader: &mut EventReader<R>,
+        limits: ParseLimits,
+        spans: bool,
+        start: &mut usize,
+    ) -> Result<Element> {
         let mut buf = Vec::new();
+        let mut nodes = 1;
 
         let mut prefixes = BTreeMap::new();
         let root: Element = loop {
+            *start = reader.buffer_position();
             let e = reader.read_event(&mut buf)?;
             match e {
                 Event::Empty(ref e) | Event::Start(ref e) => {
-                    break build_element(reader, e, &mut prefixes)?;
+                    let mut elem = build_element(reader, e, &mut prefixes)?;
+                    if spans {
+                        elem.record_span(*start, reader.buffer_position());
+                    }
+                    break elem;
                 }
                 Event::Eof => {
                     return Err(Error::EndOfDocument);
@@ -340,20 +841,34 @@ impl Element {
         let mut prefix_stack = vec![prefixes];
 
         loop {
+            *start = reader.buffer_position();
             match reader.read_event(&mut buf)? {
                 Event::Empty(ref e) => {
+                    nodes += 1;
+                    limits.check(stack.len() + 1, nodes)?;
                     let mut prefixes = prefix_stack.last().unwrap().clone();
-                    let elem = build_element(reader, e, &mut prefixes)?;
+                    let mut elem = build_element(reader, e, &mut prefixes)?;
+                    if spans {
+                        elem.record_span(*start, reader.buffer_position());
+                    }
                     // Since there is no Event::End after, directly append it to the current node
                     stack.last_mut().unwrap().append_child(elem);
                 }
                 Event::Start(ref e) => {
+                    nodes += 1;
+                    limits.check(stack.len() + 1, nodes)?;
                     let mut prefixes = prefix_stack.last().unwrap().clone();
-                    let elem = build_element(reader, e, &mut prefixes)?;
+                    let mut elem = build_element(reader, e, &mut prefixes)?;
+                    if spans {
+                        elem.record_span(*start, *start);
+                    }
                     stack.push(elem);
                     prefix_stack.push(prefixes);
                 }
                 Event::End(ref e) => {
+                    if let Some(span) = stack.last_mut().unwrap().span.as_mut() {
+                        span.end = reader.buffer_position();
+                    }
                     if stack.len() <= 1 {
                         break;
                     }
@@ -416,6 +931,8 @@ impl Element {
                 Event::Text(s) => {
                     let text = s.unescape_and_decode(reader)?;
                     if !text.is_empty() {
+                        nodes += 1;
+                        limits.check(stack.len(), nodes)?;
                         let current_elem = stack.last_mut().unwrap();
                         current_elem.append_text_node(text);
                     }
@@ -423,6 +940,8 @@ impl Element {
                 Event::CData(s) => {
                     let text = s.unescape_and_decode(&reader)?;
                     if !text.is_empty() {
+                        nodes += 1;
+                        limits.check(stack.len(), nodes)?;
                         let current_elem = stack.last_mut().unwrap();
                         current_elem.append_text_node(text);
                     }
@@ -437,6 +956,10 @@ impl Element {
         Ok(stack.pop().unwrap())
     }
 
+    fn record_span(&mut self, start: usize, end: usize) {
+        self.span = Some(Box::new(Span { start, end }));
+    }
+
     /// Output a document to a `Writer`.
     pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
         self.to_writer(&mut EventWriter::new(writer))
@@ -447,6 +970,21 @@ impl Element {
         self.to_writer_decl(&mut EventWriter::new(writer))
     }
 
+    /// Output a document to an asynchronous writer, one tag or text node at a time instead of
+    /// serialising it whole first, which matters for big documents.
+    ///
+    /// The bytes written are the same as with `write_to()`.
+    #[cfg(feature = "async")]
+    pub async fn write_to_async<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        let mut buffer = EventWriter::new(Vec::new());
+        for event in Events::new(self, &mut BTreeMap::new()) {
+            buffer.write_event(event)?;
+            write_all(writer, buffer.inner()).await?;
+            buffer.inner().clear();
+        }
+        Ok(())
+    }
+
     /// Output the document to quick-xml `Writer`
     pub fn to_writer<W: Write>(&self, writer: &mut EventWriter<W>) -> Result<()> {
         self.write_to_inner(writer, &mut BTreeMap::new())
@@ -464,6 +1002,18 @@ impl Element {
         writer: &mut EventWriter<W>,
         all_prefixes: &mut BTreeMap<Prefix, Namespace>,
     ) -> Result<()> {
+        for event in Events::new(self, all_prefixes) {
+            writer.write_event(event)?;
+        }
+        Ok(())
+    }
+
+    /// Builds the start tag of this element, along with its qualified name, declaring in
+    /// `all_prefixes` the namespaces it introduces.
+    fn start_tag(
+        &self,
+        all_prefixes: &mut BTreeMap<Prefix, Namespace>,
+    ) -> (String, BytesStart<'static>) {
         let local_prefixes: &BTreeMap<Option<String>, String> = self.prefixes.declared_prefixes();
 
         // Element namespace
@@ -500,10 +1050,10 @@ impl Element {
         };
 
         let name = match self_prefix {
-            (Some(ref prefix), _) => Cow::Owned(format!("{}:{}", prefix, self.name)),
-            _ => Cow::Borrowed(&self.name),
+            (Some(ref prefix), _) => format!("{}:{}", prefix, self.name),
+            _ => self.name.clone(),
         };
-        let mut start = BytesStart::borrowed(name.as_bytes(), name.len());
+        let mut start = BytesStart::owned_name(name.clone());
 
         // Write self prefix if necessary
         match self_prefix {
@@ -537,22 +1087,10 @@ impl Element {
         }
 
         for (key, value) in &self.attributes {
-            start.push_attribute((key.as_bytes(), escape(value.as_bytes()).as_ref()));
+            start.push_attribute((key.as_bytes(), escape_attribute(value.as_bytes()).as_ref()));
         }
 
-        if self.children.is_empty() {
-            writer.write_event(Event::Empty(start))?;
-            return Ok(());
-        }
-
-        writer.write_event(Event::Start(start))?;
-
-        for child in &self.children {
-            child.write_to_inner(writer, &mut all_prefixes.clone())?;
-        }
-
-        writer.write_event(Event::End(BytesEnd::borrowed(name.as_bytes())))?;
-        Ok(())
+        (name, start)
     }
 
     /// Returns an iterator over references to every child node of this element.
@@ -830,6 +1368,107 @@ impl Element {
         })?;
         self.children.remove(idx).into_element()
     }
+
+    /// Removes the first child with this name and namespace, if it exists, and returns it.
+    ///
+    /// This is the same as [`remove_child`](#method.remove_child), named to pair with
+    /// [`take_children`](#method.take_children).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minidom::Element;
+    ///
+    /// let mut elem: Element = r#"<node xmlns="ns"><a /><b /><a /></node>"#.parse().unwrap();
+    /// assert!(elem.take_child("a", "ns").unwrap().is("a", "ns"));
+    /// assert_eq!(elem.children().count(), 2);
+    /// ```
+    pub fn take_child<'a, N: AsRef<str>, NS: Into<NSChoice<'a>>>(
+        &mut self,
+        name: N,
+        namespace: NS,
+    ) -> Option<Element> {
+        self.remove_child(name, namespace)
+    }
+
+    /// Removes every child with this name and namespace, and returns them in document order.
+    ///
+    /// Remaining nodes, including text nodes, keep their relative order.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minidom::Element;
+    ///
+    /// let mut elem: Element = r#"<node xmlns="ns">x<a />y<b />z<a /></node>"#.parse().unwrap();
+    /// let removed = elem.take_children("a", "ns");
+    /// assert_eq!(removed.len(), 2);
+    /// assert_eq!(elem.children().count(), 1);
+    /// assert_eq!(elem.text(), "xyz");
+    /// ```
+    pub fn take_children<'a, N: AsRef<str>, NS: Into<NSChoice<'a>>>(
+        &mut self,
+        name: N,
+        namespace: NS,
+    ) -> Vec<Element> {
+        let name = name.as_ref();
+        let namespace = namespace.into();
+        self.drain_children_where(|child| child.is(name, namespace))
+    }
+
+    /// Keeps only the child elements for which `f` returns `true`.
+    ///
+    /// Text nodes are always kept, and every kept node stays in the same relative order.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minidom::Element;
+    ///
+    /// let mut elem: Element = r#"<node xmlns="ns">x<a />y<b />z</node>"#.parse().unwrap();
+    /// elem.retain_children(|child| child.name() != "a");
+    /// assert!(!elem.has_child("a", "ns"));
+    /// assert!(elem.has_child("b", "ns"));
+    /// assert_eq!(elem.text(), "xyz");
+    /// ```
+    pub fn retain_children<F: FnMut(&Element) -> bool>(&mut self, mut f: F) {
+        self.children.retain(|node| match node {
+            Node::Element(ref elem) => f(elem),
+            Node::Text(_) => true,
+        });
+    }
+
+    /// Removes every child element for which `pred` returns `true`, and returns them in document
+    /// order.
+    ///
+    /// Text nodes are never removed, and remaining nodes stay in the same relative order.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use minidom::Element;
+    ///
+    /// let mut elem: Element = r#"<node xmlns="ns"><a /><b /><c /></node>"#.parse().unwrap();
+    /// let removed = elem.drain_children_where(|child| child.name() != "b");
+    /// assert_eq!(removed.len(), 2);
+    /// assert_eq!(removed[0].name(), "a");
+    /// assert_eq!(removed[1].name(), "c");
+    /// assert_eq!(elem.children().count(), 1);
+    /// ```
+    pub fn drain_children_where<F: FnMut(&Element) -> bool>(
+        &mut self,
+        mut pred: F,
+    ) -> Vec<Element> {
+        let mut removed = Vec::new();
+        let children = std::mem::take(&mut self.children);
+        for node in children {
+            match node {
+                Node::Element(elem) if pred(&elem) => removed.push(elem),
+                node => self.children.push(node),
+            }
+        }
+        removed
+    }
 }
 
 fn split_element_name<S: AsRef<str>>(s: S) -> Result<(Option<String>, String)> {
@@ -47,6 +47,110 @@ pub enum Error {
 
     /// An error which is returned when a prefixed is defined twice
     DuplicatePrefix,
+
+    /// An error which is returned when elements are nested deeper than the `ParseLimits` allow
+    TooDeep,
+
+    /// An error which is returned when a document has more nodes than the `ParseLimits` allow
+    TooManyNodes,
+
+    /// Any of the above, with where in the document the parser encountered it
+    Positioned(Box<Error>, Position),
+}
+
+/// Where in a document the parser encountered an error, or some markup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    /// The number of bytes before it.
+    pub offset: usize,
+    /// Its line and column, both counting from 1, the column in characters; `None` when the
+    /// document wasn’t at hand to count them, see [`Error::locate`].
+    pub line_column: Option<(usize, usize)>,
+}
+
+impl Position {
+    /// The position `offset` bytes into `source`, with its line and column.
+    pub fn in_source(source: &[u8], offset: usize) -> Position {
+        let before = &source[..offset.min(source.len())];
+        let line_start = before
+            .iter()
+            .rposition(|&byte| byte == b'\n')
+            .map_or(0, |newline| newline + 1);
+        let line = before.iter().filter(|&&byte| byte == b'\n').count() + 1;
+        // Count characters rather than bytes, by skipping UTF-8 continuation bytes.
+        let column = before[line_start..]
+            .iter()
+            .filter(|&&byte| byte & 0xc0 != 0x80)
+            .count()
+            + 1;
+        Position {
+            offset,
+            line_column: Some((line, column)),
+        }
+    }
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.line_column {
+            Some((line, column)) => write!(
+                fmt,
+                "line {}, column {} (byte {})",
+                line, column, self.offset
+            ),
+            None => write!(fmt, "byte {}", self.offset),
+        }
+    }
+}
+
+impl Error {
+    /// This error without its position.
+    pub fn kind(&self) -> &Error {
+        match self {
+            Error::Positioned(error, _) => error.kind(),
+            error => error,
+        }
+    }
+
+    /// This error without its position, by value.
+    pub fn into_kind(self) -> Error {
+        match self {
+            Error::Positioned(error, _) => error.into_kind(),
+            error => error,
+        }
+    }
+
+    /// Where in the document the parser encountered this error, if it did.
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            Error::Positioned(_, position) => Some(*position),
+            _ => None,
+        }
+    }
+
+    /// Fills the line and column of the position in from `source`, the document this error
+    /// comes from, for parsers which only count bytes such as [`crate::Element::from_reader`].
+    pub fn locate(self, source: &[u8]) -> Error {
+        match self {
+            Error::Positioned(error, position) => {
+                Error::Positioned(error, Position::in_source(source, position.offset))
+            }
+            error => error,
+        }
+    }
+
+    pub(crate) fn at(self, offset: usize) -> Error {
+        match self {
+            Error::Positioned(..) => self,
+            error => Error::Positioned(
+                Box::new(error),
+                Position {
+                    offset,
+                    line_column: None,
+                },
+            ),
+        }
+    }
 }
 
 impl StdError for Error {
@@ -62,6 +166,9 @@ impl StdError for Error {
             Error::MissingNamespace => None,
             Error::NoComments => None,
             Error::DuplicatePrefix => None,
+            Error::TooDeep => None,
+            Error::TooManyNodes => None,
+            Error::Positioned(e, _) => Some(&**e),
         }
     }
 }
@@ -86,6 +193,9 @@ impl std::fmt::Display for Error {
                 "a comment has been found even though comments are forbidden"
             ),
             Error::DuplicatePrefix => write!(fmt, "the prefix is already defined"),
+            Error::TooDeep => write!(fmt, "the elements are nested too deeply"),
+            Error::TooManyNodes => write!(fmt, "the document has too many nodes"),
+            Error::Positioned(e, position) => write!(fmt, "{} at {}", e, position),
         }
     }
 }
@@ -88,7 +88,7 @@ mod prefixes;
 mod tests;
 
 pub use convert::IntoAttributeValue;
-pub use element::{Children, ChildrenMut, Element, ElementBuilder};
-pub use error::{Error, Result};
+pub use element::{Children, ChildrenMut, Element, ElementBuilder, ParseLimits, Span};
+pub use error::{Error, Position, Result};
 pub use namespaces::NSChoice;
 pub use node::Node;
@@ -9,15 +9,16 @@
 //! Provides the `Node` struct, which represents a node in the DOM.
 
 use crate::element::{Element, ElementBuilder};
-use crate::error::Result;
 
-use std::collections::BTreeMap;
-use std::io::Write;
-
-use quick_xml::events::{BytesText, Event};
-use quick_xml::Writer as EventWriter;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 /// A node in an element tree.
+///
+/// With the `serde` feature, a node is represented as either `{"element": {…}}` or
+/// `{"text": "…"}`.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 #[derive(Clone, Debug, Eq)]
 pub enum Node {
     /// An `Element`.
@@ -158,22 +159,6 @@ impl Node {
             Node::Text(s) => Some(s),
         }
     }
-
-    #[doc(hidden)]
-    pub(crate) fn write_to_inner<W: Write>(
-        &self,
-        writer: &mut EventWriter<W>,
-        prefixes: &mut BTreeMap<Option<String>, String>,
-    ) -> Result<()> {
-        match *self {
-            Node::Element(ref elmt) => elmt.write_to_inner(writer, prefixes)?,
-            Node::Text(ref s) => {
-                writer.write_event(Event::Text(BytesText::from_plain_str(s)))?;
-            }
-        }
-
-        Ok(())
-    }
 }
 
 impl<I> From<I> for Node
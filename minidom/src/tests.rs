@@ -10,8 +10,9 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::element::Element;
-use crate::error::Error;
+use crate::element::{Element, ParseLimits, Span};
+use crate::error::{Error, Position};
+use crate::node::Node;
 
 use quick_xml::Reader;
 
@@ -43,6 +44,23 @@ fn reader_works() {
     );
 }
 
+#[test]
+fn reader_configuration() {
+    let xml = "<a xmlns='ns'>\n  <b/>\n  <body> Hi </body>\n</a>";
+    let mut reader = Reader::from_str(xml);
+    reader.expand_empty_elements(true);
+    assert_eq!(
+        Element::from_reader(&mut reader).unwrap(),
+        xml.parse::<Element>().unwrap()
+    );
+
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    let elem = Element::from_reader(&mut reader).unwrap();
+    assert_eq!(elem.nodes().count(), 2);
+    assert_eq!(elem.get_child("body", "ns").unwrap().text(), "Hi");
+}
+
 #[test]
 fn reader_deduplicate_prefixes() {
     // The reader shouldn't complain that "child" doesn't have a namespace. It should reuse the
@@ -53,7 +71,10 @@ fn reader_deduplicate_prefixes() {
         .parse()
         .unwrap();
 
-    match r#"<p1:root xmlns:p1="ns1"><child/></p1:root>"#.parse::<Element>() {
+    match r#"<p1:root xmlns:p1="ns1"><child/></p1:root>"#
+        .parse::<Element>()
+        .map_err(Error::into_kind)
+    {
         Err(Error::MissingNamespace) => (),
         Err(err) => panic!("No or wrong error: {:?}", err),
         Ok(elem) => panic!(
@@ -66,7 +87,10 @@ fn reader_deduplicate_prefixes() {
 #[test]
 fn reader_no_deduplicate_sibling_prefixes() {
     // The reader shouldn't reuse the sibling's prefixes
-    match r#"<root xmlns="ns1"><p1:child1 xmlns:p1="ns2"/><p1:child2/></root>"#.parse::<Element>() {
+    match r#"<root xmlns="ns1"><p1:child1 xmlns:p1="ns2"/><p1:child2/></root>"#
+        .parse::<Element>()
+        .map_err(Error::into_kind)
+    {
         Err(Error::MissingNamespace) => (),
         Err(err) => panic!("No or wrong error: {:?}", err),
         Ok(elem) => panic!(
@@ -268,6 +292,93 @@ fn writer_escapes_text() {
     );
 }
 
+#[test]
+fn writer_escapes_minimally() {
+    let root = Element::builder("root", "ns1")
+        .attr("a", "it's <b> & \"c\"\tline\nbreak")
+        .append("it's <b> & \"c\" ]]> done\r\n")
+        .build();
+    assert_eq!(
+        String::from(&root),
+        "<root xmlns=\"ns1\" a=\"it's &lt;b> &amp; &quot;c&quot;&#9;line&#10;break\">it's &lt;b> &amp; \"c\" ]]&gt; done&#13;\n</root>"
+    );
+}
+
+#[test]
+fn escaping_round_trips() {
+    let inputs = [
+        "plain",
+        "<tag attr='value'>",
+        "a & b && c",
+        "]]> ]> ]]]]>",
+        "'single' \"double\"",
+        "tab\tnewline\ncarriage\rreturn",
+        "&amp; already escaped",
+    ];
+    for input in inputs.iter() {
+        let root = Element::builder("root", "ns1")
+            .attr("a", *input)
+            .append(*input)
+            .build();
+        let parsed: Element = String::from(&root).parse().unwrap();
+        assert_eq!(parsed.attr("a"), Some(*input));
+        assert_eq!(parsed.text(), *input);
+    }
+}
+
+#[test]
+fn error_elements() {
+    let error = Element::error(
+        "jabber:client",
+        "modify",
+        "bad-request",
+        Some("No <body/>."),
+    );
+    let expected: Element = "<error xmlns='jabber:client' type='modify'><bad-request xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/><text xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'>No &lt;body/&gt;.</text></error>".parse().unwrap();
+    assert!(error.strict_eq(&expected));
+
+    let error = Element::error(
+        "jabber:component:accept",
+        "wait",
+        "resource-constraint",
+        None,
+    );
+    assert_eq!(
+        String::from(&error),
+        "<error xmlns=\"jabber:component:accept\" type=\"wait\"><resource-constraint xmlns=\"urn:ietf:params:xml:ns:xmpp-stanzas\"/></error>"
+    );
+}
+
+#[test]
+fn error_reply_swaps_addresses() {
+    let message: Element = "<message xmlns='jabber:client' from='juliet@capulet.lit/balcony' to='romeo@montague.lit' id='m1' type='chat'><body>Hi</body></message>".parse().unwrap();
+    let reply = message.error_reply("cancel", "item-not-found", Some("Gone."));
+    let expected: Element = "<message xmlns='jabber:client' from='romeo@montague.lit' to='juliet@capulet.lit/balcony' id='m1' type='error'><error type='cancel'><item-not-found xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/><text xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'>Gone.</text></error></message>".parse().unwrap();
+    assert!(reply.strict_eq(&expected));
+
+    // Missing addresses and id stay missing.
+    let presence: Element = "<presence xmlns='jabber:client'/>".parse().unwrap();
+    let reply = presence.error_reply("cancel", "not-allowed", None);
+    assert_eq!(reply.attr("id"), None);
+    assert_eq!(reply.attr("from"), None);
+    assert_eq!(reply.attr("to"), None);
+    assert_eq!(reply.attr("type"), Some("error"));
+}
+
+#[test]
+fn escapers_differ_by_context() {
+    use crate::element::{escape_attribute, escape_text};
+    use std::borrow::Cow;
+
+    // Nothing to escape, nothing gets allocated.
+    assert!(matches!(escape_text(b"a > b"), Cow::Borrowed(_)));
+    assert!(matches!(escape_attribute(b"it's"), Cow::Borrowed(_)));
+
+    let input = b"<'\">&\n";
+    assert_eq!(&*escape_text(input), &b"&lt;'\">&amp;\n"[..]);
+    assert_eq!(&*escape_attribute(input), &b"&lt;'&quot;>&amp;&#10;"[..]);
+}
+
 #[test]
 fn builder_works() {
     let elem = Element::builder("a", "b")
@@ -316,6 +427,72 @@ fn get_child_works() {
     );
 }
 
+fn build_interleaved_tree() -> Element {
+    let mut root = Element::bare("root", "root_ns");
+    root.append_text_node("a");
+    root.append_child(Element::bare("private", "hints_ns"));
+    root.append_text_node("b");
+    root.append_child(Element::bare("body", "root_ns"));
+    root.append_text_node("c");
+    root.append_child(Element::bare("private", "hints_ns"));
+    root.append_text_node("d");
+    root
+}
+
+fn node_names(root: &Element) -> Vec<String> {
+    root.nodes()
+        .map(|node| match node {
+            Node::Element(elem) => format!("<{}>", elem.name()),
+            Node::Text(text) => text.clone(),
+        })
+        .collect()
+}
+
+#[test]
+fn take_children_keeps_text_order() {
+    let mut root = build_interleaved_tree();
+    let removed = root.take_children("private", "hints_ns");
+    assert_eq!(removed.len(), 2);
+    assert!(removed.iter().all(|elem| elem.is("private", "hints_ns")));
+    assert_eq!(node_names(&root), vec!["a", "b", "<body>", "c", "d"]);
+    assert!(root.take_children("private", "hints_ns").is_empty());
+}
+
+#[test]
+fn take_child_removes_first_match_only() {
+    let mut root = build_interleaved_tree();
+    assert!(root.take_child("private", "hints_ns").is_some());
+    assert_eq!(
+        node_names(&root),
+        vec!["a", "b", "<body>", "c", "<private>", "d"]
+    );
+    assert!(root.take_child("private", "root_ns").is_none());
+}
+
+#[test]
+fn retain_children_keeps_text_order() {
+    let mut root = build_interleaved_tree();
+    root.retain_children(|child| !child.is("body", "root_ns"));
+    assert_eq!(
+        node_names(&root),
+        vec!["a", "<private>", "b", "c", "<private>", "d"]
+    );
+    root.retain_children(|_| false);
+    assert_eq!(node_names(&root), vec!["a", "b", "c", "d"]);
+    assert_eq!(root.text(), "abcd");
+}
+
+#[test]
+fn drain_children_where_returns_removed_in_order() {
+    let mut root = build_interleaved_tree();
+    root.children_mut().last().unwrap().set_attr("id", "last");
+    let removed = root.drain_children_where(|child| child.has_ns("hints_ns"));
+    assert_eq!(removed.len(), 2);
+    assert_eq!(removed[0].attr("id"), None);
+    assert_eq!(removed[1].attr("id"), Some("last"));
+    assert_eq!(node_names(&root), vec!["a", "b", "<body>", "c", "d"]);
+}
+
 #[test]
 fn namespace_propagation_works() {
     let mut root = Element::builder("root", "root_ns").build();
@@ -346,6 +523,29 @@ fn two_elements_with_same_arguments_different_order_are_equal() {
     assert_ne!(elem1, elem2);
 }
 
+#[test]
+fn strict_eq_compares_every_node() {
+    let elem1: Element = "<a xmlns='ns1' b='a' c=''><d/>text</a>".parse().unwrap();
+    let elem2: Element = "<a xmlns='ns1' c='' b='a'><d/>text</a>".parse().unwrap();
+    assert!(elem1.strict_eq(&elem2));
+
+    // Trailing nodes only count for strict_eq.
+    let elem2: Element = "<a xmlns='ns1' b='a' c=''><d/></a>".parse().unwrap();
+    assert_eq!(elem1, elem2);
+    assert!(!elem1.strict_eq(&elem2));
+    assert!(!elem2.strict_eq(&elem1));
+
+    let elem1: Element = "<a xmlns='ns1'><b/> </a>".parse().unwrap();
+    let elem2: Element = "<a xmlns='ns1'><b/>  </a>".parse().unwrap();
+    assert!(!elem1.strict_eq(&elem2));
+
+    let elem1: Element = "<a xmlns='ns1'><b xmlns='ns2'/></a>".parse().unwrap();
+    let elem2: Element = "<a xmlns='ns1'><b xmlns='ns3'/></a>".parse().unwrap();
+    assert!(!elem1.strict_eq(&elem2));
+    let elem2: Element = "<a xmlns='ns1' xmlns:x='ns2'><x:b/></a>".parse().unwrap();
+    assert!(elem1.strict_eq(&elem2));
+}
+
 #[test]
 fn namespace_attributes_works() {
     let mut reader = Reader::from_str(TEST_STRING);
@@ -423,7 +623,7 @@ fn namespace_inherited_prefixed2() {
 #[test]
 fn fail_comments() {
     let elem: Result<Element, Error> = "<foo xmlns='ns1'><!-- bar --></foo>".parse();
-    match elem {
+    match elem.map_err(Error::into_kind) {
         Err(Error::NoComments) => (),
         _ => panic!(),
     };
@@ -431,20 +631,83 @@ fn fail_comments() {
 
 #[test]
 fn xml_error() {
-    match "<a xmlns='ns1'></b>".parse::<Element>() {
+    match "<a xmlns='ns1'></b>"
+        .parse::<Element>()
+        .map_err(Error::into_kind)
+    {
         Err(crate::error::Error::XmlError(_)) => (),
         err => panic!("No or wrong error: {:?}", err),
     }
 
-    match "<a xmlns='ns1'></".parse::<Element>() {
+    match "<a xmlns='ns1'></"
+        .parse::<Element>()
+        .map_err(Error::into_kind)
+    {
         Err(crate::error::Error::XmlError(_)) => (),
         err => panic!("No or wrong error: {:?}", err),
     }
 }
 
+#[test]
+fn error_position() {
+    // The mismatched end tag starts on the third line, after a two-byte character.
+    let xml = "<a xmlns='ns1'>\n  <b>\n  é</c>\n</a>";
+    let error = xml.parse::<Element>().unwrap_err();
+    assert!(matches!(error.kind(), Error::XmlError(_)));
+    let position = error.position().unwrap();
+    assert_eq!(position.offset, 26);
+    assert_eq!(position.line_column, Some((3, 4)));
+    assert!(error
+        .to_string()
+        .ends_with(" at line 3, column 4 (byte 26)"));
+
+    // Readers only count bytes, until given the document.
+    let mut reader = Reader::from_str(xml);
+    let error = Element::from_reader(&mut reader).unwrap_err();
+    assert_eq!(
+        error.position(),
+        Some(Position {
+            offset: 26,
+            line_column: None
+        })
+    );
+    assert!(error.to_string().ends_with(" at byte 26"));
+    let error = error.locate(xml.as_bytes());
+    assert_eq!(error.position().unwrap().line_column, Some((3, 4)));
+
+    // Errors of minidom itself too.
+    let error = "<a xmlns='ns1'>\n<!-- no -->\n</a>"
+        .parse::<Element>()
+        .unwrap_err();
+    assert!(matches!(error.kind(), Error::NoComments));
+    assert_eq!(error.position().unwrap().line_column, Some((2, 1)));
+}
+
+#[test]
+fn source_spans() {
+    let xml = "<a xmlns='ns1'>\n  <b>text<c/></b>\n  <d x='y'/>\n</a>";
+    let mut reader = Reader::from_str(xml);
+    let elem = Element::from_reader_with_spans(&mut reader, ParseLimits::default()).unwrap();
+    let source = |elem: &Element| {
+        let span = elem.source_span().unwrap();
+        &xml[span.start..span.end]
+    };
+    assert_eq!(source(&elem), xml);
+    let b = elem.get_child("b", "ns1").unwrap();
+    assert_eq!(b.source_span(), Some(&Span { start: 18, end: 33 }));
+    assert_eq!(source(b), "<b>text<c/></b>");
+    assert_eq!(source(b.get_child("c", "ns1").unwrap()), "<c/>");
+    assert_eq!(source(elem.get_child("d", "ns1").unwrap()), "<d x='y'/>");
+
+    // Spans don’t take part in comparisons, and aren’t recorded otherwise.
+    let plain: Element = xml.parse().unwrap();
+    assert_eq!(plain.source_span(), None);
+    assert_eq!(plain, elem);
+}
+
 #[test]
 fn invalid_element_error() {
-    match "<a:b:c>".parse::<Element>() {
+    match "<a:b:c>".parse::<Element>().map_err(Error::into_kind) {
         Err(crate::error::Error::InvalidElement) => (),
         err => panic!("No or wrong error: {:?}", err),
     }
@@ -452,8 +715,207 @@ fn invalid_element_error() {
 
 #[test]
 fn missing_namespace_error() {
-    match "<a/>".parse::<Element>() {
+    match "<a/>".parse::<Element>().map_err(Error::into_kind) {
         Err(crate::error::Error::MissingNamespace) => (),
         err => panic!("No or wrong error: {:?}", err),
     }
 }
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_round_trip() {
+    let root = build_test_tree();
+    let json = serde_json::to_value(&root).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "name": "root",
+            "ns": "root_ns",
+            "attrs": {"a": "b", "xml:lang": "en"},
+            "children": [
+                {"text": "meow"},
+                {"element": {
+                    "name": "child",
+                    "ns": "root_ns",
+                    "attrs": {"c": "d"},
+                    "children": [],
+                }},
+                {"element": {
+                    "name": "child",
+                    "ns": "child_ns",
+                    "attrs": {"d": "e", "xml:lang": "fr"},
+                    "children": [],
+                }},
+                {"text": "nya"},
+            ],
+        })
+    );
+    let root2: Element = serde_json::from_value(json).unwrap();
+    assert_eq!(root, root2);
+    assert_eq!(String::from(&root), String::from(&root2));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_defaults_and_errors() {
+    let elem: Element = serde_json::from_str(r#"{"name": "a", "ns": "ns1"}"#).unwrap();
+    assert_eq!(elem, Element::bare("a", "ns1"));
+
+    let error = serde_json::from_str::<Element>(r#"{"name": "p:a", "ns": "ns1"}"#);
+    assert!(error.is_err());
+}
+
+#[test]
+fn into_prefixless() {
+    let elem: Element = "<stream:stream xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams'><stream:features/><message><body xmlns='urn:example:foo' xml:lang='en'>Hello</body></message></stream:stream>"
+        .parse()
+        .unwrap();
+    let message = elem.get_child("message", "jabber:client").unwrap().clone();
+    let message = message.into_prefixless();
+    assert_eq!(
+        String::from(&message),
+        r#"<message xmlns="jabber:client"><body xmlns="urn:example:foo" xml:lang="en">Hello</body></message>"#
+    );
+
+    // Namespaces are left untouched.
+    let elem = elem.into_prefixless();
+    assert_eq!(elem.ns(), "http://etherx.jabber.org/streams");
+    let features = elem.children().next().unwrap();
+    assert_eq!(features.ns(), "http://etherx.jabber.org/streams");
+    assert_eq!(
+        String::from(features),
+        r#"<features xmlns="http://etherx.jabber.org/streams"/>"#
+    );
+}
+
+#[test]
+fn depth_bomb() {
+    let depth = 100_000;
+    let xml = format!("{}{}", "<a xmlns='ns'>".repeat(depth), "</a>".repeat(depth));
+    match xml.parse::<Element>().map_err(Error::into_kind) {
+        Err(Error::TooDeep) => (),
+        result => panic!("{:?}", result.map(|_| ())),
+    }
+
+    // Exactly at the limit is fine.
+    let limits = ParseLimits {
+        max_depth: 3,
+        ..ParseLimits::default()
+    };
+    let mut reader = Reader::from_str("<a xmlns='ns'><b><c>text</c><d/></b></a>");
+    assert!(Element::from_reader_with_limits(&mut reader, limits).is_ok());
+    let mut reader = Reader::from_str("<a xmlns='ns'><b><c><d/></c></b></a>");
+    match Element::from_reader_with_limits(&mut reader, limits).map_err(Error::into_kind) {
+        Err(Error::TooDeep) => (),
+        result => panic!("{:?}", result),
+    }
+}
+
+#[test]
+fn breadth_bomb() {
+    let xml = format!("<a xmlns='ns'>{}</a>", "<b/>".repeat(1_000_000));
+    let start = std::time::Instant::now();
+    match xml.parse::<Element>().map_err(Error::into_kind) {
+        Err(Error::TooManyNodes) => (),
+        result => panic!("{:?}", result.map(|_| ())),
+    }
+    // Parsing stopped at the limit, long before the end of the document.
+    let mut reader = Reader::from_str(&xml);
+    let _ = Element::from_reader(&mut reader);
+    assert!(reader.buffer_position() < xml.len() / 5);
+    assert!(start.elapsed() < std::time::Duration::from_secs(30));
+
+    // Text nodes count too.
+    let limits = ParseLimits {
+        max_nodes: 3,
+        ..ParseLimits::default()
+    };
+    let mut reader = Reader::from_str("<a xmlns='ns'>x<b/>y</a>");
+    match Element::from_reader_with_limits(&mut reader, limits).map_err(Error::into_kind) {
+        Err(Error::TooManyNodes) => (),
+        result => panic!("{:?}", result),
+    }
+    let mut reader = Reader::from_str("<a xmlns='ns'><b/>y</a>");
+    assert!(Element::from_reader_with_limits(&mut reader, limits).is_ok());
+}
+
+#[cfg(feature = "async")]
+mod write_async {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::io::AsyncWrite;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Accepts at most three bytes per write, like a congested socket.
+    struct Trickle(Vec<u8>);
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let len = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..len]);
+            Poll::Ready(Ok(len))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn big_tree() -> Element {
+        let mut root = Element::builder("items", "urn:example:big").build();
+        for i in 0..10_000 {
+            let item = Element::builder("item", "urn:example:big")
+                .attr("id", i.to_string())
+                .append(format!(
+                    "payload <{}> & more payload, {}",
+                    i,
+                    "x".repeat(64)
+                ))
+                .build();
+            root.append_child(item);
+        }
+        root
+    }
+
+    #[test]
+    fn write_to_async_matches_write_to() {
+        let corpus: Vec<Element> = vec![
+            TEST_STRING.parse().unwrap(),
+            build_test_tree(),
+            "<a xmlns='ns1'/>".parse().unwrap(),
+            "<a xmlns='ns1' b='&quot;&lt;&amp;'>&lt;&gt;&amp; '\"</a>"
+                .parse()
+                .unwrap(),
+            "<a xmlns='ns1' xmlns:x='ns2'><x:b><c xmlns='ns3'><x:d/></c></x:b>text</a>"
+                .parse()
+                .unwrap(),
+            Element::builder("a", "ns1")
+                .prefix(Some(String::from("p")), "ns1")
+                .unwrap()
+                .build(),
+            big_tree(),
+        ];
+        for elem in &corpus {
+            let mut sync = Vec::new();
+            elem.write_to(&mut sync).unwrap();
+
+            let mut asynchronous = Vec::new();
+            block_on(elem.write_to_async(&mut asynchronous)).unwrap();
+            assert_eq!(asynchronous, sync);
+
+            let mut trickle = Trickle(Vec::new());
+            block_on(elem.write_to_async(&mut trickle)).unwrap();
+            assert_eq!(trickle.0, sync);
+        }
+    }
+}
@@ -0,0 +1,328 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::data_forms::DataForm;
+use crate::iq::{IqResultPayload, IqSetPayload};
+use crate::ns;
+use crate::util::error::Error;
+use crate::util::helpers::Text;
+use crate::Element;
+use std::convert::TryFrom;
+
+generate_attribute!(
+    /// What the requester wants to do with a command.
+    Action, "action", {
+        /// Start the command, or go on with its current stage.
+        Execute => "execute",
+
+        /// Abort the command.
+        Cancel => "cancel",
+
+        /// Go back to the previous stage.
+        Prev => "prev",
+
+        /// Go on to the next stage.
+        Next => "next",
+
+        /// Finish the command with the data of the current stage.
+        Complete => "complete",
+    }, Default = Execute
+);
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        fmt.write_str(match self {
+            Action::Execute => "execute",
+            Action::Cancel => "cancel",
+            Action::Prev => "prev",
+            Action::Next => "next",
+            Action::Complete => "complete",
+        })
+    }
+}
+
+generate_attribute!(
+    /// Where a command is at.
+    Status, "status", {
+        /// The command is waiting for more data from the requester.
+        Executing => "executing",
+
+        /// The command is done.
+        Completed => "completed",
+
+        /// The command got aborted.
+        Canceled => "canceled",
+    }
+);
+
+generate_attribute!(
+    /// How important a note is.
+    NoteType, "type", {
+        /// Some information, such as the result of the command.
+        Info => "info",
+
+        /// Something went wrong but the command could go on.
+        Warn => "warn",
+
+        /// The command failed.
+        Error => "error",
+    }, Default = Info
+);
+
+generate_element!(
+    /// A message from the responder about the command.
+    Note, "note", COMMANDS,
+    attributes: [
+        /// How important this note is.
+        type_: Default<NoteType> = "type"
+    ],
+    text: (
+        /// The text of this note.
+        text: Text<String>
+    )
+);
+
+/// The actions the requester can take at the current stage of a command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Actions {
+    /// The action which `execute` stands for at this stage.
+    pub execute: Option<Action>,
+
+    /// The actions allowed, among `prev`, `next` and `complete`.
+    pub allowed: Vec<Action>,
+}
+
+impl TryFrom<Element> for Actions {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<Actions, Error> {
+        check_self!(elem, "actions", COMMANDS);
+        check_no_unknown_attributes!(elem, "actions", ["execute"]);
+        let mut allowed = Vec::new();
+        for child in elem.children() {
+            if child.ns() != ns::COMMANDS {
+                return Err(Error::ParseError("Unknown child in actions element."));
+            }
+            check_no_children!(child, "action");
+            check_no_attributes!(child, "action");
+            allowed.push(match child.name() {
+                "prev" => Action::Prev,
+                "next" => Action::Next,
+                "complete" => Action::Complete,
+                _ => return Err(Error::ParseError("Unknown child in actions element.")),
+            });
+        }
+        Ok(Actions {
+            execute: get_attr!(elem, "execute", Option),
+            allowed,
+        })
+    }
+}
+
+impl From<Actions> for Element {
+    fn from(actions: Actions) -> Element {
+        Element::builder("actions", ns::COMMANDS)
+            .attr("execute", actions.execute.map(|action| action.to_string()))
+            .append_all(
+                actions
+                    .allowed
+                    .into_iter()
+                    .map(|action| Element::builder(action.to_string(), ns::COMMANDS)),
+            )
+            .build()
+    }
+}
+
+generate_element!(
+    /// A request to run a command, or the answer of the responder.
+    Command, "command", COMMANDS,
+    attributes: [
+        /// The node of the command.
+        node: Required<String> = "node",
+
+        /// The session of a command spanning several stages, set by the
+        /// responder.
+        session_id: Option<String> = "sessionid",
+
+        /// What the requester wants to do.
+        action: Default<Action> = "action",
+
+        /// Where the command is at, set by the responder.
+        status: Option<Status> = "status",
+    ],
+    children: [
+        /// The actions allowed at this stage.
+        actions: Option<Actions> = ("actions", COMMANDS) => Actions,
+
+        /// The messages from the responder.
+        notes: Vec<Note> = ("note", COMMANDS) => Note,
+
+        /// The form to fill, or its result.
+        form: Option<DataForm> = ("x", DATA_FORMS) => DataForm
+    ]
+);
+
+impl IqSetPayload for Command {}
+impl IqResultPayload for Command {}
+
+impl Command {
+    /// Execute the command of this `node`.
+    pub fn new<N: Into<String>>(node: N) -> Command {
+        Command {
+            node: node.into(),
+            session_id: None,
+            action: Action::Execute,
+            status: None,
+            actions: None,
+            notes: Vec::new(),
+            form: None,
+        }
+    }
+
+    /// Continue the given session.
+    pub fn with_session_id<S: Into<String>>(mut self, session_id: S) -> Command {
+        self.session_id = Some(session_id.into());
+        self
+    }
+
+    /// Do something else than executing.
+    pub fn with_action(mut self, action: Action) -> Command {
+        self.action = action;
+        self
+    }
+
+    /// Send this form along.
+    pub fn with_form(mut self, form: DataForm) -> Command {
+        self.form = Some(form);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_forms::DataFormType;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(Action, 1);
+        assert_size!(Status, 1);
+        assert_size!(NoteType, 1);
+        assert_size!(Note, 16);
+        assert_size!(Actions, 16);
+        assert_size!(Command, 108);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(Action, 1);
+        assert_size!(Status, 1);
+        assert_size!(NoteType, 1);
+        assert_size!(Note, 32);
+        assert_size!(Actions, 32);
+        assert_size!(Command, 216);
+    }
+
+    #[test]
+    fn test_execute() {
+        let elem: Element =
+            "<command xmlns='http://jabber.org/protocol/commands' node='list' action='execute'/>"
+                .parse()
+                .unwrap();
+        let command = Command::try_from(elem).unwrap();
+        assert_eq!(command, Command::new("list"));
+
+        // The default action doesn’t get serialised.
+        let elem: Element = Command::new("list").into();
+        assert_eq!(elem.attr("action"), None);
+        assert_eq!(elem.attr("node"), Some("list"));
+    }
+
+    #[test]
+    fn test_stages() {
+        let elem: Element = "<command xmlns='http://jabber.org/protocol/commands' sessionid='config:20020923T213616Z-700' node='config' status='executing'>
+            <actions execute='next'><next/></actions>
+            <x xmlns='jabber:x:data' type='form'>
+                <title>Configure Service</title>
+                <field var='mode' type='list-single'><option><value>1</value></option></field>
+            </x>
+        </command>"
+            .parse()
+            .unwrap();
+        let command = Command::try_from(elem).unwrap();
+        assert_eq!(
+            command.session_id.as_deref(),
+            Some("config:20020923T213616Z-700")
+        );
+        assert_eq!(command.status, Some(Status::Executing));
+        let actions = command.actions.unwrap();
+        assert_eq!(actions.execute, Some(Action::Next));
+        assert_eq!(actions.allowed, vec![Action::Next]);
+        assert_eq!(command.form.unwrap().type_, DataFormType::Form);
+
+        let elem: Element = "<command xmlns='http://jabber.org/protocol/commands' sessionid='config:20020923T213616Z-700' node='config' status='completed'>
+            <note type='info'>Service 'httpd' has been configured.</note>
+            <note type='warn'>Restart needed.</note>
+        </command>"
+            .parse()
+            .unwrap();
+        let command = Command::try_from(elem).unwrap();
+        assert_eq!(command.status, Some(Status::Completed));
+        assert_eq!(command.notes.len(), 2);
+        assert_eq!(command.notes[0].type_, NoteType::Info);
+        assert_eq!(
+            command.notes[0].text,
+            "Service 'httpd' has been configured."
+        );
+        assert_eq!(command.notes[1].type_, NoteType::Warn);
+    }
+
+    #[test]
+    fn test_serialise() {
+        let command = Command::new("config")
+            .with_session_id("config:20020923T213616Z-700")
+            .with_action(Action::Cancel);
+        let elem: Element = command.clone().into();
+        assert_eq!(elem.attr("action"), Some("cancel"));
+        assert_eq!(Command::try_from(elem).unwrap(), command);
+
+        let actions = Actions {
+            execute: Some(Action::Complete),
+            allowed: vec![Action::Prev, Action::Complete],
+        };
+        let elem: Element = actions.clone().into();
+        assert_eq!(elem.attr("execute"), Some("complete"));
+        assert!(elem.has_child("prev", ns::COMMANDS));
+        assert!(elem.has_child("complete", ns::COMMANDS));
+        assert_eq!(Actions::try_from(elem).unwrap(), actions);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let elem: Element = "<command xmlns='http://jabber.org/protocol/commands'/>"
+            .parse()
+            .unwrap();
+        let error = Command::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Required attribute 'node' missing.");
+
+        let elem: Element =
+            "<actions xmlns='http://jabber.org/protocol/commands'><cancel/></actions>"
+                .parse()
+                .unwrap();
+        let error = Actions::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Unknown child in actions element.");
+    }
+}
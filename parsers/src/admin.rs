@@ -0,0 +1,308 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The commands of this XEP are run with [ad-hoc commands](../adhoc/index.html):
+//! [`execute`] starts one at its node, the server answers with a form
+//! carrying a session id, and [`submit`] sends that form back filled,
+//! using one of the `*_form` functions here.  Commands such as
+//! [`GET_ONLINE_USERS_NUM`] complete right away, their result is then
+//! extracted from the answer with [`OnlineUsersCount`] or [`OnlineUsers`].
+
+use crate::adhoc::Command;
+use crate::data_forms::{DataForm, DataFormType, Field, FieldType};
+use crate::iq::Iq;
+use crate::ns;
+use crate::util::error::Error;
+use crate::{BareJid, Jid};
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+/// The node of the command adding a user.
+pub const ADD_USER: &str = "http://jabber.org/protocol/admin#add-user";
+
+/// The node of the command deleting users.
+pub const DELETE_USER: &str = "http://jabber.org/protocol/admin#delete-user";
+
+/// The node of the command ending all sessions of users.
+pub const END_USER_SESSION: &str = "http://jabber.org/protocol/admin#end-user-session";
+
+/// The node of the command counting the online users.
+pub const GET_ONLINE_USERS_NUM: &str = "http://jabber.org/protocol/admin#get-online-users-num";
+
+/// The node of the command listing the online users.
+pub const GET_ONLINE_USERS_LIST: &str = "http://jabber.org/protocol/admin#get-online-users-list";
+
+/// The iq starting the command at `node` on `server`.
+pub fn execute<I: Into<String>>(id: I, server: BareJid, node: &str) -> Iq {
+    Iq::from_set(id, Command::new(node)).with_to(Jid::Bare(server))
+}
+
+/// The iq sending `form` back to `server`, in the session of `stage`, the
+/// answer of the server to the previous iq.
+pub fn submit<I: Into<String>>(id: I, server: BareJid, stage: &Command, form: DataForm) -> Iq {
+    let mut command = Command::new(stage.node.clone()).with_form(form);
+    command.session_id = stage.session_id.clone();
+    Iq::from_set(id, command).with_to(Jid::Bare(server))
+}
+
+fn form(fields: Vec<Field>) -> DataForm {
+    DataForm::new(DataFormType::Submit, ns::ADMIN, fields)
+}
+
+fn jid_multi(var: &str, jids: &[BareJid]) -> Field {
+    let mut field = Field::new(var, FieldType::JidMulti);
+    field.values = jids.iter().map(|jid| jid.to_string()).collect();
+    field
+}
+
+/// The form of [`ADD_USER`], creating `jid` with `password`.
+pub fn add_user_form(jid: &BareJid, password: &str) -> DataForm {
+    form(vec![
+        Field::new("accountjid", FieldType::JidSingle).with_value(&jid.to_string()),
+        Field::new("password", FieldType::TextPrivate).with_value(password),
+        Field::new("password-verify", FieldType::TextPrivate).with_value(password),
+    ])
+}
+
+/// The form of [`DELETE_USER`], deleting the accounts of `jids`.
+pub fn delete_user_form(jids: &[BareJid]) -> DataForm {
+    form(vec![jid_multi("accountjids", jids)])
+}
+
+/// The form of [`END_USER_SESSION`], disconnecting every resource of
+/// `jids`.
+pub fn end_user_session_form(jids: &[BareJid]) -> DataForm {
+    form(vec![jid_multi("accountjids", jids)])
+}
+
+/// The form of [`GET_ONLINE_USERS_LIST`], listing at most `max` users, or
+/// all of them.
+pub fn get_online_users_list_form(max: Option<u32>) -> DataForm {
+    let max = match max {
+        Some(max) => max.to_string(),
+        None => String::from("none"),
+    };
+    form(vec![
+        Field::new("max_items", FieldType::ListSingle).with_value(&max)
+    ])
+}
+
+/// The values of the field `var` in the result form of `command`.
+fn result_values(command: Command, var: &str) -> Result<Vec<String>, Error> {
+    let form = command
+        .form
+        .ok_or(Error::ParseError("Missing result form in command."))?;
+    form.fields
+        .into_iter()
+        .find(|field| field.var == var)
+        .map(|field| field.values)
+        .ok_or(Error::ParseError("Missing field in admin result form."))
+}
+
+/// The result of [`GET_ONLINE_USERS_NUM`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OnlineUsersCount(pub u64);
+
+impl TryFrom<Command> for OnlineUsersCount {
+    type Error = Error;
+
+    fn try_from(command: Command) -> Result<OnlineUsersCount, Error> {
+        let values = result_values(command, "onlineusersnum")?;
+        match values.as_slice() {
+            [count] => Ok(OnlineUsersCount(count.trim().parse()?)),
+            _ => Err(Error::ParseError(
+                "Wrong number of values in onlineusersnum.",
+            )),
+        }
+    }
+}
+
+/// The result of [`GET_ONLINE_USERS_LIST`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnlineUsers(pub Vec<Jid>);
+
+impl TryFrom<Command> for OnlineUsers {
+    type Error = Error;
+
+    fn try_from(command: Command) -> Result<OnlineUsers, Error> {
+        let values = result_values(command, "onlineuserjids")?;
+        let jids = values
+            .iter()
+            .map(|jid| Jid::from_str(jid.trim()))
+            .collect::<Result<_, _>>()?;
+        Ok(OnlineUsers(jids))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adhoc::Status;
+    use crate::iq::IqType;
+    use crate::Element;
+
+    fn command(iq: &str) -> Command {
+        let iq = Iq::try_from(iq.parse::<Element>().unwrap()).unwrap();
+        match iq.payload {
+            IqType::Result(Some(payload)) => Command::try_from(payload).unwrap(),
+            payload => panic!("{:?}", payload),
+        }
+    }
+
+    fn server() -> BareJid {
+        BareJid::from_str("shakespeare.lit").unwrap()
+    }
+
+    #[test]
+    fn test_add_user() {
+        let iq: Element = execute("add-user-1", server(), ADD_USER).into();
+        let expected: Element = "<iq xmlns='jabber:client' id='add-user-1' to='shakespeare.lit' type='set'><command xmlns='http://jabber.org/protocol/commands' node='http://jabber.org/protocol/admin#add-user'/></iq>".parse().unwrap();
+        assert_eq!(iq, expected);
+
+        let stage = command("<iq xmlns='jabber:client' from='shakespeare.lit' id='add-user-1' to='bard@shakespeare.lit/globe' type='result' xml:lang='en'>
+            <command xmlns='http://jabber.org/protocol/commands' node='http://jabber.org/protocol/admin#add-user' sessionid='add-user:20040408T0337Z' status='executing'>
+                <actions execute='complete'><complete/></actions>
+                <x xmlns='jabber:x:data' type='form'>
+                    <title>Adding a User</title>
+                    <instructions>Fill out this form to add a user.</instructions>
+                    <field type='hidden' var='FORM_TYPE'><value>http://jabber.org/protocol/admin</value></field>
+                    <field label='The Jabber ID for the account to be added' type='jid-single' var='accountjid'><required/></field>
+                    <field label='The password for this account' type='text-private' var='password'/>
+                    <field label='Retype password' type='text-private' var='password-verify'/>
+                    <field label='Email address' type='text-single' var='email'/>
+                    <field label='Given name' type='text-single' var='given_name'/>
+                    <field label='Family name' type='text-single' var='surname'/>
+                </x>
+            </command>
+        </iq>");
+        assert_eq!(stage.status, Some(Status::Executing));
+
+        let jid = BareJid::from_str("juliet@shakespeare.lit").unwrap();
+        let iq: Element =
+            submit("add-user-2", server(), &stage, add_user_form(&jid, "R0m30")).into();
+        let expected: Element = "<iq xmlns='jabber:client' id='add-user-2' to='shakespeare.lit' type='set'><command xmlns='http://jabber.org/protocol/commands' node='http://jabber.org/protocol/admin#add-user' sessionid='add-user:20040408T0337Z'><x xmlns='jabber:x:data' type='submit'><field type='hidden' var='FORM_TYPE'><value>http://jabber.org/protocol/admin</value></field><field type='jid-single' var='accountjid'><value>juliet@shakespeare.lit</value></field><field type='text-private' var='password'><value>R0m30</value></field><field type='text-private' var='password-verify'><value>R0m30</value></field></x></command></iq>".parse().unwrap();
+        assert_eq!(iq, expected);
+
+        let done = command("<iq xmlns='jabber:client' from='shakespeare.lit' id='add-user-2' to='bard@shakespeare.lit/globe' type='result' xml:lang='en'>
+            <command xmlns='http://jabber.org/protocol/commands' node='http://jabber.org/protocol/admin#add-user' sessionid='add-user:20040408T0337Z' status='completed'/>
+        </iq>");
+        assert_eq!(done.status, Some(Status::Completed));
+    }
+
+    #[test]
+    fn test_delete_user() {
+        let stage = command("<iq xmlns='jabber:client' from='shakespeare.lit' id='delete-user-1' to='bard@shakespeare.lit/globe' type='result' xml:lang='en'>
+            <command xmlns='http://jabber.org/protocol/commands' node='http://jabber.org/protocol/admin#delete-user' sessionid='delete-user:20040408T0337Z' status='executing'>
+                <x xmlns='jabber:x:data' type='form'>
+                    <title>Deleting a User</title>
+                    <instructions>Fill out this form to delete a user.</instructions>
+                    <field type='hidden' var='FORM_TYPE'><value>http://jabber.org/protocol/admin</value></field>
+                    <field label='The Jabber ID(s) to delete' type='jid-multi' var='accountjids'><required/></field>
+                </x>
+            </command>
+        </iq>");
+
+        let jids = [
+            BareJid::from_str("juliet@shakespeare.lit").unwrap(),
+            BareJid::from_str("romeo@shakespeare.lit").unwrap(),
+        ];
+        let iq = submit("delete-user-2", server(), &stage, delete_user_form(&jids));
+        assert_eq!(iq.to, Some(Jid::from_str("shakespeare.lit").unwrap()));
+        let command = match iq.payload {
+            IqType::Set(payload) => Command::try_from(payload).unwrap(),
+            payload => panic!("{:?}", payload),
+        };
+        assert_eq!(command.node, DELETE_USER);
+        assert_eq!(
+            command.session_id.as_deref(),
+            Some("delete-user:20040408T0337Z")
+        );
+        let form = command.form.unwrap();
+        assert_eq!(form.type_, DataFormType::Submit);
+        assert_eq!(form.form_type.as_deref(), Some(ns::ADMIN));
+        assert_eq!(form.fields[0].var, "accountjids");
+        assert_eq!(
+            form.fields[0].values,
+            ["juliet@shakespeare.lit", "romeo@shakespeare.lit"]
+        );
+    }
+
+    #[test]
+    fn test_get_online_users() {
+        let done = command("<iq xmlns='jabber:client' from='shakespeare.lit' id='get-online-users-num-1' to='bard@shakespeare.lit/globe' type='result' xml:lang='en'>
+            <command xmlns='http://jabber.org/protocol/commands' node='http://jabber.org/protocol/admin#get-online-users-num' sessionid='get-online-users-num:20040408T0337Z' status='completed'>
+                <x xmlns='jabber:x:data' type='result'>
+                    <field type='hidden' var='FORM_TYPE'><value>http://jabber.org/protocol/admin</value></field>
+                    <field label='The number of online users' var='onlineusersnum'><value>75</value></field>
+                </x>
+            </command>
+        </iq>");
+        assert_eq!(
+            OnlineUsersCount::try_from(done).unwrap(),
+            OnlineUsersCount(75)
+        );
+
+        let stage = command("<iq xmlns='jabber:client' from='shakespeare.lit' id='get-online-users-list-1' to='bard@shakespeare.lit/globe' type='result' xml:lang='en'>
+            <command xmlns='http://jabber.org/protocol/commands' node='http://jabber.org/protocol/admin#get-online-users-list' sessionid='get-online-users-list:20040408T0337Z' status='executing'>
+                <x xmlns='jabber:x:data' type='form'>
+                    <field type='hidden' var='FORM_TYPE'><value>http://jabber.org/protocol/admin</value></field>
+                    <field label='Maximum number of items to show' type='list-single' var='max_items'>
+                        <option label='25'><value>25</value></option>
+                        <option label='None'><value>none</value></option>
+                    </field>
+                </x>
+            </command>
+        </iq>");
+        let iq = submit(
+            "get-online-users-list-2",
+            server(),
+            &stage,
+            get_online_users_list_form(Some(25)),
+        );
+        let command_ = match iq.payload {
+            IqType::Set(payload) => Command::try_from(payload).unwrap(),
+            payload => panic!("{:?}", payload),
+        };
+        assert_eq!(command_.form.unwrap().fields[0].values, ["25"]);
+
+        let done = command("<iq xmlns='jabber:client' from='shakespeare.lit' id='get-online-users-list-2' to='bard@shakespeare.lit/globe' type='result' xml:lang='en'>
+            <command xmlns='http://jabber.org/protocol/commands' node='http://jabber.org/protocol/admin#get-online-users-list' sessionid='get-online-users-list:20040408T0337Z' status='completed'>
+                <x xmlns='jabber:x:data' type='result'>
+                    <field type='hidden' var='FORM_TYPE'><value>http://jabber.org/protocol/admin</value></field>
+                    <field label='The list of all online users' var='onlineuserjids' type='jid-multi'>
+                        <value>juliet@shakespeare.lit</value>
+                        <value>romeo@montague.net</value>
+                    </field>
+                </x>
+            </command>
+        </iq>");
+        assert_eq!(
+            OnlineUsers::try_from(done).unwrap(),
+            OnlineUsers(vec![
+                Jid::from_str("juliet@shakespeare.lit").unwrap(),
+                Jid::from_str("romeo@montague.net").unwrap(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_invalid_result() {
+        let error = OnlineUsersCount::try_from(Command::new(GET_ONLINE_USERS_NUM)).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Missing result form in command.");
+
+        let command = Command::new(GET_ONLINE_USERS_NUM).with_form(form(vec![]));
+        let error = OnlineUsers::try_from(command).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Missing field in admin result form.");
+    }
+}
@@ -24,7 +24,7 @@ generate_element!(
     Info, "info", AVATAR_METADATA,
     attributes: [
         /// The size of the image data in bytes.
-        bytes: Required<u16> = "bytes",
+        bytes: Required<u32> = "bytes",
 
         /// The width of the image in pixels.
         width: Option<u16> = "width",
@@ -75,7 +75,7 @@ mod tests {
     #[test]
     fn test_size() {
         assert_size!(Metadata, 24);
-        assert_size!(Info, 120);
+        assert_size!(Info, 112);
         assert_size!(Data, 24);
     }
 
@@ -112,6 +112,26 @@ mod tests {
         assert_eq!(data.data, b"\0\0\0");
     }
 
+    #[test]
+    fn test_variants() {
+        let elem: Element = "<metadata xmlns='urn:xmpp:avatar:metadata'><info bytes='12345' width='64' height='64' id='111f4b3c50d7b0df729d299bc6f8e9ef9066971f' type='image/png'/><info bytes='1234567' width='1024' height='768' id='e279f80c38f99c1e7e53e262b440993b2f7eea57' type='image/jpeg' url='https://example.org/avatar.jpg'/></metadata>"
+            .parse()
+            .unwrap();
+        let metadata = Metadata::try_from(elem.clone()).unwrap();
+        assert_eq!(metadata.infos.len(), 2);
+        let info = &metadata.infos[1];
+        assert_eq!(info.bytes, 1234567);
+        assert_eq!(info.width, Some(1024));
+        assert_eq!(info.height, Some(768));
+        assert_eq!(info.type_, "image/jpeg");
+        assert_eq!(info.url.as_deref(), Some("https://example.org/avatar.jpg"));
+        assert_eq!(
+            &*info.id.to_hex(),
+            "e279f80c38f99c1e7e53e262b440993b2f7eea57"
+        );
+        assert_eq!(Element::from(metadata), elem);
+    }
+
     #[cfg(not(feature = "disable-validation"))]
     #[test]
     fn test_invalid() {
@@ -38,7 +38,7 @@ impl FromStr for ContentId {
                 let algo = match lhs {
                     "sha1" => Algo::Sha_1,
                     "sha256" => Algo::Sha_256,
-                    _ => unimplemented!(),
+                    _ => return Err(Error::ParseError("Unknown hash algorithm in cid URI.")),
                 };
                 (algo, rhs)
             }
@@ -157,6 +157,13 @@ mod tests {
         };
         assert_eq!(message, "Wrong domain for cid URI.");
 
+        let error = "md5+1234@bob.xmpp.org".parse::<ContentId>().unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Unknown hash algorithm in cid URI.");
+
         let error = "sha1+invalid@bob.xmpp.org"
             .parse::<ContentId>()
             .unwrap_err();
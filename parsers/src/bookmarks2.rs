@@ -4,6 +4,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 use crate::ns;
+use crate::pubsub::PubSubPayload;
 use crate::util::error::Error;
 use crate::Element;
 use std::convert::TryFrom;
@@ -41,6 +42,8 @@ impl Conference {
     }
 }
 
+impl PubSubPayload for Conference {}
+
 impl TryFrom<Element> for Conference {
     type Error = Error;
 
@@ -165,6 +168,22 @@ mod tests {
         assert!(conference.clone().extensions[0].is("test", "urn:xmpp:unknown"));
     }
 
+    #[test]
+    fn extensions_round_trip() {
+        let elem: Element = "<conference xmlns='urn:xmpp:bookmarks:1' autojoin='true'><extensions><state xmlns='http://myclient.example/bookmark/state' minimized='true'/><test xmlns='urn:xmpp:unknown'><nested>text</nested></test></extensions></conference>".parse().unwrap();
+        let elem1 = elem.clone();
+        let mut conference = Conference::try_from(elem).unwrap();
+        assert_eq!(conference.extensions.len(), 2);
+
+        conference.autojoin = Autojoin::False;
+        let elem2 = Element::from(conference);
+        assert_eq!(elem2.attr("autojoin"), None);
+        assert_eq!(
+            elem1.get_child("extensions", ns::BOOKMARKS2),
+            elem2.get_child("extensions", ns::BOOKMARKS2)
+        );
+    }
+
     #[test]
     fn wrapped() {
         let elem: Element = "<item xmlns='http://jabber.org/protocol/pubsub' id='test-muc@muc.localhost'><conference xmlns='urn:xmpp:bookmarks:1' autojoin='true' name='Test MUC'><nick>Coucou</nick><password>secret</password></conference></item>".parse().unwrap();
@@ -0,0 +1,245 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::iq::{IqGetPayload, IqResultPayload, IqSetPayload};
+use crate::util::helpers::JidCodec;
+use digest::Digest;
+use jid::Jid;
+use sha1::Sha1;
+
+pub use crate::jingle_s5b::{Mode, StreamId};
+
+generate_element!(
+    /// A host offering to relay the bytestream, either the initiator itself
+    /// or a proxy.
+    StreamHost, "streamhost", BYTESTREAMS,
+    attributes: [
+        /// The JID of this host.
+        jid: Required<Jid> = "jid",
+
+        /// Its IP address or host name.
+        host: Required<String> = "host",
+
+        /// The port on which it accepts SOCKS5 connections.
+        port: Required<u16> = "port",
+    ]
+);
+
+impl StreamHost {
+    /// Creates a new streamhost.
+    pub fn new<H: Into<String>>(jid: Jid, host: H, port: u16) -> StreamHost {
+        StreamHost {
+            jid,
+            host: host.into(),
+            port,
+        }
+    }
+}
+
+generate_element!(
+    /// The streamhost the target connected to.
+    StreamHostUsed, "streamhost-used", BYTESTREAMS,
+    attributes: [
+        /// The JID of that streamhost.
+        jid: Required<Jid> = "jid",
+    ]
+);
+
+generate_element!(
+    /// Asks a proxy to start relaying between the initiator and this target.
+    Activate, "activate", BYTESTREAMS,
+    text: (
+        /// The JID of the target.
+        jid: JidCodec<Jid>
+    )
+);
+
+generate_element!(
+    /// The payload of every SOCKS5 bytestream negotiation step: the streamhosts
+    /// offered by the initiator, the one the target used, the activation
+    /// request to a proxy, or the discovery of the network address of a proxy.
+    Query, "query", BYTESTREAMS,
+    attributes: [
+        /// The identifier of the bytestream, absent when discovering a proxy.
+        sid: Option<StreamId> = "sid",
+
+        /// Which transport to use.
+        mode: Default<Mode> = "mode",
+    ],
+    children: [
+        /// The streamhosts to try, by order of preference.
+        streamhosts: Vec<StreamHost> = ("streamhost", BYTESTREAMS) => StreamHost,
+
+        /// The streamhost the target connected to.
+        streamhost_used: Option<StreamHostUsed> = ("streamhost-used", BYTESTREAMS) => StreamHostUsed,
+
+        /// The target a proxy should relay the bytestream to.
+        activate: Option<Activate> = ("activate", BYTESTREAMS) => Activate
+    ]
+);
+
+impl IqGetPayload for Query {}
+impl IqSetPayload for Query {}
+impl IqResultPayload for Query {}
+
+impl Query {
+    /// Creates an empty query for the bytestream `sid`.
+    pub fn new(sid: StreamId) -> Query {
+        Query {
+            sid: Some(sid),
+            mode: Mode::Tcp,
+            streamhosts: Vec::new(),
+            streamhost_used: None,
+            activate: None,
+        }
+    }
+
+    /// Creates a query asking a proxy for its network address.
+    pub fn discover() -> Query {
+        Query {
+            sid: None,
+            mode: Mode::Tcp,
+            streamhosts: Vec::new(),
+            streamhost_used: None,
+            activate: None,
+        }
+    }
+
+    /// Offers these streamhosts to the target.
+    pub fn with_streamhosts(mut self, streamhosts: Vec<StreamHost>) -> Query {
+        self.streamhosts = streamhosts;
+        self
+    }
+
+    /// Tells the initiator which streamhost the target connected to.
+    pub fn with_streamhost_used(mut self, jid: Jid) -> Query {
+        self.streamhost_used = Some(StreamHostUsed { jid });
+        self
+    }
+
+    /// Asks a proxy to relay the bytestream to `target`.
+    pub fn with_activate(mut self, target: Jid) -> Query {
+        self.activate = Some(Activate { jid: target });
+        self
+    }
+}
+
+/// The destination address both sides send to the streamhost in the SOCKS5
+/// CONNECT request, as a domain name: the hex-encoded SHA-1 of the stream
+/// identifier, the JID of the initiator and that of the target.
+pub fn dstaddr(sid: &StreamId, requester: &Jid, target: &Jid) -> String {
+    let input = format!("{}{}{}", sid.0, requester, target);
+    format!("{:x}", Sha1::digest(input.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::error::Error;
+    use crate::Element;
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_initiation() {
+        // XEP-0065 example 12.
+        let elem: Element = "<query xmlns='http://jabber.org/protocol/bytestreams' sid='vxf9n471bn46' mode='tcp'><streamhost jid='requester@example.com/foo' host='192.168.4.1' port='5086'/><streamhost jid='streamer.example.com' host='24.24.24.1' port='7625'/></query>"
+            .parse()
+            .unwrap();
+        let query = Query::try_from(elem).unwrap();
+        assert_eq!(query.sid, Some(StreamId(String::from("vxf9n471bn46"))));
+        assert_eq!(query.mode, Mode::Tcp);
+        assert_eq!(query.streamhosts.len(), 2);
+        assert_eq!(
+            query.streamhosts[1],
+            StreamHost::new(
+                Jid::from_str("streamer.example.com").unwrap(),
+                "24.24.24.1",
+                7625
+            )
+        );
+        assert!(query.streamhost_used.is_none());
+        // TCP being the default mode, it doesn’t get serialised.
+        let elem: Element = "<query xmlns='http://jabber.org/protocol/bytestreams' sid='vxf9n471bn46'><streamhost jid='requester@example.com/foo' host='192.168.4.1' port='5086'/><streamhost jid='streamer.example.com' host='24.24.24.1' port='7625'/></query>"
+            .parse()
+            .unwrap();
+        assert_eq!(Element::from(query), elem);
+    }
+
+    #[test]
+    fn test_streamhost_used() {
+        // XEP-0065 example 18.
+        let elem: Element = "<query xmlns='http://jabber.org/protocol/bytestreams' sid='vxf9n471bn46'><streamhost-used jid='streamer.example.com'/></query>"
+            .parse()
+            .unwrap();
+        let query = Query::try_from(elem.clone()).unwrap();
+        let expected = Query::new(StreamId(String::from("vxf9n471bn46")))
+            .with_streamhost_used(Jid::from_str("streamer.example.com").unwrap());
+        assert_eq!(query, expected);
+        assert_eq!(Element::from(expected), elem);
+    }
+
+    #[test]
+    fn test_activate() {
+        // XEP-0065 example 24.
+        let elem: Element = "<query xmlns='http://jabber.org/protocol/bytestreams' sid='vxf9n471bn46'><activate>target@example.org/bar</activate></query>"
+            .parse()
+            .unwrap();
+        let query = Query::try_from(elem.clone()).unwrap();
+        let target = Jid::from_str("target@example.org/bar").unwrap();
+        assert_eq!(
+            query.activate.as_ref().map(|activate| &activate.jid),
+            Some(&target)
+        );
+        assert_eq!(
+            Element::from(Query::new(StreamId(String::from("vxf9n471bn46"))).with_activate(target)),
+            elem
+        );
+    }
+
+    #[test]
+    fn test_discovery() {
+        // XEP-0065 examples 7 and 8.
+        let elem: Element = "<query xmlns='http://jabber.org/protocol/bytestreams'/>"
+            .parse()
+            .unwrap();
+        assert_eq!(Query::try_from(elem.clone()).unwrap(), Query::discover());
+        assert_eq!(Element::from(Query::discover()), elem);
+
+        let elem: Element = "<query xmlns='http://jabber.org/protocol/bytestreams'><streamhost jid='streamer.example.com' host='24.24.24.1' port='7625'/></query>"
+            .parse()
+            .unwrap();
+        let query = Query::try_from(elem).unwrap();
+        assert_eq!(query.sid, None);
+        assert_eq!(query.streamhosts[0].port, 7625);
+    }
+
+    #[test]
+    fn test_invalid_streamhost() {
+        let elem: Element =
+            "<streamhost xmlns='http://jabber.org/protocol/bytestreams' jid='streamer.example.com' host='24.24.24.1'/>"
+                .parse()
+                .unwrap();
+        let error = StreamHost::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Required attribute 'port' missing.");
+    }
+
+    #[test]
+    fn test_dstaddr() {
+        // XEP-0065 §5.3.2, with the JIDs of the examples.
+        let dstaddr = dstaddr(
+            &StreamId(String::from("vxf9n471bn46")),
+            &Jid::from_str("requester@example.com/foo").unwrap(),
+            &Jid::from_str("target@example.org/bar").unwrap(),
+        );
+        assert_eq!(dstaddr, "98b8d688d0f5d895fd41c5e7309a2e9e33ba32ff");
+        assert_eq!(dstaddr.len(), 40);
+    }
+}
@@ -4,31 +4,384 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+//! The profiles of XEP-0082, used by every element carrying a timestamp.
+//!
+//! Parsing accepts the whole RFC 3339 grammar, while serialisation is canonical: a [`DateTime`]
+//! is always written in UTC with a `Z`, and with milliseconds only when it has a fraction of a
+//! second.  The legacy format of XEP-0082 §4 is only accepted by [`DateTime::parse_legacy`].
+//!
+//! With the `chrono` feature, these types convert to and from those of the chrono crate.
+
 use crate::util::error::Error;
-use chrono::{DateTime as ChronoDateTime, FixedOffset};
 use minidom::{IntoAttributeValue, Node};
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
 
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// An offset from UTC, the TZD of XEP-0082, either `Z` or `±hh:mm`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Offset {
+    minutes: i16,
+}
+
+impl Offset {
+    /// Coordinated Universal Time.
+    pub const UTC: Offset = Offset { minutes: 0 };
+
+    /// An offset of `minutes` east of UTC, which must be less than a day.
+    pub fn from_minutes(minutes: i16) -> Option<Offset> {
+        if minutes.abs() < 24 * 60 {
+            Some(Offset { minutes })
+        } else {
+            None
+        }
+    }
+
+    /// How many minutes east of UTC this offset is.
+    pub fn minutes(&self) -> i16 {
+        self.minutes
+    }
+}
+
+/// Always in the `±hh:mm` form, even for UTC.
+impl fmt::Display for Offset {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.minutes < 0 { '-' } else { '+' };
+        let minutes = self.minutes.abs();
+        write!(fmt, "{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+    }
+}
+
+impl FromStr for Offset {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Offset, Error> {
+        let mut reader = Reader::new(s);
+        let offset = reader.offset()?;
+        reader.end()?;
+        Ok(offset)
+    }
+}
+
+/// Implements the Date profile of XEP-0082, a calendar day in the `CCYY-MM-DD` form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    year: i16,
+    month: u8,
+    day: u8,
+}
+
+impl Date {
+    /// This day, if it exists and its year has four digits.
+    pub fn new(year: i32, month: u8, day: u8) -> Option<Date> {
+        if !(0..=9999).contains(&year) || !(1..=12).contains(&month) {
+            return None;
+        }
+        if day == 0 || day > days_in_month(year, month) {
+            return None;
+        }
+        Some(Date {
+            year: year as i16,
+            month,
+            day,
+        })
+    }
+
+    /// The year.
+    pub fn year(&self) -> i32 {
+        i32::from(self.year)
+    }
+
+    /// The month, from 1 to 12.
+    pub fn month(&self) -> u8 {
+        self.month
+    }
+
+    /// The day of the month, from 1.
+    pub fn day(&self) -> u8 {
+        self.day
+    }
+
+    fn days_since_epoch(&self) -> i64 {
+        // Counting from March, so that the leap day is the last one of the year.
+        let month = i64::from(self.month);
+        let year = i64::from(self.year) - if month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + i64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146_097 + day_of_era - 719_468
+    }
+
+    fn from_days_since_epoch(days: i64) -> Date {
+        let days = days + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        Date {
+            year: year as i16,
+            month: month as u8,
+            day: day as u8,
+        }
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+impl FromStr for Date {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Date, Error> {
+        let mut reader = Reader::new(s);
+        let date = reader.date()?;
+        reader.end()?;
+        Ok(date)
+    }
+}
+
+/// Implements the Time profile of XEP-0082, a time of day in the `hh:mm:ss[.sss][TZD]` form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Time {
+    hour: u8,
+    minute: u8,
+    second: u8,
+    nanosecond: u32,
+    offset: Option<Offset>,
+}
+
+impl Time {
+    /// This time of day, without any offset; `second` may be 60 for a leap second.
+    pub fn new(hour: u8, minute: u8, second: u8, nanosecond: u32) -> Option<Time> {
+        if hour > 23 || minute > 59 || second > 60 || nanosecond >= 1_000_000_000 {
+            return None;
+        }
+        Some(Time {
+            hour,
+            minute,
+            second,
+            nanosecond,
+            offset: None,
+        })
+    }
+
+    /// The same time of day, at this offset from UTC.
+    pub fn with_offset(self, offset: Offset) -> Time {
+        Time {
+            offset: Some(offset),
+            ..self
+        }
+    }
+
+    /// The hour, from 0 to 23.
+    pub fn hour(&self) -> u8 {
+        self.hour
+    }
+
+    /// The minute, from 0 to 59.
+    pub fn minute(&self) -> u8 {
+        self.minute
+    }
+
+    /// The second, from 0 to 60 for a leap second.
+    pub fn second(&self) -> u8 {
+        self.second
+    }
+
+    /// The fraction of a second, in nanoseconds.
+    pub fn nanosecond(&self) -> u32 {
+        self.nanosecond
+    }
+
+    /// The offset from UTC, if any.
+    pub fn offset(&self) -> Option<Offset> {
+        self.offset
+    }
+
+    fn fmt_without_offset(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{:02}:{:02}:{:02}",
+            self.hour, self.minute, self.second
+        )?;
+        let milliseconds = self.nanosecond / 1_000_000;
+        if milliseconds != 0 {
+            write!(fmt, ".{:03}", milliseconds)?;
+        }
+        Ok(())
+    }
+}
+
+/// Milliseconds are only written when there is a fraction of a second, and the offset `Z` is
+/// written `+00:00`.
+impl fmt::Display for Time {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_without_offset(fmt)?;
+        match self.offset {
+            Some(offset) => write!(fmt, "{}", offset),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromStr for Time {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Time, Error> {
+        let mut reader = Reader::new(s);
+        let mut time = reader.time()?;
+        if !reader.is_empty() {
+            time.offset = Some(reader.offset()?);
+        }
+        reader.end()?;
+        Ok(time)
+    }
+}
+
 /// Implements the DateTime profile of XEP-0082, which represents a
 /// non-recurring moment in time, with an accuracy of seconds or fraction of
 /// seconds, and includes a timezone.
-#[derive(Debug, Clone, PartialEq)]
-pub struct DateTime(pub ChronoDateTime<FixedOffset>);
+///
+/// Two `DateTime`s are equal when they are the same moment, whatever their offsets.
+#[derive(Debug, Clone, Copy)]
+pub struct DateTime {
+    date: Date,
+    /// Always with an offset.
+    time: Time,
+}
 
 impl DateTime {
-    /// Retrieves the associated timezone.
-    pub fn timezone(&self) -> FixedOffset {
-        self.0.timezone()
+    /// The moment at `time` on `date`, which has to have an offset.
+    pub fn new(date: Date, time: Time) -> Result<DateTime, Error> {
+        if time.offset.is_none() {
+            return Err(Error::ParseError("Missing timezone in date-time."));
+        }
+        let date_time = DateTime { date, time };
+        // Serialising it converts it to UTC first.
+        if !(0..=9999).contains(&date_time.to_utc().date.year) {
+            return Err(Error::ParseError("Date-time out of range once in UTC."));
+        }
+        Ok(date_time)
+    }
+
+    /// Parses the legacy `CCYYMMDDThh:mm:ss` format of XEP-0082 §4, always in UTC, which was
+    /// notably used by XEP-0091.
+    pub fn parse_legacy(s: &str) -> Result<DateTime, Error> {
+        let mut reader = Reader::new(s);
+        let year = reader.digits(4)?;
+        let month = reader.digits(2)?;
+        let day = reader.digits(2)?;
+        let date = Date::new(year as i32, month as u8, day as u8)
+            .ok_or(Error::ParseError("Invalid date."))?;
+        reader.separator(b'T')?;
+        let time = reader.time()?;
+        if time.nanosecond != 0 {
+            return Err(Error::ParseError("Invalid time."));
+        }
+        reader.end()?;
+        DateTime::new(date, time.with_offset(Offset::UTC))
+    }
+
+    /// The day, at the offset of this `DateTime`.
+    pub fn date(&self) -> Date {
+        self.date
+    }
+
+    /// The time of day, at the offset of this `DateTime`.
+    pub fn time(&self) -> Time {
+        self.time
+    }
+
+    /// The offset from UTC.
+    pub fn offset(&self) -> Offset {
+        self.time.offset.unwrap_or(Offset::UTC)
+    }
+
+    /// The same moment, at another offset from UTC.
+    pub fn with_offset(&self, offset: Offset) -> DateTime {
+        let (seconds, _) = self.timestamp();
+        let leap = self.time.second == 60;
+        let local = seconds + i64::from(offset.minutes) * 60 - if leap { 1 } else { 0 };
+        let day_seconds = local.rem_euclid(SECONDS_PER_DAY);
+        let time = Time {
+            hour: (day_seconds / 3600) as u8,
+            minute: (day_seconds / 60 % 60) as u8,
+            second: if leap { 60 } else { (day_seconds % 60) as u8 },
+            nanosecond: self.time.nanosecond,
+            offset: Some(offset),
+        };
+        DateTime {
+            date: Date::from_days_since_epoch(local.div_euclid(SECONDS_PER_DAY)),
+            time,
+        }
+    }
+
+    /// The same moment, in UTC.
+    pub fn to_utc(&self) -> DateTime {
+        self.with_offset(Offset::UTC)
+    }
+
+    /// Seconds since the Unix epoch, and nanoseconds.
+    pub fn timestamp(&self) -> (i64, u32) {
+        let time = &self.time;
+        let seconds = self.date.days_since_epoch() * SECONDS_PER_DAY
+            + i64::from(time.hour) * 3600
+            + i64::from(time.minute) * 60
+            + i64::from(time.second)
+            - i64::from(self.offset().minutes) * 60;
+        (seconds, time.nanosecond)
     }
+}
+
+impl PartialEq for DateTime {
+    fn eq(&self, other: &DateTime) -> bool {
+        self.timestamp() == other.timestamp()
+    }
+}
+
+impl Eq for DateTime {}
+
+impl PartialOrd for DateTime {
+    fn partial_cmp(&self, other: &DateTime) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DateTime {
+    fn cmp(&self, other: &DateTime) -> Ordering {
+        self.timestamp().cmp(&other.timestamp())
+    }
+}
 
-    /// Returns a new `DateTime` with a different timezone.
-    pub fn with_timezone(&self, tz: FixedOffset) -> DateTime {
-        DateTime(self.0.with_timezone(&tz))
+impl Hash for DateTime {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.timestamp().hash(state);
     }
+}
 
-    /// Formats this `DateTime` with the specified format string.
-    pub fn format(&self, fmt: &str) -> String {
-        format!("{}", self.0.format(fmt))
+/// Always in UTC, the canonical form.
+impl fmt::Display for DateTime {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let utc = self.to_utc();
+        write!(fmt, "{}T", utc.date)?;
+        utc.time.fmt_without_offset(fmt)?;
+        write!(fmt, "Z")
     }
 }
 
@@ -36,102 +389,451 @@ impl FromStr for DateTime {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<DateTime, Error> {
-        Ok(DateTime(ChronoDateTime::parse_from_rfc3339(s)?))
+        let mut reader = Reader::new(s);
+        let date = reader.date()?;
+        reader.separator(b'T')?;
+        let time = reader.time()?;
+        let offset = reader.offset()?;
+        reader.end()?;
+        DateTime::new(date, time.with_offset(offset))
     }
 }
 
-impl IntoAttributeValue for DateTime {
-    fn into_attribute_value(self) -> Option<String> {
-        Some(self.0.to_rfc3339())
+macro_rules! impl_text {
+    ($type:ty) => {
+        impl IntoAttributeValue for $type {
+            fn into_attribute_value(self) -> Option<String> {
+                Some(self.to_string())
+            }
+        }
+
+        impl From<$type> for Node {
+            fn from(value: $type) -> Node {
+                Node::Text(value.to_string())
+            }
+        }
+    };
+}
+
+impl_text!(Date);
+impl_text!(Time);
+impl_text!(DateTime);
+
+fn is_leap_year(year: i32) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i32, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
     }
 }
 
-impl From<DateTime> for Node {
-    fn from(date: DateTime) -> Node {
-        Node::Text(date.0.to_rfc3339())
+/// Reads the productions of RFC 3339 one after the other.
+struct Reader<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(input: &'a str) -> Reader<'a> {
+        Reader {
+            input: input.as_bytes(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.input.is_empty()
+    }
+
+    fn end(&self) -> Result<(), Error> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::ParseError("Trailing characters after date or time."))
+        }
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let (first, rest) = self.input.split_first()?;
+        self.input = rest;
+        Some(*first)
+    }
+
+    /// Exactly `count` digits.
+    fn digits(&mut self, count: usize) -> Result<u32, Error> {
+        let mut value = 0;
+        for _ in 0..count {
+            match self.next() {
+                Some(digit @ b'0'..=b'9') => value = value * 10 + u32::from(digit - b'0'),
+                _ => return Err(Error::ParseError("Expected a digit in date or time.")),
+            }
+        }
+        Ok(value)
+    }
+
+    /// `separator` or its lower case, like RFC 3339 allows for `T` and `Z`.
+    fn separator(&mut self, separator: u8) -> Result<(), Error> {
+        match self.next() {
+            Some(byte) if byte.to_ascii_uppercase() == separator => Ok(()),
+            _ => Err(Error::ParseError("Missing separator in date or time.")),
+        }
+    }
+
+    fn date(&mut self) -> Result<Date, Error> {
+        let year = self.digits(4)?;
+        self.separator(b'-')?;
+        let month = self.digits(2)?;
+        self.separator(b'-')?;
+        let day = self.digits(2)?;
+        Date::new(year as i32, month as u8, day as u8).ok_or(Error::ParseError("Invalid date."))
+    }
+
+    fn time(&mut self) -> Result<Time, Error> {
+        let hour = self.digits(2)?;
+        self.separator(b':')?;
+        let minute = self.digits(2)?;
+        self.separator(b':')?;
+        let second = self.digits(2)?;
+        let mut nanosecond = 0;
+        if self.input.first() == Some(&b'.') {
+            self.next();
+            let count = self
+                .input
+                .iter()
+                .take_while(|byte| byte.is_ascii_digit())
+                .count();
+            if count == 0 {
+                return Err(Error::ParseError("Expected a digit in date or time."));
+            }
+            // Anything below the nanosecond gets truncated.
+            let mut scale = 100_000_000;
+            for _ in 0..count {
+                let digit = self.digits(1)?;
+                nanosecond += digit * scale;
+                scale /= 10;
+            }
+        }
+        Time::new(hour as u8, minute as u8, second as u8, nanosecond)
+            .ok_or(Error::ParseError("Invalid time."))
+    }
+
+    fn offset(&mut self) -> Result<Offset, Error> {
+        let sign = match self.next() {
+            Some(b'Z') | Some(b'z') => return Ok(Offset::UTC),
+            Some(b'+') => 1,
+            Some(b'-') => -1,
+            _ => return Err(Error::ParseError("Missing timezone in date or time.")),
+        };
+        let hours = self.digits(2)?;
+        self.separator(b':')?;
+        let minutes = self.digits(2)?;
+        if hours > 23 || minutes > 59 {
+            return Err(Error::ParseError("Invalid timezone."));
+        }
+        Ok(Offset {
+            minutes: sign * (hours * 60 + minutes) as i16,
+        })
+    }
+}
+
+#[cfg(feature = "chrono")]
+mod chrono_conversions {
+    use super::*;
+    use chrono::{Datelike, FixedOffset, NaiveDate, TimeZone, Timelike};
+    use std::convert::TryFrom;
+
+    impl From<Date> for NaiveDate {
+        fn from(date: Date) -> NaiveDate {
+            NaiveDate::from_ymd_opt(date.year(), date.month.into(), date.day.into())
+                .expect("every Date exists")
+        }
+    }
+
+    impl TryFrom<NaiveDate> for Date {
+        type Error = Error;
+
+        fn try_from(date: NaiveDate) -> Result<Date, Error> {
+            Date::new(date.year(), date.month() as u8, date.day() as u8)
+                .ok_or(Error::ParseError("Date out of range."))
+        }
+    }
+
+    impl From<DateTime> for chrono::DateTime<FixedOffset> {
+        fn from(date_time: DateTime) -> chrono::DateTime<FixedOffset> {
+            let (seconds, nanoseconds) = date_time.timestamp();
+            let offset = FixedOffset::east_opt(i32::from(date_time.offset().minutes) * 60)
+                .expect("offsets are less than a day");
+            // chrono represents a leap second with the nanoseconds of the previous second.
+            let (seconds, nanoseconds) = if date_time.time.second == 60 {
+                (seconds - 1, nanoseconds + 1_000_000_000)
+            } else {
+                (seconds, nanoseconds)
+            };
+            offset
+                .timestamp_opt(seconds, nanoseconds)
+                .single()
+                .expect("every DateTime exists")
+        }
+    }
+
+    impl TryFrom<chrono::DateTime<FixedOffset>> for DateTime {
+        type Error = Error;
+
+        fn try_from(date_time: chrono::DateTime<FixedOffset>) -> Result<DateTime, Error> {
+            let date = Date::try_from(date_time.date_naive())?;
+            let (second, nanosecond) = match date_time.nanosecond() {
+                nanosecond if nanosecond >= 1_000_000_000 => (60, nanosecond - 1_000_000_000),
+                nanosecond => (date_time.second() as u8, nanosecond),
+            };
+            let time = Time::new(
+                date_time.hour() as u8,
+                date_time.minute() as u8,
+                second,
+                nanosecond,
+            )
+            .ok_or(Error::ParseError("Invalid time."))?;
+            let offset = date_time.offset().local_minus_utc() / 60;
+            DateTime::new(
+                date,
+                time.with_offset(Offset::from_minutes(offset as i16).unwrap()),
+            )
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use chrono::{Datelike, Timelike};
 
     // DateTime’s size doesn’t depend on the architecture.
     #[test]
     fn test_size() {
+        assert_size!(Offset, 2);
+        assert_size!(Date, 4);
+        assert_size!(Time, 12);
         assert_size!(DateTime, 16);
     }
 
+    fn parse_error<T: FromStr<Err = Error> + fmt::Debug>(s: &str) -> &'static str {
+        match T::from_str(s) {
+            Err(Error::ParseError(message)) => message,
+            result => panic!("unexpected result for {}: {:?}", s, result),
+        }
+    }
+
     #[test]
     fn test_simple() {
         let date: DateTime = "2002-09-10T23:08:25Z".parse().unwrap();
-        assert_eq!(date.0.year(), 2002);
-        assert_eq!(date.0.month(), 9);
-        assert_eq!(date.0.day(), 10);
-        assert_eq!(date.0.hour(), 23);
-        assert_eq!(date.0.minute(), 08);
-        assert_eq!(date.0.second(), 25);
-        assert_eq!(date.0.nanosecond(), 0);
-        assert_eq!(date.0.timezone(), FixedOffset::east(0));
+        assert_eq!(date.date(), Date::new(2002, 9, 10).unwrap());
+        let time = date.time();
+        assert_eq!(time.hour(), 23);
+        assert_eq!(time.minute(), 8);
+        assert_eq!(time.second(), 25);
+        assert_eq!(time.nanosecond(), 0);
+        assert_eq!(date.offset(), Offset::UTC);
+        assert_eq!(date.timestamp(), (1_031_699_305, 0));
+    }
+
+    #[test]
+    fn test_xep_0082_examples() {
+        // Date
+        let date = Date::from_str("1776-07-04").unwrap();
+        assert_eq!((date.year(), date.month(), date.day()), (1776, 7, 4));
+        assert_eq!(date.to_string(), "1776-07-04");
+
+        // DateTime
+        let date_time = DateTime::from_str("1969-07-21T02:56:15Z").unwrap();
+        assert_eq!(date_time.to_string(), "1969-07-21T02:56:15Z");
+        assert_eq!(date_time.timestamp(), (-14_159_025, 0));
+        let houston = DateTime::from_str("1969-07-20T21:56:15-05:00").unwrap();
+        assert_eq!(houston, date_time);
+        assert_eq!(houston.to_string(), "1969-07-21T02:56:15Z");
+        assert_eq!(houston.date(), Date::new(1969, 7, 20).unwrap());
+
+        // Time
+        let time = Time::from_str("16:00:00").unwrap();
+        assert_eq!(time.offset(), None);
+        assert_eq!(time.to_string(), "16:00:00");
+        let time = Time::from_str("16:00:00Z").unwrap();
+        assert_eq!(time.offset(), Some(Offset::UTC));
+        assert_eq!(time.to_string(), "16:00:00+00:00");
+        let time = Time::from_str("16:00:00.123-06:00").unwrap();
+        assert_eq!(time.nanosecond(), 123_000_000);
+        assert_eq!(time.offset(), Offset::from_minutes(-360));
+        assert_eq!(time.to_string(), "16:00:00.123-06:00");
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        // Lower case separators, and any precision.
+        let date_time = DateTime::from_str("1985-04-12t23:20:50.52z").unwrap();
+        assert_eq!(date_time.time().nanosecond(), 520_000_000);
+        assert_eq!(date_time.to_string(), "1985-04-12T23:20:50.520Z");
+        let date_time = DateTime::from_str("1996-12-19T16:39:57.123456789123-08:00").unwrap();
+        assert_eq!(date_time.time().nanosecond(), 123_456_789);
+        assert_eq!(date_time.to_string(), "1996-12-20T00:39:57.123Z");
+
+        // Leap seconds.
+        let date_time = DateTime::from_str("1990-12-31T15:59:60-08:00").unwrap();
+        assert_eq!(date_time.to_string(), "1990-12-31T23:59:60Z");
+        assert_eq!(date_time.to_utc().time().second(), 60);
+
+        // Crossing a leap day.
+        let date_time = DateTime::from_str("2000-03-01T01:00:00+02:00").unwrap();
+        assert_eq!(date_time.to_string(), "2000-02-29T23:00:00Z");
+        let offset = Offset::from_minutes(5 * 60 + 30).unwrap();
+        assert_eq!(
+            date_time.with_offset(offset).to_utc().date(),
+            date_time.to_utc().date()
+        );
+        assert_eq!(
+            date_time.with_offset(offset).date(),
+            Date::new(2000, 3, 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_legacy() {
+        let date_time = DateTime::parse_legacy("20020910T23:08:25").unwrap();
+        assert_eq!(
+            date_time,
+            DateTime::from_str("2002-09-10T23:08:25Z").unwrap()
+        );
+
+        // Only when asked for.
+        assert_eq!(
+            parse_error::<DateTime>("20020910T23:08:25"),
+            "Missing separator in date or time."
+        );
+        assert!(DateTime::parse_legacy("2002-09-10T23:08:25Z").is_err());
+        assert!(DateTime::parse_legacy("20020910T23:08:25Z").is_err());
     }
 
     #[test]
     fn test_invalid_date() {
         // There is no thirteenth month.
-        let error = DateTime::from_str("2017-13-01T12:23:34Z").unwrap_err();
-        let message = match error {
-            Error::ChronoParseError(string) => string,
-            _ => panic!(),
-        };
-        assert_eq!(message.to_string(), "input is out of range");
+        assert_eq!(
+            parse_error::<DateTime>("2017-13-01T12:23:34Z"),
+            "Invalid date."
+        );
+
+        // Nor a 29th of February outside of leap years.
+        assert_eq!(
+            parse_error::<DateTime>("1900-02-29T12:23:34Z"),
+            "Invalid date."
+        );
+        assert!(DateTime::from_str("2000-02-29T12:23:34Z").is_ok());
+
+        // Nor a 25th hour.
+        assert_eq!(
+            parse_error::<DateTime>("2017-05-27T24:11:02Z"),
+            "Invalid time."
+        );
 
         // Timezone ≥24:00 aren’t allowed.
-        let error = DateTime::from_str("2017-05-27T12:11:02+25:00").unwrap_err();
-        let message = match error {
-            Error::ChronoParseError(string) => string,
-            _ => panic!(),
-        };
-        assert_eq!(message.to_string(), "input is out of range");
+        assert_eq!(
+            parse_error::<DateTime>("2017-05-27T12:11:02+25:00"),
+            "Invalid timezone."
+        );
 
         // Timezone without the : separator aren’t allowed.
-        let error = DateTime::from_str("2017-05-27T12:11:02+0100").unwrap_err();
-        let message = match error {
-            Error::ChronoParseError(string) => string,
-            _ => panic!(),
-        };
-        assert_eq!(message.to_string(), "input contains invalid characters");
+        assert_eq!(
+            parse_error::<DateTime>("2017-05-27T12:11:02+0100"),
+            "Missing separator in date or time."
+        );
 
-        // No seconds, error message could be improved.
-        let error = DateTime::from_str("2017-05-27T12:11+01:00").unwrap_err();
-        let message = match error {
-            Error::ChronoParseError(string) => string,
-            _ => panic!(),
-        };
-        assert_eq!(message.to_string(), "input contains invalid characters");
+        // No seconds.
+        assert_eq!(
+            parse_error::<DateTime>("2017-05-27T12:11+01:00"),
+            "Missing separator in date or time."
+        );
 
-        // TODO: maybe we’ll want to support this one, as per XEP-0082 §4.
-        let error = DateTime::from_str("20170527T12:11:02+01:00").unwrap_err();
-        let message = match error {
-            Error::ChronoParseError(string) => string,
-            _ => panic!(),
-        };
-        assert_eq!(message.to_string(), "input contains invalid characters");
+        // A fraction needs digits.
+        assert_eq!(
+            parse_error::<DateTime>("2017-05-27T12:11:02.Z"),
+            "Expected a digit in date or time."
+        );
 
         // No timezone.
-        let error = DateTime::from_str("2017-05-27T12:11:02").unwrap_err();
-        let message = match error {
-            Error::ChronoParseError(string) => string,
-            _ => panic!(),
-        };
-        assert_eq!(message.to_string(), "premature end of input");
+        assert_eq!(
+            parse_error::<DateTime>("2017-05-27T12:11:02"),
+            "Missing timezone in date or time."
+        );
+
+        // Only four digits years.
+        assert_eq!(
+            parse_error::<DateTime>("12017-05-27T12:11:02Z"),
+            "Missing separator in date or time."
+        );
+        assert_eq!(
+            parse_error::<DateTime>("0000-01-01T00:30:00+01:00"),
+            "Date-time out of range once in UTC."
+        );
+
+        // Nothing else.
+        assert_eq!(
+            parse_error::<DateTime>("2017-05-27T12:11:02Z "),
+            "Trailing characters after date or time."
+        );
+        assert_eq!(
+            parse_error::<Date>("2017-05-27Z"),
+            "Trailing characters after date or time."
+        );
+        assert_eq!(
+            parse_error::<Offset>("+01"),
+            "Missing separator in date or time."
+        );
+        assert_eq!(
+            parse_error::<DateTime>(""),
+            "Expected a digit in date or time."
+        );
     }
 
     #[test]
     fn test_serialise() {
-        let date =
-            DateTime(ChronoDateTime::parse_from_rfc3339("2017-05-21T20:19:55+01:00").unwrap());
+        let date = DateTime::from_str("2017-05-21T20:19:55+01:00").unwrap();
         let attr = date.into_attribute_value();
-        assert_eq!(attr, Some(String::from("2017-05-21T20:19:55+01:00")));
+        assert_eq!(attr, Some(String::from("2017-05-21T19:19:55Z")));
+        assert_eq!(Offset::UTC.to_string(), "+00:00");
+        assert_eq!(Offset::from_minutes(-90).unwrap().to_string(), "-01:30");
+        assert_eq!(Offset::from_str("Z").unwrap(), Offset::UTC);
+        assert_eq!(Offset::from_minutes(24 * 60), None);
+    }
+
+    #[test]
+    fn test_ordering() {
+        let early = DateTime::from_str("2021-06-01T12:00:00+02:00").unwrap();
+        let late = DateTime::from_str("2021-06-01T11:00:00Z").unwrap();
+        assert!(early < late);
+        assert_eq!(early.max(late), late);
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_chrono() {
+        use chrono::{FixedOffset, NaiveDate};
+        use std::convert::TryFrom;
+
+        let date_time = DateTime::from_str("1996-12-19T16:39:57.5-08:00").unwrap();
+        let converted = chrono::DateTime::<FixedOffset>::from(date_time);
+        assert_eq!(converted.to_rfc3339(), "1996-12-19T16:39:57.500-08:00");
+        let back = DateTime::try_from(converted).unwrap();
+        assert_eq!(back, date_time);
+        assert_eq!(back.offset(), date_time.offset());
+
+        let leap = DateTime::from_str("1990-12-31T23:59:60Z").unwrap();
+        let converted = chrono::DateTime::<FixedOffset>::from(leap);
+        assert_eq!(converted.to_rfc3339(), "1990-12-31T23:59:60+00:00");
+        assert_eq!(DateTime::try_from(converted).unwrap(), leap);
+
+        let date = Date::new(2000, 2, 29).unwrap();
+        assert_eq!(
+            NaiveDate::from(date),
+            NaiveDate::from_ymd_opt(2000, 2, 29).unwrap()
+        );
+        assert!(Date::try_from(NaiveDate::from_ymd_opt(10000, 1, 1).unwrap()).is_err());
     }
 }
@@ -93,7 +93,7 @@ mod tests {
 
     #[test]
     fn test_serialise() {
-        let elem: Element = "<delay xmlns='urn:xmpp:delay' stamp='2002-09-10T23:08:25+00:00'/>"
+        let elem: Element = "<delay xmlns='urn:xmpp:delay' stamp='2002-09-10T23:08:25Z'/>"
             .parse()
             .unwrap();
         let delay = Delay {
@@ -107,7 +107,7 @@ mod tests {
 
     #[test]
     fn test_serialise_data() {
-        let elem: Element = "<delay xmlns='urn:xmpp:delay' from='juliet@example.org' stamp='2002-09-10T23:08:25+00:00'>Reason</delay>".parse().unwrap();
+        let elem: Element = "<delay xmlns='urn:xmpp:delay' from='juliet@example.org' stamp='2002-09-10T23:08:25Z'>Reason</delay>".parse().unwrap();
         let delay = Delay {
             from: Some(Jid::Bare(BareJid::new("juliet", "example.org"))),
             stamp: DateTime::from_str("2002-09-10T23:08:25Z").unwrap(),
@@ -116,4 +116,19 @@ mod tests {
         let elem2 = delay.into();
         assert_eq!(elem, elem2);
     }
+
+    #[test]
+    fn test_roundtrip() {
+        // Stamps are sent back in UTC, with milliseconds if any.
+        let elem: Element = "<delay xmlns='urn:xmpp:delay' stamp='2002-09-10T18:08:25.5-05:00'/>"
+            .parse()
+            .unwrap();
+        let delay = Delay::try_from(elem).unwrap();
+        let elem: Element = delay.clone().into();
+        let expected: Element = "<delay xmlns='urn:xmpp:delay' stamp='2002-09-10T23:08:25.500Z'/>"
+            .parse()
+            .unwrap();
+        assert_eq!(elem, expected);
+        assert_eq!(Delay::try_from(elem).unwrap(), delay);
+    }
 }
@@ -0,0 +1,128 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::message::MessagePayload;
+use jid::BareJid;
+
+generate_attribute!(
+    /// Whether the invitation continues a one-to-one discussion.
+    Continue,
+    "continue",
+    bool
+);
+
+generate_element!(
+    /// An invitation to join a room, sent directly to the invitee instead
+    /// of through the room.
+    Invitation, "x", DIRECT_MUC,
+    attributes: [
+        /// The room to join.
+        jid: Required<BareJid> = "jid",
+
+        /// The password needed to join the room.
+        password: Option<String> = "password",
+
+        /// Why the invitee should join.
+        reason: Option<String> = "reason",
+
+        /// Whether this continues a one-to-one discussion.
+        continue_: Default<Continue> = "continue",
+
+        /// The thread being continued in the room.
+        thread: Option<String> = "thread",
+    ]
+);
+
+impl MessagePayload for Invitation {}
+
+impl Invitation {
+    /// Invite to `room`, with no password nor reason.
+    pub fn new(room: BareJid) -> Invitation {
+        Invitation {
+            jid: room,
+            password: None,
+            reason: None,
+            continue_: Continue::False,
+            thread: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::error::Error;
+    use crate::Element;
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(Continue, 1);
+        assert_size!(Invitation, 64);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(Continue, 1);
+        assert_size!(Invitation, 128);
+    }
+
+    #[test]
+    fn test_simple() {
+        let elem: Element =
+            "<x xmlns='jabber:x:conference' jid='darkcave@macbeth.shakespeare.lit'/>"
+                .parse()
+                .unwrap();
+        let elem1 = elem.clone();
+        let invitation = Invitation::try_from(elem).unwrap();
+        assert_eq!(
+            invitation.jid,
+            BareJid::from_str("darkcave@macbeth.shakespeare.lit").unwrap()
+        );
+        assert_eq!(invitation.password, None);
+        assert_eq!(invitation.continue_, Continue::False);
+
+        let elem2 = Element::from(invitation);
+        assert_eq!(elem1, elem2);
+    }
+
+    #[test]
+    fn test_full() {
+        let elem: Element = "<x xmlns='jabber:x:conference'
+                jid='darkcave@macbeth.shakespeare.lit'
+                password='cauldronburn'
+                reason='Hey Hecate, this is the place for all good witches!'
+                continue='true'
+                thread='e0ffe42b28561960c6b12b944a092794b9683a38'/>"
+            .parse()
+            .unwrap();
+        let invitation = Invitation::try_from(elem).unwrap();
+        assert_eq!(invitation.password.as_deref(), Some("cauldronburn"));
+        assert_eq!(
+            invitation.reason.as_deref(),
+            Some("Hey Hecate, this is the place for all good witches!")
+        );
+        assert_eq!(invitation.continue_, Continue::True);
+        assert_eq!(
+            invitation.thread.as_deref(),
+            Some("e0ffe42b28561960c6b12b944a092794b9683a38")
+        );
+    }
+
+    #[test]
+    fn test_missing_jid() {
+        let elem: Element = "<x xmlns='jabber:x:conference'/>".parse().unwrap();
+        let error = Invitation::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Required attribute 'jid' missing.");
+    }
+}
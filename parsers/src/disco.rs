@@ -110,6 +110,10 @@ pub struct DiscoInfoResult {
 
     /// List of extensions reported by this entity.
     pub extensions: Vec<DataForm>,
+
+    /// Any other child, such as an extension of another protocol, kept so that forwarding this
+    /// result doesn’t lose it.
+    pub unknown: Vec<Element>,
 }
 
 impl IqResultPayload for DiscoInfoResult {}
@@ -126,6 +130,7 @@ impl TryFrom<Element> for DiscoInfoResult {
             identities: vec![],
             features: vec![],
             extensions: vec![],
+            unknown: vec![],
         };
 
         for child in elem.children() {
@@ -147,7 +152,7 @@ impl TryFrom<Element> for DiscoInfoResult {
                 }
                 result.extensions.push(data_form);
             } else {
-                return Err(Error::ParseError("Unknown element in disco#info."));
+                result.unknown.push(child.clone());
             }
         }
 
@@ -180,6 +185,7 @@ impl From<DiscoInfoResult> for Element {
             .append_all(disco.identities.into_iter())
             .append_all(disco.features.into_iter())
             .append_all(disco.extensions.iter().cloned().map(Element::from))
+            .append_all(disco.unknown)
             .build()
     }
 }
@@ -239,7 +245,7 @@ mod tests {
         assert_size!(Identity, 48);
         assert_size!(Feature, 12);
         assert_size!(DiscoInfoQuery, 12);
-        assert_size!(DiscoInfoResult, 48);
+        assert_size!(DiscoInfoResult, 60);
 
         assert_size!(Item, 64);
         assert_size!(DiscoItemsQuery, 12);
@@ -252,7 +258,7 @@ mod tests {
         assert_size!(Identity, 96);
         assert_size!(Feature, 24);
         assert_size!(DiscoInfoQuery, 24);
-        assert_size!(DiscoInfoResult, 96);
+        assert_size!(DiscoInfoResult, 120);
 
         assert_size!(Item, 128);
         assert_size!(DiscoItemsQuery, 24);
@@ -303,17 +309,14 @@ mod tests {
     }
 
     #[test]
-    fn test_invalid() {
-        let elem: Element =
-            "<query xmlns='http://jabber.org/protocol/disco#info'><coucou/></query>"
-                .parse()
-                .unwrap();
-        let error = DiscoInfoResult::try_from(elem).unwrap_err();
-        let message = match error {
-            Error::ParseError(string) => string,
-            _ => panic!(),
-        };
-        assert_eq!(message, "Unknown element in disco#info.");
+    fn test_unknown_children() {
+        let elem: Element = "<query xmlns='http://jabber.org/protocol/disco#info'><identity category='client' type='pc'/><feature var='http://jabber.org/protocol/disco#info'/><coucou xmlns='urn:example:coucou'><hibou/></coucou></query>".parse().unwrap();
+        let query = DiscoInfoResult::try_from(elem.clone()).unwrap();
+        assert_eq!(query.identities.len(), 1);
+        assert_eq!(query.features.len(), 1);
+        assert_eq!(query.unknown.len(), 1);
+        assert!(query.unknown[0].is("coucou", "urn:example:coucou"));
+        assert_eq!(Element::from(query), elem);
     }
 
     #[test]
@@ -0,0 +1,127 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::message::MessagePayload;
+use crate::ns;
+use crate::util::error::Error;
+use crate::Element;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+/// Hint that the recipients of a message should only retain it for a limited
+/// amount of time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ephemeral {
+    /// How long the message should be kept after it has been received, with
+    /// a granularity of one second.
+    pub timer: Duration,
+}
+
+impl MessagePayload for Ephemeral {}
+
+impl Ephemeral {
+    /// Create a new ephemeral hint for the given retention time.
+    pub fn new(timer: Duration) -> Ephemeral {
+        Ephemeral { timer }
+    }
+}
+
+impl TryFrom<Element> for Ephemeral {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<Ephemeral, Error> {
+        check_self!(elem, "ephemeral", EPHEMERAL);
+        check_no_children!(elem, "ephemeral");
+        check_no_unknown_attributes!(elem, "ephemeral", ["timer"]);
+        let timer: u64 = get_attr!(elem, "timer", Required);
+        Ok(Ephemeral {
+            timer: Duration::from_secs(timer),
+        })
+    }
+}
+
+impl From<Ephemeral> for Element {
+    fn from(ephemeral: Ephemeral) -> Element {
+        Element::builder("ephemeral", ns::EPHEMERAL)
+            .attr("timer", ephemeral.timer.as_secs())
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(Ephemeral, 12);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(Ephemeral, 16);
+    }
+
+    #[test]
+    fn test_simple() {
+        let elem: Element = "<ephemeral xmlns='urn:xmpp:ephemeral:0' timer='86400'/>"
+            .parse()
+            .unwrap();
+        let ephemeral = Ephemeral::try_from(elem.clone()).unwrap();
+        assert_eq!(ephemeral.timer, Duration::from_secs(86400));
+
+        let elem2 = Element::from(ephemeral);
+        assert_eq!(elem, elem2);
+    }
+
+    #[test]
+    fn test_serialise() {
+        let ephemeral = Ephemeral::new(Duration::from_millis(90_500));
+        let elem: Element = ephemeral.into();
+        assert_eq!(elem.attr("timer"), Some("90"));
+    }
+
+    #[test]
+    fn test_invalid_timer() {
+        let elem: Element = "<ephemeral xmlns='urn:xmpp:ephemeral:0'/>".parse().unwrap();
+        let error = Ephemeral::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Required attribute 'timer' missing.");
+
+        let elem: Element = "<ephemeral xmlns='urn:xmpp:ephemeral:0' timer='-5'/>"
+            .parse()
+            .unwrap();
+        let error = Ephemeral::try_from(elem).unwrap_err();
+        match error {
+            Error::ParseIntError(_) => (),
+            _ => panic!(),
+        }
+
+        let elem: Element = "<ephemeral xmlns='urn:xmpp:ephemeral:0' timer='1h'/>"
+            .parse()
+            .unwrap();
+        Ephemeral::try_from(elem).unwrap_err();
+    }
+
+    #[test]
+    fn test_invalid_child() {
+        let elem: Element =
+            "<ephemeral xmlns='urn:xmpp:ephemeral:0' timer='60'><coucou/></ephemeral>"
+                .parse()
+                .unwrap();
+        let error = Ephemeral::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Unknown child in ephemeral element.");
+    }
+}
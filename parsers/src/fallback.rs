@@ -0,0 +1,258 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::message::MessagePayload;
+use std::ops::Range;
+
+generate_element!(
+    /// A part of the body which is only there for clients not understanding
+    /// a payload, counted in code points.
+    BodyRange, "body", FALLBACK,
+    attributes: [
+        /// Where the fallback text starts, the beginning of the body if
+        /// absent.
+        start: Option<usize> = "start",
+
+        /// Where the fallback text ends, excluded, the end of the body if
+        /// absent.
+        end: Option<usize> = "end",
+    ]
+);
+
+generate_element!(
+    /// A part of the subject which is only there for clients not
+    /// understanding a payload, counted in code points.
+    SubjectRange, "subject", FALLBACK,
+    attributes: [
+        /// Where the fallback text starts, the beginning of the subject if
+        /// absent.
+        start: Option<usize> = "start",
+
+        /// Where the fallback text ends, excluded, the end of the subject
+        /// if absent.
+        end: Option<usize> = "end",
+    ]
+);
+
+generate_element!(
+    /// Tells which parts of a message are only there for clients not
+    /// understanding one of its payloads.
+    Fallback, "fallback", FALLBACK,
+    attributes: [
+        /// The namespace of the payload this is a fallback for.
+        for_: Required<String> = "for",
+    ],
+    children: [
+        /// The parts of the body which are fallback text.
+        bodies: Vec<BodyRange> = ("body", FALLBACK) => BodyRange,
+
+        /// The parts of the subject which are fallback text.
+        subjects: Vec<SubjectRange> = ("subject", FALLBACK) => SubjectRange
+    ]
+);
+
+impl MessagePayload for Fallback {}
+
+impl Fallback {
+    /// A fallback for the payload of namespace `for_`, covering the whole
+    /// body.
+    pub fn new<S: Into<String>>(for_: S) -> Fallback {
+        Fallback {
+            for_: for_.into(),
+            bodies: Vec::new(),
+            subjects: Vec::new(),
+        }
+    }
+
+    /// The byte ranges of `body` which are fallback text, all of it when
+    /// neither body nor subject ranges are given.
+    pub fn body_ranges(&self, body: &str) -> Vec<Range<usize>> {
+        if self.bodies.is_empty() && self.subjects.is_empty() {
+            let whole = 0..body.len();
+            return vec![whole];
+        }
+        self.bodies
+            .iter()
+            .map(|range| byte_range(body, range.start, range.end))
+            .collect()
+    }
+
+    /// The byte ranges of `subject` which are fallback text.
+    pub fn subject_ranges(&self, subject: &str) -> Vec<Range<usize>> {
+        self.subjects
+            .iter()
+            .map(|range| byte_range(subject, range.start, range.end))
+            .collect()
+    }
+}
+
+/// The byte offset of the code point at `offset` in `text`, clamped to its
+/// end.
+fn byte_offset(text: &str, offset: usize) -> usize {
+    text.char_indices()
+        .nth(offset)
+        .map(|(index, _)| index)
+        .unwrap_or(text.len())
+}
+
+/// The bytes of `text` between the code points at `start` and `end`, empty
+/// if they are reversed.
+fn byte_range(text: &str, start: Option<usize>, end: Option<usize>) -> Range<usize> {
+    let start = start.map_or(0, |start| byte_offset(text, start));
+    let end = end.map_or(text.len(), |end| byte_offset(text, end));
+    start..end.max(start)
+}
+
+/// `text` without the given byte `ranges`, which may overlap.
+pub fn strip(text: &str, ranges: &[Range<usize>]) -> String {
+    let mut ranges = ranges.to_vec();
+    ranges.sort_by_key(|range| range.start);
+    let mut stripped = String::with_capacity(text.len());
+    let mut kept_from = 0;
+    for range in ranges {
+        if range.start > kept_from {
+            stripped.push_str(&text[kept_from..range.start]);
+        }
+        kept_from = kept_from.max(range.end);
+    }
+    if kept_from < text.len() {
+        stripped.push_str(&text[kept_from..]);
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::error::Error;
+    use crate::Element;
+    use std::convert::TryFrom;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(BodyRange, 16);
+        assert_size!(Fallback, 36);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(BodyRange, 32);
+        assert_size!(Fallback, 72);
+    }
+
+    #[test]
+    fn test_simple() {
+        let elem: Element =
+            "<fallback xmlns='urn:xmpp:fallback:0' for='eu.siacs.conversations.axolotl'/>"
+                .parse()
+                .unwrap();
+        let elem1 = elem.clone();
+        let fallback = Fallback::try_from(elem).unwrap();
+        assert_eq!(fallback.for_, "eu.siacs.conversations.axolotl");
+        let body = "[This message is OMEMO encrypted]";
+        let whole = 0..body.len();
+        assert_eq!(fallback.body_ranges(body), [whole]);
+        assert!(fallback.subject_ranges("Subject").is_empty());
+
+        let elem2 = Element::from(Fallback::new("eu.siacs.conversations.axolotl"));
+        assert_eq!(elem1, elem2);
+    }
+
+    #[test]
+    fn test_ranges() {
+        let elem: Element = "<fallback xmlns='urn:xmpp:fallback:0' for='urn:xmpp:reply:0'><body start='0' end='8'/><subject end='2'/></fallback>"
+            .parse()
+            .unwrap();
+        let fallback = Fallback::try_from(elem).unwrap();
+        assert_eq!(fallback.bodies.len(), 1);
+        assert_eq!(fallback.subjects.len(), 1);
+
+        let body = "> Hi!\nHello";
+        assert_eq!(strip(body, &fallback.body_ranges(body)), "llo");
+        // Only the subject being covered, the body is kept.
+        let fallback = Fallback {
+            bodies: vec![],
+            ..fallback
+        };
+        assert!(fallback.body_ranges(body).is_empty());
+        assert_eq!(strip("Re: Hi", &fallback.subject_ranges("Re: Hi")), ": Hi");
+    }
+
+    #[test]
+    fn test_code_points() {
+        let range = |start, end| BodyRange {
+            start: Some(start),
+            end: Some(end),
+        };
+        let fallback = Fallback {
+            for_: String::from("urn:xmpp:reply:0"),
+            bodies: vec![range(0, 9)],
+            subjects: vec![],
+        };
+
+        // Two bytes each.
+        let body = "> Éléphant\nOui";
+        assert_eq!(fallback.body_ranges(body), [Range { start: 0, end: 11 }]);
+        assert_eq!(strip(body, &fallback.body_ranges(body)), "t\nOui");
+
+        // Outside of the basic multilingual plane, four bytes and a
+        // single code point each, even though UTF-16 counts two.
+        let body = "> 🐘🐘 ok\n🐘!";
+        let fallback = Fallback {
+            bodies: vec![range(0, 8)],
+            ..fallback
+        };
+        assert_eq!(fallback.body_ranges(body), [Range { start: 0, end: 14 }]);
+        assert_eq!(strip(body, &fallback.body_ranges(body)), "🐘!");
+
+        // Combining characters count as separate code points.
+        let body = "e\u{301}e\u{301}!";
+        let fallback = Fallback {
+            bodies: vec![range(1, 3)],
+            ..fallback
+        };
+        assert_eq!(strip(body, &fallback.body_ranges(body)), "e\u{301}!");
+    }
+
+    #[test]
+    fn test_out_of_bounds() {
+        let fallback = Fallback {
+            for_: String::from("urn:xmpp:reply:0"),
+            bodies: vec![
+                BodyRange {
+                    start: Some(3),
+                    end: Some(100),
+                },
+                BodyRange {
+                    start: Some(5),
+                    end: Some(1),
+                },
+                BodyRange {
+                    start: Some(1),
+                    end: Some(4),
+                },
+            ],
+            subjects: vec![],
+        };
+        let body = "ça va";
+        // Past the end is clamped, reversed is empty, overlaps merge.
+        assert_eq!(fallback.body_ranges(body), [4..6, 6..6, 2..5]);
+        assert_eq!(strip(body, &fallback.body_ranges(body)), "ç");
+    }
+
+    #[test]
+    fn test_missing_for() {
+        let elem: Element = "<fallback xmlns='urn:xmpp:fallback:0'/>".parse().unwrap();
+        let error = Fallback::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Required attribute 'for' missing.");
+    }
+}
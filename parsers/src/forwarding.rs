@@ -10,6 +10,10 @@ use crate::message::Message;
 generate_element!(
     /// Contains a forwarded stanza, either standalone or part of another
     /// extension (such as carbons).
+    ///
+    /// A forwarded message may itself forward another one, converting each
+    /// level recursing once; the [`ParseLimits`](crate::ParseLimits) of
+    /// the parser which built the `Element` keep that bounded.
     Forwarded, "forwarded", FORWARD,
     children: [
         /// When the stanza originally got sent.
@@ -74,7 +78,7 @@ mod tests {
 
     #[test]
     fn test_serialize_with_delay_and_stanza() {
-        let reference: Element = "<forwarded xmlns='urn:xmpp:forward:0'><delay xmlns='urn:xmpp:delay' from='capulet.com' stamp='2002-09-10T23:08:25+00:00'/><message xmlns='jabber:client' to='juliet@capulet.example/balcony' from='romeo@montague.example/home'/></forwarded>"
+        let reference: Element = "<forwarded xmlns='urn:xmpp:forward:0'><delay xmlns='urn:xmpp:delay' from='capulet.com' stamp='2002-09-10T23:08:25Z'/><message xmlns='jabber:client' to='juliet@capulet.example/balcony' from='romeo@montague.example/home'/></forwarded>"
         .parse()
         .unwrap();
 
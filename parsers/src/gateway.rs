@@ -0,0 +1,179 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::iq::{IqGetPayload, IqResultPayload, IqSetPayload};
+use crate::ns;
+use crate::util::error::Error;
+use crate::Element;
+use jid::Jid;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+generate_empty_element!(
+    /// Asks a gateway how to address the users of its legacy service.
+    ///
+    /// It should only be used in an `<iq type='get'/>`, as it can only
+    /// represent the request, and not a result.
+    PromptQuery,
+    "query",
+    GATEWAY
+);
+
+impl IqGetPayload for PromptQuery {}
+
+generate_element!(
+    /// The answer to a [`PromptQuery`], or in an `<iq type='set'/>` the
+    /// address of a legacy user to translate into a JID, without any
+    /// description.
+    Prompt, "query", GATEWAY,
+    children: [
+        /// Natural language instructions about the address to enter.
+        desc: Option<String> = ("desc", GATEWAY) => String,
+
+        /// The label of the address, such as “Contact ID”, or the address
+        /// itself in a request.
+        prompt: Required<String> = ("prompt", GATEWAY) => String
+    ]
+);
+
+impl IqResultPayload for Prompt {}
+impl IqSetPayload for Prompt {}
+
+impl Prompt {
+    /// Asks for the JID of the legacy user at `address`.
+    pub fn translate<A: Into<String>>(address: A) -> Prompt {
+        Prompt {
+            desc: None,
+            prompt: address.into(),
+        }
+    }
+}
+
+/// The JID a gateway translated the address of a legacy user into.
+///
+/// It should only be used in an `<iq type='result'/>`, as it can only
+/// represent the result, and not a request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Translated {
+    /// The JID to address this user at.
+    pub jid: Jid,
+}
+
+impl IqResultPayload for Translated {}
+
+impl TryFrom<Element> for Translated {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<Translated, Error> {
+        check_self!(elem, "query", GATEWAY, "gateway query");
+        check_no_attributes!(elem, "query");
+        let mut jid = None;
+        for child in elem.children() {
+            // Gateways predating version 1.0 of the XEP put it in a <prompt/>.
+            if child.is("jid", ns::GATEWAY) || child.is("prompt", ns::GATEWAY) {
+                if jid.is_some() {
+                    return Err(Error::ParseError(
+                        "Gateway query must not have more than one JID.",
+                    ));
+                }
+                jid = Some(Jid::from_str(&child.text())?);
+            } else {
+                return Err(Error::ParseError("Unknown child in gateway query."));
+            }
+        }
+        jid.map(|jid| Translated { jid })
+            .ok_or(Error::ParseError("Missing JID in gateway query."))
+    }
+}
+
+impl From<Translated> for Element {
+    fn from(translated: Translated) -> Element {
+        Element::builder("query", ns::GATEWAY)
+            .append(Element::builder("jid", ns::GATEWAY).append(String::from(translated.jid)))
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jid::BareJid;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(PromptQuery, 0);
+        assert_size!(Prompt, 24);
+        assert_size!(Translated, 36);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(PromptQuery, 0);
+        assert_size!(Prompt, 48);
+        assert_size!(Translated, 72);
+    }
+
+    #[test]
+    fn test_prompt() {
+        let elem: Element = "<query xmlns='jabber:iq:gateway'/>".parse().unwrap();
+        let query = PromptQuery::try_from(elem.clone()).unwrap();
+        assert_eq!(Element::from(query), elem);
+
+        let elem: Element = "<query xmlns='jabber:iq:gateway'><desc>Please enter the ICQ Number of the person you would like to contact.</desc><prompt>Contact ID</prompt></query>"
+            .parse()
+            .unwrap();
+        let prompt = Prompt::try_from(elem.clone()).unwrap();
+        assert_eq!(
+            prompt.desc.as_deref(),
+            Some("Please enter the ICQ Number of the person you would like to contact.")
+        );
+        assert_eq!(prompt.prompt, "Contact ID");
+        assert_eq!(Element::from(prompt), elem);
+
+        let elem: Element =
+            "<query xmlns='jabber:iq:gateway'><prompt>+13158675309</prompt></query>"
+                .parse()
+                .unwrap();
+        assert_eq!(Element::from(Prompt::translate("+13158675309")), elem);
+    }
+
+    #[test]
+    fn test_translated() {
+        let elem: Element =
+            "<query xmlns='jabber:iq:gateway'><jid>+13158675309@sms.shakespeare.lit</jid></query>"
+                .parse()
+                .unwrap();
+        let translated = Translated::try_from(elem.clone()).unwrap();
+        assert_eq!(
+            translated.jid,
+            BareJid::new("+13158675309", "sms.shakespeare.lit")
+        );
+        assert_eq!(Element::from(translated), elem);
+
+        let elem: Element =
+            "<query xmlns='jabber:iq:gateway'><prompt>123456@icq.shakespeare.lit</prompt></query>"
+                .parse()
+                .unwrap();
+        let translated = Translated::try_from(elem).unwrap();
+        assert_eq!(
+            translated.jid,
+            BareJid::new("123456", "icq.shakespeare.lit")
+        );
+    }
+
+    #[test]
+    fn test_missing_jid() {
+        let elem: Element = "<query xmlns='jabber:iq:gateway'/>".parse().unwrap();
+        let error = Translated::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Missing JID in gateway query.");
+    }
+}
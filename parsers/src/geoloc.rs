@@ -0,0 +1,355 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::date::DateTime;
+use crate::ns;
+use crate::pubsub::PubSubPayload;
+use crate::util::error::Error;
+use crate::Element;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+/// The geographical location of an entity, every field being optional.
+///
+/// An empty element, which is what [`Geoloc::default`] serialises to, means that the entity
+/// stopped publishing its location.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Geoloc {
+    /// The language of the human-readable fields.
+    pub lang: Option<String>,
+
+    /// Horizontal GPS error in meters.
+    pub accuracy: Option<f64>,
+
+    /// Altitude in meters above or below sea level.
+    pub alt: Option<f64>,
+
+    /// Vertical GPS error in meters.
+    pub altaccuracy: Option<f64>,
+
+    /// A named area such as a campus or neighborhood.
+    pub area: Option<String>,
+
+    /// GPS bearing (direction in which the entity is heading to reach its next waypoint),
+    /// measured in decimal degrees relative to true north.
+    pub bearing: Option<f64>,
+
+    /// A specific building on a street or in an area.
+    pub building: Option<String>,
+
+    /// The nation where the user is located.
+    pub country: Option<String>,
+
+    /// The ISO 3166 two-letter country code.
+    pub countrycode: Option<String>,
+
+    /// GPS datum, WGS84 if unset.
+    pub datum: Option<String>,
+
+    /// A natural-language name for or description of the location.
+    pub description: Option<String>,
+
+    /// A particular floor in a building.
+    pub floor: Option<String>,
+
+    /// Latitude in decimal degrees North.
+    pub lat: Option<f64>,
+
+    /// A locality within the administrative region, such as a town or city.
+    pub locality: Option<String>,
+
+    /// Longitude in decimal degrees East.
+    pub lon: Option<f64>,
+
+    /// A code used for postal delivery.
+    pub postalcode: Option<String>,
+
+    /// An administrative region of the nation, such as a state or province.
+    pub region: Option<String>,
+
+    /// A particular room in a building.
+    pub room: Option<String>,
+
+    /// The speed at which the entity is moving, in meters per second.
+    pub speed: Option<f64>,
+
+    /// A thoroughfare within the locality, or a crossing of two thoroughfares.
+    pub street: Option<String>,
+
+    /// A catch-all element that captures any other information about the location.
+    pub text: Option<String>,
+
+    /// UTC timestamp specifying the moment when the reading was taken.
+    pub timestamp: Option<DateTime>,
+
+    /// The time zone offset from UTC for the current location, such as “-07:00”.
+    pub tzo: Option<String>,
+
+    /// A URI or URL pointing to information about the location.
+    pub uri: Option<String>,
+}
+
+impl PubSubPayload for Geoloc {}
+
+impl Geoloc {
+    /// Whether this says nothing about the location, meaning that the entity stopped
+    /// publishing it.
+    pub fn is_empty(&self) -> bool {
+        *self
+            == Geoloc {
+                lang: self.lang.clone(),
+                ..Geoloc::default()
+            }
+    }
+}
+
+/// Parse an xs:decimal, which unlike what `f64::from_str` accepts has no exponent nor
+/// special value.
+fn parse_decimal(text: &str) -> Result<f64, Error> {
+    let text = text.trim();
+    let digits = text.strip_prefix(|c| c == '-' || c == '+').unwrap_or(text);
+    let error = Error::ParseError("Invalid decimal in geoloc element.");
+    if digits.is_empty()
+        || digits == "."
+        || !digits.chars().all(|c| c.is_ascii_digit() || c == '.')
+        || digits.matches('.').count() > 1
+    {
+        return Err(error);
+    }
+    f64::from_str(text).map_err(|_| error)
+}
+
+/// Set `field` to `value`, unless a previous child already did.
+fn set_once<T>(field: &mut Option<T>, value: T) -> Result<(), Error> {
+    if field.is_some() {
+        return Err(Error::ParseError(
+            "Geoloc can’t have more than one of the same child.",
+        ));
+    }
+    *field = Some(value);
+    Ok(())
+}
+
+impl TryFrom<Element> for Geoloc {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<Geoloc, Error> {
+        check_self!(elem, "geoloc", GEOLOC);
+        check_no_unknown_attributes!(elem, "geoloc", ["xml:lang"]);
+
+        let mut geoloc = Geoloc {
+            lang: get_attr!(elem, "xml:lang", Option),
+            ..Geoloc::default()
+        };
+        for child in elem.children() {
+            if child.ns() != ns::GEOLOC {
+                return Err(Error::ParseError("Unknown element in User Location."));
+            }
+            check_no_children!(child, "geoloc child");
+            check_no_attributes!(child, "geoloc child");
+            let text = child.text();
+            match child.name() {
+                "accuracy" => set_once(&mut geoloc.accuracy, parse_decimal(&text)?)?,
+                "alt" => set_once(&mut geoloc.alt, parse_decimal(&text)?)?,
+                "altaccuracy" => set_once(&mut geoloc.altaccuracy, parse_decimal(&text)?)?,
+                "area" => set_once(&mut geoloc.area, text)?,
+                "bearing" => set_once(&mut geoloc.bearing, parse_decimal(&text)?)?,
+                "building" => set_once(&mut geoloc.building, text)?,
+                "country" => set_once(&mut geoloc.country, text)?,
+                "countrycode" => set_once(&mut geoloc.countrycode, text)?,
+                "datum" => set_once(&mut geoloc.datum, text)?,
+                "description" => set_once(&mut geoloc.description, text)?,
+                // Deprecated in favour of accuracy, and in arc minutes rather than meters.
+                "error" => (),
+                "floor" => set_once(&mut geoloc.floor, text)?,
+                "lat" => set_once(&mut geoloc.lat, parse_decimal(&text)?)?,
+                "locality" => set_once(&mut geoloc.locality, text)?,
+                "lon" => set_once(&mut geoloc.lon, parse_decimal(&text)?)?,
+                "postalcode" => set_once(&mut geoloc.postalcode, text)?,
+                "region" => set_once(&mut geoloc.region, text)?,
+                "room" => set_once(&mut geoloc.room, text)?,
+                "speed" => set_once(&mut geoloc.speed, parse_decimal(&text)?)?,
+                "street" => set_once(&mut geoloc.street, text)?,
+                "text" => set_once(&mut geoloc.text, text)?,
+                "timestamp" => set_once(&mut geoloc.timestamp, DateTime::from_str(&text)?)?,
+                "tzo" => set_once(&mut geoloc.tzo, text)?,
+                "uri" => set_once(&mut geoloc.uri, text)?,
+                _ => return Err(Error::ParseError("Unknown element in User Location.")),
+            }
+        }
+
+        Ok(geoloc)
+    }
+}
+
+/// The child `name` of geoloc, if there is a `value` for it.
+///
+/// Decimals get written as the shortest string parsing back to the same `f64`, never with an
+/// exponent.
+fn child<T: ToString>(name: &str, value: Option<T>) -> Option<Element> {
+    value.map(|value| {
+        Element::builder(name, ns::GEOLOC)
+            .append(value.to_string())
+            .build()
+    })
+}
+
+impl From<Geoloc> for Element {
+    fn from(geoloc: Geoloc) -> Element {
+        Element::builder("geoloc", ns::GEOLOC)
+            .attr("xml:lang", geoloc.lang)
+            .append_all(child("accuracy", geoloc.accuracy))
+            .append_all(child("alt", geoloc.alt))
+            .append_all(child("altaccuracy", geoloc.altaccuracy))
+            .append_all(child("area", geoloc.area))
+            .append_all(child("bearing", geoloc.bearing))
+            .append_all(child("building", geoloc.building))
+            .append_all(child("country", geoloc.country))
+            .append_all(child("countrycode", geoloc.countrycode))
+            .append_all(child("datum", geoloc.datum))
+            .append_all(child("description", geoloc.description))
+            .append_all(child("floor", geoloc.floor))
+            .append_all(child("lat", geoloc.lat))
+            .append_all(child("locality", geoloc.locality))
+            .append_all(child("lon", geoloc.lon))
+            .append_all(child("postalcode", geoloc.postalcode))
+            .append_all(child("region", geoloc.region))
+            .append_all(child("room", geoloc.room))
+            .append_all(child("speed", geoloc.speed))
+            .append_all(child("street", geoloc.street))
+            .append_all(child("text", geoloc.text))
+            .append_all(child("timestamp", geoloc.timestamp))
+            .append_all(child("tzo", geoloc.tzo))
+            .append_all(child("uri", geoloc.uri))
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(Geoloc, 292);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(Geoloc, 512);
+    }
+
+    #[test]
+    fn test_all_fields() {
+        let elem: Element = "<geoloc xmlns='http://jabber.org/protocol/geoloc' xml:lang='en'><accuracy>20</accuracy><alt>1609.5</alt><altaccuracy>3.25</altaccuracy><area>Central Park</area><bearing>182.5</bearing><building>Empire State Building</building><country>United States</country><countrycode>US</countrycode><datum>WGS84</datum><description>Bill's house</description><floor>102</floor><lat>39.75</lat><locality>New York City</locality><lon>-104.99</lon><postalcode>10118</postalcode><region>New York</region><room>Observatory</room><speed>52.69</speed><street>350 Fifth Avenue / 34th and Broadway</street><text>Northwest corner of the lobby</text><timestamp>2004-02-19T21:12:00Z</timestamp><tzo>-07:00</tzo><uri>http://www.esbnyc.com/</uri></geoloc>"
+            .parse()
+            .unwrap();
+        let geoloc = Geoloc::try_from(elem.clone()).unwrap();
+        assert_eq!(geoloc.lang.as_deref(), Some("en"));
+        assert_eq!(geoloc.accuracy, Some(20.));
+        assert_eq!(geoloc.alt, Some(1609.5));
+        assert_eq!(geoloc.bearing, Some(182.5));
+        assert_eq!(geoloc.countrycode.as_deref(), Some("US"));
+        assert_eq!(geoloc.lat, Some(39.75));
+        assert_eq!(geoloc.lon, Some(-104.99));
+        assert_eq!(geoloc.speed, Some(52.69));
+        assert_eq!(
+            geoloc.timestamp,
+            Some(DateTime::from_str("2004-02-19T21:12:00Z").unwrap())
+        );
+        assert_eq!(geoloc.tzo.as_deref(), Some("-07:00"));
+        assert_eq!(geoloc.uri.as_deref(), Some("http://www.esbnyc.com/"));
+        assert!(!geoloc.is_empty());
+
+        let elem2 = Element::from(geoloc.clone());
+        assert_eq!(Geoloc::try_from(elem2.clone()).unwrap(), geoloc);
+        assert_eq!(
+            elem2.get_child("lon", ns::GEOLOC).unwrap().text(),
+            "-104.99"
+        );
+        assert_eq!(
+            elem2.get_child("accuracy", ns::GEOLOC).unwrap().text(),
+            "20"
+        );
+    }
+
+    #[test]
+    fn test_empty() {
+        let elem: Element = "<geoloc xmlns='http://jabber.org/protocol/geoloc'/>"
+            .parse()
+            .unwrap();
+        let geoloc = Geoloc::try_from(elem.clone()).unwrap();
+        assert_eq!(geoloc, Geoloc::default());
+        assert!(geoloc.is_empty());
+        assert_eq!(Element::from(geoloc), elem);
+    }
+
+    #[test]
+    fn test_precision() {
+        let elem: Element = "<geoloc xmlns='http://jabber.org/protocol/geoloc'><lat>48.858370099999995</lat><lon>+2.29448129999999</lon><alt>0.000001</alt></geoloc>"
+            .parse()
+            .unwrap();
+        let geoloc = Geoloc::try_from(elem).unwrap();
+        assert_eq!(geoloc.lat, Some(48.858370099999995));
+        assert_eq!(geoloc.lon, Some(2.29448129999999));
+        let elem = Element::from(geoloc);
+        let text = |name| elem.get_child(name, ns::GEOLOC).unwrap().text();
+        assert_eq!(text("lat"), "48.858370099999995");
+        assert_eq!(text("lon"), "2.29448129999999");
+        // No exponent, which xs:decimal doesn’t allow.
+        assert_eq!(text("alt"), "0.000001");
+
+        // Digits beyond what an f64 holds get dropped.
+        let elem: Element = "<geoloc xmlns='http://jabber.org/protocol/geoloc'><lat>-33.856784499999999999999</lat></geoloc>"
+            .parse()
+            .unwrap();
+        let elem = Element::from(Geoloc::try_from(elem).unwrap());
+        assert_eq!(
+            elem.get_child("lat", ns::GEOLOC).unwrap().text(),
+            "-33.8567845"
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        for value in &["1e3", "NaN", "inf", "", "-", ".", "1.2.3", "0x10"] {
+            let elem: Element = format!(
+                "<geoloc xmlns='http://jabber.org/protocol/geoloc'><lat>{}</lat></geoloc>",
+                value
+            )
+            .parse()
+            .unwrap();
+            assert!(Geoloc::try_from(elem).is_err(), "{} accepted", value);
+        }
+
+        let elem: Element =
+            "<geoloc xmlns='http://jabber.org/protocol/geoloc'><lat>1</lat><lat>2</lat></geoloc>"
+                .parse()
+                .unwrap();
+        let error = Geoloc::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(
+            message,
+            "Geoloc can’t have more than one of the same child."
+        );
+
+        let elem: Element =
+            "<geoloc xmlns='http://jabber.org/protocol/geoloc'><altitude>1</altitude></geoloc>"
+                .parse()
+                .unwrap();
+        let error = Geoloc::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Unknown element in User Location.");
+    }
+}
@@ -71,10 +71,10 @@ mod tests {
             .unwrap();
         let error = Idle::try_from(elem).unwrap_err();
         let message = match error {
-            Error::ChronoParseError(string) => string,
+            Error::ParseError(string) => string,
             _ => panic!(),
         };
-        assert_eq!(message.to_string(), "input is out of range");
+        assert_eq!(message, "Invalid date.");
 
         // Timezone ≥24:00 aren’t allowed.
         let elem: Element = "<idle xmlns='urn:xmpp:idle:1' since='2017-05-27T12:11:02+25:00'/>"
@@ -82,10 +82,10 @@ mod tests {
             .unwrap();
         let error = Idle::try_from(elem).unwrap_err();
         let message = match error {
-            Error::ChronoParseError(string) => string,
+            Error::ParseError(string) => string,
             _ => panic!(),
         };
-        assert_eq!(message.to_string(), "input is out of range");
+        assert_eq!(message, "Invalid timezone.");
 
         // Timezone without the : separator aren’t allowed.
         let elem: Element = "<idle xmlns='urn:xmpp:idle:1' since='2017-05-27T12:11:02+0100'/>"
@@ -93,32 +93,32 @@ mod tests {
             .unwrap();
         let error = Idle::try_from(elem).unwrap_err();
         let message = match error {
-            Error::ChronoParseError(string) => string,
+            Error::ParseError(string) => string,
             _ => panic!(),
         };
-        assert_eq!(message.to_string(), "input contains invalid characters");
+        assert_eq!(message, "Missing separator in date or time.");
 
-        // No seconds, error message could be improved.
+        // No seconds.
         let elem: Element = "<idle xmlns='urn:xmpp:idle:1' since='2017-05-27T12:11+01:00'/>"
             .parse()
             .unwrap();
         let error = Idle::try_from(elem).unwrap_err();
         let message = match error {
-            Error::ChronoParseError(string) => string,
+            Error::ParseError(string) => string,
             _ => panic!(),
         };
-        assert_eq!(message.to_string(), "input contains invalid characters");
+        assert_eq!(message, "Missing separator in date or time.");
 
-        // TODO: maybe we’ll want to support this one, as per XEP-0082 §4.
+        // The legacy format of XEP-0082 §4 isn’t allowed here.
         let elem: Element = "<idle xmlns='urn:xmpp:idle:1' since='20170527T12:11:02+01:00'/>"
             .parse()
             .unwrap();
         let error = Idle::try_from(elem).unwrap_err();
         let message = match error {
-            Error::ChronoParseError(string) => string,
+            Error::ParseError(string) => string,
             _ => panic!(),
         };
-        assert_eq!(message.to_string(), "input contains invalid characters");
+        assert_eq!(message, "Missing separator in date or time.");
 
         // No timezone.
         let elem: Element = "<idle xmlns='urn:xmpp:idle:1' since='2017-05-27T12:11:02'/>"
@@ -126,15 +126,16 @@ mod tests {
             .unwrap();
         let error = Idle::try_from(elem).unwrap_err();
         let message = match error {
-            Error::ChronoParseError(string) => string,
+            Error::ParseError(string) => string,
             _ => panic!(),
         };
-        assert_eq!(message.to_string(), "premature end of input");
+        assert_eq!(message, "Missing timezone in date or time.");
     }
 
     #[test]
     fn test_serialise() {
-        let elem: Element = "<idle xmlns='urn:xmpp:idle:1' since='2017-05-21T20:19:55+01:00'/>"
+        // Always in UTC.
+        let elem: Element = "<idle xmlns='urn:xmpp:idle:1' since='2017-05-21T19:19:55Z'/>"
             .parse()
             .unwrap();
         let idle = Idle {
@@ -143,4 +144,15 @@ mod tests {
         let elem2 = idle.into();
         assert_eq!(elem, elem2);
     }
+
+    #[test]
+    fn test_roundtrip() {
+        let elem: Element = "<idle xmlns='urn:xmpp:idle:1' since='2017-05-21T20:19:55.125+01:00'/>"
+            .parse()
+            .unwrap();
+        let idle = Idle::try_from(elem).unwrap();
+        let elem: Element = idle.clone().into();
+        assert_eq!(elem.attr("since"), Some("2017-05-21T19:19:55.125Z"));
+        assert_eq!(Idle::try_from(elem).unwrap().since, idle.since);
+    }
 }
@@ -673,6 +673,7 @@ impl From<Jingle> for Element {
             .append_all(jingle.contents)
             .append_all(jingle.reason.map(Element::from))
             .append_all(jingle.group.map(Element::from))
+            .append_all(jingle.other)
             .build()
     }
 }
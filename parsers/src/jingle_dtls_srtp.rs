@@ -58,12 +58,18 @@ impl Fingerprint {
     }
 
     /// Create a new Fingerprint from a Setup and parsing the hash.
+    ///
+    /// Only the sha-1, sha-256 and sha-512 algorithms are accepted, any other
+    /// one results in an `Error::UnsupportedFingerprintAlgo`.
     pub fn from_colon_separated_hex(
         setup: Setup,
         algo: &str,
         hash: &str,
     ) -> Result<Fingerprint, Error> {
-        let algo = algo.parse()?;
+        let algo = match algo.parse()? {
+            algo @ Algo::Sha_1 | algo @ Algo::Sha_256 | algo @ Algo::Sha_512 => algo,
+            _ => return Err(Error::UnsupportedFingerprintAlgo(algo.to_owned())),
+        };
         let hash = Hash::from_colon_separated_hex(algo, hash)?;
         Ok(Fingerprint::from_hash(setup, hash))
     }
@@ -105,4 +111,21 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_from_colon_separated_hex() {
+        let fingerprint =
+            Fingerprint::from_colon_separated_hex(Setup::Active, "sha-1", "02:1A:CC:54").unwrap();
+        assert_eq!(fingerprint.hash, Algo::Sha_1);
+        assert_eq!(fingerprint.value, [2, 26, 204, 84]);
+
+        for algo in &["unknownalg", "sha3-256", "blake2b-512"] {
+            let error =
+                Fingerprint::from_colon_separated_hex(Setup::Active, algo, "02:1A").unwrap_err();
+            match error {
+                Error::UnsupportedFingerprintAlgo(name) => assert_eq!(name, *algo),
+                _ => panic!(),
+            }
+        }
+    }
 }
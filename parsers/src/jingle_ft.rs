@@ -8,6 +8,7 @@ use crate::date::DateTime;
 use crate::hashes::Hash;
 use crate::jingle::{ContentId, Creator};
 use crate::ns;
+use crate::thumbnail::Thumbnail;
 use crate::util::error::Error;
 use minidom::{Element, Node};
 use std::collections::BTreeMap;
@@ -69,6 +70,10 @@ pub struct File {
 
     /// A list of hashes matching this entire file.
     pub hashes: Vec<Hash>,
+
+    /// Previews of this file (XEP-0264), such as a smaller version of an
+    /// image.
+    pub thumbnails: Vec<Thumbnail>,
 }
 
 impl File {
@@ -125,6 +130,12 @@ impl File {
         self.hashes.push(hash);
         self
     }
+
+    /// Add a thumbnail of this file.
+    pub fn add_thumbnail(mut self, thumbnail: Thumbnail) -> File {
+        self.thumbnails.push(thumbnail);
+        self
+    }
 }
 
 impl TryFrom<Element> for File {
@@ -142,6 +153,7 @@ impl TryFrom<Element> for File {
             size: None,
             range: None,
             hashes: vec![],
+            thumbnails: vec![],
         };
 
         for child in elem.children() {
@@ -182,6 +194,8 @@ impl TryFrom<Element> for File {
                 file.range = Some(Range::try_from(child.clone())?);
             } else if child.is("hash", ns::HASHES) {
                 file.hashes.push(Hash::try_from(child.clone())?);
+            } else if child.is("thumbnail", ns::THUMBS) {
+                file.thumbnails.push(Thumbnail::try_from(child.clone())?);
             } else {
                 return Err(Error::ParseError("Unknown element in JingleFT file."));
             }
@@ -219,6 +233,7 @@ impl From<File> for Element {
             )
             .append_all(file.range)
             .append_all(file.hashes)
+            .append_all(file.thumbnails)
             .build()
     }
 }
@@ -336,8 +351,8 @@ mod tests {
     #[test]
     fn test_size() {
         assert_size!(Range, 40);
-        assert_size!(File, 128);
-        assert_size!(Description, 128);
+        assert_size!(File, 140);
+        assert_size!(Description, 140);
         assert_size!(Checksum, 144);
         assert_size!(Received, 16);
     }
@@ -346,8 +361,8 @@ mod tests {
     #[test]
     fn test_size() {
         assert_size!(Range, 48);
-        assert_size!(File, 184);
-        assert_size!(Description, 184);
+        assert_size!(File, 208);
+        assert_size!(Description, 208);
         assert_size!(Checksum, 216);
         assert_size!(Received, 32);
     }
@@ -383,6 +398,38 @@ mod tests {
             desc.file.hashes[0].hash,
             base64::decode("w0mcJylzCn+AfvuGdqkty2+KP48=").unwrap()
         );
+
+        // The date gets sent back in UTC.
+        let elem: Element = desc.file.into();
+        let date = elem.get_child("date", ns::JINGLE_FT).unwrap();
+        assert_eq!(date.text(), "2015-07-26T20:46:00Z");
+    }
+
+    #[test]
+    fn test_thumbnail() {
+        let elem: Element = r#"
+<file xmlns='urn:xmpp:jingle:apps:file-transfer:5'>
+  <media-type>image/jpeg</media-type>
+  <name>holidays.jpg</name>
+  <thumbnail xmlns='urn:xmpp:thumbs:1'
+             uri='cid:sha1+ffd7c8d28e9c5e82afea41f97108c6b4@bob.xmpp.org'
+             media-type='image/png'
+             width='128'
+             height='96'/>
+</file>
+"#
+        .parse()
+        .unwrap();
+        let file = File::try_from(elem).unwrap();
+        assert_eq!(file.thumbnails.len(), 1);
+        assert_eq!(file.thumbnails[0].width, Some(128));
+
+        let elem: Element = file.into();
+        let thumbnail = elem.get_child("thumbnail", ns::THUMBS).unwrap();
+        assert_eq!(
+            thumbnail.attr("uri"),
+            Some("cid:sha1+ffd7c8d28e9c5e82afea41f97108c6b4@bob.xmpp.org")
+        );
     }
 
     #[test]
@@ -9,10 +9,13 @@ use crate::jingle::ContentId;
 generate_attribute!(
     /// The semantics of the grouping.
     Semantics, "semantics", {
-        /// Lip synchronsation.
+        /// Lip synchronisation, defined in RFC5888.
         Ls => "LS",
 
-        /// Bundle.
+        /// Flow identification, defined in RFC5888.
+        Fid => "FID",
+
+        /// Bundle, defined in RFC8843.
         Bundle => "BUNDLE",
     }
 );
@@ -51,6 +54,7 @@ generate_element!(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::util::error::Error;
     use crate::Element;
     use std::convert::TryFrom;
 
@@ -87,4 +91,43 @@ mod tests {
             &[Content::new("voice"), Content::new("webcam")]
         );
     }
+
+    #[test]
+    fn lip_sync_round_trip() {
+        let elem: Element = "<group xmlns='urn:xmpp:jingle:apps:grouping:0' semantics='LS'><content name='audio'/><content name='video'/></group>"
+            .parse()
+            .unwrap();
+        let group = Group::try_from(elem.clone()).unwrap();
+        assert_eq!(group.semantics, Semantics::Ls);
+        assert_eq!(
+            group.contents,
+            &[Content::new("audio"), Content::new("video")]
+        );
+        let elem2 = Element::from(group);
+        assert_eq!(elem2.attr("semantics"), Some("LS"));
+        assert_eq!(elem, elem2);
+    }
+
+    #[test]
+    fn flow_identification() {
+        let elem: Element = "<group xmlns='urn:xmpp:jingle:apps:grouping:0' semantics='FID'><content name='video'/></group>"
+            .parse()
+            .unwrap();
+        let group = Group::try_from(elem).unwrap();
+        assert_eq!(group.semantics, Semantics::Fid);
+        assert_eq!(Element::from(group).attr("semantics"), Some("FID"));
+    }
+
+    #[test]
+    fn unknown_semantics() {
+        let elem: Element = "<group xmlns='urn:xmpp:jingle:apps:grouping:0' semantics='FOO'/>"
+            .parse()
+            .unwrap();
+        let error = Group::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Unknown value for 'semantics' attribute.");
+    }
 }
@@ -4,9 +4,14 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use crate::jingle::{Action, ContentId, Creator, Jingle, SessionId};
 use crate::jingle_rtcp_fb::RtcpFb;
 use crate::jingle_rtp_hdrext::RtpHdrext;
 use crate::jingle_ssma::{Group, Source};
+use crate::ns;
+use crate::util::error::Error;
+use crate::Element;
+use std::convert::TryFrom;
 
 generate_empty_element!(
     /// Specifies the ability to multiplex RTP Data and Control Packets on a single port as
@@ -149,11 +154,116 @@ generate_element!(
     ]
 );
 
+/// An informational message about an RTP session, sent in a session-info Jingle.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Info {
+    /// The session is active again, after having been on hold or muted.
+    Active,
+
+    /// The session has been put on hold.
+    Hold,
+
+    /// The session isn’t on hold anymore.
+    Unhold,
+
+    /// The sender stopped sending media.
+    Mute {
+        /// Who created the muted content.
+        creator: Option<Creator>,
+
+        /// The muted content, all of them if absent.
+        name: Option<ContentId>,
+    },
+
+    /// The sender is sending media again.
+    Unmute {
+        /// Who created the unmuted content.
+        creator: Option<Creator>,
+
+        /// The unmuted content, all of them if absent.
+        name: Option<ContentId>,
+    },
+
+    /// The call is ringing on the responder’s side.
+    Ringing,
+}
+
+impl Info {
+    /// Wrap this info in a session-info Jingle for the session `sid`.
+    pub fn into_jingle(self, sid: SessionId) -> Jingle {
+        let mut jingle = Jingle::new(Action::SessionInfo, sid);
+        jingle.other.push(self.into());
+        jingle
+    }
+
+    /// Extract the info from a session-info Jingle, `None` if it doesn’t contain any, as is the
+    /// case for pings.
+    pub fn from_jingle(jingle: &Jingle) -> Result<Option<Info>, Error> {
+        if jingle.action != Action::SessionInfo {
+            return Err(Error::ParseError("This is not a session-info Jingle."));
+        }
+        jingle
+            .other
+            .iter()
+            .find(|elem| elem.has_ns(ns::JINGLE_RTP_INFO))
+            .cloned()
+            .map(Info::try_from)
+            .transpose()
+    }
+}
+
+impl TryFrom<Element> for Info {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<Info, Error> {
+        check_ns_only!(elem, "RTP info", JINGLE_RTP_INFO);
+        check_no_children!(elem, "RTP info");
+        let info = match elem.name() {
+            "mute" | "unmute" => {
+                check_no_unknown_attributes!(elem, "RTP info", ["creator", "name"]);
+                let creator = get_attr!(elem, "creator", Option);
+                let name = get_attr!(elem, "name", Option);
+                if elem.name() == "mute" {
+                    Info::Mute { creator, name }
+                } else {
+                    Info::Unmute { creator, name }
+                }
+            }
+            name => {
+                check_no_attributes!(elem, "RTP info");
+                match name {
+                    "active" => Info::Active,
+                    "hold" => Info::Hold,
+                    "unhold" => Info::Unhold,
+                    "ringing" => Info::Ringing,
+                    _ => return Err(Error::ParseError("Unknown RTP info element.")),
+                }
+            }
+        };
+        Ok(info)
+    }
+}
+
+impl From<Info> for Element {
+    fn from(info: Info) -> Element {
+        let (name, creator, content) = match info {
+            Info::Active => ("active", None, None),
+            Info::Hold => ("hold", None, None),
+            Info::Unhold => ("unhold", None, None),
+            Info::Mute { creator, name } => ("mute", creator, name),
+            Info::Unmute { creator, name } => ("unmute", creator, name),
+            Info::Ringing => ("ringing", None, None),
+        };
+        Element::builder(name, ns::JINGLE_RTP_INFO)
+            .attr("creator", creator)
+            .attr("name", content)
+            .build()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::Element;
-    use std::convert::TryFrom;
 
     #[cfg(target_pointer_width = "32")]
     #[test]
@@ -162,6 +272,7 @@ mod tests {
         assert_size!(Channels, 1);
         assert_size!(PayloadType, 64);
         assert_size!(Parameter, 24);
+        assert_size!(Info, 16);
     }
 
     #[cfg(target_pointer_width = "64")]
@@ -171,6 +282,7 @@ mod tests {
         assert_size!(Channels, 1);
         assert_size!(PayloadType, 104);
         assert_size!(Parameter, 48);
+        assert_size!(Info, 32);
     }
 
     #[test]
@@ -206,4 +318,85 @@ mod tests {
         assert_eq!(desc.media, "audio");
         assert_eq!(desc.ssrc, None);
     }
+
+    #[test]
+    fn test_info() {
+        let elem: Element = "<jingle xmlns='urn:xmpp:jingle:1' action='session-info' sid='a73sjjvkla37jfea'><mute xmlns='urn:xmpp:jingle:apps:rtp:info:1' creator='responder' name='voice'/></jingle>"
+            .parse()
+            .unwrap();
+        let jingle = Jingle::try_from(elem.clone()).unwrap();
+        let info = Info::from_jingle(&jingle).unwrap().unwrap();
+        assert_eq!(
+            info,
+            Info::Mute {
+                creator: Some(Creator::Responder),
+                name: Some(ContentId(String::from("voice"))),
+            }
+        );
+        let elem2: Element = info
+            .into_jingle(SessionId(String::from("a73sjjvkla37jfea")))
+            .into();
+        assert_eq!(elem, elem2);
+
+        let elem: Element = Info::Ringing
+            .into_jingle(SessionId(String::from("a73sjjvkla37jfea")))
+            .into();
+        let expected: Element = "<jingle xmlns='urn:xmpp:jingle:1' action='session-info' sid='a73sjjvkla37jfea'><ringing xmlns='urn:xmpp:jingle:apps:rtp:info:1'/></jingle>"
+            .parse()
+            .unwrap();
+        assert_eq!(elem, expected);
+
+        for name in &["active", "hold", "unhold", "unmute", "ringing"] {
+            let elem: Element = format!("<{} xmlns='urn:xmpp:jingle:apps:rtp:info:1'/>", name)
+                .parse()
+                .unwrap();
+            let info = Info::try_from(elem.clone()).unwrap();
+            assert_eq!(Element::from(info), elem);
+        }
+
+        // An empty session-info is only a ping.
+        let jingle = Jingle::new(
+            Action::SessionInfo,
+            SessionId(String::from("a73sjjvkla37jfea")),
+        );
+        assert_eq!(Info::from_jingle(&jingle).unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalid_info() {
+        let elem: Element = "<coucou xmlns='urn:xmpp:jingle:apps:rtp:info:1'/>"
+            .parse()
+            .unwrap();
+        let error = Info::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Unknown RTP info element.");
+
+        let jingle = Jingle::new(
+            Action::SessionTerminate,
+            SessionId(String::from("a73sjjvkla37jfea")),
+        );
+        let error = Info::from_jingle(&jingle).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "This is not a session-info Jingle.");
+    }
+
+    #[cfg(not(feature = "disable-validation"))]
+    #[test]
+    fn test_invalid_info_attribute() {
+        let elem: Element = "<hold xmlns='urn:xmpp:jingle:apps:rtp:info:1' name='voice'/>"
+            .parse()
+            .unwrap();
+        let error = Info::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Unknown attribute in RTP info element.");
+    }
 }
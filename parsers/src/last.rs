@@ -0,0 +1,102 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::iq::{IqGetPayload, IqResultPayload};
+use crate::util::helpers::PlainText;
+
+generate_empty_element!(
+    /// Asks for the last activity of an entity: the idle time of a client,
+    /// the time since an account got disconnected, or the uptime of a server.
+    ///
+    /// It should only be used in an `<iq type='get'/>`, as it can only
+    /// represent the request, and not a result.
+    LastActivityQuery,
+    "query",
+    LAST
+);
+
+impl IqGetPayload for LastActivityQuery {}
+
+generate_element!(
+    /// The answer to a [`LastActivityQuery`].
+    ///
+    /// It should only be used in an `<iq type='result'/>`, as it can only
+    /// represent the result, and not a request.
+    LastActivityResult, "query", LAST,
+    attributes: [
+        /// How many seconds ago this activity happened, or for how long a
+        /// server has been running.
+        seconds: Required<u64> = "seconds"
+    ],
+    text: (
+        /// The status of an offline account, as it was when it logged out.
+        status: PlainText<Option<String>>
+    )
+);
+
+impl IqResultPayload for LastActivityResult {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::error::Error;
+    use crate::Element;
+    use std::convert::TryFrom;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(LastActivityQuery, 0);
+        assert_size!(LastActivityResult, 20);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(LastActivityQuery, 0);
+        assert_size!(LastActivityResult, 32);
+    }
+
+    #[test]
+    fn query() {
+        let elem: Element = "<query xmlns='jabber:iq:last'/>".parse().unwrap();
+        let query = LastActivityQuery::try_from(elem.clone()).unwrap();
+        assert_eq!(Element::from(query), elem);
+    }
+
+    #[test]
+    fn uptime() {
+        let elem: Element = "<query xmlns='jabber:iq:last' seconds='123456'/>"
+            .parse()
+            .unwrap();
+        let result = LastActivityResult::try_from(elem.clone()).unwrap();
+        assert_eq!(result.seconds, 123456);
+        assert_eq!(result.status, None);
+        assert_eq!(Element::from(result), elem);
+    }
+
+    #[test]
+    fn offline_status() {
+        let elem: Element = "<query xmlns='jabber:iq:last' seconds='903'>Heading Home</query>"
+            .parse()
+            .unwrap();
+        let result = LastActivityResult::try_from(elem.clone()).unwrap();
+        assert_eq!(result.seconds, 903);
+        assert_eq!(result.status, Some(String::from("Heading Home")));
+        assert_eq!(Element::from(result), elem);
+    }
+
+    #[test]
+    fn missing_seconds() {
+        let elem: Element = "<query xmlns='jabber:iq:last'/>".parse().unwrap();
+        let error = LastActivityResult::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Required attribute 'seconds' missing.");
+    }
+}
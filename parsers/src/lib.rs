@@ -25,10 +25,11 @@
 
 pub use crate::util::error::Error;
 pub use jid::{BareJid, FullJid, Jid, JidParseError};
-pub use minidom::Element;
+pub use minidom::{Element, ParseLimits};
 
 /// XML namespace definitions used through XMPP.
 pub mod ns;
+pub use crate::ns::{supported_protocols, ProtocolInfo};
 
 #[macro_use]
 mod util;
@@ -57,6 +58,9 @@ pub mod websocket;
 /// XEP-0004: Data Forms
 pub mod data_forms;
 
+/// XEP-0012: Last Activity
+pub mod last;
+
 /// XEP-0030: Service Discovery
 pub mod disco;
 
@@ -69,18 +73,27 @@ pub mod ibb;
 /// XEP-0048: Bookmarks
 pub mod bookmarks;
 
+/// XEP-0050: Ad-Hoc Commands
+pub mod adhoc;
+
 /// XEP-0059: Result Set Management
 pub mod rsm;
 
 /// XEP-0060: Publish-Subscribe
 pub mod pubsub;
 
+/// XEP-0065: SOCKS5 Bytestreams
+pub mod bytestreams;
+
 /// XEP-0071: XHTML-IM
 pub mod xhtml;
 
 /// XEP-0077: In-Band Registration
 pub mod ibr;
 
+/// XEP-0080: User Location
+pub mod geoloc;
+
 /// XEP-0082: XMPP Date and Time Profiles
 pub mod date;
 
@@ -93,6 +106,9 @@ pub mod chatstates;
 /// XEP-0092: Software Version
 pub mod version;
 
+/// XEP-0100: Gateway Interaction
+pub mod gateway;
+
 /// XEP-0107: User Mood
 pub mod mood;
 
@@ -105,6 +121,12 @@ pub mod caps;
 /// XEP-0118: User Tune
 pub mod tune;
 
+/// XEP-0133: Service Administration
+pub mod admin;
+
+/// XEP-0153: vCard-Based Avatars
+pub mod vcard_update;
+
 /// XEP-0157: Contact Addresses for XMPP Services
 pub mod server_info;
 
@@ -156,15 +178,24 @@ pub mod bob;
 /// XEP-0234: Jingle File Transfer
 pub mod jingle_ft;
 
+/// XEP-0249: Direct MUC Invitations
+pub mod direct_muc;
+
 /// XEP-0257: Client Certificate Management for SASL EXTERNAL
 pub mod cert_management;
 
+/// XEP-0258: Security Labels in XMPP
+pub mod sec_label;
+
 /// XEP-0260: Jingle SOCKS5 Bytestreams Transport Method
 pub mod jingle_s5b;
 
 /// XEP-0261: Jingle In-Band Bytestreams Transport Method
 pub mod jingle_ibb;
 
+/// XEP-0264: Jingle Content Thumbnails
+pub mod thumbnail;
+
 /// XEP-0280: Message Carbons
 pub mod carbons;
 
@@ -207,6 +238,9 @@ pub mod csi;
 /// XEP-0353: Jingle Message Initiation
 pub mod jingle_message;
 
+/// XEP-0356: Privileged Entity
+pub mod privilege;
+
 /// XEP-0359: Unique and Stable Stanza IDs
 pub mod stanza_id;
 
@@ -225,11 +259,20 @@ pub mod eme;
 /// XEP-0390: Entity Capabilities 2.0
 pub mod ecaps2;
 
+/// XEP-0393: Message Styling
+pub mod message_styling;
+
 /// XEP-0402: PEP Native Bookmarks
 pub mod bookmarks2;
 
 /// XEP-0421: Anonymous unique occupant identifiers for MUCs
 pub mod occupant_id;
 
+/// XEP-0428: Fallback Indication
+pub mod fallback;
+
 /// XEP-0441: Message Archive Management Preferences
 pub mod mam_prefs;
+
+/// XEP-0466: Ephemeral Messages
+pub mod ephemeral;
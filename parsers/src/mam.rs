@@ -5,6 +5,7 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use crate::data_forms::DataForm;
+use crate::date::DateTime;
 use crate::forwarding::Forwarded;
 use crate::iq::{IqGetPayload, IqResultPayload, IqSetPayload};
 use crate::message::MessagePayload;
@@ -87,12 +88,62 @@ generate_element!(
 
 impl IqResultPayload for Fin {}
 
+generate_empty_element!(
+    /// Requests the ids and timestamps of the oldest and newest messages of
+    /// the archive, answered with a [MetadataResult].
+    MetadataQuery,
+    "metadata",
+    MAM
+);
+
+impl IqGetPayload for MetadataQuery {}
+
+generate_element!(
+    /// The oldest message in the archive.
+    Start, "start", MAM,
+    attributes: [
+        /// The stanza-id under which the archive stored this message.
+        id: Required<String> = "id",
+
+        /// When this message got archived.
+        timestamp: Required<DateTime> = "timestamp",
+    ]
+);
+
+generate_element!(
+    /// The newest message in the archive.
+    End, "end", MAM,
+    attributes: [
+        /// The stanza-id under which the archive stored this message.
+        id: Required<String> = "id",
+
+        /// When this message got archived.
+        timestamp: Required<DateTime> = "timestamp",
+    ]
+);
+
+generate_element!(
+    /// The bounds of the archive, neither of them being present when it is
+    /// empty.
+    MetadataResult, "metadata", MAM,
+    children: [
+        /// The oldest message in the archive.
+        start: Option<Start> = ("start", MAM) => Start,
+
+        /// The newest message in the archive.
+        end: Option<End> = ("end", MAM) => End
+    ]
+);
+
+impl IqResultPayload for MetadataResult {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::util::error::Error;
     use minidom::Element;
     use std::convert::TryFrom;
+    use std::str::FromStr;
 
     #[cfg(target_pointer_width = "32")]
     #[test]
@@ -102,6 +153,10 @@ mod tests {
         assert_size!(Result_, 236);
         assert_size!(Complete, 1);
         assert_size!(Fin, 44);
+        assert_size!(MetadataQuery, 0);
+        assert_size!(Start, 28);
+        assert_size!(End, 28);
+        assert_size!(MetadataResult, 56);
     }
 
     #[cfg(target_pointer_width = "64")]
@@ -112,6 +167,10 @@ mod tests {
         assert_size!(Result_, 456);
         assert_size!(Complete, 1);
         assert_size!(Fin, 88);
+        assert_size!(MetadataQuery, 0);
+        assert_size!(Start, 40);
+        assert_size!(End, 40);
+        assert_size!(MetadataResult, 80);
     }
 
     #[test]
@@ -255,7 +314,7 @@ mod tests {
 
     #[test]
     fn test_serialize_result() {
-        let reference: Element = "<result xmlns='urn:xmpp:mam:2' queryid='f27' id='28482-98726-73623'><forwarded xmlns='urn:xmpp:forward:0'><delay xmlns='urn:xmpp:delay' stamp='2002-09-10T23:08:25+00:00'/><message xmlns='jabber:client' to='juliet@capulet.example/balcony' from='romeo@montague.example/home'/></forwarded></result>"
+        let reference: Element = "<result xmlns='urn:xmpp:mam:2' queryid='f27' id='28482-98726-73623'><forwarded xmlns='urn:xmpp:forward:0'><delay xmlns='urn:xmpp:delay' stamp='2002-09-10T23:08:25Z'/><message xmlns='jabber:client' to='juliet@capulet.example/balcony' from='romeo@montague.example/home'/></forwarded></result>"
         .parse()
         .unwrap();
 
@@ -293,4 +352,52 @@ mod tests {
         let serialized: Element = fin.into();
         assert_eq!(serialized, reference);
     }
+
+    #[test]
+    fn test_metadata() {
+        let elem: Element = "<metadata xmlns='urn:xmpp:mam:2'/>".parse().unwrap();
+        MetadataQuery::try_from(elem.clone()).unwrap();
+        let empty = MetadataResult::try_from(elem.clone()).unwrap();
+        assert_eq!(empty.start, None);
+        assert_eq!(empty.end, None);
+        assert_eq!(Element::from(MetadataQuery), elem);
+
+        let elem: Element = "<metadata xmlns='urn:xmpp:mam:2'><start id='YWxwaGEg' timestamp='2008-08-22T21:09:04Z'/><end id='b21lZ2Eg' timestamp='2020-04-20T14:34:21Z'/></metadata>"
+            .parse()
+            .unwrap();
+        let metadata = MetadataResult::try_from(elem.clone()).unwrap();
+        let start = metadata.start.clone().unwrap();
+        assert_eq!(start.id, "YWxwaGEg");
+        assert_eq!(
+            start.timestamp,
+            DateTime::from_str("2008-08-22T21:09:04Z").unwrap()
+        );
+        assert_eq!(metadata.end.clone().unwrap().id, "b21lZ2Eg");
+        let elem2 = Element::from(metadata.clone());
+        assert_eq!(elem, elem2);
+        assert_eq!(MetadataResult::try_from(elem2).unwrap(), metadata);
+    }
+
+    #[test]
+    fn test_invalid_metadata() {
+        let elem: Element = "<metadata xmlns='urn:xmpp:mam:2'><end id='b21lZ2Eg'/></metadata>"
+            .parse()
+            .unwrap();
+        let error = MetadataResult::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Required attribute 'timestamp' missing.");
+
+        let elem: Element = "<metadata xmlns='urn:xmpp:mam:2'><start id='a' timestamp='2008-08-22T21:09:04Z'/></metadata>"
+            .parse()
+            .unwrap();
+        let error = MetadataQuery::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Unknown child in metadata element.");
+    }
 }
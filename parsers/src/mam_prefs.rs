@@ -26,8 +26,18 @@ generate_attribute!(
     }
 );
 
+generate_empty_element!(
+    /// Requests the archiving preferences of the user, answered with their
+    /// [Prefs].
+    PrefsQuery,
+    "prefs",
+    MAM
+);
+
+impl IqGetPayload for PrefsQuery {}
+
 /// Controls the archiving preferences of the user.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Prefs {
     /// The default preference for JIDs in neither
     /// [always](#structfield.always) or [never](#structfield.never) lists.
@@ -115,6 +125,7 @@ mod tests {
     #[test]
     fn test_size() {
         assert_size!(DefaultPrefs, 1);
+        assert_size!(PrefsQuery, 0);
         assert_size!(Prefs, 28);
     }
 
@@ -122,9 +133,25 @@ mod tests {
     #[test]
     fn test_size() {
         assert_size!(DefaultPrefs, 1);
+        assert_size!(PrefsQuery, 0);
         assert_size!(Prefs, 56);
     }
 
+    #[test]
+    fn test_prefs_query() {
+        let elem: Element = "<prefs xmlns='urn:xmpp:mam:2'/>".parse().unwrap();
+        PrefsQuery::try_from(elem.clone()).unwrap();
+        assert_eq!(Element::from(PrefsQuery), elem);
+
+        // Preferences always have a default.
+        let error = Prefs::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Required attribute 'default' missing.");
+    }
+
     #[test]
     fn test_prefs_get() {
         let elem: Element = "<prefs xmlns='urn:xmpp:mam:2' default='always'/>"
@@ -171,5 +198,6 @@ mod tests {
         assert_eq!(prefs.default_, prefs2.default_);
         assert_eq!(prefs.always, prefs2.always);
         assert_eq!(prefs.never, prefs2.never);
+        assert_eq!(prefs, prefs2);
     }
 }
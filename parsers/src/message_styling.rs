@@ -0,0 +1,422 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Message styling is a plain text convention, so besides the hint to
+//! disable it, this module parses message bodies into the spans a user
+//! interface should style.
+
+use crate::message::{Message, MessagePayload};
+use crate::ns;
+use std::ops::Range;
+
+generate_empty_element!(
+    /// Asks the recipient not to style the body of this message.
+    Unstyled,
+    "unstyled",
+    STYLING
+);
+
+impl MessagePayload for Unstyled {}
+
+/// Whether `message` asks not to be styled, with an [`Unstyled`] hint.
+pub fn is_unstyled(message: &Message) -> bool {
+    message
+        .payloads
+        .iter()
+        .any(|payload| payload.is("unstyled", ns::STYLING))
+}
+
+/// How a span of text is styled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    /// Between `*`, usually rendered in bold.
+    Strong,
+
+    /// Between `_`, usually rendered in italics.
+    Emphasis,
+
+    /// Between `~`, rendered struck through.
+    Strikethrough,
+
+    /// Between `` ` ``, rendered in a monospace font, and never styled
+    /// further.
+    Preformatted,
+
+    /// Lines between two lines starting with ```` ``` ````, rendered in a
+    /// monospace font and never styled further.
+    PreformattedBlock,
+
+    /// Lines starting with `>`, which may themselves contain any block.
+    BlockQuote,
+}
+
+/// A styled part of a message body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    /// Where the span is in the body, in bytes, its styling directives
+    /// included.
+    pub range: Range<usize>,
+
+    /// How to style it.
+    pub style: Style,
+
+    /// Where its styling directives are in the body: the two delimiters
+    /// of an inline span, the fences of a preformatted block, or the
+    /// `>` starting each line of a block quote along with the whitespace
+    /// following it.  A user interface may hide or dim those.
+    pub directives: Vec<Range<usize>>,
+
+    /// The spans styled inside of this one.
+    pub children: Vec<Span>,
+}
+
+/// Parses the styling of `body`, the spans being ordered by position.
+///
+/// Text which isn’t in any span is plain.
+pub fn parse(body: &str) -> Vec<Span> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for line in body.split('\n') {
+        lines.push((start, line));
+        start += line.len() + 1;
+    }
+    parse_blocks(&lines)
+}
+
+/// Removes every styling directive from `body`, for places where styles
+/// can’t be rendered, such as notifications.
+pub fn strip(body: &str) -> String {
+    fn collect(spans: &[Span], directives: &mut Vec<Range<usize>>) {
+        for span in spans {
+            directives.extend(span.directives.iter().cloned());
+            collect(&span.children, directives);
+        }
+    }
+    let mut directives = Vec::new();
+    collect(&parse(body), &mut directives);
+    directives.sort_by_key(|range| range.start);
+
+    let mut stripped = String::with_capacity(body.len());
+    let mut position = 0;
+    for range in directives {
+        if range.start > position {
+            stripped.push_str(&body[position..range.start]);
+        }
+        position = position.max(range.end);
+    }
+    stripped.push_str(&body[position..]);
+    stripped
+}
+
+/// Parses the blocks made of `lines`, each along with its offset in the
+/// body.
+fn parse_blocks(lines: &[(usize, &str)]) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let (start, line) = lines[i];
+        if line.starts_with("```") {
+            // The rest of the opening line may be a hint about the content, which isn’t shown.
+            let mut opening = start..start + line.len();
+            if i + 1 < lines.len() {
+                opening.end += 1;
+            }
+            let mut directives = Vec::new();
+            directives.push(opening);
+            let closing = lines[i + 1..]
+                .iter()
+                .position(|(_, line)| *line == "```")
+                .map(|position| i + 1 + position);
+            let last = closing.unwrap_or(lines.len() - 1);
+            if let Some(closing) = closing {
+                let (closing_start, _) = lines[closing];
+                let (previous_start, previous) = lines[closing - 1];
+                // Hide the line break before the closing fence too, if nothing stands in between.
+                let end_of_previous = previous_start + previous.len();
+                if closing - 1 > i && end_of_previous + 1 == closing_start {
+                    directives.push(end_of_previous..closing_start + 3);
+                } else {
+                    directives.push(closing_start..closing_start + 3);
+                }
+            }
+            let (last_start, last_line) = lines[last];
+            spans.push(Span {
+                range: start..last_start + last_line.len(),
+                style: Style::PreformattedBlock,
+                directives,
+                children: Vec::new(),
+            });
+            i = last + 1;
+        } else if line.starts_with('>') {
+            let mut directives = Vec::new();
+            let mut quoted = Vec::new();
+            let mut end = i;
+            while end < lines.len() && lines[end].1.starts_with('>') {
+                let (start, line) = lines[end];
+                let content = line[1..].trim_start();
+                let prefix = line.len() - content.len();
+                directives.push(start..start + prefix);
+                quoted.push((start + prefix, content));
+                end += 1;
+            }
+            let (last_start, last_line) = lines[end - 1];
+            spans.push(Span {
+                range: start..last_start + last_line.len(),
+                style: Style::BlockQuote,
+                directives,
+                children: parse_blocks(&quoted),
+            });
+            i = end;
+        } else {
+            spans.extend(parse_spans(start, line, None));
+            i += 1;
+        }
+    }
+    spans
+}
+
+fn directive_style(directive: u8) -> Option<Style> {
+    match directive {
+        b'*' => Some(Style::Strong),
+        b'_' => Some(Style::Emphasis),
+        b'~' => Some(Style::Strikethrough),
+        b'`' => Some(Style::Preformatted),
+        _ => None,
+    }
+}
+
+/// Parses the inline spans of `text`, which starts at `offset` in the
+/// body and right after the opening directive `parent` if in a span.
+fn parse_spans(offset: usize, text: &str, parent: Option<u8>) -> Vec<Span> {
+    let bytes = text.as_bytes();
+    let mut spans = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let directive = bytes[i];
+        let style = match directive_style(directive) {
+            Some(style) => style,
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+        // An opening directive comes first, after whitespace or after another opening
+        // directive, and never before whitespace.
+        let after_opening = match text[..i].chars().next_back() {
+            None => parent != Some(directive),
+            Some(previous) => previous.is_whitespace(),
+        };
+        let before_text = text[i + 1..]
+            .chars()
+            .next()
+            .map(|next| !next.is_whitespace())
+            .unwrap_or(false);
+        if !(after_opening && before_text) {
+            i += 1;
+            continue;
+        }
+        // The closing directive is the first one which isn’t after whitespace, and leaves
+        // something to style.
+        let closing = (i + 2..bytes.len()).find(|&j| {
+            bytes[j] == directive
+                && !text[..j]
+                    .chars()
+                    .next_back()
+                    .map(char::is_whitespace)
+                    .unwrap_or(true)
+        });
+        let closing = match closing {
+            Some(closing) => closing,
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+        let children = match style {
+            Style::Preformatted => Vec::new(),
+            _ => parse_spans(offset + i + 1, &text[i + 1..closing], Some(directive)),
+        };
+        spans.push(Span {
+            range: offset + i..offset + closing + 1,
+            style,
+            directives: vec![
+                offset + i..offset + i + 1,
+                offset + closing..offset + closing + 1,
+            ],
+            children,
+        });
+        i = closing + 1;
+    }
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "disable-validation"))]
+    use crate::util::error::Error;
+    use crate::Element;
+    use std::convert::TryFrom;
+
+    /// Every span of `body` depth first, as its style and text.
+    fn styles(body: &str) -> Vec<(Style, &str)> {
+        fn walk<'a>(body: &'a str, spans: &[Span], out: &mut Vec<(Style, &'a str)>) {
+            for span in spans {
+                out.push((span.style, &body[span.range.clone()]));
+                walk(body, &span.children, out);
+            }
+        }
+        let mut out = Vec::new();
+        walk(body, &parse(body), &mut out);
+        out
+    }
+
+    #[test]
+    fn test_size() {
+        assert_size!(Unstyled, 0);
+    }
+
+    #[test]
+    fn test_unstyled() {
+        let elem: Element = "<unstyled xmlns='urn:xmpp:styling:0'/>".parse().unwrap();
+        Unstyled::try_from(elem).unwrap();
+
+        let elem: Element = "<message xmlns='jabber:client'><body>*raw*</body><unstyled xmlns='urn:xmpp:styling:0'/></message>".parse().unwrap();
+        assert!(is_unstyled(&Message::try_from(elem).unwrap()));
+        let elem: Element = "<message xmlns='jabber:client'><body>*styled*</body></message>"
+            .parse()
+            .unwrap();
+        assert!(!is_unstyled(&Message::try_from(elem).unwrap()));
+    }
+
+    #[cfg(not(feature = "disable-validation"))]
+    #[test]
+    fn test_invalid_child() {
+        let elem: Element = "<unstyled xmlns='urn:xmpp:styling:0'><coucou/></unstyled>"
+            .parse()
+            .unwrap();
+        let error = Unstyled::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Unknown child in unstyled element.");
+    }
+
+    #[test]
+    fn test_spans() {
+        assert_eq!(styles("I am *fully* awake."), [(Style::Strong, "*fully*")]);
+        assert_eq!(
+            styles("_emphasis_ ~gone~ `x = *y*`"),
+            [
+                (Style::Emphasis, "_emphasis_"),
+                (Style::Strikethrough, "~gone~"),
+                // Nothing gets styled inside preformatted text.
+                (Style::Preformatted, "`x = *y*`"),
+            ]
+        );
+        assert_eq!(
+            styles("*strong _and emphasised_*"),
+            [
+                (Style::Strong, "*strong _and emphasised_*"),
+                (Style::Emphasis, "_and emphasised_"),
+            ]
+        );
+        assert_eq!(
+            styles("_*both*_"),
+            [(Style::Emphasis, "_*both*_"), (Style::Strong, "*both*")]
+        );
+        // The first valid closing directive wins.
+        assert_eq!(styles("*one *two* three*"), [(Style::Strong, "*one *two*")]);
+        assert_eq!(styles("*a*"), [(Style::Strong, "*a*")]);
+        assert_eq!(styles("ünï *cödé*"), [(Style::Strong, "*cödé*")]);
+
+        let body = "Hi *there*";
+        let span = &parse(body)[0];
+        assert_eq!(span.range, 3..10);
+        assert_eq!(span.directives, [3..4, 9..10]);
+    }
+
+    #[test]
+    fn test_not_styled() {
+        for body in &[
+            // Nothing between the directives.
+            "**",
+            "__ and ~~",
+            // Whitespace right inside the directives.
+            "* not strong*",
+            "*not strong *",
+            // Directives in the middle of a word.
+            "snake_case_name",
+            "2*3*4",
+            // Spans don’t span lines.
+            "*not\nstrong*",
+            // Unmatched directives.
+            "*not strong",
+            "`not preformatted",
+            "a > b",
+        ] {
+            assert_eq!(styles(body), [], "{:?}", body);
+            assert_eq!(strip(body), *body);
+        }
+    }
+
+    #[test]
+    fn test_preformatted_block() {
+        let body = "Look:\n```rust\nlet _x = *y*;\n```\nDone *now*";
+        assert_eq!(
+            styles(body),
+            [
+                (Style::PreformattedBlock, "```rust\nlet _x = *y*;\n```"),
+                (Style::Strong, "*now*"),
+            ]
+        );
+        assert_eq!(strip(body), "Look:\nlet _x = *y*;\nDone now");
+
+        // Without a closing fence, the block lasts until the end.
+        let body = "```\n*a*\n``` not a fence\n*b*";
+        assert_eq!(styles(body), [(Style::PreformattedBlock, body)]);
+        assert_eq!(strip(body), "*a*\n``` not a fence\n*b*");
+
+        // A fence must start its line.
+        assert!(parse("not ```\n*a*")
+            .iter()
+            .all(|span| span.style != Style::PreformattedBlock));
+    }
+
+    #[test]
+    fn test_block_quote() {
+        let body = "> To be, or *not* to be\n>> that is\n> > the question\nI agree.";
+        assert_eq!(
+            styles(body),
+            [
+                (
+                    Style::BlockQuote,
+                    "> To be, or *not* to be\n>> that is\n> > the question"
+                ),
+                (Style::Strong, "*not*"),
+                // Both “>>” and “> >” quote twice.
+                (Style::BlockQuote, "> that is\n> > the question"),
+            ]
+        );
+        assert_eq!(
+            strip(body),
+            "To be, or not to be\nthat is\nthe question\nI agree."
+        );
+
+        // A preformatted block ends along with its quote.
+        let body = "> ```\n> *a*\n*b*";
+        assert_eq!(
+            styles(body),
+            [
+                (Style::BlockQuote, "> ```\n> *a*"),
+                (Style::PreformattedBlock, "```\n> *a*"),
+                (Style::Strong, "*b*"),
+            ]
+        );
+        assert_eq!(strip(body), "*a*\nb");
+    }
+}
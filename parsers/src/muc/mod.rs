@@ -10,5 +10,9 @@ pub mod muc;
 /// The http://jabber.org/protocol/muc#user protocol.
 pub mod user;
 
+/// The http://jabber.org/protocol/muc#owner protocol.
+pub mod owner;
+
 pub use self::muc::Muc;
+pub use self::owner::MucOwner;
 pub use self::user::MucUser;
@@ -0,0 +1,97 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::data_forms::DataForm;
+use crate::iq::{IqGetPayload, IqResultPayload, IqSetPayload};
+
+generate_element!(
+    /// Request or submission of the configuration of a room, sent by one of
+    /// its owners.
+    #[derive(Default)]
+    MucOwner, "query", MUC_OWNER,
+    children: [
+        /// The configuration form, absent when requesting it.
+        form: Option<DataForm> = ("x", DATA_FORMS) => DataForm
+    ]
+);
+
+impl IqGetPayload for MucOwner {}
+impl IqSetPayload for MucOwner {}
+impl IqResultPayload for MucOwner {}
+
+impl MucOwner {
+    /// Create an empty query, to request the configuration form of a room.
+    pub fn new() -> MucOwner {
+        MucOwner::default()
+    }
+
+    /// Create a query carrying a configuration form.
+    pub fn with_form(form: DataForm) -> MucOwner {
+        MucOwner { form: Some(form) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_forms::DataFormType;
+    use crate::util::error::Error;
+    use crate::Element;
+    use std::convert::TryFrom;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(MucOwner, 52);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(MucOwner, 104);
+    }
+
+    #[test]
+    fn test_request() {
+        let elem: Element = "<query xmlns='http://jabber.org/protocol/muc#owner'/>"
+            .parse()
+            .unwrap();
+        let query = MucOwner::try_from(elem.clone()).unwrap();
+        assert!(query.form.is_none());
+
+        let elem2 = Element::from(MucOwner::new());
+        assert_eq!(elem, elem2);
+    }
+
+    #[test]
+    fn test_form() {
+        let elem: Element = "<query xmlns='http://jabber.org/protocol/muc#owner'><x xmlns='jabber:x:data' type='form'><field var='FORM_TYPE' type='hidden'><value>http://jabber.org/protocol/muc#roomconfig</value></field><field var='muc#roomconfig_roomname' type='text-single' label='Natural-Language Room Name'/></x></query>"
+            .parse()
+            .unwrap();
+        let query = MucOwner::try_from(elem).unwrap();
+        let form = query.form.unwrap();
+        assert_eq!(form.type_, DataFormType::Form);
+        assert_eq!(
+            form.form_type.as_deref(),
+            Some("http://jabber.org/protocol/muc#roomconfig")
+        );
+        assert_eq!(form.fields.len(), 1);
+        assert_eq!(form.fields[0].var, "muc#roomconfig_roomname");
+    }
+
+    #[test]
+    fn test_invalid_child() {
+        let elem: Element = "<query xmlns='http://jabber.org/protocol/muc#owner'><coucou/></query>"
+            .parse()
+            .unwrap();
+        let error = MucOwner::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Unknown child in query element.");
+    }
+}
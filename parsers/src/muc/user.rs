@@ -8,70 +8,204 @@
 use crate::ns;
 use crate::util::error::Error;
 use crate::Element;
-use jid::FullJid;
+use jid::{FullJid, Jid};
 use std::convert::TryFrom;
 
-generate_attribute_enum!(
 /// Lists all of the possible status codes used in MUC presences.
-Status, "status", MUC_USER, "code", {
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
     /// Inform user that any occupant is allowed to see the user's full JID
-    NonAnonymousRoom => 100,
+    NonAnonymousRoom,
 
     /// Inform user that his or her affiliation changed while not in the room
-    AffiliationChange => 101,
+    AffiliationChange,
 
     /// Inform occupants that room now shows unavailable members
-    ConfigShowsUnavailableMembers => 102,
+    ConfigShowsUnavailableMembers,
 
     /// Inform occupants that room now does not show unavailable members
-    ConfigHidesUnavailableMembers => 103,
+    ConfigHidesUnavailableMembers,
 
     /// Inform occupants that a non-privacy-related room configuration change has occurred
-    ConfigNonPrivacyRelated => 104,
+    ConfigNonPrivacyRelated,
 
     /// Inform user that presence refers to itself
-    SelfPresence => 110,
+    SelfPresence,
 
     /// Inform occupants that room logging is now enabled
-    ConfigRoomLoggingEnabled => 170,
+    ConfigRoomLoggingEnabled,
 
     /// Inform occupants that room logging is now disabled
-    ConfigRoomLoggingDisabled => 171,
+    ConfigRoomLoggingDisabled,
 
     /// Inform occupants that the room is now non-anonymous
-    ConfigRoomNonAnonymous => 172,
+    ConfigRoomNonAnonymous,
 
     /// Inform occupants that the room is now semi-anonymous
-    ConfigRoomSemiAnonymous => 173,
+    ConfigRoomSemiAnonymous,
+
+    /// Inform occupants that the room is now fully-anonymous
+    ConfigRoomFullyAnonymous,
 
     /// Inform user that a new room has been created
-    RoomHasBeenCreated => 201,
+    RoomHasBeenCreated,
 
     /// Inform user that service has assigned or modified occupant's roomnick
-    AssignedNick => 210,
+    AssignedNick,
 
     /// Inform user that he or she has been banned from the room
-    Banned => 301,
+    Banned,
 
     /// Inform all occupants of new room nickname
-    NewNick => 303,
+    NewNick,
 
     /// Inform user that he or she has been kicked from the room
-    Kicked => 307,
+    Kicked,
 
     /// Inform user that he or she is being removed from the room
     /// because of an affiliation change
-    RemovalFromRoom => 321,
+    RemovalFromRoom,
 
     /// Inform user that he or she is being removed from the room
     /// because the room has been changed to members-only and the
     /// user is not a member
-    ConfigMembersOnly => 322,
+    ConfigMembersOnly,
 
     /// Inform user that he or she is being removed from the room
     /// because the MUC service is being shut down
-    ServiceShutdown => 332,
-});
+    ServiceShutdown,
+
+    /// Inform user that he or she is being removed from the room
+    /// because of an error, such as their server not answering
+    RemovalOnError,
+
+    /// A status code this library doesn’t know about, kept as is
+    Unknown(u16),
+}
+
+/// Why an occupant got removed from a room, as told by the status codes
+/// of its last presence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalKind {
+    /// Banned from the room (301).
+    Banned,
+
+    /// Kicked from the room (307).
+    Kicked,
+
+    /// No longer allowed in after an affiliation change (321).
+    AffiliationChange,
+
+    /// Not a member of a room which became members-only (322).
+    MembersOnly,
+
+    /// The MUC service is being shut down (332).
+    ServiceShutdown,
+
+    /// Something went wrong, such as the occupant’s server not
+    /// answering (333).
+    Error,
+}
+
+impl Status {
+    /// The status code for this value.
+    pub fn code(&self) -> u16 {
+        match self {
+            Status::NonAnonymousRoom => 100,
+            Status::AffiliationChange => 101,
+            Status::ConfigShowsUnavailableMembers => 102,
+            Status::ConfigHidesUnavailableMembers => 103,
+            Status::ConfigNonPrivacyRelated => 104,
+            Status::SelfPresence => 110,
+            Status::ConfigRoomLoggingEnabled => 170,
+            Status::ConfigRoomLoggingDisabled => 171,
+            Status::ConfigRoomNonAnonymous => 172,
+            Status::ConfigRoomSemiAnonymous => 173,
+            Status::ConfigRoomFullyAnonymous => 174,
+            Status::RoomHasBeenCreated => 201,
+            Status::AssignedNick => 210,
+            Status::Banned => 301,
+            Status::NewNick => 303,
+            Status::Kicked => 307,
+            Status::RemovalFromRoom => 321,
+            Status::ConfigMembersOnly => 322,
+            Status::ServiceShutdown => 332,
+            Status::RemovalOnError => 333,
+            Status::Unknown(code) => *code,
+        }
+    }
+
+    /// The value for this status code, [`Status::Unknown`] if it isn’t
+    /// registered.
+    pub fn from_code(code: u16) -> Status {
+        match code {
+            100 => Status::NonAnonymousRoom,
+            101 => Status::AffiliationChange,
+            102 => Status::ConfigShowsUnavailableMembers,
+            103 => Status::ConfigHidesUnavailableMembers,
+            104 => Status::ConfigNonPrivacyRelated,
+            110 => Status::SelfPresence,
+            170 => Status::ConfigRoomLoggingEnabled,
+            171 => Status::ConfigRoomLoggingDisabled,
+            172 => Status::ConfigRoomNonAnonymous,
+            173 => Status::ConfigRoomSemiAnonymous,
+            174 => Status::ConfigRoomFullyAnonymous,
+            201 => Status::RoomHasBeenCreated,
+            210 => Status::AssignedNick,
+            301 => Status::Banned,
+            303 => Status::NewNick,
+            307 => Status::Kicked,
+            321 => Status::RemovalFromRoom,
+            322 => Status::ConfigMembersOnly,
+            332 => Status::ServiceShutdown,
+            333 => Status::RemovalOnError,
+            code => Status::Unknown(code),
+        }
+    }
+
+    /// Whether the presence refers to the occupant receiving it.
+    pub fn is_self_presence(&self) -> bool {
+        *self == Status::SelfPresence
+    }
+
+    /// Whether the occupant is no longer in the room against its will.
+    pub fn indicates_removal(&self) -> bool {
+        self.removal_reason().is_some()
+    }
+
+    /// Why the occupant got removed from the room, if it did.
+    pub fn removal_reason(&self) -> Option<RemovalKind> {
+        Some(match self {
+            Status::Banned => RemovalKind::Banned,
+            Status::Kicked => RemovalKind::Kicked,
+            Status::RemovalFromRoom => RemovalKind::AffiliationChange,
+            Status::ConfigMembersOnly => RemovalKind::MembersOnly,
+            Status::ServiceShutdown => RemovalKind::ServiceShutdown,
+            Status::RemovalOnError => RemovalKind::Error,
+            _ => return None,
+        })
+    }
+}
+
+impl TryFrom<Element> for Status {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<Status, Error> {
+        check_ns_only!(elem, "status", MUC_USER);
+        check_no_children!(elem, "status");
+        check_no_unknown_attributes!(elem, "status", ["code"]);
+        let code: u16 = get_attr!(elem, "code", Required);
+        Ok(Status::from_code(code))
+    }
+}
+
+impl From<Status> for Element {
+    fn from(status: Status) -> Element {
+        Element::builder("status", ns::MUC_USER)
+            .attr("code", status.code())
+            .build()
+    }
+}
 
 /// Optional <actor/> element used in <item/> elements inside presence stanzas of type
 /// "unavailable" that are sent to users who are kick or banned, as well as within IQs for tracking
@@ -222,6 +356,24 @@ impl Item {
     }
 }
 
+generate_element!(
+    /// An invitation to join a room, sent to the room which then relays it
+    /// to the invitee.
+    Invite, "invite", MUC_USER, attributes: [
+        /// Who sent the invitation, as relayed by the room.
+        from: Option<Jid> = "from",
+
+        /// Who to invite, as sent to the room.
+        to: Option<Jid> = "to",
+    ], children: [
+        /// Why the invitee should join.
+        reason: Option<Reason> = ("reason", MUC_USER) => Reason,
+
+        /// Whether this continues a one-to-one discussion.
+        continue_: Option<Continue> = ("continue", MUC_USER) => Continue
+    ]
+);
+
 generate_element!(
     /// The main muc#user element.
     MucUser, "x", MUC_USER, children: [
@@ -229,13 +381,21 @@ generate_element!(
         status: Vec<Status> = ("status", MUC_USER) => Status,
 
         /// List of items.
-        items: Vec<Item> = ("item", MUC_USER) => Item
-    ]
+        items: Vec<Item> = ("item", MUC_USER) => Item,
+
+        /// Invitations to join this room.
+        invites: Vec<Invite> = ("invite", MUC_USER) => Invite,
+
+        /// The password to join this room, along with an invitation.
+        password: Option<String> = ("password", MUC_USER) => String
+    ],
+    unknown_children: preserve
 );
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::str::FromStr;
 
     #[test]
     fn test_simple() {
@@ -268,20 +428,59 @@ mod tests {
     }
 
     #[test]
-    fn test_invalid_child() {
+    fn test_invite() {
         let elem: Element = "
             <x xmlns='http://jabber.org/protocol/muc#user'>
-                <coucou/>
+                <invite from='crone1@shakespeare.lit/desktop'>
+                    <reason>Hey Hecate, this is the place for all good witches!</reason>
+                </invite>
+                <password>cauldronburn</password>
             </x>
         "
         .parse()
         .unwrap();
-        let error = MucUser::try_from(elem).unwrap_err();
-        let message = match error {
-            Error::ParseError(string) => string,
-            _ => panic!(),
-        };
-        assert_eq!(message, "Unknown child in x element.");
+        let muc_user = MucUser::try_from(elem).unwrap();
+        assert_eq!(muc_user.invites.len(), 1);
+        let invite = &muc_user.invites[0];
+        assert_eq!(
+            invite.from,
+            Some(Jid::from_str("crone1@shakespeare.lit/desktop").unwrap())
+        );
+        assert_eq!(invite.to, None);
+        assert_eq!(
+            invite.reason,
+            Some(Reason(String::from(
+                "Hey Hecate, this is the place for all good witches!"
+            )))
+        );
+        assert_eq!(muc_user.password.as_deref(), Some("cauldronburn"));
+
+        let elem: Element = "<x xmlns='http://jabber.org/protocol/muc#user'><invite to='hecate@shakespeare.lit'/><invite to='wiccarocks@shakespeare.lit/laptop'/></x>"
+            .parse()
+            .unwrap();
+        let elem1 = elem.clone();
+        let muc_user = MucUser::try_from(elem).unwrap();
+        assert_eq!(muc_user.invites.len(), 2);
+        let elem2 = Element::from(muc_user);
+        assert_eq!(elem1, elem2);
+    }
+
+    #[test]
+    fn test_unknown_children() {
+        let elem: Element = "<x xmlns='http://jabber.org/protocol/muc#user'><coucou xmlns='urn:example:coucou'/><status code='110'/><hibou xmlns='urn:example:hibou'>chouette</hibou></x>"
+            .parse()
+            .unwrap();
+        let muc_user = MucUser::try_from(elem).unwrap();
+        assert_eq!(muc_user.status, [Status::SelfPresence]);
+        assert_eq!(muc_user.unknown.len(), 2);
+        assert!(muc_user.unknown[0].is("coucou", "urn:example:coucou"));
+        assert_eq!(muc_user.unknown[1].text(), "chouette");
+
+        // The unknown children come after the known ones, in their original order.
+        let elem: Element = "<x xmlns='http://jabber.org/protocol/muc#user'><status code='110'/><coucou xmlns='urn:example:coucou'/><hibou xmlns='urn:example:hibou'>chouette</hibou></x>"
+            .parse()
+            .unwrap();
+        assert_eq!(Element::from(muc_user), elem);
     }
 
     #[test]
@@ -294,6 +493,9 @@ mod tests {
         let muc = MucUser {
             status: vec![],
             items: vec![],
+            invites: vec![],
+            password: None,
+            unknown: vec![],
         };
         let elem2 = muc.into();
         assert_eq!(elem, elem2);
@@ -370,18 +572,69 @@ mod tests {
     }
 
     #[test]
-    fn test_status_invalid_code() {
+    fn test_status_unknown_code() {
         let elem: Element = "
             <status xmlns='http://jabber.org/protocol/muc#user' code='666'/>
         "
         .parse()
         .unwrap();
-        let error = Status::try_from(elem).unwrap_err();
-        let message = match error {
-            Error::ParseError(string) => string,
-            _ => panic!(),
-        };
-        assert_eq!(message, "Invalid status code value.");
+        let status = Status::try_from(elem.clone()).unwrap();
+        assert_eq!(status, Status::Unknown(666));
+        assert_eq!(Element::from(status), elem);
+    }
+
+    #[test]
+    fn test_status_mixed_codes() {
+        let elem: Element = "<x xmlns='http://jabber.org/protocol/muc#user'><status code='110'/><status code='174'/><status code='999'/><status code='307'/></x>"
+            .parse()
+            .unwrap();
+        let muc_user = MucUser::try_from(elem.clone()).unwrap();
+        assert_eq!(
+            muc_user.status,
+            [
+                Status::SelfPresence,
+                Status::ConfigRoomFullyAnonymous,
+                Status::Unknown(999),
+                Status::Kicked
+            ]
+        );
+        assert_eq!(Element::from(muc_user), elem);
+    }
+
+    #[test]
+    fn test_status_predicates() {
+        // Code, whether it refers to ourself, and why we got removed.
+        let table = [
+            (100, false, None),
+            (101, false, None),
+            (102, false, None),
+            (103, false, None),
+            (104, false, None),
+            (110, true, None),
+            (170, false, None),
+            (171, false, None),
+            (172, false, None),
+            (173, false, None),
+            (174, false, None),
+            (201, false, None),
+            (210, false, None),
+            (301, false, Some(RemovalKind::Banned)),
+            (303, false, None),
+            (307, false, Some(RemovalKind::Kicked)),
+            (321, false, Some(RemovalKind::AffiliationChange)),
+            (322, false, Some(RemovalKind::MembersOnly)),
+            (332, false, Some(RemovalKind::ServiceShutdown)),
+            (333, false, Some(RemovalKind::Error)),
+            (999, false, None),
+        ];
+        for (code, self_presence, removal) in table.iter().cloned() {
+            let status = Status::from_code(code);
+            assert_eq!(status.code(), code);
+            assert_eq!(status.is_self_presence(), self_presence, "{}", code);
+            assert_eq!(status.removal_reason(), removal, "{}", code);
+            assert_eq!(status.indicates_removal(), removal.is_some(), "{}", code);
+        }
+        assert_eq!(Status::from_code(999), Status::Unknown(999));
     }
 
     #[test]
@@ -5,262 +5,409 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-/// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
-pub const JABBER_CLIENT: &str = "jabber:client";
-/// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
-pub const XMPP_STANZAS: &str = "urn:ietf:params:xml:ns:xmpp-stanzas";
-/// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
-pub const STREAM: &str = "http://etherx.jabber.org/streams";
-/// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
-pub const TLS: &str = "urn:ietf:params:xml:ns:xmpp-tls";
-/// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
-pub const SASL: &str = "urn:ietf:params:xml:ns:xmpp-sasl";
-/// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
-pub const BIND: &str = "urn:ietf:params:xml:ns:xmpp-bind";
-
-/// RFC 6121: Extensible Messaging and Presence Protocol (XMPP): Instant Messaging and Presence
-pub const ROSTER: &str = "jabber:iq:roster";
-
-/// RFC 7395: An Extensible Messaging and Presence Protocol (XMPP) Subprotocol for WebSocket
-pub const WEBSOCKET: &str = "urn:ietf:params:xml:ns:xmpp-framing";
+//! The namespaces of every protocol this crate implements, along with how much of them, see
+//! [`supported_protocols`].
+
+/// How much of a protocol the parsers of this crate implement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Support {
+    /// Everything it defines can be parsed and serialised.
+    Complete,
+    /// Only some of it, the module implementing it tells what is missing.
+    Partial,
+}
+
+/// A namespace declared in this module, along with the specification defining it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolInfo {
+    /// The name of its constant in this module, such as `DISCO_INFO`.
+    pub constant: &'static str,
+    /// The namespace itself.
+    pub ns: &'static str,
+    /// How much of its specification is implemented.
+    pub support: Support,
+    /// Its documentation, naming the specification.
+    doc: &'static str,
+}
+
+impl ProtocolInfo {
+    /// The specification defining this namespace, such as `XEP-0030: Service Discovery`.
+    pub fn spec(&self) -> &'static str {
+        self.doc.trim()
+    }
+
+    /// The number of the XEP defining this namespace, `None` for an RFC.
+    pub fn xep(&self) -> Option<u16> {
+        let spec = self.spec().strip_prefix("XEP-")?;
+        spec.split(':').next()?.parse().ok()
+    }
+
+    /// The name of the specification, such as `Service Discovery`.
+    pub fn name(&self) -> &'static str {
+        let spec = self.spec();
+        match spec.find(": ") {
+            Some(index) => &spec[index + 2..],
+            None => spec,
+        }
+    }
+}
+
+/// Declares each namespace as a constant, documented with the specification defining it, and
+/// lists them all in [`supported_protocols`].
+macro_rules! namespaces {
+    ($(
+        #[doc = $doc:literal]
+        $name:ident = $ns:literal, $support:ident;
+    )*) => {
+        $(
+            #[doc = $doc]
+            pub const $name: &str = $ns;
+        )*
+
+        static PROTOCOLS: &[ProtocolInfo] = &[$(
+            ProtocolInfo {
+                constant: stringify!($name),
+                ns: $name,
+                support: Support::$support,
+                doc: $doc,
+            },
+        )*];
+    };
+}
+
+/// Every namespace declared in this module, in the order of their specifications.
+///
+/// A namespace may appear more than once under different constants, such as
+/// [`COMPONENT`] and [`COMPONENT_ACCEPT`].
+pub fn supported_protocols() -> &'static [ProtocolInfo] {
+    PROTOCOLS
+}
+
+namespaces! {
+    /// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
+    JABBER_CLIENT = "jabber:client", Complete;
+    /// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
+    JABBER_SERVER = "jabber:server", Complete;
+    /// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
+    XMPP_STANZAS = "urn:ietf:params:xml:ns:xmpp-stanzas", Complete;
+    /// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
+    STREAM = "http://etherx.jabber.org/streams", Complete;
+    /// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
+    XMPP_STREAMS = "urn:ietf:params:xml:ns:xmpp-streams", Complete;
+    /// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
+    TLS = "urn:ietf:params:xml:ns:xmpp-tls", Complete;
+    /// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
+    SASL = "urn:ietf:params:xml:ns:xmpp-sasl", Complete;
+    /// RFC 6120: Extensible Messaging and Presence Protocol (XMPP): Core
+    BIND = "urn:ietf:params:xml:ns:xmpp-bind", Complete;
+
+    /// RFC 6121: Extensible Messaging and Presence Protocol (XMPP): Instant Messaging and Presence
+    ROSTER = "jabber:iq:roster", Complete;
+
+    /// RFC 7395: An Extensible Messaging and Presence Protocol (XMPP) Subprotocol for WebSocket
+    WEBSOCKET = "urn:ietf:params:xml:ns:xmpp-framing", Complete;
+
+    /// XEP-0004: Data Forms
+    DATA_FORMS = "jabber:x:data", Partial;
+
+    /// XEP-0012: Last Activity
+    LAST = "jabber:iq:last", Complete;
+
+    /// XEP-0030: Service Discovery
+    DISCO_INFO = "http://jabber.org/protocol/disco#info", Complete;
+    /// XEP-0030: Service Discovery
+    DISCO_ITEMS = "http://jabber.org/protocol/disco#items", Complete;
+
+    /// XEP-0045: Multi-User Chat
+    MUC = "http://jabber.org/protocol/muc", Complete;
+    /// XEP-0045: Multi-User Chat
+    MUC_USER = "http://jabber.org/protocol/muc#user", Complete;
+    /// XEP-0045: Multi-User Chat
+    MUC_OWNER = "http://jabber.org/protocol/muc#owner", Complete;
+
+    /// XEP-0047: In-Band Bytestreams
+    IBB = "http://jabber.org/protocol/ibb", Complete;
+
+    /// XEP-0048: Bookmarks
+    BOOKMARKS = "storage:bookmarks", Complete;
+
+    /// XEP-0050: Ad-Hoc Commands
+    COMMANDS = "http://jabber.org/protocol/commands", Complete;
+
+    /// XEP-0059: Result Set Management
+    RSM = "http://jabber.org/protocol/rsm", Complete;
+
+    /// XEP-0060: Publish-Subscribe
+    PUBSUB = "http://jabber.org/protocol/pubsub", Partial;
+    /// XEP-0060: Publish-Subscribe
+    PUBSUB_ERRORS = "http://jabber.org/protocol/pubsub#errors", Partial;
+    /// XEP-0060: Publish-Subscribe
+    PUBSUB_EVENT = "http://jabber.org/protocol/pubsub#event", Partial;
+    /// XEP-0060: Publish-Subscribe
+    PUBSUB_OWNER = "http://jabber.org/protocol/pubsub#owner", Partial;
+    /// XEP-0060: Publish-Subscribe node configuration
+    PUBSUB_CONFIGURE = "http://jabber.org/protocol/pubsub#node_config", Partial;
+
+    /// XEP-0065: SOCKS5 Bytestreams
+    BYTESTREAMS = "http://jabber.org/protocol/bytestreams", Complete;
+
+    /// XEP-0071: XHTML-IM
+    XHTML_IM = "http://jabber.org/protocol/xhtml-im", Complete;
+    /// XEP-0071: XHTML-IM
+    XHTML = "http://www.w3.org/1999/xhtml", Complete;
+
+    /// XEP-0077: In-Band Registration
+    REGISTER = "jabber:iq:register", Complete;
+
+    /// XEP-0080: User Location
+    GEOLOC = "http://jabber.org/protocol/geoloc", Complete;
+
+    /// XEP-0084: User Avatar
+    AVATAR_DATA = "urn:xmpp:avatar:data", Complete;
+    /// XEP-0084: User Avatar
+    AVATAR_METADATA = "urn:xmpp:avatar:metadata", Complete;
+
+    /// XEP-0085: Chat State Notifications
+    CHATSTATES = "http://jabber.org/protocol/chatstates", Complete;
+
+    /// XEP-0092: Software Version
+    VERSION = "jabber:iq:version", Complete;
+
+    /// XEP-0100: Gateway Interaction
+    GATEWAY = "jabber:iq:gateway", Complete;
+
+    /// XEP-0107: User Mood
+    MOOD = "http://jabber.org/protocol/mood", Complete;
 
-/// XEP-0004: Data Forms
-pub const DATA_FORMS: &str = "jabber:x:data";
+    /// XEP-0114: Jabber Component Protocol
+    COMPONENT_ACCEPT = "jabber:component:accept", Complete;
 
-/// XEP-0030: Service Discovery
-pub const DISCO_INFO: &str = "http://jabber.org/protocol/disco#info";
-/// XEP-0030: Service Discovery
-pub const DISCO_ITEMS: &str = "http://jabber.org/protocol/disco#items";
+    /// XEP-0114: Jabber Component Protocol
+    COMPONENT = "jabber:component:accept", Complete;
 
-/// XEP-0045: Multi-User Chat
-pub const MUC: &str = "http://jabber.org/protocol/muc";
-/// XEP-0045: Multi-User Chat
-pub const MUC_USER: &str = "http://jabber.org/protocol/muc#user";
+    /// XEP-0115: Entity Capabilities
+    CAPS = "http://jabber.org/protocol/caps", Complete;
 
-/// XEP-0047: In-Band Bytestreams
-pub const IBB: &str = "http://jabber.org/protocol/ibb";
+    /// XEP-0118: User Tune
+    TUNE = "http://jabber.org/protocol/tune", Complete;
 
-/// XEP-0048: Bookmarks
-pub const BOOKMARKS: &str = "storage:bookmarks";
+    /// XEP-0133: Service Administration
+    ADMIN = "http://jabber.org/protocol/admin", Partial;
 
-/// XEP-0059: Result Set Management
-pub const RSM: &str = "http://jabber.org/protocol/rsm";
+    /// XEP-0138: Stream Compression
+    COMPRESS = "http://jabber.org/protocol/compress", Partial;
+    /// XEP-0138: Stream Compression
+    COMPRESS_FEATURE = "http://jabber.org/features/compress", Partial;
 
-/// XEP-0060: Publish-Subscribe
-pub const PUBSUB: &str = "http://jabber.org/protocol/pubsub";
-/// XEP-0060: Publish-Subscribe
-pub const PUBSUB_ERRORS: &str = "http://jabber.org/protocol/pubsub#errors";
-/// XEP-0060: Publish-Subscribe
-pub const PUBSUB_EVENT: &str = "http://jabber.org/protocol/pubsub#event";
-/// XEP-0060: Publish-Subscribe
-pub const PUBSUB_OWNER: &str = "http://jabber.org/protocol/pubsub#owner";
-/// XEP-0060: Publish-Subscribe node configuration
-pub const PUBSUB_CONFIGURE: &str = "http://jabber.org/protocol/pubsub#node_config";
+    /// XEP-0153: vCard-Based Avatars
+    VCARD_UPDATE = "vcard-temp:x:update", Partial;
 
-/// XEP-0071: XHTML-IM
-pub const XHTML_IM: &str = "http://jabber.org/protocol/xhtml-im";
-/// XEP-0071: XHTML-IM
-pub const XHTML: &str = "http://www.w3.org/1999/xhtml";
+    /// XEP-0157: Contact Addresses for XMPP Services
+    SERVER_INFO = "http://jabber.org/network/serverinfo", Complete;
 
-/// XEP-0077: In-Band Registration
-pub const REGISTER: &str = "jabber:iq:register";
+    /// XEP-0166: Jingle
+    JINGLE = "urn:xmpp:jingle:1", Complete;
+    /// XEP-0166: Jingle
+    JINGLE_ERRORS = "urn:xmpp:jingle:errors:1", Complete;
 
-/// XEP-0084: User Avatar
-pub const AVATAR_DATA: &str = "urn:xmpp:avatar:data";
-/// XEP-0084: User Avatar
-pub const AVATAR_METADATA: &str = "urn:xmpp:avatar:metadata";
+    /// XEP-0167: Jingle RTP Sessions
+    JINGLE_RTP = "urn:xmpp:jingle:apps:rtp:1", Complete;
+    /// XEP-0167: Jingle RTP Sessions
+    JINGLE_RTP_AUDIO = "urn:xmpp:jingle:apps:rtp:audio", Complete;
+    /// XEP-0167: Jingle RTP Sessions
+    JINGLE_RTP_VIDEO = "urn:xmpp:jingle:apps:rtp:video", Complete;
+    /// XEP-0167: Jingle RTP Sessions
+    JINGLE_RTP_INFO = "urn:xmpp:jingle:apps:rtp:info:1", Complete;
 
-/// XEP-0085: Chat State Notifications
-pub const CHATSTATES: &str = "http://jabber.org/protocol/chatstates";
+    /// XEP-0172: User Nickname
+    NICK = "http://jabber.org/protocol/nick", Complete;
 
-/// XEP-0092: Software Version
-pub const VERSION: &str = "jabber:iq:version";
+    /// XEP-0176: Jingle ICE-UDP Transport Method
+    JINGLE_ICE_UDP = "urn:xmpp:jingle:transports:ice-udp:1", Complete;
 
-/// XEP-0107: User Mood
-pub const MOOD: &str = "http://jabber.org/protocol/mood";
+    /// XEP-0177: Jingle Raw UDP Transport Method
+    JINGLE_RAW_UDP = "urn:xmpp:jingle:transports:raw-udp:1", Complete;
 
-/// XEP-0114: Jabber Component Protocol
-pub const COMPONENT_ACCEPT: &str = "jabber:component:accept";
+    /// XEP-0184: Message Delivery Receipts
+    RECEIPTS = "urn:xmpp:receipts", Complete;
 
-/// XEP-0114: Jabber Component Protocol
-pub const COMPONENT: &str = "jabber:component:accept";
+    /// XEP-0191: Blocking Command
+    BLOCKING = "urn:xmpp:blocking", Complete;
+    /// XEP-0191: Blocking Command
+    BLOCKING_ERRORS = "urn:xmpp:blocking:errors", Complete;
 
-/// XEP-0115: Entity Capabilities
-pub const CAPS: &str = "http://jabber.org/protocol/caps";
+    /// XEP-0198: Stream Management
+    SM = "urn:xmpp:sm:3", Complete;
 
-/// XEP-0118: User Tune
-pub const TUNE: &str = "http://jabber.org/protocol/tune";
+    /// XEP-0199: XMPP Ping
+    PING = "urn:xmpp:ping", Complete;
 
-/// XEP-0157: Contact Addresses for XMPP Services
-pub const SERVER_INFO: &str = "http://jabber.org/network/serverinfo";
+    /// XEP-0202: Entity Time
+    TIME = "urn:xmpp:time", Complete;
 
-/// XEP-0166: Jingle
-pub const JINGLE: &str = "urn:xmpp:jingle:1";
+    /// XEP-0203: Delayed Delivery
+    DELAY = "urn:xmpp:delay", Complete;
 
-/// XEP-0167: Jingle RTP Sessions
-pub const JINGLE_RTP: &str = "urn:xmpp:jingle:apps:rtp:1";
-/// XEP-0167: Jingle RTP Sessions
-pub const JINGLE_RTP_AUDIO: &str = "urn:xmpp:jingle:apps:rtp:audio";
-/// XEP-0167: Jingle RTP Sessions
-pub const JINGLE_RTP_VIDEO: &str = "urn:xmpp:jingle:apps:rtp:video";
+    /// XEP-0215: External Service Discovery
+    EXT_DISCO = "urn:xmpp:extdisco:2", Complete;
 
-/// XEP-0172: User Nickname
-pub const NICK: &str = "http://jabber.org/protocol/nick";
+    /// XEP-0221: Data Forms Media Element
+    MEDIA_ELEMENT = "urn:xmpp:media-element", Complete;
 
-/// XEP-0176: Jingle ICE-UDP Transport Method
-pub const JINGLE_ICE_UDP: &str = "urn:xmpp:jingle:transports:ice-udp:1";
+    /// XEP-0224: Attention
+    ATTENTION = "urn:xmpp:attention:0", Complete;
 
-/// XEP-0177: Jingle Raw UDP Transport Method
-pub const JINGLE_RAW_UDP: &str = "urn:xmpp:jingle:transports:raw-udp:1";
+    /// XEP-0231: Bits of Binary
+    BOB = "urn:xmpp:bob", Complete;
 
-/// XEP-0184: Message Delivery Receipts
-pub const RECEIPTS: &str = "urn:xmpp:receipts";
+    /// XEP-0234: Jingle File Transfer
+    JINGLE_FT = "urn:xmpp:jingle:apps:file-transfer:5", Complete;
+    /// XEP-0234: Jingle File Transfer
+    JINGLE_FT_ERROR = "urn:xmpp:jingle:apps:file-transfer:errors:0", Complete;
 
-/// XEP-0191: Blocking Command
-pub const BLOCKING: &str = "urn:xmpp:blocking";
-/// XEP-0191: Blocking Command
-pub const BLOCKING_ERRORS: &str = "urn:xmpp:blocking:errors";
+    /// XEP-0249: Direct MUC Invitations
+    DIRECT_MUC = "jabber:x:conference", Complete;
 
-/// XEP-0198: Stream Management
-pub const SM: &str = "urn:xmpp:sm:3";
+    /// XEP-0257: Client Certificate Management for SASL EXTERNAL
+    SASL_CERT = "urn:xmpp:saslcert:1", Complete;
 
-/// XEP-0199: XMPP Ping
-pub const PING: &str = "urn:xmpp:ping";
+    /// XEP-0258: Security Labels in XMPP
+    SEC_LABEL = "urn:xmpp:sec-label:0", Complete;
+    /// XEP-0258: Security Labels in XMPP
+    SEC_LABEL_CATALOG = "urn:xmpp:sec-label:catalog:2", Complete;
 
-/// XEP-0202: Entity Time
-pub const TIME: &str = "urn:xmpp:time";
+    /// XEP-0260: Jingle SOCKS5 Bytestreams Transport Method
+    JINGLE_S5B = "urn:xmpp:jingle:transports:s5b:1", Complete;
 
-/// XEP-0203: Delayed Delivery
-pub const DELAY: &str = "urn:xmpp:delay";
+    /// XEP-0261: Jingle In-Band Bytestreams Transport Method
+    JINGLE_IBB = "urn:xmpp:jingle:transports:ibb:1", Complete;
 
-/// XEP-0215: External Service Discovery
-pub const EXT_DISCO: &str = "urn:xmpp:extdisco:2";
+    /// XEP-0264: Jingle Content Thumbnails
+    THUMBS = "urn:xmpp:thumbs:1", Complete;
 
-/// XEP-0221: Data Forms Media Element
-pub const MEDIA_ELEMENT: &str = "urn:xmpp:media-element";
+    /// XEP-0277: Microblogging over XMPP
+    MICROBLOG = "urn:xmpp:microblog:0", Partial;
+    /// XEP-0277: Microblogging over XMPP
+    ATOM = "http://www.w3.org/2005/Atom", Partial;
 
-/// XEP-0224: Attention
-pub const ATTENTION: &str = "urn:xmpp:attention:0";
+    /// XEP-0280: Message Carbons
+    CARBONS = "urn:xmpp:carbons:2", Complete;
 
-/// XEP-0231: Bits of Binary
-pub const BOB: &str = "urn:xmpp:bob";
+    /// XEP-0293: Jingle RTP Feedback Negotiation
+    JINGLE_RTCP_FB = "urn:xmpp:jingle:apps:rtp:rtcp-fb:0", Partial;
 
-/// XEP-0234: Jingle File Transfer
-pub const JINGLE_FT: &str = "urn:xmpp:jingle:apps:file-transfer:5";
-/// XEP-0234: Jingle File Transfer
-pub const JINGLE_FT_ERROR: &str = "urn:xmpp:jingle:apps:file-transfer:errors:0";
+    /// XEP-0294: Jingle RTP Header Extensions Negociation
+    JINGLE_RTP_HDREXT = "urn:xmpp:jingle:apps:rtp:rtp-hdrext:0", Partial;
 
-/// XEP-0257: Client Certificate Management for SASL EXTERNAL
-pub const SASL_CERT: &str = "urn:xmpp:saslcert:1";
+    /// XEP-0297: Stanza Forwarding
+    FORWARD = "urn:xmpp:forward:0", Complete;
 
-/// XEP-0260: Jingle SOCKS5 Bytestreams Transport Method
-pub const JINGLE_S5B: &str = "urn:xmpp:jingle:transports:s5b:1";
+    /// XEP-0300: Use of Cryptographic Hash Functions in XMPP
+    HASHES = "urn:xmpp:hashes:2", Complete;
+    /// XEP-0300: Use of Cryptographic Hash Functions in XMPP
+    HASH_ALGO_SHA_256 = "urn:xmpp:hash-function-text-names:sha-256", Complete;
+    /// XEP-0300: Use of Cryptographic Hash Functions in XMPP
+    HASH_ALGO_SHA_512 = "urn:xmpp:hash-function-text-names:sha-512", Complete;
+    /// XEP-0300: Use of Cryptographic Hash Functions in XMPP
+    HASH_ALGO_SHA3_256 = "urn:xmpp:hash-function-text-names:sha3-256", Complete;
+    /// XEP-0300: Use of Cryptographic Hash Functions in XMPP
+    HASH_ALGO_SHA3_512 = "urn:xmpp:hash-function-text-names:sha3-512", Complete;
+    /// XEP-0300: Use of Cryptographic Hash Functions in XMPP
+    HASH_ALGO_BLAKE2B_256 = "urn:xmpp:hash-function-text-names:id-blake2b256", Complete;
+    /// XEP-0300: Use of Cryptographic Hash Functions in XMPP
+    HASH_ALGO_BLAKE2B_512 = "urn:xmpp:hash-function-text-names:id-blake2b512", Complete;
 
-/// XEP-0261: Jingle In-Band Bytestreams Transport Method
-pub const JINGLE_IBB: &str = "urn:xmpp:jingle:transports:ibb:1";
+    /// XEP-0308: Last Message Correction
+    MESSAGE_CORRECT = "urn:xmpp:message-correct:0", Complete;
 
-/// XEP-0277: Microblogging over XMPP
-pub const MICROBLOG: &str = "urn:xmpp:microblog:0";
+    /// XEP-0313: Message Archive Management
+    MAM = "urn:xmpp:mam:2", Complete;
 
-/// XEP-0280: Message Carbons
-pub const CARBONS: &str = "urn:xmpp:carbons:2";
+    /// XEP-0313: Message Archive Management, with metadata and id queries
+    MAM_EXTENDED = "urn:xmpp:mam:2#extended", Complete;
 
-/// XEP-0293: Jingle RTP Feedback Negotiation
-pub const JINGLE_RTCP_FB: &str = "urn:xmpp:jingle:apps:rtp:rtcp-fb:0";
+    /// XEP-0319: Last User Interaction in Presence
+    IDLE = "urn:xmpp:idle:1", Complete;
 
-/// XEP-0294: Jingle RTP Header Extensions Negociation
-pub const JINGLE_RTP_HDREXT: &str = "urn:xmpp:jingle:apps:rtp:rtp-hdrext:0";
+    /// XEP-0320: Use of DTLS-SRTP in Jingle Sessions
+    JINGLE_DTLS = "urn:xmpp:jingle:apps:dtls:0", Complete;
 
-/// XEP-0297: Stanza Forwarding
-pub const FORWARD: &str = "urn:xmpp:forward:0";
+    /// XEP-0328: JID Prep
+    JID_PREP = "urn:xmpp:jidprep:0", Complete;
 
-/// XEP-0300: Use of Cryptographic Hash Functions in XMPP
-pub const HASHES: &str = "urn:xmpp:hashes:2";
-/// XEP-0300: Use of Cryptographic Hash Functions in XMPP
-pub const HASH_ALGO_SHA_256: &str = "urn:xmpp:hash-function-text-names:sha-256";
-/// XEP-0300: Use of Cryptographic Hash Functions in XMPP
-pub const HASH_ALGO_SHA_512: &str = "urn:xmpp:hash-function-text-names:sha-512";
-/// XEP-0300: Use of Cryptographic Hash Functions in XMPP
-pub const HASH_ALGO_SHA3_256: &str = "urn:xmpp:hash-function-text-names:sha3-256";
-/// XEP-0300: Use of Cryptographic Hash Functions in XMPP
-pub const HASH_ALGO_SHA3_512: &str = "urn:xmpp:hash-function-text-names:sha3-512";
-/// XEP-0300: Use of Cryptographic Hash Functions in XMPP
-pub const HASH_ALGO_BLAKE2B_256: &str = "urn:xmpp:hash-function-text-names:id-blake2b256";
-/// XEP-0300: Use of Cryptographic Hash Functions in XMPP
-pub const HASH_ALGO_BLAKE2B_512: &str = "urn:xmpp:hash-function-text-names:id-blake2b512";
+    /// XEP-0338: Jingle Grouping Framework
+    JINGLE_GROUPING = "urn:xmpp:jingle:apps:grouping:0", Complete;
 
-/// XEP-0308: Last Message Correction
-pub const MESSAGE_CORRECT: &str = "urn:xmpp:message-correct:0";
+    /// XEP-0339: Source-Specific Media Attributes in Jingle
+    JINGLE_SSMA = "urn:xmpp:jingle:apps:rtp:ssma:0", Complete;
 
-/// XEP-0313: Message Archive Management
-pub const MAM: &str = "urn:xmpp:mam:2";
+    /// XEP-0352: Client State Indication
+    CSI = "urn:xmpp:csi:0", Complete;
 
-/// XEP-0319: Last User Interaction in Presence
-pub const IDLE: &str = "urn:xmpp:idle:1";
+    /// XEP-0353: Jingle Message Initiation
+    JINGLE_MESSAGE = "urn:xmpp:jingle-message:0", Complete;
 
-/// XEP-0320: Use of DTLS-SRTP in Jingle Sessions
-pub const JINGLE_DTLS: &str = "urn:xmpp:jingle:apps:dtls:0";
+    /// XEP-0356: Privileged Entity
+    PRIVILEGE = "urn:xmpp:privilege:2", Partial;
 
-/// XEP-0328: JID Prep
-pub const JID_PREP: &str = "urn:xmpp:jidprep:0";
+    /// XEP-0359: Unique and Stable Stanza IDs
+    SID = "urn:xmpp:sid:0", Complete;
 
-/// XEP-0338: Jingle Grouping Framework
-pub const JINGLE_GROUPING: &str = "urn:xmpp:jingle:apps:grouping:0";
+    /// XEP-0363: HTTP File Upload
+    HTTP_UPLOAD = "urn:xmpp:http:upload:0", Complete;
 
-/// XEP-0339: Source-Specific Media Attributes in Jingle
-pub const JINGLE_SSMA: &str = "urn:xmpp:jingle:apps:rtp:ssma:0";
-
-/// XEP-0352: Client State Indication
-pub const CSI: &str = "urn:xmpp:csi:0";
-
-/// XEP-0353: Jingle Message Initiation
-pub const JINGLE_MESSAGE: &str = "urn:xmpp:jingle-message:0";
-
-/// XEP-0359: Unique and Stable Stanza IDs
-pub const SID: &str = "urn:xmpp:sid:0";
-
-/// XEP-0363: HTTP File Upload
-pub const HTTP_UPLOAD: &str = "urn:xmpp:http:upload:0";
-
-/// XEP-0369: Mediated Information eXchange (MIX)
-pub const MIX_CORE: &str = "urn:xmpp:mix:core:1";
-/// XEP-0369: Mediated Information eXchange (MIX)
-pub const MIX_CORE_SEARCHABLE: &str = "urn:xmpp:mix:core:1#searchable";
-/// XEP-0369: Mediated Information eXchange (MIX)
-pub const MIX_CORE_CREATE_CHANNEL: &str = "urn:xmpp:mix:core:1#create-channel";
-/// XEP-0369: Mediated Information eXchange (MIX)
-pub const MIX_NODES_PRESENCE: &str = "urn:xmpp:mix:nodes:presence";
-/// XEP-0369: Mediated Information eXchange (MIX)
-pub const MIX_NODES_PARTICIPANTS: &str = "urn:xmpp:mix:nodes:participants";
-/// XEP-0369: Mediated Information eXchange (MIX)
-pub const MIX_NODES_MESSAGES: &str = "urn:xmpp:mix:nodes:messages";
-/// XEP-0369: Mediated Information eXchange (MIX)
-pub const MIX_NODES_CONFIG: &str = "urn:xmpp:mix:nodes:config";
-/// XEP-0369: Mediated Information eXchange (MIX)
-pub const MIX_NODES_INFO: &str = "urn:xmpp:mix:nodes:info";
-
-/// XEP-0373: OpenPGP for XMPP
-pub const OX: &str = "urn:xmpp:openpgp:0";
-/// XEP-0373: OpenPGP for XMPP
-pub const OX_PUBKEYS: &str = "urn:xmpp:openpgp:0:public-keys";
-
-/// XEP-0380: Explicit Message Encryption
-pub const EME: &str = "urn:xmpp:eme:0";
-
-/// XEP-0390: Entity Capabilities 2.0
-pub const ECAPS2: &str = "urn:xmpp:caps";
-/// XEP-0390: Entity Capabilities 2.0
-pub const ECAPS2_OPTIMIZE: &str = "urn:xmpp:caps:optimize";
-
-/// XEP-0402: PEP Native Bookmarks
-pub const BOOKMARKS2: &str = "urn:xmpp:bookmarks:1";
-/// XEP-0402: PEP Native Bookmarks
-pub const BOOKMARKS2_COMPAT: &str = "urn:xmpp:bookmarks:1#compat";
-/// XEP-0402: PEP Native Bookmarks
-pub const BOOKMARKS2_COMPAT_PEP: &str = "urn:xmpp:bookmarks:1#compat-pep";
-
-/// XEP-0421: Anonymous unique occupant identifiers for MUCs
-pub const OID: &str = "urn:xmpp:occupant-id:0";
+    /// XEP-0369: Mediated Information eXchange (MIX)
+    MIX_CORE = "urn:xmpp:mix:core:1", Complete;
+    /// XEP-0369: Mediated Information eXchange (MIX)
+    MIX_CORE_SEARCHABLE = "urn:xmpp:mix:core:1#searchable", Complete;
+    /// XEP-0369: Mediated Information eXchange (MIX)
+    MIX_CORE_CREATE_CHANNEL = "urn:xmpp:mix:core:1#create-channel", Complete;
+    /// XEP-0369: Mediated Information eXchange (MIX)
+    MIX_NODES_PRESENCE = "urn:xmpp:mix:nodes:presence", Complete;
+    /// XEP-0369: Mediated Information eXchange (MIX)
+    MIX_NODES_PARTICIPANTS = "urn:xmpp:mix:nodes:participants", Complete;
+    /// XEP-0369: Mediated Information eXchange (MIX)
+    MIX_NODES_MESSAGES = "urn:xmpp:mix:nodes:messages", Complete;
+    /// XEP-0369: Mediated Information eXchange (MIX)
+    MIX_NODES_CONFIG = "urn:xmpp:mix:nodes:config", Complete;
+    /// XEP-0369: Mediated Information eXchange (MIX)
+    MIX_NODES_INFO = "urn:xmpp:mix:nodes:info", Complete;
+
+    /// XEP-0373: OpenPGP for XMPP
+    OX = "urn:xmpp:openpgp:0", Partial;
+    /// XEP-0373: OpenPGP for XMPP
+    OX_PUBKEYS = "urn:xmpp:openpgp:0:public-keys", Partial;
+
+    /// XEP-0380: Explicit Message Encryption
+    EME = "urn:xmpp:eme:0", Complete;
+
+    /// XEP-0390: Entity Capabilities 2.0
+    ECAPS2 = "urn:xmpp:caps", Complete;
+    /// XEP-0390: Entity Capabilities 2.0
+    ECAPS2_OPTIMIZE = "urn:xmpp:caps:optimize", Complete;
+
+    /// XEP-0393: Message Styling
+    STYLING = "urn:xmpp:styling:0", Complete;
+
+    /// XEP-0398: User Avatar to vCard-Based Avatars Conversion
+    PEP_VCARD_CONVERSION = "urn:xmpp:pep-vcard-conversion:0", Complete;
+
+    /// XEP-0402: PEP Native Bookmarks
+    BOOKMARKS2 = "urn:xmpp:bookmarks:1", Complete;
+    /// XEP-0402: PEP Native Bookmarks
+    BOOKMARKS2_COMPAT = "urn:xmpp:bookmarks:1#compat", Complete;
+    /// XEP-0402: PEP Native Bookmarks
+    BOOKMARKS2_COMPAT_PEP = "urn:xmpp:bookmarks:1#compat-pep", Complete;
+
+    /// XEP-0421: Anonymous unique occupant identifiers for MUCs
+    OID = "urn:xmpp:occupant-id:0", Complete;
+
+    /// XEP-0428: Fallback Indication
+    FALLBACK = "urn:xmpp:fallback:0", Complete;
+
+    /// XEP-0466: Ephemeral Messages
+    EPHEMERAL = "urn:xmpp:ephemeral:0", Complete;
+}
 
 /// Alias for the main namespace of the stream, that is "jabber:client" when
 /// the component feature isn’t enabled.
@@ -271,3 +418,61 @@ pub const DEFAULT_NS: &str = JABBER_CLIENT;
 /// "jabber:component:accept" when the component feature is enabled.
 #[cfg(feature = "component")]
 pub const DEFAULT_NS: &str = COMPONENT_ACCEPT;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_protocol_info() {
+        let disco = supported_protocols()
+            .iter()
+            .find(|protocol| protocol.constant == "DISCO_INFO")
+            .unwrap();
+        assert_eq!(disco.ns, DISCO_INFO);
+        assert_eq!(disco.spec(), "XEP-0030: Service Discovery");
+        assert_eq!(disco.xep(), Some(30));
+        assert_eq!(disco.name(), "Service Discovery");
+        assert_eq!(disco.support, Support::Complete);
+
+        let roster = supported_protocols()
+            .iter()
+            .find(|protocol| protocol.ns == ROSTER)
+            .unwrap();
+        assert_eq!(roster.xep(), None);
+
+        let constants: HashSet<_> = supported_protocols()
+            .iter()
+            .map(|protocol| protocol.constant)
+            .collect();
+        assert_eq!(constants.len(), supported_protocols().len());
+    }
+
+    /// The support levels have to match the ones the DOAP file announces.
+    #[test]
+    fn test_doap() {
+        let doap = include_str!("../doap.xml");
+        for protocol in supported_protocols() {
+            let xep = match protocol.xep() {
+                Some(xep) => xep,
+                None => continue,
+            };
+            let resource = format!("xep-{:04}.html\"/>", xep);
+            let entry = match doap.find(&resource) {
+                Some(index) => &doap[index..],
+                None => panic!("{} is missing from doap.xml", protocol.spec()),
+            };
+            let status = entry
+                .split("<xmpp:status>")
+                .nth(1)
+                .and_then(|status| status.split('<').next())
+                .unwrap();
+            let expected = match protocol.support {
+                Support::Complete => "complete",
+                Support::Partial => "partial",
+            };
+            assert_eq!(status, expected, "{}", protocol.spec());
+        }
+    }
+}
@@ -79,6 +79,7 @@ mod tests {
         let pubsub = Publish {
             node: NodeName(format!("{}:{}", ns::OX_PUBKEYS, "some-fingerprint")),
             items: vec![PubSubItem(Item::new(None, None, Some(pubkey)))],
+            unknown: vec![],
         };
         println!("Foo2: {:?}", pubsub);
     }
@@ -96,6 +97,7 @@ mod tests {
         let pubsub = Publish {
             node: NodeName("foo".to_owned()),
             items: vec![PubSubItem(Item::new(None, None, Some(pubkeymeta)))],
+            unknown: vec![],
         };
         println!("Foo2: {:?}", pubsub);
     }
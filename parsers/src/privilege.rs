@@ -0,0 +1,217 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::forwarding::Forwarded;
+use crate::message::{Message, MessagePayload};
+
+generate_attribute!(
+    /// What a permission applies to.
+    Access, "access", {
+        /// Reading and modifying the roster of users.
+        Roster => "roster",
+
+        /// Sending messages on behalf of users.
+        Message => "message",
+
+        /// Receiving the presences of users.
+        Presence => "presence",
+
+        /// Sending IQs of the given namespaces on behalf of users.
+        Iq => "iq",
+    }
+);
+
+generate_attribute!(
+    /// How much of an access got granted, the meaningful values depending on it.
+    Type, "type", {
+        /// No permission at all.
+        None => "none",
+
+        /// Only get requests, for rosters and IQs.
+        Get => "get",
+
+        /// Only set requests, for rosters and IQs.
+        Set => "set",
+
+        /// Both get and set requests, for rosters and IQs.
+        Both => "both",
+
+        /// Sending messages on behalf of users.
+        Outgoing => "outgoing",
+
+        /// Receiving the presences of the users of the server.
+        ManagedEntity => "managed_entity",
+
+        /// Receiving the presences of the users of the server, and of their contacts.
+        Roster => "roster",
+    }
+);
+
+generate_attribute!(
+    /// Whether roster pushes get forwarded to the entity.
+    Push,
+    "push",
+    bool
+);
+
+generate_element!(
+    /// An IQ namespace the entity may use on behalf of users.
+    Namespace, "namespace", PRIVILEGE,
+    attributes: [
+        /// The namespace of the IQ payload.
+        ns: Required<String> = "ns",
+
+        /// Which types of IQs are allowed.
+        type_: Required<Type> = "type",
+    ]
+);
+
+generate_element!(
+    /// A permission granted by the server.
+    Perm, "perm", PRIVILEGE,
+    attributes: [
+        /// What this permission applies to.
+        access: Required<Access> = "access",
+
+        /// How much of it got granted, absent for IQs.
+        type_: Option<Type> = "type",
+
+        /// Whether roster pushes get forwarded too.
+        push: Default<Push> = "push",
+    ],
+    children: [
+        /// The namespaces allowed for IQs.
+        namespaces: Vec<Namespace> = ("namespace", PRIVILEGE) => Namespace
+    ]
+);
+
+generate_element!(
+    /// Either the permissions the server grants to an entity, or a message this entity sends
+    /// on behalf of a user.
+    Privilege, "privilege", PRIVILEGE,
+    children: [
+        /// The permissions granted, when advertised by the server.
+        perms: Vec<Perm> = ("perm", PRIVILEGE) => Perm,
+
+        /// The message to send on behalf of a user.
+        forwarded: Option<Forwarded> = ("forwarded", FORWARD) => Forwarded
+    ]
+);
+
+impl MessagePayload for Privilege {}
+
+impl Privilege {
+    /// Wraps a message to send on behalf of the user in its `from`.
+    pub fn forward(message: Message) -> Privilege {
+        Privilege {
+            perms: Vec::new(),
+            forwarded: Some(Forwarded {
+                delay: None,
+                stanza: Some(message),
+            }),
+        }
+    }
+
+    /// The type granted for this access, `Type::None` if it isn’t advertised.
+    pub fn granted(&self, access: Access) -> Type {
+        self.perms
+            .iter()
+            .find(|perm| perm.access == access)
+            .and_then(|perm| perm.type_.clone())
+            .unwrap_or(Type::None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Body;
+    use crate::util::error::Error;
+    use crate::{Element, Jid};
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(Access, 1);
+        assert_size!(Type, 1);
+        assert_size!(Push, 1);
+        assert_size!(Namespace, 16);
+        assert_size!(Perm, 16);
+        assert_size!(Privilege, 224);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(Access, 1);
+        assert_size!(Type, 1);
+        assert_size!(Push, 1);
+        assert_size!(Namespace, 32);
+        assert_size!(Perm, 32);
+        assert_size!(Privilege, 408);
+    }
+
+    #[test]
+    fn test_advertisement() {
+        let elem: Element =
+            "<message xmlns='jabber:client' from='capulet.lit' to='pubsub.capulet.lit' id='54321'>
+    <privilege xmlns='urn:xmpp:privilege:2'>
+        <perm access='roster' type='both' push='true'/>
+        <perm access='message' type='outgoing'/>
+        <perm access='presence' type='managed_entity'/>
+        <perm access='iq'>
+            <namespace ns='urn:xmpp:mam:2' type='both'/>
+        </perm>
+    </privilege>
+</message>"
+                .parse()
+                .unwrap();
+        let message = Message::try_from(elem).unwrap();
+        let privilege = Privilege::try_from(message.payloads[0].clone()).unwrap();
+        assert_eq!(privilege.perms.len(), 4);
+        assert!(privilege.forwarded.is_none());
+        assert_eq!(privilege.perms[0].push, Push::True);
+        assert_eq!(privilege.granted(Access::Roster), Type::Both);
+        assert_eq!(privilege.granted(Access::Message), Type::Outgoing);
+        assert_eq!(privilege.granted(Access::Presence), Type::ManagedEntity);
+        assert_eq!(privilege.granted(Access::Iq), Type::None);
+        assert_eq!(
+            privilege.perms[3].namespaces,
+            [Namespace {
+                ns: String::from("urn:xmpp:mam:2"),
+                type_: Type::Both,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_forward() {
+        let mut message = Message::new(Jid::from_str("romeo@montague.lit").unwrap());
+        message.from = Some(Jid::from_str("juliet@capulet.lit").unwrap());
+        message
+            .bodies
+            .insert(String::new(), Body(String::from("Romeo?")));
+        let elem: Element = Privilege::forward(message).into();
+        let expected: Element = "<privilege xmlns='urn:xmpp:privilege:2'><forwarded xmlns='urn:xmpp:forward:0'><message xmlns='jabber:client' from='juliet@capulet.lit' to='romeo@montague.lit' type='chat'><body>Romeo?</body></message></forwarded></privilege>".parse().unwrap();
+        assert_eq!(elem, expected);
+    }
+
+    #[test]
+    fn test_invalid_access() {
+        let elem: Element =
+            "<privilege xmlns='urn:xmpp:privilege:2'><perm access='vcard' type='get'/></privilege>"
+                .parse()
+                .unwrap();
+        let error = Privilege::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Unknown value for 'access' attribute.");
+    }
+}
@@ -156,6 +156,7 @@ impl TryFrom<Element> for PubSubEvent {
                 };
                 payload = Some(PubSubEvent::Configuration { node, form });
             } else if child.is("delete", ns::PUBSUB_EVENT) {
+                check_no_unknown_attributes!(child, "delete", ["node"]);
                 let mut redirect = None;
                 for item in child.children() {
                     if item.is("redirect", ns::PUBSUB_EVENT) {
@@ -164,6 +165,8 @@ impl TryFrom<Element> for PubSubEvent {
                                 "More than one redirect in delete element.",
                             ));
                         }
+                        check_no_children!(item, "redirect");
+                        check_no_unknown_attributes!(item, "redirect", ["uri"]);
                         let uri = get_attr!(item, "uri", Required);
                         redirect = Some(uri);
                     } else {
@@ -201,7 +204,7 @@ impl From<PubSubEvent> for Element {
                     .attr("node", node)
                     .append_all(form.map(Element::from))
             }
-            PubSubEvent::Delete { node, redirect } => Element::builder("purge", ns::PUBSUB_EVENT)
+            PubSubEvent::Delete { node, redirect } => Element::builder("delete", ns::PUBSUB_EVENT)
                 .attr("node", node)
                 .append_all(redirect.map(|redirect| {
                     Element::builder("redirect", ns::PUBSUB_EVENT).attr("uri", redirect)
@@ -326,6 +329,58 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_ex159_delete_with_redirect() {
+        let elem: Element = "<event xmlns='http://jabber.org/protocol/pubsub#event'><delete node='princely_musings'><redirect uri='xmpp:hamlet@denmark.lit?;node=blog'/></delete></event>".parse().unwrap();
+        let event = PubSubEvent::try_from(elem.clone()).unwrap();
+        match event.clone() {
+            PubSubEvent::Delete { node, redirect } => {
+                assert_eq!(node, NodeName(String::from("princely_musings")));
+                assert_eq!(
+                    redirect.as_deref(),
+                    Some("xmpp:hamlet@denmark.lit?;node=blog")
+                );
+            }
+            _ => panic!(),
+        }
+        let elem2: Element = event.into();
+        assert_eq!(elem, elem2);
+    }
+
+    #[test]
+    fn test_ex158_delete() {
+        let elem: Element = "<event xmlns='http://jabber.org/protocol/pubsub#event'><delete node='princely_musings'/></event>".parse().unwrap();
+        let event = PubSubEvent::try_from(elem.clone()).unwrap();
+        match event.clone() {
+            PubSubEvent::Delete { node, redirect } => {
+                assert_eq!(node, NodeName(String::from("princely_musings")));
+                assert_eq!(redirect, None);
+            }
+            _ => panic!(),
+        }
+        let elem2: Element = event.into();
+        assert_eq!(elem, elem2);
+    }
+
+    #[test]
+    fn test_invalid_delete() {
+        let elem: Element = "<event xmlns='http://jabber.org/protocol/pubsub#event'><delete node='princely_musings'><redirect uri='xmpp:a@b'/><redirect uri='xmpp:c@d'/></delete></event>".parse().unwrap();
+        let error = PubSubEvent::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "More than one redirect in delete element.");
+    }
+
+    #[test]
+    fn test_ex115_retract_serialise() {
+        let elem: Element = "<event xmlns='http://jabber.org/protocol/pubsub#event'><items node='princely_musings'><retract id='ae890ac52d0df67ed7cfdf51b644e901'/></items></event>".parse().unwrap();
+        let event = PubSubEvent::try_from(elem.clone()).unwrap();
+        let elem2: Element = event.into();
+        assert_eq!(elem, elem2);
+    }
+
     #[test]
     fn test_simple_purge() {
         let elem: Element =
@@ -354,6 +409,42 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_ex150_configuration() {
+        let elem: Element = "<event xmlns='http://jabber.org/protocol/pubsub#event'><configuration node='princely_musings'><x xmlns='jabber:x:data' type='result'><field var='FORM_TYPE' type='hidden'><value>http://jabber.org/protocol/pubsub#node_config</value></field><field var='pubsub#title'><value>Princely Musings (Atom)</value></field></x></configuration></event>".parse().unwrap();
+        let event = PubSubEvent::try_from(elem.clone()).unwrap();
+        match event.clone() {
+            PubSubEvent::Configuration {
+                node,
+                form: Some(form),
+            } => {
+                assert_eq!(node, NodeName(String::from("princely_musings")));
+                assert_eq!(
+                    form.form_type.as_deref(),
+                    Some("http://jabber.org/protocol/pubsub#node_config")
+                );
+                assert_eq!(form.fields[0].var, "pubsub#title");
+            }
+            _ => panic!(),
+        }
+        let elem2: Element = event.into();
+        assert_eq!(elem, elem2);
+    }
+
+    #[test]
+    fn test_ex149_configuration_without_form() {
+        let elem: Element = "<event xmlns='http://jabber.org/protocol/pubsub#event'><configuration node='princely_musings'/></event>".parse().unwrap();
+        let event = PubSubEvent::try_from(elem.clone()).unwrap();
+        match event.clone() {
+            PubSubEvent::Configuration { node, form: None } => {
+                assert_eq!(node, NodeName(String::from("princely_musings")));
+            }
+            _ => panic!(),
+        }
+        let elem2: Element = event.into();
+        assert_eq!(elem, elem2);
+    }
+
     #[test]
     fn test_invalid() {
         let elem: Element =
@@ -410,7 +501,12 @@ mod tests {
             _ => panic!(),
         }
 
+        // The expiry gets written back in UTC with a Z.
         let elem2: Element = event.into();
-        assert_eq!(elem, elem2);
+        let canonical: Element = "<event xmlns='http://jabber.org/protocol/pubsub#event'><subscription expiry='2006-02-28T23:59:59Z' jid='francisco@denmark.lit' node='princely_musings' subid='ba49252aaa4f5d320c24d3766f0bdcade78c78d3' subscription='subscribed'/></event>"
+        .parse()
+        .unwrap();
+        assert_eq!(elem2, canonical);
+        assert_ne!(elem, elem2);
     }
 }
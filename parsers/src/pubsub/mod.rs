@@ -13,6 +13,9 @@ pub mod owner;
 /// The `http://jabber.org/protocol/pubsub` protocol.
 pub mod pubsub;
 
+/// References from an item to the node of its comments.
+pub mod reference;
+
 pub use self::event::PubSubEvent;
 pub use self::owner::PubSubOwner;
 pub use self::pubsub::PubSub;
@@ -10,6 +10,7 @@ use crate::ns;
 use crate::pubsub::{
     AffiliationAttribute, Item as PubSubItem, NodeName, Subscription, SubscriptionId,
 };
+use crate::rsm::{SetQuery, SetResult};
 use crate::util::error::Error;
 use crate::Element;
 use jid::Jid;
@@ -27,7 +28,8 @@ generate_element!(
     children: [
         /// The actual list of affiliation elements.
         affiliations: Vec<Affiliation> = ("affiliation", PUBSUB) => Affiliation
-    ]
+    ],
+    unknown_children: preserve
 );
 
 generate_element!(
@@ -89,7 +91,8 @@ generate_element!(
     children: [
         /// The actual list of items returned.
         items: Vec<Item> = ("item", PUBSUB) => Item
-    ]
+    ],
+    unknown_children: preserve
 );
 
 impl Items {
@@ -100,6 +103,7 @@ impl Items {
             max_items: None,
             subid: None,
             items: Vec::new(),
+            unknown: Vec::new(),
         }
     }
 }
@@ -139,7 +143,8 @@ generate_element!(
     children: [
         /// The items you want to publish.
         items: Vec<Item> = ("item", PUBSUB) => Item
-    ]
+    ],
+    unknown_children: preserve
 );
 
 generate_element!(
@@ -171,7 +176,8 @@ generate_element!(
     children: [
         /// The items affected by this request.
         items: Vec<Item> = ("item", PUBSUB) => Item
-    ]
+    ],
+    unknown_children: preserve
 );
 
 /// Indicate that the subscription can be configured.
@@ -240,7 +246,8 @@ generate_element!(
     children: [
         /// The list of subscription elements returned.
         subscription: Vec<SubscriptionElem> = ("subscription", PUBSUB) => SubscriptionElem
-    ]
+    ],
+    unknown_children: preserve
 );
 
 generate_element!(
@@ -321,6 +328,24 @@ pub enum PubSub {
     /// A request for a list of items.
     Items(Items),
 
+    /// A request for a page of the items of a node (XEP-0059).
+    ItemsQuery {
+        /// The node to list the items of.
+        items: Items,
+
+        /// Which page to return.
+        set: SetQuery,
+    },
+
+    /// A page of the items of a node, as returned for an `ItemsQuery`.
+    ItemsPage {
+        /// The items of this page.
+        items: Items,
+
+        /// Where this page is in the full list of items.
+        set: SetResult,
+    },
+
     /// A request to retract some items from a node.
     Retract(Retract),
 
@@ -459,6 +484,20 @@ impl TryFrom<Element> for PubSub {
                 }
                 let items = Items::try_from(child.clone())?;
                 payload = Some(PubSub::Items(items));
+            } else if child.is("set", ns::RSM) {
+                let items = match payload {
+                    Some(PubSub::Items(items)) => items,
+                    _ => return Err(Error::ParseError("Set without items in pubsub element.")),
+                };
+                // The children of a query and those of a result don’t overlap, an empty set
+                // only makes sense as the result of a query for an empty node.
+                payload = Some(match SetResult::try_from(child.clone()) {
+                    Ok(set) => PubSub::ItemsPage { items, set },
+                    Err(_) => PubSub::ItemsQuery {
+                        items,
+                        set: SetQuery::try_from(child.clone())?,
+                    },
+                });
             } else if child.is("retract", ns::PUBSUB) {
                 if payload.is_some() {
                     return Err(Error::ParseError(
@@ -533,6 +572,10 @@ impl From<PubSub> for Element {
                 PubSub::Affiliations(affiliations) => vec![Element::from(affiliations)],
                 PubSub::Default(default) => vec![Element::from(default)],
                 PubSub::Items(items) => vec![Element::from(items)],
+                PubSub::ItemsQuery { items, set } => {
+                    vec![Element::from(items), Element::from(set)]
+                }
+                PubSub::ItemsPage { items, set } => vec![Element::from(items), Element::from(set)],
                 PubSub::Retract(retract) => vec![Element::from(retract)],
                 PubSub::Subscription(subscription) => vec![Element::from(subscription)],
                 PubSub::Subscriptions(subscriptions) => vec![Element::from(subscriptions)],
@@ -662,6 +705,24 @@ mod tests {
         assert_eq!(elem1, elem2);
     }
 
+    #[test]
+    fn publish_unknown_children() {
+        let elem: Element = "<pubsub xmlns='http://jabber.org/protocol/pubsub'><publish node='coucou'><item id='a'/><hint xmlns='urn:example:hint' level='2'/></publish></pubsub>".parse().unwrap();
+        let elem1 = elem.clone();
+        let pubsub = PubSub::try_from(elem).unwrap();
+        match pubsub.clone() {
+            PubSub::Publish { publish, .. } => {
+                assert_eq!(publish.items.len(), 1);
+                assert_eq!(publish.unknown.len(), 1);
+                assert_eq!(publish.unknown[0].attr("level"), Some("2"));
+            }
+            _ => panic!(),
+        }
+
+        let elem2 = Element::from(pubsub);
+        assert_eq!(elem1, elem2);
+    }
+
     #[test]
     fn publish_with_publish_options() {
         let elem: Element = "<pubsub xmlns='http://jabber.org/protocol/pubsub'><publish node='coucou'/><publish-options/></pubsub>".parse().unwrap();
@@ -682,6 +743,49 @@ mod tests {
         assert_eq!(elem1, elem2);
     }
 
+    #[test]
+    fn items_paged() {
+        let elem: Element = "<pubsub xmlns='http://jabber.org/protocol/pubsub'><items node='urn:xmpp:microblog:0:comments/1'/><set xmlns='http://jabber.org/protocol/rsm'><max>10</max><after>2</after></set></pubsub>"
+            .parse()
+            .unwrap();
+        let elem1 = elem.clone();
+        let pubsub = PubSub::try_from(elem).unwrap();
+        match pubsub.clone() {
+            PubSub::ItemsQuery { items, set } => {
+                assert_eq!(items.node.0, "urn:xmpp:microblog:0:comments/1");
+                assert_eq!(set.max, Some(10));
+                assert_eq!(set.after.as_deref(), Some("2"));
+            }
+            _ => panic!(),
+        }
+        assert_eq!(Element::from(pubsub), elem1);
+
+        let elem: Element = "<pubsub xmlns='http://jabber.org/protocol/pubsub'><items node='urn:xmpp:microblog:0:comments/1'><item id='3'/></items><set xmlns='http://jabber.org/protocol/rsm'><first index='2'>3</first><last>3</last><count>3</count></set></pubsub>"
+            .parse()
+            .unwrap();
+        let elem1 = elem.clone();
+        let pubsub = PubSub::try_from(elem).unwrap();
+        match pubsub.clone() {
+            PubSub::ItemsPage { items, set } => {
+                assert_eq!(items.items.len(), 1);
+                assert_eq!(set.first_index, Some(2));
+                assert_eq!(set.count, Some(3));
+            }
+            _ => panic!(),
+        }
+        assert_eq!(Element::from(pubsub), elem1);
+
+        let elem: Element = "<pubsub xmlns='http://jabber.org/protocol/pubsub'><create/><set xmlns='http://jabber.org/protocol/rsm'/></pubsub>"
+            .parse()
+            .unwrap();
+        let error = PubSub::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Set without items in pubsub element.");
+    }
+
     #[test]
     fn invalid_empty_pubsub() {
         let elem: Element = "<pubsub xmlns='http://jabber.org/protocol/pubsub'/>"
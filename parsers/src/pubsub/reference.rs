@@ -0,0 +1,270 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Pointing a PubSub item at the node holding the comments on it, the way social feeds
+//! (XEP-0277, XEP-0472) do it: an Atom `<link rel='replies'/>` in its payload, whose `href` is
+//! an `xmpp:` URI naming the service and the node.
+//!
+//! The rest of the Atom entry isn’t parsed here.
+
+use crate::data_forms::{DataForm, DataFormType, Field, FieldType};
+use crate::ns;
+use crate::pubsub::{ItemId, NodeName};
+use crate::util::error::Error;
+use crate::Element;
+use jid::Jid;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+/// The node holding the comments on an item.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    /// The PubSub service hosting the node.
+    pub service: Jid,
+
+    /// The name of the node on that service.
+    pub node: NodeName,
+}
+
+impl Reference {
+    /// Where the comments on the item `item` get published by convention, on `service`.
+    pub fn for_item(service: Jid, item: &ItemId) -> Reference {
+        Reference {
+            service,
+            node: NodeName(format!("{}:comments/{}", ns::MICROBLOG, item.0)),
+        }
+    }
+
+    /// The first valid reference to comments among the children of `payload`, usually an Atom
+    /// entry.
+    pub fn find(payload: &Element) -> Option<Reference> {
+        payload
+            .children()
+            .filter(|child| child.is("link", ns::ATOM))
+            .find_map(|link| Reference::try_from(link.clone()).ok())
+    }
+
+    /// The `xmpp:` URI of the node, as found in the `href` of the link.
+    pub fn to_uri(&self) -> String {
+        format!(
+            "xmpp:{}?;node={}",
+            percent_encode(&self.service.to_string()),
+            percent_encode(&self.node.0)
+        )
+    }
+}
+
+impl FromStr for Reference {
+    type Err = Error;
+
+    /// Parses an `xmpp:service?;node=name` URI, as defined by RFC 5122.
+    fn from_str(uri: &str) -> Result<Reference, Error> {
+        let rest = match uri.find(':') {
+            Some(i) if uri[..i].eq_ignore_ascii_case("xmpp") => &uri[i + 1..],
+            _ => return Err(Error::ParseError("Comments URI isn’t an xmpp: URI.")),
+        };
+        let (service, query) = match rest.find('?') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => return Err(Error::ParseError("Missing node in comments URI.")),
+        };
+        if service.starts_with("//") {
+            return Err(Error::ParseError(
+                "Comments URI can’t have an authority component.",
+            ));
+        }
+        let service = Jid::from_str(&percent_decode(service)?)?;
+        // The first key is the action, usually none for PubSub nodes.
+        let node = query
+            .split(';')
+            .skip(1)
+            .find_map(|pair| pair.strip_prefix("node="))
+            .ok_or(Error::ParseError("Missing node in comments URI."))?;
+        let node = percent_decode(node)?;
+        if node.is_empty() {
+            return Err(Error::ParseError("Missing node in comments URI."));
+        }
+        Ok(Reference {
+            service,
+            node: NodeName(node),
+        })
+    }
+}
+
+impl TryFrom<Element> for Reference {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<Reference, Error> {
+        check_self!(elem, "link", ATOM);
+        if elem.attr("rel") != Some("replies") {
+            return Err(Error::ParseError("Link isn’t one to replies."));
+        }
+        // Other attributes such as the title and the children are free-form in Atom.
+        let href: String = get_attr!(elem, "href", Required);
+        href.parse()
+    }
+}
+
+impl From<Reference> for Element {
+    fn from(reference: Reference) -> Element {
+        Element::builder("link", ns::ATOM)
+            .attr("rel", "replies")
+            .attr("title", "comments")
+            .attr("href", reference.to_uri())
+            .build()
+    }
+}
+
+/// The configuration to create a comments node with: anyone may read it and publish to it,
+/// and every comment is kept.
+pub fn comments_node_config() -> DataForm {
+    DataForm::new(
+        DataFormType::Submit,
+        ns::PUBSUB_CONFIGURE,
+        vec![
+            Field::text_single("pubsub#access_model", "open"),
+            Field::text_single("pubsub#publish_model", "open"),
+            Field::new("pubsub#persist_items", FieldType::Boolean).with_value("true"),
+            Field::text_single("pubsub#max_items", "max"),
+            Field::new("pubsub#notify_retract", FieldType::Boolean).with_value("true"),
+        ],
+    )
+}
+
+/// Encodes everything but the unreserved characters of RFC 3986.
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn percent_decode(s: &str) -> Result<String, Error> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or(Error::ParseError(
+                    "Invalid percent-encoding in comments URI.",
+                ))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded)
+        .map_err(|_| Error::ParseError("Invalid percent-encoding in comments URI."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jid::BareJid;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(Reference, 48);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(Reference, 96);
+    }
+
+    #[test]
+    fn test_link() {
+        let elem: Element = "<link xmlns='http://www.w3.org/2005/Atom' rel='replies' title='comments' href='xmpp:pubsub.montague.lit?;node=urn%3Axmpp%3Amicroblog%3A0%3Acomments%2Fball'/>"
+            .parse()
+            .unwrap();
+        let reference = Reference::try_from(elem.clone()).unwrap();
+        assert_eq!(
+            reference.service,
+            Jid::Bare(BareJid::domain("pubsub.montague.lit"))
+        );
+        assert_eq!(reference.node.0, "urn:xmpp:microblog:0:comments/ball");
+        assert_eq!(
+            reference,
+            Reference::for_item(reference.service.clone(), &ItemId(String::from("ball")))
+        );
+        assert_eq!(Element::from(reference), elem);
+    }
+
+    #[test]
+    fn test_find() {
+        let entry: Element = "<entry xmlns='http://www.w3.org/2005/Atom'>
+                <title>Balcony</title>
+                <link rel='alternate' href='https://montague.lit/balcony'/>
+                <link rel='replies' href='xmpp:romeo@montague.lit?pubsub;action=subscribe;node=comments%2Fbalcony'/>
+            </entry>"
+            .parse()
+            .unwrap();
+        let reference = Reference::find(&entry).unwrap();
+        assert_eq!(
+            reference.service,
+            Jid::Bare(BareJid::new("romeo", "montague.lit"))
+        );
+        assert_eq!(reference.node.0, "comments/balcony");
+
+        let entry: Element = "<entry xmlns='http://www.w3.org/2005/Atom'><link rel='replies' href='https://montague.lit/balcony#comments'/></entry>"
+            .parse()
+            .unwrap();
+        assert_eq!(Reference::find(&entry), None);
+    }
+
+    #[test]
+    fn test_invalid_uri() {
+        let message = |uri: &str| match uri.parse::<Reference>().unwrap_err() {
+            Error::ParseError(string) => string,
+            error => panic!("Unexpected error {:?}", error),
+        };
+        assert_eq!(
+            message("https://pubsub.montague.lit/comments"),
+            "Comments URI isn’t an xmpp: URI."
+        );
+        assert_eq!(
+            message("xmpp:pubsub.montague.lit"),
+            "Missing node in comments URI."
+        );
+        assert_eq!(
+            message("xmpp:pubsub.montague.lit?;node="),
+            "Missing node in comments URI."
+        );
+        assert_eq!(
+            message("xmpp://romeo@montague.lit/pubsub.montague.lit?;node=comments"),
+            "Comments URI can’t have an authority component."
+        );
+        assert_eq!(
+            message("xmpp:pubsub.montague.lit?;node=comments%2"),
+            "Invalid percent-encoding in comments URI."
+        );
+    }
+
+    #[test]
+    fn test_node_config() {
+        let form = comments_node_config();
+        assert_eq!(form.form_type.as_deref(), Some(ns::PUBSUB_CONFIGURE));
+        let publish_model = form
+            .fields
+            .iter()
+            .find(|field| field.var == "pubsub#publish_model")
+            .unwrap();
+        assert_eq!(publish_model.values, ["open"]);
+    }
+}
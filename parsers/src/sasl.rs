@@ -40,6 +40,15 @@ generate_attribute!(
         /// Creates a temporary JID on login, which will be destroyed on
         /// disconnect.
         Anonymous => "ANONYMOUS",
+
+        /// Transmits an OAuth 2.0 bearer token instead of a password.
+        ///
+        /// See https://tools.ietf.org/html/rfc7628
+        OAuthBearer => "OAUTHBEARER",
+
+        /// Same as [OAuthBearer](#structfield.OAuthBearer), in the older
+        /// non-standard format some servers still only support.
+        XOAuth2 => "X-OAUTH2",
     }
 );
 
@@ -0,0 +1,586 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::iq::{IqGetPayload, IqResultPayload};
+use crate::message::MessagePayload;
+use crate::ns;
+use crate::util::error::Error;
+use crate::util::helpers::Text;
+use crate::Element;
+use jid::Jid;
+use minidom::IntoAttributeValue;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+/// The CSS named colors, sorted so that they can be searched.
+const NAMED_COLORS: [&str; 148] = [
+    "aliceblue",
+    "antiquewhite",
+    "aqua",
+    "aquamarine",
+    "azure",
+    "beige",
+    "bisque",
+    "black",
+    "blanchedalmond",
+    "blue",
+    "blueviolet",
+    "brown",
+    "burlywood",
+    "cadetblue",
+    "chartreuse",
+    "chocolate",
+    "coral",
+    "cornflowerblue",
+    "cornsilk",
+    "crimson",
+    "cyan",
+    "darkblue",
+    "darkcyan",
+    "darkgoldenrod",
+    "darkgray",
+    "darkgreen",
+    "darkgrey",
+    "darkkhaki",
+    "darkmagenta",
+    "darkolivegreen",
+    "darkorange",
+    "darkorchid",
+    "darkred",
+    "darksalmon",
+    "darkseagreen",
+    "darkslateblue",
+    "darkslategray",
+    "darkslategrey",
+    "darkturquoise",
+    "darkviolet",
+    "deeppink",
+    "deepskyblue",
+    "dimgray",
+    "dimgrey",
+    "dodgerblue",
+    "firebrick",
+    "floralwhite",
+    "forestgreen",
+    "fuchsia",
+    "gainsboro",
+    "ghostwhite",
+    "gold",
+    "goldenrod",
+    "gray",
+    "green",
+    "greenyellow",
+    "grey",
+    "honeydew",
+    "hotpink",
+    "indianred",
+    "indigo",
+    "ivory",
+    "khaki",
+    "lavender",
+    "lavenderblush",
+    "lawngreen",
+    "lemonchiffon",
+    "lightblue",
+    "lightcoral",
+    "lightcyan",
+    "lightgoldenrodyellow",
+    "lightgray",
+    "lightgreen",
+    "lightgrey",
+    "lightpink",
+    "lightsalmon",
+    "lightseagreen",
+    "lightskyblue",
+    "lightslategray",
+    "lightslategrey",
+    "lightsteelblue",
+    "lightyellow",
+    "lime",
+    "limegreen",
+    "linen",
+    "magenta",
+    "maroon",
+    "mediumaquamarine",
+    "mediumblue",
+    "mediumorchid",
+    "mediumpurple",
+    "mediumseagreen",
+    "mediumslateblue",
+    "mediumspringgreen",
+    "mediumturquoise",
+    "mediumvioletred",
+    "midnightblue",
+    "mintcream",
+    "mistyrose",
+    "moccasin",
+    "navajowhite",
+    "navy",
+    "oldlace",
+    "olive",
+    "olivedrab",
+    "orange",
+    "orangered",
+    "orchid",
+    "palegoldenrod",
+    "palegreen",
+    "paleturquoise",
+    "palevioletred",
+    "papayawhip",
+    "peachpuff",
+    "peru",
+    "pink",
+    "plum",
+    "powderblue",
+    "purple",
+    "rebeccapurple",
+    "red",
+    "rosybrown",
+    "royalblue",
+    "saddlebrown",
+    "salmon",
+    "sandybrown",
+    "seagreen",
+    "seashell",
+    "sienna",
+    "silver",
+    "skyblue",
+    "slateblue",
+    "slategray",
+    "slategrey",
+    "snow",
+    "springgreen",
+    "steelblue",
+    "tan",
+    "teal",
+    "thistle",
+    "tomato",
+    "turquoise",
+    "violet",
+    "wheat",
+    "white",
+    "whitesmoke",
+    "yellow",
+    "yellowgreen",
+];
+
+/// A CSS color, either one of its named colors or in hexadecimal notation
+/// (`#rgb` or `#rrggbb`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Color(String);
+
+impl Color {
+    /// The color as it was written, which isn’t normalised.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Color {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Color, Error> {
+        let valid = match s.strip_prefix('#') {
+            Some(hex) => {
+                (hex.len() == 3 || hex.len() == 6) && hex.bytes().all(|c| c.is_ascii_hexdigit())
+            }
+            None => NAMED_COLORS
+                .binary_search(&s.to_ascii_lowercase().as_str())
+                .is_ok(),
+        };
+        if !valid {
+            return Err(Error::ParseError("Invalid CSS color."));
+        }
+        Ok(Color(String::from(s)))
+    }
+}
+
+impl IntoAttributeValue for Color {
+    fn into_attribute_value(self) -> Option<String> {
+        Some(self.0)
+    }
+}
+
+generate_element!(
+    /// How a label should be shown to users, for instance a red banner.
+    DisplayMarking, "displaymarking", SEC_LABEL,
+    attributes: [
+        /// The color of the text, black if unset.
+        fgcolor: Option<Color> = "fgcolor",
+
+        /// The color of the background, white if unset.
+        bgcolor: Option<Color> = "bgcolor"
+    ],
+    text: (
+        /// The text to display, such as “SECRET”.
+        marking: Text<String>
+    )
+);
+
+fn parse_label(elem: &Element) -> Result<Element, Error> {
+    let mut children = elem.children();
+    let payload = children
+        .next()
+        .ok_or(Error::ParseError("Missing label in label element."))?;
+    if children.next().is_some() {
+        return Err(Error::ParseError(
+            "Label element must not have more than one label.",
+        ));
+    }
+    Ok(payload.clone())
+}
+
+/// The label itself, in a format depending on the policy in use, such as
+/// ESS (RFC 2634) whose labels are encoded in
+/// `<esssecuritylabel xmlns='urn:xmpp:sec-label:ess:0'/>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    /// The label, left for the application to interpret.
+    pub payload: Element,
+}
+
+impl TryFrom<Element> for Label {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<Label, Error> {
+        check_self!(elem, "label", SEC_LABEL);
+        check_no_attributes!(elem, "label");
+        Ok(Label {
+            payload: parse_label(&elem)?,
+        })
+    }
+}
+
+impl From<Label> for Element {
+    fn from(label: Label) -> Element {
+        Element::builder("label", ns::SEC_LABEL)
+            .append(label.payload)
+            .build()
+    }
+}
+
+/// A label which is equivalent to the main one under another policy, so
+/// that entities not understanding the first can still make use of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EquivalentLabel {
+    /// The label, left for the application to interpret.
+    pub payload: Element,
+}
+
+impl TryFrom<Element> for EquivalentLabel {
+    type Error = Error;
+
+    fn try_from(elem: Element) -> Result<EquivalentLabel, Error> {
+        check_self!(elem, "equivalentlabel", SEC_LABEL);
+        check_no_attributes!(elem, "equivalentlabel");
+        Ok(EquivalentLabel {
+            payload: parse_label(&elem)?,
+        })
+    }
+}
+
+impl From<EquivalentLabel> for Element {
+    fn from(label: EquivalentLabel) -> Element {
+        Element::builder("equivalentlabel", ns::SEC_LABEL)
+            .append(label.payload)
+            .build()
+    }
+}
+
+generate_element!(
+    /// A security label, attached to a message to tell how it must be
+    /// handled.
+    SecurityLabel, "securitylabel", SEC_LABEL,
+    children: [
+        /// How to display this label.
+        display_marking: Option<DisplayMarking> = ("displaymarking", SEC_LABEL) => DisplayMarking,
+
+        /// The label itself, absent when the message isn’t labelled.
+        label: Option<Label> = ("label", SEC_LABEL) => Label,
+
+        /// The same label under other policies.
+        equivalent_labels: Vec<EquivalentLabel> = ("equivalentlabel", SEC_LABEL) => EquivalentLabel
+    ]
+);
+
+impl MessagePayload for SecurityLabel {}
+
+generate_element!(
+    /// Asks a server for the labels which can be used when sending to a
+    /// given entity.
+    ///
+    /// It should only be used in an `<iq type='get'/>`, as it can only
+    /// represent the request, and not a result.
+    CatalogQuery, "catalog", SEC_LABEL_CATALOG,
+    attributes: [
+        /// The entity the labelled stanzas would be sent to.
+        to: Option<Jid> = "to"
+    ]
+);
+
+impl IqGetPayload for CatalogQuery {}
+
+generate_attribute!(
+    /// Whether an item is the label to use by default.
+    IsDefault,
+    "default",
+    bool
+);
+
+generate_attribute!(
+    /// Whether only the labels of the catalog may be used.
+    Restrict,
+    "restrict",
+    bool
+);
+
+generate_element!(
+    /// One of the labels of a catalog.
+    Item, "item", SEC_LABEL_CATALOG,
+    attributes: [
+        /// Where to show this label in a user interface, its levels being
+        /// separated by `|`.
+        selector: Option<String> = "selector",
+
+        /// Whether this label should be selected by default.
+        default: Default<IsDefault> = "default"
+    ],
+    children: [
+        /// The label, absent to allow sending unlabelled stanzas.
+        label: Option<SecurityLabel> = ("securitylabel", SEC_LABEL) => SecurityLabel
+    ]
+);
+
+generate_element!(
+    /// The labels which can be used when sending to a given entity.
+    ///
+    /// It should only be used in an `<iq type='result'/>`, as it can only
+    /// represent the result, and not a request.
+    Catalog, "catalog", SEC_LABEL_CATALOG,
+    attributes: [
+        /// The entity this catalog applies to.
+        to: Option<Jid> = "to",
+
+        /// The name of this catalog.
+        name: Option<String> = "name",
+
+        /// A human-readable description of this catalog.
+        desc: Option<String> = "desc",
+
+        /// An identifier for this catalog.
+        id: Option<String> = "id",
+
+        /// How many items this catalog contains.
+        size: Option<usize> = "size",
+
+        /// Whether the labels must be picked from this catalog.
+        restrict: Default<Restrict> = "restrict"
+    ],
+    children: [
+        /// The labels, in the order they should be presented.
+        items: Vec<Item> = ("item", SEC_LABEL_CATALOG) => Item
+    ]
+);
+
+impl IqResultPayload for Catalog {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(Color, 12);
+        assert_size!(DisplayMarking, 36);
+        assert_size!(Label, 76);
+        assert_size!(EquivalentLabel, 76);
+        assert_size!(SecurityLabel, 124);
+        assert_size!(CatalogQuery, 36);
+        assert_size!(IsDefault, 1);
+        assert_size!(Restrict, 1);
+        assert_size!(Item, 140);
+        assert_size!(Catalog, 96);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(Color, 24);
+        assert_size!(DisplayMarking, 72);
+        assert_size!(Label, 152);
+        assert_size!(EquivalentLabel, 152);
+        assert_size!(SecurityLabel, 248);
+        assert_size!(CatalogQuery, 72);
+        assert_size!(IsDefault, 1);
+        assert_size!(Restrict, 1);
+        assert_size!(Item, 280);
+        assert_size!(Catalog, 192);
+    }
+
+    #[test]
+    fn test_security_label() {
+        let elem: Element = "<securitylabel xmlns='urn:xmpp:sec-label:0'><displaymarking fgcolor='black' bgcolor='red'>SECRET</displaymarking><label><esssecuritylabel xmlns='urn:xmpp:sec-label:ess:0'>MQYCAQQGASk=</esssecuritylabel></label><equivalentlabel><esssecuritylabel xmlns='urn:xmpp:sec-label:ess:0'>MRUCAgD9DA9BcXVhIChvYnNvbGV0ZSk=</esssecuritylabel></equivalentlabel></securitylabel>"
+            .parse()
+            .unwrap();
+        let label = SecurityLabel::try_from(elem.clone()).unwrap();
+        let marking = label.display_marking.as_ref().unwrap();
+        assert_eq!(marking.marking, "SECRET");
+        assert_eq!(marking.fgcolor.as_ref().unwrap().as_str(), "black");
+        assert_eq!(marking.bgcolor.as_ref().unwrap().as_str(), "red");
+        let payload = &label.label.as_ref().unwrap().payload;
+        assert!(payload.is("esssecuritylabel", "urn:xmpp:sec-label:ess:0"));
+        assert_eq!(payload.text(), "MQYCAQQGASk=");
+        assert_eq!(label.equivalent_labels.len(), 1);
+        assert_eq!(
+            label.equivalent_labels[0].payload.text(),
+            "MRUCAgD9DA9BcXVhIChvYnNvbGV0ZSk="
+        );
+        assert_eq!(Element::from(label), elem);
+    }
+
+    #[test]
+    fn test_colors() {
+        for color in &["red", "Navy", "rebeccapurple", "#fff", "#00FF7f"] {
+            assert_eq!(Color::from_str(color).unwrap().as_str(), *color);
+        }
+        for color in &[
+            "",
+            "#",
+            "#ff",
+            "#ffff",
+            "#ggg",
+            "blurple",
+            "red; background: url(http://example.org/)",
+            "rgb(255, 0, 0)",
+        ] {
+            match Color::from_str(color) {
+                Err(Error::ParseError(string)) => assert_eq!(string, "Invalid CSS color."),
+                other => panic!("{:?} gave {:?}", color, other),
+            }
+        }
+
+        let elem: Element =
+            "<displaymarking xmlns='urn:xmpp:sec-label:0' fgcolor='javascript:alert(1)'>SECRET</displaymarking>"
+                .parse()
+                .unwrap();
+        let error = DisplayMarking::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Invalid CSS color.");
+    }
+
+    #[test]
+    fn test_invalid_label() {
+        let elem: Element = "<label xmlns='urn:xmpp:sec-label:0'/>".parse().unwrap();
+        let error = Label::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Missing label in label element.");
+
+        let elem: Element = "<equivalentlabel xmlns='urn:xmpp:sec-label:0'><a xmlns='urn:example'/><b xmlns='urn:example'/></equivalentlabel>"
+            .parse()
+            .unwrap();
+        let error = EquivalentLabel::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Label element must not have more than one label.");
+    }
+
+    #[test]
+    fn test_catalog_query() {
+        let elem: Element = "<catalog xmlns='urn:xmpp:sec-label:catalog:2' to='example.com'/>"
+            .parse()
+            .unwrap();
+        let query = CatalogQuery::try_from(elem.clone()).unwrap();
+        assert_eq!(query.to, Some(Jid::from_str("example.com").unwrap()));
+        assert_eq!(Element::from(query), elem);
+    }
+
+    #[test]
+    fn test_catalog() {
+        let elem: Element = "<catalog xmlns='urn:xmpp:sec-label:catalog:2' to='example.com' name='Default' desc='an example set of labels' restrict='false'>
+            <item selector='Classified|SECRET'>
+                <securitylabel xmlns='urn:xmpp:sec-label:0'>
+                    <displaymarking bgcolor='red' fgcolor='black'>SECRET</displaymarking>
+                    <label><esssecuritylabel xmlns='urn:xmpp:sec-label:ess:0'>MQYCAQQGASk=</esssecuritylabel></label>
+                </securitylabel>
+            </item>
+            <item selector='Classified|CONFIDENTIAL'>
+                <securitylabel xmlns='urn:xmpp:sec-label:0'>
+                    <displaymarking bgcolor='navy' fgcolor='black'>CONFIDENTIAL</displaymarking>
+                    <label><esssecuritylabel xmlns='urn:xmpp:sec-label:ess:0'>MQYCAQMGASk=</esssecuritylabel></label>
+                </securitylabel>
+            </item>
+            <item selector='Classified|RESTRICTED'>
+                <securitylabel xmlns='urn:xmpp:sec-label:0'>
+                    <displaymarking bgcolor='aqua' fgcolor='black'>RESTRICTED</displaymarking>
+                    <label><esssecuritylabel xmlns='urn:xmpp:sec-label:ess:0'>MQYCAQIGASk=</esssecuritylabel></label>
+                </securitylabel>
+            </item>
+            <item selector='UNCLASSIFIED' default='true'/>
+        </catalog>"
+            .parse()
+            .unwrap();
+        let catalog = Catalog::try_from(elem).unwrap();
+        assert_eq!(catalog.to, Some(Jid::from_str("example.com").unwrap()));
+        assert_eq!(catalog.name.as_deref(), Some("Default"));
+        assert_eq!(catalog.desc.as_deref(), Some("an example set of labels"));
+        assert_eq!(catalog.restrict, Restrict::False);
+        assert_eq!(catalog.items.len(), 4);
+        let selectors: Vec<_> = catalog
+            .items
+            .iter()
+            .map(|item| item.selector.as_deref().unwrap())
+            .collect();
+        assert_eq!(
+            selectors,
+            [
+                "Classified|SECRET",
+                "Classified|CONFIDENTIAL",
+                "Classified|RESTRICTED",
+                "UNCLASSIFIED"
+            ]
+        );
+        assert_eq!(catalog.items[0].default, IsDefault::False);
+        let marking = catalog.items[1]
+            .label
+            .as_ref()
+            .unwrap()
+            .display_marking
+            .as_ref()
+            .unwrap();
+        assert_eq!(marking.marking, "CONFIDENTIAL");
+        assert_eq!(marking.bgcolor.as_ref().unwrap().as_str(), "navy");
+        assert_eq!(catalog.items[3].default, IsDefault::True);
+        assert_eq!(catalog.items[3].label, None);
+
+        // restrict='false' being the default, it doesn’t get serialised back.
+        let elem = Element::from(catalog.clone());
+        assert_eq!(elem.attr("restrict"), None);
+        assert_eq!(Catalog::try_from(elem).unwrap(), catalog);
+    }
+
+    #[test]
+    fn test_invalid_catalog_item() {
+        let elem: Element =
+            "<item xmlns='urn:xmpp:sec-label:catalog:2' selector='SECRET' default='maybe'/>"
+                .parse()
+                .unwrap();
+        let error = Item::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Unknown value for 'default' attribute.");
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::bob::ContentId;
+use crate::util::error::Error;
+use minidom::IntoAttributeValue;
+use std::str::FromStr;
+
+/// Where the data of a thumbnail can be found.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ThumbnailUri {
+    /// Bits of Binary (XEP-0231) to request from the entity which sent the
+    /// thumbnail, unless they are already cached.
+    Cid(ContentId),
+
+    /// An http: or https: URL to download it from.
+    Http(String),
+}
+
+impl FromStr for ThumbnailUri {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ThumbnailUri, Error> {
+        let scheme = s.split(':').next().unwrap_or("");
+        if s.len() == scheme.len() {
+            return Err(Error::ParseError("Missing scheme in thumbnail URI."));
+        }
+        if scheme.eq_ignore_ascii_case("cid") {
+            Ok(ThumbnailUri::Cid(s[scheme.len() + 1..].parse()?))
+        } else if scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https") {
+            Ok(ThumbnailUri::Http(String::from(s)))
+        } else {
+            Err(Error::ParseError(
+                "Thumbnail URI must use the cid, http or https scheme.",
+            ))
+        }
+    }
+}
+
+impl IntoAttributeValue for ThumbnailUri {
+    fn into_attribute_value(self) -> Option<String> {
+        match self {
+            ThumbnailUri::Cid(cid) => cid.into_attribute_value().map(|cid| format!("cid:{}", cid)),
+            ThumbnailUri::Http(url) => Some(url),
+        }
+    }
+}
+
+generate_element!(
+    /// A small preview of a file, such as an image, to be displayed before
+    /// it gets downloaded.
+    Thumbnail, "thumbnail", THUMBS,
+    attributes: [
+        /// Where to get the thumbnail from.
+        uri: Required<ThumbnailUri> = "uri",
+
+        /// The MIME type of the thumbnail.
+        ///
+        /// See the [IANA MIME Media Types Registry][1] for a list of
+        /// registered types, but unregistered or yet-to-be-registered are
+        /// accepted too.
+        ///
+        /// [1]: https://www.iana.org/assignments/media-types/media-types.xhtml
+        media_type: Option<String> = "media-type",
+
+        /// The width of the thumbnail, in pixels.
+        width: Option<u16> = "width",
+
+        /// The height of the thumbnail, in pixels.
+        height: Option<u16> = "height"
+    ]
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Element;
+    use std::convert::TryFrom;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(ThumbnailUri, 24);
+        assert_size!(Thumbnail, 44);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(ThumbnailUri, 48);
+        assert_size!(Thumbnail, 80);
+    }
+
+    #[test]
+    fn test_cid() {
+        let elem: Element = "<thumbnail xmlns='urn:xmpp:thumbs:1' uri='cid:sha1+ffd7c8d28e9c5e82afea41f97108c6b4@bob.xmpp.org' media-type='image/png' width='128' height='96'/>"
+            .parse()
+            .unwrap();
+        let thumbnail = Thumbnail::try_from(elem.clone()).unwrap();
+        match &thumbnail.uri {
+            ThumbnailUri::Cid(cid) => assert_eq!(
+                cid,
+                &"sha1+ffd7c8d28e9c5e82afea41f97108c6b4@bob.xmpp.org"
+                    .parse::<ContentId>()
+                    .unwrap()
+            ),
+            uri => panic!("Unexpected URI {:?}", uri),
+        }
+        assert_eq!(thumbnail.media_type.as_deref(), Some("image/png"));
+        assert_eq!(thumbnail.width, Some(128));
+        assert_eq!(thumbnail.height, Some(96));
+        assert_eq!(Element::from(thumbnail), elem);
+    }
+
+    #[test]
+    fn test_http() {
+        let elem: Element =
+            "<thumbnail xmlns='urn:xmpp:thumbs:1' uri='https://example.org/preview.jpg'/>"
+                .parse()
+                .unwrap();
+        let thumbnail = Thumbnail::try_from(elem.clone()).unwrap();
+        assert_eq!(
+            thumbnail.uri,
+            ThumbnailUri::Http(String::from("https://example.org/preview.jpg"))
+        );
+        assert_eq!(thumbnail.media_type, None);
+        assert_eq!(thumbnail.width, None);
+        assert_eq!(Element::from(thumbnail), elem);
+
+        let uri: ThumbnailUri = "HTTP://example.org/preview.jpg".parse().unwrap();
+        assert_eq!(
+            uri,
+            ThumbnailUri::Http(String::from("HTTP://example.org/preview.jpg"))
+        );
+    }
+
+    #[test]
+    fn test_invalid_uri() {
+        let message = |uri: &str| match uri.parse::<ThumbnailUri>().unwrap_err() {
+            Error::ParseError(string) => string,
+            error => panic!("Unexpected error {:?}", error),
+        };
+        assert_eq!(
+            message("file:///tmp/preview.png"),
+            "Thumbnail URI must use the cid, http or https scheme."
+        );
+        assert_eq!(message("preview.png"), "Missing scheme in thumbnail URI.");
+        assert_eq!(message("cid:preview.png"), "Missing @ in cid URI.");
+        // A cid whose hash can’t be verified is no use.
+        assert_eq!(
+            message("cid:md5+1234@bob.xmpp.org"),
+            "Unknown hash algorithm in cid URI."
+        );
+
+        let elem: Element =
+            "<thumbnail xmlns='urn:xmpp:thumbs:1' uri='data:image/png;base64,AAAA'/>"
+                .parse()
+                .unwrap();
+        assert!(Thumbnail::try_from(elem).is_err());
+
+        let elem: Element = "<thumbnail xmlns='urn:xmpp:thumbs:1'/>".parse().unwrap();
+        assert!(Thumbnail::try_from(elem).is_err());
+    }
+}
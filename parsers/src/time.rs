@@ -4,12 +4,11 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::date::DateTime;
+use crate::date::{DateTime, Offset};
 use crate::iq::{IqGetPayload, IqResultPayload};
 use crate::ns;
 use crate::util::error::Error;
 use crate::Element;
-use chrono::FixedOffset;
 use std::convert::TryFrom;
 use std::str::FromStr;
 
@@ -45,10 +44,7 @@ impl TryFrom<Element> for TimeResult {
                 }
                 check_no_children!(child, "tzo");
                 check_no_attributes!(child, "tzo");
-                // TODO: Add a FromStr implementation to FixedOffset to avoid this hack.
-                let fake_date = String::from("2019-04-22T11:38:00") + &child.text();
-                let date_time = DateTime::from_str(&fake_date)?;
-                tzo = Some(date_time.timezone());
+                tzo = Some(Offset::from_str(&child.text())?);
             } else if child.is("utc", ns::TIME) {
                 if utc.is_some() {
                     return Err(Error::ParseError("More than one utc element in time."));
@@ -56,7 +52,7 @@ impl TryFrom<Element> for TimeResult {
                 check_no_children!(child, "utc");
                 check_no_attributes!(child, "utc");
                 let date_time = DateTime::from_str(&child.text())?;
-                if date_time.timezone() != FixedOffset::east(0) {
+                if date_time.offset() != Offset::UTC {
                     return Err(Error::ParseError("Non-UTC timezone for utc element."));
                 }
                 utc = Some(date_time);
@@ -67,7 +63,7 @@ impl TryFrom<Element> for TimeResult {
 
         let tzo = tzo.ok_or(Error::ParseError("Missing tzo child in time element."))?;
         let utc = utc.ok_or(Error::ParseError("Missing utc child in time element."))?;
-        let date = utc.with_timezone(tzo);
+        let date = utc.with_offset(tzo);
 
         Ok(TimeResult(date))
     }
@@ -76,11 +72,8 @@ impl TryFrom<Element> for TimeResult {
 impl From<TimeResult> for Element {
     fn from(time: TimeResult) -> Element {
         Element::builder("time", ns::TIME)
-            .append(Element::builder("tzo", ns::TIME).append(format!("{}", time.0.timezone())))
-            .append(
-                Element::builder("utc", ns::TIME)
-                    .append(time.0.with_timezone(FixedOffset::east(0)).format("%FT%TZ")),
-            )
+            .append(Element::builder("tzo", ns::TIME).append(time.0.offset().to_string()))
+            .append(Element::builder("utc", ns::TIME).append(time.0))
             .build()
     }
 }
@@ -104,7 +97,7 @@ mod tests {
                 .unwrap();
         let elem1 = elem.clone();
         let time = TimeResult::try_from(elem).unwrap();
-        assert_eq!(time.0.timezone(), FixedOffset::west(6 * 3600));
+        assert_eq!(time.0.offset(), Offset::from_minutes(-6 * 60).unwrap());
         assert_eq!(
             time.0,
             DateTime::from_str("2006-12-19T12:58:35-05:00").unwrap()
@@ -112,4 +105,39 @@ mod tests {
         let elem2 = Element::from(time);
         assert_eq!(elem1, elem2);
     }
+
+    #[test]
+    fn test_canonical() {
+        // The utc element is always written with a Z, and the tzo one in full.
+        let elem: Element =
+            "<time xmlns='urn:xmpp:time'><tzo>Z</tzo><utc>2006-12-19T17:58:35.250+00:00</utc></time>"
+                .parse()
+                .unwrap();
+        let time = TimeResult::try_from(elem).unwrap();
+        let elem = Element::from(time.clone());
+        let expected: Element =
+            "<time xmlns='urn:xmpp:time'><tzo>+00:00</tzo><utc>2006-12-19T17:58:35.250Z</utc></time>"
+                .parse()
+                .unwrap();
+        assert_eq!(elem, expected);
+        assert_eq!(TimeResult::try_from(elem).unwrap().0, time.0);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let elem: Element =
+            "<time xmlns='urn:xmpp:time'><tzo>-06</tzo><utc>2006-12-19T17:58:35Z</utc></time>"
+                .parse()
+                .unwrap();
+        assert!(TimeResult::try_from(elem).is_err());
+        let elem: Element =
+            "<time xmlns='urn:xmpp:time'><tzo>-06:00</tzo><utc>2006-12-19T11:58:35-06:00</utc></time>"
+                .parse()
+                .unwrap();
+        let message = match TimeResult::try_from(elem) {
+            Err(Error::ParseError(message)) => message,
+            result => panic!("unexpected result: {:?}", result),
+        };
+        assert_eq!(message, "Non-UTC timezone for utc element.");
+    }
 }
@@ -35,9 +35,9 @@ pub enum Error {
     /// fails to parse.
     JidParseError(jid::JidParseError),
 
-    /// Generated when text which should be a
-    /// [DateTime](../date/struct.DateTime.html) fails to parse.
-    ChronoParseError(chrono::ParseError),
+    /// Generated when a DTLS fingerprint uses a hash algorithm we can’t
+    /// handle, contains the name of this algorithm.
+    UnsupportedFingerprintAlgo(String),
 }
 
 impl StdError for Error {
@@ -49,7 +49,7 @@ impl StdError for Error {
             Error::ParseStringError(e) => Some(e),
             Error::ParseAddrError(e) => Some(e),
             Error::JidParseError(e) => Some(e),
-            Error::ChronoParseError(e) => Some(e),
+            Error::UnsupportedFingerprintAlgo(_) => None,
         }
     }
 }
@@ -63,7 +63,9 @@ impl fmt::Display for Error {
             Error::ParseStringError(e) => write!(fmt, "string parsing error: {}", e),
             Error::ParseAddrError(e) => write!(fmt, "IP address parsing error: {}", e),
             Error::JidParseError(e) => write!(fmt, "JID parsing error: {}", e),
-            Error::ChronoParseError(e) => write!(fmt, "time parsing error: {}", e),
+            Error::UnsupportedFingerprintAlgo(algo) => {
+                write!(fmt, "unsupported fingerprint hash algorithm: {}", algo)
+            }
         }
     }
 }
@@ -97,9 +99,3 @@ impl From<jid::JidParseError> for Error {
         Error::JidParseError(err)
     }
 }
-
-impl From<chrono::ParseError> for Error {
-    fn from(err: chrono::ParseError) -> Error {
-        Error::ChronoParseError(err)
-    }
-}
@@ -263,43 +263,6 @@ macro_rules! generate_element_enum {
     );
 }
 
-macro_rules! generate_attribute_enum {
-    ($(#[$meta:meta])* $elem:ident, $name:tt, $ns:ident, $attr:tt, {$($(#[$enum_meta:meta])* $enum:ident => $enum_name:tt),+,}) => (
-        generate_attribute_enum!($(#[$meta])* $elem, $name, $ns, $attr, {$($(#[$enum_meta])* $enum => $enum_name),+});
-    );
-    ($(#[$meta:meta])* $elem:ident, $name:tt, $ns:ident, $attr:tt, {$($(#[$enum_meta:meta])* $enum:ident => $enum_name:tt),+}) => (
-        $(#[$meta])*
-        #[derive(Debug, Clone, PartialEq)]
-        pub enum $elem {
-            $(
-                $(#[$enum_meta])*
-                $enum
-            ),+
-        }
-        impl ::std::convert::TryFrom<crate::Element> for $elem {
-            type Error = crate::util::error::Error;
-            fn try_from(elem: crate::Element) -> Result<$elem, crate::util::error::Error> {
-                check_ns_only!(elem, $name, $ns);
-                check_no_children!(elem, $name);
-                check_no_unknown_attributes!(elem, $name, [$attr]);
-                Ok(match get_attr!(elem, $attr, Required) {
-                    $($enum_name => $elem::$enum,)+
-                    _ => return Err(crate::util::error::Error::ParseError(concat!("Invalid ", $name, " ", $attr, " value."))),
-                })
-            }
-        }
-        impl From<$elem> for crate::Element {
-            fn from(elem: $elem) -> crate::Element {
-                crate::Element::builder($name, crate::ns::$ns)
-                    .attr($attr, match elem {
-                         $($elem::$enum => $enum_name,)+
-                     })
-                     .build()
-            }
-        }
-    );
-}
-
 macro_rules! check_self {
     ($elem:ident, $name:tt, $ns:ident) => {
         check_self!($elem, $name, $ns, $name);
@@ -612,6 +575,18 @@ macro_rules! generate_serialiser {
     };
 }
 
+// The `unknown_children` directive of [`generate_element!`], whose only mode is `preserve`:
+// children matching none of those listed get kept in order in a `pub unknown: Vec<Element>`
+// field instead of failing the parsing, and get serialised back after the known ones.
+macro_rules! unknown_children {
+    (preserve) => (
+        Vec<crate::Element>
+    );
+    (preserve, $value:expr) => {
+        $value
+    };
+}
+
 macro_rules! generate_child_test {
     ($child:ident, $name:tt, *) => {
         $child.is($name, ::minidom::NSChoice::Any)
@@ -622,17 +597,17 @@ macro_rules! generate_child_test {
 }
 
 macro_rules! generate_element {
-    ($(#[$meta:meta])* $elem:ident, $name:tt, $ns:ident, attributes: [$($(#[$attr_meta:meta])* $attr:ident: $attr_action:tt<$attr_type:ty> = $attr_name:tt),+,]) => (
-        generate_element!($(#[$meta])* $elem, $name, $ns, attributes: [$($(#[$attr_meta])* $attr: $attr_action<$attr_type> = $attr_name),*], children: []);
+    ($(#[$meta:meta])* $elem:ident, $name:tt, $ns:ident, attributes: [$($(#[$attr_meta:meta])* $attr:ident: $attr_action:tt<$attr_type:ty> = $attr_name:tt),+,]$(, unknown_children: $unknown:ident)?) => (
+        generate_element!($(#[$meta])* $elem, $name, $ns, attributes: [$($(#[$attr_meta])* $attr: $attr_action<$attr_type> = $attr_name),*], children: []$(, unknown_children: $unknown)?);
     );
-    ($(#[$meta:meta])* $elem:ident, $name:tt, $ns:ident, attributes: [$($(#[$attr_meta:meta])* $attr:ident: $attr_action:tt<$attr_type:ty> = $attr_name:tt),+]) => (
-        generate_element!($(#[$meta])* $elem, $name, $ns, attributes: [$($(#[$attr_meta])* $attr: $attr_action<$attr_type> = $attr_name),*], children: []);
+    ($(#[$meta:meta])* $elem:ident, $name:tt, $ns:ident, attributes: [$($(#[$attr_meta:meta])* $attr:ident: $attr_action:tt<$attr_type:ty> = $attr_name:tt),+]$(, unknown_children: $unknown:ident)?) => (
+        generate_element!($(#[$meta])* $elem, $name, $ns, attributes: [$($(#[$attr_meta])* $attr: $attr_action<$attr_type> = $attr_name),*], children: []$(, unknown_children: $unknown)?);
     );
-    ($(#[$meta:meta])* $elem:ident, $name:tt, $ns:ident, children: [$($(#[$child_meta:meta])* $child_ident:ident: $coucou:tt<$child_type:ty> = ($child_name:tt, $child_ns:tt) => $child_constructor:ident),*]) => (
-        generate_element!($(#[$meta])* $elem, $name, $ns, attributes: [], children: [$($(#[$child_meta])* $child_ident: $coucou<$child_type> = ($child_name, $child_ns) => $child_constructor),*]);
+    ($(#[$meta:meta])* $elem:ident, $name:tt, $ns:ident, children: [$($(#[$child_meta:meta])* $child_ident:ident: $coucou:tt<$child_type:ty> = ($child_name:tt, $child_ns:tt) => $child_constructor:ident),*]$(, unknown_children: $unknown:ident)?) => (
+        generate_element!($(#[$meta])* $elem, $name, $ns, attributes: [], children: [$($(#[$child_meta])* $child_ident: $coucou<$child_type> = ($child_name, $child_ns) => $child_constructor),*]$(, unknown_children: $unknown)?);
     );
-    ($(#[$meta:meta])* $elem:ident, $name:tt, $ns:ident, attributes: [$($(#[$attr_meta:meta])* $attr:ident: $attr_action:tt<$attr_type:ty> = $attr_name:tt),*,], children: [$($(#[$child_meta:meta])* $child_ident:ident: $coucou:tt<$child_type:ty> = ($child_name:tt, $child_ns:tt) => $child_constructor:ident),*]) => (
-        generate_element!($(#[$meta])* $elem, $name, $ns, attributes: [$($(#[$attr_meta])* $attr: $attr_action<$attr_type> = $attr_name),*], children: [$($(#[$child_meta])* $child_ident: $coucou<$child_type> = ($child_name, $child_ns) => $child_constructor),*]);
+    ($(#[$meta:meta])* $elem:ident, $name:tt, $ns:ident, attributes: [$($(#[$attr_meta:meta])* $attr:ident: $attr_action:tt<$attr_type:ty> = $attr_name:tt),*,], children: [$($(#[$child_meta:meta])* $child_ident:ident: $coucou:tt<$child_type:ty> = ($child_name:tt, $child_ns:tt) => $child_constructor:ident),*]$(, unknown_children: $unknown:ident)?) => (
+        generate_element!($(#[$meta])* $elem, $name, $ns, attributes: [$($(#[$attr_meta])* $attr: $attr_action<$attr_type> = $attr_name),*], children: [$($(#[$child_meta])* $child_ident: $coucou<$child_type> = ($child_name, $child_ns) => $child_constructor),*]$(, unknown_children: $unknown)?);
     );
     ($(#[$meta:meta])* $elem:ident, $name:tt, $ns:ident, text: ($(#[$text_meta:meta])* $text_ident:ident: $codec:ident < $text_type:ty >)) => (
         generate_element!($(#[$meta])* $elem, $name, $ns, attributes: [], children: [], text: ($(#[$text_meta])* $text_ident: $codec<$text_type>));
@@ -640,7 +615,7 @@ macro_rules! generate_element {
     ($(#[$meta:meta])* $elem:ident, $name:tt, $ns:ident, attributes: [$($(#[$attr_meta:meta])* $attr:ident: $attr_action:tt<$attr_type:ty> = $attr_name:tt),+], text: ($(#[$text_meta:meta])* $text_ident:ident: $codec:ident < $text_type:ty >)) => (
         generate_element!($(#[$meta])* $elem, $name, $ns, attributes: [$($(#[$attr_meta])* $attr: $attr_action<$attr_type> = $attr_name),*], children: [], text: ($(#[$text_meta])* $text_ident: $codec<$text_type>));
     );
-    ($(#[$meta:meta])* $elem:ident, $name:tt, $ns:ident, attributes: [$($(#[$attr_meta:meta])* $attr:ident: $attr_action:tt<$attr_type:ty> = $attr_name:tt),*], children: [$($(#[$child_meta:meta])* $child_ident:ident: $coucou:tt<$child_type:ty> = ($child_name:tt, $child_ns:tt) => $child_constructor:ident),*] $(, text: ($(#[$text_meta:meta])* $text_ident:ident: $codec:ident < $text_type:ty >))*) => (
+    ($(#[$meta:meta])* $elem:ident, $name:tt, $ns:ident, attributes: [$($(#[$attr_meta:meta])* $attr:ident: $attr_action:tt<$attr_type:ty> = $attr_name:tt),*], children: [$($(#[$child_meta:meta])* $child_ident:ident: $coucou:tt<$child_type:ty> = ($child_name:tt, $child_ns:tt) => $child_constructor:ident),*] $(, unknown_children: $unknown:ident)? $(, text: ($(#[$text_meta:meta])* $text_ident:ident: $codec:ident < $text_type:ty >))*) => (
         $(#[$meta])*
         #[derive(Debug, Clone, PartialEq)]
         pub struct $elem {
@@ -656,6 +631,11 @@ macro_rules! generate_element {
                 $(#[$text_meta])*
                 pub $text_ident: $text_type,
             )*
+            $(
+                /// The children this version doesn’t know about, kept as they are so that they
+                /// survive forwarding this element.
+                pub unknown: unknown_children!($unknown),
+            )?
         }
 
         impl ::std::convert::TryFrom<crate::Element> for $elem {
@@ -667,6 +647,9 @@ macro_rules! generate_element {
                 $(
                     start_parse_elem!($child_ident: $coucou);
                 )*
+                $(
+                    let mut unknown_elems: unknown_children!($unknown) = Vec::new();
+                )?
                 for _child in elem.children() {
                     $(
                     if generate_child_test!(_child, $child_name, $child_ns) {
@@ -674,6 +657,11 @@ macro_rules! generate_element {
                         continue;
                     }
                     )*
+                    $(
+                        unknown_children!($unknown, unknown_elems.push(_child.clone()));
+                        continue;
+                    )?
+                    #[allow(unreachable_code)]
                     return Err(crate::util::error::Error::ParseError(concat!("Unknown child in ", $name, " element.")));
                 }
                 Ok($elem {
@@ -686,6 +674,9 @@ macro_rules! generate_element {
                     $(
                         $text_ident: $codec::decode(&elem.text())?,
                     )*
+                    $(
+                        unknown: unknown_children!($unknown, unknown_elems),
+                    )?
                 })
             }
         }
@@ -699,6 +690,9 @@ macro_rules! generate_element {
                 $(
                     builder = generate_serialiser!(builder, elem, $child_ident, $coucou, $child_constructor, ($child_name, $child_ns));
                 )*
+                $(
+                    builder = builder.append_all(unknown_children!($unknown, elem.unknown));
+                )?
                 $(
                     builder = builder.append_all($codec::encode(&elem.$text_ident).map(::minidom::Node::Text).into_iter());
                 )*
@@ -0,0 +1,90 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::presence::PresencePayload;
+use crate::util::helpers::PlainText;
+
+generate_element!(
+    /// Advertises the avatar of the sender in its presence.
+    VCardUpdate, "x", VCARD_UPDATE,
+    children: [
+        /// Absent if the client isn’t yet ready to advertise an avatar.
+        photo: Option<Photo> = ("photo", VCARD_UPDATE) => Photo
+    ]
+);
+
+impl PresencePayload for VCardUpdate {}
+
+generate_element!(
+    /// The avatar currently in use.
+    Photo, "photo", VCARD_UPDATE,
+    text: (
+        /// The hex-encoded SHA-1 hash of the avatar, or None if the user
+        /// doesn’t have one.
+        data: PlainText<Option<String>>
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::error::Error;
+    use crate::Element;
+    use std::convert::TryFrom;
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn test_size() {
+        assert_size!(VCardUpdate, 12);
+        assert_size!(Photo, 12);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_size() {
+        assert_size!(VCardUpdate, 24);
+        assert_size!(Photo, 24);
+    }
+
+    #[test]
+    fn test_simple() {
+        let elem: Element = "<x xmlns='vcard-temp:x:update'><photo>01b87fcd030b72895ff8e88db57ec525450f000d</photo></x>"
+            .parse()
+            .unwrap();
+        let update = VCardUpdate::try_from(elem.clone()).unwrap();
+        assert_eq!(
+            update.photo.clone().unwrap().data.unwrap(),
+            "01b87fcd030b72895ff8e88db57ec525450f000d"
+        );
+        let elem2 = Element::from(update);
+        assert_eq!(elem, elem2);
+
+        // No avatar.
+        let elem: Element = "<x xmlns='vcard-temp:x:update'><photo/></x>"
+            .parse()
+            .unwrap();
+        let update = VCardUpdate::try_from(elem).unwrap();
+        assert_eq!(update.photo.unwrap().data, None);
+
+        // Not ready to advertise one yet.
+        let elem: Element = "<x xmlns='vcard-temp:x:update'/>".parse().unwrap();
+        let update = VCardUpdate::try_from(elem).unwrap();
+        assert!(update.photo.is_none());
+    }
+
+    #[test]
+    fn test_invalid_child() {
+        let elem: Element = "<x xmlns='vcard-temp:x:update'><coucou/></x>"
+            .parse()
+            .unwrap();
+        let error = VCardUpdate::try_from(elem).unwrap_err();
+        let message = match error {
+            Error::ParseError(string) => string,
+            _ => panic!(),
+        };
+        assert_eq!(message, "Unknown child in x element.");
+    }
+}
@@ -180,6 +180,7 @@ fn make_disco() -> DiscoInfoResult {
         identities,
         features,
         extensions: vec![],
+        unknown: vec![],
     }
 }
 
@@ -205,6 +206,7 @@ fn download_avatar(from: Jid) -> Iq {
             node: NodeName(String::from(ns::AVATAR_DATA)),
             subid: None,
             items: Vec::new(),
+            unknown: Vec::new(),
         }),
     )
     .with_to(from)
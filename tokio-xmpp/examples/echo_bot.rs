@@ -2,8 +2,9 @@ use futures::stream::StreamExt;
 use std::convert::TryFrom;
 use std::env::args;
 use std::process::exit;
+use std::str::FromStr;
 use tokio;
-use tokio_xmpp::AsyncClient as Client;
+use tokio_xmpp::{AsyncClient as Client, AsyncConfig as Config, AsyncServerConfig as ServerConfig};
 use xmpp_parsers::message::{Body, Message, MessageType};
 use xmpp_parsers::presence::{Presence, Show as PresenceShow, Type as PresenceType};
 use xmpp_parsers::{Element, Jid};
@@ -11,15 +12,20 @@ use xmpp_parsers::{Element, Jid};
 #[tokio::main]
 async fn main() {
     let args: Vec<String> = args().collect();
-    if args.len() != 3 {
-        println!("Usage: {} <jid> <password>", args[0]);
-        exit(1);
-    }
-    let jid = &args[1];
-    let password = &args[2];
-
-    // Client instance
-    let mut client = Client::new(jid, password.to_owned()).unwrap();
+    let mut client = match args.len() {
+        // As bot@localhost, on the server of the mini_server example
+        2 if args[1] == "localhost" => Client::new_with_config(Config::new(
+            Jid::from_str("bot@localhost").unwrap(),
+            "bot",
+            ServerConfig::Loopback { port: 5222 },
+        )),
+        3 => Client::new(&args[1], args[2].to_owned()).unwrap(),
+        _ => {
+            println!("Usage: {} <jid> <password>", args[0]);
+            println!("   or: {} localhost", args[0]);
+            exit(1);
+        }
+    };
     client.set_reconnect(true);
 
     // Main loop, processes events
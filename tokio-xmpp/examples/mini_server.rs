@@ -0,0 +1,34 @@
+use std::env::args;
+use std::process::exit;
+use tokio_xmpp::mini_server::MiniServer;
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = args().collect();
+    let port = match args.len() {
+        1 => 5222,
+        2 => match args[1].parse() {
+            Ok(port) => port,
+            Err(_) => {
+                println!("Usage: {} [port]", args[0]);
+                exit(1);
+            }
+        },
+        _ => {
+            println!("Usage: {} [port]", args[0]);
+            exit(1);
+        }
+    };
+
+    let server = MiniServer::new("localhost")
+        .user("bot", "bot")
+        .user("user", "user")
+        .room("lounge");
+    let room = server.room_jid().unwrap().clone();
+    let (port, server) = server.listen(port).await.unwrap();
+    println!("Listening on 127.0.0.1:{}", port);
+    println!("Users: bot@localhost (password bot), user@localhost (password user)");
+    println!("Room: {}", room);
+    println!("Try: cargo run --example echo_bot -- localhost");
+    server.await.unwrap();
+}
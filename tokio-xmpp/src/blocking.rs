@@ -0,0 +1,94 @@
+//! A synchronous XMPP client, for scripts which don't otherwise use
+//! tokio
+//!
+//! ```no_run
+//! use std::str::FromStr;
+//! use tokio_xmpp::blocking::BlockingClient;
+//! use xmpp_parsers::message::{Body, Message};
+//! use xmpp_parsers::Jid;
+//!
+//! let mut client = BlockingClient::connect("cron@example.org", "password").unwrap();
+//! let mut message = Message::new(Some(Jid::from_str("admin@example.org").unwrap()));
+//! message.bodies.insert(String::new(), Body(String::from("Backup done.")));
+//! client.send_stanza(message).unwrap();
+//! client.end().unwrap();
+//! ```
+
+use futures::stream::StreamExt;
+use tokio::runtime::{Builder, Runtime};
+use xmpp_parsers::{Element, Jid};
+
+use crate::{AsyncClient, Error, Event, SharedError};
+
+/// A client blocking the current thread on each call
+///
+/// It wraps an [`AsyncClient`](crate::AsyncClient) which doesn't
+/// reconnect, driven by its own single-threaded runtime. It must not be
+/// used from within another tokio runtime.
+pub struct BlockingClient {
+    // Dropped before the runtime it runs on.
+    client: AsyncClient,
+    runtime: Runtime,
+}
+
+impl BlockingClient {
+    /// Connect, and wait for a usable session
+    ///
+    /// Fails with the reason of the disconnection, as in
+    /// `Event::Disconnected`.
+    pub fn connect<P: Into<String>>(jid: &str, password: P) -> Result<Self, SharedError> {
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(Error::from)?;
+        let mut client = AsyncClient::new(jid, password).map_err(Error::from)?;
+        client.set_reconnect(false);
+        runtime.block_on(async {
+            loop {
+                match client.next().await {
+                    Some(Event::Online { .. }) => return Ok(()),
+                    Some(Event::Disconnected(e)) => return Err(e),
+                    Some(_) => (),
+                    None => return Err(Error::Disconnected.into()),
+                }
+            }
+        })?;
+        Ok(BlockingClient { client, runtime })
+    }
+
+    /// Get the client's bound JID (the one reported by the XMPP
+    /// server).
+    pub fn bound_jid(&self) -> Option<&Jid> {
+        self.client.bound_jid()
+    }
+
+    /// Get the name of the SASL mechanism we authenticated with
+    pub fn sasl_mechanism(&self) -> Option<&str> {
+        self.client.sasl_mechanism()
+    }
+
+    /// Send stanza, and wait until it is written
+    pub fn send_stanza<E: Into<Element>>(&mut self, stanza: E) -> Result<(), Error> {
+        let client = &mut self.client;
+        self.runtime.block_on(client.send_stanza(stanza.into()))
+    }
+
+    /// Wait for the next event, `None` once the connection is over
+    pub fn next_event(&mut self) -> Option<Event> {
+        let client = &mut self.client;
+        self.runtime.block_on(client.next())
+    }
+
+    /// End connection by sending `</stream:stream>`, and wait for the
+    /// server to do the same
+    pub fn end(mut self) -> Result<(), Error> {
+        let client = &mut self.client;
+        self.runtime.block_on(client.send_end())?;
+        while let Some(event) = self.next_event() {
+            if let Event::Disconnected(_) = event {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
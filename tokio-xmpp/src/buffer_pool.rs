@@ -0,0 +1,167 @@
+//! Reusable byte buffers, so that a connection doesn't allocate new ones
+//! for every stanza it decodes or encodes
+
+use bytes::BytesMut;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// How many buffers a pool keeps by default
+pub(crate) const DEFAULT_MAX_BUFFERS: usize = 8;
+
+/// The biggest buffer a pool keeps by default, enough for the 64 KiB
+/// the codec reserves to encode a stanza
+pub(crate) const DEFAULT_MAX_CAPACITY: usize = 128 * 1024;
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    oversized: AtomicU64,
+}
+
+/// How much the buffer pool of a connection saves, see
+/// [`XMPPStream::pool_stats`](crate::xmpp_stream::XMPPStream::pool_stats)
+///
+/// This is a handle to counters which keep being updated.
+#[derive(Debug, Clone, Default)]
+pub struct PoolStats(Arc<Counters>);
+
+impl PoolStats {
+    /// How many buffers got reused
+    pub fn hits(&self) -> u64 {
+        self.0.hits.load(Ordering::Relaxed)
+    }
+
+    /// How many buffers had to be allocated, none being available
+    pub fn misses(&self) -> u64 {
+        self.0.misses.load(Ordering::Relaxed)
+    }
+
+    /// How many buffers got dropped instead of reused, as they grew too
+    /// big for one stanza
+    pub fn oversized(&self) -> u64 {
+        self.0.oversized.load(Ordering::Relaxed)
+    }
+}
+
+/// Buffers shared by the decoding and encoding sides of a connection
+///
+/// Those given back are kept up to a number and a capacity, so that a
+/// single huge stanza doesn't pin its memory for the rest of the
+/// connection.
+#[derive(Debug, Clone)]
+pub(crate) struct BufferPool {
+    buffers: Arc<Mutex<Vec<BytesMut>>>,
+    max_buffers: usize,
+    max_capacity: usize,
+    stats: PoolStats,
+}
+
+impl BufferPool {
+    pub(crate) fn new() -> BufferPool {
+        BufferPool::with_limits(DEFAULT_MAX_BUFFERS, DEFAULT_MAX_CAPACITY)
+    }
+
+    /// Keep at most `max_buffers`, of at most `max_capacity` bytes each
+    pub(crate) fn with_limits(max_buffers: usize, max_capacity: usize) -> BufferPool {
+        BufferPool {
+            buffers: Arc::new(Mutex::new(Vec::with_capacity(max_buffers))),
+            max_buffers,
+            max_capacity,
+            stats: PoolStats::default(),
+        }
+    }
+
+    /// An empty buffer, reused if one is available
+    pub(crate) fn take(&self) -> BytesMut {
+        match self.buffers.lock().unwrap().pop() {
+            Some(buffer) => {
+                self.stats.0.hits.fetch_add(1, Ordering::Relaxed);
+                buffer
+            }
+            None => {
+                self.stats.0.misses.fetch_add(1, Ordering::Relaxed);
+                BytesMut::new()
+            }
+        }
+    }
+
+    /// Give `buffer` back, to be reused unless it is too big or the pool
+    /// is full
+    pub(crate) fn put(&self, mut buffer: BytesMut) {
+        if buffer.capacity() > self.max_capacity {
+            self.stats.0.oversized.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if buffer.capacity() == 0 {
+            return;
+        }
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffer.clear();
+            buffers.push(buffer);
+        }
+    }
+
+    pub(crate) fn stats(&self) -> PoolStats {
+        self.stats.clone()
+    }
+
+    /// How many bytes the buffers kept take
+    #[cfg(test)]
+    pub(crate) fn pooled_capacity(&self) -> usize {
+        let buffers = self.buffers.lock().unwrap();
+        buffers.iter().map(BytesMut::capacity).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+
+    #[test]
+    fn test_reuse() {
+        let pool = BufferPool::new();
+        let mut buffer = pool.take();
+        buffer.put_slice(b"<presence/>");
+        let capacity = buffer.capacity();
+        pool.put(buffer);
+        let buffer = pool.take();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.capacity(), capacity);
+        let stats = pool.stats();
+        assert_eq!((stats.hits(), stats.misses(), stats.oversized()), (1, 1, 0));
+
+        // Unused buffers aren't worth keeping.
+        pool.put(BytesMut::new());
+        assert_eq!(pool.pooled_capacity(), 0);
+    }
+
+    #[test]
+    fn test_soak() {
+        // Occasional huge stanzas don't make the pool grow.
+        let pool = BufferPool::with_limits(4, 1024);
+        let mut in_flight = Vec::new();
+        for i in 0..10_000 {
+            let size = if i % 100 == 99 { 1 << 20 } else { 50 + i % 150 };
+            let mut buffer = pool.take();
+            buffer.put_bytes(b'x', size);
+            in_flight.push(buffer);
+            // Up to three buffers waiting to be written at once.
+            if in_flight.len() > i % 3 {
+                for buffer in in_flight.drain(..) {
+                    pool.put(buffer);
+                }
+            }
+            assert!(pool.pooled_capacity() <= 4 * 1024);
+        }
+        for buffer in in_flight {
+            pool.put(buffer);
+        }
+        let stats = pool.stats();
+        assert_eq!(stats.oversized(), 100);
+        assert_eq!(stats.hits() + stats.misses(), 10_000);
+        assert!(stats.misses() < 10_000 / 10);
+    }
+}
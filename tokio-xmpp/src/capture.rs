@@ -0,0 +1,705 @@
+//! Recording of the raw bytes of connections, and their replay, to
+//! reproduce bugs without access to the account they happened with
+//!
+//! With [`crate::AsyncConfig::capture`], every connection gets recorded
+//! once encrypted, before compression, with what the server sent and
+//! optionally what we sent. The contents of the authentication
+//! exchanges are replaced by `REDACTED` unless told otherwise, so that
+//! captures can be shared.
+//!
+//! A capture is a file starting with [`MAGIC`], followed by records of:
+//! - one byte telling what it is, see [`Kind`],
+//! - the time elapsed since the connection started, in milliseconds, as
+//!   a big-endian `u64`,
+//! - the length of the data, as a big-endian `u32`,
+//! - the data.
+//!
+//! Each connection starts with a [`Kind::Connection`] record, without
+//! data. [`read_capture`] gives them back, and [`packets`] runs the codec
+//! over one of them, the way the client would have parsed it.
+//!
+//! Running `cargo test replay` with the path of a capture in
+//! `XMPP_CAPTURE` replays it through the codec, then through the Agent of
+//! the xmpp crate, failing on the first packet which doesn't parse.
+//! Without it, the capture in `tests/captures` gets replayed.
+
+use futures::{stream, Stream, StreamExt};
+use log::warn;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_util::codec::{Framed, FramedParts};
+use xmpp_parsers::ns;
+
+use crate::xmpp_codec::{Packet, XMPPCodec};
+use crate::ParserError;
+
+/// What every capture starts with
+pub const MAGIC: &[u8; 8] = b"XMPPCAP\x01";
+
+/// Where to record connections, see [`crate::AsyncConfig::capture`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureConfig {
+    /// The file the connections get appended to
+    pub path: PathBuf,
+    /// Also record what we send
+    pub record_sent: bool,
+    /// Replace the contents of SASL, component handshake and password
+    /// elements by `REDACTED`
+    pub redact_auth: bool,
+}
+
+impl CaptureConfig {
+    /// Record what the server sends to `path`, redacted
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        CaptureConfig {
+            path: path.into(),
+            record_sent: false,
+            redact_auth: true,
+        }
+    }
+}
+
+/// What a record holds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// The start of a connection
+    Connection,
+    /// Bytes the server sent
+    Received,
+    /// Bytes we sent
+    Sent,
+}
+
+impl Kind {
+    fn byte(self) -> u8 {
+        match self {
+            Kind::Connection => b'C',
+            Kind::Received => b'R',
+            Kind::Sent => b'S',
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Kind> {
+        match byte {
+            b'C' => Some(Kind::Connection),
+            b'R' => Some(Kind::Received),
+            b'S' => Some(Kind::Sent),
+            _ => None,
+        }
+    }
+}
+
+/// One record of a capture
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    /// What it holds
+    pub kind: Kind,
+    /// When it happened, since the start of the connection
+    pub elapsed: Duration,
+    /// The bytes received or sent
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let length = u32::try_from(self.data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record too big"))?;
+        let mut bytes = Vec::with_capacity(13 + self.data.len());
+        bytes.push(self.kind.byte());
+        bytes.extend_from_slice(&(self.elapsed.as_millis() as u64).to_be_bytes());
+        bytes.extend_from_slice(&length.to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        writer.write_all(&bytes)
+    }
+}
+
+/// Reads a capture, returning the records of each of its connections
+pub fn read_capture<R: Read>(mut reader: R) -> io::Result<Vec<Vec<Record>>> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a capture"));
+    }
+    let mut connections: Vec<Vec<Record>> = Vec::new();
+    loop {
+        let mut header = [0; 13];
+        match reader.read_exact(&mut header[..1]) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        reader.read_exact(&mut header[1..])?;
+        let kind = Kind::from_byte(header[0]).ok_or_else(|| invalid("unknown record"))?;
+        let mut elapsed = [0; 8];
+        elapsed.copy_from_slice(&header[1..9]);
+        let mut length = [0; 4];
+        length.copy_from_slice(&header[9..]);
+        let mut data = vec![0; u32::from_be_bytes(length) as usize];
+        reader.read_exact(&mut data)?;
+        let record = Record {
+            kind,
+            elapsed: Duration::from_millis(u64::from_be_bytes(elapsed)),
+            data,
+        };
+        match (kind, connections.last_mut()) {
+            (Kind::Connection, _) => connections.push(vec![record]),
+            (_, Some(connection)) => connection.push(record),
+            (_, None) => return Err(invalid("record outside of a connection")),
+        }
+    }
+    Ok(connections)
+}
+
+/// Where the redaction of one direction is at
+enum RedactState {
+    /// Between tags
+    Text,
+    /// In the name of a tag, this far
+    TagName(Vec<u8>),
+    /// In a tag, of a secret element or not
+    Tag {
+        secret: bool,
+        quote: Option<u8>,
+        last: u8,
+    },
+    /// In the contents of a secret element, whether they got replaced
+    /// already
+    Secret(bool),
+}
+
+/// Replaces the contents of secret elements, byte after byte so that
+/// where the transport splits them doesn't matter
+struct Redactor {
+    state: RedactState,
+}
+
+impl Redactor {
+    fn new() -> Redactor {
+        Redactor {
+            state: RedactState::Text,
+        }
+    }
+
+    /// Whether the element with this name, prefix included, holds
+    /// something to redact: the SASL exchange, component handshake, or
+    /// a password for in-band registration or legacy authentication
+    fn is_secret(name: &[u8]) -> bool {
+        let local = match name.iter().rposition(|&b| b == b':') {
+            Some(colon) => &name[colon + 1..],
+            None => name,
+        };
+        matches!(
+            local,
+            b"auth" | b"response" | b"challenge" | b"success" | b"handshake" | b"password"
+        )
+    }
+
+    fn redact(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(data.len());
+        for &byte in data {
+            if let RedactState::TagName(ref mut name) = self.state {
+                if !(byte.is_ascii_whitespace() || byte == b'>' || byte == b'/') {
+                    // No secret element has a longer name.
+                    if name.len() < 16 {
+                        name.push(byte);
+                    }
+                    output.push(byte);
+                    continue;
+                }
+                // Closing tags, comments and processing instructions
+                // start with something else than a name.
+                let secret = !name.is_empty() && Self::is_secret(name);
+                self.state = RedactState::Tag {
+                    secret,
+                    quote: None,
+                    last: b'<',
+                };
+            }
+            match self.state {
+                RedactState::Text => {
+                    if byte == b'<' {
+                        self.state = RedactState::TagName(Vec::new());
+                    }
+                    output.push(byte);
+                }
+                RedactState::TagName(_) => unreachable!(),
+                RedactState::Tag {
+                    secret,
+                    ref mut quote,
+                    ref mut last,
+                } => {
+                    output.push(byte);
+                    match *quote {
+                        Some(q) if byte == q => *quote = None,
+                        Some(_) => (),
+                        None if byte == b'\'' || byte == b'"' => *quote = Some(byte),
+                        None if byte == b'>' => {
+                            self.state = if secret && *last != b'/' {
+                                RedactState::Secret(false)
+                            } else {
+                                RedactState::Text
+                            };
+                            continue;
+                        }
+                        None => (),
+                    }
+                    *last = byte;
+                }
+                RedactState::Secret(ref mut replaced) => {
+                    if byte == b'<' {
+                        self.state = RedactState::TagName(Vec::new());
+                        output.push(byte);
+                    } else if !*replaced {
+                        *replaced = true;
+                        output.extend_from_slice(b"REDACTED");
+                    }
+                }
+            }
+        }
+        output
+    }
+}
+
+/// Wraps a transport, recording what goes through it
+pub struct Recorder<S> {
+    inner: S,
+    output: Option<Box<dyn Write + Send>>,
+    start: Instant,
+    record_sent: bool,
+    redactors: Option<(Redactor, Redactor)>,
+}
+
+impl<S> Recorder<S> {
+    /// Record the connection over `inner` to `output`, which must
+    /// already have [`MAGIC`] written at its start
+    pub fn new<W: Write + Send + 'static>(
+        inner: S,
+        output: W,
+        record_sent: bool,
+        redact_auth: bool,
+    ) -> io::Result<Self> {
+        let mut recorder = Recorder {
+            inner,
+            output: Some(Box::new(output)),
+            start: Instant::now(),
+            record_sent,
+            redactors: if redact_auth {
+                Some((Redactor::new(), Redactor::new()))
+            } else {
+                None
+            },
+        };
+        recorder.record(Kind::Connection, &[])?;
+        Ok(recorder)
+    }
+
+    /// Record the connection over `inner` as `config` says, appending
+    /// it to the file
+    pub fn create(inner: S, config: &CaptureConfig) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(MAGIC)?;
+        }
+        Recorder::new(inner, file, config.record_sent, config.redact_auth)
+    }
+
+    /// The wrapped transport
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn record(&mut self, kind: Kind, data: &[u8]) -> io::Result<()> {
+        let data = match (&mut self.redactors, kind) {
+            (Some((received, _)), Kind::Received) => received.redact(data),
+            (Some((_, sent)), Kind::Sent) => sent.redact(data),
+            _ => data.to_vec(),
+        };
+        let record = Record {
+            kind,
+            elapsed: self.start.elapsed(),
+            data,
+        };
+        match self.output {
+            Some(ref mut output) => record.write_to(output),
+            None => Ok(()),
+        }
+    }
+
+    /// Record, giving up on recording rather than on the connection if
+    /// that fails
+    fn record_or_stop(&mut self, kind: Kind, data: &[u8]) {
+        if let Err(e) = self.record(kind, data) {
+            warn!("Stopped recording the connection: {}", e);
+            self.output = None;
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorder<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            if buf.filled().len() > before {
+                let data = buf.filled()[before..].to_vec();
+                self.record_or_stop(Kind::Received, &data);
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorder<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            if self.record_sent && written > 0 {
+                self.record_or_stop(Kind::Sent, &buf[..written]);
+            }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// A transport playing back what the server sent during a recorded
+/// connection, as fast as it gets read
+///
+/// What gets written is kept, see [`Replay::sent`]. Once everything got
+/// read, the transport reads as closed.
+pub struct Replay {
+    received: VecDeque<Vec<u8>>,
+    offset: usize,
+    sent: Vec<u8>,
+}
+
+impl Replay {
+    /// Play back the [`Kind::Received`] records among `connection`
+    pub fn new(connection: Vec<Record>) -> Self {
+        Replay {
+            received: connection
+                .into_iter()
+                .filter(|record| record.kind == Kind::Received)
+                .map(|record| record.data)
+                .collect(),
+            offset: 0,
+            sent: Vec::new(),
+        }
+    }
+
+    /// Everything written so far
+    pub fn sent(&self) -> &[u8] {
+        &self.sent
+    }
+}
+
+impl AsyncRead for Replay {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        // One record at most per read, as it arrived.
+        let offset = self.offset;
+        let (read, done) = match self.received.front() {
+            Some(data) => {
+                let read = (data.len() - offset).min(buf.remaining());
+                buf.put_slice(&data[offset..offset + read]);
+                (read, offset + read == data.len())
+            }
+            None => return Poll::Ready(Ok(())),
+        };
+        if done {
+            self.received.pop_front();
+            self.offset = 0;
+        } else {
+            self.offset += read;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Replay {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.sent.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The packets the server sent during a recorded connection, parsed
+/// the way the client did, ending with the first error
+pub fn packets(
+    connection: Vec<Record>,
+) -> Pin<Box<dyn Stream<Item = Result<Packet, ParserError>>>> {
+    let framed = Framed::new(Replay::new(connection), XMPPCodec::new());
+    Box::pin(stream::unfold(Some(framed), |framed| async move {
+        let mut framed = framed?;
+        let packet = framed.next().await?;
+        match packet {
+            // The stream restarts after authentication, with a new
+            // parser.
+            Ok(Packet::Stanza(ref stanza)) if stanza.is("success", ns::SASL) => {
+                let old = framed.into_parts();
+                let mut parts = FramedParts::new(old.io, XMPPCodec::new());
+                parts.read_buf = old.read_buf;
+                Some((packet, Some(Framed::from_parts(parts))))
+            }
+            Ok(_) => Some((packet, Some(framed))),
+            Err(_) => Some((packet, None)),
+        }
+    }))
+}
+
+/// Reads the capture in the file at `path`
+pub fn read_capture_file<P: Into<PathBuf>>(path: P) -> io::Result<Vec<Vec<Record>>> {
+    read_capture(io::BufReader::new(std::fs::File::open(path.into())?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use xmpp_parsers::{Element, FullJid, Jid};
+
+    use crate::client::async_client::login;
+    use crate::client::{BindConflictPolicy, Secret};
+    use crate::test_harness::{Handshake, Matcher, ScriptedServer};
+
+    /// The base64 of the PLAIN authentication of foo with password meh
+    const PLAIN: &[u8] = b"AGZvbwBtZWg=";
+
+    /// A capture kept in memory
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn test_format() {
+        let records = vec![
+            Record {
+                kind: Kind::Connection,
+                elapsed: Duration::from_millis(0),
+                data: Vec::new(),
+            },
+            Record {
+                kind: Kind::Received,
+                elapsed: Duration::from_millis(12),
+                data: b"<stream:stream>".to_vec(),
+            },
+            Record {
+                kind: Kind::Sent,
+                elapsed: Duration::from_millis(3000),
+                data: b" ".to_vec(),
+            },
+        ];
+        let mut capture = MAGIC.to_vec();
+        for record in records.iter().chain(&records[..2]) {
+            record.write_to(&mut capture).unwrap();
+        }
+        let connections = read_capture(&capture[..]).unwrap();
+        assert_eq!(connections, [records.clone(), records[..2].to_vec()]);
+
+        assert!(read_capture(&b"XMPPCAP\x02"[..]).is_err());
+        // Truncated in the middle of a record.
+        assert!(read_capture(&capture[..capture.len() - 1]).is_err());
+        let mut orphan = MAGIC.to_vec();
+        records[1].write_to(&mut orphan).unwrap();
+        assert!(read_capture(&orphan[..]).is_err());
+    }
+
+    #[test]
+    fn test_redaction() {
+        assert!(CaptureConfig::new("capture").redact_auth);
+
+        let exchange: &[u8] = b"<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='PLAIN'>AGZvbwBtZWg=</auth><sasl:challenge xmlns:sasl='urn:ietf:params:xml:ns:xmpp-sasl' a='>'>cj1meWtv</sasl:challenge><success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/><mechanisms><mechanism>PLAIN</mechanism></mechanisms><iq type='set'><query xmlns='jabber:iq:register'><username>foo</username><password>meh</password></query></iq>";
+        let expected: &[u8] = b"<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='PLAIN'>REDACTED</auth><sasl:challenge xmlns:sasl='urn:ietf:params:xml:ns:xmpp-sasl' a='>'>REDACTED</sasl:challenge><success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/><mechanisms><mechanism>PLAIN</mechanism></mechanisms><iq type='set'><query xmlns='jabber:iq:register'><username>foo</username><password>REDACTED</password></query></iq>";
+        assert_eq!(Redactor::new().redact(exchange), expected);
+
+        // However the transport splits it.
+        for split in 1..exchange.len() {
+            let mut redactor = Redactor::new();
+            let mut output = redactor.redact(&exchange[..split]);
+            output.extend(redactor.redact(&exchange[split..]));
+            assert_eq!(output, expected, "split at {}", split);
+        }
+    }
+
+    async fn record_login(record_sent: bool, redact_auth: bool) -> Vec<u8> {
+        let jid = FullJid::from_str("foo@bar/baz").unwrap();
+        let message: Element =
+            "<message xmlns='jabber:client' from='juliet@capulet.lit/balcony' to='foo@bar/baz' type='chat'><body>Hi!</body></message>"
+                .parse()
+                .unwrap();
+        let (stream, server) = ScriptedServer::new(Handshake::Login(jid.clone()))
+            .expect(Matcher::new("presence", ns::JABBER_CLIENT))
+            .send(message)
+            .start();
+        let capture = Buffer::default();
+        let stream = Recorder::new(stream, capture.clone(), record_sent, redact_auth).unwrap();
+        let client = async move {
+            let (mut stream, _) = login(
+                stream,
+                Jid::from_str("foo@bar").unwrap(),
+                Secret::Password(String::from("meh")),
+                None,
+                &BindConflictPolicy::default(),
+                &[],
+            )
+            .await
+            .unwrap();
+            stream
+                .send_stanza(Element::bare("presence", ns::JABBER_CLIENT))
+                .await
+                .unwrap();
+            while let Some(Ok(_)) = stream.next().await {}
+        };
+        tokio::join!(client, server);
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&capture.0.lock().unwrap());
+        bytes
+    }
+
+    #[tokio::test]
+    async fn test_record_redacted() {
+        let capture = record_login(true, true).await;
+        let mut connections = read_capture(&capture[..]).unwrap();
+        assert_eq!(connections.len(), 1);
+        let connection = connections.pop().unwrap();
+        let sent: Vec<u8> = connection
+            .iter()
+            .filter(|record| record.kind == Kind::Sent)
+            .flat_map(|record| record.data.clone())
+            .collect();
+        assert!(!contains(&sent, PLAIN));
+        assert!(contains(&sent, b">REDACTED</auth>"));
+        assert!(contains(&sent, b"<presence"));
+
+        // Without redaction, for private use.
+        let capture = record_login(true, false).await;
+        assert!(contains(&capture, PLAIN));
+        // Only what the server sent, by default.
+        let capture = record_login(false, true).await;
+        let connection = read_capture(&capture[..]).unwrap().pop().unwrap();
+        assert!(connection.iter().all(|record| record.kind != Kind::Sent));
+    }
+
+    #[tokio::test]
+    async fn test_replay_login() {
+        let capture = record_login(false, true).await;
+        let connection = read_capture(&capture[..]).unwrap().pop().unwrap();
+
+        // The client logs in again, and gets the same stanzas.
+        let (mut stream, sasl_mechanism) = login(
+            Replay::new(connection.clone()),
+            Jid::from_str("foo@bar").unwrap(),
+            Secret::Password(String::from("meh")),
+            None,
+            &BindConflictPolicy::default(),
+            &[],
+        )
+        .await
+        .unwrap();
+        assert_eq!(sasl_mechanism, "PLAIN");
+        assert_eq!(stream.jid, Jid::from_str("foo@bar/baz").unwrap());
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(message))) => assert!(message.is("message", ns::JABBER_CLIENT)),
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+
+        let packets: Vec<_> = packets(connection).collect().await;
+        assert!(packets.iter().all(|packet| packet.is_ok()));
+        assert!(packets.iter().any(|packet| match packet {
+            Ok(Packet::Stanza(stanza)) => stanza.is("message", ns::JABBER_CLIENT),
+            _ => false,
+        }));
+    }
+
+    /// Replays every connection of `capture`, failing on the first
+    /// packet which doesn't parse
+    async fn replay_capture(capture: Vec<Vec<Record>>) -> usize {
+        let mut stanzas = 0;
+        for (i, connection) in capture.into_iter().enumerate() {
+            let mut packets = packets(connection);
+            while let Some(packet) = packets.next().await {
+                match packet {
+                    Ok(Packet::Stanza(_)) => stanzas += 1,
+                    Ok(_) => (),
+                    Err(e) => panic!("connection {}, after {} stanzas: {}", i, stanzas, e),
+                }
+            }
+        }
+        stanzas
+    }
+
+    #[tokio::test]
+    async fn test_replay_fixture() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/captures/login.xmppcap");
+        let capture = read_capture_file(path).unwrap();
+        assert_eq!(capture.len(), 1);
+        assert!(replay_capture(capture).await > 0);
+    }
+
+    #[tokio::test]
+    async fn test_replay_env() {
+        if let Some(path) = std::env::var_os("XMPP_CAPTURE") {
+            let capture = read_capture_file(path).unwrap();
+            let stanzas = replay_capture(capture).await;
+            println!("Replayed {} stanzas.", stanzas);
+        }
+    }
+}
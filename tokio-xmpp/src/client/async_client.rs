@@ -1,30 +1,48 @@
-use futures::{sink::SinkExt, task::Poll, Future, Sink, Stream};
-use sasl::common::{ChannelBinding, Credentials};
+use futures::{future::poll_fn, ready, sink::SinkExt, task::Poll, Future, Sink, Stream};
+use std::collections::VecDeque;
 use std::mem::replace;
+use std::net::Ipv4Addr;
 use std::pin::Pin;
 use std::str::FromStr;
-use std::task::Context;
+use std::task::{Context, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio::task::JoinHandle;
 use tokio::task::LocalSet;
-#[cfg(feature = "tls-native")]
-use tokio_native_tls::TlsStream;
-#[cfg(feature = "tls-rust")]
-use tokio_rustls::client::TlsStream;
-use xmpp_parsers::{ns, Element, Jid, JidParseError};
+use tokio::time::Sleep;
+use xmpp_parsers::{ns, BareJid, Element, Jid, JidParseError};
 
 use super::auth::auth;
-use super::bind::bind;
+use super::bind::{bind, BindConflictPolicy};
+use super::credentials::{CredentialsProvider, Secret};
+use super::inbound::{Admit, InboundPolicy, Throttle};
+use super::ping::{PingPolicy, Pinger};
+use super::reconnect::{Backoff, ReconnectPolicy, Retry};
+use super::sm::{self, Outcome, Plan, Received, StreamManagement};
+use super::stream_feature::{
+    negotiate, stream_feature_handler, BoxedStream, Negotiated, StreamFeatureHook,
+};
+use crate::buffer_pool::PoolStats;
+use crate::capture::{CaptureConfig, Recorder};
+#[cfg(feature = "compression")]
+use crate::compression;
 use crate::event::Event;
-use crate::happy_eyeballs::{connect_to_host, connect_with_srv};
+use crate::happy_eyeballs::connect_with_srv;
+#[cfg(feature = "test-internals")]
+use crate::internals::{QueueDepths, Timer, TimerKind};
+use crate::proxy::{self, ProxyConfig};
 use crate::starttls::starttls;
-use crate::xmpp_codec::Packet;
+use crate::stream_error::redirect;
+use crate::xmpp_codec::{Packet, StreamHeader};
 use crate::xmpp_stream;
-use crate::{Error, ProtocolError};
+use crate::{Error, ProtocolError, SharedError};
 
 /// XMPP client connection and state
 ///
-/// It is able to reconnect. TODO: implement session management.
+/// It is able to reconnect, following its `ReconnectPolicy`, and to
+/// resume its session if stream management is enabled, see
+/// [`Config::stream_management`].
 ///
 /// This implements the `futures` crate's [`Stream`](#impl-Stream) and
 /// [`Sink`](#impl-Sink<Packet>) traits.
@@ -32,33 +50,190 @@ pub struct Client {
     config: Config,
     state: ClientState,
     reconnect: bool,
+    backoff: Backoff,
+    /// Why sending failed, to be returned as the next event
+    send_error: Option<SharedError>,
+    /// Why the last connection attempt failed, for
+    /// `Event::ReconnectionSuspended`
+    last_failure: Option<SharedError>,
+    /// Events received while waiting for a state transition, to be
+    /// returned first
+    pending: VecDeque<Event>,
+    /// To wake the task reading events when sending fails
+    waker: Option<Waker>,
+    throttle: Throttle,
+    /// Until when reading is paused by the inbound rate limit
+    inbound_delay: Option<Pin<Box<Sleep>>>,
+    /// How often to send a whitespace keepalive when idle
+    keepalive: Option<Duration>,
+    /// When to ping the server, and which pings it didn't answer yet
+    pinger: Pinger,
+    /// Wakes the client up when `pinger` has something to do
+    ping_timer: Option<Pin<Box<Sleep>>>,
+    /// How long to wait for the server to close its side of the
+    /// stream after we closed ours
+    close_timeout: Option<Duration>,
+    /// How long a stanza may take to be received
+    stanza_timeout: Option<Duration>,
+    /// The biggest write to the connection
+    write_chunk_size: Option<usize>,
+    /// The SASL mechanism of the last successful login
+    sasl_mechanism: Option<String>,
+    /// Where the server told us to connect instead, for the next
+    /// attempt only
+    redirect: Option<ServerConfig>,
+    /// Redirects followed since we were last online
+    redirects: u32,
+    max_redirects: u32,
+    sm: StreamManagement,
+    /// Our answer to the last `<r/>` of the server, until the
+    /// connection is ready to send it
+    sm_answer: Option<Element>,
+    /// When the pending connection attempt starts, once backed off
+    #[cfg(feature = "test-internals")]
+    reconnect_at: Option<tokio::time::Instant>,
     // TODO: tls_required=true
 }
 
 /// XMPP server connection configuration
 #[derive(Clone)]
 pub enum ServerConfig {
+    /// Look up the server using the `_xmpp-client._tcp` SRV record of
+    /// the JID's domain
     UseSrv,
+    /// Connect to this host and port directly
     #[allow(unused)]
     Manual {
+        /// Host name or IP address
         host: String,
+        /// TCP port
+        port: u16,
+    },
+    /// Connect to this port of the loopback interface, without TLS if
+    /// the server doesn't offer it since nothing leaves the machine,
+    /// such as for the mini server of the `mini-server` feature
+    Loopback {
+        /// TCP port
         port: u16,
     },
 }
 
 /// XMMPP client configuration
+#[derive(Clone)]
 pub struct Config {
-    jid: Jid,
-    password: String,
-    server: ServerConfig,
+    /// Account to log in with, including the resource to request
+    pub jid: Jid,
+    /// Password of the account
+    pub password: String,
+    /// Called before every connection attempt to get the secret to
+    /// authenticate with, instead of `password`
+    pub credentials: Option<CredentialsProvider>,
+    /// How to reach the server
+    pub server: ServerConfig,
+    /// What to do if the requested resource is already in use
+    pub bind_conflict_policy: BindConflictPolicy,
+    /// Negotiate these stream features after authentication, see
+    /// [`Config::on_stream_feature`]
+    pub stream_feature_hooks: Vec<StreamFeatureHook>,
+    /// The `xml:lang` of our stream headers, see [`Config::stream_lang`]
+    pub stream_lang: Option<String>,
+    /// Record every connection, see [`Config::capture`]
+    pub capture: Option<CaptureConfig>,
+    /// Reach the server through a proxy, see [`Config::proxy`]
+    pub proxy: Option<ProxyConfig>,
+    /// Enable stream management, see [`Config::stream_management`]
+    pub stream_management: bool,
+}
+
+impl Config {
+    /// Log in to the account `jid` with `password`, reaching its
+    /// server as `server` says
+    ///
+    /// Everything else is left to its default, to be changed with the
+    /// other methods or by setting the fields.
+    pub fn new<P: Into<String>>(jid: Jid, password: P, server: ServerConfig) -> Config {
+        Config {
+            jid,
+            password: password.into(),
+            credentials: None,
+            server,
+            bind_conflict_policy: BindConflictPolicy::default(),
+            stream_feature_hooks: Vec::new(),
+            stream_lang: None,
+            capture: None,
+            proxy: None,
+            stream_management: false,
+        }
+    }
+
+    /// Negotiate the stream feature `<name xmlns=ns/>` with `handler`
+    /// whenever the server offers it after authentication
+    ///
+    /// The handler gets the stream, may exchange nonzas on it, and
+    /// returns it as is or replaced, for instance wrapped to compress
+    /// it. The built-in logic then skips that feature, except for
+    /// resource binding which always happens last.
+    pub fn on_stream_feature<F, Fut>(mut self, ns: &str, name: &str, handler: F) -> Self
+    where
+        F: Fn(xmpp_stream::XMPPStream<BoxedStream>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Negotiated, Error>> + 'static,
+    {
+        self.stream_feature_hooks.push(StreamFeatureHook {
+            ns: ns.to_owned(),
+            name: name.to_owned(),
+            handler: stream_feature_handler(handler),
+        });
+        self
+    }
+
+    /// Set the `xml:lang` of the streams we open, the language in
+    /// which the server should write the text it generates, such as
+    /// that of stream and stanza errors
+    pub fn stream_lang(mut self, lang: &str) -> Self {
+        self.stream_lang = Some(lang.to_owned());
+        self
+    }
+
+    /// Record every connection as `capture` says, to replay it later
+    /// when reporting a bug, see the [`crate::capture`] module
+    pub fn capture(mut self, capture: CaptureConfig) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Reach the server through `proxy`, such as an HTTP proxy only
+    /// letting `CONNECT` through, STARTTLS then happening over the
+    /// tunnel as usual
+    ///
+    /// [`ServerConfig::Loopback`] connections never go through it.
+    pub fn proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Enable stream management (XEP-0198) whenever the server supports
+    /// it, to resume the session after a disconnection instead of
+    /// starting a new one
+    ///
+    /// The session is only resumed as long as the server keeps it, see
+    /// [`Client::resumption_deadline_after_disconnect`]. Stanzas sent
+    /// but not yet acknowledged when the connection ended aren't sent
+    /// again.
+    pub fn stream_management(mut self) -> Self {
+        self.stream_management = true;
+        self
+    }
 }
 
-type XMPPStream = xmpp_stream::XMPPStream<TlsStream<TcpStream>>;
+type XMPPStream = xmpp_stream::XMPPStream<BoxedStream>;
 
 enum ClientState {
     Invalid,
     Disconnected,
-    Connecting(JoinHandle<Result<XMPPStream, Error>>, LocalSet),
+    Connecting(
+        JoinHandle<Result<(XMPPStream, String, Outcome), Error>>,
+        LocalSet,
+    ),
     Connected(XMPPStream),
 }
 
@@ -69,27 +244,56 @@ impl Client {
     /// and yield events.
     pub fn new<P: Into<String>>(jid: &str, password: P) -> Result<Self, JidParseError> {
         let jid = Jid::from_str(jid)?;
-        let config = Config {
-            jid: jid.clone(),
-            password: password.into(),
-            server: ServerConfig::UseSrv,
-        };
+        let config = Config::new(jid.clone(), password, ServerConfig::UseSrv);
         let client = Self::new_with_config(config);
         Ok(client)
     }
 
+    /// Start a new XMPP client which asks `credentials` for its
+    /// secret before every connection, for instance to refresh an
+    /// OAuth token
+    pub fn new_with_credentials(
+        jid: &str,
+        credentials: CredentialsProvider,
+    ) -> Result<Self, JidParseError> {
+        let jid = Jid::from_str(jid)?;
+        let config = Config {
+            credentials: Some(credentials),
+            ..Config::new(jid, "", ServerConfig::UseSrv)
+        };
+        Ok(Self::new_with_config(config))
+    }
+
     /// Start a new client given that the JID is already parsed.
     pub fn new_with_config(config: Config) -> Self {
-        let local = LocalSet::new();
-        let connect = local.spawn_local(Self::connect(
-            config.server.clone(),
-            config.jid.clone(),
-            config.password.clone(),
-        ));
+        let mut sm = StreamManagement::default();
+        let (plan, _) = sm.plan(config.stream_management, Duration::from_secs(0));
+        let state = Self::start_connect(&config, &config.server, Duration::from_secs(0), plan);
         let client = Client {
             config,
-            state: ClientState::Connecting(connect, local),
+            state,
             reconnect: false,
+            backoff: Backoff::default(),
+            send_error: None,
+            last_failure: None,
+            pending: VecDeque::new(),
+            waker: None,
+            throttle: Throttle::default(),
+            inbound_delay: None,
+            keepalive: None,
+            pinger: Pinger::default(),
+            ping_timer: None,
+            close_timeout: None,
+            stanza_timeout: Some(xmpp_stream::DEFAULT_STANZA_TIMEOUT),
+            write_chunk_size: None,
+            sasl_mechanism: None,
+            redirect: None,
+            redirects: 0,
+            max_redirects: 5,
+            sm,
+            sm_answer: None,
+            #[cfg(feature = "test-internals")]
+            reconnect_at: None,
         };
         client
     }
@@ -101,50 +305,250 @@ impl Client {
         self
     }
 
+    /// Set how to space out reconnection attempts, and when to suspend
+    /// them for a while
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) -> &mut Self {
+        self.backoff.policy = policy;
+        self
+    }
+
+    /// Replace the source of randomness used to jitter reconnection
+    /// delays, which must return values between 0 and 1
+    pub fn set_reconnect_random<R: FnMut() -> f64 + Send + 'static>(
+        &mut self,
+        random: R,
+    ) -> &mut Self {
+        self.backoff.set_random(random);
+        self
+    }
+
+    /// Set how fast to read incoming stanzas, so that a flood of them
+    /// doesn't starve the rest of the application
+    pub fn set_inbound_policy(&mut self, policy: InboundPolicy) -> &mut Self {
+        self.throttle = Throttle::new(policy);
+        self.inbound_delay = None;
+        self
+    }
+
+    /// Send a whitespace keepalive whenever nothing got sent for
+    /// `interval`, or never if `None`
+    ///
+    /// Some NATs drop connections idle for less than the server's own
+    /// pings allow. Unlike a ping, a keepalive isn't a stanza, and the
+    /// server doesn't answer it.
+    pub fn set_keepalive_interval(&mut self, interval: Option<Duration>) -> &mut Self {
+        self.keepalive = interval;
+        if let ClientState::Connected(ref mut stream) = self.state {
+            stream.set_keepalive(interval);
+        }
+        self
+    }
+
+    /// Ping the server whenever nothing got received for a while, and
+    /// end the connection with `Error::KeepaliveTimeout` if it doesn't
+    /// answer in time, or never ping it if `None`
+    ///
+    /// Unlike a whitespace keepalive, this notices a connection which
+    /// got silently dropped along the way, such as by a NAT.
+    pub fn set_ping_policy(&mut self, policy: Option<PingPolicy>) -> &mut Self {
+        self.pinger.set_policy(policy);
+        self.ping_timer = None;
+        if let Some(ref waker) = self.waker {
+            waker.wake_by_ref();
+        }
+        self
+    }
+
+    /// Set how long to wait, once we sent `</stream:stream>`, for the
+    /// server to close its side, 10 seconds by default
+    ///
+    /// Stanzas it still sends meanwhile are received as usual, before
+    /// the `Event::Disconnected`.
+    pub fn set_close_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.close_timeout = Some(timeout);
+        if let ClientState::Connected(ref mut stream) = self.state {
+            stream.set_close_timeout(timeout);
+        }
+        self
+    }
+
+    /// Drop the connection with `ProtocolError::StanzaTimeout` if a
+    /// stanza isn't complete `timeout` after it started being received,
+    /// 5 minutes by default, or never if `None`
+    ///
+    /// A server trickling a stanza slower and slower would otherwise
+    /// keep it from ever being handled, as well as all the ones after it.
+    pub fn set_stanza_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.stanza_timeout = timeout;
+        if let ClientState::Connected(ref mut stream) = self.state {
+            stream.set_stanza_timeout(timeout);
+        }
+        self
+    }
+
+    /// Write at most `size` bytes at once to the connection, 16 KiB by
+    /// default
+    ///
+    /// Between these writes, small urgent stanzas such as iq replies,
+    /// pings and stream management acks get ahead of a big batch being
+    /// sent, instead of waiting for all of it to go through.
+    pub fn set_write_chunk_size(&mut self, size: usize) -> &mut Self {
+        self.write_chunk_size = Some(size);
+        if let ClientState::Connected(ref mut stream) = self.state {
+            stream.set_write_chunk_size(size);
+        }
+        self
+    }
+
+    /// Set how many `<see-other-host/>` redirects in a row are
+    /// followed when reconnecting, 5 by default
+    ///
+    /// Beyond that, or when not reconnecting, the redirect is only
+    /// reported as `Error::Redirect` in `Event::Disconnected`.
+    pub fn set_max_redirects(&mut self, max_redirects: u32) -> &mut Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// Reconnect right away, skipping the remaining delay or suspension
+    ///
+    /// The count of failed attempts starts over. Does nothing while
+    /// connected.
+    pub fn retry_now(&mut self) {
+        if let ClientState::Connected(_) = self.state {
+            return;
+        }
+        self.backoff.reset();
+        if let ClientState::Connecting(ref connect, _) = self.state {
+            connect.abort();
+        }
+        if let Some(event) = self.reconnect_after(Duration::from_secs(0)) {
+            self.pending.push_back(event);
+        }
+    }
+
+    /// Connect to the configured server once `delay` elapsed, or to
+    /// where the server asked us to resume our session
+    ///
+    /// Returns `Event::ResumptionExpired` if the session will be over
+    /// by then.
+    fn reconnect_after(&mut self, delay: Duration) -> Option<Event> {
+        #[cfg(feature = "test-internals")]
+        {
+            self.reconnect_at = Some(tokio::time::Instant::now() + delay);
+        }
+        let (plan, expired) = self.sm.plan(self.config.stream_management, delay);
+        let server = match plan {
+            Plan::Resume(ref session, _) => match session.location() {
+                Some((host, port)) => ServerConfig::Manual { host, port },
+                None => self.config.server.clone(),
+            },
+            _ => self.config.server.clone(),
+        };
+        self.state = Self::start_connect(&self.config, &server, delay, plan);
+        expired
+    }
+
+    fn start_connect(
+        config: &Config,
+        server: &ServerConfig,
+        delay: Duration,
+        plan: Plan,
+    ) -> ClientState {
+        let local = LocalSet::new();
+        let connect = Self::connect(server.clone(), config.clone(), plan);
+        let connect = local.spawn_local(async move {
+            if delay > Duration::from_secs(0) {
+                tokio::time::sleep(delay).await;
+            }
+            connect.await
+        });
+        ClientState::Connecting(connect, local)
+    }
+
     async fn connect(
         server: ServerConfig,
-        jid: Jid,
-        password: String,
-    ) -> Result<XMPPStream, Error> {
-        let username = jid.clone().node().unwrap();
-        let password = password;
+        config: Config,
+        plan: Plan,
+    ) -> Result<(XMPPStream, String, Outcome), Error> {
+        let Config {
+            jid,
+            password,
+            credentials,
+            bind_conflict_policy,
+            stream_feature_hooks,
+            stream_lang,
+            capture,
+            proxy,
+            ..
+        } = config;
+        let secret = match credentials {
+            Some(credentials) => credentials().await?,
+            None => Secret::Password(password),
+        };
 
         // TCP connection
+        let loopback = matches!(server, ServerConfig::Loopback { .. });
         let tcp_stream = match server {
             ServerConfig::UseSrv => {
-                connect_with_srv(&jid.clone().domain(), "_xmpp-client._tcp", 5222).await?
+                connect_with_srv(
+                    &jid.clone().domain(),
+                    "_xmpp-client._tcp",
+                    5222,
+                    proxy.as_ref(),
+                )
+                .await?
+            }
+            ServerConfig::Manual { host, port } => {
+                proxy::connect(proxy.as_ref(), host.as_str(), port).await?
+            }
+            ServerConfig::Loopback { port } => {
+                TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await?
             }
-            ServerConfig::Manual { host, port } => connect_to_host(host.as_str(), port).await?,
         };
 
         // Unencryped XMPPStream
-        let xmpp_stream =
-            xmpp_stream::XMPPStream::start(tcp_stream, jid.clone(), ns::JABBER_CLIENT.to_owned())
-                .await?;
+        let xmpp_stream = xmpp_stream::XMPPStream::start_with_lang(
+            tcp_stream,
+            jid.clone(),
+            ns::JABBER_CLIENT.to_owned(),
+            stream_lang.clone(),
+        )
+        .await?;
 
-        let xmpp_stream = if xmpp_stream.stream_features.can_starttls() {
+        let tls_stream = if xmpp_stream.stream_features.can_starttls() {
             // TlsStream
-            let tls_stream = starttls(xmpp_stream).await?;
-            // Encrypted XMPPStream
-            xmpp_stream::XMPPStream::start(tls_stream, jid.clone(), ns::JABBER_CLIENT.to_owned())
-                .await?
+            starttls(xmpp_stream).await?
+        } else if loopback {
+            // Unencrypted, but nothing leaves the machine.
+            return login_started(
+                xmpp_stream,
+                jid,
+                secret,
+                stream_lang.as_deref(),
+                &bind_conflict_policy,
+                &stream_feature_hooks,
+                plan,
+            )
+            .await;
         } else {
             return Err(Error::Protocol(ProtocolError::NoTls));
         };
+        let tls_stream: BoxedStream = match capture {
+            Some(capture) => Box::new(Recorder::create(tls_stream, &capture)?),
+            None => Box::new(tls_stream),
+        };
 
-        let creds = Credentials::default()
-            .with_username(username)
-            .with_password(password)
-            .with_channel_binding(ChannelBinding::None);
-        // Authenticated (unspecified) stream
-        let stream = auth(xmpp_stream, creds).await?;
-        // Authenticated XMPPStream
-        let xmpp_stream =
-            xmpp_stream::XMPPStream::start(stream, jid, ns::JABBER_CLIENT.to_owned()).await?;
-
-        // XMPPStream bound to user session
-        let xmpp_stream = bind(xmpp_stream).await?;
-        Ok(xmpp_stream)
+        login_with_plan(
+            tls_stream,
+            jid,
+            secret,
+            stream_lang.as_deref(),
+            &bind_conflict_policy,
+            &stream_feature_hooks,
+            plan,
+        )
+        .await
     }
 
     /// Get the client's bound JID (the one reported by the XMPP
@@ -156,20 +560,389 @@ impl Client {
         }
     }
 
+    /// Get the header of the current stream, telling its id and
+    /// language among others
+    pub fn stream_header(&self) -> Option<&StreamHeader> {
+        match self.state {
+            ClientState::Connected(ref stream) => Some(&stream.header),
+            _ => None,
+        }
+    }
+
+    /// Get the counters of the buffers the current connection reuses to
+    /// decode and encode packets
+    pub fn pool_stats(&self) -> Option<PoolStats> {
+        match self.state {
+            ClientState::Connected(ref stream) => Some(stream.pool_stats()),
+            _ => None,
+        }
+    }
+
+    /// Get how long the server keeps our session once disconnected, as
+    /// told when it enabled stream management
+    ///
+    /// Past that, the next connection starts a new session, after
+    /// `Event::ResumptionExpired`. Applications managing power can
+    /// schedule a wakeup to reconnect in time.
+    pub fn resumption_deadline_after_disconnect(&self) -> Option<Duration> {
+        match self.state {
+            ClientState::Connected(_) => self.sm.window(),
+            _ => None,
+        }
+    }
+
+    /// Get where the server prefers us to reconnect to resume our
+    /// session, such as `host:port`, the next attempt going there
+    pub fn resumption_location(&self) -> Option<&str> {
+        self.sm.location()
+    }
+
+    /// Get the name of the SASL mechanism used by the last successful
+    /// login, such as `SCRAM-SHA-256` or `PLAIN`
+    ///
+    /// Security-conscious applications can check that they didn't end
+    /// up on a weaker mechanism than they expected.
+    pub fn sasl_mechanism(&self) -> Option<&str> {
+        self.sasl_mechanism.as_deref()
+    }
+
     /// Send stanza
     pub async fn send_stanza(&mut self, stanza: Element) -> Result<(), Error> {
         self.send(Packet::Stanza(stanza)).await
     }
 
+    /// Send a single space, to keep the connection from looking idle
+    pub async fn send_whitespace_keepalive(&mut self) -> Result<(), Error> {
+        self.send(Packet::Text(String::from(" "))).await
+    }
+
+    /// Send all stanzas in order, flushing only once at the end
+    ///
+    /// If one of them fails, the previous ones are still sent and
+    /// `Error::PartialSend` tells how many.
+    ///
+    /// An iq reply sent afterwards may go out before the end of a long
+    /// batch, but never ahead of the stanzas to the same bare JID, nor of
+    /// those sent with it.
+    pub async fn send_all<I: IntoIterator<Item = Element>>(
+        &mut self,
+        stanzas: I,
+    ) -> Result<(), Error> {
+        xmpp_stream::send_all(self, stanzas.into_iter().map(Packet::Stanza)).await
+    }
+
     /// End connection by sending `</stream:stream>`
     ///
-    /// You may expect the server to respond with the same. This
-    /// client will then drop its connection.
+    /// Nothing can be sent afterwards, but the stanzas the server still
+    /// sends are received until it responds with the same, or until the
+    /// close timeout passes. This client will then drop its connection.
     ///
     /// Make sure to disable reconnect.
     pub async fn send_end(&mut self) -> Result<(), Error> {
         self.send(Packet::StreamEnd).await
     }
+
+    /// Wait until the client is online, returning the bound JID
+    ///
+    /// Resolves right away if it already is. The other events received
+    /// meanwhile are returned by the stream afterwards, but the
+    /// `Event::Online` itself isn't. Fails with the reason of the
+    /// disconnection if reconnecting is disabled, or with
+    /// `Error::Disconnected` if the stream ended.
+    pub async fn wait_online(&mut self) -> Result<Jid, SharedError> {
+        if let Some(index) = self.pending.iter().position(Event::is_online) {
+            match self.pending.remove(index) {
+                Some(Event::Online { bound_jid, .. }) => return Ok(bound_jid),
+                _ => unreachable!(),
+            }
+        }
+        if let ClientState::Connected(ref stream) = self.state {
+            return Ok(stream.jid.clone());
+        }
+        loop {
+            match self.next_event().await {
+                Some(Event::Online { bound_jid, .. }) => return Ok(bound_jid),
+                Some(Event::Disconnected(e)) if !self.reconnect => return Err(e),
+                Some(event) => self.pending.push_back(event),
+                None => return Err(Error::Disconnected.into()),
+            }
+        }
+    }
+
+    /// Wait until the client gets disconnected, returning why
+    ///
+    /// The other events received meanwhile are returned by the stream
+    /// afterwards, which may take a lot of memory for a long-lived
+    /// connection, but the `Event::Disconnected` itself isn't. Resolves
+    /// with `Error::Disconnected` right away if the stream already
+    /// ended.
+    pub async fn wait_disconnected(&mut self) -> SharedError {
+        if let Some(index) = self
+            .pending
+            .iter()
+            .position(|event| matches!(event, Event::Disconnected(_)))
+        {
+            match self.pending.remove(index) {
+                Some(Event::Disconnected(e)) => return e,
+                _ => unreachable!(),
+            }
+        }
+        loop {
+            match self.next_event().await {
+                Some(Event::Disconnected(e)) => return e,
+                Some(event) => self.pending.push_back(event),
+                None => return Error::Disconnected.into(),
+            }
+        }
+    }
+
+    /// The next event from the connection, skipping the pending ones
+    async fn next_event(&mut self) -> Option<Event> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_event(cx)).await
+    }
+
+    /// Gets woken up when the pinger has something to do
+    fn poll_ping_timer(&mut self, cx: &mut Context) {
+        let deadline = match self.pinger.wake_at() {
+            Some(deadline) => deadline,
+            None => {
+                self.ping_timer = None;
+                return;
+            }
+        };
+        let timer = match self.ping_timer {
+            Some(ref mut timer) => {
+                if timer.deadline() != deadline {
+                    timer.as_mut().reset(deadline);
+                }
+                timer
+            }
+            None => self
+                .ping_timer
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline))),
+        };
+        if timer.as_mut().poll(cx).is_ready() {
+            cx.waker().wake_by_ref();
+        }
+    }
+
+    /// Drops the connection after sending failed, the error being
+    /// returned by the next `Event::Disconnected`, and the caller only
+    /// getting `Error::Disconnected`
+    fn send_failed(&mut self, e: Error) -> Error {
+        if let ClientState::Connected(stream) = replace(&mut self.state, ClientState::Disconnected)
+        {
+            self.send_error = Some(self.disconnection(stream, e));
+            if let Some(waker) = self.waker.take() {
+                waker.wake();
+            }
+        }
+        Error::Disconnected
+    }
+
+    /// The reason of a disconnection, along with the stanzas which
+    /// didn't make it to the server
+    fn disconnection(&mut self, mut stream: XMPPStream, e: Error) -> SharedError {
+        self.sm.disconnected();
+        let unsent = stream.take_unwritten();
+        if unsent.is_empty() {
+            e.into()
+        } else {
+            Error::Unsent(unsent, Box::new(e)).into()
+        }
+    }
+
+    /// Connect to the host of a redirect on the next attempt, unless
+    /// we already got redirected too many times in a row
+    fn follow_redirect(&mut self, e: &Error) {
+        if let Error::Redirect { host, port } = e {
+            if self.reconnect && self.redirects < self.max_redirects {
+                self.redirects += 1;
+                self.redirect = Some(ServerConfig::Manual {
+                    host: host.clone(),
+                    port: *port,
+                });
+            }
+        }
+    }
+}
+
+/// Logs in like [`login_with_plan`], without stream management
+#[cfg(test)]
+pub(crate) async fn login<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: S,
+    jid: Jid,
+    secret: Secret,
+    stream_lang: Option<&str>,
+    bind_conflict_policy: &BindConflictPolicy,
+    stream_feature_hooks: &[StreamFeatureHook],
+) -> Result<(XMPPStream, String), Error> {
+    let (stream, sasl_mechanism, _) = login_with_plan(
+        stream,
+        jid,
+        secret,
+        stream_lang,
+        bind_conflict_policy,
+        stream_feature_hooks,
+        Plan::Off,
+    )
+    .await?;
+    Ok((stream, sasl_mechanism))
+}
+
+/// Logs in on an already encrypted connection: authenticates,
+/// negotiates the other stream features, compression included if
+/// possible, resumes or enables stream management as `plan` says, and
+/// binds a resource unless resumed
+///
+/// Returns the bound stream along with the SASL mechanism used and the
+/// outcome of stream management.
+async fn login_with_plan<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: S,
+    jid: Jid,
+    secret: Secret,
+    stream_lang: Option<&str>,
+    bind_conflict_policy: &BindConflictPolicy,
+    stream_feature_hooks: &[StreamFeatureHook],
+    plan: Plan,
+) -> Result<(XMPPStream, String, Outcome), Error> {
+    // Encrypted XMPPStream
+    let xmpp_stream = xmpp_stream::XMPPStream::start_with_lang(
+        stream,
+        jid.clone(),
+        ns::JABBER_CLIENT.to_owned(),
+        stream_lang.map(String::from),
+    )
+    .await?;
+    login_started(
+        xmpp_stream,
+        jid,
+        secret,
+        stream_lang,
+        bind_conflict_policy,
+        stream_feature_hooks,
+        plan,
+    )
+    .await
+}
+
+/// Logs in like [`login_with_plan`], on a stream already started
+async fn login_started<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    xmpp_stream: xmpp_stream::XMPPStream<S>,
+    jid: Jid,
+    secret: Secret,
+    stream_lang: Option<&str>,
+    bind_conflict_policy: &BindConflictPolicy,
+    stream_feature_hooks: &[StreamFeatureHook],
+    plan: Plan,
+) -> Result<(XMPPStream, String, Outcome), Error> {
+    // Authenticated (unspecified) stream
+    let (stream, sasl_mechanism) = auth(xmpp_stream, &jid, secret).await?;
+    let stream: BoxedStream = Box::new(stream);
+    // Authenticated XMPPStream
+    let xmpp_stream = xmpp_stream::XMPPStream::start_with_lang(
+        stream,
+        jid.clone(),
+        ns::JABBER_CLIENT.to_owned(),
+        stream_lang.map(String::from),
+    )
+    .await?;
+
+    // The hooks given come first, so that they take over the built-in ones.
+    #[allow(unused_mut)]
+    let mut hooks = stream_feature_hooks.to_vec();
+    #[cfg(feature = "compression")]
+    hooks.push(StreamFeatureHook {
+        ns: ns::COMPRESS_FEATURE.to_owned(),
+        name: String::from("compression"),
+        handler: stream_feature_handler(compression::negotiate),
+    });
+    let mut xmpp_stream = negotiate(xmpp_stream, &jid, stream_lang, &hooks).await?;
+
+    // A hook taking stream management over leaves it to the application.
+    let plan = match plan {
+        _ if !xmpp_stream.stream_features.can_stream_management() => Plan::Off,
+        _ if stream_feature_hooks.iter().any(|hook| hook.ns == ns::SM) => Plan::Off,
+        plan => plan,
+    };
+    if let Plan::Resume(ref session, handled) = plan {
+        // The session resumed keeps its bound resource
+        if sm::resume(&mut xmpp_stream, session, handled).await? {
+            return Ok((xmpp_stream, sasl_mechanism, Outcome::Resumed));
+        }
+    }
+
+    // XMPPStream bound to user session
+    let mut xmpp_stream = bind(xmpp_stream, bind_conflict_policy).await?;
+    let outcome = match plan {
+        Plan::Off => Outcome::Off,
+        Plan::Enable | Plan::Resume(..) => {
+            sm::enable(&mut xmpp_stream).await?;
+            Outcome::Enabling
+        }
+    };
+    Ok((xmpp_stream, sasl_mechanism, outcome))
+}
+
+#[cfg(feature = "test-internals")]
+impl Client {
+    /// The timers pending, for tests driving the client under tokio's
+    /// paused time
+    pub fn timers(&self) -> Vec<Timer> {
+        let mut timers = Vec::new();
+        let mut push = |kind, deadline| timers.push(Timer { kind, deadline });
+        match self.state {
+            ClientState::Connected(ref stream) => {
+                if let Some(deadline) = stream.keepalive_deadline() {
+                    push(TimerKind::Keepalive, deadline);
+                }
+                if let Some(deadline) = self.pinger.wake_at() {
+                    push(TimerKind::Ping, deadline);
+                }
+                if let Some(deadline) = stream.stanza_deadline() {
+                    push(TimerKind::StanzaTimeout, deadline);
+                }
+                if let Some(ref delay) = self.inbound_delay {
+                    push(TimerKind::InboundDelay, delay.deadline());
+                }
+            }
+            ClientState::Connecting(..) => {
+                if let Some(deadline) = self.reconnect_at {
+                    if deadline > tokio::time::Instant::now() {
+                        push(TimerKind::Reconnect, deadline);
+                    }
+                }
+            }
+            _ => (),
+        }
+        timers
+    }
+
+    /// How much is waiting in the queues of the client
+    pub fn queue_depths(&self) -> QueueDepths {
+        let (outgoing, unwritten_bytes) = match self.state {
+            ClientState::Connected(ref stream) => stream.queue_depth(),
+            _ => (0, 0),
+        };
+        QueueDepths {
+            outgoing,
+            unwritten_bytes,
+            pending_events: self.pending.len(),
+        }
+    }
+}
+
+/// Cancels a connection attempt still in progress
+///
+/// The DNS lookups, socket and TLS handshake of the connect task are
+/// released right away, without relying on the order in which the
+/// `LocalSet` and the task handle get dropped.
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let ClientState::Connecting(ref connect, _) = self.state {
+            connect.abort();
+        }
+    }
 }
 
 /// Incoming XMPP events
@@ -190,36 +963,103 @@ impl Stream for Client {
     ///
     /// ...for your client
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Poll::Ready(Some(event));
+        }
+        self.poll_event(cx)
+    }
+}
+
+impl Client {
+    /// Everything `poll_next()` does, except returning the pending
+    /// events
+    fn poll_event(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Event>> {
+        if let Some(e) = self.send_error.take() {
+            return Poll::Ready(Some(Event::Disconnected(e)));
+        }
+        match self.waker {
+            Some(ref waker) if waker.will_wake(cx.waker()) => (),
+            _ => self.waker = Some(cx.waker().clone()),
+        }
         let state = replace(&mut self.state, ClientState::Invalid);
 
         match state {
             ClientState::Invalid => panic!("Invalid client state"),
             ClientState::Disconnected if self.reconnect => {
+                if let Some(server) = self.redirect.take() {
+                    #[cfg(feature = "test-internals")]
+                    {
+                        self.reconnect_at = None;
+                    }
+                    // A redirect takes precedence over resuming elsewhere.
+                    let enable = self.config.stream_management;
+                    let (plan, expired) = self.sm.plan(enable, Duration::from_secs(0));
+                    self.state =
+                        Self::start_connect(&self.config, &server, Duration::from_secs(0), plan);
+                    if let Some(event) = expired {
+                        return Poll::Ready(Some(event));
+                    }
+                    return self.poll_event(cx);
+                }
                 // TODO: add timeout
-                let mut local = LocalSet::new();
-                let connect = local.spawn_local(Self::connect(
-                    self.config.server.clone(),
-                    self.config.jid.clone(),
-                    self.config.password.clone(),
-                ));
-                let _ = Pin::new(&mut local).poll(cx);
-                self.state = ClientState::Connecting(connect, local);
-                self.poll_next(cx)
-            }
-            ClientState::Disconnected => Poll::Ready(None),
+                let now = Instant::now();
+                match self.backoff.next(now) {
+                    Retry::After(delay) => match self.reconnect_after(delay) {
+                        Some(expired) => Poll::Ready(Some(expired)),
+                        None => self.poll_event(cx),
+                    },
+                    Retry::SuspendedUntil(until) => {
+                        if let Some(expired) = self.reconnect_after(until - now) {
+                            self.pending.push_back(expired);
+                        }
+                        let error = match self.last_failure {
+                            Some(ref error) => error.clone(),
+                            None => Error::Disconnected.into(),
+                        };
+                        Poll::Ready(Some(Event::ReconnectionSuspended { until, error }))
+                    }
+                }
+            }
+            ClientState::Disconnected => {
+                // Keep ending, for those waiting on a transition
+                self.state = ClientState::Disconnected;
+                Poll::Ready(None)
+            }
             ClientState::Connecting(mut connect, mut local) => {
                 match Pin::new(&mut connect).poll(cx) {
-                    Poll::Ready(Ok(Ok(stream))) => {
+                    Poll::Ready(Ok(Ok((mut stream, sasl_mechanism, outcome)))) => {
+                        self.backoff.reset();
+                        self.last_failure = None;
+                        self.redirects = 0;
+                        self.sasl_mechanism = Some(sasl_mechanism);
+                        self.sm.logged_in(&outcome);
+                        self.sm_answer = None;
+                        stream.set_keepalive(self.keepalive);
+                        if let Some(timeout) = self.close_timeout {
+                            stream.set_close_timeout(timeout);
+                        }
+                        stream.set_stanza_timeout(self.stanza_timeout);
+                        if let Some(size) = self.write_chunk_size {
+                            stream.set_write_chunk_size(size);
+                        }
+                        self.pinger.reset();
                         let bound_jid = stream.jid.clone();
                         self.state = ClientState::Connected(stream);
                         Poll::Ready(Some(Event::Online {
                             bound_jid,
-                            resumed: false,
+                            resumed: matches!(outcome, Outcome::Resumed),
                         }))
                     }
                     Poll::Ready(Ok(Err(e))) => {
+                        // Being redirected isn't a failure to back off from.
+                        match e {
+                            Error::Redirect { .. } => self.follow_redirect(&e),
+                            _ => self.backoff.failed(),
+                        }
                         self.state = ClientState::Disconnected;
-                        return Poll::Ready(Some(Event::Disconnected(e.into())));
+                        let e = SharedError::from(e);
+                        self.last_failure = Some(e.clone());
+                        Poll::Ready(Some(Event::Disconnected(e)))
                     }
                     Poll::Ready(Err(e)) => {
                         self.state = ClientState::Disconnected;
@@ -233,57 +1073,157 @@ impl Stream for Client {
                     }
                 }
             }
-            ClientState::Connected(mut stream) => {
+            ClientState::Connected(mut stream) => loop {
                 // Poll sink
-                match Pin::new(&mut stream).poll_ready(cx) {
-                    Poll::Pending => (),
-                    Poll::Ready(Ok(())) => (),
+                let mut ready = match Pin::new(&mut stream).poll_ready(cx) {
+                    Poll::Pending => false,
+                    Poll::Ready(Ok(())) => true,
                     Poll::Ready(Err(e)) => {
                         self.state = ClientState::Disconnected;
-                        return Poll::Ready(Some(Event::Disconnected(e.into())));
+                        return Poll::Ready(Some(Event::Disconnected(
+                            self.disconnection(stream, e),
+                        )));
                     }
                 };
 
-                // Poll stream
+                // Acknowledge what the server asked for, once there is
+                // room to
+                if ready {
+                    if let Some(answer) = self.sm_answer.take() {
+                        if let Err(e) = Pin::new(&mut stream).start_send(Packet::Stanza(answer)) {
+                            self.state = ClientState::Disconnected;
+                            return Poll::Ready(Some(Event::Disconnected(
+                                self.disconnection(stream, e),
+                            )));
+                        }
+                        // Errors show up on the next poll_ready().
+                        let _ = Pin::new(&mut stream).poll_flush(cx);
+                        ready = false;
+                    }
+                }
+
+                // Ping the server when idle, and give up on it once it
+                // doesn't answer, even if the socket is stuck
+                let now = tokio::time::Instant::now();
+                if self.pinger.timed_out(now) {
+                    self.state = ClientState::Disconnected;
+                    return Poll::Ready(Some(Event::Disconnected(
+                        self.disconnection(stream, Error::KeepaliveTimeout),
+                    )));
+                }
+                if ready && self.pinger.due(now) {
+                    let server = BareJid::domain(stream.jid.clone().domain());
+                    let ping = self.pinger.ping(server, now);
+                    if let Err(e) = Pin::new(&mut stream).start_send(Packet::Stanza(ping)) {
+                        self.state = ClientState::Disconnected;
+                        return Poll::Ready(Some(Event::Disconnected(
+                            self.disconnection(stream, e),
+                        )));
+                    }
+                    // Errors show up on the next poll_ready().
+                    let _ = Pin::new(&mut stream).poll_flush(cx);
+                }
+                self.poll_ping_timer(cx);
+
+                // Pace reading, leaving the stanzas we don't read yet in
+                // the socket
+                if let Some(ref mut delay) = self.inbound_delay {
+                    if delay.as_mut().poll(cx).is_pending() {
+                        self.state = ClientState::Connected(stream);
+                        return Poll::Pending;
+                    }
+                    self.inbound_delay = None;
+                }
+                match self.throttle.admit(Instant::now()) {
+                    Admit::Now => (),
+                    Admit::Yield => {
+                        // Let other tasks run, then come back
+                        cx.waker().wake_by_ref();
+                        self.state = ClientState::Connected(stream);
+                        return Poll::Pending;
+                    }
+                    Admit::After(delay) => {
+                        let mut delay = Box::pin(tokio::time::sleep(delay));
+                        if delay.as_mut().poll(cx).is_ready() {
+                            cx.waker().wake_by_ref();
+                        }
+                        self.inbound_delay = Some(delay);
+                        self.state = ClientState::Connected(stream);
+                        return Poll::Pending;
+                    }
+                }
+
+                // Poll stream, reading on past what the client consumes
+                // itself
                 match Pin::new(&mut stream).poll_next(cx) {
                     Poll::Ready(None) => {
                         // EOF
                         self.state = ClientState::Disconnected;
-                        Poll::Ready(Some(Event::Disconnected(Error::Disconnected)))
+                        return Poll::Ready(Some(Event::Disconnected(
+                            self.disconnection(stream, Error::Disconnected),
+                        )));
                     }
                     Poll::Ready(Some(Ok(Packet::Stanza(stanza)))) => {
+                        if let Some(e) = redirect(&stanza) {
+                            // The server is about to close the stream
+                            self.follow_redirect(&e);
+                            self.state = ClientState::Disconnected;
+                            return Poll::Ready(Some(Event::Disconnected(
+                                self.disconnection(stream, e),
+                            )));
+                        }
+                        let stanza = match self.sm.received(stanza, &stream.jid) {
+                            Received::Stanza(stanza) => stanza,
+                            Received::Handled => continue,
+                            Received::Answer(answer) => {
+                                // Sent once the sink is ready, a newer
+                                // answer superseding an unsent one
+                                self.sm_answer = Some(answer);
+                                continue;
+                            }
+                        };
                         // Receive stanza
+                        self.throttle.received();
+                        if self.pinger.received(&stanza, tokio::time::Instant::now()) {
+                            // Answers to our own pings are none of the
+                            // application's business
+                            continue;
+                        }
                         self.state = ClientState::Connected(stream);
-                        Poll::Ready(Some(Event::Stanza(stanza)))
+                        return Poll::Ready(Some(Event::Stanza(stanza)));
                     }
                     Poll::Ready(Some(Ok(Packet::Text(_)))) => {
-                        // Ignore text between stanzas
-                        self.state = ClientState::Connected(stream);
-                        Poll::Pending
+                        // Ignore text between stanzas, and read on
+                        continue;
                     }
                     Poll::Ready(Some(Ok(Packet::StreamStart(_)))) => {
                         // <stream:stream>
                         self.state = ClientState::Disconnected;
-                        Poll::Ready(Some(Event::Disconnected(
-                            ProtocolError::InvalidStreamStart.into(),
-                        )))
+                        return Poll::Ready(Some(Event::Disconnected(
+                            Error::from(ProtocolError::InvalidStreamStart).into(),
+                        )));
                     }
                     Poll::Ready(Some(Ok(Packet::StreamEnd))) => {
                         // End of stream: </stream:stream>
                         self.state = ClientState::Disconnected;
-                        Poll::Ready(Some(Event::Disconnected(Error::Disconnected)))
+                        return Poll::Ready(Some(Event::Disconnected(
+                            self.disconnection(stream, Error::Disconnected),
+                        )));
                     }
                     Poll::Pending => {
                         // Try again later
+                        self.throttle.idle();
                         self.state = ClientState::Connected(stream);
-                        Poll::Pending
+                        return Poll::Pending;
                     }
                     Poll::Ready(Some(Err(e))) => {
                         self.state = ClientState::Disconnected;
-                        Poll::Ready(Some(Event::Disconnected(e.into())))
+                        return Poll::Ready(Some(Event::Disconnected(
+                            self.disconnection(stream, e),
+                        )));
                     }
                 }
-            }
+            },
         }
     }
 }
@@ -295,38 +1235,1011 @@ impl Sink<Packet> for Client {
     type Error = Error;
 
     fn start_send(mut self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
-        match self.state {
-            ClientState::Connected(ref mut stream) => {
-                Pin::new(stream).start_send(item).map_err(|e| e.into())
-            }
-            _ => Err(Error::InvalidState),
+        let result = match self.state {
+            ClientState::Connected(ref mut stream) => Pin::new(stream).start_send(item),
+            _ => return Err(Error::InvalidState),
+        };
+        match result {
+            // The connection is still up, to read what the server sends.
+            Err(Error::Closing) => Err(Error::Closing),
+            result => result.map_err(|e| self.send_failed(e)),
         }
     }
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        match self.state {
-            ClientState::Connected(ref mut stream) => {
-                Pin::new(stream).poll_ready(cx).map_err(|e| e.into())
+        let result = match self.state {
+            ClientState::Connected(ref mut stream) => ready!(Pin::new(stream).poll_ready(cx)),
+            _ => return Poll::Pending,
+        };
+        Poll::Ready(result.map_err(|e| self.send_failed(e)))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let result = match self.state {
+            ClientState::Connected(ref mut stream) => ready!(Pin::new(stream).poll_flush(cx)),
+            _ => return Poll::Pending,
+        };
+        Poll::Ready(result.map_err(|e| self.send_failed(e)))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let result = match self.state {
+            ClientState::Connected(ref mut stream) => ready!(Pin::new(stream).poll_close(cx)),
+            _ => return Poll::Pending,
+        };
+        Poll::Ready(result.map_err(|e| self.send_failed(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::StreamExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+    use tokio_util::codec::Framed;
+    use xmpp_parsers::FullJid;
+
+    use crate::client::credentials_provider;
+    #[cfg(feature = "compression")]
+    use crate::compression::ZlibStream;
+    use crate::test_harness::{
+        authenticate, bind_resource, expect, send, start_stream, Handshake, Matcher, ScriptedServer,
+    };
+    use crate::xmpp_codec::XMPPCodec;
+
+    fn iq(xml: &str) -> Element {
+        xml.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let jid = FullJid::from_str("foo@bar/baz").unwrap();
+        let (stream, server) = ScriptedServer::new(Handshake::Login(jid.clone())).start();
+        let policy = BindConflictPolicy::default();
+        let client = login(
+            stream,
+            Jid::from_str("foo@bar").unwrap(),
+            Secret::Password(String::from("meh")),
+            None,
+            &policy,
+            &[],
+        );
+        let (result, ()) = tokio::join!(client, server);
+        let (stream, sasl_mechanism) = result.unwrap();
+        assert_eq!(stream.jid, Jid::Full(jid));
+        assert_eq!(sasl_mechanism, "PLAIN");
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_login() {
+        let jid = FullJid::from_str("a1b2c3@bar/d4e5f6").unwrap();
+        let (stream, server) = ScriptedServer::new(Handshake::Login(jid.clone())).start();
+        let policy = BindConflictPolicy::default();
+        let client = login(
+            stream,
+            Jid::from_str("bar").unwrap(),
+            Secret::Password(String::new()),
+            None,
+            &policy,
+            &[],
+        );
+        let (result, ()) = tokio::join!(client, server);
+        let (stream, sasl_mechanism) = result.unwrap();
+        // Node and resource both come from the server.
+        assert_eq!(stream.jid, Jid::Full(jid));
+        assert_eq!(sasl_mechanism, "ANONYMOUS");
+    }
+
+    #[tokio::test]
+    async fn test_iq_roundtrip() {
+        let jid = FullJid::from_str("foo@bar/baz").unwrap();
+        let ping = "<iq xmlns='jabber:client' type='get' to='bar' id='ping-1'><ping xmlns='urn:xmpp:ping'/></iq>";
+        let (stream, server) = ScriptedServer::new(Handshake::Login(jid.clone()))
+            .expect(Matcher::element(iq(ping)))
+            .reply(iq("<iq xmlns='jabber:client' type='result' from='bar'/>"))
+            .start();
+        let client = async move {
+            let (mut stream, _) = login(
+                stream,
+                Jid::Full(jid),
+                Secret::Password(String::from("meh")),
+                None,
+                &BindConflictPolicy::default(),
+                &[],
+            )
+            .await
+            .unwrap();
+            stream.send_stanza(iq(ping)).await.unwrap();
+            loop {
+                match stream.next().await {
+                    Some(Ok(Packet::Stanza(stanza))) => break stanza,
+                    Some(Ok(_)) => (),
+                    packet => panic!("unexpected packet: {:?}", packet),
+                }
             }
-            _ => Poll::Pending,
+        };
+        let (result, ()) = tokio::join!(client, server);
+        assert_eq!(result.attr("type"), Some("result"));
+        assert_eq!(result.attr("id"), Some("ping-1"));
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_mid_stream() {
+        let jid = FullJid::from_str("foo@bar/baz").unwrap();
+        let (stream, server) = ScriptedServer::new(Handshake::Login(jid.clone()))
+            .send(iq(
+                "<message xmlns='jabber:client'><body>Bye</body></message>",
+            ))
+            .disconnect()
+            .start();
+        let client = async move {
+            let (mut stream, _) = login(
+                stream,
+                Jid::Full(jid),
+                Secret::Password(String::from("meh")),
+                None,
+                &BindConflictPolicy::default(),
+                &[],
+            )
+            .await
+            .unwrap();
+            let mut packets = Vec::new();
+            while let Some(packet) = stream.next().await {
+                packets.push(packet);
+            }
+            packets
+        };
+        let (packets, ()) = tokio::join!(client, server);
+        // The message arrives, then the connection ends without a
+        // closing </stream:stream>.
+        assert_eq!(packets.len(), 1);
+        match packets[0] {
+            Ok(Packet::Stanza(ref stanza)) => assert!(stanza.is("message", ns::JABBER_CLIENT)),
+            ref packet => panic!("unexpected packet: {:?}", packet),
         }
     }
 
-    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        match self.state {
-            ClientState::Connected(ref mut stream) => {
-                Pin::new(stream).poll_flush(cx).map_err(|e| e.into())
+    fn features(children: Vec<Element>) -> Element {
+        Element::builder("features", ns::STREAM)
+            .append_all(children)
+            .build()
+    }
+
+    fn zlib_feature() -> Element {
+        Element::builder("compression", ns::COMPRESS_FEATURE)
+            .append(Element::builder("method", ns::COMPRESS_FEATURE).append("zlib"))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_stream_lang() {
+        let jid = FullJid::from_str("foo@bar/baz").unwrap();
+        let (client, server) = duplex(65536);
+        let server = {
+            let jid = jid.clone();
+            async move {
+                let mut stream = Framed::new(server, XMPPCodec::new());
+                let mechanisms = Element::builder("mechanisms", ns::SASL)
+                    .append(Element::builder("mechanism", ns::SASL).append("PLAIN"))
+                    .build();
+                let header = start_stream(&mut stream, features(vec![mechanisms])).await;
+                assert_eq!(header.lang.as_deref(), Some("fr"));
+                expect(&mut stream, &Matcher::new("auth", ns::SASL), "auth").await;
+                send(&mut stream, Element::bare("success", ns::SASL)).await;
+
+                // The restarted stream keeps the language.
+                let mut stream = Framed::new(stream.into_inner(), XMPPCodec::new());
+                let offered = vec![Element::bare("bind", ns::BIND)];
+                let header = start_stream(&mut stream, features(offered)).await;
+                assert_eq!(header.lang.as_deref(), Some("fr"));
+                bind_resource(&mut stream, jid).await;
+            }
+        };
+
+        let config =
+            Config::new(Jid::Full(jid.clone()), "meh", ServerConfig::UseSrv).stream_lang("fr");
+        let client = login(
+            client,
+            config.jid.clone(),
+            Secret::Password(config.password.clone()),
+            config.stream_lang.as_deref(),
+            &config.bind_conflict_policy,
+            &config.stream_feature_hooks,
+        );
+
+        let (result, ()) = tokio::join!(client, server);
+        let (stream, _) = result.unwrap();
+        assert_eq!(stream.jid, Jid::Full(jid));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ping_timeout() {
+        let jid = FullJid::from_str("foo@bar/baz").unwrap();
+        let (stream, server) = duplex(65536);
+        let server = {
+            let jid = jid.clone();
+            async move {
+                let mut stream = authenticate(Framed::new(server, XMPPCodec::new())).await;
+                start_stream(&mut stream, features(vec![Element::bare("bind", ns::BIND)])).await;
+                bind_resource(&mut stream, jid).await;
+                stream
+            }
+        };
+        let policy = BindConflictPolicy::default();
+        let login = login(
+            stream,
+            Jid::Full(jid.clone()),
+            Secret::Password(String::from("meh")),
+            None,
+            &policy,
+            &[],
+        );
+        let (result, mut server) = tokio::join!(login, server);
+        let (stream, _) = result.unwrap();
+
+        let mut client =
+            Client::new_with_config(Config::new(Jid::Full(jid), "meh", ServerConfig::UseSrv));
+        client.state = ClientState::Connected(stream);
+        client.set_ping_policy(Some(PingPolicy {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(5),
+        }));
+
+        let ping = Matcher::new("iq", ns::JABBER_CLIENT)
+            .attr("type", "get")
+            .attr("to", "bar");
+        let server = async move {
+            let request = expect(&mut server, &ping, "first ping").await;
+            let pong = Element::builder("iq", ns::JABBER_CLIENT)
+                .attr("type", "result")
+                .attr("id", request.attr("id"))
+                .attr("from", "bar")
+                .build();
+            send(&mut server, pong).await;
+            send(
+                &mut server,
+                iq("<message xmlns='jabber:client' from='baz@bar'/>"),
+            )
+            .await;
+            // This one stays unanswered.
+            expect(&mut server, &ping, "second ping").await;
+            server
+        };
+        let client = async {
+            let start = tokio::time::Instant::now();
+            // The answer to the ping isn't an event.
+            match client.next().await {
+                Some(Event::Stanza(stanza)) if stanza.is("message", ns::JABBER_CLIENT) => (),
+                event => panic!("unexpected event: {:?}", event),
+            }
+            assert!(start.elapsed() >= Duration::from_secs(10));
+            match client.next().await {
+                Some(Event::Disconnected(e)) if matches!(*e, Error::KeepaliveTimeout) => (),
+                event => panic!("unexpected event: {:?}", event),
+            }
+            assert!(start.elapsed() >= Duration::from_secs(25));
+        };
+        let (_server, ()) = tokio::join!(server, client);
+    }
+
+    #[tokio::test]
+    async fn test_stream_feature_hooks() {
+        const CUSTOM: &str = "urn:example:custom";
+        let jid = FullJid::from_str("foo@bar/baz").unwrap();
+        let (client, server) = duplex(65536);
+        let server = {
+            let jid = jid.clone();
+            async move {
+                let mut stream = authenticate(Framed::new(server, XMPPCodec::new())).await;
+                let offered = vec![
+                    Element::bare("custom", CUSTOM),
+                    zlib_feature(),
+                    Element::bare("bind", ns::BIND),
+                ];
+                start_stream(&mut stream, features(offered)).await;
+                expect(&mut stream, &Matcher::new("enable", CUSTOM), "custom").await;
+                send(&mut stream, Element::bare("enabled", CUSTOM)).await;
+                // Compression got taken over, so the client binds right away.
+                bind_resource(&mut stream, jid).await;
+            }
+        };
+
+        let compression_offers = Arc::new(AtomicUsize::new(0));
+        let config = Config::new(Jid::Full(jid.clone()), "meh", ServerConfig::UseSrv)
+            .on_stream_feature(CUSTOM, "custom", |mut stream| async move {
+                stream.send_stanza(Element::bare("enable", CUSTOM)).await?;
+                loop {
+                    match stream.next().await {
+                        Some(Ok(Packet::Stanza(stanza))) if stanza.is("enabled", CUSTOM) => break,
+                        Some(Ok(Packet::Text(_))) => (),
+                        packet => panic!("unexpected packet: {:?}", packet),
+                    }
+                }
+                Ok(Negotiated::Continue(Box::new(stream)))
+            })
+            .on_stream_feature(ns::COMPRESS_FEATURE, "compression", {
+                let compression_offers = compression_offers.clone();
+                move |stream| {
+                    compression_offers.fetch_add(1, Ordering::SeqCst);
+                    async move { Ok(Negotiated::Continue(Box::new(stream))) }
+                }
+            });
+        let client = login(
+            client,
+            config.jid.clone(),
+            Secret::Password(config.password.clone()),
+            None,
+            &config.bind_conflict_policy,
+            &config.stream_feature_hooks,
+        );
+
+        let (result, ()) = tokio::join!(client, server);
+        let (stream, _) = result.unwrap();
+        assert_eq!(stream.jid, Jid::Full(jid));
+        assert_eq!(compression_offers.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_login_compressed() {
+        let jid = FullJid::from_str("foo@bar/baz").unwrap();
+        let (client, server) = duplex(65536);
+        let server = {
+            let jid = jid.clone();
+            async move {
+                let stream = Framed::new(ZlibStream::Plain(server), XMPPCodec::new());
+                let mut stream = authenticate(stream).await;
+                let offered = vec![zlib_feature(), Element::bare("bind", ns::BIND)];
+                start_stream(&mut stream, features(offered)).await;
+                let matcher = Matcher::new("compress", ns::COMPRESS);
+                let compress = expect(&mut stream, &matcher, "compression").await;
+                let method = compress.get_child("method", ns::COMPRESS).unwrap();
+                assert_eq!(method.text(), "zlib");
+                send(&mut stream, Element::bare("compressed", ns::COMPRESS)).await;
+
+                // The stream restarts, compressed.
+                let stream = stream.into_inner().into_compressed();
+                let mut stream = Framed::new(stream, XMPPCodec::new());
+                let offered = vec![Element::bare("bind", ns::BIND)];
+                start_stream(&mut stream, features(offered)).await;
+                bind_resource(&mut stream, jid).await;
+                send(&mut stream, iq("<message xmlns='jabber:client'/>")).await;
+                // Dropping it would end the zlib stream abruptly, failing the client.
+                stream
+            }
+        };
+        let client = async move {
+            let (mut stream, _) = login(
+                client,
+                Jid::from_str("foo@bar").unwrap(),
+                Secret::Password(String::from("meh")),
+                None,
+                &BindConflictPolicy::default(),
+                &[],
+            )
+            .await
+            .unwrap();
+            loop {
+                match stream.next().await {
+                    Some(Ok(Packet::Stanza(stanza))) => break (stream.jid.clone(), stanza),
+                    Some(Ok(_)) => (),
+                    packet => panic!("unexpected packet: {:?}", packet),
+                }
+            }
+        };
+
+        let ((bound_jid, stanza), _server) = tokio::join!(client, server);
+        assert_eq!(bound_jid, Jid::Full(jid));
+        assert!(stanza.is("message", ns::JABBER_CLIENT));
+    }
+
+    #[tokio::test]
+    async fn test_half_close() {
+        let jid = FullJid::from_str("foo@bar/baz").unwrap();
+        let (stream, server) = ScriptedServer::new(Handshake::Login(jid.clone()))
+            .expect_end()
+            .send(iq(
+                "<message xmlns='jabber:client'><body>Last words</body></message>",
+            ))
+            .start();
+        let client = async move {
+            let (mut stream, _) = login(
+                stream,
+                Jid::Full(jid),
+                Secret::Password(String::from("meh")),
+                None,
+                &BindConflictPolicy::default(),
+                &[],
+            )
+            .await
+            .unwrap();
+            // Returns once the server closed its side too.
+            stream.close().await.unwrap();
+            match stream
+                .send_stanza(iq("<presence xmlns='jabber:client'/>"))
+                .await
+            {
+                Err(Error::Closing) => (),
+                result => panic!("unexpected result: {:?}", result),
+            }
+            let mut packets = Vec::new();
+            while let Some(packet) = stream.next().await {
+                packets.push(packet);
+            }
+            packets
+        };
+        let (packets, ()) = tokio::join!(client, server);
+        // What the server sent after our </stream:stream> still arrives,
+        // before its own.
+        assert_eq!(packets.len(), 2);
+        match packets[0] {
+            Ok(Packet::Stanza(ref stanza)) => assert!(stanza.is("message", ns::JABBER_CLIENT)),
+            ref packet => panic!("unexpected packet: {:?}", packet),
+        }
+        assert!(matches!(packets[1], Ok(Packet::StreamEnd)));
+    }
+
+    #[tokio::test]
+    async fn test_drop_while_connecting() {
+        // A server which accepts the TCP connection but never answers the stream header, so
+        // that the client stays stuck in its connect task.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = Client::new_with_config(Config::new(
+            Jid::from_str("foo@127.0.0.1").unwrap(),
+            "meh",
+            ServerConfig::Manual {
+                host: String::from("127.0.0.1"),
+                port,
+            },
+        ));
+
+        let (mut socket, _) = tokio::select! {
+            accepted = listener.accept() => accepted.unwrap(),
+            event = client.next() => panic!("unexpected event: {:?}", event),
+        };
+        let mut buf = [0u8; 1024];
+        let mut header = Vec::new();
+        while !header.ends_with(b">\n") {
+            tokio::select! {
+                read = socket.read(&mut buf) => header.extend_from_slice(&buf[..read.unwrap()]),
+                event = client.next() => panic!("unexpected event: {:?}", event),
             }
-            _ => Poll::Pending,
         }
+
+        drop(client);
+
+        // The connect task must have been torn down along with its socket.
+        let read = timeout(Duration::from_secs(5), socket.read(&mut buf))
+            .await
+            .expect("socket still open after dropping the client");
+        assert_eq!(read.unwrap(), 0);
     }
 
-    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        match self.state {
-            ClientState::Connected(ref mut stream) => {
-                Pin::new(stream).poll_close(cx).map_err(|e| e.into())
+    #[tokio::test]
+    async fn test_wait_online() {
+        // Nothing listens on that port anymore, so connecting fails right away.
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let mut client = Client::new_with_config(Config::new(
+            Jid::from_str("foo@127.0.0.1").unwrap(),
+            "meh",
+            ServerConfig::Manual {
+                host: String::from("127.0.0.1"),
+                port,
+            },
+        ));
+        let stanza =
+            |id: &str| Event::Stanza(iq(&format!("<message xmlns='jabber:client' id='{}'/>", id)));
+        let bound_jid = Jid::from_str("foo@127.0.0.1/baz").unwrap();
+        client.pending.extend(vec![
+            stanza("before"),
+            Event::Online {
+                bound_jid: bound_jid.clone(),
+                resumed: false,
+            },
+            stanza("after"),
+        ]);
+
+        // The transition is picked among the events received meanwhile, the other ones are
+        // still returned in order.
+        assert_eq!(client.wait_online().await.unwrap(), bound_jid);
+        for id in &["before", "after"] {
+            match client.next().await {
+                Some(Event::Stanza(stanza)) => assert_eq!(stanza.attr("id"), Some(*id)),
+                event => panic!("unexpected event: {:?}", event),
+            }
+        }
+
+        // Without reconnecting, a failed connection ends the wait.
+        match timeout(Duration::from_secs(5), client.wait_online())
+            .await
+            .unwrap()
+        {
+            Err(e) if matches!(*e, Error::Io(_)) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(matches!(
+            *client.wait_disconnected().await,
+            Error::Disconnected
+        ));
+        assert!(client.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reconnection_suspended() {
+        // Nothing listens on that port anymore, so every attempt fails right away.
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let mut client = Client::new_with_config(Config::new(
+            Jid::from_str("foo@127.0.0.1").unwrap(),
+            "meh",
+            ServerConfig::Manual {
+                host: String::from("127.0.0.1"),
+                port,
+            },
+        ));
+        client
+            .set_reconnect(true)
+            .set_reconnect_policy(ReconnectPolicy {
+                initial_delay: Duration::from_millis(1),
+                max_failures: Some(2),
+                suspension: Duration::from_secs(3600),
+                ..Default::default()
+            })
+            .set_reconnect_random(|| 0.5);
+
+        for _ in 0..2 {
+            match timeout(Duration::from_secs(5), client.next())
+                .await
+                .unwrap()
+            {
+                Some(Event::Disconnected(_)) => (),
+                event => panic!("unexpected event: {:?}", event),
+            }
+        }
+        let before = Instant::now();
+        match timeout(Duration::from_secs(5), client.next())
+            .await
+            .unwrap()
+        {
+            Some(Event::ReconnectionSuspended { until, error }) => {
+                assert!(until >= before + Duration::from_secs(3599));
+                assert!(matches!(*error, Error::Io(_) | Error::Connection(_)));
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        // The suspension would last an hour, but an operator can cut it short.
+        timeout(Duration::from_millis(100), client.next())
+            .await
+            .expect_err("still suspended");
+        client.retry_now();
+        match timeout(Duration::from_secs(5), client.next())
+            .await
+            .unwrap()
+        {
+            Some(Event::Disconnected(_)) => (),
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_disconnection() {
+        // Nothing listens on that port anymore, so the connection is refused.
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let mut client = Client::new_with_config(Config::new(
+            Jid::from_str("foo@127.0.0.1").unwrap(),
+            "meh",
+            ServerConfig::Loopback { port },
+        ));
+        let (sender, mut first) = tokio::sync::broadcast::channel(4);
+        let mut second = sender.subscribe();
+        let event = timeout(Duration::from_secs(5), client.next())
+            .await
+            .unwrap()
+            .unwrap();
+        sender.send(event).unwrap();
+
+        let mut messages = Vec::new();
+        for receiver in [&mut first, &mut second].iter_mut() {
+            let e = match receiver.recv().await.unwrap() {
+                Event::Disconnected(e) => e,
+                event => panic!("unexpected event: {:?}", event),
+            };
+            assert!(matches!(*e, Error::Io(_)));
+            let source = std::error::Error::source(&e).unwrap();
+            assert!(source.downcast_ref::<std::io::Error>().is_some());
+            messages.push(e.to_string());
+        }
+        assert_eq!(messages[0], messages[1]);
+        assert!(client.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_credentials_provider() {
+        // Nothing listens on that port anymore, so every attempt fails right away.
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let calls = Arc::new(AtomicUsize::new(0));
+        let provider = {
+            let calls = calls.clone();
+            credentials_provider(move || {
+                let call = calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    match call {
+                        0 => Err(Error::Config(String::from("token expired"))),
+                        _ => Ok(Secret::OAuthToken(String::from("refreshed"))),
+                    }
+                }
+            })
+        };
+        let mut client = Client::new_with_config(Config {
+            credentials: Some(provider),
+            ..Config::new(
+                Jid::from_str("foo@127.0.0.1").unwrap(),
+                "",
+                ServerConfig::Manual {
+                    host: String::from("127.0.0.1"),
+                    port,
+                },
+            )
+        });
+        client
+            .set_reconnect(true)
+            .set_reconnect_policy(ReconnectPolicy {
+                initial_delay: Duration::from_millis(1),
+                ..Default::default()
+            })
+            .set_reconnect_random(|| 0.5);
+
+        // The provider failing fails the attempt, and gets asked again on the next one.
+        match timeout(Duration::from_secs(5), client.next())
+            .await
+            .unwrap()
+        {
+            Some(Event::Disconnected(e)) if matches!(*e, Error::Config(_)) => (),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        match timeout(Duration::from_secs(5), client.next())
+            .await
+            .unwrap()
+        {
+            Some(Event::Disconnected(e)) if matches!(*e, Error::Io(_)) => (),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    /// Accepts a connection, and answers its stream header with a
+    /// `<see-other-host/>` pointing to `port`
+    async fn redirect_once(listener: &TcpListener, port: u16) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 1024];
+        let mut header = Vec::new();
+        while !header.ends_with(b">\n") {
+            let read = socket.read(&mut buf).await.unwrap();
+            header.extend_from_slice(&buf[..read]);
+        }
+        let reply = format!("<?xml version='1.0'?><stream:stream xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams' id='redirect' from='127.0.0.1' version='1.0'><stream:error><see-other-host xmlns='urn:ietf:params:xml:ns:xmpp-streams'>127.0.0.1:{}</see-other-host></stream:error></stream:stream>", port);
+        socket.write_all(reply.as_bytes()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_see_other_host() {
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let other = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let other_port = other.local_addr().unwrap().port();
+        let config = || {
+            Config::new(
+                Jid::from_str("foo@127.0.0.1").unwrap(),
+                "meh",
+                ServerConfig::Manual {
+                    host: String::from("127.0.0.1"),
+                    port: first.local_addr().unwrap().port(),
+                },
+            )
+        };
+
+        // Without reconnecting, the application is told where to go.
+        let mut client = Client::new_with_config(config());
+        let (_, event) = tokio::join!(redirect_once(&first, other_port), client.next());
+        match event {
+            Some(Event::Disconnected(e)) => match *e {
+                Error::Redirect { ref host, port } => {
+                    assert_eq!(host, "127.0.0.1");
+                    assert_eq!(port, other_port);
+                }
+                _ => panic!("unexpected error: {}", e),
+            },
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        // Otherwise the next attempt goes to the other host.
+        let mut client = Client::new_with_config(config());
+        client.set_reconnect(true);
+        let (_, event) = tokio::join!(redirect_once(&first, other_port), client.next());
+        assert!(matches!(
+            event,
+            Some(Event::Disconnected(e)) if matches!(*e, Error::Redirect { .. })
+        ));
+        tokio::select! {
+            accepted = other.accept() => drop(accepted.unwrap()),
+            event = client.next() => panic!("unexpected event: {:?}", event),
+        }
+
+        // Up to a limit, after which the configured server is used again.
+        let mut client = Client::new_with_config(config());
+        client
+            .set_reconnect(true)
+            .set_max_redirects(0)
+            .set_reconnect_policy(ReconnectPolicy {
+                initial_delay: Duration::from_millis(1),
+                ..Default::default()
+            });
+        let (_, event) = tokio::join!(redirect_once(&first, other_port), client.next());
+        assert!(matches!(
+            event,
+            Some(Event::Disconnected(e)) if matches!(*e, Error::Redirect { .. })
+        ));
+        tokio::select! {
+            accepted = first.accept() => drop(accepted.unwrap()),
+            accepted = other.accept() => panic!("redirect followed: {:?}", accepted),
+            event = client.next() => panic!("unexpected event: {:?}", event),
+        }
+    }
+
+    #[cfg(feature = "test-internals")]
+    #[tokio::test(start_paused = true)]
+    async fn test_internals_keepalive() {
+        use futures::FutureExt;
+
+        let jid = FullJid::from_str("foo@bar/baz").unwrap();
+        let (stream, server) = duplex(65536);
+        let server = {
+            let jid = jid.clone();
+            async move {
+                let mut stream = authenticate(Framed::new(server, XMPPCodec::new())).await;
+                start_stream(&mut stream, features(vec![Element::bare("bind", ns::BIND)])).await;
+                bind_resource(&mut stream, jid).await;
+                stream
+            }
+        };
+        let policy = BindConflictPolicy::default();
+        let login = login(
+            stream,
+            Jid::Full(jid.clone()),
+            Secret::Password(String::from("meh")),
+            None,
+            &policy,
+            &[],
+        );
+        let (result, _server) = tokio::join!(login, server);
+        let (stream, _) = result.unwrap();
+
+        let mut client =
+            Client::new_with_config(Config::new(Jid::Full(jid), "meh", ServerConfig::UseSrv));
+        client.state = ClientState::Connected(stream);
+        let start = tokio::time::Instant::now();
+        client.set_keepalive_interval(Some(Duration::from_secs(30)));
+        assert_eq!(
+            client.timers(),
+            [Timer {
+                kind: TimerKind::Keepalive,
+                deadline: start + Duration::from_secs(30),
+            }]
+        );
+
+        // Nothing happens until the deadline.
+        tokio::time::advance(Duration::from_secs(29)).await;
+        assert!(client.next().now_or_never().is_none());
+        assert_eq!(client.timers()[0].deadline, start + Duration::from_secs(30));
+
+        // Once sent, the next keepalive is due a whole interval later.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(client.next().now_or_never().is_none());
+        assert_eq!(client.timers()[0].deadline, start + Duration::from_secs(60));
+        assert_eq!(client.queue_depths(), QueueDepths::default());
+    }
+
+    #[cfg(feature = "test-internals")]
+    #[tokio::test(start_paused = true)]
+    async fn test_internals_reconnect_backoff() {
+        use futures::FutureExt;
+
+        // Nothing listens on that port anymore, so every attempt fails right away.
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let mut client = Client::new_with_config(Config::new(
+            Jid::from_str("foo@127.0.0.1").unwrap(),
+            "meh",
+            ServerConfig::Manual {
+                host: String::from("127.0.0.1"),
+                port,
+            },
+        ));
+        client
+            .set_reconnect(true)
+            .set_reconnect_policy(ReconnectPolicy {
+                initial_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(8),
+                jitter: false,
+                max_failures: None,
+                ..Default::default()
+            });
+
+        // The first attempt isn't delayed.
+        match client.next().await {
+            Some(Event::Disconnected(_)) => (),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        // Doubling with every consecutive failure, up to the cap.
+        for delay in &[2, 4, 8, 8] {
+            assert!(client.next().now_or_never().is_none());
+            let now = tokio::time::Instant::now();
+            assert_eq!(
+                client.timers(),
+                [Timer {
+                    kind: TimerKind::Reconnect,
+                    deadline: now + Duration::from_secs(*delay),
+                }]
+            );
+            match client.next().await {
+                Some(Event::Disconnected(_)) => (),
+                event => panic!("unexpected event: {:?}", event),
             }
-            _ => Poll::Pending,
+            assert!(now.elapsed() >= Duration::from_secs(*delay));
         }
     }
+
+    /// Logs in with stream management enabled, letting the session be
+    /// resumed for `max` seconds
+    async fn login_with_sm<S: AsyncRead + AsyncWrite + Unpin>(
+        socket: S,
+        jid: FullJid,
+        max: u32,
+    ) -> Framed<S, XMPPCodec> {
+        let mut stream = authenticate(Framed::new(socket, XMPPCodec::new())).await;
+        let features = features(vec![
+            Element::bare("bind", ns::BIND),
+            Element::bare("sm", ns::SM),
+        ]);
+        start_stream(&mut stream, features).await;
+        // Binding a resource, not resuming.
+        bind_resource(&mut stream, jid).await;
+        let enable = Matcher::new("enable", ns::SM).attr("resume", "true");
+        expect(&mut stream, &enable, "stream management").await;
+        let enabled = Element::builder("enabled", ns::SM)
+            .attr("id", "some-long-sm-id")
+            .attr("max", max)
+            .attr("resume", "true")
+            .build();
+        send(&mut stream, enabled).await;
+        stream
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_resumption_expired() {
+        let jid = FullJid::from_str("foo@127.0.0.1/baz").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = {
+            let jid = jid.clone();
+            async move {
+                for _ in 0..2 {
+                    let (socket, _) = listener.accept().await.unwrap();
+                    // Then the connection drops.
+                    drop(login_with_sm(socket, jid.clone(), 60).await);
+                }
+            }
+        };
+        let mut client = Client::new_with_config(
+            Config::new(
+                Jid::Full(jid.clone()),
+                "meh",
+                ServerConfig::Loopback { port },
+            )
+            .stream_management(),
+        );
+        client.set_reconnect(true);
+
+        // The scripted server holds the codec, which isn't Send.
+        let events = async {
+            match client.next().await {
+                Some(Event::Online { resumed: false, .. }) => (),
+                event => panic!("unexpected event: {:?}", event),
+            }
+            // The <enabled/> is handled by the client itself.
+            match client.next().await {
+                Some(Event::Disconnected(e)) if matches!(*e, Error::Disconnected) => (),
+                event => panic!("unexpected event: {:?}", event),
+            }
+            assert_eq!(client.sm.window(), Some(Duration::from_secs(60)));
+
+            // Offline for twice as long as the server keeps the session, so
+            // the next attempt binds a new one right away.
+            tokio::time::advance(Duration::from_secs(120)).await;
+            match client.next().await {
+                Some(Event::ResumptionExpired {
+                    window,
+                    offline_for,
+                }) => {
+                    assert_eq!(window, Duration::from_secs(60));
+                    assert!(offline_for >= Duration::from_secs(120));
+                }
+                event => panic!("unexpected event: {:?}", event),
+            }
+            match client.next().await {
+                Some(Event::Online {
+                    bound_jid,
+                    resumed: false,
+                }) => assert_eq!(bound_jid, Jid::Full(jid)),
+                event => panic!("unexpected event: {:?}", event),
+            }
+            match client.next().await {
+                Some(Event::Disconnected(_)) => (),
+                event => panic!("unexpected event: {:?}", event),
+            }
+        };
+        tokio::join!(server, events);
+    }
+
+    #[tokio::test]
+    async fn test_sm_nonza_burst() {
+        let jid = FullJid::from_str("foo@127.0.0.1/baz").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = {
+            let jid = jid.clone();
+            async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut stream = login_with_sm(socket, jid, 60).await;
+                // Without reading any of the answers.
+                for _ in 0..10_000 {
+                    send(&mut stream, Element::bare("r", ns::SM)).await;
+                    let ack = Element::builder("a", ns::SM).attr("h", 0).build();
+                    send(&mut stream, ack).await;
+                }
+                send(
+                    &mut stream,
+                    iq("<message xmlns='jabber:client'><body>Done</body></message>"),
+                )
+                .await;
+                stream
+            }
+        };
+        let mut client = Client::new_with_config(
+            Config::new(Jid::Full(jid), "meh", ServerConfig::Loopback { port }).stream_management(),
+        );
+
+        // Every nonza is consumed by the client, in a loop rather than
+        // a frame each.
+        let events = async {
+            match client.next().await {
+                Some(Event::Online { .. }) => (),
+                event => panic!("unexpected event: {:?}", event),
+            }
+            match client.next().await {
+                Some(Event::Stanza(stanza)) => assert!(stanza.is("message", ns::JABBER_CLIENT)),
+                event => panic!("unexpected event: {:?}", event),
+            }
+        };
+        let (_stream, ()) = tokio::join!(server, events);
+    }
 }
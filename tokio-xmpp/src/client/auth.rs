@@ -1,28 +1,64 @@
 use futures::stream::StreamExt;
 use sasl::client::mechanisms::{Anonymous, Plain, Scram};
-use sasl::client::Mechanism;
+use sasl::client::{Mechanism, MechanismError};
 use sasl::common::scram::{Sha1, Sha256};
-use sasl::common::Credentials;
+use sasl::common::{ChannelBinding, Credentials, Identity, Password, Secret as SaslSecret};
 use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::str::FromStr;
 use tokio::io::{AsyncRead, AsyncWrite};
 use xmpp_parsers::sasl::{Auth, Challenge, Failure, Mechanism as XMPPMechanism, Response, Success};
+use xmpp_parsers::{BareJid, Jid};
 
+use super::credentials::Secret;
 use crate::xmpp_codec::Packet;
 use crate::xmpp_stream::XMPPStream;
 use crate::{AuthError, Error, ProtocolError};
 
+type LocalMechanisms = Vec<Box<dyn Fn() -> Box<dyn Mechanism + Send + Sync> + Send>>;
+
+/// Authenticates `jid` with the best mechanism both sides support for
+/// `secret`, and returns the stream along with the name of that
+/// mechanism
 pub async fn auth<S: AsyncRead + AsyncWrite + Unpin>(
     mut stream: XMPPStream<S>,
-    creds: Credentials,
-) -> Result<S, Error> {
-    let local_mechs: Vec<Box<dyn Fn() -> Box<dyn Mechanism + Send + Sync> + Send>> = vec![
-        Box::new(|| Box::new(Scram::<Sha256>::from_credentials(creds.clone()).unwrap())),
-        Box::new(|| Box::new(Scram::<Sha1>::from_credentials(creds.clone()).unwrap())),
-        Box::new(|| Box::new(Plain::from_credentials(creds.clone()).unwrap())),
-        Box::new(|| Box::new(Anonymous::new())),
-    ];
+    jid: &Jid,
+    secret: Secret,
+) -> Result<(S, String), Error> {
+    let local_mechs: LocalMechanisms = match secret {
+        // Without a node to authenticate as, the server is to assign one.
+        Secret::Password(_) if jid.clone().node().is_none() => {
+            vec![Box::new(|| Box::new(Anonymous::new()))]
+        }
+        Secret::Password(password) => {
+            let creds = Credentials::default()
+                .with_username(jid.clone().node().unwrap())
+                .with_password(password)
+                .with_channel_binding(ChannelBinding::None);
+            vec![
+                Box::new({
+                    let creds = creds.clone();
+                    move || Box::new(Scram::<Sha256>::from_credentials(creds.clone()).unwrap())
+                }),
+                Box::new({
+                    let creds = creds.clone();
+                    move || Box::new(Scram::<Sha1>::from_credentials(creds.clone()).unwrap())
+                }),
+                Box::new(move || Box::new(Plain::from_credentials(creds.clone()).unwrap())),
+                Box::new(|| Box::new(Anonymous::new())),
+            ]
+        }
+        // A token is no password, so it never gets offered to the other mechanisms.
+        Secret::OAuthToken(token) => {
+            let authzid = BareJid::from(jid.clone()).to_string();
+            let bearer = OAuthBearer::new(authzid.clone(), token.clone());
+            let xoauth2 = XOAuth2::new(authzid, token);
+            vec![
+                Box::new(move || Box::new(bearer.clone())),
+                Box::new(move || Box::new(xoauth2.clone())),
+            ]
+        }
+    };
 
     let remote_mechs: HashSet<String> = stream.stream_features.sasl_mechanisms()?.collect();
 
@@ -51,7 +87,7 @@ pub async fn auth<S: AsyncRead + AsyncWrite + Unpin>(
                             // Send response and loop
                             stream.send_stanza(Response { data: response }).await?;
                         } else if let Ok(_) = Success::try_from(stanza.clone()) {
-                            return Ok(stream.into_inner());
+                            return Ok((stream.into_inner(), mechanism.name().to_owned()));
                         } else if let Ok(failure) = Failure::try_from(stanza.clone()) {
                             return Err(Error::Auth(AuthError::Fail(failure.defined_condition)));
                         // TODO: This code was needed for compatibility with some broken server,
@@ -78,3 +114,126 @@ pub async fn auth<S: AsyncRead + AsyncWrite + Unpin>(
 
     Err(AuthError::NoMechanism.into())
 }
+
+/// The SASL OAUTHBEARER mechanism (RFC 7628)
+#[derive(Clone)]
+pub(crate) struct OAuthBearer {
+    authzid: String,
+    token: String,
+}
+
+impl OAuthBearer {
+    pub(crate) fn new(authzid: String, token: String) -> Self {
+        OAuthBearer { authzid, token }
+    }
+}
+
+impl Mechanism for OAuthBearer {
+    fn name(&self) -> &str {
+        "OAUTHBEARER"
+    }
+
+    fn from_credentials(credentials: Credentials) -> Result<Self, MechanismError> {
+        let token = match credentials.secret {
+            SaslSecret::Password(Password::Plain(token)) => token,
+            _ => return Err(MechanismError::PlainRequiresPlaintextPassword),
+        };
+        let authzid = match credentials.identity {
+            Identity::Username(username) => username,
+            Identity::None => String::new(),
+        };
+        Ok(OAuthBearer::new(authzid, token))
+    }
+
+    fn initial(&mut self) -> Vec<u8> {
+        // The GS2 header escapes these two, RFC 5801 §4.
+        let authzid = self.authzid.replace('=', "=3D").replace(',', "=2C");
+        let authzid = if authzid.is_empty() {
+            authzid
+        } else {
+            format!("a={}", authzid)
+        };
+        format!("n,{},\x01auth=Bearer {}\x01\x01", authzid, self.token).into_bytes()
+    }
+
+    fn response(&mut self, _challenge: &[u8]) -> Result<Vec<u8>, MechanismError> {
+        // A challenge only ever explains why the token got refused, to
+        // which a lone separator is the only valid answer, RFC 7628 §3.2.3.
+        Ok(vec![0x01])
+    }
+}
+
+/// The X-OAUTH2 mechanism, used by some servers before OAUTHBEARER
+/// existed
+#[derive(Clone)]
+pub(crate) struct XOAuth2 {
+    username: String,
+    token: String,
+}
+
+impl XOAuth2 {
+    pub(crate) fn new(username: String, token: String) -> Self {
+        XOAuth2 { username, token }
+    }
+}
+
+impl Mechanism for XOAuth2 {
+    fn name(&self) -> &str {
+        "X-OAUTH2"
+    }
+
+    fn from_credentials(credentials: Credentials) -> Result<Self, MechanismError> {
+        let token = match credentials.secret {
+            SaslSecret::Password(Password::Plain(token)) => token,
+            _ => return Err(MechanismError::PlainRequiresPlaintextPassword),
+        };
+        let username = match credentials.identity {
+            Identity::Username(username) => username,
+            Identity::None => String::new(),
+        };
+        Ok(XOAuth2::new(username, token))
+    }
+
+    fn initial(&mut self) -> Vec<u8> {
+        format!("\0{}\0{}", self.username, self.token).into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oauthbearer() {
+        let mut mechanism = OAuthBearer::new(
+            String::from("juliet@capulet.lit"),
+            String::from("vF9dft4qmT"),
+        );
+        assert_eq!(mechanism.name(), "OAUTHBEARER");
+        assert_eq!(
+            mechanism.initial(),
+            b"n,a=juliet@capulet.lit,\x01auth=Bearer vF9dft4qmT\x01\x01"
+        );
+        // A refusal gets acknowledged, for the server to send its failure.
+        let refusal = br#"{"status":"invalid_token"}"#;
+        assert_eq!(mechanism.response(refusal).unwrap(), b"\x01");
+
+        let mut escaped = OAuthBearer::new(String::from("a=b,c@d"), String::from("t"));
+        assert_eq!(
+            escaped.initial(),
+            b"n,a=a=3Db=2Cc@d,\x01auth=Bearer t\x01\x01"
+        );
+        let mut anonymous = OAuthBearer::new(String::new(), String::from("t"));
+        assert_eq!(anonymous.initial(), b"n,,\x01auth=Bearer t\x01\x01");
+    }
+
+    #[test]
+    fn test_xoauth2() {
+        let credentials = Credentials::default()
+            .with_username("juliet@capulet.lit")
+            .with_password("vF9dft4qmT");
+        let mut mechanism = XOAuth2::from_credentials(credentials).unwrap();
+        assert_eq!(mechanism.name(), "X-OAUTH2");
+        assert_eq!(mechanism.initial(), b"\0juliet@capulet.lit\0vF9dft4qmT");
+    }
+}
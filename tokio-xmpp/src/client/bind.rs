@@ -4,6 +4,7 @@ use std::marker::Unpin;
 use tokio::io::{AsyncRead, AsyncWrite};
 use xmpp_parsers::bind::{BindQuery, BindResponse};
 use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::stanza_error::DefinedCondition;
 use xmpp_parsers::Jid;
 
 use crate::xmpp_codec::Packet;
@@ -12,35 +13,64 @@ use crate::{Error, ProtocolError};
 
 const BIND_REQ_ID: &str = "resource-bind";
 
+/// What to do when the server refuses to bind the requested resource
+/// because it is already in use
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum BindConflictPolicy {
+    /// Fail with `ProtocolError::BindConflict`
+    #[default]
+    Error,
+    /// Bind again without a resource, letting the server pick one
+    ServerAssigned,
+    /// Bind again with `-2`, `-3`, … appended to the resource, giving
+    /// up after that many retries
+    RetryWithSuffix(u32),
+}
+
 pub async fn bind<S: AsyncRead + AsyncWrite + Unpin>(
     mut stream: XMPPStream<S>,
+    policy: &BindConflictPolicy,
 ) -> Result<XMPPStream<S>, Error> {
     if stream.stream_features.can_bind() {
-        let resource = if let Jid::Full(jid) = stream.jid.clone() {
+        let requested = if let Jid::Full(jid) = stream.jid.clone() {
             Some(jid.resource)
         } else {
             None
         };
-        let iq = Iq::from_set(BIND_REQ_ID, BindQuery::new(resource));
-        stream.send_stanza(iq).await?;
+        let mut resource = requested.clone();
+        let mut retries = 0;
 
         loop {
-            match stream.next().await {
-                Some(Ok(Packet::Stanza(stanza))) => match Iq::try_from(stanza) {
-                    Ok(iq) if iq.id == BIND_REQ_ID => match iq.payload {
-                        IqType::Result(payload) => {
-                            payload
-                                .and_then(|payload| BindResponse::try_from(payload).ok())
-                                .map(|bind| stream.jid = bind.into());
-                            return Ok(stream);
+            let iq = Iq::from_set(BIND_REQ_ID, BindQuery::new(resource.clone()));
+            stream.send_stanza(iq).await?;
+
+            match wait_for_response(&mut stream).await? {
+                IqType::Result(payload) => {
+                    // The whole JID is the server's to assign, node included, such as after
+                    // SASL ANONYMOUS, not only the resource.
+                    let bind = payload
+                        .and_then(|payload| BindResponse::try_from(payload).ok())
+                        .ok_or(ProtocolError::InvalidBindResponse)?;
+                    stream.jid = bind.into();
+                    return Ok(stream);
+                }
+                IqType::Error(error)
+                    if error.defined_condition == DefinedCondition::Conflict
+                        && resource.is_some() =>
+                {
+                    resource = match policy {
+                        BindConflictPolicy::ServerAssigned => None,
+                        BindConflictPolicy::RetryWithSuffix(max_retries)
+                            if retries < *max_retries =>
+                        {
+                            retries += 1;
+                            // Suffixes start at -2, the original resource being the first one.
+                            Some(format!("{}-{}", requested.as_ref().unwrap(), retries + 1))
                         }
-                        _ => return Err(ProtocolError::InvalidBindResponse.into()),
-                    },
-                    _ => {}
-                },
-                Some(Ok(_)) => {}
-                Some(Err(e)) => return Err(e),
-                None => return Err(Error::Disconnected),
+                        _ => return Err(ProtocolError::BindConflict.into()),
+                    };
+                }
+                _ => return Err(ProtocolError::InvalidBindResponse.into()),
             }
         }
     } else {
@@ -49,3 +79,165 @@ pub async fn bind<S: AsyncRead + AsyncWrite + Unpin>(
         return Ok(stream);
     }
 }
+
+async fn wait_for_response<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut XMPPStream<S>,
+) -> Result<IqType, Error> {
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) => match Iq::try_from(stanza) {
+                Ok(iq) if iq.id == BIND_REQ_ID => return Ok(iq.payload),
+                _ => {}
+            },
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e),
+            None => return Err(Error::Disconnected),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::sink::SinkExt;
+    use std::str::FromStr;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::Framed;
+    use xmpp_parsers::{ns, Element};
+
+    use crate::xmpp_codec::{StreamHeader, XMPPCodec};
+
+    fn stream_start() -> Packet {
+        Packet::StreamStart(StreamHeader {
+            ns: Some(String::from(ns::JABBER_CLIENT)),
+            ..StreamHeader::default()
+        })
+    }
+
+    /// Binds `jid` against a server which considers `taken` resources to
+    /// be in use, returns the result and every resource that was asked for.
+    async fn bind_with(
+        jid: &str,
+        taken: &'static [&'static str],
+        policy: BindConflictPolicy,
+    ) -> (Result<Jid, Error>, Vec<Option<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let mut server = Framed::new(server.unwrap().0, XMPPCodec::new());
+        let mut stream = XMPPStream::new(
+            Jid::from_str(jid).unwrap(),
+            Framed::new(client.unwrap(), XMPPCodec::new()),
+            String::from(ns::JABBER_CLIENT),
+            String::from("id"),
+            Element::builder("features", ns::STREAM)
+                .append(Element::bare("bind", ns::BIND))
+                .build(),
+        );
+        stream.send(stream_start()).await.unwrap();
+        server.send(stream_start()).await.unwrap();
+
+        let server = async move {
+            let mut requested = Vec::new();
+            while let Some(Ok(packet)) = server.next().await {
+                let iq = match packet {
+                    Packet::Stanza(stanza) => Iq::try_from(stanza).unwrap(),
+                    _ => continue,
+                };
+                let resource = match iq.payload {
+                    IqType::Set(payload) => {
+                        payload.get_child("resource", ns::BIND).map(Element::text)
+                    }
+                    _ => panic!(),
+                };
+                requested.push(resource.clone());
+                let reply: Element = match resource {
+                    Some(resource) if resource == "unbound" => format!(
+                        "<iq xmlns='jabber:client' type='result' id='{}'/>",
+                        iq.id
+                    ),
+                    Some(resource) if taken.contains(&resource.as_str()) => format!(
+                        "<iq xmlns='jabber:client' type='error' id='{}'><error type='cancel'><conflict xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/></error></iq>",
+                        iq.id
+                    ),
+                    resource => format!(
+                        "<iq xmlns='jabber:client' type='result' id='{}'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'><jid>foo@bar/{}</jid></bind></iq>",
+                        iq.id,
+                        resource.unwrap_or_else(|| String::from("assigned"))
+                    ),
+                }
+                .parse()
+                .unwrap();
+                server.send(Packet::Stanza(reply)).await.unwrap();
+            }
+            requested
+        };
+        let client = async { bind(stream, &policy).await.map(|stream| stream.jid) };
+
+        tokio::join!(client, server)
+    }
+
+    #[tokio::test]
+    async fn test_no_conflict() {
+        let (result, requested) = bind_with("foo@bar/phone", &[], Default::default()).await;
+        assert_eq!(result.unwrap(), Jid::from_str("foo@bar/phone").unwrap());
+        assert_eq!(requested, [Some(String::from("phone"))]);
+    }
+
+    #[tokio::test]
+    async fn test_server_assigned_jid() {
+        // Without a node to log in with, such as with SASL ANONYMOUS, the server assigns one.
+        let (result, requested) = bind_with("bar", &[], Default::default()).await;
+        assert_eq!(result.unwrap(), Jid::from_str("foo@bar/assigned").unwrap());
+        assert_eq!(requested, [None]);
+
+        // A result is useless without the JID bound.
+        let (result, _) = bind_with("foo@bar/unbound", &[], Default::default()).await;
+        match result {
+            Err(Error::Protocol(ProtocolError::InvalidBindResponse)) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conflict_error() {
+        let (result, requested) = bind_with("foo@bar/phone", &["phone"], Default::default()).await;
+        match result {
+            Err(Error::Protocol(ProtocolError::BindConflict)) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(requested.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_conflict_server_assigned() {
+        let policy = BindConflictPolicy::ServerAssigned;
+        let (result, requested) = bind_with("foo@bar/phone", &["phone"], policy).await;
+        assert_eq!(result.unwrap(), Jid::from_str("foo@bar/assigned").unwrap());
+        assert_eq!(requested, [Some(String::from("phone")), None]);
+    }
+
+    #[tokio::test]
+    async fn test_conflict_retry_with_suffix() {
+        let policy = BindConflictPolicy::RetryWithSuffix(2);
+        let (result, requested) = bind_with("foo@bar/phone", &["phone", "phone-2"], policy).await;
+        assert_eq!(result.unwrap(), Jid::from_str("foo@bar/phone-3").unwrap());
+        assert_eq!(
+            requested,
+            [
+                Some(String::from("phone")),
+                Some(String::from("phone-2")),
+                Some(String::from("phone-3")),
+            ]
+        );
+
+        // Giving up once all retries are exhausted.
+        let policy = BindConflictPolicy::RetryWithSuffix(1);
+        let (result, requested) = bind_with("foo@bar/phone", &["phone", "phone-2"], policy).await;
+        match result {
+            Err(Error::Protocol(ProtocolError::BindConflict)) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(requested.len(), 2);
+    }
+}
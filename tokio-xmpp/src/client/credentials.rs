@@ -0,0 +1,41 @@
+use futures::Future;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::Error;
+
+/// What to authenticate with
+#[derive(Clone, PartialEq)]
+pub enum Secret {
+    /// The password of the account, used with SCRAM or PLAIN
+    Password(String),
+    /// An OAuth 2.0 access token, used with OAUTHBEARER or X-OAUTH2
+    OAuthToken(String),
+}
+
+impl fmt::Debug for Secret {
+    // Secrets don't belong in logs.
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Secret::Password(_) => write!(fmt, "Password(…)"),
+            Secret::OAuthToken(_) => write!(fmt, "OAuthToken(…)"),
+        }
+    }
+}
+
+/// Called before every connection attempt, reconnections included,
+/// to get the current secret, for instance a freshly refreshed token
+///
+/// An error fails that attempt like a connection error would.
+pub type CredentialsProvider =
+    Arc<dyn Fn() -> Pin<Box<dyn Future<Output = Result<Secret, Error>>>> + Send + Sync>;
+
+/// Wrap `provider` into a [`CredentialsProvider`]
+pub fn credentials_provider<F, Fut>(provider: F) -> CredentialsProvider
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Secret, Error>> + 'static,
+{
+    Arc::new(move || Box::pin(provider()))
+}
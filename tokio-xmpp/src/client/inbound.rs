@@ -0,0 +1,172 @@
+use std::time::{Duration, Instant};
+
+/// How a client paces the stanzas it reads, so that a flood of them
+/// doesn't starve the rest of the application
+///
+/// Stanzas which aren't read yet stay in the socket, so a server
+/// sending faster than the limit gets slowed down by TCP flow control.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InboundPolicy {
+    /// Let other tasks run after yielding that many stanzas in a row
+    /// without waiting for the network, or never if `None`
+    pub budget: Option<u32>,
+    /// Read at most that many stanzas per second on average, or as
+    /// fast as they come if `None`
+    pub stanzas_per_second: Option<u32>,
+    /// How many stanzas can be read at once after being idle, when
+    /// `stanzas_per_second` is set
+    pub burst: u32,
+}
+
+impl Default for InboundPolicy {
+    fn default() -> Self {
+        InboundPolicy {
+            budget: Some(64),
+            stanzas_per_second: None,
+            burst: 100,
+        }
+    }
+}
+
+/// Whether the next stanza can be read
+#[derive(Debug, PartialEq)]
+pub(crate) enum Admit {
+    Now,
+    /// Wake up and come back, to let other tasks run first
+    Yield,
+    After(Duration),
+}
+
+/// Applies an `InboundPolicy`
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    pub(crate) policy: InboundPolicy,
+    /// Stanzas yielded since the stream was last pending
+    streak: u32,
+    /// Stanzas which can be read right away, refilled over time
+    tokens: f64,
+    last: Option<Instant>,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Throttle::new(InboundPolicy::default())
+    }
+}
+
+impl Throttle {
+    pub(crate) fn new(policy: InboundPolicy) -> Self {
+        Throttle {
+            tokens: f64::from(policy.burst.max(1)),
+            policy,
+            streak: 0,
+            last: None,
+        }
+    }
+
+    /// Whether to read the next stanza now, given the current time
+    pub(crate) fn admit(&mut self, now: Instant) -> Admit {
+        if let Some(budget) = self.policy.budget {
+            if self.streak >= budget.max(1) {
+                self.streak = 0;
+                return Admit::Yield;
+            }
+        }
+        let rate = match self.policy.stanzas_per_second {
+            Some(rate) => f64::from(rate.max(1)),
+            None => return Admit::Now,
+        };
+        if let Some(last) = self.last {
+            let elapsed = now.saturating_duration_since(last);
+            let burst = f64::from(self.policy.burst.max(1));
+            self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(burst);
+        }
+        self.last = Some(now);
+        if self.tokens >= 1. {
+            Admit::Now
+        } else {
+            Admit::After(Duration::from_secs_f64((1. - self.tokens) / rate))
+        }
+    }
+
+    /// A stanza got read
+    pub(crate) fn received(&mut self) {
+        self.streak = self.streak.saturating_add(1);
+        if self.policy.stanzas_per_second.is_some() {
+            self.tokens -= 1.;
+        }
+    }
+
+    /// The stream has nothing more to read for now
+    pub(crate) fn idle(&mut self) {
+        self.streak = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let now = Instant::now();
+        let mut throttle = Throttle::new(InboundPolicy {
+            budget: Some(3),
+            ..InboundPolicy::default()
+        });
+        for _ in 0..3 {
+            assert_eq!(throttle.admit(now), Admit::Now);
+            throttle.received();
+        }
+        assert_eq!(throttle.admit(now), Admit::Yield);
+        // Once other tasks had their turn, a new streak starts.
+        assert_eq!(throttle.admit(now), Admit::Now);
+
+        // Waiting for the network also ends the streak.
+        throttle.received();
+        throttle.received();
+        throttle.idle();
+        throttle.received();
+        throttle.received();
+        assert_eq!(throttle.admit(now), Admit::Now);
+    }
+
+    #[test]
+    fn test_rate() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(InboundPolicy {
+            budget: None,
+            stanzas_per_second: Some(10),
+            burst: 2,
+        });
+
+        // The burst goes through right away.
+        for _ in 0..2 {
+            assert_eq!(throttle.admit(start), Admit::Now);
+            throttle.received();
+        }
+        assert_eq!(
+            throttle.admit(start),
+            Admit::After(Duration::from_millis(100))
+        );
+        let later = start + Duration::from_millis(50);
+        assert_eq!(
+            throttle.admit(later),
+            Admit::After(Duration::from_millis(50))
+        );
+        let later = start + Duration::from_millis(100);
+        assert_eq!(throttle.admit(later), Admit::Now);
+        throttle.received();
+
+        // Being idle for a long time doesn't allow more than the burst.
+        let later = start + Duration::from_secs(3600);
+        for _ in 0..2 {
+            assert_eq!(throttle.admit(later), Admit::Now);
+            throttle.received();
+        }
+        assert_eq!(
+            throttle.admit(later),
+            Admit::After(Duration::from_millis(100))
+        );
+    }
+}
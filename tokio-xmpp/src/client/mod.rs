@@ -1,5 +1,20 @@
 mod auth;
 mod bind;
+pub use bind::BindConflictPolicy;
+mod credentials;
+pub use credentials::{credentials_provider, CredentialsProvider, Secret};
+mod inbound;
+pub use inbound::InboundPolicy;
+mod ping;
+pub use ping::PingPolicy;
+mod reconnect;
+pub use reconnect::ReconnectPolicy;
+mod sm;
+mod stream_feature;
+pub use stream_feature::{
+    stream_feature_handler, AsyncReadAndWrite, BoxedStream, Negotiated, StreamFeatureHandler,
+    StreamFeatureHook,
+};
 
 pub mod async_client;
 pub mod simple_client;
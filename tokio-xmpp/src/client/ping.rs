@@ -0,0 +1,185 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+use xmpp_parsers::{iq::Iq, ns, ping::Ping, BareJid, Element, Jid};
+
+/// How a client pings its server (XEP-0199) to find out whether the
+/// connection still works
+#[derive(Debug, Clone, PartialEq)]
+pub struct PingPolicy {
+    /// Ping once nothing got received for that long
+    pub interval: Duration,
+    /// Give up on the connection if a ping isn't answered within that
+    /// long, with `Error::KeepaliveTimeout`
+    pub timeout: Duration,
+}
+
+impl Default for PingPolicy {
+    fn default() -> Self {
+        PingPolicy {
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Applies a `PingPolicy` to the current connection
+#[derive(Debug, Default)]
+pub(crate) struct Pinger {
+    policy: Option<PingPolicy>,
+    /// When to ping if nothing gets received until then, unset until
+    /// the connection is first polled
+    next_ping: Option<Instant>,
+    /// The pings not answered yet, oldest first, with when they time
+    /// out
+    outstanding: VecDeque<(String, Instant)>,
+    /// Who got pinged, and so answers
+    server: Option<String>,
+    /// For the id of the next ping
+    count: u64,
+}
+
+impl Pinger {
+    pub(crate) fn set_policy(&mut self, policy: Option<PingPolicy>) {
+        self.policy = policy;
+        self.next_ping = None;
+        self.outstanding.clear();
+    }
+
+    /// Forget about the previous connection
+    pub(crate) fn reset(&mut self) {
+        self.next_ping = None;
+        self.outstanding.clear();
+    }
+
+    /// Whether a ping went unanswered for too long
+    pub(crate) fn timed_out(&self, now: Instant) -> bool {
+        match self.outstanding.front() {
+            Some((_, deadline)) => *deadline <= now,
+            None => false,
+        }
+    }
+
+    /// Whether it is time to ping
+    pub(crate) fn due(&mut self, now: Instant) -> bool {
+        let interval = match self.policy {
+            Some(ref policy) => policy.interval,
+            None => return false,
+        };
+        *self.next_ping.get_or_insert(now + interval) <= now
+    }
+
+    /// The ping to send to `server` now
+    pub(crate) fn ping(&mut self, server: BareJid, now: Instant) -> Element {
+        let policy = self.policy.clone().unwrap_or_default();
+        self.count += 1;
+        let id = format!("keepalive-{}", self.count);
+        self.outstanding
+            .push_back((id.clone(), now + policy.timeout));
+        self.next_ping = Some(now + policy.interval);
+        self.server = Some(String::from(server.clone()));
+        Iq::from_get(id, Ping).with_to(Jid::Bare(server)).into()
+    }
+
+    /// Something got received, which postpones the next ping, and
+    /// returns whether it answers one of ours
+    pub(crate) fn received(&mut self, stanza: &Element, now: Instant) -> bool {
+        if let Some(ref policy) = self.policy {
+            self.next_ping = Some(now + policy.interval);
+        }
+        if !stanza.is("iq", ns::JABBER_CLIENT) {
+            return false;
+        }
+        match stanza.attr("type") {
+            // The server may not support pings, but it answered.
+            Some("result") | Some("error") => (),
+            _ => return false,
+        }
+        if let Some(from) = stanza.attr("from") {
+            if Some(from) != self.server.as_deref() {
+                return false;
+            }
+        }
+        let id = stanza.attr("id");
+        match self
+            .outstanding
+            .iter()
+            .position(|(ping, _)| Some(ping.as_str()) == id)
+        {
+            Some(index) => {
+                self.outstanding.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// When something will be due next, to be woken up
+    pub(crate) fn wake_at(&self) -> Option<Instant> {
+        let timeout = self.outstanding.front().map(|(_, deadline)| *deadline);
+        match (self.next_ping, timeout) {
+            (Some(ping), Some(timeout)) => Some(ping.min(timeout)),
+            (ping, timeout) => ping.or(timeout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn pong(id: &str, from: &str) -> Element {
+        Element::builder("iq", ns::JABBER_CLIENT)
+            .attr("type", "result")
+            .attr("id", id)
+            .attr("from", from)
+            .build()
+    }
+
+    #[test]
+    fn test_pinger() {
+        let server = BareJid::from_str("bar").unwrap();
+        let second = Duration::from_secs(1);
+        let start = Instant::now();
+        let mut pinger = Pinger::default();
+        assert!(!pinger.due(start + 1000 * second));
+        assert_eq!(pinger.wake_at(), None);
+
+        pinger.set_policy(Some(PingPolicy {
+            interval: 10 * second,
+            timeout: 5 * second,
+        }));
+        assert!(!pinger.due(start));
+        assert_eq!(pinger.wake_at(), Some(start + 10 * second));
+
+        // Receiving anything postpones the ping.
+        let message = Element::bare("message", ns::JABBER_CLIENT);
+        assert!(!pinger.received(&message, start + 8 * second));
+        assert!(!pinger.due(start + 10 * second));
+        assert!(pinger.due(start + 18 * second));
+
+        let ping = pinger.ping(server.clone(), start + 18 * second);
+        assert_eq!(ping.attr("id"), Some("keepalive-1"));
+        assert_eq!(ping.attr("to"), Some("bar"));
+        assert!(ping.has_child("ping", ns::PING));
+        assert_eq!(pinger.wake_at(), Some(start + 23 * second));
+
+        // Only the answer counts, not anything else received.
+        assert!(!pinger.received(&pong("other", "bar"), start + 20 * second));
+        assert!(!pinger.received(&pong("keepalive-1", "baz@bar"), start + 20 * second));
+        assert!(pinger.received(&pong("keepalive-1", "bar"), start + 21 * second));
+        assert!(!pinger.timed_out(start + 25 * second));
+        assert_eq!(pinger.wake_at(), Some(start + 31 * second));
+
+        pinger.ping(server, start + 31 * second);
+        assert!(!pinger.timed_out(start + 35 * second));
+        assert!(pinger.timed_out(start + 36 * second));
+
+        // A new connection starts over.
+        pinger.reset();
+        assert!(!pinger.timed_out(start + 36 * second));
+        assert!(!pinger.due(start + 36 * second));
+        assert_eq!(pinger.wake_at(), Some(start + 46 * second));
+    }
+}
@@ -0,0 +1,234 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How a client spaces out its reconnection attempts
+///
+/// The delay before each attempt doubles with every consecutive
+/// failure, up to `max_delay`. With `jitter`, the actual delay is
+/// picked uniformly between zero and that value, so that many clients
+/// disconnected at once don't all come back at the same instant.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Upper bound of the first delay
+    pub initial_delay: Duration,
+    /// Upper bound of every delay
+    pub max_delay: Duration,
+    /// Randomize each delay between zero and its upper bound
+    pub jitter: bool,
+    /// Suspend reconnecting after that many consecutive failed
+    /// attempts, or never if `None`
+    pub max_failures: Option<u32>,
+    /// How long to stay suspended before trying again
+    pub suspension: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            jitter: true,
+            max_failures: Some(10),
+            suspension: Duration::from_secs(600),
+        }
+    }
+}
+
+/// When to attempt the next connection
+#[derive(Debug, PartialEq)]
+pub(crate) enum Retry {
+    After(Duration),
+    SuspendedUntil(Instant),
+}
+
+/// Applies a `ReconnectPolicy`, counting consecutive failures
+pub(crate) struct Backoff {
+    pub(crate) policy: ReconnectPolicy,
+    failures: u32,
+    /// Returns values in `[0, 1)`
+    random: Box<dyn FnMut() -> f64 + Send>,
+}
+
+impl fmt::Debug for Backoff {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Backoff")
+            .field("policy", &self.policy)
+            .field("failures", &self.failures)
+            .finish()
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(ReconnectPolicy::default(), rand::random::<f64>)
+    }
+}
+
+impl Backoff {
+    pub(crate) fn new<R: FnMut() -> f64 + Send + 'static>(
+        policy: ReconnectPolicy,
+        random: R,
+    ) -> Self {
+        Backoff {
+            policy,
+            failures: 0,
+            random: Box::new(random),
+        }
+    }
+
+    pub(crate) fn set_random<R: FnMut() -> f64 + Send + 'static>(&mut self, random: R) {
+        self.random = Box::new(random);
+    }
+
+    /// A connection attempt failed
+    pub(crate) fn failed(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    /// A connection attempt succeeded, or the caller wants a fresh start
+    pub(crate) fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// When to make the next attempt, given the current time
+    ///
+    /// Once tripped, the breaker lets a single attempt through after
+    /// the suspension, and trips again if that one fails too.
+    pub(crate) fn next(&mut self, now: Instant) -> Retry {
+        if let Some(max_failures) = self.policy.max_failures {
+            if self.failures >= max_failures.max(1) {
+                self.failures = max_failures.max(1) - 1;
+                return Retry::SuspendedUntil(now + self.policy.suspension);
+            }
+        }
+        let factor = 1u32.checked_shl(self.failures).unwrap_or(u32::MAX);
+        let delay = self
+            .policy
+            .initial_delay
+            .checked_mul(factor)
+            .unwrap_or(self.policy.max_delay)
+            .min(self.policy.max_delay);
+        if self.policy.jitter {
+            let random = (self.random)().clamp(0., 1.);
+            Retry::After(delay.mul_f64(random))
+        } else {
+            Retry::After(delay)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    fn policy() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            jitter: true,
+            max_failures: Some(3),
+            suspension: Duration::from_secs(300),
+        }
+    }
+
+    /// A random source returning whatever was last stored in the
+    /// returned handle
+    fn fixed_random() -> (Arc<AtomicU64>, impl FnMut() -> f64 + Send + 'static) {
+        let value = Arc::new(AtomicU64::new(0));
+        let handle = value.clone();
+        (handle, move || f64::from_bits(value.load(Ordering::SeqCst)))
+    }
+
+    #[test]
+    fn test_jitter_bounds() {
+        let now = Instant::now();
+        let (random, source) = fixed_random();
+        let mut backoff = Backoff::new(policy(), source);
+        backoff.failed();
+        backoff.failed();
+
+        // Two failures, so anywhere between zero and four seconds.
+        random.store(0f64.to_bits(), Ordering::SeqCst);
+        assert_eq!(backoff.next(now), Retry::After(Duration::from_secs(0)));
+        random.store(0.5f64.to_bits(), Ordering::SeqCst);
+        assert_eq!(backoff.next(now), Retry::After(Duration::from_secs(2)));
+        random.store(0.999f64.to_bits(), Ordering::SeqCst);
+        match backoff.next(now) {
+            Retry::After(delay) => {
+                assert!(delay < Duration::from_secs(4));
+                assert!(delay > Duration::from_millis(3990));
+            }
+            retry => panic!("unexpected retry: {:?}", retry),
+        }
+
+        // Out of range values don't escape the bounds either.
+        random.store(42f64.to_bits(), Ordering::SeqCst);
+        assert_eq!(backoff.next(now), Retry::After(Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn test_cap() {
+        let now = Instant::now();
+        let mut backoff = Backoff::new(
+            ReconnectPolicy {
+                jitter: false,
+                max_failures: None,
+                ..policy()
+            },
+            || panic!("no jitter was asked for"),
+        );
+        let mut delays = Vec::new();
+        for _ in 0..6 {
+            match backoff.next(now) {
+                Retry::After(delay) => delays.push(delay.as_secs()),
+                retry => panic!("unexpected retry: {:?}", retry),
+            }
+            backoff.failed();
+        }
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+
+        // Way past the point where the delay would overflow.
+        for _ in 0..100 {
+            backoff.failed();
+        }
+        assert_eq!(backoff.next(now), Retry::After(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_breaker() {
+        let now = Instant::now();
+        let mut backoff = Backoff::new(
+            ReconnectPolicy {
+                jitter: false,
+                ..policy()
+            },
+            || 0.,
+        );
+        for _ in 0..3 {
+            assert!(matches!(backoff.next(now), Retry::After(_)));
+            backoff.failed();
+        }
+
+        // Tripped after three failures in a row.
+        let later = now + Duration::from_secs(5);
+        assert_eq!(
+            backoff.next(later),
+            Retry::SuspendedUntil(later + Duration::from_secs(300))
+        );
+
+        // The client makes a single attempt once suspended, the breaker
+        // trips again if it fails.
+        let later = later + Duration::from_secs(300);
+        backoff.failed();
+        assert_eq!(
+            backoff.next(later),
+            Retry::SuspendedUntil(later + Duration::from_secs(300))
+        );
+
+        // A success closes it again.
+        backoff.reset();
+        assert_eq!(backoff.next(later), Retry::After(Duration::from_secs(1)));
+    }
+}
@@ -1,6 +1,4 @@
 use futures::{sink::SinkExt, Sink, Stream};
-use idna;
-use sasl::common::{ChannelBinding, Credentials};
 use std::pin::Pin;
 use std::str::FromStr;
 use std::task::{Context, Poll};
@@ -14,6 +12,7 @@ use xmpp_parsers::{ns, Element, Jid};
 
 use super::auth::auth;
 use super::bind::bind;
+use super::credentials::Secret;
 use crate::happy_eyeballs::connect_with_srv;
 use crate::starttls::starttls;
 use crate::xmpp_codec::Packet;
@@ -26,6 +25,7 @@ use crate::{Error, ProtocolError};
 /// [`Sink`](#impl-Sink<Packet>) traits.
 pub struct Client {
     stream: XMPPStream,
+    sasl_mechanism: String,
 }
 
 type XMPPStream = xmpp_stream::XMPPStream<TlsStream<TcpStream>>;
@@ -40,8 +40,11 @@ impl Client {
 
     /// Start a new client given that the JID is already parsed.
     pub async fn new_with_jid(jid: Jid, password: String) -> Result<Self, Error> {
-        let stream = Self::connect(jid.clone(), password.clone()).await?;
-        Ok(Client { stream })
+        let (stream, sasl_mechanism) = Self::connect(jid.clone(), password.clone()).await?;
+        Ok(Client {
+            stream,
+            sasl_mechanism,
+        })
     }
 
     /// Get direct access to inner XMPP Stream
@@ -49,13 +52,14 @@ impl Client {
         self.stream
     }
 
-    async fn connect(jid: Jid, password: String) -> Result<XMPPStream, Error> {
-        let username = jid.clone().node().unwrap();
-        let password = password;
-        let domain = idna::domain_to_ascii(&jid.clone().domain()).map_err(|_| Error::Idna)?;
+    async fn connect(jid: Jid, password: String) -> Result<(XMPPStream, String), Error> {
+        let domain = jid
+            .to_ascii_domain_form()
+            .map_err(|_| Error::Idna)?
+            .domain();
 
         // TCP connection
-        let tcp_stream = connect_with_srv(&domain, "_xmpp-client._tcp", 5222).await?;
+        let tcp_stream = connect_with_srv(&domain, "_xmpp-client._tcp", 5222, None).await?;
 
         // Unencryped XMPPStream
         let xmpp_stream =
@@ -72,19 +76,15 @@ impl Client {
             return Err(Error::Protocol(ProtocolError::NoTls));
         };
 
-        let creds = Credentials::default()
-            .with_username(username)
-            .with_password(password)
-            .with_channel_binding(ChannelBinding::None);
         // Authenticated (unspecified) stream
-        let stream = auth(xmpp_stream, creds).await?;
+        let (stream, sasl_mechanism) = auth(xmpp_stream, &jid, Secret::Password(password)).await?;
         // Authenticated XMPPStream
         let xmpp_stream =
             xmpp_stream::XMPPStream::start(stream, jid, ns::JABBER_CLIENT.to_owned()).await?;
 
         // XMPPStream bound to user session
-        let xmpp_stream = bind(xmpp_stream).await?;
-        Ok(xmpp_stream)
+        let xmpp_stream = bind(xmpp_stream, &Default::default()).await?;
+        Ok((xmpp_stream, sasl_mechanism))
     }
 
     /// Get the client's bound JID (the one reported by the XMPP
@@ -93,6 +93,12 @@ impl Client {
         &self.stream.jid
     }
 
+    /// Get the name of the SASL mechanism we authenticated with, such
+    /// as `SCRAM-SHA-256` or `PLAIN`
+    pub fn sasl_mechanism(&self) -> &str {
+        &self.sasl_mechanism
+    }
+
     /// Send stanza
     pub async fn send_stanza<E>(&mut self, stanza: E) -> Result<(), Error>
     where
@@ -0,0 +1,343 @@
+use futures::{sink::SinkExt, stream::StreamExt};
+use std::convert::TryFrom;
+use std::marker::Unpin;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::Instant;
+use xmpp_parsers::sm::{Enable, Enabled, Resume, ResumeAttr, Resumed, StreamId, A};
+use xmpp_parsers::{ns, Element, Jid};
+
+use crate::event::Event;
+use crate::xmpp_codec::Packet;
+use crate::xmpp_stream::XMPPStream;
+use crate::{Error, ProtocolError};
+
+/// Stream management (XEP-0198) on the connections of a client: the
+/// stanzas it handled, and the session it may resume after a
+/// disconnection
+#[derive(Debug, Default)]
+pub(crate) struct StreamManagement {
+    /// Whether the server counts the stanzas of the current connection
+    enabled: bool,
+    /// Stanzas received since stream management got enabled
+    handled: u32,
+    /// The session the server keeps for a while after a disconnection
+    session: Option<Session>,
+    /// When we noticed the last connection had ended
+    disconnected_at: Option<Instant>,
+}
+
+/// A session which the server lets us resume
+#[derive(Debug, Clone)]
+pub(crate) struct Session {
+    id: StreamId,
+    /// Where the server prefers us to reconnect to resume it
+    location: Option<String>,
+    /// How long the server keeps it after a disconnection, if it told
+    max: Option<Duration>,
+    /// The JID bound to it, which stays the same once resumed
+    jid: Jid,
+}
+
+/// What to do about stream management when logging in
+#[derive(Debug)]
+pub(crate) enum Plan {
+    /// Nothing
+    Off,
+    /// Enable it once bound, if the server supports it
+    Enable,
+    /// Resume this session instead of binding a new one, and enable
+    /// it again if the server doesn't let us
+    Resume(Session, u32),
+}
+
+/// What became of stream management when logging in
+#[derive(Debug)]
+pub(crate) enum Outcome {
+    /// The server doesn't support it, or we didn't ask
+    Off,
+    /// Asked for with `<enable/>`, the server answering soon
+    Enabling,
+    /// The previous session goes on
+    Resumed,
+}
+
+/// What to do with an element received while connected
+pub(crate) enum Received {
+    /// Pass it on to the application
+    Stanza(Element),
+    /// Nothing, stream management took care of it
+    Handled,
+    /// Send this answer to a request of the server
+    Answer(Element),
+}
+
+impl StreamManagement {
+    /// What to do when logging in after `delay`, given whether to use
+    /// stream management at all
+    ///
+    /// A session whose window will be over by then isn't worth the
+    /// round trip to get refused, the event returned then tells the
+    /// application that it is gone along with the stanzas in flight.
+    pub(crate) fn plan(&mut self, enable: bool, delay: Duration) -> (Plan, Option<Event>) {
+        if !enable {
+            return (Plan::Off, None);
+        }
+        let session = match self.session.take() {
+            Some(session) => session,
+            None => return (Plan::Enable, None),
+        };
+        if let (Some(window), Some(disconnected_at)) = (session.max, self.disconnected_at) {
+            let offline_for = (Instant::now() + delay).saturating_duration_since(disconnected_at);
+            if offline_for > window {
+                let event = Event::ResumptionExpired {
+                    window,
+                    offline_for,
+                };
+                return (Plan::Enable, Some(event));
+            }
+        }
+        let plan = Plan::Resume(session.clone(), self.handled);
+        // Kept until we know whether the server let us resume it
+        self.session = Some(session);
+        (plan, None)
+    }
+
+    /// Takes what happened on a new connection into account
+    pub(crate) fn logged_in(&mut self, outcome: &Outcome) {
+        match outcome {
+            Outcome::Resumed => self.enabled = true,
+            Outcome::Off | Outcome::Enabling => {
+                // Counting starts over once the server enabled it.
+                self.enabled = false;
+                self.handled = 0;
+                self.session = None;
+            }
+        }
+        self.disconnected_at = None;
+    }
+
+    /// Takes note of when the connection ended
+    pub(crate) fn disconnected(&mut self) {
+        self.enabled = false;
+        self.disconnected_at = Some(Instant::now());
+    }
+
+    /// Counts the stanzas received, and handles the nonzas of stream
+    /// management
+    pub(crate) fn received(&mut self, element: Element, jid: &Jid) -> Received {
+        if element.ns() != ns::SM {
+            if self.enabled && is_stanza(&element) {
+                self.handled = self.handled.wrapping_add(1);
+            }
+            return Received::Stanza(element);
+        }
+        if element.is("enabled", ns::SM) {
+            if let Ok(enabled) = Enabled::try_from(element) {
+                let Enabled {
+                    id,
+                    location,
+                    max,
+                    resume,
+                } = enabled;
+                self.enabled = true;
+                self.session = id.filter(|_| resume == ResumeAttr::True).map(|id| Session {
+                    id,
+                    location,
+                    max: max.map(|max| Duration::from_secs(max.into())),
+                    jid: jid.clone(),
+                });
+            }
+            Received::Handled
+        } else if element.is("r", ns::SM) && self.enabled {
+            Received::Answer(A::new(self.handled).into())
+        } else {
+            // Our own requests for acks aren't sent, and neither is
+            // anything else expecting an answer.
+            Received::Handled
+        }
+    }
+
+    /// How long the server keeps the session once disconnected, as it
+    /// told when enabling stream management
+    pub(crate) fn window(&self) -> Option<Duration> {
+        self.session.as_ref().and_then(|session| session.max)
+    }
+
+    /// Where the server prefers us to reconnect to resume the session
+    pub(crate) fn location(&self) -> Option<&str> {
+        self.session
+            .as_ref()
+            .and_then(|session| session.location.as_deref())
+    }
+}
+
+impl Session {
+    /// Where to reconnect to resume it, as `(host, port)`
+    pub(crate) fn location(&self) -> Option<(String, u16)> {
+        self.location.as_deref().and_then(parse_location)
+    }
+}
+
+fn is_stanza(element: &Element) -> bool {
+    element.ns() == ns::JABBER_CLIENT
+        && (element.name() == "message" || element.name() == "presence" || element.name() == "iq")
+}
+
+/// Parses `host`, `host:port` or `[ipv6]:port`, the port being 5222
+/// unless told otherwise
+fn parse_location(location: &str) -> Option<(String, u16)> {
+    let (host, port) = if let Some(ipv6) = location.strip_prefix('[') {
+        let end = ipv6.find(']')?;
+        (&ipv6[..end], ipv6[end + 1..].strip_prefix(':'))
+    } else if location.matches(':').count() > 1 {
+        // A bare IPv6 address
+        (location, None)
+    } else {
+        match location.rfind(':') {
+            Some(i) => (&location[..i], Some(&location[i + 1..])),
+            None => (location, None),
+        }
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => 5222,
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_owned(), port))
+}
+
+/// Resumes `session` on a freshly authenticated stream, returning
+/// whether the server let us
+pub(crate) async fn resume<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut XMPPStream<S>,
+    session: &Session,
+    handled: u32,
+) -> Result<bool, Error> {
+    let resume = Resume {
+        h: handled,
+        previd: session.id.clone(),
+    };
+    stream.send(Packet::Stanza(resume.into())).await?;
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) if stanza.is("resumed", ns::SM) => {
+                Resumed::try_from(stanza).map_err(ProtocolError::from)?;
+                stream.jid = session.jid.clone();
+                return Ok(true);
+            }
+            Some(Ok(Packet::Stanza(stanza))) if stanza.is("failed", ns::SM) => return Ok(false),
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e),
+            None => return Err(Error::Disconnected),
+        }
+    }
+}
+
+/// Asks the server to enable stream management, and to let us resume
+/// the session, the answer being handled with the stanzas
+pub(crate) async fn enable<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut XMPPStream<S>,
+) -> Result<(), Error> {
+    let enable = Enable::new().with_resume();
+    stream.send(Packet::Stanza(enable.into())).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn enabled(xml: &str) -> StreamManagement {
+        let mut sm = StreamManagement::default();
+        let jid = Jid::from_str("foo@bar/baz").unwrap();
+        sm.logged_in(&Outcome::Enabling);
+        assert!(matches!(
+            sm.received(xml.parse().unwrap(), &jid),
+            Received::Handled
+        ));
+        sm
+    }
+
+    #[test]
+    fn test_location() {
+        assert_eq!(
+            parse_location("xmpp.example.org:5223"),
+            Some((String::from("xmpp.example.org"), 5223))
+        );
+        assert_eq!(
+            parse_location("192.0.2.1"),
+            Some((String::from("192.0.2.1"), 5222))
+        );
+        assert_eq!(
+            parse_location("[2001:db8::1]:5223"),
+            Some((String::from("2001:db8::1"), 5223))
+        );
+        assert_eq!(
+            parse_location("2001:db8::1"),
+            Some((String::from("2001:db8::1"), 5222))
+        );
+        assert_eq!(parse_location(":5222"), None);
+        assert_eq!(parse_location("xmpp.example.org:http"), None);
+    }
+
+    #[test]
+    fn test_enabled() {
+        let sm = enabled(
+            "<enabled xmlns='urn:xmpp:sm:3' id='some-long-sm-id' location='[2001:db8::1]:5222' max='600' resume='true'/>",
+        );
+        assert_eq!(sm.window(), Some(Duration::from_secs(600)));
+        assert_eq!(sm.location(), Some("[2001:db8::1]:5222"));
+
+        // Without resumption, there is no session to keep.
+        let sm = enabled("<enabled xmlns='urn:xmpp:sm:3' id='some-long-sm-id' max='600'/>");
+        assert!(sm.enabled);
+        assert_eq!(sm.window(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_plan() {
+        let mut sm =
+            enabled("<enabled xmlns='urn:xmpp:sm:3' id='some-long-sm-id' max='60' resume='true'/>");
+        let jid = Jid::from_str("foo@bar/baz").unwrap();
+        let message: Element = "<message xmlns='jabber:client'/>".parse().unwrap();
+        assert!(matches!(sm.received(message, &jid), Received::Stanza(_)));
+        match sm.received("<r xmlns='urn:xmpp:sm:3'/>".parse().unwrap(), &jid) {
+            Received::Answer(answer) => assert_eq!(answer, A::new(1).into()),
+            _ => panic!("unanswered request"),
+        }
+        sm.disconnected();
+
+        // Still within the window when the attempt starts.
+        tokio::time::advance(Duration::from_secs(30)).await;
+        match sm.plan(true, Duration::from_secs(29)) {
+            (Plan::Resume(session, 1), None) => assert_eq!(session.jid, jid),
+            plan => panic!("unexpected plan: {:?}", plan),
+        }
+        // Over by then.
+        match sm.plan(true, Duration::from_secs(31)) {
+            (
+                Plan::Enable,
+                Some(Event::ResumptionExpired {
+                    window,
+                    offline_for,
+                }),
+            ) => {
+                assert_eq!(window, Duration::from_secs(60));
+                assert_eq!(offline_for, Duration::from_secs(61));
+            }
+            plan => panic!("unexpected plan: {:?}", plan),
+        }
+        // The session is forgotten.
+        assert!(matches!(
+            sm.plan(true, Duration::from_secs(0)),
+            (Plan::Enable, None)
+        ));
+        assert!(matches!(
+            sm.plan(false, Duration::from_secs(0)),
+            (Plan::Off, None)
+        ));
+    }
+}
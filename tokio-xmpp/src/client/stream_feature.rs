@@ -0,0 +1,97 @@
+use futures::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use xmpp_parsers::{ns, Jid};
+
+use crate::xmpp_stream::XMPPStream;
+use crate::Error;
+
+/// A binary stream, for [`BoxedStream`]
+pub trait AsyncReadAndWrite: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncReadAndWrite for T {}
+
+/// The binary stream once logged in, whose type got erased so that
+/// stream feature handlers may wrap it
+pub type BoxedStream = Box<dyn AsyncReadAndWrite>;
+
+/// What a stream feature handler did to the stream
+pub enum Negotiated {
+    /// Nothing changed below the XML stream, which goes on
+    Continue(Box<XMPPStream<BoxedStream>>),
+    /// The binary stream got replaced, for instance wrapped to
+    /// compress it, so the XML stream gets restarted on it
+    Restart(BoxedStream),
+}
+
+/// Negotiates a stream feature, see
+/// [`Config::on_stream_feature`](crate::AsyncConfig::on_stream_feature)
+pub type StreamFeatureHandler = Arc<
+    dyn Fn(XMPPStream<BoxedStream>) -> Pin<Box<dyn Future<Output = Result<Negotiated, Error>>>>
+        + Send
+        + Sync,
+>;
+
+/// Wrap `handler` into a [`StreamFeatureHandler`]
+pub fn stream_feature_handler<F, Fut>(handler: F) -> StreamFeatureHandler
+where
+    F: Fn(XMPPStream<BoxedStream>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Negotiated, Error>> + 'static,
+{
+    Arc::new(move |stream| Box::pin(handler(stream)))
+}
+
+/// A handler along with the `<stream:features/>` child it handles
+#[derive(Clone)]
+pub struct StreamFeatureHook {
+    /// Namespace of the feature
+    pub ns: String,
+    /// Name of the feature
+    pub name: String,
+    /// Called when the server offers the feature
+    pub handler: StreamFeatureHandler,
+}
+
+/// Runs the first hook of each feature the server offers, restarting
+/// the stream whenever one asks for it, until none is left
+///
+/// A feature is negotiated at most once, even if offered again after a
+/// restart.
+pub(crate) async fn negotiate(
+    mut xmpp_stream: XMPPStream<BoxedStream>,
+    jid: &Jid,
+    lang: Option<&str>,
+    hooks: &[StreamFeatureHook],
+) -> Result<XMPPStream<BoxedStream>, Error> {
+    let mut handled: Vec<&StreamFeatureHook> = Vec::new();
+    loop {
+        let hook = hooks.iter().find(|hook| {
+            !handled
+                .iter()
+                .any(|done| done.ns == hook.ns && done.name == hook.name)
+                && xmpp_stream
+                    .stream_features
+                    .0
+                    .get_child(&hook.name, hook.ns.as_str())
+                    .is_some()
+        });
+        let hook = match hook {
+            Some(hook) => hook,
+            None => return Ok(xmpp_stream),
+        };
+        handled.push(hook);
+        xmpp_stream = match (hook.handler)(xmpp_stream).await? {
+            Negotiated::Continue(xmpp_stream) => *xmpp_stream,
+            Negotiated::Restart(stream) => {
+                XMPPStream::start_with_lang(
+                    stream,
+                    jid.clone(),
+                    ns::JABBER_CLIENT.to_owned(),
+                    lang.map(String::from),
+                )
+                .await?
+            }
+        };
+    }
+}
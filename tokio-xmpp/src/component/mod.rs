@@ -1,19 +1,27 @@
 //! Components in XMPP are services/gateways that are logged into an
 //! XMPP server under a JID consisting of just a domain name. They are
 //! allowed to use any user and resource identifiers in their stanzas.
+use bytes::Bytes;
 use futures::{sink::SinkExt, task::Poll, Sink, Stream};
 use std::pin::Pin;
 use std::str::FromStr;
 use std::task::Context;
+use std::time::Duration;
 use tokio::net::TcpStream;
-use xmpp_parsers::{ns, Element, Jid};
+use xmpp_parsers::message::Message;
+use xmpp_parsers::presence::{Presence, Type as PresenceType};
+use xmpp_parsers::privilege::{Access, Privilege};
+use xmpp_parsers::{ns, BareJid, Element, Jid, ParseLimits};
 
-use super::happy_eyeballs::connect_to_host;
+use super::proxy::{self, ProxyConfig};
 use super::xmpp_codec::Packet;
 use super::xmpp_stream;
 use super::Error;
 
 mod auth;
+mod privilege;
+
+use privilege::Privileges;
 
 /// Component connection to an XMPP server
 ///
@@ -23,17 +31,109 @@ pub struct Component {
     /// The component's Jabber-Id
     pub jid: Jid,
     stream: XMPPStream,
+    /// What the server last advertised we may do on behalf of its users
+    privileges: Option<Privileges>,
 }
 
 type XMPPStream = xmpp_stream::XMPPStream<TcpStream>;
 
+/// Namespaces servers use for stanzas on a component connection, depending on their
+/// implementation.
+const STANZA_NAMESPACES: &[&str] = &[ns::JABBER_CLIENT, ns::JABBER_SERVER, ns::COMPONENT_ACCEPT];
+
+/// Move a stanza, and its children which were in the same namespace, to the `to` namespace.
+///
+/// Elements which aren’t in one of the stanza namespaces are returned untouched.
+fn normalize_ns(mut stanza: Element, to: &str) -> Element {
+    fn rename(elem: &mut Element, from: &str, to: &str) {
+        elem.set_ns(to);
+        for child in elem.children_mut() {
+            if child.ns() == from {
+                rename(child, from, to);
+            }
+        }
+    }
+
+    let from = stanza.ns();
+    if from != to && STANZA_NAMESPACES.contains(&from.as_str()) {
+        rename(&mut stanza, &from, to);
+    }
+    stanza
+}
+
 impl Component {
     /// Start a new XMPP component
     pub async fn new(jid: &str, password: &str, server: &str, port: u16) -> Result<Self, Error> {
+        Self::connect_with(jid, password, server, port, None).await
+    }
+
+    /// Start a new XMPP component, reaching its server through `proxy`
+    pub async fn new_with_proxy(
+        jid: &str,
+        password: &str,
+        server: &str,
+        port: u16,
+        proxy: ProxyConfig,
+    ) -> Result<Self, Error> {
+        Self::connect_with(jid, password, server, port, Some(&proxy)).await
+    }
+
+    async fn connect_with(
+        jid: &str,
+        password: &str,
+        server: &str,
+        port: u16,
+        proxy: Option<&ProxyConfig>,
+    ) -> Result<Self, Error> {
         let jid = Jid::from_str(jid)?;
         let password = password.to_owned();
-        let stream = Self::connect(jid.clone(), password, server, port).await?;
-        Ok(Component { jid, stream })
+        let stream = Self::connect(jid.clone(), password, server, port, proxy).await?;
+        Ok(Component {
+            jid,
+            stream,
+            privileges: None,
+        })
+    }
+
+    /// Set how long to wait, once we sent `</stream:stream>`, for the
+    /// server to close its side, 10 seconds by default
+    pub fn set_close_timeout(&mut self, timeout: Duration) {
+        self.stream.set_close_timeout(timeout);
+    }
+
+    /// Drop the connection with `ProtocolError::StanzaTimeout` if a
+    /// stanza isn't complete `timeout` after it started being received,
+    /// 5 minutes by default, or never if `None`
+    pub fn set_stanza_timeout(&mut self, timeout: Option<Duration>) {
+        self.stream.set_stanza_timeout(timeout);
+    }
+
+    /// Write at most `size` bytes at once to the connection, 16 KiB by
+    /// default, so that small urgent stanzas such as iq replies get
+    /// ahead of a big batch being sent
+    pub fn set_write_chunk_size(&mut self, size: usize) {
+        self.stream.set_write_chunk_size(size);
+    }
+
+    /// Set how big incoming stanzas may be, for components which relay
+    /// deeper or bigger payloads than clients usually get
+    ///
+    /// A stanza exceeding these limits ends the stream with a parser
+    /// error.
+    pub fn set_parse_limits(&mut self, limits: ParseLimits) {
+        self.stream.set_parse_limits(limits);
+    }
+
+    /// Keep the bytes of every stanza as received, for instance to
+    /// archive or forward them without serializing them again
+    pub fn set_keep_raw(&mut self, keep: bool) {
+        self.stream.set_keep_raw(keep);
+    }
+
+    /// The bytes of the stanza last yielded, exactly as the server sent
+    /// them, if [`Component::set_keep_raw`] got enabled
+    pub fn raw_stanza(&self) -> Option<Bytes> {
+        self.stream.raw_stanza()
     }
 
     async fn connect(
@@ -41,9 +141,10 @@ impl Component {
         password: String,
         server: &str,
         port: u16,
+        proxy: Option<&ProxyConfig>,
     ) -> Result<XMPPStream, Error> {
         let password = password;
-        let tcp_stream = connect_to_host(server, port).await?;
+        let tcp_stream = proxy::connect(proxy, server, port).await?;
         let mut xmpp_stream =
             xmpp_stream::XMPPStream::start(tcp_stream, jid, ns::COMPONENT_ACCEPT.to_owned())
                 .await?;
@@ -51,24 +152,103 @@ impl Component {
         Ok(xmpp_stream)
     }
 
+    /// The privileges (XEP-0356) the server granted us, once it
+    /// advertised them
+    ///
+    /// The advertisement is still yielded like any other stanza.
+    pub fn privileges(&self) -> Option<&Privilege> {
+        self.privileges
+            .as_ref()
+            .map(|privileges| &privileges.privilege)
+    }
+
+    /// Send `message` on behalf of `user`, which the server must have
+    /// allowed with the outgoing message privilege (XEP-0356)
+    pub async fn send_as(&mut self, user: BareJid, message: Message) -> Result<(), Error> {
+        let stanza = self
+            .privileges
+            .as_ref()
+            .ok_or(Error::NotPrivileged(Access::Message))?
+            .message_as(&self.jid, user, message)
+            .map_err(Error::NotPrivileged)?;
+        self.send(stanza).await
+    }
+
+    /// Ask for the roster of `user`, which the server must have allowed
+    /// with the roster privilege (XEP-0356)
+    ///
+    /// The server answers with an IQ of the same `id`, received like
+    /// any other stanza.
+    pub async fn get_user_roster(&mut self, user: BareJid, id: &str) -> Result<(), Error> {
+        let stanza = self
+            .privileges
+            .as_ref()
+            .ok_or(Error::NotPrivileged(Access::Roster))?
+            .roster_query(&self.jid, user, id)
+            .map_err(Error::NotPrivileged)?;
+        self.send(stanza).await
+    }
+
+    /// Ask for the current presence of `to`, on behalf of `from`, one
+    /// of the JIDs this component serves
+    ///
+    /// Its server answers with the last presence of each available
+    /// resource of `to`, or an unavailable one, received like any other
+    /// stanza, provided `to` shares its presence with `from`.
+    pub async fn send_presence_probe(&mut self, from: Jid, to: BareJid) -> Result<(), Error> {
+        let probe = Presence::new(PresenceType::Probe)
+            .with_from(from)
+            .with_to(Jid::Bare(to));
+        self.send(probe.into()).await
+    }
+
     /// Send stanza
+    ///
+    /// Stanzas in the jabber:client or jabber:server namespaces are sent as
+    /// jabber:component:accept.
     pub async fn send_stanza(&mut self, stanza: Element) -> Result<(), Error> {
         self.send(stanza).await
     }
 
+    /// Send all stanzas in order, flushing only once at the end
+    ///
+    /// This is faster than calling `send_stanza()` in a loop, for example
+    /// when replaying a room history.  If one of them fails, the previous
+    /// ones are still sent and `Error::PartialSend` tells how many.
+    pub async fn send_all<I: IntoIterator<Item = Element>>(
+        &mut self,
+        stanzas: I,
+    ) -> Result<(), Error> {
+        xmpp_stream::send_all(self, stanzas).await
+    }
+
     /// End connection
+    ///
+    /// Nothing can be sent afterwards, and the connection only gets
+    /// dropped once the server closed its side too, or the close
+    /// timeout passed. The stanzas it sent meanwhile are still
+    /// returned by the stream.
     pub async fn send_end(&mut self) -> Result<(), Error> {
         self.close().await
     }
 }
 
+/// Received stanzas are moved to the namespace xmpp-parsers expects
+/// (`ns::DEFAULT_NS`), whichever of jabber:client, jabber:server or
+/// jabber:component:accept the server used.
 impl Stream for Component {
     type Item = Element;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         loop {
             match Pin::new(&mut self.stream).poll_next(cx) {
-                Poll::Ready(Some(Ok(Packet::Stanza(stanza)))) => return Poll::Ready(Some(stanza)),
+                Poll::Ready(Some(Ok(Packet::Stanza(stanza)))) => {
+                    let stanza = normalize_ns(stanza, ns::DEFAULT_NS);
+                    if let Some(privileges) = Privileges::advertised(&self.jid, &stanza) {
+                        self.privileges = Some(privileges);
+                    }
+                    return Poll::Ready(Some(stanza));
+                }
                 Poll::Ready(Some(Ok(Packet::Text(_)))) => {
                     // retry
                 }
@@ -90,7 +270,7 @@ impl Sink<Element> for Component {
 
     fn start_send(mut self: Pin<&mut Self>, item: Element) -> Result<(), Self::Error> {
         Pin::new(&mut self.stream)
-            .start_send(Packet::Stanza(item))
+            .start_send(Packet::Stanza(normalize_ns(item, ns::COMPONENT_ACCEPT)))
             .map_err(|e| e.into())
     }
 
@@ -112,3 +292,50 @@ impl Sink<Element> for Component {
             .map_err(|e| e.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use xmpp_parsers::message::Message;
+
+    #[test]
+    fn test_normalize_incoming() {
+        let messages: Vec<Message> = STANZA_NAMESPACES
+            .iter()
+            .map(|stanza_ns| {
+                let xml = format!(
+                    "<message xmlns='{}' from='a@b' to='c.b' type='chat'><body>Hi</body><subject>S</subject><x xmlns='urn:example'><body/></x></message>",
+                    stanza_ns
+                );
+                let elem = normalize_ns(xml.parse().unwrap(), ns::DEFAULT_NS);
+                assert_eq!(elem.ns(), ns::DEFAULT_NS);
+                Message::try_from(elem).unwrap()
+            })
+            .collect();
+        assert_eq!(messages[0].bodies[""].0, "Hi");
+        assert_eq!(messages[0].subjects[""].0, "S");
+        // Payloads in their own namespace are left alone.
+        assert_eq!(messages[0].payloads.len(), 1);
+        assert_eq!(messages[0].payloads[0].ns(), "urn:example");
+        assert!(messages[0].payloads[0].has_child("body", "urn:example"));
+        assert_eq!(messages[0], messages[1]);
+        assert_eq!(messages[0], messages[2]);
+    }
+
+    #[test]
+    fn test_normalize_outgoing() {
+        let elem: Element = "<iq xmlns='jabber:client' type='error' id='a'><error type='cancel'><item-not-found xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/></error></iq>"
+            .parse()
+            .unwrap();
+        let elem = normalize_ns(elem, ns::COMPONENT_ACCEPT);
+        assert_eq!(
+            String::from(&elem),
+            "<iq xmlns=\"jabber:component:accept\" id=\"a\" type=\"error\"><error type=\"cancel\"><item-not-found xmlns=\"urn:ietf:params:xml:ns:xmpp-stanzas\"/></error></iq>"
+        );
+
+        // Stream-level elements aren’t stanzas.
+        let elem: Element = "<handshake xmlns='urn:example'/>".parse().unwrap();
+        assert_eq!(normalize_ns(elem, ns::COMPONENT_ACCEPT).ns(), "urn:example");
+    }
+}
@@ -0,0 +1,162 @@
+//! Acting on behalf of the users of the server, as allowed by the
+//! privileges it granted us (XEP-0356)
+
+use std::convert::TryFrom;
+use std::str::FromStr;
+use xmpp_parsers::iq::Iq;
+use xmpp_parsers::message::Message;
+use xmpp_parsers::privilege::{Access, Privilege, Type};
+use xmpp_parsers::roster::Roster;
+use xmpp_parsers::{ns, BareJid, Element, Jid};
+
+/// The privileges granted to a component, and by which server
+pub(crate) struct Privileges {
+    server: Jid,
+    pub(crate) privilege: Privilege,
+}
+
+impl Privileges {
+    /// If `stanza` is the server of `component` advertising the
+    /// privileges it grants, those privileges
+    ///
+    /// Only the domains `component` is a subdomain of may do so.
+    pub(crate) fn advertised(component: &Jid, stanza: &Element) -> Option<Privileges> {
+        if !stanza.is("message", ns::DEFAULT_NS) {
+            return None;
+        }
+        let server = match Jid::from_str(stanza.attr("from")?).ok()? {
+            Jid::Bare(BareJid { node: None, domain }) => domain,
+            _ => return None,
+        };
+        if !component
+            .clone()
+            .domain()
+            .ends_with(&format!(".{}", server))
+        {
+            return None;
+        }
+        let privilege = stanza
+            .get_child("privilege", ns::PRIVILEGE)
+            .and_then(|privilege| Privilege::try_from(privilege.clone()).ok())?;
+        Some(Privileges {
+            server: Jid::Bare(BareJid::domain(server)),
+            privilege,
+        })
+    }
+
+    /// Wraps `message` so that the server sends it from `user`, or
+    /// tells which access is missing
+    pub(crate) fn message_as(
+        &self,
+        component: &Jid,
+        user: BareJid,
+        mut message: Message,
+    ) -> Result<Element, Access> {
+        if self.privilege.granted(Access::Message) != Type::Outgoing {
+            return Err(Access::Message);
+        }
+        message.from = Some(Jid::Bare(user));
+        let mut wrapper = Message::new(Some(self.server.clone()));
+        wrapper.from = Some(component.clone());
+        wrapper.payloads.push(Privilege::forward(message).into());
+        Ok(wrapper.into())
+    }
+
+    /// Asks for the roster of `user`, or tells which access is missing
+    pub(crate) fn roster_query(
+        &self,
+        component: &Jid,
+        user: BareJid,
+        id: &str,
+    ) -> Result<Element, Access> {
+        match self.privilege.granted(Access::Roster) {
+            Type::Get | Type::Both => (),
+            _ => return Err(Access::Roster),
+        }
+        let query = Roster {
+            ver: None,
+            items: vec![],
+        };
+        let iq = Iq::from_get(id, query)
+            .with_from(component.clone())
+            .with_to(Jid::Bare(user));
+        Ok(iq.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xmpp_parsers::message::Body;
+
+    fn advertisement(from: &str, perms: &str) -> Element {
+        format!(
+            "<message xmlns='jabber:client' from='{}' to='pubsub.capulet.lit' id='54321'><privilege xmlns='urn:xmpp:privilege:2'>{}</privilege></message>",
+            from, perms
+        )
+        .parse()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_advertised() {
+        let component = Jid::from_str("pubsub.capulet.lit").unwrap();
+        let perms = "<perm access='roster' type='both'/><perm access='message' type='outgoing'/>";
+        let privileges =
+            Privileges::advertised(&component, &advertisement("capulet.lit", perms)).unwrap();
+        assert_eq!(privileges.privilege.perms.len(), 2);
+
+        // Neither other servers nor users can grant anything.
+        assert!(
+            Privileges::advertised(&component, &advertisement("montague.lit", perms)).is_none()
+        );
+        assert!(
+            Privileges::advertised(&component, &advertisement("juliet@capulet.lit", perms))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_privileged_stanzas() {
+        let component = Jid::from_str("pubsub.capulet.lit").unwrap();
+        let juliet = BareJid::from_str("juliet@capulet.lit").unwrap();
+        let perms = "<perm access='roster' type='get'/><perm access='message' type='outgoing'/>";
+        let privileges =
+            Privileges::advertised(&component, &advertisement("capulet.lit", perms)).unwrap();
+
+        let mut message = Message::new(Jid::from_str("romeo@montague.lit").unwrap());
+        message
+            .bodies
+            .insert(String::new(), Body(String::from("Romeo?")));
+        let elem = privileges
+            .message_as(&component, juliet.clone(), message)
+            .unwrap();
+        let expected: Element = "<message xmlns='jabber:client' from='pubsub.capulet.lit' to='capulet.lit' type='chat'><privilege xmlns='urn:xmpp:privilege:2'><forwarded xmlns='urn:xmpp:forward:0'><message xmlns='jabber:client' from='juliet@capulet.lit' to='romeo@montague.lit' type='chat'><body>Romeo?</body></message></forwarded></privilege></message>".parse().unwrap();
+        assert_eq!(elem, expected);
+
+        let elem = privileges
+            .roster_query(&component, juliet, "roster1")
+            .unwrap();
+        let expected: Element = "<iq xmlns='jabber:client' from='pubsub.capulet.lit' to='juliet@capulet.lit' id='roster1' type='get'><query xmlns='jabber:iq:roster'/></iq>".parse().unwrap();
+        assert_eq!(elem, expected);
+    }
+
+    #[test]
+    fn test_not_granted() {
+        let component = Jid::from_str("pubsub.capulet.lit").unwrap();
+        let juliet = BareJid::from_str("juliet@capulet.lit").unwrap();
+        let perms = "<perm access='roster' type='set'/><perm access='message' type='none'/>";
+        let privileges =
+            Privileges::advertised(&component, &advertisement("capulet.lit", perms)).unwrap();
+
+        let message = Message::new(Jid::from_str("romeo@montague.lit").unwrap());
+        match privileges.message_as(&component, juliet.clone(), message) {
+            Err(Access::Message) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        match privileges.roster_query(&component, juliet, "roster1") {
+            Err(Access::Roster) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+}
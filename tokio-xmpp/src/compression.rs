@@ -0,0 +1,235 @@
+//! XEP-0138: Stream Compression, with zlib
+//!
+//! Compressing secrets along with data an attacker can influence, over
+//! an encrypted stream, can leak those secrets through the size of the
+//! encrypted data, the same way CRIME did for TLS compression. That is
+//! why this is only available with the `compression` feature.
+
+use async_compression::tokio::{bufread::ZlibDecoder, write::ZlibEncoder};
+use futures::{sink::SinkExt, stream::StreamExt};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{split, AsyncRead, AsyncWrite, BufReader, ReadBuf, ReadHalf, WriteHalf};
+use xmpp_parsers::{ns, Element};
+
+use crate::xmpp_codec::Packet;
+use crate::xmpp_stream::XMPPStream;
+use crate::{BoxedStream, Error, Negotiated, ProtocolError};
+
+/// A binary stream which starts uncompressed, and switches to zlib in
+/// both directions once compression has been negotiated
+pub enum ZlibStream<S> {
+    /// Not compressed (yet)
+    Plain(S),
+    /// Compressed in both directions
+    Compressed {
+        /// Inflates what is received
+        reader: ZlibDecoder<BufReader<ReadHalf<S>>>,
+        /// Deflates what is sent, every flush ending a zlib block so
+        /// that the peer can read everything up to there
+        writer: ZlibEncoder<WriteHalf<S>>,
+    },
+}
+
+impl<S: AsyncRead + AsyncWrite> ZlibStream<S> {
+    /// Compress everything from now on
+    pub fn into_compressed(self) -> Self {
+        match self {
+            ZlibStream::Plain(stream) => {
+                let (reader, writer) = split(stream);
+                ZlibStream::Compressed {
+                    reader: ZlibDecoder::new(BufReader::new(reader)),
+                    writer: ZlibEncoder::new(writer),
+                }
+            }
+            compressed => compressed,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for ZlibStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ZlibStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ZlibStream::Compressed { reader, .. } => Pin::new(reader).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for ZlibStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ZlibStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ZlibStream::Compressed { writer, .. } => Pin::new(writer).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ZlibStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ZlibStream::Compressed { writer, .. } => Pin::new(writer).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ZlibStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ZlibStream::Compressed { writer, .. } => Pin::new(writer).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Performs `<compress/>` on an XMPPStream and returns the binary
+/// stream, now compressed, on which the XML stream has to be restarted.
+pub async fn compress<S: AsyncRead + AsyncWrite + Unpin>(
+    mut xmpp_stream: XMPPStream<ZlibStream<S>>,
+) -> Result<ZlibStream<S>, Error> {
+    request_compression(&mut xmpp_stream).await?;
+    Ok(xmpp_stream.into_inner().into_compressed())
+}
+
+/// Negotiates compression as a stream feature handler, which is how
+/// the client uses it
+pub(crate) async fn negotiate(
+    mut xmpp_stream: XMPPStream<BoxedStream>,
+) -> Result<Negotiated, Error> {
+    if !xmpp_stream.stream_features.can_compress() {
+        return Ok(Negotiated::Continue(Box::new(xmpp_stream)));
+    }
+    request_compression(&mut xmpp_stream).await?;
+    let stream = ZlibStream::Plain(xmpp_stream.into_inner()).into_compressed();
+    Ok(Negotiated::Restart(Box::new(stream)))
+}
+
+/// Sends `<compress/>` and waits for the server to accept it
+async fn request_compression<S: AsyncRead + AsyncWrite + Unpin>(
+    xmpp_stream: &mut XMPPStream<S>,
+) -> Result<(), Error> {
+    let nonza = Element::builder("compress", ns::COMPRESS)
+        .append(Element::builder("method", ns::COMPRESS).append("zlib"))
+        .build();
+    xmpp_stream.send(Packet::Stanza(nonza)).await?;
+
+    loop {
+        match xmpp_stream.next().await {
+            Some(Ok(Packet::Stanza(ref stanza))) if stanza.is("compressed", ns::COMPRESS) => break,
+            Some(Ok(Packet::Text(_))) => {}
+            Some(Err(e)) => return Err(e),
+            _ => return Err(ProtocolError::CompressionFailed.into()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::Framed;
+    use xmpp_parsers::Jid;
+
+    use crate::xmpp_codec::{StreamHeader, XMPPCodec};
+
+    fn stream_start() -> Packet {
+        Packet::StreamStart(StreamHeader {
+            ns: Some(String::from(ns::JABBER_CLIENT)),
+            ..StreamHeader::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_flush_delivers() {
+        let (a, b) = duplex(4096);
+        let mut a = ZlibStream::Plain(a).into_compressed();
+        let mut b = ZlibStream::Plain(b).into_compressed();
+
+        // Each flush must let the peer read everything written so far,
+        // without waiting for the end of the zlib stream.
+        for stanza in &["<presence/>", "<message><body>Hello</body></message>"] {
+            a.write_all(stanza.as_bytes()).await.unwrap();
+            a.flush().await.unwrap();
+            let mut buf = vec![0; stanza.len()];
+            b.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, stanza.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_negotiation() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let mut server = Framed::new(ZlibStream::Plain(server.unwrap().0), XMPPCodec::new());
+        let mut stream = XMPPStream::new(
+            Jid::from_str("foo@bar/baz").unwrap(),
+            Framed::new(ZlibStream::Plain(client.unwrap()), XMPPCodec::new()),
+            String::from(ns::JABBER_CLIENT),
+            String::from("id"),
+            "<features xmlns='http://etherx.jabber.org/streams'><compression xmlns='http://jabber.org/features/compress'><method>zlib</method></compression></features>"
+                .parse()
+                .unwrap(),
+        );
+        assert!(stream.stream_features.can_compress());
+        stream.send(stream_start()).await.unwrap();
+        server.send(stream_start()).await.unwrap();
+        match stream.next().await {
+            Some(Ok(Packet::StreamStart(_))) => (),
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+
+        let server = async move {
+            loop {
+                match server.next().await {
+                    Some(Ok(Packet::Stanza(stanza))) => {
+                        assert!(stanza.is("compress", ns::COMPRESS));
+                        assert_eq!(
+                            stanza.get_child("method", ns::COMPRESS).unwrap().text(),
+                            "zlib"
+                        );
+                        break;
+                    }
+                    Some(Ok(_)) => (),
+                    packet => panic!("unexpected packet: {:?}", packet),
+                }
+            }
+            let compressed = Element::bare("compressed", ns::COMPRESS);
+            server.send(Packet::Stanza(compressed)).await.unwrap();
+
+            // The stream restarts, compressed.
+            let mut server = Framed::new(server.into_inner().into_compressed(), XMPPCodec::new());
+            loop {
+                match server.next().await {
+                    Some(Ok(Packet::StreamStart(_))) => break,
+                    Some(Ok(Packet::Text(_))) => (),
+                    packet => panic!("unexpected packet: {:?}", packet),
+                }
+            }
+            server.send(stream_start()).await.unwrap();
+            let presence = Element::bare("presence", ns::JABBER_CLIENT);
+            server.send(Packet::Stanza(presence)).await.unwrap();
+            server
+        };
+        let client = async move {
+            let stream = compress(stream).await.unwrap();
+            let mut stream = Framed::new(stream, XMPPCodec::new());
+            stream.send(stream_start()).await.unwrap();
+            loop {
+                match stream.next().await {
+                    Some(Ok(Packet::Stanza(stanza))) => return stanza,
+                    Some(Ok(_)) => (),
+                    packet => panic!("unexpected packet: {:?}", packet),
+                }
+            }
+        };
+
+        let (_server, stanza) = tokio::join!(server, client);
+        assert!(stanza.is("presence", ns::JABBER_CLIENT));
+    }
+}
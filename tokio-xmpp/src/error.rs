@@ -1,3 +1,4 @@
+use minidom::Position;
 #[cfg(feature = "tls-native")]
 use native_tls::Error as TlsError;
 use sasl::client::MechanismError as SaslMechanismError;
@@ -5,7 +6,9 @@ use std::borrow::Cow;
 use std::error::Error as StdError;
 use std::fmt;
 use std::io::Error as IoError;
+use std::ops::Deref;
 use std::str::Utf8Error;
+use std::sync::Arc;
 #[cfg(feature = "tls-rust")]
 use tokio_rustls::rustls::client::InvalidDnsNameError;
 #[cfg(feature = "tls-rust")]
@@ -13,8 +16,10 @@ use tokio_rustls::rustls::Error as TlsError;
 use trust_dns_proto::error::ProtoError;
 use trust_dns_resolver::error::ResolveError;
 
+use xmpp_parsers::privilege::Access;
 use xmpp_parsers::sasl::DefinedCondition as SaslDefinedCondition;
-use xmpp_parsers::{Error as ParsersError, JidParseError};
+use xmpp_parsers::stanza_error::StanzaError;
+use xmpp_parsers::{Element, Error as ParsersError, JidParseError};
 
 /// Top-level error type
 #[derive(Debug)]
@@ -39,6 +44,33 @@ pub enum Error {
     DnsNameError(InvalidDnsNameError),
     /// Connection closed
     Disconnected,
+    /// Only this many stanzas of a batch got sent before the error
+    PartialSend(usize, Box<Error>),
+    /// The remote entity answered a request with an error
+    Stanza(StanzaError),
+    /// These stanzas were sent but never written before the error, so
+    /// the server didn't get them
+    Unsent(Vec<Element>, Box<Error>),
+    /// The server didn't grant the privilege (XEP-0356) this needs
+    NotPrivileged(Access),
+    /// The server told us to connect to another host instead, with a
+    /// `<see-other-host/>` stream error
+    Redirect {
+        /// Host name or IP address
+        host: String,
+        /// TCP port
+        port: u16,
+    },
+    /// Nothing can be sent anymore once `</stream:stream>` got sent
+    Closing,
+    /// The server didn't answer a keepalive ping in time, see
+    /// [`PingPolicy`](crate::PingPolicy), so the connection is likely
+    /// broken somewhere along the network
+    KeepaliveTimeout,
+    /// The remote entity didn't answer a request in time
+    RequestTimeout,
+    /// The configuration given is invalid, for the reason explained
+    Config(String),
     /// Shoud never happen
     InvalidState,
 }
@@ -56,11 +88,37 @@ impl fmt::Display for Error {
             #[cfg(feature = "tls-rust")]
             Error::DnsNameError(e) => write!(fmt, "DNS name error: {}", e),
             Error::Disconnected => write!(fmt, "disconnected"),
+            Error::PartialSend(sent, e) => write!(fmt, "only {} stanzas sent: {}", sent, e),
+            Error::Stanza(e) => write!(fmt, "stanza error: {:?}", e.defined_condition),
+            Error::Unsent(unsent, e) => write!(fmt, "{} stanzas never sent: {}", unsent.len(), e),
+            Error::NotPrivileged(access) => write!(fmt, "privilege not granted: {:?}", access),
+            Error::Redirect { host, port } => write!(fmt, "redirected to {}:{}", host, port),
+            Error::Closing => write!(fmt, "stream closing"),
+            Error::KeepaliveTimeout => write!(fmt, "keepalive ping timed out"),
+            Error::RequestTimeout => write!(fmt, "request timed out"),
+            Error::Config(e) => write!(fmt, "invalid configuration: {}", e),
             Error::InvalidState => write!(fmt, "invalid state"),
         }
     }
 }
 
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Connection(e) => Some(e),
+            Error::JidParse(e) => Some(e),
+            Error::Protocol(e) => Some(e),
+            Error::Auth(e) => Some(e),
+            Error::Tls(e) => Some(e),
+            #[cfg(feature = "tls-rust")]
+            Error::DnsNameError(e) => Some(e),
+            Error::PartialSend(_, e) | Error::Unsent(_, e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
 impl From<IoError> for Error {
     fn from(e: IoError) -> Self {
         Error::Io(e)
@@ -104,6 +162,53 @@ impl From<InvalidDnsNameError> for Error {
     }
 }
 
+/// An [`Error`] which every consumer of an event gets, such as the
+/// subscribers of a broadcast channel, cloning it being cheap
+///
+/// It dereferences to the error, to match on its kind, and displays
+/// and chains to the same sources.
+#[derive(Debug, Clone)]
+pub struct SharedError(Arc<Error>);
+
+impl SharedError {
+    /// The error itself, unless it is still shared
+    pub fn try_unwrap(self) -> Result<Error, SharedError> {
+        Arc::try_unwrap(self.0).map_err(SharedError)
+    }
+}
+
+impl Deref for SharedError {
+    type Target = Error;
+
+    fn deref(&self) -> &Error {
+        &self.0
+    }
+}
+
+impl AsRef<Error> for SharedError {
+    fn as_ref(&self) -> &Error {
+        &self.0
+    }
+}
+
+impl fmt::Display for SharedError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(fmt)
+    }
+}
+
+impl StdError for SharedError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.0.source()
+    }
+}
+
+impl From<Error> for SharedError {
+    fn from(e: Error) -> Self {
+        SharedError(Arc::new(e))
+    }
+}
+
 /// Causes for stream parsing errors
 #[derive(Debug)]
 pub enum ParserError {
@@ -113,8 +218,33 @@ pub enum ParserError {
     Parse(ParseError),
     /// Illegal `</>`
     ShortTag,
+    /// A stanza has elements nested too deeply
+    TooDeep,
+    /// A stanza has too many nodes
+    TooManyNodes,
     /// Required by `impl Decoder`
     Io(IoError),
+    /// A `Parse` or `ShortTag` error, in the stanza starting at this
+    /// position of the stream
+    InStanza(Box<ParserError>, Position),
+}
+
+impl ParserError {
+    /// This error without the position of its stanza
+    pub fn kind(&self) -> &ParserError {
+        match self {
+            ParserError::InStanza(e, _) => e.kind(),
+            e => e,
+        }
+    }
+
+    /// Where the stanza this error is in starts in the stream, if known
+    pub fn stanza_position(&self) -> Option<Position> {
+        match self {
+            ParserError::InStanza(_, position) => Some(*position),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for ParserError {
@@ -123,7 +253,12 @@ impl fmt::Display for ParserError {
             ParserError::Utf8(e) => write!(fmt, "UTF-8 error: {}", e),
             ParserError::Parse(e) => write!(fmt, "parse error: {}", e),
             ParserError::ShortTag => write!(fmt, "short tag"),
+            ParserError::TooDeep => write!(fmt, "stanza nested too deeply"),
+            ParserError::TooManyNodes => write!(fmt, "stanza with too many nodes"),
             ParserError::Io(e) => write!(fmt, "IO error: {}", e),
+            ParserError::InStanza(e, position) => {
+                write!(fmt, "{} in the stanza starting at {}", e, position)
+            }
         }
     }
 }
@@ -170,6 +305,8 @@ pub enum ProtocolError {
     NoTls,
     /// Invalid response to resource binding
     InvalidBindResponse,
+    /// The requested resource is already in use
+    BindConflict,
     /// No xmlns attribute in <stream:stream>
     NoStreamNamespace,
     /// No id attribute in <stream:stream>
@@ -178,6 +315,11 @@ pub enum ProtocolError {
     InvalidToken,
     /// Unexpected <stream:stream> (shouldn't occur)
     InvalidStreamStart,
+    /// The server refused to compress the stream
+    CompressionFailed,
+    /// A stanza started being received, but wasn't complete in time, see
+    /// [`XMPPStream::set_stanza_timeout`](crate::xmpp_stream::XMPPStream::set_stanza_timeout)
+    StanzaTimeout,
 }
 
 impl fmt::Display for ProtocolError {
@@ -189,12 +331,24 @@ impl fmt::Display for ProtocolError {
             ProtocolError::InvalidBindResponse => {
                 write!(fmt, "invalid response to resource binding")
             }
+            ProtocolError::BindConflict => write!(fmt, "requested resource already in use"),
+            ProtocolError::CompressionFailed => write!(fmt, "stream compression failed"),
             ProtocolError::NoStreamNamespace => {
                 write!(fmt, "no xmlns attribute in <stream:stream>")
             }
             ProtocolError::NoStreamId => write!(fmt, "no id attribute in <stream:stream>"),
             ProtocolError::InvalidToken => write!(fmt, "encountered an unexpected XML token"),
             ProtocolError::InvalidStreamStart => write!(fmt, "unexpected <stream:stream>"),
+            ProtocolError::StanzaTimeout => write!(fmt, "stanza not received in time"),
+        }
+    }
+}
+
+impl StdError for ProtocolError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            ProtocolError::Parsers(e) => Some(e),
+            _ => None,
         }
     }
 }
@@ -235,21 +389,130 @@ impl fmt::Display for AuthError {
     }
 }
 
+impl StdError for AuthError {}
+
 /// Error establishing connection
 #[derive(Debug)]
 pub enum ConnecterError {
-    /// All attempts failed, no error available
-    AllFailed,
+    /// All attempts failed, with the host, port and error of each
+    /// address tried, in order
+    AllFailed(Vec<(String, u16, IoError)>),
     /// DNS protocol error
     Dns(ProtoError),
     /// DNS resolution error
     Resolve(ResolveError),
+    /// The proxy didn't open a tunnel to the server
+    Proxy(ProxyError),
+    /// The SOCKS5 streamhost didn't connect us to the bytestream
+    Socks5(Socks5Error),
 }
 
-impl std::error::Error for ConnecterError {}
+impl std::error::Error for ConnecterError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            ConnecterError::Dns(e) => Some(e),
+            ConnecterError::Resolve(e) => Some(e),
+            ConnecterError::Proxy(e) => Some(e),
+            ConnecterError::Socks5(e) => Some(e),
+            ConnecterError::AllFailed(_) => None,
+        }
+    }
+}
 
 impl std::fmt::Display for ConnecterError {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        write!(fmt, "{:?}", self)
+        match self {
+            ConnecterError::AllFailed(attempts) if attempts.is_empty() => {
+                write!(fmt, "no address to connect to")
+            }
+            ConnecterError::AllFailed(attempts) => {
+                write!(fmt, "all connection attempts failed")?;
+                for (i, (host, port, error)) in attempts.iter().enumerate() {
+                    let separator = if i == 0 { ":" } else { ";" };
+                    if host.contains(':') {
+                        write!(fmt, "{} [{}]:{}: {}", separator, host, port, error)?;
+                    } else {
+                        write!(fmt, "{} {}:{}: {}", separator, host, port, error)?;
+                    }
+                }
+                Ok(())
+            }
+            ConnecterError::Proxy(e) => write!(fmt, "proxy error: {}", e),
+            ConnecterError::Socks5(e) => write!(fmt, "SOCKS5 error: {}", e),
+            _ => write!(fmt, "{:?}", self),
+        }
+    }
+}
+
+impl From<ProxyError> for ConnecterError {
+    fn from(e: ProxyError) -> Self {
+        ConnecterError::Proxy(e)
+    }
+}
+
+/// Why an HTTP proxy didn't open a tunnel with `CONNECT`
+#[derive(Debug)]
+pub enum ProxyError {
+    /// The proxy wants credentials, or rejected those given, with a
+    /// `407 Proxy Authentication Required`
+    AuthRequired,
+    /// The proxy answered with this other status code and reason
+    Status(u16, String),
+    /// The proxy didn't answer with HTTP
+    InvalidResponse,
+}
+
+impl StdError for ProxyError {}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProxyError::AuthRequired => write!(
+                fmt,
+                "the proxy requires authentication, check the username and password given for it"
+            ),
+            ProxyError::Status(code, reason) => {
+                write!(fmt, "the proxy refused the tunnel: {} {}", code, reason)
+            }
+            ProxyError::InvalidResponse => write!(fmt, "invalid response from the proxy"),
+        }
+    }
+}
+
+impl From<Socks5Error> for ConnecterError {
+    fn from(e: Socks5Error) -> Self {
+        ConnecterError::Socks5(e)
+    }
+}
+
+/// Why a SOCKS5 streamhost (XEP-0065) didn't connect us to a bytestream
+#[derive(Debug)]
+pub enum Socks5Error {
+    /// The streamhost requires authentication, which bytestreams don't use
+    NoAcceptableMethod,
+    /// The streamhost refused the `CONNECT` with this reply code, such as
+    /// 0x04 when the other side didn't connect with the same address
+    Refused(u8),
+    /// The streamhost didn't answer with SOCKS5
+    InvalidResponse,
+}
+
+impl StdError for Socks5Error {}
+
+impl fmt::Display for Socks5Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Socks5Error::NoAcceptableMethod => {
+                write!(fmt, "the streamhost requires an authentication method")
+            }
+            Socks5Error::Refused(code) => {
+                write!(
+                    fmt,
+                    "the streamhost refused the connection: reply {:#04x}",
+                    code
+                )
+            }
+            Socks5Error::InvalidResponse => write!(fmt, "invalid response from the streamhost"),
+        }
     }
 }
@@ -1,8 +1,12 @@
-use super::Error;
+use super::SharedError;
+use std::time::{Duration, Instant};
 use xmpp_parsers::{Element, Jid};
 
 /// High-level event on the Stream implemented by Client and Component
-#[derive(Debug)]
+///
+/// Events can be cloned, to hand them to several consumers such as the
+/// subscribers of a broadcast channel, errors being shared between them.
+#[derive(Debug, Clone)]
 pub enum Event {
     /// Stream is connected and initialized
     Online {
@@ -14,11 +18,31 @@ pub enum Event {
         bound_jid: Jid,
         /// Was this session resumed?
         ///
-        /// Not yet implemented for the Client
+        /// Only with stream management enabled, see
+        /// [`Config::stream_management`](crate::AsyncConfig::stream_management)
         resumed: bool,
     },
     /// Stream end
-    Disconnected(Error),
+    Disconnected(SharedError),
+    /// Too many reconnection attempts failed in a row, the next one
+    /// will only happen at `until`, unless `retry_now()` is called
+    ReconnectionSuspended {
+        /// When the next attempt will happen
+        until: Instant,
+        /// Why the last attempt failed
+        error: SharedError,
+    },
+    /// The session couldn't be resumed, the server having forgotten it
+    /// along with the stanzas in flight, so the next connection starts
+    /// a new one
+    ///
+    /// Messages received meanwhile may be in the archive instead.
+    ResumptionExpired {
+        /// How long the server keeps the session once disconnected
+        window: Duration,
+        /// How long we will have been disconnected, when reconnecting
+        offline_for: Duration,
+    },
     /// Received stanza/nonza
     Stanza(Element),
 }
@@ -1,11 +1,30 @@
-use crate::{ConnecterError, Error};
-use idna;
-use std::net::SocketAddr;
+use crate::proxy::{self, ProxyConfig};
+use crate::{ConnecterError, Error, ProxyError};
+use std::io::{Error as IoError, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
 use tokio::net::TcpStream;
 use trust_dns_resolver::{IntoName, TokioAsyncResolver};
+use xmpp_parsers::BareJid;
+
+/// Tries each of `ips` in turn, adding every failure to `attempts`
+async fn connect_to_ips<I: IntoIterator<Item = IpAddr>>(
+    ips: I,
+    port: u16,
+    attempts: &mut Vec<(String, u16, IoError)>,
+) -> Option<TcpStream> {
+    for ip in ips {
+        match TcpStream::connect(&SocketAddr::new(ip, port)).await {
+            Ok(stream) => return Some(stream),
+            Err(e) => attempts.push((ip.to_string(), port, e)),
+        }
+    }
+    None
+}
 
 pub async fn connect_to_host(domain: &str, port: u16) -> Result<TcpStream, Error> {
-    let ascii_domain = idna::domain_to_ascii(&domain).map_err(|_| Error::Idna)?;
+    let ascii_domain = BareJid::domain(domain)
+        .domain_to_ascii()
+        .map_err(|_| Error::Idna)?;
 
     if let Ok(ip) = ascii_domain.parse() {
         return Ok(TcpStream::connect(&SocketAddr::new(ip, port)).await?);
@@ -17,24 +36,27 @@ pub async fn connect_to_host(domain: &str, port: u16) -> Result<TcpStream, Error
         .lookup_ip(ascii_domain)
         .await
         .map_err(ConnecterError::Resolve)?;
-    for ip in ips.iter() {
-        match TcpStream::connect(&SocketAddr::new(ip, port)).await {
-            Ok(stream) => return Ok(stream),
-            Err(_) => {}
-        }
+    let mut attempts = Vec::new();
+    match connect_to_ips(ips.iter(), port, &mut attempts).await {
+        Some(stream) => Ok(stream),
+        None => Err(ConnecterError::AllFailed(attempts).into()),
     }
-    Err(Error::Disconnected)
 }
 
+/// Connects to the host of the `srv` records of `domain`, or to `domain`
+/// on `fallback_port` if there is none, through `proxy` if it applies
 pub async fn connect_with_srv(
     domain: &str,
     srv: &str,
     fallback_port: u16,
+    proxy: Option<&ProxyConfig>,
 ) -> Result<TcpStream, Error> {
-    let ascii_domain = idna::domain_to_ascii(&domain).map_err(|_| Error::Idna)?;
+    let ascii_domain = BareJid::domain(domain)
+        .domain_to_ascii()
+        .map_err(|_| Error::Idna)?;
 
-    if let Ok(ip) = ascii_domain.parse() {
-        return Ok(TcpStream::connect(&SocketAddr::new(ip, fallback_port)).await?);
+    if ascii_domain.parse::<IpAddr>().is_ok() {
+        return proxy::connect(proxy, &ascii_domain, fallback_port).await;
     }
 
     let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(ConnecterError::Resolve)?;
@@ -47,17 +69,83 @@ pub async fn connect_with_srv(
     match srv_records {
         Some(lookup) => {
             // TODO: sort lookup records by priority/weight
+            let mut attempts = Vec::new();
             for srv in lookup.iter() {
-                match connect_to_host(&srv.target().to_ascii(), srv.port()).await {
-                    Ok(stream) => return Ok(stream),
-                    Err(_) => {}
+                let target = srv.target().to_ascii();
+                let host = target.trim_end_matches('.');
+                if proxy::applies(proxy, host) {
+                    // The proxy resolves the target itself.
+                    match proxy::connect(proxy, host, srv.port()).await {
+                        Ok(stream) => return Ok(stream),
+                        // Other targets won't get through either.
+                        Err(Error::Connection(ConnecterError::Proxy(ProxyError::AuthRequired))) => {
+                            return Err(ConnecterError::Proxy(ProxyError::AuthRequired).into())
+                        }
+                        Err(e) => {
+                            attempts.push((target, srv.port(), IoError::other(e.to_string())))
+                        }
+                    }
+                    continue;
+                }
+                match resolver.lookup_ip(target.as_str()).await {
+                    Ok(ips) => {
+                        let stream = connect_to_ips(ips.iter(), srv.port(), &mut attempts).await;
+                        if let Some(stream) = stream {
+                            return Ok(stream);
+                        }
+                    }
+                    // The other targets may still resolve.
+                    Err(e) => attempts.push((
+                        target,
+                        srv.port(),
+                        IoError::new(ErrorKind::NotFound, e.to_string()),
+                    )),
                 }
             }
-            Err(Error::Disconnected)
+            Err(ConnecterError::AllFailed(attempts).into())
         }
         None => {
             // SRV lookup error, retry with hostname
-            connect_to_host(domain, fallback_port).await
+            proxy::connect(proxy, domain, fallback_port).await
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_attempts() {
+        // A port nobody listens on anymore.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        // An IP address fails with the I/O error itself, a name with every address
+        // it resolves to.
+        assert!(matches!(
+            connect_to_host("127.0.0.1", port).await,
+            Err(Error::Io(_))
+        ));
+        let mut attempts = Vec::new();
+        let localhost = IpAddr::from([127, 0, 0, 1]);
+        assert!(
+            connect_to_ips(vec![localhost, localhost], port, &mut attempts)
+                .await
+                .is_none()
+        );
+        assert_eq!(attempts.len(), 2);
+        let (host, attempted_port, error) = &attempts[0];
+        assert_eq!(host, "127.0.0.1");
+        assert_eq!(*attempted_port, port);
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+
+        let error = ConnecterError::AllFailed(attempts);
+        assert!(error.to_string().starts_with(&format!(
+            "all connection attempts failed: 127.0.0.1:{}: ",
+            port
+        )));
+    }
+}
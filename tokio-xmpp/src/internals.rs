@@ -0,0 +1,44 @@
+//! Introspection of the timers and queues of a client, so that tests
+//! running it under tokio's paused time can assert on them instead of
+//! sleeping
+//!
+//! Only built with the `test-internals` feature.
+
+use tokio::time::Instant;
+
+/// What a timer of a [`Client`](crate::AsyncClient) is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerKind {
+    /// Sending a whitespace keepalive, the output having stayed silent
+    Keepalive,
+    /// Pinging the server, or giving up on a ping it didn't answer
+    Ping,
+    /// Giving up on a stanza which started being received, but isn't
+    /// complete yet
+    StanzaTimeout,
+    /// Reading again, once paused by the inbound rate limit
+    InboundDelay,
+    /// The next connection attempt, backed off after failures
+    Reconnect,
+}
+
+/// A pending timer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timer {
+    /// What it is for
+    pub kind: TimerKind,
+    /// When it fires
+    pub deadline: Instant,
+}
+
+/// How much is waiting in the queues of a client
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueueDepths {
+    /// Packets encoded but not put together to be written yet
+    pub outgoing: usize,
+    /// Bytes being written to the connection
+    pub unwritten_bytes: usize,
+    /// Events received while waiting for a state transition, to be
+    /// returned first
+    pub pending_events: usize,
+}
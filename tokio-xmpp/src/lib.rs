@@ -2,19 +2,52 @@
 
 #![deny(unsafe_code, missing_docs, bare_trait_objects)]
 
+mod buffer_pool;
+pub use buffer_pool::PoolStats;
 mod starttls;
+mod stream_error;
 mod stream_start;
 mod xmpp_codec;
-pub use crate::xmpp_codec::Packet;
+pub use crate::xmpp_codec::{Packet, StreamHeader};
 mod event;
 pub use event::Event;
+pub mod capture;
 mod client;
 mod happy_eyeballs;
+mod proxy;
+pub use proxy::{Basic, ProxyConfig};
+mod socks5;
+pub use socks5::Socks5Stream;
 pub mod stream_features;
+mod write_queue;
 pub mod xmpp_stream;
-pub use client::{async_client::Client as AsyncClient, simple_client::Client as SimpleClient};
+pub use client::{
+    async_client::{
+        Client as AsyncClient, Config as AsyncConfig, ServerConfig as AsyncServerConfig,
+    },
+    credentials_provider,
+    simple_client::Client as SimpleClient,
+    stream_feature_handler, AsyncReadAndWrite, BindConflictPolicy, BoxedStream,
+    CredentialsProvider, InboundPolicy, Negotiated, PingPolicy, ReconnectPolicy, Secret,
+    StreamFeatureHandler, StreamFeatureHook,
+};
 mod component;
 pub use crate::component::Component;
 mod error;
-pub use crate::error::{AuthError, ConnecterError, Error, ParseError, ParserError, ProtocolError};
+pub use crate::error::{
+    AuthError, ConnecterError, Error, ParseError, ParserError, ProtocolError, ProxyError,
+    SharedError, Socks5Error,
+};
 pub use starttls::starttls;
+#[cfg(feature = "compression")]
+mod compression;
+#[cfg(feature = "compression")]
+pub use compression::{compress, ZlibStream};
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "test-internals")]
+pub mod internals;
+#[cfg(any(test, feature = "mini-server"))]
+pub mod mini_server;
+#[cfg(any(test, feature = "test-harness"))]
+pub mod test_harness;
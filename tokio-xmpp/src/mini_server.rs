@@ -0,0 +1,989 @@
+//! A minimal XMPP server, to run the examples and integration tests
+//! against on this machine, without an account anywhere
+//!
+//! It implements just enough of RFC 6120 to exercise a client end to
+//! end: PLAIN authentication against the users it was given, resource
+//! binding, routing of stanzas between the connected resources, ping
+//! and disco#info, and optionally a single room with presence fan-out.
+//! There is no TLS, so it only ever listens on the loopback interface,
+//! where clients reach it with
+//! [`ServerConfig::Loopback`](crate::AsyncServerConfig::Loopback).
+//! Nothing gets stored: no rosters, no offline messages.
+//!
+//! Only built with the `mini-server` feature, it isn't meant for
+//! anything but development.
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use tokio_xmpp::mini_server::MiniServer;
+//!
+//! let server = MiniServer::new("localhost")
+//!     .user("bot", "bot")
+//!     .user("user", "user")
+//!     .room("lounge");
+//! let (port, server) = server.listen(5222).await?;
+//! println!("Listening on port {}", port);
+//! server.await
+//! # }
+//! ```
+
+use futures::{sink::SinkExt, stream::StreamExt, Future};
+use log::debug;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::io;
+use std::net::Ipv4Addr;
+use std::rc::Rc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::LocalSet;
+use tokio_util::codec::Framed;
+use xmpp_parsers::{
+    disco::{DiscoInfoResult, Feature, Identity},
+    iq::{Iq, IqType},
+    message::{Message, MessageType, Subject},
+    muc::user::{Affiliation, Item as MucItem, MucUser, Role, Status},
+    ns,
+    presence::{Presence, Type as PresenceType},
+    roster::Roster,
+    sasl::{Auth, DefinedCondition as SaslCondition, Failure, Mechanism, Success},
+    stanza_error::{DefinedCondition, ErrorType, StanzaError},
+    BareJid, Element, FullJid, Jid,
+};
+
+use crate::xmpp_codec::{Packet, StreamHeader, XMPPCodec};
+use crate::{Error, ProtocolError};
+
+type ServerStream<S> = Framed<S, XMPPCodec>;
+
+/// The configuration of a server, see the [module
+/// documentation](self)
+#[derive(Debug, Clone)]
+pub struct MiniServer {
+    domain: String,
+    users: HashMap<String, String>,
+    room: Option<BareJid>,
+}
+
+impl MiniServer {
+    /// A server for `domain`, with no user yet
+    pub fn new(domain: &str) -> Self {
+        MiniServer {
+            domain: String::from(domain),
+            users: HashMap::new(),
+            room: None,
+        }
+    }
+
+    /// Let `name` log in with `password`
+    pub fn user(mut self, name: &str, password: &str) -> Self {
+        self.users
+            .insert(String::from(name), String::from(password));
+        self
+    }
+
+    /// Host a room named `name`, as `name@conference.<domain>`
+    pub fn room(mut self, name: &str) -> Self {
+        let domain = format!("conference.{}", self.domain);
+        self.room = Some(BareJid::new(name, &domain));
+        self
+    }
+
+    /// The JID of the room, if there is one
+    pub fn room_jid(&self) -> Option<&BareJid> {
+        self.room.as_ref()
+    }
+
+    /// Listens on `port` of the loopback interface, 0 picking a free
+    /// one, returning it along with the server to run
+    ///
+    /// The server runs until dropped, every connection along with it.
+    pub async fn listen(
+        self,
+        port: u16,
+    ) -> io::Result<(u16, impl Future<Output = io::Result<()>>)> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
+        let port = listener.local_addr()?.port();
+        let state = Rc::new(RefCell::new(State::new(self)));
+        let run = async move {
+            let local = LocalSet::new();
+            local.run_until(accept(listener, state)).await
+        };
+        Ok((port, run))
+    }
+
+    /// The user `data` authenticates as with SASL PLAIN, if it gives
+    /// the right password
+    fn check_plain(&self, data: &[u8]) -> Option<String> {
+        let mut parts = data.split(|byte| *byte == 0);
+        let authzid = std::str::from_utf8(parts.next()?).ok()?;
+        let user = std::str::from_utf8(parts.next()?).ok()?;
+        let password = std::str::from_utf8(parts.next()?).ok()?;
+        if parts.next().is_some() || self.users.get(user)? != password {
+            return None;
+        }
+        if !authzid.is_empty() && authzid != format!("{}@{}", user, self.domain) {
+            return None;
+        }
+        Some(String::from(user))
+    }
+}
+
+async fn accept(listener: TcpListener, state: Rc<RefCell<State>>) -> io::Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let state = state.clone();
+        tokio::task::spawn_local(async move {
+            if let Err(e) = serve(state, socket).await {
+                debug!("Connection closed: {}", e);
+            }
+        });
+    }
+}
+
+/// Where the client wants a stanza to go
+#[derive(Debug, PartialEq)]
+enum Target {
+    /// The server itself
+    Server,
+    /// An account, or the server on its behalf
+    Account(BareJid),
+    /// A resource of an account, bound or not
+    Resource(FullJid),
+    /// The room
+    Room,
+    /// An occupant of the room, by nick
+    Occupant(String),
+    /// Anything not hosted here
+    Unknown,
+}
+
+/// What every connection shares
+#[derive(Debug)]
+struct State {
+    server: MiniServer,
+    /// Where to send the stanzas for each bound resource
+    sessions: HashMap<FullJid, mpsc::UnboundedSender<Element>>,
+    /// The occupants of the room, by nick
+    occupants: BTreeMap<String, FullJid>,
+    next_id: u64,
+}
+
+impl State {
+    fn new(server: MiniServer) -> State {
+        State {
+            server,
+            sessions: HashMap::new(),
+            occupants: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn next_id(&mut self) -> String {
+        self.next_id += 1;
+        format!("mini-{}", self.next_id)
+    }
+
+    /// Bind `resource` for `user`, or another one if it is taken or
+    /// missing
+    fn bind(&mut self, user: &str, resource: Option<String>) -> FullJid {
+        let bare = BareJid::new(user, &self.server.domain);
+        if let Some(resource) = resource {
+            let jid = bare.clone().with_resource(resource);
+            if !self.sessions.contains_key(&jid) {
+                return jid;
+            }
+        }
+        let id = self.next_id();
+        bare.with_resource(id)
+    }
+
+    fn target(&self, to: Option<&Jid>) -> Target {
+        let to = match to {
+            Some(to) => to,
+            None => return Target::Server,
+        };
+        let bare = BareJid::from(to.clone());
+        if Some(&bare) == self.server.room.as_ref() {
+            return match to {
+                Jid::Full(occupant) => Target::Occupant(occupant.resource.clone()),
+                Jid::Bare(_) => Target::Room,
+            };
+        }
+        if bare.domain != self.server.domain {
+            return Target::Unknown;
+        }
+        match (to, &bare.node) {
+            (Jid::Bare(_), None) => Target::Server,
+            (_, Some(node)) if !self.server.users.contains_key(node) => Target::Unknown,
+            (Jid::Full(jid), Some(_)) => Target::Resource(jid.clone()),
+            (Jid::Bare(jid), Some(_)) => Target::Account(jid.clone()),
+            (Jid::Full(_), None) => Target::Unknown,
+        }
+    }
+
+    /// The bound resources of `account`
+    fn resources(&self, account: &BareJid) -> Vec<FullJid> {
+        self.sessions
+            .keys()
+            .filter(|jid| BareJid::from((*jid).clone()) == *account)
+            .cloned()
+            .collect()
+    }
+
+    fn deliver<E: Into<Element>>(&self, to: &FullJid, stanza: E) {
+        if let Some(session) = self.sessions.get(to) {
+            let _ = session.send(stanza.into());
+        }
+    }
+
+    /// Route a stanza `from` sent, whatever it claims to be from
+    fn route(&mut self, from: &FullJid, stanza: Element) {
+        if stanza.is("iq", ns::JABBER_CLIENT) {
+            if let Ok(iq) = Iq::try_from(stanza) {
+                self.route_iq(from, iq.with_from(Jid::Full(from.clone())));
+            }
+        } else if stanza.is("message", ns::JABBER_CLIENT) {
+            if let Ok(mut message) = Message::try_from(stanza) {
+                message.from = Some(Jid::Full(from.clone()));
+                self.route_message(from, message);
+            }
+        } else if stanza.is("presence", ns::JABBER_CLIENT) {
+            if let Ok(presence) = Presence::try_from(stanza) {
+                self.route_presence(from, presence.with_from(Jid::Full(from.clone())));
+            }
+        }
+    }
+
+    fn route_iq(&mut self, from: &FullJid, iq: Iq) {
+        let request = matches!(iq.payload, IqType::Get(_) | IqType::Set(_));
+        let own_account = BareJid::from(from.clone());
+        match self.target(iq.to.as_ref()) {
+            Target::Resource(ref jid) if self.sessions.contains_key(jid) => self.deliver(jid, iq),
+            Target::Server => self.answer(from, iq, None),
+            Target::Account(ref account) if *account == own_account => {
+                self.answer(from, iq, Some(account.clone()))
+            }
+            Target::Room if request => {
+                let answer = match iq.payload {
+                    IqType::Get(ref payload) if payload.is("query", ns::DISCO_INFO) => {
+                        Some(self.room_disco())
+                    }
+                    _ => None,
+                };
+                self.reply(from, iq, answer);
+            }
+            _ if request => self.reply(from, iq, None),
+            _ => (),
+        }
+    }
+
+    /// Answer an iq sent to the server, or to the own account of the
+    /// client
+    fn answer(&mut self, from: &FullJid, iq: Iq, account: Option<BareJid>) {
+        let payload = match iq.payload {
+            IqType::Get(ref payload) => payload,
+            IqType::Set(_) => return self.reply(from, iq, None),
+            IqType::Result(_) | IqType::Error(_) => return,
+        };
+        let answer = if payload.is("ping", ns::PING) {
+            Some(None)
+        } else if payload.is("query", ns::DISCO_INFO) {
+            Some(Some(self.server_disco(account).into()))
+        } else if payload.is("query", ns::ROSTER) {
+            let roster = Roster {
+                ver: None,
+                items: Vec::new(),
+            };
+            Some(Some(roster.into()))
+        } else {
+            None
+        };
+        self.reply(from, iq, answer);
+    }
+
+    /// Answer the request `iq` with `answer`, or with an error if there
+    /// is none
+    fn reply(&mut self, from: &FullJid, iq: Iq, answer: Option<Option<Element>>) {
+        let payload = match answer {
+            Some(payload) => IqType::Result(payload),
+            None => IqType::Error(StanzaError::new(
+                ErrorType::Cancel,
+                DefinedCondition::ServiceUnavailable,
+                "en",
+                "Not supported by this server.",
+            )),
+        };
+        let reply = Iq {
+            from: iq.to,
+            to: Some(Jid::Full(from.clone())),
+            id: iq.id,
+            payload,
+        };
+        self.deliver(from, reply);
+    }
+
+    fn server_disco(&self, account: Option<BareJid>) -> DiscoInfoResult {
+        let identities = match account {
+            Some(account) => vec![Identity::new("account", "registered", "en", account)],
+            None => vec![Identity::new("server", "im", "en", "Mini server")],
+        };
+        DiscoInfoResult {
+            node: None,
+            identities,
+            features: vec![Feature::new(ns::DISCO_INFO), Feature::new(ns::PING)],
+            extensions: Vec::new(),
+            unknown: Vec::new(),
+        }
+    }
+
+    fn room_disco(&self) -> Option<Element> {
+        let room = self.server.room.as_ref()?;
+        let name = room.node.clone().unwrap_or_default();
+        let disco = DiscoInfoResult {
+            node: None,
+            identities: vec![Identity::new("conference", "text", "en", name)],
+            features: vec![Feature::new(ns::DISCO_INFO), Feature::new(ns::MUC)],
+            extensions: Vec::new(),
+            unknown: Vec::new(),
+        };
+        Some(disco.into())
+    }
+
+    fn route_message(&mut self, from: &FullJid, message: Message) {
+        let recipients = match self.target(message.to.as_ref()) {
+            Target::Resource(ref jid) if self.sessions.contains_key(jid) => vec![jid.clone()],
+            // Like for the bare JID when the resource is gone.
+            Target::Resource(jid) => self.resources(&BareJid::from(jid)),
+            Target::Account(ref account) => self.resources(account),
+            Target::Server if message.to.is_none() => self.resources(&from.clone().into()),
+            Target::Room if message.type_ == MessageType::Groupchat => {
+                return self.groupchat(from, message)
+            }
+            Target::Occupant(ref nick) => return self.private_message(from, nick, message),
+            _ => Vec::new(),
+        };
+        if recipients.is_empty() {
+            return self.bounce(from, message, DefinedCondition::ServiceUnavailable);
+        }
+        for recipient in recipients {
+            self.deliver(&recipient, message.clone());
+        }
+    }
+
+    fn groupchat(&mut self, from: &FullJid, mut message: Message) {
+        let nick = match self.nick(from) {
+            Some(nick) => nick,
+            None => return self.bounce(from, message, DefinedCondition::NotAcceptable),
+        };
+        message.from = Some(Jid::Full(self.occupant_jid(&nick)));
+        for occupant in self.occupants.values() {
+            let mut message = message.clone();
+            message.to = Some(Jid::Full(occupant.clone()));
+            self.deliver(occupant, message);
+        }
+    }
+
+    fn private_message(&mut self, from: &FullJid, to: &str, mut message: Message) {
+        let recipient = self.occupants.get(to).cloned();
+        let (nick, recipient) = match (self.nick(from), recipient) {
+            (Some(nick), Some(recipient)) => (nick, recipient),
+            _ => return self.bounce(from, message, DefinedCondition::ItemNotFound),
+        };
+        message.from = Some(Jid::Full(self.occupant_jid(&nick)));
+        message.to = Some(Jid::Full(recipient.clone()));
+        self.deliver(&recipient, message);
+    }
+
+    /// Send `message` back to `from`, with `condition`
+    fn bounce(&mut self, from: &FullJid, message: Message, condition: DefinedCondition) {
+        if message.type_ == MessageType::Error {
+            return;
+        }
+        let error = StanzaError::new(ErrorType::Cancel, condition, "en", "Undeliverable.");
+        let mut bounce = Message::new(Some(Jid::Full(from.clone())));
+        bounce.from = message.to;
+        bounce.id = message.id;
+        bounce.type_ = MessageType::Error;
+        bounce.payloads.push(error.into());
+        self.deliver(from, bounce);
+    }
+
+    fn route_presence(&mut self, from: &FullJid, presence: Presence) {
+        match self.target(presence.to.as_ref()) {
+            // Without rosters, only our other resources get it.
+            Target::Server if presence.to.is_none() => {
+                for resource in self.resources(&from.clone().into()) {
+                    if resource != *from {
+                        self.deliver(&resource, presence.clone());
+                    }
+                }
+            }
+            Target::Resource(ref jid) => self.deliver(jid, presence),
+            Target::Account(ref account) => {
+                for resource in self.resources(account) {
+                    self.deliver(&resource, presence.clone());
+                }
+            }
+            Target::Occupant(nick) => match presence.type_ {
+                PresenceType::None => self.join(from, nick, presence),
+                PresenceType::Unavailable => self.leave(from),
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    fn nick(&self, jid: &FullJid) -> Option<String> {
+        self.occupants
+            .iter()
+            .find(|(_, occupant)| *occupant == jid)
+            .map(|(nick, _)| nick.clone())
+    }
+
+    fn occupant_jid(&self, nick: &str) -> FullJid {
+        let room = self.server.room.clone().unwrap();
+        room.with_resource(nick)
+    }
+
+    /// The presence of `nick` in the room, for the occupant `to`
+    fn occupant_presence(&self, nick: &str, to: &FullJid, type_: PresenceType) -> Presence {
+        let mut status = Vec::new();
+        if self.occupants.get(nick) == Some(to) {
+            status.push(Status::SelfPresence);
+        }
+        let muc_user = MucUser {
+            status,
+            items: vec![MucItem::new(Affiliation::None, Role::Participant)],
+            invites: Vec::new(),
+            password: None,
+            unknown: Vec::new(),
+        };
+        let mut presence = Presence::new(type_)
+            .with_from(Jid::Full(self.occupant_jid(nick)))
+            .with_to(Jid::Full(to.clone()));
+        presence.payloads.push(muc_user.into());
+        presence
+    }
+
+    fn join(&mut self, from: &FullJid, nick: String, presence: Presence) {
+        let already_in = self.nick(from);
+        let taken = match self.occupants.get(&nick) {
+            Some(occupant) => occupant != from,
+            None => already_in.is_some(),
+        };
+        if taken {
+            let error = StanzaError::new(
+                ErrorType::Cancel,
+                DefinedCondition::Conflict,
+                "en",
+                "This nick is already taken.",
+            );
+            let mut refusal = Presence::new(PresenceType::Error)
+                .with_from(Jid::Full(self.occupant_jid(&nick)))
+                .with_to(Jid::Full(from.clone()));
+            refusal.id = presence.id;
+            refusal.payloads.push(error.into());
+            return self.deliver(from, refusal);
+        }
+        if already_in.is_none() {
+            for (other, _) in self.occupants.iter() {
+                self.deliver(
+                    from,
+                    self.occupant_presence(other, from, PresenceType::None),
+                );
+            }
+        }
+        self.occupants.insert(nick.clone(), from.clone());
+        for occupant in self.occupants.values() {
+            let mut update = self.occupant_presence(&nick, occupant, PresenceType::None);
+            update.show = presence.show.clone();
+            update.statuses = presence.statuses.clone();
+            self.deliver(occupant, update);
+        }
+        if already_in.is_none() {
+            let room = self.server.room.clone().unwrap();
+            let mut subject = Message::new(Some(Jid::Full(from.clone())));
+            subject.from = Some(Jid::Bare(room));
+            subject.type_ = MessageType::Groupchat;
+            subject
+                .subjects
+                .insert(String::new(), Subject(String::new()));
+            self.deliver(from, subject);
+        }
+    }
+
+    fn leave(&mut self, from: &FullJid) {
+        let nick = match self.nick(from) {
+            Some(nick) => nick,
+            None => return,
+        };
+        for occupant in self.occupants.values() {
+            let update = self.occupant_presence(&nick, occupant, PresenceType::Unavailable);
+            self.deliver(occupant, update);
+        }
+        self.occupants.remove(&nick);
+    }
+
+    /// The connection of `jid` is gone
+    fn disconnected(&mut self, jid: &FullJid) {
+        self.leave(jid);
+        self.sessions.remove(jid);
+    }
+}
+
+/// Serve one client, from its stream header to its end
+async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    state: Rc<RefCell<State>>,
+    stream: S,
+) -> Result<(), Error> {
+    let mut stream = Framed::new(stream, XMPPCodec::new());
+    let mechanisms = Element::builder("mechanisms", ns::SASL)
+        .append(Element::builder("mechanism", ns::SASL).append("PLAIN"))
+        .build();
+    if !start_stream(&mut stream, &state, mechanisms).await? {
+        return Ok(());
+    }
+    let user = match authenticate(&mut stream, &state).await? {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+
+    // The client restarts the stream, with a new parser.
+    let mut stream = Framed::new(stream.into_inner(), XMPPCodec::new());
+    if !start_stream(&mut stream, &state, Element::bare("bind", ns::BIND)).await? {
+        return Ok(());
+    }
+    let jid = match bind(&mut stream, &state, &user).await? {
+        Some(jid) => jid,
+        None => return Ok(()),
+    };
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    state.borrow_mut().sessions.insert(jid.clone(), sender);
+    let result = async {
+        loop {
+            tokio::select! {
+                stanza = next_stanza(&mut stream) => match stanza? {
+                    Some(stanza) => state.borrow_mut().route(&jid, stanza),
+                    None => break,
+                },
+                Some(stanza) = receiver.recv() => stream.send(Packet::Stanza(stanza)).await?,
+            }
+        }
+        stream.send(Packet::StreamEnd).await?;
+        Ok(())
+    }
+    .await;
+    state.borrow_mut().disconnected(&jid);
+    result
+}
+
+/// Answers the stream header of the client, and offers `feature`,
+/// returning whether the client can go on
+async fn start_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut ServerStream<S>,
+    state: &RefCell<State>,
+    feature: Element,
+) -> Result<bool, Error> {
+    let received = loop {
+        match stream.next().await {
+            Some(Ok(Packet::StreamStart(header))) => break header,
+            Some(Ok(Packet::Text(_))) => (),
+            Some(Ok(_)) => return Err(ProtocolError::InvalidToken.into()),
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err(Error::Disconnected),
+        }
+    };
+    let (domain, id) = {
+        let mut state = state.borrow_mut();
+        (state.server.domain.clone(), state.next_id())
+    };
+    let header = StreamHeader {
+        ns: Some(String::from(ns::JABBER_CLIENT)),
+        id: Some(id),
+        from: Some(domain.clone()),
+        version: Some(String::from("1.0")),
+        ..StreamHeader::default()
+    };
+    stream.send(Packet::StreamStart(header)).await?;
+    if received.to.as_ref() != Some(&domain) {
+        let error = Element::builder("error", ns::STREAM)
+            .append(Element::bare("host-unknown", ns::XMPP_STREAMS))
+            .build();
+        stream.send(Packet::Stanza(error)).await?;
+        stream.send(Packet::StreamEnd).await?;
+        return Ok(false);
+    }
+    let features = Element::builder("features", ns::STREAM)
+        .append(feature)
+        .build();
+    stream.send(Packet::Stanza(features)).await?;
+    Ok(true)
+}
+
+/// The next stanza of the client, `None` once it closed its stream
+async fn next_stanza<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut ServerStream<S>,
+) -> Result<Option<Element>, Error> {
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) => return Ok(Some(stanza)),
+            Some(Ok(Packet::Text(_))) => (),
+            Some(Ok(Packet::StreamEnd)) => return Ok(None),
+            Some(Ok(Packet::StreamStart(_))) => {
+                return Err(ProtocolError::InvalidStreamStart.into())
+            }
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err(Error::Disconnected),
+        }
+    }
+}
+
+/// Authenticates the client with SASL PLAIN, letting it retry until it
+/// gives up, returning the user it authenticated as
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut ServerStream<S>,
+    state: &RefCell<State>,
+) -> Result<Option<String>, Error> {
+    while let Some(stanza) = next_stanza(stream).await? {
+        let condition = match Auth::try_from(stanza) {
+            Ok(Auth {
+                mechanism: Mechanism::Plain,
+                data,
+            }) => match check_plain(state, &data) {
+                Some(user) => {
+                    let success = Element::from(Success { data: Vec::new() });
+                    stream.send(Packet::Stanza(success)).await?;
+                    return Ok(Some(user));
+                }
+                None => SaslCondition::NotAuthorized,
+            },
+            Ok(_) => SaslCondition::InvalidMechanism,
+            Err(_) => SaslCondition::MalformedRequest,
+        };
+        let failure = Failure {
+            defined_condition: condition,
+            texts: BTreeMap::new(),
+        };
+        stream.send(Packet::Stanza(failure.into())).await?;
+    }
+    stream.send(Packet::StreamEnd).await?;
+    Ok(None)
+}
+
+fn check_plain(state: &RefCell<State>, data: &[u8]) -> Option<String> {
+    state.borrow().server.check_plain(data)
+}
+
+/// Binds a resource for `user`, the one it asks for if available
+async fn bind<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut ServerStream<S>,
+    state: &RefCell<State>,
+    user: &str,
+) -> Result<Option<FullJid>, Error> {
+    let request = match next_stanza(stream).await? {
+        Some(request) => request,
+        None => {
+            stream.send(Packet::StreamEnd).await?;
+            return Ok(None);
+        }
+    };
+    let bind = request
+        .get_child("bind", ns::BIND)
+        .filter(|_| request.is("iq", ns::JABBER_CLIENT) && request.attr("type") == Some("set"))
+        .ok_or(ProtocolError::InvalidToken)?;
+    let resource = bind
+        .get_child("resource", ns::BIND)
+        .map(Element::text)
+        .filter(|resource| !resource.is_empty());
+    let jid = state.borrow_mut().bind(user, resource);
+    let bound = Element::builder("bind", ns::BIND)
+        .append(Element::builder("jid", ns::BIND).append(String::from(jid.clone())))
+        .build();
+    let result = Element::builder("iq", ns::JABBER_CLIENT)
+        .attr("type", "result")
+        .attr("id", request.attr("id"))
+        .append(bound)
+        .build();
+    stream.send(Packet::Stanza(result)).await?;
+    Ok(Some(jid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use tokio::io::duplex;
+    use tokio::task::JoinHandle;
+
+    use crate::client::async_client::login;
+    use crate::client::{BindConflictPolicy, Secret};
+    use crate::xmpp_stream::XMPPStream;
+
+    fn server() -> MiniServer {
+        MiniServer::new("localhost")
+            .user("juliet", "r0m30")
+            .user("romeo", "jul137")
+            .room("balcony")
+    }
+
+    fn jid(jid: &str) -> FullJid {
+        FullJid::from_str(jid).unwrap()
+    }
+
+    fn stanza(xml: &str) -> Element {
+        xml.parse().unwrap()
+    }
+
+    type Client = XMPPStream<crate::client::BoxedStream>;
+
+    /// A client logged in on `state`, along with the task serving its
+    /// connection, to be run in a `LocalSet`
+    async fn connect(
+        state: &Rc<RefCell<State>>,
+        jid: &str,
+        password: &str,
+    ) -> (Result<Client, Error>, JoinHandle<Result<(), Error>>) {
+        let (client, server) = duplex(65536);
+        let serving = tokio::task::spawn_local(serve(state.clone(), server));
+        let stream = login(
+            client,
+            Jid::from_str(jid).unwrap(),
+            Secret::Password(String::from(password)),
+            None,
+            &BindConflictPolicy::default(),
+            &[],
+        )
+        .await;
+        (stream.map(|(stream, _)| stream), serving)
+    }
+
+    async fn next(stream: &mut Client) -> Element {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) => stanza,
+            packet => panic!("Unexpected packet {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn test_plain() {
+        let server = server();
+        assert_eq!(
+            server.check_plain(b"\0juliet\0r0m30").as_deref(),
+            Some("juliet")
+        );
+        assert_eq!(
+            server
+                .check_plain(b"juliet@localhost\0juliet\0r0m30")
+                .as_deref(),
+            Some("juliet")
+        );
+        assert_eq!(server.check_plain(b"\0juliet\0jul137"), None);
+        assert_eq!(server.check_plain(b"romeo@localhost\0juliet\0r0m30"), None);
+        assert_eq!(server.check_plain(b"\0tybalt\0"), None);
+        assert_eq!(server.check_plain(b"juliet\0r0m30"), None);
+    }
+
+    #[test]
+    fn test_targets() {
+        let state = State::new(server());
+        let target = |to: &str| state.target(Some(&Jid::from_str(to).unwrap()));
+        assert_eq!(state.target(None), Target::Server);
+        assert_eq!(target("localhost"), Target::Server);
+        assert_eq!(
+            target("juliet@localhost"),
+            Target::Account(BareJid::from_str("juliet@localhost").unwrap())
+        );
+        assert_eq!(
+            target("juliet@localhost/balcony"),
+            Target::Resource(jid("juliet@localhost/balcony"))
+        );
+        assert_eq!(target("balcony@conference.localhost"), Target::Room);
+        assert_eq!(
+            target("balcony@conference.localhost/nurse"),
+            Target::Occupant(String::from("nurse"))
+        );
+        assert_eq!(target("tybalt@localhost"), Target::Unknown);
+        assert_eq!(target("juliet@verona.lit"), Target::Unknown);
+    }
+
+    #[test]
+    fn test_bind() {
+        let mut state = State::new(server());
+        let bound = state.bind("juliet", Some(String::from("balcony")));
+        assert_eq!(bound, jid("juliet@localhost/balcony"));
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        state.sessions.insert(bound.clone(), sender);
+        // Taken, or missing, another one gets picked.
+        let other = state.bind("juliet", Some(String::from("balcony")));
+        assert_ne!(other, bound);
+        assert_eq!(other.node.as_deref(), Some("juliet"));
+        assert_ne!(state.bind("juliet", None), other);
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let state = Rc::new(RefCell::new(State::new(server())));
+        LocalSet::new()
+            .run_until(async {
+                let (stream, _) = connect(&state, "juliet@localhost/balcony", "r0m30").await;
+                assert_eq!(
+                    stream.unwrap().jid,
+                    Jid::Full(jid("juliet@localhost/balcony"))
+                );
+                assert_eq!(state.borrow().sessions.len(), 1);
+
+                let (stream, _) = connect(&state, "juliet@localhost", "wrong").await;
+                assert!(matches!(stream, Err(Error::Auth(_))));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_unknown_host() {
+        let state = Rc::new(RefCell::new(State::new(server())));
+        let (client, server) = duplex(65536);
+        let client = XMPPStream::start(
+            client,
+            Jid::from_str("juliet@verona.lit").unwrap(),
+            String::from(ns::JABBER_CLIENT),
+        );
+        let (client, served) = tokio::join!(client, serve(state, server));
+        assert!(client.is_err());
+        served.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_routing() {
+        let state = Rc::new(RefCell::new(State::new(server())));
+        LocalSet::new()
+            .run_until(async {
+                let (juliet, juliet_serving) =
+                    connect(&state, "juliet@localhost/balcony", "r0m30").await;
+                let (romeo, _) = connect(&state, "romeo@localhost/orchard", "jul137").await;
+                let (mut juliet, mut romeo) = (juliet.unwrap(), romeo.unwrap());
+
+                juliet
+                    .send_stanza(stanza("<iq xmlns='jabber:client' type='get' id='p' to='localhost'><ping xmlns='urn:xmpp:ping'/></iq>"))
+                    .await
+                    .unwrap();
+                let pong = next(&mut juliet).await;
+                assert_eq!(pong.attr("type"), Some("result"));
+                assert_eq!(pong.attr("id"), Some("p"));
+
+                // Messages to the bare JID reach every resource, and carry
+                // the real sender.
+                juliet
+                    .send_stanza(stanza("<message xmlns='jabber:client' to='romeo@localhost' from='tybalt@localhost' type='chat'><body>Hi!</body></message>"))
+                    .await
+                    .unwrap();
+                let message = next(&mut romeo).await;
+                assert_eq!(message.attr("from"), Some("juliet@localhost/balcony"));
+                assert_eq!(message.attr("to"), Some("romeo@localhost"));
+
+                // Nobody else is there.
+                juliet
+                    .send_stanza(stanza("<message xmlns='jabber:client' to='nurse@localhost' id='m1' type='chat'><body>Hi!</body></message>"))
+                    .await
+                    .unwrap();
+                let bounce = next(&mut juliet).await;
+                assert_eq!(bounce.attr("type"), Some("error"));
+                assert_eq!(bounce.attr("id"), Some("m1"));
+
+                // Iqs for a resource get to it, their results back.
+                romeo
+                    .send_stanza(stanza("<iq xmlns='jabber:client' type='get' id='v' to='juliet@localhost/balcony'><query xmlns='jabber:iq:version'/></iq>"))
+                    .await
+                    .unwrap();
+                let request = next(&mut juliet).await;
+                assert_eq!(request.attr("from"), Some("romeo@localhost/orchard"));
+                juliet
+                    .send_stanza(stanza("<iq xmlns='jabber:client' type='result' id='v' to='romeo@localhost/orchard'/>"))
+                    .await
+                    .unwrap();
+                let result = next(&mut romeo).await;
+                assert_eq!(result.attr("type"), Some("result"));
+
+                // Closing the stream ends the session.
+                juliet.send(Packet::StreamEnd).await.unwrap();
+                juliet_serving.await.unwrap().unwrap();
+                assert_eq!(state.borrow().sessions.len(), 1);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_room() {
+        let state = Rc::new(RefCell::new(State::new(server())));
+        let join = |nick: &str| {
+            stanza(&format!(
+                "<presence xmlns='jabber:client' to='balcony@conference.localhost/{}'><x xmlns='http://jabber.org/protocol/muc'/></presence>",
+                nick
+            ))
+        };
+        LocalSet::new()
+            .run_until(async {
+                let (juliet, _) = connect(&state, "juliet@localhost/balcony", "r0m30").await;
+                let (romeo, romeo_serving) =
+                    connect(&state, "romeo@localhost/orchard", "jul137").await;
+                let (mut juliet, mut romeo) = (juliet.unwrap(), romeo.unwrap());
+
+                juliet.send_stanza(join("juliet")).await.unwrap();
+                let own = next(&mut juliet).await;
+                assert_eq!(own.attr("from"), Some("balcony@conference.localhost/juliet"));
+                let muc_user = own.get_child("x", ns::MUC_USER).unwrap();
+                let status = muc_user.get_child("status", ns::MUC_USER).unwrap();
+                assert_eq!(status.attr("code"), Some("110"));
+                let subject = next(&mut juliet).await;
+                assert!(subject.get_child("subject", ns::JABBER_CLIENT).is_some());
+
+                // The nick is taken.
+                romeo.send_stanza(join("juliet")).await.unwrap();
+                let refused = next(&mut romeo).await;
+                assert_eq!(refused.attr("type"), Some("error"));
+
+                // The newcomer first learns about who is there, then
+                // everyone about the newcomer.
+                romeo.send_stanza(join("romeo")).await.unwrap();
+                let existing = next(&mut romeo).await;
+                assert_eq!(
+                    existing.attr("from"),
+                    Some("balcony@conference.localhost/juliet")
+                );
+                let own = next(&mut romeo).await;
+                assert_eq!(own.attr("from"), Some("balcony@conference.localhost/romeo"));
+                next(&mut romeo).await;
+                let arrival = next(&mut juliet).await;
+                assert_eq!(
+                    arrival.attr("from"),
+                    Some("balcony@conference.localhost/romeo")
+                );
+
+                romeo
+                    .send_stanza(stanza("<message xmlns='jabber:client' to='balcony@conference.localhost' type='groupchat'><body>Hi!</body></message>"))
+                    .await
+                    .unwrap();
+                for stream in [&mut juliet, &mut romeo] {
+                    let message = next(stream).await;
+                    assert_eq!(
+                        message.attr("from"),
+                        Some("balcony@conference.localhost/romeo")
+                    );
+                }
+
+                // Disconnecting leaves the room.
+                drop(romeo);
+                assert!(romeo_serving.await.unwrap().is_err());
+                let departure = next(&mut juliet).await;
+                assert_eq!(departure.attr("type"), Some("unavailable"));
+                assert_eq!(
+                    state.borrow().occupants.keys().collect::<Vec<_>>(),
+                    ["juliet"]
+                );
+            })
+            .await;
+    }
+}
@@ -0,0 +1,222 @@
+//! XEP-0065: SOCKS5 Bytestreams, the connection to a streamhost
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use xmpp_parsers::bytestreams::StreamHost;
+
+use crate::happy_eyeballs::connect_to_host;
+use crate::{ConnecterError, Error, Socks5Error};
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 1;
+const SUCCEEDED: u8 = 0;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+/// A bytestream through a SOCKS5 streamhost, either the other side
+/// itself or a proxy relaying between both sides
+#[derive(Debug)]
+pub struct Socks5Stream {
+    stream: TcpStream,
+}
+
+impl Socks5Stream {
+    /// Connect to `streamhost`, and ask it for the bytestream of
+    /// `dstaddr`, as computed by [`xmpp_parsers::bytestreams::dstaddr`]
+    ///
+    /// A proxy only relays once the initiator activated the bytestream,
+    /// which it can only do after the target connected.
+    pub async fn connect(streamhost: &StreamHost, dstaddr: &str) -> Result<Socks5Stream, Error> {
+        if dstaddr.len() > u8::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "dstaddr too long").into());
+        }
+        let mut stream = connect_to_host(&streamhost.host, streamhost.port).await?;
+
+        stream.write_all(&[VERSION, 1, NO_AUTHENTICATION]).await?;
+        let mut method = [0; 2];
+        stream.read_exact(&mut method).await?;
+        match method {
+            [VERSION, NO_AUTHENTICATION] => (),
+            [VERSION, NO_ACCEPTABLE_METHOD] => {
+                return Err(ConnecterError::from(Socks5Error::NoAcceptableMethod).into())
+            }
+            _ => return Err(ConnecterError::from(Socks5Error::InvalidResponse).into()),
+        }
+
+        // The port is always 0, the address being enough to tell
+        // bytestreams apart.
+        let mut request = vec![VERSION, CONNECT, 0, DOMAIN_NAME, dstaddr.len() as u8];
+        request.extend_from_slice(dstaddr.as_bytes());
+        request.extend_from_slice(&[0, 0]);
+        stream.write_all(&request).await?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        match reply {
+            [VERSION, SUCCEEDED, _, _] => (),
+            [VERSION, code, _, _] => {
+                return Err(ConnecterError::from(Socks5Error::Refused(code)).into())
+            }
+            _ => return Err(ConnecterError::from(Socks5Error::InvalidResponse).into()),
+        }
+        // Skip the bound address and port, which don't matter here.
+        let address_len = match reply[3] {
+            IPV4 => 4,
+            IPV6 => 16,
+            DOMAIN_NAME => stream.read_u8().await? as usize,
+            _ => return Err(ConnecterError::from(Socks5Error::InvalidResponse).into()),
+        };
+        let mut bound = vec![0; address_len + 2];
+        stream.read_exact(&mut bound).await?;
+
+        Ok(Socks5Stream { stream })
+    }
+
+    /// The TCP connection to the streamhost
+    pub fn into_inner(self) -> TcpStream {
+        self.stream
+    }
+}
+
+impl AsyncRead for Socks5Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Socks5Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use tokio::net::TcpListener;
+    use xmpp_parsers::bytestreams::{dstaddr, StreamId};
+    use xmpp_parsers::Jid;
+
+    /// Accept a connection the way a streamhost does, answering
+    /// `method` to the greeting and `code` to the `CONNECT`, returning
+    /// the connection and the address it asked for
+    async fn accept(listener: &TcpListener, method: u8, code: u8) -> (TcpStream, String) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut greeting = [0; 3];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [VERSION, 1, NO_AUTHENTICATION]);
+        stream.write_all(&[VERSION, method]).await.unwrap();
+        if method != NO_AUTHENTICATION {
+            return (stream, String::new());
+        }
+        let mut header = [0; 5];
+        stream.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[..4], [VERSION, CONNECT, 0, DOMAIN_NAME]);
+        let mut address = vec![0; header[4] as usize + 2];
+        stream.read_exact(&mut address).await.unwrap();
+        assert_eq!(address[address.len() - 2..], [0, 0]);
+        address.truncate(address.len() - 2);
+        // Streamhosts echo the address back as the bound one.
+        let mut reply = vec![VERSION, code, 0, DOMAIN_NAME, address.len() as u8];
+        reply.extend_from_slice(&address);
+        reply.extend_from_slice(&[0, 0]);
+        stream.write_all(&reply).await.unwrap();
+        (stream, String::from_utf8(address).unwrap())
+    }
+
+    async fn listen() -> (TcpListener, StreamHost) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let jid = Jid::from_str("streamer.example.com").unwrap();
+        (listener, StreamHost::new(jid, "127.0.0.1", port))
+    }
+
+    fn example_dstaddr() -> String {
+        dstaddr(
+            &StreamId(String::from("vxf9n471bn46")),
+            &Jid::from_str("requester@example.com/foo").unwrap(),
+            &Jid::from_str("target@example.org/bar").unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let (listener, streamhost) = listen().await;
+        let dstaddr = example_dstaddr();
+        // A proxy relaying between the two connections for the same address.
+        let proxy = tokio::spawn(async move {
+            let (mut target, first) = accept(&listener, NO_AUTHENTICATION, SUCCEEDED).await;
+            let (mut requester, second) = accept(&listener, NO_AUTHENTICATION, SUCCEEDED).await;
+            assert_eq!(first, second);
+            // Either side may have closed abruptly by the end.
+            let _ = tokio::io::copy_bidirectional(&mut target, &mut requester).await;
+            first
+        });
+
+        let mut target = Socks5Stream::connect(&streamhost, &dstaddr).await.unwrap();
+        let mut requester = Socks5Stream::connect(&streamhost, &dstaddr).await.unwrap();
+        requester.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        target.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        target.write_all(b"world").await.unwrap();
+        requester.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+
+        drop(target);
+        drop(requester);
+        assert_eq!(proxy.await.unwrap(), dstaddr);
+    }
+
+    #[tokio::test]
+    async fn test_refused() {
+        let (listener, streamhost) = listen().await;
+        let server = tokio::spawn(async move { accept(&listener, NO_AUTHENTICATION, 4).await });
+        let error = Socks5Stream::connect(&streamhost, &example_dstaddr())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            Error::Connection(ConnecterError::Socks5(Socks5Error::Refused(4)))
+        ));
+        assert_eq!(
+            error.to_string(),
+            "connection error: SOCKS5 error: the streamhost refused the connection: reply 0x04"
+        );
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_no_acceptable_method() {
+        let (listener, streamhost) = listen().await;
+        let server =
+            tokio::spawn(async move { accept(&listener, NO_ACCEPTABLE_METHOD, SUCCEEDED).await });
+        let error = Socks5Stream::connect(&streamhost, &example_dstaddr())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            Error::Connection(ConnecterError::Socks5(Socks5Error::NoAcceptableMethod))
+        ));
+        server.await.unwrap();
+    }
+}
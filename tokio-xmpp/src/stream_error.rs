@@ -0,0 +1,73 @@
+//! Stream errors the client acts upon
+
+use xmpp_parsers::{ns, Element};
+
+use crate::Error;
+
+/// The port to connect to when a `<see-other-host/>` doesn't tell
+const DEFAULT_PORT: u16 = 5222;
+
+/// If `stanza` is a `<see-other-host/>` stream error, the
+/// `Error::Redirect` to the host it points to
+pub(crate) fn redirect(stanza: &Element) -> Option<Error> {
+    if !stanza.is("error", ns::STREAM) {
+        return None;
+    }
+    let text = stanza.get_child("see-other-host", ns::XMPP_STREAMS)?.text();
+    let (host, port) = parse_host(text.trim())?;
+    Some(Error::Redirect { host, port })
+}
+
+/// Splits `host`, `host:port`, `[ipv6]` or `[ipv6]:port`
+fn parse_host(value: &str) -> Option<(String, u16)> {
+    if let Some(rest) = value.strip_prefix('[') {
+        let (ip, rest) = rest.split_once(']')?;
+        let port = match rest {
+            "" => DEFAULT_PORT,
+            _ => rest.strip_prefix(':')?.parse().ok()?,
+        };
+        return Some((ip.to_owned(), port));
+    }
+    let (host, port) = match value.split_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (value, DEFAULT_PORT),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_owned(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_host() {
+        let parsed = |host: &str, port| Some((String::from(host), port));
+        assert_eq!(parse_host("example.org"), parsed("example.org", 5222));
+        assert_eq!(parse_host("example.org:5223"), parsed("example.org", 5223));
+        assert_eq!(parse_host("[2001:db8::1]"), parsed("2001:db8::1", 5222));
+        assert_eq!(
+            parse_host("[2001:db8::1]:5223"),
+            parsed("2001:db8::1", 5223)
+        );
+        assert_eq!(parse_host(""), None);
+        assert_eq!(parse_host("example.org:port"), None);
+        assert_eq!(parse_host("[2001:db8::1"), None);
+    }
+
+    #[test]
+    fn test_redirect() {
+        let stanza: Element = "<stream:error xmlns:stream='http://etherx.jabber.org/streams'><see-other-host xmlns='urn:ietf:params:xml:ns:xmpp-streams'>other.example.org:5223</see-other-host></stream:error>".parse().unwrap();
+        match redirect(&stanza) {
+            Some(Error::Redirect { host, port }) => {
+                assert_eq!(host, "other.example.org");
+                assert_eq!(port, 5223);
+            }
+            error => panic!("unexpected error: {:?}", error),
+        }
+        let stanza: Element = "<stream:error xmlns:stream='http://etherx.jabber.org/streams'><system-shutdown xmlns='urn:ietf:params:xml:ns:xmpp-streams'/></stream:error>".parse().unwrap();
+        assert!(redirect(&stanza).is_none());
+    }
+}
@@ -21,6 +21,18 @@ impl StreamFeatures {
         self.0.get_child("starttls", ns::TLS).is_some()
     }
 
+    /// Can compress the stream with zlib (XEP-0138)?
+    pub fn can_compress(&self) -> bool {
+        self.0
+            .get_child("compression", ns::COMPRESS_FEATURE)
+            .map(|compression| {
+                compression.children().any(|method| {
+                    method.is("method", ns::COMPRESS_FEATURE) && method.text() == "zlib"
+                })
+            })
+            .unwrap_or(false)
+    }
+
     /// Iterate over SASL mechanisms
     pub fn sasl_mechanisms<'a>(&'a self) -> Result<impl Iterator<Item = String> + 'a, AuthError> {
         Ok(self
@@ -36,4 +48,9 @@ impl StreamFeatures {
     pub fn can_bind(&self) -> bool {
         self.0.get_child("bind", ns::BIND).is_some()
     }
+
+    /// Can enable stream management (XEP-0198)?
+    pub fn can_stream_management(&self) -> bool {
+        self.0.get_child("sm", ns::SM).is_some()
+    }
 }
@@ -4,33 +4,33 @@ use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::codec::Framed;
 use xmpp_parsers::{ns, Element, Jid};
 
-use crate::xmpp_codec::{Packet, XMPPCodec};
+use crate::stream_error::redirect;
+use crate::xmpp_codec::{Packet, StreamHeader, XMPPCodec};
 use crate::xmpp_stream::XMPPStream;
 use crate::{Error, ProtocolError};
 
-/// Sends a `<stream:stream>`, then wait for one from the server, and
-/// construct an XMPPStream.
+/// Sends a `<stream:stream>`, with `xml:lang` if `lang` is set, then
+/// wait for one from the server, and construct an XMPPStream.
 pub async fn start<S: AsyncRead + AsyncWrite + Unpin>(
     mut stream: Framed<S, XMPPCodec>,
     jid: Jid,
     ns: String,
+    lang: Option<String>,
 ) -> Result<XMPPStream<S>, Error> {
-    let attrs = [
-        ("to".to_owned(), jid.clone().domain()),
-        ("version".to_owned(), "1.0".to_owned()),
-        ("xmlns".to_owned(), ns.clone()),
-        ("xmlns:stream".to_owned(), ns::STREAM.to_owned()),
-    ]
-    .iter()
-    .cloned()
-    .collect();
-    stream.send(Packet::StreamStart(attrs)).await?;
+    let header = StreamHeader {
+        ns: Some(ns.clone()),
+        to: Some(jid.clone().domain()),
+        version: Some("1.0".to_owned()),
+        lang,
+        ..StreamHeader::default()
+    };
+    stream.send(Packet::StreamStart(header)).await?;
 
-    let stream_attrs;
+    let header;
     loop {
         match stream.next().await {
-            Some(Ok(Packet::StreamStart(attrs))) => {
-                stream_attrs = attrs;
+            Some(Ok(Packet::StreamStart(received))) => {
+                header = received;
                 break;
             }
             Some(Ok(_)) => {}
@@ -39,15 +39,9 @@ pub async fn start<S: AsyncRead + AsyncWrite + Unpin>(
         }
     }
 
-    let stream_ns = stream_attrs
-        .get("xmlns")
-        .ok_or(ProtocolError::NoStreamNamespace)?
-        .clone();
-    let stream_id = stream_attrs
-        .get("id")
-        .ok_or(ProtocolError::NoStreamId)?
-        .clone();
-    let stream = if stream_ns == "jabber:client" && stream_attrs.get("version").is_some() {
+    let stream_ns = header.ns.clone().ok_or(ProtocolError::NoStreamNamespace)?;
+    let stream_id = header.id.clone().ok_or(ProtocolError::NoStreamId)?;
+    let mut stream = if stream_ns == "jabber:client" && header.version.is_some() {
         let stream_features;
         loop {
             match stream.next().await {
@@ -55,6 +49,11 @@ pub async fn start<S: AsyncRead + AsyncWrite + Unpin>(
                     stream_features = stanza;
                     break;
                 }
+                Some(Ok(Packet::Stanza(stanza))) => {
+                    if let Some(e) = redirect(&stanza) {
+                        return Err(e);
+                    }
+                }
                 Some(Ok(_)) => {}
                 Some(Err(e)) => return Err(e.into()),
                 None => return Err(Error::Disconnected),
@@ -71,5 +70,6 @@ pub async fn start<S: AsyncRead + AsyncWrite + Unpin>(
             Element::builder(stream_id, ns::STREAM).build(),
         )
     };
+    stream.header = header;
     Ok(stream)
 }
@@ -0,0 +1,450 @@
+//! A scripted XMPP server, to test clients against a canned transcript
+//!
+//! The server runs over an in-memory pipe: hand the client end to the
+//! code under test, and run the server next to it, usually with
+//! `tokio::join!`. Any deviation from the script panics, showing both
+//! what was expected and what was received.
+//!
+//! ```no_run
+//! # async fn example() {
+//! use tokio_xmpp::test_harness::{Handshake, Matcher, ScriptedServer};
+//! use xmpp_parsers::{ns, Element};
+//!
+//! let features = Element::bare("features", ns::STREAM);
+//! let pong: Element = "<iq xmlns='jabber:client' type='result'/>".parse().unwrap();
+//! let (stream, server) = ScriptedServer::new(Handshake::Features(features))
+//!     .expect(Matcher::new("iq", ns::JABBER_CLIENT).attr("type", "get"))
+//!     .reply(pong)
+//!     .start();
+//! // Drive the client over `stream` while `server` runs.
+//! # drop(stream);
+//! server.await;
+//! # }
+//! ```
+
+use futures::{sink::SinkExt, stream::StreamExt, Future};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+use tokio::io::{duplex, AsyncRead, AsyncWrite, DuplexStream};
+use tokio_util::codec::Framed;
+use xmpp_parsers::{ns, Element, FullJid};
+
+use crate::xmpp_codec::{Packet, StreamHeader, XMPPCodec};
+
+type ServerStream<S = DuplexStream> = Framed<S, XMPPCodec>;
+
+/// Describes an element the server expects from the client
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    name: String,
+    ns: String,
+    attrs: Vec<(String, String)>,
+    element: Option<Element>,
+}
+
+impl Matcher {
+    /// Any element with this name and namespace
+    pub fn new<N: Into<String>, NS: Into<String>>(name: N, ns: NS) -> Self {
+        Matcher {
+            name: name.into(),
+            ns: ns.into(),
+            attrs: Vec::new(),
+            element: None,
+        }
+    }
+
+    /// Also require this attribute to have this value
+    pub fn attr<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.attrs.push((name.into(), value.into()));
+        self
+    }
+
+    /// Exactly this element, except for the `id` attributes of it and
+    /// its descendants, which clients usually generate
+    pub fn element(element: Element) -> Self {
+        Matcher {
+            name: element.name().to_owned(),
+            ns: element.ns(),
+            attrs: Vec::new(),
+            element: Some(element),
+        }
+    }
+
+    /// Whether `element` is what this matcher expects
+    pub fn matches(&self, element: &Element) -> bool {
+        match self.element {
+            Some(ref expected) => same_element(expected, element),
+            None => {
+                element.is(&self.name, self.ns.as_str())
+                    && self
+                        .attrs
+                        .iter()
+                        .all(|(name, value)| element.attr(name) == Some(value))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref element) = self.element {
+            return write!(fmt, "{}", String::from(element));
+        }
+        write!(fmt, "<{} xmlns='{}'", self.name, self.ns)?;
+        for (name, value) in &self.attrs {
+            write!(fmt, " {}='{}'", name, value)?;
+        }
+        write!(fmt, " …>")
+    }
+}
+
+/// Compares two elements, except for their `id` attributes and
+/// namespace declarations
+fn same_element(a: &Element, b: &Element) -> bool {
+    fn attrs(element: &Element) -> BTreeMap<&str, &str> {
+        element
+            .attrs()
+            .filter(|(name, _)| *name != "id" && *name != "xmlns" && !name.starts_with("xmlns:"))
+            .collect()
+    }
+    a.name() == b.name()
+        && a.ns() == b.ns()
+        && attrs(a) == attrs(b)
+        && a.text() == b.text()
+        && a.children().count() == b.children().count()
+        && a.children()
+            .zip(b.children())
+            .all(|(a, b)| same_element(a, b))
+}
+
+/// What the server does before running its script
+#[derive(Debug, Clone)]
+pub enum Handshake {
+    /// Nothing, the script starts right away, for clients which don't
+    /// start a stream
+    None,
+    /// Answer the stream header of the client, with these
+    /// `<stream:features/>`
+    Features(Element),
+    /// Everything a client logging in expects on an already encrypted
+    /// connection: accept any SASL authentication, then bind this JID
+    Login(FullJid),
+}
+
+/// One step of a script
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Wait for the client to send a matching element
+    Expect(Matcher),
+    /// Wait for the client to close the stream with `</stream:stream>`
+    ExpectEnd,
+    /// Send this element
+    Send(Element),
+    /// Send this element, with the `id` of the last expected one
+    Reply(Element),
+    /// Pause for that long
+    Wait(Duration),
+    /// Drop the connection without closing the stream, like a network
+    /// failure
+    Disconnect,
+}
+
+/// A server following a script, see the [module documentation](self)
+#[derive(Debug, Clone)]
+pub struct ScriptedServer {
+    handshake: Handshake,
+    steps: Vec<Step>,
+}
+
+impl ScriptedServer {
+    /// A server performing `handshake`, with an empty script
+    pub fn new(handshake: Handshake) -> Self {
+        ScriptedServer {
+            handshake,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Add a step to the script
+    pub fn step(mut self, step: Step) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// Wait for the client to send a matching element
+    pub fn expect(self, matcher: Matcher) -> Self {
+        self.step(Step::Expect(matcher))
+    }
+
+    /// Wait for the client to close the stream
+    pub fn expect_end(self) -> Self {
+        self.step(Step::ExpectEnd)
+    }
+
+    /// Send this element
+    pub fn send(self, element: Element) -> Self {
+        self.step(Step::Send(element))
+    }
+
+    /// Send this element, with the `id` of the last expected one
+    pub fn reply(self, element: Element) -> Self {
+        self.step(Step::Reply(element))
+    }
+
+    /// Pause for that long
+    pub fn wait(self, duration: Duration) -> Self {
+        self.step(Step::Wait(duration))
+    }
+
+    /// Drop the connection without closing the stream
+    pub fn disconnect(self) -> Self {
+        self.step(Step::Disconnect)
+    }
+
+    /// Returns the client end of the connection, and the server
+    /// running the script on the other end
+    ///
+    /// Once the script is over, the server closes the stream and the
+    /// connection. It panics if the client deviates from the script.
+    pub fn start(self) -> (DuplexStream, impl Future<Output = ()>) {
+        let (client, server) = duplex(65536);
+        (client, self.run(Framed::new(server, XMPPCodec::new())))
+    }
+
+    async fn run(self, mut stream: ServerStream) {
+        let started = match self.handshake {
+            Handshake::None => false,
+            Handshake::Features(features) => {
+                start_stream(&mut stream, features).await;
+                true
+            }
+            Handshake::Login(jid) => {
+                stream = login(stream, jid).await;
+                true
+            }
+        };
+
+        let mut last_id = None;
+        for (i, step) in self.steps.into_iter().enumerate() {
+            let context = format!("step {}", i + 1);
+            match step {
+                Step::Expect(matcher) => {
+                    let element = expect(&mut stream, &matcher, &context).await;
+                    last_id = element.attr("id").map(String::from);
+                }
+                Step::ExpectEnd => expect_end(&mut stream, &context).await,
+                Step::Send(element) => send(&mut stream, element).await,
+                Step::Reply(mut element) => {
+                    if let Some(ref id) = last_id {
+                        element.set_attr("id", id.as_str());
+                    }
+                    send(&mut stream, element).await;
+                }
+                Step::Wait(duration) => tokio::time::sleep(duration).await,
+                Step::Disconnect => return,
+            }
+        }
+        if started {
+            // The client may already be gone.
+            let _ = stream.send(Packet::StreamEnd).await;
+        }
+    }
+}
+
+pub(crate) async fn send<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut ServerStream<S>,
+    element: Element,
+) {
+    stream
+        .send(Packet::Stanza(element))
+        .await
+        .expect("scripted server failed to send");
+}
+
+/// Reads the next element, skipping whitespace, and panics unless it
+/// matches
+pub(crate) async fn expect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut ServerStream<S>,
+    matcher: &Matcher,
+    context: &str,
+) -> Element {
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(element))) if matcher.matches(&element) => return element,
+            Some(Ok(Packet::Stanza(element))) => panic!(
+                "{}: unexpected element\nexpected: {}\nreceived: {}",
+                context,
+                matcher,
+                String::from(&element)
+            ),
+            Some(Ok(Packet::Text(_))) => (),
+            Some(Ok(packet)) => panic!(
+                "{}: unexpected packet\nexpected: {}\nreceived: {:?}",
+                context, matcher, packet
+            ),
+            Some(Err(e)) => panic!("{}: expected {}, got error: {}", context, matcher, e),
+            None => panic!(
+                "{}: expected {}, but the client disconnected",
+                context, matcher
+            ),
+        }
+    }
+}
+
+/// Reads the next packet, skipping whitespace, and panics unless it
+/// is `</stream:stream>`
+async fn expect_end(stream: &mut ServerStream, context: &str) {
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::StreamEnd)) => return,
+            Some(Ok(Packet::Text(_))) => (),
+            Some(Ok(packet)) => panic!(
+                "{}: expected the end of the stream, got {:?}",
+                context, packet
+            ),
+            Some(Err(e)) => panic!(
+                "{}: expected the end of the stream, got error: {}",
+                context, e
+            ),
+            None => panic!(
+                "{}: expected the end of the stream, but the client disconnected",
+                context
+            ),
+        }
+    }
+}
+
+/// Waits for the stream header of the client, answers it and sends
+/// `features`, returning the header received
+pub(crate) async fn start_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut ServerStream<S>,
+    features: Element,
+) -> StreamHeader {
+    let received = loop {
+        match stream.next().await {
+            Some(Ok(Packet::StreamStart(header))) => break header,
+            Some(Ok(Packet::Text(_))) => (),
+            packet => panic!("handshake: expected a stream header, got {:?}", packet),
+        }
+    };
+    let header = StreamHeader {
+        ns: Some(String::from(ns::JABBER_CLIENT)),
+        id: Some(String::from("scripted")),
+        from: received.to.clone(),
+        version: Some(String::from("1.0")),
+        ..StreamHeader::default()
+    };
+    stream
+        .send(Packet::StreamStart(header))
+        .await
+        .expect("scripted server failed to send");
+    send(stream, features).await;
+    received
+}
+
+async fn login(stream: ServerStream, jid: FullJid) -> ServerStream {
+    let mut stream = authenticate(stream).await;
+    let features = Element::builder("features", ns::STREAM)
+        .append(Element::bare("bind", ns::BIND))
+        .build();
+    start_stream(&mut stream, features).await;
+    bind_resource(&mut stream, jid).await;
+    stream
+}
+
+/// Accepts any SASL authentication, and returns the stream on which
+/// the client restarts, without answering its header yet
+pub(crate) async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: ServerStream<S>,
+) -> ServerStream<S> {
+    let mechanisms = Element::builder("mechanisms", ns::SASL)
+        .append(Element::builder("mechanism", ns::SASL).append("PLAIN"))
+        .append(Element::builder("mechanism", ns::SASL).append("ANONYMOUS"))
+        .build();
+    let features = Element::builder("features", ns::STREAM)
+        .append(mechanisms)
+        .build();
+    start_stream(&mut stream, features).await;
+    expect(&mut stream, &Matcher::new("auth", ns::SASL), "handshake").await;
+    send(&mut stream, Element::bare("success", ns::SASL)).await;
+
+    // The client restarts the stream, with a new parser.
+    Framed::new(stream.into_inner(), XMPPCodec::new())
+}
+
+/// Binds `jid` for the client
+pub(crate) async fn bind_resource<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut ServerStream<S>,
+    jid: FullJid,
+) {
+    let matcher = Matcher::new("iq", ns::JABBER_CLIENT).attr("type", "set");
+    let request = expect(stream, &matcher, "handshake").await;
+    let bind = Element::builder("bind", ns::BIND)
+        .append(Element::builder("jid", ns::BIND).append(String::from(jid)))
+        .build();
+    let result = Element::builder("iq", ns::JABBER_CLIENT)
+        .attr("type", "result")
+        .attr("id", request.attr("id"))
+        .append(bind)
+        .build();
+    send(stream, result).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use xmpp_parsers::Jid;
+
+    use crate::xmpp_stream::XMPPStream;
+
+    #[test]
+    fn test_matchers() {
+        let iq: Element = "<iq xmlns='jabber:client' type='get' id='abc'><ping xmlns='urn:xmpp:ping' id='def'/></iq>"
+            .parse()
+            .unwrap();
+
+        assert!(Matcher::new("iq", ns::JABBER_CLIENT).matches(&iq));
+        assert!(Matcher::new("iq", ns::JABBER_CLIENT)
+            .attr("type", "get")
+            .matches(&iq));
+        assert!(!Matcher::new("iq", ns::JABBER_CLIENT)
+            .attr("type", "set")
+            .matches(&iq));
+        assert!(!Matcher::new("iq", ns::COMPONENT_ACCEPT).matches(&iq));
+
+        let same: Element = "<iq xmlns='jabber:client' type='get' id='123'><ping xmlns='urn:xmpp:ping' id='456'/></iq>"
+            .parse()
+            .unwrap();
+        assert!(Matcher::element(same).matches(&iq));
+        let built = Element::builder("iq", ns::JABBER_CLIENT)
+            .attr("type", "get")
+            .append(Element::bare("ping", ns::PING))
+            .build();
+        assert!(Matcher::element(built).matches(&iq));
+        let other: Element = "<iq xmlns='jabber:client' type='get' id='123'/>"
+            .parse()
+            .unwrap();
+        assert!(!Matcher::element(other).matches(&iq));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "received: <presence")]
+    async fn test_unexpected_element() {
+        let features = Element::bare("features", ns::STREAM);
+        let (client, server) = ScriptedServer::new(Handshake::Features(features))
+            .expect(Matcher::new("message", ns::JABBER_CLIENT))
+            .start();
+        let client = async move {
+            let jid = Jid::from_str("foo@bar").unwrap();
+            let mut stream = XMPPStream::start(client, jid, String::from(ns::JABBER_CLIENT))
+                .await
+                .unwrap();
+            let presence = Element::bare("presence", ns::JABBER_CLIENT);
+            stream.send_stanza(presence).await.unwrap();
+            stream
+        };
+        tokio::join!(client, server);
+    }
+}
@@ -0,0 +1,634 @@
+//! Schedules what `XMPPStream` writes, in chunks, with small urgent
+//! packets going out before bulk ones
+//!
+//! Urgent packets only overtake those they aren't ordered with. Every
+//! packet gets a sequence number when queued, and is written after all
+//! of those queued before it which:
+//! - are stanzas to the same bare JID, a missing `to` counting as one,
+//! - or were queued along with it, between the same two flushes, as
+//!   the stanzas of a single `send_all()`,
+//! - or are stream headers or footers, which nothing overtakes.
+//!
+//! Keepalives and stream management acks and requests are ordered with
+//! nothing but those headers and footers.
+
+use bytes::BytesMut;
+use jid::{BareJid, Jid};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use xmpp_parsers::{ns, Element};
+
+use crate::buffer_pool::{BufferPool, PoolStats};
+use crate::xmpp_codec::Packet;
+
+/// The biggest write by default, that of a TLS record
+pub(crate) const DEFAULT_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Whether `packet` is one of those a peer waits on, which shouldn't get
+/// stuck behind bulk data: stream management acks, pings and iq replies,
+/// or a whitespace keepalive
+pub(crate) fn is_urgent(packet: &Packet) -> bool {
+    match packet {
+        Packet::Text(_) => true,
+        Packet::Stanza(stanza) if stanza.name() == "iq" => match stanza.attr("type") {
+            Some("result") | Some("error") => true,
+            Some("get") => stanza.has_child("ping", ns::PING),
+            _ => false,
+        },
+        Packet::Stanza(stanza) => stanza.is("a", ns::SM) || stanza.is("r", ns::SM),
+        Packet::StreamStart(_) | Packet::StreamEnd => false,
+    }
+}
+
+/// Which packets queued before a packet it has to be written after,
+/// besides those of its group
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Order {
+    /// None of them
+    Free,
+    /// Stanzas to this bare JID, empty for those without a `to`
+    To(String),
+    /// All of them, and all those queued after it have to wait for it
+    Barrier,
+}
+
+impl Order {
+    fn follows(&self, other: &Order) -> bool {
+        match (self, other) {
+            (Order::Barrier, _) | (_, Order::Barrier) => true,
+            (Order::To(jid), Order::To(other)) => jid == other,
+            _ => false,
+        }
+    }
+}
+
+/// How `packet` is ordered with the others, see the module documentation
+pub(crate) fn order(packet: &Packet) -> Order {
+    match packet {
+        Packet::Text(_) => Order::Free,
+        Packet::Stanza(stanza) if stanza.is("a", ns::SM) || stanza.is("r", ns::SM) => Order::Free,
+        Packet::Stanza(stanza) => match stanza.attr("to") {
+            Some(to) => match Jid::from_str(to) {
+                Ok(jid) => Order::To(BareJid::from(jid).to_string()),
+                Err(_) => Order::To(to.to_owned()),
+            },
+            None => Order::To(String::new()),
+        },
+        Packet::StreamStart(_) | Packet::StreamEnd => Order::Barrier,
+    }
+}
+
+/// An encoded packet waiting to be written
+struct Queued {
+    /// In which order packets got queued
+    seq: u64,
+    order: Order,
+    /// Packets queued between the same two flushes, except free ones
+    group: Option<u64>,
+    bytes: BytesMut,
+    stanza: Option<Element>,
+}
+
+impl Queued {
+    /// Whether `self` has to be written after `other`, if queued after it
+    fn follows(&self, other: &Queued) -> bool {
+        (self.group.is_some() && self.group == other.group) || self.order.follows(&other.order)
+    }
+}
+
+/// Two queues of encoded packets, each written in order, the urgent one
+/// first whenever the next chunk gets put together
+///
+/// A packet is never interrupted by another, so urgent ones only get
+/// ahead at the end of a chunk.
+pub(crate) struct WriteQueue {
+    chunk_size: usize,
+    urgent: VecDeque<Queued>,
+    bulk: VecDeque<Queued>,
+    /// The packet only partially put in chunks so far, and how much of
+    /// it
+    current: Option<(Queued, usize)>,
+    /// What is being written
+    chunk: BytesMut,
+    /// How much of `chunk` got written
+    written: usize,
+    /// The stanzas starting in `chunk`, with where they start
+    starts: Vec<(usize, u64, Element)>,
+    next_seq: u64,
+    /// The group of the packets queued from now on
+    group: u64,
+    /// Whether a flush started since the current group got its first
+    /// packet, which then ends it
+    group_ended: bool,
+    /// Where the encoded packets go back once put in chunks
+    pool: BufferPool,
+    /// The packets put in chunks so far, for tests to check their order
+    #[cfg(test)]
+    wire: Vec<(u64, Order, Option<u64>)>,
+}
+
+impl WriteQueue {
+    pub(crate) fn new(pool: BufferPool) -> WriteQueue {
+        WriteQueue {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            urgent: VecDeque::new(),
+            bulk: VecDeque::new(),
+            current: None,
+            chunk: BytesMut::new(),
+            written: 0,
+            starts: Vec::new(),
+            next_seq: 0,
+            group: 0,
+            group_ended: false,
+            pool,
+            #[cfg(test)]
+            wire: Vec::new(),
+        }
+    }
+
+    /// Write at most `size` bytes at once, starting with the next chunk
+    pub(crate) fn set_chunk_size(&mut self, size: usize) {
+        self.chunk_size = size.max(1);
+    }
+
+    /// Queue `bytes`, the encoding of `stanza` if it is one
+    ///
+    /// A packet bigger than a chunk can't be urgent, as it would hold
+    /// the others back just as much.
+    pub(crate) fn push(
+        &mut self,
+        urgent: bool,
+        order: Order,
+        bytes: BytesMut,
+        stanza: Option<Element>,
+    ) {
+        let group = if order == Order::Free {
+            None
+        } else {
+            if self.group_ended {
+                self.group += 1;
+                self.group_ended = false;
+            }
+            Some(self.group)
+        };
+        let queued = Queued {
+            seq: self.next_seq,
+            order,
+            group,
+            bytes,
+            stanza,
+        };
+        self.next_seq += 1;
+        if urgent && queued.bytes.len() <= self.chunk_size {
+            self.urgent.push_back(queued);
+        } else {
+            self.bulk.push_back(queued);
+        }
+    }
+
+    pub(crate) fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    /// How many packets wait to be put in a chunk, and how many bytes
+    /// of the current one are still to be written
+    #[cfg(feature = "test-internals")]
+    pub(crate) fn depth(&self) -> (usize, usize) {
+        let packets = self.urgent.len() + self.bulk.len() + self.current.iter().count();
+        (packets, self.chunk.len() - self.written)
+    }
+
+    /// Whether everything got written
+    pub(crate) fn is_empty(&self) -> bool {
+        self.written == self.chunk.len()
+            && self.current.is_none()
+            && self.urgent.is_empty()
+            && self.bulk.is_empty()
+    }
+
+    /// What to write next, putting the next chunk together once the
+    /// previous one got written
+    pub(crate) fn next_chunk(&mut self) -> Option<&[u8]> {
+        self.group_ended = true;
+        if self.written == self.chunk.len() {
+            self.chunk.clear();
+            self.starts.clear();
+            self.written = 0;
+            self.fill();
+        }
+        match &self.chunk[self.written..] {
+            [] => None,
+            chunk => Some(chunk),
+        }
+    }
+
+    /// `written` more bytes of the chunk got written, returning whether
+    /// all of it did
+    pub(crate) fn advance(&mut self, written: usize) -> bool {
+        self.written = (self.written + written).min(self.chunk.len());
+        self.written == self.chunk.len()
+    }
+
+    fn fill(&mut self) {
+        while self.chunk.len() < self.chunk_size {
+            let (queued, offset) = match self.current.take() {
+                Some(current) => current,
+                None => match self.pop_next() {
+                    Some(mut queued) => {
+                        if let Some(stanza) = queued.stanza.take() {
+                            self.starts.push((self.chunk.len(), queued.seq, stanza));
+                        }
+                        (queued, 0)
+                    }
+                    None => break,
+                },
+            };
+            let room = self.chunk_size - self.chunk.len();
+            let rest = &queued.bytes[offset..];
+            if rest.len() <= room {
+                self.chunk.extend_from_slice(rest);
+                self.pool.put(queued.bytes);
+            } else {
+                self.chunk.extend_from_slice(&rest[..room]);
+                self.current = Some((queued, offset + room));
+            }
+        }
+    }
+
+    /// The next packet to put in a chunk: the first urgent one which
+    /// doesn't have to wait for another, or else the oldest bulk one
+    ///
+    /// The oldest packet of all never waits, so when every urgent one
+    /// does, it is the oldest bulk one.
+    fn pop_next(&mut self) -> Option<Queued> {
+        let ready = (0..self.urgent.len()).find(|&i| !self.must_wait(&self.urgent[i]));
+        let queued = match ready {
+            Some(i) => self.urgent.remove(i),
+            None => self.bulk.pop_front().or_else(|| self.urgent.pop_front()),
+        }?;
+        debug_assert!(
+            !self.must_wait(&queued),
+            "packet {} would overtake one it has to follow",
+            queued.seq
+        );
+        #[cfg(test)]
+        self.wire
+            .push((queued.seq, queued.order.clone(), queued.group));
+        Some(queued)
+    }
+
+    /// Whether a packet queued before `packet` and still waiting has to
+    /// be written first
+    fn must_wait(&self, packet: &Queued) -> bool {
+        self.urgent
+            .iter()
+            .chain(self.bulk.iter())
+            .any(|other| other.seq < packet.seq && packet.follows(other))
+    }
+
+    /// Empty the queue, returning the stanzas which didn't start being
+    /// written, in the order they got queued
+    pub(crate) fn take_unwritten(&mut self) -> Vec<Element> {
+        let written = self.written;
+        let mut unwritten: Vec<(u64, Element)> = self
+            .starts
+            .drain(..)
+            .filter(|(start, _, _)| *start >= written)
+            .map(|(_, seq, stanza)| (seq, stanza))
+            .collect();
+        for queued in self.urgent.drain(..).chain(self.bulk.drain(..)) {
+            if let Some(stanza) = queued.stanza {
+                unwritten.push((queued.seq, stanza));
+            }
+            self.pool.put(queued.bytes);
+        }
+        unwritten.sort_by_key(|(seq, _)| *seq);
+        self.current = None;
+        self.chunk.clear();
+        self.written = 0;
+        unwritten.into_iter().map(|(_, stanza)| stanza).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stanza(name: &str) -> Element {
+        Element::builder(name, ns::JABBER_CLIENT).build()
+    }
+
+    /// Write everything, `size` bytes at a time at most
+    fn write_all(queue: &mut WriteQueue, size: usize) -> Vec<u8> {
+        let mut output = Vec::new();
+        while let Some(chunk) = queue.next_chunk() {
+            let written = chunk.len().min(size);
+            output.extend_from_slice(&chunk[..written]);
+            queue.advance(written);
+        }
+        assert!(queue.is_empty());
+        output
+    }
+
+    #[test]
+    fn test_classes() {
+        let iq = |type_: &str| {
+            Element::builder("iq", ns::JABBER_CLIENT)
+                .attr("type", type_)
+                .build()
+        };
+        assert!(is_urgent(&Packet::Stanza(iq("result"))));
+        assert!(is_urgent(&Packet::Stanza(iq("error"))));
+        assert!(!is_urgent(&Packet::Stanza(iq("get"))));
+        assert!(!is_urgent(&Packet::Stanza(iq("set"))));
+        let ping = Element::builder("iq", ns::JABBER_CLIENT)
+            .attr("type", "get")
+            .append(Element::builder("ping", ns::PING))
+            .build();
+        assert!(is_urgent(&Packet::Stanza(ping)));
+        let ack = Element::builder("a", ns::SM).attr("h", 3).build();
+        assert!(is_urgent(&Packet::Stanza(ack)));
+        assert!(is_urgent(&Packet::Stanza(Element::bare("r", ns::SM))));
+        assert!(!is_urgent(&Packet::Stanza(stanza("message"))));
+        assert!(is_urgent(&Packet::Text(String::from(" "))));
+        assert!(!is_urgent(&Packet::StreamEnd));
+    }
+
+    #[test]
+    fn test_chunks() {
+        let mut queue = WriteQueue::new(BufferPool::new());
+        queue.set_chunk_size(4);
+        queue.push(false, Order::Free, BytesMut::from(&b"0123456789"[..]), None);
+        let mut chunks = Vec::new();
+        while let Some(chunk) = queue.next_chunk() {
+            chunks.push(chunk.to_vec());
+            let written = chunk.len();
+            queue.advance(written);
+        }
+        assert_eq!(chunks, [&b"0123"[..], b"4567", b"89"]);
+
+        // A partial write of a chunk gets finished before anything else.
+        queue.push(false, Order::Free, BytesMut::from(&b"abcdef"[..]), None);
+        assert_eq!(queue.next_chunk(), Some(&b"abcd"[..]));
+        queue.advance(1);
+        queue.push(true, Order::Free, BytesMut::from(&b"!"[..]), None);
+        assert_eq!(queue.next_chunk(), Some(&b"bcd"[..]));
+        queue.advance(3);
+        // An urgent packet doesn't interrupt a bulk one either.
+        assert_eq!(queue.next_chunk(), Some(&b"ef!"[..]));
+        queue.advance(3);
+        assert_eq!(queue.next_chunk(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_urgent_first() {
+        let mut queue = WriteQueue::new(BufferPool::new());
+        queue.set_chunk_size(8);
+        for bulk in &["aaaa", "bbbb", "cccc"] {
+            queue.push(false, Order::Free, BytesMut::from(bulk.as_bytes()), None);
+        }
+        assert_eq!(queue.next_chunk(), Some(&b"aaaabbbb"[..]));
+        queue.push(true, Order::Free, BytesMut::from(&b"!"[..]), None);
+        queue.push(true, Order::Free, BytesMut::from(&b"?"[..]), None);
+        // Too big to be urgent.
+        queue.push(true, Order::Free, BytesMut::from(&b"123456789"[..]), None);
+        queue.advance(8);
+        assert_eq!(write_all(&mut queue, 100), b"!?cccc123456789");
+    }
+
+    #[test]
+    fn test_unwritten() {
+        let mut queue = WriteQueue::new(BufferPool::new());
+        queue.set_chunk_size(40);
+        for name in &["one", "two", "three"] {
+            let stanza = stanza(name);
+            let bytes = BytesMut::from(String::from(&stanza).as_bytes());
+            queue.push(
+                false,
+                order(&Packet::Stanza(stanza.clone())),
+                bytes,
+                Some(stanza),
+            );
+        }
+        queue.push(true, Order::Free, BytesMut::from(&b" "[..]), None);
+        let ack = Element::builder("a", ns::SM).attr("h", 3).build();
+        queue.push(
+            true,
+            Order::Free,
+            BytesMut::from(String::from(&ack).as_bytes()),
+            Some(ack.clone()),
+        );
+
+        // The ack went first, and the first message got partially written.
+        let chunk = queue.next_chunk().unwrap().to_vec();
+        assert!(chunk.starts_with(b" <a "));
+        queue.advance(chunk.len() - 1);
+        assert_eq!(queue.take_unwritten(), [stanza("two"), stanza("three")]);
+        assert!(queue.is_empty());
+        assert!(queue.take_unwritten().is_empty());
+    }
+
+    /// Queue `stanza` the way `XMPPStream` does
+    fn push_stanza(queue: &mut WriteQueue, stanza: Element) {
+        let packet = Packet::Stanza(stanza.clone());
+        let bytes = BytesMut::from(String::from(&stanza).as_bytes());
+        queue.push(is_urgent(&packet), order(&packet), bytes, Some(stanza));
+    }
+
+    fn addressed(name: &str, type_: &str, to: &str) -> Element {
+        Element::builder(name, ns::JABBER_CLIENT)
+            .attr("type", type_)
+            .attr("to", to)
+            .build()
+    }
+
+    /// Check that no packet got written before one queued earlier which
+    /// it has to follow, returning how many times one overtook another
+    fn check_wire(wire: &[(u64, Order, Option<u64>)]) -> usize {
+        let mut overtaken = 0;
+        for (i, (seq, order, group)) in wire.iter().enumerate() {
+            for (other_seq, other_order, other_group) in &wire[i + 1..] {
+                if other_seq > seq {
+                    continue;
+                }
+                overtaken += 1;
+                assert!(
+                    group.is_none() || group != other_group,
+                    "{} overtook {} of its group",
+                    seq,
+                    other_seq
+                );
+                assert!(
+                    !order.follows(other_order),
+                    "{} ({:?}) overtook {} ({:?})",
+                    seq,
+                    order,
+                    other_seq,
+                    other_order
+                );
+            }
+        }
+        overtaken
+    }
+
+    #[test]
+    fn test_order() {
+        assert_eq!(
+            order(&Packet::Stanza(addressed(
+                "message",
+                "chat",
+                "juliet@capulet.example/balcony"
+            ))),
+            Order::To(String::from("juliet@capulet.example"))
+        );
+        assert_eq!(
+            order(&Packet::Stanza(stanza("iq"))),
+            Order::To(String::new())
+        );
+        assert_eq!(order(&Packet::Text(String::from(" "))), Order::Free);
+        assert_eq!(order(&Packet::StreamEnd), Order::Barrier);
+
+        let mut queue = WriteQueue::new(BufferPool::new());
+        queue.set_chunk_size(100);
+        // Messages bigger than a chunk, long to write.
+        let message = |to: &str| {
+            let mut message = addressed("message", "chat", to);
+            message.append_child(
+                Element::builder("body", ns::JABBER_CLIENT)
+                    .append("x".repeat(200))
+                    .build(),
+            );
+            message
+        };
+        push_stanza(&mut queue, message("juliet@capulet.example"));
+        assert!(queue.next_chunk().is_some());
+        push_stanza(&mut queue, message("romeo@montague.example"));
+        push_stanza(&mut queue, message("juliet@capulet.example"));
+        assert!(queue.next_chunk().is_some());
+        // A result to another JID gets ahead of the bulk, but not one to a
+        // JID with a message still waiting, even to another resource.
+        push_stanza(
+            &mut queue,
+            addressed("iq", "result", "juliet@capulet.example/balcony"),
+        );
+        assert!(queue.next_chunk().is_some());
+        push_stanza(
+            &mut queue,
+            addressed("iq", "result", "nurse@capulet.example"),
+        );
+        assert!(queue.next_chunk().is_some());
+        // Nor one queued along with a message, whatever its recipient.
+        push_stanza(&mut queue, message("romeo@montague.example"));
+        push_stanza(
+            &mut queue,
+            addressed("iq", "result", "benvolio@montague.example"),
+        );
+        write_all(&mut queue, 100);
+        let order: Vec<u64> = queue.wire.iter().map(|(seq, _, _)| *seq).collect();
+        assert_eq!(order, [0, 4, 1, 2, 3, 5, 6]);
+        assert_eq!(check_wire(&queue.wire), 3);
+    }
+
+    /// A tiny xorshift generator, so that failures can be reproduced
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    #[test]
+    fn test_order_randomized() {
+        let jids = [
+            None,
+            Some("juliet@capulet.example"),
+            Some("juliet@capulet.example/balcony"),
+            Some("romeo@montague.example"),
+        ];
+        let mut overtaken = 0;
+        for seed in 1..300 {
+            let mut rng = Rng(seed);
+            let mut queue = WriteQueue::new(BufferPool::new());
+            queue.set_chunk_size(1 + rng.below(64) as usize);
+            let mut packets = Vec::new();
+            let mut output = Vec::new();
+            for _ in 0..100 {
+                if rng.below(3) == 0 {
+                    // Some of what is queued gets written.
+                    if let Some(chunk) = queue.next_chunk() {
+                        let written = 1 + rng.below(chunk.len() as u64) as usize;
+                        output.extend_from_slice(&chunk[..written]);
+                        queue.advance(written);
+                    }
+                    continue;
+                }
+                // One of the submitters queues a few packets at once.
+                for _ in 0..1 + rng.below(3) {
+                    let to = jids[rng.below(jids.len() as u64) as usize];
+                    let addressed = |name: &str, type_: &str| {
+                        let mut stanza = Element::builder(name, ns::JABBER_CLIENT)
+                            .attr("type", type_)
+                            .build();
+                        if let Some(to) = to {
+                            stanza.set_attr("to", to);
+                        }
+                        Packet::Stanza(stanza)
+                    };
+                    let packet = match rng.below(5) {
+                        0 => addressed("message", "chat"),
+                        1 => addressed("iq", "get"),
+                        2 | 3 => addressed("iq", "result"),
+                        _ if rng.below(2) == 0 => Packet::Text(String::from(" ")),
+                        _ => Packet::Stanza(Element::bare("r", ns::SM)),
+                    };
+                    // Recognisable bytes, bigger than a chunk at times.
+                    let mut bytes = format!("[{}]", packets.len()).into_bytes();
+                    bytes.resize(bytes.len() + rng.below(80) as usize, b'.');
+                    packets.push(bytes.clone());
+                    queue.push(
+                        is_urgent(&packet),
+                        order(&packet),
+                        BytesMut::from(&bytes[..]),
+                        None,
+                    );
+                }
+            }
+            output.extend(write_all(&mut queue, 1 + rng.below(100) as usize));
+
+            // Every packet got written whole, in the order of the wire.
+            assert_eq!(queue.wire.len(), packets.len());
+            let expected: Vec<u8> = queue
+                .wire
+                .iter()
+                .flat_map(|(seq, _, _)| packets[*seq as usize].clone())
+                .collect();
+            assert_eq!(output, expected, "seed {}", seed);
+            overtaken += check_wire(&queue.wire);
+        }
+        // Urgent packets did get ahead, when allowed to.
+        assert!(overtaken > 100);
+    }
+
+    #[test]
+    fn test_buffers_reused() {
+        let pool = BufferPool::new();
+        let mut queue = WriteQueue::new(pool.clone());
+        queue.set_chunk_size(4);
+        let mut buffer = pool.take();
+        buffer.extend_from_slice(b"0123456789");
+        queue.push(false, Order::Free, buffer, None);
+        assert_eq!(queue.next_chunk(), Some(&b"0123"[..]));
+        queue.advance(4);
+        // Only once completely put in chunks does a packet go back.
+        assert_eq!(pool.pooled_capacity(), 0);
+        assert_eq!(write_all(&mut queue, 100), b"456789");
+        assert!(pool.pooled_capacity() >= 10);
+        assert!(pool.take().is_empty());
+        assert_eq!(pool.stats().hits(), 1);
+    }
+}
@@ -1,30 +1,32 @@
 //! XML stream parser for XMPP
 
+use crate::buffer_pool::{BufferPool, PoolStats};
 use crate::{ParseError, ParserError};
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use log::{debug, error};
+use minidom::element::{escape_attribute, escape_text};
+use minidom::Position;
 use std;
 use std::borrow::Cow;
 use std::collections::vec_deque::VecDeque;
-use std::collections::HashMap;
 use std::default::Default;
 use std::fmt::Write;
 use std::io;
-use std::iter::FromIterator;
 use std::str::from_utf8;
 use std::sync::Arc;
 use std::sync::Mutex;
 use tokio_util::codec::{Decoder, Encoder};
 use xml5ever::buffer_queue::BufferQueue;
 use xml5ever::interface::Attribute;
+use xml5ever::tendril::StrTendril;
 use xml5ever::tokenizer::{Tag, TagKind, Token, TokenSink, XmlTokenizer};
-use xmpp_parsers::Element;
+use xmpp_parsers::{ns, Element, ParseLimits};
 
 /// Anything that can be sent or received on an XMPP/XML stream
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Packet {
     /// `<stream:stream>` start tag
-    StreamStart(HashMap<String, String>),
+    StreamStart(StreamHeader),
     /// A complete stanza or nonza
     Stanza(Element),
     /// Plain text (think whitespace keep-alive)
@@ -33,15 +35,130 @@ pub enum Packet {
     StreamEnd,
 }
 
+/// The attributes of a `<stream:stream>` start tag
+///
+/// Other attributes are ignored when receiving one, and never sent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamHeader {
+    /// The default namespace of the stream, such as `jabber:client`
+    pub ns: Option<String>,
+    /// The stream id, only set by the receiving entity
+    pub id: Option<String>,
+    /// The entity sending this header
+    pub from: Option<String>,
+    /// The entity this header is sent to
+    pub to: Option<String>,
+    /// The XMPP version, `1.0` for anything but very old servers
+    pub version: Option<String>,
+    /// The default language of the stream, from `xml:lang`
+    pub lang: Option<String>,
+}
+
+impl StreamHeader {
+    fn from_attrs(attrs: &[Attribute]) -> StreamHeader {
+        let mut header = StreamHeader::default();
+        for attr in attrs {
+            let value = Some(attr.value.as_ref().to_owned());
+            let prefix = attr.name.prefix.as_ref().map(|prefix| prefix.as_ref());
+            match (prefix, attr.name.local.as_ref()) {
+                (None, "xmlns") => header.ns = value,
+                (None, "id") => header.id = value,
+                (None, "from") => header.from = value,
+                (None, "to") => header.to = value,
+                (None, "version") => header.version = value,
+                (Some("xml"), "lang") => header.lang = value,
+                _ => (),
+            }
+        }
+        header
+    }
+}
+
 type QueueItem = Result<Packet, ParserError>;
 
+/// Namespaces seen on most stanzas, declared without allocating
+const COMMON_NAMESPACES: &[&str] = &[
+    ns::JABBER_CLIENT,
+    ns::JABBER_SERVER,
+    ns::COMPONENT_ACCEPT,
+    ns::STREAM,
+    ns::XMPP_STANZAS,
+    ns::XMPP_STREAMS,
+    ns::TLS,
+    ns::SASL,
+    ns::BIND,
+    ns::SM,
+    ns::COMPRESS_FEATURE,
+    ns::ROSTER,
+    ns::DATA_FORMS,
+    ns::DISCO_INFO,
+    ns::DISCO_ITEMS,
+    ns::MUC,
+    ns::MUC_USER,
+    ns::MUC_OWNER,
+    ns::RSM,
+    ns::PUBSUB,
+    ns::PUBSUB_EVENT,
+    ns::PUBSUB_OWNER,
+    ns::AVATAR_METADATA,
+    ns::BOOKMARKS2,
+    ns::CHATSTATES,
+    ns::VERSION,
+    ns::CAPS,
+    ns::ECAPS2,
+    ns::VCARD_UPDATE,
+    ns::NICK,
+    ns::RECEIPTS,
+    ns::PING,
+    ns::TIME,
+    ns::DELAY,
+    ns::IDLE,
+    ns::ATTENTION,
+    ns::CARBONS,
+    ns::FORWARD,
+    ns::HASHES,
+    ns::MESSAGE_CORRECT,
+    ns::MAM,
+    ns::SID,
+    ns::OID,
+    ns::CSI,
+    ns::EME,
+    ns::HTTP_UPLOAD,
+    ns::XHTML_IM,
+    ns::XHTML,
+];
+
+/// Shares the namespaces from `COMMON_NAMESPACES`, copying the others
+fn intern(namespace: &str) -> Cow<'static, str> {
+    match COMMON_NAMESPACES
+        .iter()
+        .find(|common| **common == namespace)
+    {
+        Some(common) => Cow::Borrowed(common),
+        None => Cow::Owned(namespace.to_owned()),
+    }
+}
+
 /// Parser state
 struct ParserSink {
     // Ready stanzas, shared with XMPPCodec
     queue: Arc<Mutex<VecDeque<QueueItem>>>,
     // Parsing stack
     stack: Vec<Element>,
-    ns_stack: Vec<HashMap<Option<String>, String>>,
+    // Namespace declarations in scope, with the depth of the element
+    // declaring them and their prefix
+    ns_stack: Vec<(usize, Option<String>, Cow<'static, str>)>,
+    // Applied to each stanza
+    limits: ParseLimits,
+    // Nodes in the current stanza
+    nodes: usize,
+    // Open tags left to skip from a stanza exceeding the limits
+    skipping: usize,
+    // Stanzas started, numbered like `Scanner` does
+    stanzas: usize,
+    // For each syntax error queued since `XMPPCodec::locate_errors`
+    // last ran, the stanza it was in or came before
+    errors: Vec<usize>,
 }
 
 impl ParserSink {
@@ -50,9 +167,33 @@ impl ParserSink {
             queue,
             stack: vec![],
             ns_stack: vec![],
+            limits: ParseLimits::default(),
+            nodes: 0,
+            skipping: 0,
+            stanzas: 0,
+            errors: vec![],
         }
     }
 
+    /// Counts a node at `depth` of the current stanza, whose remains
+    /// get dropped if this exceeds the limits
+    fn admit(&mut self, depth: usize) -> bool {
+        self.nodes += 1;
+        let error = if depth > self.limits.max_depth {
+            ParserError::TooDeep
+        } else if self.nodes > self.limits.max_nodes {
+            ParserError::TooManyNodes
+        } else {
+            return true;
+        };
+        // Every open tag of the stanza, but not the stream's.
+        self.skipping = self.stack.len() - 1;
+        self.stack.truncate(1);
+        self.ns_stack.retain(|(depth, _, _)| *depth == 0);
+        self.push_queue_error(error);
+        false
+    }
+
     fn push_queue(&self, pkt: Packet) {
         self.queue.lock().unwrap().push_back(Ok(pkt));
     }
@@ -61,19 +202,48 @@ impl ParserSink {
         self.queue.lock().unwrap().push_back(Err(e));
     }
 
-    /// Lookup XML namespace declaration for given prefix (or no prefix)
-    fn lookup_ns(&self, prefix: &Option<String>) -> Option<&str> {
-        for nss in self.ns_stack.iter().rev() {
-            if let Some(ns) = nss.get(prefix) {
-                return Some(ns);
-            }
-        }
+    /// Queues an error in the markup, to be located by `XMPPCodec`
+    fn push_syntax_error(&mut self, e: ParserError) {
+        let in_stanza = self.stack.len() > 1 || self.skipping > 0;
+        // Otherwise it is in the start tag of the next one, if any.
+        let stanza = if in_stanza {
+            self.stanzas
+        } else {
+            self.stanzas + 1
+        };
+        self.errors.push(stanza);
+        self.push_queue_error(e);
+    }
 
-        None
+    /// Lookup XML namespace declaration for given prefix (or no prefix)
+    fn lookup_ns(&self, prefix: Option<&str>) -> Option<&str> {
+        self.ns_stack
+            .iter()
+            .rev()
+            .find(|(_, declared, _)| declared.as_deref() == prefix)
+            .map(|(_, _, ns)| ns.as_ref())
     }
 
     fn handle_start_tag(&mut self, tag: Tag) {
-        let mut nss = HashMap::new();
+        if self.skipping > 0 {
+            self.skipping += 1;
+            return;
+        }
+        match self.stack.len() {
+            0 => (),
+            depth => {
+                if depth == 1 {
+                    self.nodes = 0;
+                    self.stanzas += 1;
+                }
+                if !self.admit(depth) {
+                    // This tag is open too.
+                    self.skipping += 1;
+                    return;
+                }
+            }
+        }
+        let depth = self.stack.len();
         let is_prefix_xmlns = |attr: &Attribute| {
             attr.name
                 .prefix
@@ -84,19 +254,23 @@ impl ParserSink {
         for attr in &tag.attrs {
             match attr.name.local.as_ref() {
                 "xmlns" => {
-                    nss.insert(None, attr.value.as_ref().to_owned());
+                    self.ns_stack
+                        .push((depth, None, intern(attr.value.as_ref())));
                 }
                 prefix if is_prefix_xmlns(attr) => {
-                    nss.insert(Some(prefix.to_owned()), attr.value.as_ref().to_owned());
+                    self.ns_stack.push((
+                        depth,
+                        Some(prefix.to_owned()),
+                        intern(attr.value.as_ref()),
+                    ));
                 }
                 _ => (),
             }
         }
-        self.ns_stack.push(nss);
 
         let el = {
             let el_ns = self
-                .lookup_ns(&tag.name.prefix.map(|prefix| prefix.as_ref().to_owned()))
+                .lookup_ns(tag.name.prefix.as_ref().map(|prefix| prefix.as_ref()))
                 .unwrap();
             let mut el_builder = Element::builder(tag.name.local.as_ref(), el_ns);
             for attr in &tag.attrs {
@@ -117,21 +291,25 @@ impl ParserSink {
         };
 
         if self.stack.is_empty() {
-            let attrs = HashMap::from_iter(tag.attrs.iter().map(|attr| {
-                (
-                    attr.name.local.as_ref().to_owned(),
-                    attr.value.as_ref().to_owned(),
-                )
-            }));
-            self.push_queue(Packet::StreamStart(attrs));
+            self.push_queue(Packet::StreamStart(StreamHeader::from_attrs(&tag.attrs)));
         }
 
         self.stack.push(el);
     }
 
     fn handle_end_tag(&mut self) {
+        if self.skipping > 0 {
+            self.skipping -= 1;
+            return;
+        }
         let el = self.stack.pop().unwrap();
-        self.ns_stack.pop();
+        let depth = self.stack.len();
+        while let Some((declared_at, _, _)) = self.ns_stack.last() {
+            if *declared_at < depth {
+                break;
+            }
+            self.ns_stack.pop();
+        }
 
         match self.stack.len() {
             // </stream:stream>
@@ -156,18 +334,21 @@ impl TokenSink for ParserSink {
                     self.handle_start_tag(tag);
                     self.handle_end_tag();
                 }
-                TagKind::ShortTag => self.push_queue_error(ParserError::ShortTag),
+                TagKind::ShortTag => self.push_syntax_error(ParserError::ShortTag),
             },
+            Token::CharacterTokens(_) if self.skipping > 0 => (),
             Token::CharacterTokens(tendril) => match self.stack.len() {
                 0 | 1 => self.push_queue(Packet::Text(tendril.into())),
                 len => {
-                    let el = &mut self.stack[len - 1];
-                    el.append_text_node(tendril);
+                    if self.admit(len) {
+                        let el = &mut self.stack[len - 1];
+                        el.append_text_node(tendril);
+                    }
                 }
             },
             Token::EOFToken => self.push_queue(Packet::StreamEnd),
             Token::ParseError(s) => {
-                self.push_queue_error(ParserError::Parse(ParseError(s)));
+                self.push_syntax_error(ParserError::Parse(ParseError(s)));
             }
             _ => (),
         }
@@ -177,17 +358,342 @@ impl TokenSink for ParserSink {
     // }
 }
 
+/// Where [`Scanner`] is in the markup
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Markup {
+    Text,
+    /// Right after `<`
+    Open,
+    /// In a start tag, within quotes if any, right after a `/` if
+    /// `slash`
+    StartTag {
+        quote: Option<u8>,
+        slash: bool,
+    },
+    EndTag,
+    /// `<?…?>`, right after a `?` if `question`
+    Instruction {
+        question: bool,
+    },
+    /// Right after `<!`
+    Bang,
+    /// `<!--…-->` or `<![CDATA[…]]>`, ending with two `end` bytes and
+    /// `>`, `run` of them having just been seen
+    Section {
+        end: u8,
+        run: u8,
+    },
+    /// `<!…>`, such as a doctype
+    Declaration,
+}
+
+/// What a byte means for the stanza around it, from [`Scanner::step`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Boundary {
+    /// This `<` may start a stanza
+    Start,
+    /// What looked like the start of a stanza isn't one
+    Cancel,
+    /// This `>` ends a stanza
+    End,
+}
+
+/// Finds where stanzas start and end in what the parser gets fed,
+/// whether or not the parser got to decode anything of them yet, such
+/// as from a start tag still being received
+///
+/// This only follows the markup, the parser still being the one
+/// checking it.
+#[derive(Debug, Clone)]
+struct Scanner {
+    markup: Markup,
+    /// Open elements, `<stream:stream>` included
+    depth: usize,
+    /// A stanza started and didn't end yet
+    in_stanza: bool,
+    /// Where the next byte is in the stream
+    offset: usize,
+    line: usize,
+    column: usize,
+    /// Where the last `<` which may start a stanza is
+    start: Position,
+    /// Stanzas started, numbered from 1 once their name begins
+    stanzas: usize,
+    /// Where the stanzas the parser may still report errors for start
+    starts: VecDeque<(usize, Position)>,
+}
+
+impl Scanner {
+    fn new() -> Scanner {
+        Scanner {
+            markup: Markup::Text,
+            depth: 0,
+            in_stanza: false,
+            offset: 0,
+            line: 1,
+            column: 1,
+            start: Position {
+                offset: 0,
+                line_column: None,
+            },
+            stanzas: 0,
+            starts: VecDeque::new(),
+        }
+    }
+
+    /// Where the stanza numbered `stanza` starts, if scanned and not
+    /// forgotten yet
+    fn stanza_start(&self, stanza: usize) -> Option<Position> {
+        self.starts
+            .iter()
+            .find(|(number, _)| *number == stanza)
+            .map(|(_, position)| *position)
+    }
+
+    /// Forget where the stanzas before the one numbered `stanza` start
+    fn forget_before(&mut self, stanza: usize) {
+        while let Some((number, _)) = self.starts.front() {
+            if *number >= stanza {
+                break;
+            }
+            self.starts.pop_front();
+        }
+    }
+
+    /// Follow `chunk`, which the parser got fed just before
+    fn scan(&mut self, chunk: &[u8]) {
+        for byte in chunk {
+            self.step(*byte);
+        }
+    }
+
+    /// Follow the next byte fed to the parser
+    fn step(&mut self, byte: u8) -> Option<Boundary> {
+        let here = Position {
+            offset: self.offset,
+            line_column: Some((self.line, self.column)),
+        };
+        self.offset += 1;
+        if byte == b'\n' {
+            self.line += 1;
+            self.column = 1;
+        } else if byte & 0xc0 != 0x80 {
+            // Not a UTF-8 continuation byte, so the start of a character
+            self.column += 1;
+        }
+
+        let mut boundary = None;
+        self.markup = match (self.markup, byte) {
+            (Markup::Text, b'<') => {
+                if self.depth == 1 {
+                    // Stanzas only follow each other, the parser never
+                    // holding more than one incomplete.
+                    debug_assert!(!self.in_stanza);
+                    boundary = Some(Boundary::Start);
+                    self.start = here;
+                }
+                Markup::Open
+            }
+            (Markup::Text, _) => Markup::Text,
+            (Markup::Open, b'/') => {
+                if self.depth == 1 {
+                    // </stream:stream>
+                    boundary = Some(Boundary::Cancel);
+                }
+                Markup::EndTag
+            }
+            (Markup::Open, b'?') | (Markup::Open, b'!') => {
+                // Not a stanza, which can't have a prolog.
+                if self.depth == 1 {
+                    boundary = Some(Boundary::Cancel);
+                }
+                if byte == b'?' {
+                    Markup::Instruction { question: false }
+                } else {
+                    Markup::Bang
+                }
+            }
+            (Markup::Open, _) => {
+                if self.depth == 1 {
+                    self.stanzas += 1;
+                    self.starts.push_back((self.stanzas, self.start));
+                }
+                Markup::StartTag {
+                    quote: None,
+                    slash: false,
+                }
+            }
+            (Markup::StartTag { quote: None, .. }, b'\'')
+            | (Markup::StartTag { quote: None, .. }, b'"') => Markup::StartTag {
+                quote: Some(byte),
+                slash: false,
+            },
+            (
+                Markup::StartTag {
+                    quote: Some(quote), ..
+                },
+                _,
+            ) => Markup::StartTag {
+                quote: if byte == quote { None } else { Some(quote) },
+                slash: false,
+            },
+            (Markup::StartTag { quote: None, slash }, b'>') => {
+                if !slash {
+                    self.depth += 1;
+                } else if self.depth == 1 {
+                    boundary = Some(Boundary::End);
+                }
+                Markup::Text
+            }
+            (Markup::StartTag { quote: None, .. }, _) => Markup::StartTag {
+                quote: None,
+                slash: byte == b'/',
+            },
+            (Markup::EndTag, b'>') => {
+                self.depth = self.depth.saturating_sub(1);
+                if self.depth == 1 {
+                    boundary = Some(Boundary::End);
+                }
+                Markup::Text
+            }
+            (Markup::EndTag, _) => Markup::EndTag,
+            (Markup::Instruction { question: true }, b'>') => Markup::Text,
+            (Markup::Instruction { .. }, _) => Markup::Instruction {
+                question: byte == b'?',
+            },
+            (Markup::Bang, b'-') | (Markup::Bang, b'[') => Markup::Section {
+                end: if byte == b'-' { b'-' } else { b']' },
+                run: 0,
+            },
+            (Markup::Bang, b'>') => Markup::Text,
+            (Markup::Bang, _) => Markup::Declaration,
+            (Markup::Section { run, .. }, b'>') if run >= 2 => Markup::Text,
+            (Markup::Section { end, run }, _) => Markup::Section {
+                end,
+                run: if byte == end {
+                    run.saturating_add(1)
+                } else {
+                    0
+                },
+            },
+            (Markup::Declaration, b'>') => Markup::Text,
+            (Markup::Declaration, _) => Markup::Declaration,
+        };
+        match boundary {
+            Some(Boundary::Start) => self.in_stanza = true,
+            Some(Boundary::Cancel) | Some(Boundary::End) => self.in_stanza = false,
+            None => (),
+        }
+        boundary
+    }
+}
+
+/// Keeps the bytes of stanzas as received, where [`Scanner`] finds
+/// them
+struct RawStanzas {
+    /// Where the current stanza starts in the bytes being scanned, if
+    /// one is
+    start: Option<usize>,
+    /// The beginning of the current stanza, from previous reads
+    partial: BytesMut,
+    /// Complete stanzas, waiting for their packets to be decoded
+    done: VecDeque<Bytes>,
+    /// Stanzas dropped for exceeding the limits, whose bytes are still
+    /// to be skipped
+    dropped: usize,
+    /// The bytes of the stanza just decoded
+    last: Option<Bytes>,
+}
+
+impl RawStanzas {
+    fn new() -> RawStanzas {
+        RawStanzas {
+            start: None,
+            partial: BytesMut::new(),
+            done: VecDeque::new(),
+            dropped: 0,
+            last: None,
+        }
+    }
+
+    /// Follow `chunk` with `scanner`, the parser having got fed it just
+    /// before
+    fn scan(&mut self, scanner: &mut Scanner, chunk: &Bytes) {
+        for (i, byte) in chunk.iter().copied().enumerate() {
+            match scanner.step(byte) {
+                Some(Boundary::Start) => self.start = Some(i),
+                Some(Boundary::Cancel) => self.cancel(),
+                Some(Boundary::End) => self.end_element(chunk, i + 1),
+                None => (),
+            }
+        }
+        if let Some(start) = self.start {
+            self.partial.extend_from_slice(&chunk[start..]);
+            self.start = Some(0);
+        }
+    }
+
+    /// A stanza got closed, right before `end` in `chunk`
+    fn end_element(&mut self, chunk: &Bytes, end: usize) {
+        let start = match self.start.take() {
+            Some(start) => start,
+            None => return,
+        };
+        let raw = if self.partial.is_empty() {
+            chunk.slice(start..end)
+        } else {
+            self.partial.extend_from_slice(&chunk[start..end]);
+            self.partial.split().freeze()
+        };
+        self.done.push_back(raw);
+    }
+
+    /// What looked like the start of a stanza isn't one
+    fn cancel(&mut self) {
+        self.start = None;
+        self.partial.clear();
+    }
+
+    /// `item` just got decoded, which the next complete stanza belongs
+    /// to if it is one
+    fn decoded(&mut self, item: Option<&QueueItem>) {
+        self.last = None;
+        match item {
+            Some(Ok(Packet::Stanza(_))) => {
+                // The stanzas dropped before this one are complete too.
+                let dropped = self.dropped.min(self.done.len());
+                self.done.drain(..dropped);
+                self.dropped -= dropped;
+                self.last = self.done.pop_front();
+            }
+            // Raised as soon as the limits get exceeded, the rest of the
+            // stanza possibly not being read yet.
+            Some(Err(ParserError::TooDeep)) | Some(Err(ParserError::TooManyNodes)) => {
+                self.dropped += 1;
+            }
+            _ => (),
+        }
+    }
+}
+
 /// Stateful encoder/decoder for a bytestream from/to XMPP `Packet`
 pub struct XMPPCodec {
     /// Outgoing
     ns: Option<String>,
     /// Incoming
     parser: XmlTokenizer<ParserSink>,
+    /// What the parser gets fed, kept to reuse its allocation
+    input: BufferQueue,
     /// For handling incoming truncated utf8
-    // TODO: optimize using  tendrils?
     buf: Vec<u8>,
     /// Shared with ParserSink
     queue: Arc<Mutex<VecDeque<QueueItem>>>,
+    /// Scratch space for decoding and targets for encoding
+    pool: BufferPool,
+    /// Where stanzas start and end in what the parser got fed
+    scanner: Scanner,
+    /// The bytes of the stanzas received, if kept
+    raw: Option<RawStanzas>,
 }
 
 impl XMPPCodec {
@@ -200,68 +706,176 @@ impl XMPPCodec {
         XMPPCodec {
             ns: None,
             parser,
+            input: BufferQueue::new(),
             queue,
             buf: vec![],
+            pool: BufferPool::new(),
+            scanner: Scanner::new(),
+            raw: None,
         }
     }
 }
 
-impl Default for XMPPCodec {
-    fn default() -> Self {
-        Self::new()
+impl XMPPCodec {
+    /// Set how big incoming stanzas may be, those exceeding `limits`
+    /// being dropped with `ParserError::TooDeep` or
+    /// `ParserError::TooManyNodes`
+    ///
+    /// The default is fine for clients, but components relaying deep
+    /// payloads may need more.
+    pub fn set_limits(&mut self, limits: ParseLimits) {
+        self.parser.sink.limits = limits;
     }
-}
 
-impl Decoder for XMPPCodec {
-    type Item = Packet;
-    type Error = ParserError;
+    /// How much the buffers this codec reuses save
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
 
-    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let buf1: Box<dyn AsRef<[u8]>> = if !self.buf.is_empty() && !buf.is_empty() {
-            let mut prefix = std::mem::replace(&mut self.buf, vec![]);
-            prefix.extend_from_slice(&buf.split_to(buf.len()));
-            Box::new(prefix)
-        } else {
-            Box::new(buf.split_to(buf.len()))
-        };
-        let buf1 = buf1.as_ref().as_ref();
-        match from_utf8(buf1) {
-            Ok(s) => {
-                debug!("<< {:?}", s);
-                if !s.is_empty() {
-                    let mut buffer_queue = BufferQueue::new();
-                    let tendril = FromIterator::from_iter(s.chars());
-                    buffer_queue.push_back(tendril);
-                    self.parser.feed(&mut buffer_queue);
+    /// The buffers to encode into, given back once written
+    pub(crate) fn pool(&self) -> &BufferPool {
+        &self.pool
+    }
+
+    /// Keep the bytes of every stanza as received, available from
+    /// [`XMPPCodec::raw_stanza`] once it got decoded
+    ///
+    /// Stanzas contained in a single read share its buffer instead of
+    /// being copied, which then doesn't get reused for the next read.
+    pub fn set_keep_raw(&mut self, keep: bool) {
+        if !keep {
+            self.raw = None;
+        } else if self.raw.is_none() {
+            self.raw = Some(RawStanzas::new());
+        }
+    }
+
+    /// Whether part of a stanza got received, but not all of it yet
+    ///
+    /// This is the case as soon as its first byte is, before the parser
+    /// can decode anything of it, and still is while what remains of a
+    /// stanza exceeding the limits gets skipped.
+    pub fn stanza_in_progress(&self) -> bool {
+        self.scanner.in_stanza
+    }
+
+    /// Wrap the syntax errors the parser just queued into
+    /// [`ParserError::InStanza`], with where their stanza starts
+    fn locate_errors(&mut self) {
+        let sink = &mut self.parser.sink;
+        if !sink.errors.is_empty() {
+            let mut queue = self.queue.lock().unwrap();
+            // They are the last ones not located yet.
+            let unlocated = queue.iter_mut().rev().filter_map(|item| match item {
+                Err(e @ ParserError::Parse(_)) | Err(e @ ParserError::ShortTag) => Some(e),
+                _ => None,
+            });
+            for (e, stanza) in unlocated.zip(sink.errors.drain(..).rev()) {
+                if let Some(position) = self.scanner.stanza_start(stanza) {
+                    let error = std::mem::replace(e, ParserError::ShortTag);
+                    *e = ParserError::InStanza(Box::new(error), position);
                 }
             }
-            // Remedies for truncated utf8
-            Err(e) if e.valid_up_to() >= buf1.len() - 3 => {
-                // Prepare all the valid data
-                let mut b = BytesMut::with_capacity(e.valid_up_to());
-                b.put(&buf1[0..e.valid_up_to()]);
-
-                // Retry
-                let result = self.decode(&mut b);
+        }
+        // Only the stanza the parser is in, or the next one, may still
+        // get errors.
+        self.scanner.forget_before(sink.stanzas);
+    }
 
-                // Keep the tail back in
-                self.buf.extend_from_slice(&buf1[e.valid_up_to()..]);
+    /// The bytes of the stanza last decoded, exactly as received, if
+    /// [`XMPPCodec::set_keep_raw`] got enabled
+    ///
+    /// This is `None` after any other packet.
+    pub fn raw_stanza(&self) -> Option<&Bytes> {
+        self.raw.as_ref().and_then(|raw| raw.last.as_ref())
+    }
 
-                return result;
+    /// Parse `input`, keeping back a truncated UTF-8 sequence at its end,
+    /// returning how many bytes got parsed
+    fn feed(&mut self, input: &[u8]) -> Result<usize, ParserError> {
+        let valid = match from_utf8(input) {
+            Ok(s) => s,
+            // Remedies for truncated utf8
+            Err(e) if e.error_len().is_none() => {
+                self.buf.extend_from_slice(&input[e.valid_up_to()..]);
+                from_utf8(&input[..e.valid_up_to()]).unwrap()
             }
             Err(e) => {
                 error!(
                     "error {} at {}/{} in {:?}",
                     e,
                     e.valid_up_to(),
-                    buf1.len(),
-                    buf1
+                    input.len(),
+                    input
                 );
                 return Err(ParserError::Utf8(e));
             }
+        };
+        debug!("<< {:?}", valid);
+        if !valid.is_empty() {
+            self.input.push_back(StrTendril::from_slice(valid));
+            self.parser.feed(&mut self.input);
+        }
+        Ok(valid.len())
+    }
+}
+
+impl Default for XMPPCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for XMPPCodec {
+    type Item = Packet;
+    type Error = ParserError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // Unless stanzas keep pointing into what got read, the read buffer
+        // gets cleared rather than split, so that it keeps its allocation
+        // for the next read.
+        if self.raw.is_some() {
+            let chunk = if !self.buf.is_empty() && !buf.is_empty() {
+                let mut joined = BytesMut::with_capacity(self.buf.len() + buf.len());
+                joined.extend_from_slice(&self.buf);
+                joined.extend_from_slice(buf);
+                self.buf.clear();
+                buf.clear();
+                joined.freeze()
+            } else {
+                buf.split().freeze()
+            };
+            let parsed = self.feed(&chunk)?;
+            if let Some(raw) = self.raw.as_mut() {
+                raw.scan(&mut self.scanner, &chunk.slice(..parsed));
+            }
+        } else if !self.buf.is_empty() && !buf.is_empty() {
+            let mut joined = self.pool.take();
+            joined.extend_from_slice(&self.buf);
+            joined.extend_from_slice(buf);
+            self.buf.clear();
+            buf.clear();
+            let result = self.feed(&joined);
+            if let Ok(parsed) = result {
+                self.scanner.scan(&joined[..parsed]);
+            }
+            self.pool.put(joined);
+            result?;
+        } else {
+            let result = self.feed(buf);
+            if let Ok(parsed) = result {
+                self.scanner.scan(&buf[..parsed]);
+            }
+            buf.clear();
+            result?;
         }
+        self.locate_errors();
 
-        match self.queue.lock().unwrap().pop_front() {
+        let item = self.queue.lock().unwrap().pop_front();
+        if let Some(raw) = self.raw.as_mut() {
+            raw.decoded(item.as_ref());
+        }
+        match item {
             None => Ok(None),
             Some(result) => result.map(|pkt| Some(pkt)),
         }
@@ -287,16 +901,27 @@ impl Encoder<Packet> for XMPPCodec {
         }
 
         match item {
-            Packet::StreamStart(start_attrs) => {
+            Packet::StreamStart(header) => {
                 let mut buf = String::new();
-                write!(buf, "<stream:stream").map_err(to_io_err)?;
-                for (name, value) in start_attrs {
-                    write!(buf, " {}=\"{}\"", escape(&name), escape(&value)).map_err(to_io_err)?;
-                    if name == "xmlns" {
-                        self.ns = Some(value);
+                write!(buf, "<stream:stream xmlns:stream=\"{}\"", ns::STREAM).map_err(to_io_err)?;
+                let attrs = [
+                    ("xmlns", &header.ns),
+                    ("id", &header.id),
+                    ("from", &header.from),
+                    ("to", &header.to),
+                    ("version", &header.version),
+                    ("xml:lang", &header.lang),
+                ];
+                for (name, value) in attrs.iter() {
+                    if let Some(value) = value {
+                        let value = escaped(value, escape_attribute);
+                        write!(buf, " {}=\"{}\"", name, value).map_err(to_io_err)?;
                     }
                 }
                 write!(buf, ">\n").map_err(to_io_err)?;
+                if header.ns.is_some() {
+                    self.ns = header.ns;
+                }
 
                 debug!(">> {:?}", buf);
                 write!(dst, "{}", buf).map_err(to_io_err)
@@ -321,24 +946,16 @@ impl Encoder<Packet> for XMPPCodec {
 
 /// Write XML-escaped text string
 pub fn write_text<W: Write>(text: &str, writer: &mut W) -> Result<(), std::fmt::Error> {
-    write!(writer, "{}", escape(text))
+    write!(writer, "{}", escaped(text, escape_text))
 }
 
-/// Copied from `RustyXML` for now
-pub fn escape(input: &str) -> String {
-    let mut result = String::with_capacity(input.len());
-
-    for c in input.chars() {
-        match c {
-            '&' => result.push_str("&amp;"),
-            '<' => result.push_str("&lt;"),
-            '>' => result.push_str("&gt;"),
-            '\'' => result.push_str("&apos;"),
-            '"' => result.push_str("&quot;"),
-            o => result.push(o),
-        }
+/// Escape `input` the same way minidom does for the same context
+fn escaped<'a>(input: &'a str, escape: fn(&[u8]) -> Cow<[u8]>) -> Cow<'a, str> {
+    match escape(input.as_bytes()) {
+        Cow::Borrowed(_) => Cow::Borrowed(input),
+        // Only ASCII characters get replaced, by ASCII entities.
+        Cow::Owned(bytes) => Cow::Owned(String::from_utf8(bytes).unwrap()),
     }
-    result
 }
 
 /// BytesMut impl only std::fmt::Write but not std::io::Write. The
@@ -382,6 +999,83 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_stream_header() {
+        let mut c = XMPPCodec::new();
+        let mut b = BytesMut::with_capacity(1024);
+        b.put_slice(b"<?xml version='1.0'?><stream:stream xmlns:stream='http://etherx.jabber.org/streams' xmlns='jabber:client' id='++TR84Sm6A3hnt3Q065SnAbbk3Y=' from='im.example.com' to='juliet@im.example.com' version='1.0' xml:lang='en' foo='bar'>");
+        let header = match c.decode(&mut b) {
+            Ok(Some(Packet::StreamStart(header))) => header,
+            r => panic!("unexpected result: {:?}", r),
+        };
+        let expected = StreamHeader {
+            ns: Some(String::from("jabber:client")),
+            id: Some(String::from("++TR84Sm6A3hnt3Q065SnAbbk3Y=")),
+            from: Some(String::from("im.example.com")),
+            to: Some(String::from("juliet@im.example.com")),
+            version: Some(String::from("1.0")),
+            lang: Some(String::from("en")),
+        };
+        assert_eq!(header, expected);
+
+        // Sent back, it reads the same.
+        let mut encoded = BytesMut::new();
+        XMPPCodec::new()
+            .encode(Packet::StreamStart(header), &mut encoded)
+            .unwrap();
+        assert_eq!(
+            &encoded[..],
+            &b"<stream:stream xmlns:stream=\"http://etherx.jabber.org/streams\" xmlns=\"jabber:client\" id=\"++TR84Sm6A3hnt3Q065SnAbbk3Y=\" from=\"im.example.com\" to=\"juliet@im.example.com\" version=\"1.0\" xml:lang=\"en\">\n"[..]
+        );
+        match XMPPCodec::new().decode(&mut encoded) {
+            Ok(Some(Packet::StreamStart(header))) => assert_eq!(header, expected),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_escaping_matches_minidom() {
+        let input = "it's <b> & \"c\" ]]>\t\r\n";
+
+        let mut text = BytesMut::new();
+        XMPPCodec::new()
+            .encode(Packet::Text(String::from(input)), &mut text)
+            .unwrap();
+        let mut stanza = BytesMut::new();
+        let elem = Element::builder("body", ns::JABBER_CLIENT)
+            .attr("a", input)
+            .append(input)
+            .build();
+        XMPPCodec::new()
+            .encode(Packet::Stanza(elem), &mut stanza)
+            .unwrap();
+
+        let expected_text = "it's &lt;b> &amp; \"c\" ]]&gt;\t&#13;\n";
+        let expected_attr = "it's &lt;b> &amp; &quot;c&quot; ]]>&#9;&#13;&#10;";
+        assert_eq!(&text[..], expected_text.as_bytes());
+        assert_eq!(
+            &stanza[..],
+            format!(
+                "<body xmlns=\"jabber:client\" a=\"{}\">{}</body>",
+                expected_attr, expected_text
+            )
+            .as_bytes()
+        );
+
+        let mut header = BytesMut::new();
+        XMPPCodec::new()
+            .encode(
+                Packet::StreamStart(StreamHeader {
+                    id: Some(String::from(input)),
+                    ..StreamHeader::default()
+                }),
+                &mut header,
+            )
+            .unwrap();
+        let header = std::str::from_utf8(&header).unwrap();
+        assert!(header.contains(&format!(" id=\"{}\"", expected_attr)));
+    }
+
     #[test]
     fn test_stream_end() {
         let mut c = XMPPCodec::new();
@@ -528,4 +1222,418 @@ mod tests {
             _ => false,
         });
     }
+
+    #[test]
+    fn test_whitespace() {
+        use futures::{executor::block_on, sink::SinkExt};
+        use std::io::Cursor;
+        use tokio_util::codec::FramedWrite;
+        let mut framed = FramedWrite::new(Cursor::new(vec![]), XMPPCodec::new());
+        block_on(framed.send(Packet::Text(String::from(" ")))).expect("send");
+        assert_eq!(framed.get_ref().get_ref(), b" ");
+
+        let mut c = XMPPCodec::new();
+        let mut b = BytesMut::with_capacity(1024);
+        b.put_slice(b"<?xml version='1.0'?><stream:stream xmlns:stream='http://etherx.jabber.org/streams' version='1.0' xmlns='jabber:client'>");
+        assert!(matches!(c.decode(&mut b), Ok(Some(Packet::StreamStart(_)))));
+
+        b.clear();
+        b.put_slice(b" ");
+        match c.decode(&mut b) {
+            Ok(Some(Packet::Text(text))) => assert_eq!(text, " "),
+            r => panic!("unexpected packet: {:?}", r),
+        }
+    }
+
+    /// Decodes every packet in `xml`, after a stream start
+    fn decode_all(c: &mut XMPPCodec, xml: &str) -> Vec<Result<Packet, ParserError>> {
+        let mut b = BytesMut::with_capacity(1024);
+        b.put_slice(b"<?xml version='1.0'?><stream:stream xmlns:stream='http://etherx.jabber.org/streams' version='1.0' xmlns='jabber:client'>");
+        b.put_slice(xml.as_bytes());
+        let mut packets = Vec::new();
+        loop {
+            match c.decode(&mut b) {
+                Ok(None) => break,
+                Ok(Some(Packet::StreamStart(_))) => (),
+                Ok(Some(packet)) => packets.push(Ok(packet)),
+                Err(e) => packets.push(Err(e)),
+            }
+        }
+        packets
+    }
+
+    #[test]
+    fn test_depth_bomb() {
+        let mut c = XMPPCodec::new();
+        let depth = 10_000;
+        let xml = format!(
+            "<message>{}{}</message><presence/>",
+            "<a xmlns='urn:example:bomb'>".repeat(depth),
+            "</a>".repeat(depth)
+        );
+        let packets = decode_all(&mut c, &xml);
+        assert_eq!(packets.len(), 2);
+        assert!(matches!(packets[0], Err(ParserError::TooDeep)));
+        // Only that stanza got dropped.
+        match packets[1] {
+            Ok(Packet::Stanza(ref stanza)) => assert_eq!(stanza.name(), "presence"),
+            ref packet => panic!("unexpected packet: {:?}", packet),
+        }
+        assert!(c.parser.sink.stack.len() <= 1);
+
+        // Components may allow more.
+        let mut c = XMPPCodec::new();
+        c.set_limits(ParseLimits {
+            max_depth: 64,
+            ..ParseLimits::default()
+        });
+        let xml = format!(
+            "<message>{}{}</message>",
+            "<a>".repeat(40),
+            "</a>".repeat(40)
+        );
+        let packets = decode_all(&mut c, &xml);
+        assert!(matches!(packets[..], [Ok(Packet::Stanza(_))]));
+    }
+
+    #[test]
+    fn test_breadth_bomb() {
+        let mut c = XMPPCodec::new();
+        let xml = format!(
+            "<message>{}</message><presence/>",
+            "<b xmlns='urn:example:bomb'>x</b>".repeat(100_000)
+        );
+        let packets = decode_all(&mut c, &xml);
+        assert_eq!(packets.len(), 2);
+        assert!(matches!(packets[0], Err(ParserError::TooManyNodes)));
+        assert!(matches!(packets[1], Ok(Packet::Stanza(_))));
+    }
+
+    /// Decode `reads` one after the other, with the bytes each stanza got
+    /// received as
+    fn decode_raw(c: &mut XMPPCodec, reads: &[&[u8]]) -> Vec<(Packet, Option<Bytes>)> {
+        let mut packets = vec![];
+        let mut b = BytesMut::new();
+        for read in reads {
+            b.put_slice(read);
+            while let Some(packet) = c.decode(&mut b).unwrap() {
+                packets.push((packet, c.raw_stanza().cloned()));
+            }
+        }
+        packets
+    }
+
+    #[test]
+    fn test_raw_stanzas() {
+        let mut c = XMPPCodec::new();
+        c.set_keep_raw(true);
+        let header = b"<?xml version='1.0'?><stream:stream xmlns:stream='http://etherx.jabber.org/streams' version='1.0' xmlns='jabber:client'>";
+        let message = "<message to='a@b' a=\"/>\" b='<\"'><body>é &amp; <![CDATA[</message>]]></body></message>";
+        let presence = "<presence\n/>";
+        let stanzas = format!("{} {}", message, presence);
+        let packets = decode_raw(&mut c, &[header, stanzas.as_bytes()]);
+        match &packets[..] {
+            [(Packet::StreamStart(_), None), (Packet::Stanza(first), Some(first_raw)), (Packet::Text(_), None), (Packet::Stanza(second), Some(second_raw))] =>
+            {
+                assert_eq!(first.name(), "message");
+                assert_eq!(&first_raw[..], message.as_bytes());
+                assert_eq!(second.name(), "presence");
+                assert_eq!(&second_raw[..], presence.as_bytes());
+                // Both point into the same read.
+                assert_eq!(
+                    first_raw.as_ptr() as usize + message.len() + 1,
+                    second_raw.as_ptr() as usize
+                );
+            }
+            packets => panic!("unexpected packets: {:?}", packets),
+        }
+
+        // Split across reads, even within a character.
+        let message = message.as_bytes();
+        let split = message.iter().position(|byte| *byte == 0xc3).unwrap() + 1;
+        let packets = decode_raw(
+            &mut c,
+            &[&message[..split], &message[split..], b"</stream:stream>"],
+        );
+        match &packets[..] {
+            [(Packet::Stanza(_), Some(raw)), (Packet::StreamEnd, None)] => {
+                assert_eq!(&raw[..], message)
+            }
+            packets => panic!("unexpected packets: {:?}", packets),
+        }
+    }
+
+    #[test]
+    fn test_raw_stanzas_late() {
+        // Enabled once the stream started, and around dropped stanzas.
+        let mut c = XMPPCodec::new();
+        let packets = decode_raw(&mut c, &[b"<stream:stream xmlns:stream='http://etherx.jabber.org/streams' xmlns='jabber:client'><iq type='get' id='1'/>"]);
+        assert!(matches!(
+            packets[..],
+            [(Packet::StreamStart(_), None), (Packet::Stanza(_), None)]
+        ));
+        c.set_keep_raw(true);
+        c.set_limits(ParseLimits {
+            max_depth: 2,
+            ..ParseLimits::default()
+        });
+        let mut b = BytesMut::new();
+        b.put_slice(b"<message><a><b/>");
+        assert!(matches!(c.decode(&mut b), Err(ParserError::TooDeep)));
+        let packets = decode_raw(&mut c, &[b"</a></message><presence type='unavailable'/>"]);
+        match &packets[..] {
+            [(Packet::Stanza(_), Some(raw))] => {
+                assert_eq!(&raw[..], b"<presence type='unavailable'/>")
+            }
+            packets => panic!("unexpected packets: {:?}", packets),
+        }
+
+        c.set_keep_raw(false);
+        let packets = decode_raw(&mut c, &[b"<presence/>"]);
+        assert!(matches!(packets[..], [(Packet::Stanza(_), None)]));
+    }
+
+    #[test]
+    fn test_stanza_in_progress() {
+        let mut c = XMPPCodec::new();
+        let mut feed = |bytes: &[u8]| {
+            let mut b = BytesMut::from(bytes);
+            while c.decode(&mut b).unwrap().is_some() {}
+            c.stanza_in_progress()
+        };
+        // Neither the stream header nor what comes between stanzas.
+        assert!(!feed(b"<?xml version='1.0'?><stream:stream xmlns:stream='http://etherx.jabber.org/streams' xmlns='jabber:client'"));
+        assert!(!feed(b">\n  "));
+        assert!(!feed(b"<iq type='get' id='1'/> "));
+
+        // From its very first byte, the parser not seeing a tag yet.
+        assert!(feed(b"<"));
+        assert!(feed(b"message to='a@b' id='>"));
+        assert!(feed(b"'><body>Hi</body"));
+        assert!(!feed(b"></message>"));
+
+        // Until what remains of a stanza exceeding the limits got skipped.
+        c.set_limits(ParseLimits {
+            max_depth: 2,
+            ..ParseLimits::default()
+        });
+        let mut b = BytesMut::from(&b"<message><a><b/>"[..]);
+        assert!(matches!(c.decode(&mut b), Err(ParserError::TooDeep)));
+        assert!(c.stanza_in_progress());
+        let mut b = BytesMut::from(&b"</a></message>\n"[..]);
+        while c.decode(&mut b).unwrap().is_some() {}
+        assert!(!c.stanza_in_progress());
+    }
+
+    #[test]
+    fn test_error_position() {
+        let mut c = XMPPCodec::new();
+        let mut errors = Vec::new();
+        let mut feed = |bytes: &[u8]| {
+            let mut b = BytesMut::from(bytes);
+            loop {
+                match c.decode(&mut b) {
+                    Ok(None) => break,
+                    Ok(Some(_)) => (),
+                    Err(e) => errors.push(e),
+                }
+            }
+        };
+        let header = "<stream:stream xmlns:stream='http://etherx.jabber.org/streams' xmlns='jabber:client'>\n";
+        feed(header.as_bytes());
+        feed(
+            b"<iq type='get' id='1'/>\n<message to='a@b'>\n  <body>\xc3\xa9</body x>\n</message>\n",
+        );
+        // The stanza started in an earlier read.
+        feed(b"\n<message to='a@b'>\n  <body>");
+        feed(b"x</body a=b>\n</message>\n<iq type='get' id='2'/>");
+
+        let mut positions: Vec<_> = errors
+            .iter()
+            .map(|e| {
+                assert!(matches!(e.kind(), ParserError::Parse(_)));
+                e.stanza_position().unwrap()
+            })
+            .collect();
+        // The parser may report several errors for the same markup.
+        positions.dedup();
+        let first = header.len() + "<iq type='get' id='1'/>\n".len();
+        let second = first + "<message to='a@b'>\n  <body>\u{e9}</body x>\n</message>\n\n".len();
+        assert_eq!(
+            positions,
+            [
+                Position {
+                    offset: first,
+                    line_column: Some((3, 1))
+                },
+                Position {
+                    offset: second,
+                    line_column: Some((7, 1))
+                },
+            ]
+        );
+        assert!(errors.last().unwrap().to_string().ends_with(&format!(
+            " in the stanza starting at line 7, column 1 (byte {})",
+            second
+        )));
+    }
+
+    /// Counts the allocations of each thread, to measure those of the
+    /// parser
+    #[allow(unsafe_code)]
+    mod counting {
+        use std::alloc::{GlobalAlloc, Layout, System};
+        use std::cell::Cell;
+
+        struct Counting;
+
+        thread_local!(static ALLOCATIONS: Cell<usize> = const { Cell::new(0) });
+
+        unsafe impl GlobalAlloc for Counting {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+                System.alloc(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                System.dealloc(ptr, layout)
+            }
+
+            unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+                let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+                System.realloc(ptr, layout, new_size)
+            }
+        }
+
+        #[global_allocator]
+        static COUNTING: Counting = Counting;
+
+        pub(super) fn allocations() -> usize {
+            ALLOCATIONS.with(Cell::get)
+        }
+    }
+
+    /// How many allocations decoding `stanza` takes, once the stream is
+    /// open
+    fn allocations_decoding(stanza: &str) -> usize {
+        let mut c = XMPPCodec::new();
+        let mut b = BytesMut::with_capacity(1024);
+        b.put_slice(b"<?xml version='1.0'?><stream:stream xmlns:stream='http://etherx.jabber.org/streams' version='1.0' xmlns='jabber:client'>");
+        assert!(matches!(c.decode(&mut b), Ok(Some(Packet::StreamStart(_)))));
+        b.put_slice(stanza.as_bytes());
+        let before = counting::allocations();
+        let packet = c.decode(&mut b);
+        let after = counting::allocations();
+        match packet {
+            Ok(Some(Packet::Stanza(_))) => (),
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+        after - before
+    }
+
+    #[test]
+    fn test_namespace_allocations() {
+        let known = "<message xmlns='jabber:client' to='a@b'><body>Hi</body><x xmlns='http://jabber.org/protocol/muc#user'/><active xmlns='http://jabber.org/protocol/chatstates'/></message>";
+        let unknown = "<message xmlns='jabber:client' to='a@b'><body>Hi</body><x xmlns='urn:example:unknown:namespace:muc#u'/><active xmlns='urn:example:unknown:namespace:chatsta'/></message>";
+        assert_eq!(known.len(), unknown.len());
+        let known = allocations_decoding(known);
+        let unknown = allocations_decoding(unknown);
+        // Only the declarations of unknown namespaces get copied.
+        assert!(known < unknown);
+    }
+
+    /// How many allocations relaying `count` stanzas takes, decoding each
+    /// then encoding it into a buffer from the pool or a new one
+    fn allocations_relaying(count: usize, pooled: bool) -> usize {
+        let stanza = format!(
+            "<message xmlns='jabber:client' to='a@b' from='c@d/e' type='chat'><body>{}</body></message>",
+            "relayed ".repeat(32)
+        );
+        let mut c = XMPPCodec::new();
+        let mut b = BytesMut::with_capacity(8 * 1024);
+        b.put_slice(b"<?xml version='1.0'?><stream:stream xmlns:stream='http://etherx.jabber.org/streams' version='1.0' xmlns='jabber:client'>");
+        assert!(matches!(c.decode(&mut b), Ok(Some(Packet::StreamStart(_)))));
+        let relay = |c: &mut XMPPCodec, b: &mut BytesMut| {
+            b.put_slice(stanza.as_bytes());
+            let packet = c.decode(b).unwrap().unwrap();
+            let mut encoded = if pooled {
+                c.pool().take()
+            } else {
+                BytesMut::new()
+            };
+            c.encode(packet, &mut encoded).unwrap();
+            if pooled {
+                c.pool().put(encoded);
+            }
+        };
+        for _ in 0..10 {
+            relay(&mut c, &mut b);
+        }
+        let capacity = b.capacity();
+        let before = counting::allocations();
+        for _ in 0..count {
+            relay(&mut c, &mut b);
+        }
+        let after = counting::allocations();
+        // The read buffer got reused rather than split off.
+        assert_eq!(b.capacity(), capacity);
+        after - before
+    }
+
+    #[test]
+    fn test_relay_allocations() {
+        let pooled = allocations_relaying(1000, true);
+        let fresh = allocations_relaying(1000, false);
+        println!(
+            "allocations per relayed stanza: {} pooled, {} without",
+            pooled as f64 / 1000.,
+            fresh as f64 / 1000.
+        );
+        // At least the 64 KiB encoding buffer of each stanza is saved.
+        assert!(pooled + 1000 <= fresh);
+    }
+
+    #[test]
+    fn test_truncated_utf8_pooled() {
+        // The scratch space joining a truncated character with the rest
+        // of it comes from the pool.
+        let mut c = XMPPCodec::new();
+        let mut b = BytesMut::with_capacity(1024);
+        b.put_slice(b"<?xml version='1.0'?><stream:stream xmlns:stream='http://etherx.jabber.org/streams' version='1.0' xmlns='jabber:client'>");
+        let _ = c.decode(&mut b);
+        for _ in 0..3 {
+            b.put_slice(b"<test>\xc3");
+            assert!(matches!(c.decode(&mut b), Ok(None)));
+            b.put_slice(b"\xa9</test>");
+            match c.decode(&mut b) {
+                Ok(Some(Packet::Stanza(stanza))) => assert_eq!(stanza.text(), "\u{e9}"),
+                packet => panic!("unexpected packet: {:?}", packet),
+            }
+        }
+        let stats = c.pool_stats();
+        assert_eq!((stats.hits(), stats.misses()), (2, 1));
+    }
+
+    #[test]
+    fn test_namespace_scopes() {
+        let mut c = XMPPCodec::new();
+        let mut b = BytesMut::with_capacity(1024);
+        b.put_slice(b"<?xml version='1.0'?><stream:stream xmlns:stream='http://etherx.jabber.org/streams' version='1.0' xmlns='jabber:client'>");
+        let _ = c.decode(&mut b);
+        b.put_slice(
+            b"<message><x xmlns='urn:example:unknown'><y/></x><body/></message><stream:error/>",
+        );
+        let message = match c.decode(&mut b) {
+            Ok(Some(Packet::Stanza(stanza))) => stanza,
+            packet => panic!("unexpected packet: {:?}", packet),
+        };
+        assert_eq!(message.ns(), ns::JABBER_CLIENT);
+        let x = message.get_child("x", "urn:example:unknown").unwrap();
+        assert!(x.has_child("y", "urn:example:unknown"));
+        assert!(message.has_child("body", ns::JABBER_CLIENT));
+        match c.decode(&mut b) {
+            Ok(Some(Packet::Stanza(stanza))) => assert!(stanza.is("error", ns::STREAM)),
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+    }
 }
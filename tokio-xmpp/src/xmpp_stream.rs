@@ -1,19 +1,41 @@
 //! `XMPPStream` provides encoding/decoding for XMPP
 
+use bytes::Bytes;
 use futures::sink::Send;
-use futures::{sink::SinkExt, task::Poll, Sink, Stream};
-use std::ops::DerefMut;
+use futures::{ready, sink::SinkExt, task::Poll, Future, Sink, Stream};
+use std::collections::VecDeque;
+use std::io;
 use std::pin::Pin;
 use std::sync::Mutex;
 use std::task::Context;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_util::codec::Framed;
-use xmpp_parsers::{Element, Jid};
+use tokio::time::{Instant, Sleep};
+use tokio_util::codec::{Encoder, Framed};
+use xmpp_parsers::{Element, Jid, ParseLimits};
 
+use crate::buffer_pool::PoolStats;
 use crate::stream_features::StreamFeatures;
 use crate::stream_start;
-use crate::xmpp_codec::{Packet, XMPPCodec};
-use crate::Error;
+use crate::write_queue::{self, WriteQueue};
+use crate::xmpp_codec::{Packet, StreamHeader, XMPPCodec};
+use crate::{Error, ProtocolError};
+
+/// How long to wait for the peer to close its side of the stream after
+/// we closed ours, by default
+const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a stanza may take to be received, from its first byte to its
+/// last, by default
+pub(crate) const DEFAULT_STANZA_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Where closing is at, once `</stream:stream>` got sent
+struct Closing {
+    /// When to stop waiting for the peer to close its side
+    deadline: Pin<Box<Sleep>>,
+    /// The peer closed its side, or we stopped waiting for it
+    peer_closed: bool,
+}
 
 /// Wraps a binary stream (tokio's `AsyncRead + AsyncWrite`) to decode
 /// and encode XMPP packets.
@@ -33,6 +55,27 @@ pub struct XMPPStream<S: AsyncRead + AsyncWrite + Unpin> {
     pub ns: String,
     /// Stream `id` attribute
     pub id: String,
+    /// The stream header received, from which `id` comes
+    pub header: StreamHeader,
+    /// What got encoded but not written yet
+    queue: WriteQueue,
+    /// How long the output may stay silent before a whitespace
+    /// keepalive gets sent, and when that will be
+    keepalive: Option<(Duration, Pin<Box<Sleep>>)>,
+    /// A keepalive was sent but isn't flushed yet
+    keepalive_unflushed: bool,
+    /// How long to wait for the peer to close its side of the stream
+    close_timeout: Duration,
+    /// How long a stanza may take to be received, if limited
+    stanza_timeout: Option<Duration>,
+    /// When the stanza being received has to be complete, if one is
+    stanza_deadline: Option<Pin<Box<Sleep>>>,
+    /// Set once we sent `</stream:stream>`, after which nothing else
+    /// may be sent
+    closing: Option<Closing>,
+    /// Packets read by `poll_close()`, still to be returned by
+    /// `poll_next()`
+    received: VecDeque<Packet>,
 }
 
 impl<S: AsyncRead + AsyncWrite + Unpin> XMPPStream<S> {
@@ -44,19 +87,205 @@ impl<S: AsyncRead + AsyncWrite + Unpin> XMPPStream<S> {
         id: String,
         stream_features: Element,
     ) -> Self {
+        let queue = WriteQueue::new(stream.codec().pool().clone());
         XMPPStream {
             jid,
             stream: Mutex::new(stream),
             stream_features: StreamFeatures::new(stream_features),
+            header: StreamHeader {
+                ns: Some(ns.clone()),
+                id: Some(id.clone()),
+                ..StreamHeader::default()
+            },
             ns,
             id,
+            queue,
+            keepalive: None,
+            keepalive_unflushed: false,
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+            stanza_timeout: Some(DEFAULT_STANZA_TIMEOUT),
+            stanza_deadline: None,
+            closing: None,
+            received: VecDeque::new(),
+        }
+    }
+
+    /// Set how long to wait, once we sent `</stream:stream>`, for the
+    /// peer to close its side before dropping the connection, 10
+    /// seconds by default
+    pub fn set_close_timeout(&mut self, timeout: Duration) {
+        self.close_timeout = timeout;
+    }
+
+    /// Fail with `ProtocolError::StanzaTimeout` if a stanza isn't
+    /// complete `timeout` after its first byte got received, 5 minutes by
+    /// default, or never if `None`
+    ///
+    /// This keeps a peer from holding the connection, and what got
+    /// buffered of a stanza, by sending it slower and slower. Whatever
+    /// time passes between stanzas doesn't count.
+    pub fn set_stanza_timeout(&mut self, timeout: Option<Duration>) {
+        self.stanza_timeout = timeout;
+        self.stanza_deadline = None;
+    }
+
+    /// Send a single space whenever nothing got written for `interval`,
+    /// or never if `None`
+    ///
+    /// Keepalives only go out while the stream is being read.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>) {
+        self.keepalive =
+            interval.map(|interval| (interval, Box::pin(tokio::time::sleep(interval))));
+    }
+
+    /// Write at most `size` bytes at once to the underlying stream, 16
+    /// KiB by default, that of a TLS record
+    ///
+    /// Between these writes, small urgent packets such as iq replies,
+    /// pings and stream management acks get ahead of the bulk of what
+    /// is being sent, and the task yields so that more of them can be.
+    pub fn set_write_chunk_size(&mut self, size: usize) {
+        self.queue.set_chunk_size(size);
+    }
+
+    /// How much reusing the buffers packets get decoded and encoded with
+    /// saves on this connection
+    pub fn pool_stats(&self) -> PoolStats {
+        self.queue.pool_stats()
+    }
+
+    /// When the next whitespace keepalive is due
+    #[cfg(feature = "test-internals")]
+    pub(crate) fn keepalive_deadline(&self) -> Option<Instant> {
+        self.keepalive.as_ref().map(|(_, timer)| timer.deadline())
+    }
+
+    /// When the stanza being received has to be complete
+    #[cfg(feature = "test-internals")]
+    pub(crate) fn stanza_deadline(&self) -> Option<Instant> {
+        self.stanza_deadline.as_ref().map(|timer| timer.deadline())
+    }
+
+    /// How many packets wait to be written, and how many bytes are
+    /// being written
+    #[cfg(feature = "test-internals")]
+    pub(crate) fn queue_depth(&self) -> (usize, usize) {
+        self.queue.depth()
+    }
+
+    /// Set how big incoming stanzas may be, see
+    /// [`XMPPCodec::set_limits`](crate::xmpp_codec::XMPPCodec::set_limits)
+    pub fn set_parse_limits(&mut self, limits: ParseLimits) {
+        if let Some(framed) = self.framed() {
+            framed.codec_mut().set_limits(limits);
         }
     }
 
+    /// Keep the bytes of every stanza as received, see
+    /// [`XMPPCodec::set_keep_raw`](crate::xmpp_codec::XMPPCodec::set_keep_raw)
+    pub fn set_keep_raw(&mut self, keep: bool) {
+        if let Some(framed) = self.framed() {
+            framed.codec_mut().set_keep_raw(keep);
+        }
+    }
+
+    /// The bytes of the stanza last received, exactly as they were, see
+    /// [`XMPPCodec::raw_stanza`](crate::xmpp_codec::XMPPCodec::raw_stanza)
+    pub fn raw_stanza(&self) -> Option<Bytes> {
+        let framed = self.stream.lock().ok()?;
+        framed.codec().raw_stanza().cloned()
+    }
+
+    /// Send a keepalive if it is time to, and flush it, returning why
+    /// this failed if it did
+    fn poll_keepalive(&mut self, cx: &mut Context) -> Option<Error> {
+        if let Some((_, ref mut timer)) = self.keepalive {
+            if timer.as_mut().poll(cx).is_ready() {
+                // Resets the timer, to be polled again below.
+                let keepalive = Packet::Text(String::from(" "));
+                if let Err(e) = Pin::new(&mut *self).start_send(keepalive) {
+                    return Some(e);
+                }
+                self.keepalive_unflushed = true;
+            }
+        }
+        if let Some((_, ref mut timer)) = self.keepalive {
+            let _ = timer.as_mut().poll(cx);
+        }
+        if self.keepalive_unflushed {
+            if let Poll::Ready(result) = Pin::new(&mut *self).poll_flush(cx) {
+                self.keepalive_unflushed = false;
+                return result.err();
+            }
+        }
+        None
+    }
+
+    /// Start the stanza timeout when a stanza started being received, or
+    /// stop it once none is, returning `ProtocolError::StanzaTimeout` if
+    /// it passed and `expire`
+    fn poll_stanza_deadline(&mut self, cx: &mut Context, expire: bool) -> Option<Error> {
+        let timeout = self.stanza_timeout?;
+        if !self.framed()?.codec().stanza_in_progress() {
+            self.stanza_deadline = None;
+            return None;
+        }
+        let deadline = self
+            .stanza_deadline
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        if expire && deadline.as_mut().poll(cx).is_ready() {
+            return Some(ProtocolError::StanzaTimeout.into());
+        }
+        None
+    }
+
+    /// Read until the peer closes its side of the stream, or the
+    /// deadline passes, keeping what it still sends for `poll_next()`
+    fn poll_peer_closed(&mut self, cx: &mut Context) -> Poll<()> {
+        let closing = match self.closing {
+            Some(ref mut closing) => closing,
+            None => return Poll::Ready(()),
+        };
+        let framed = match self.stream.get_mut() {
+            Ok(framed) => framed,
+            Err(_) => {
+                closing.peer_closed = true;
+                return Poll::Ready(());
+            }
+        };
+        while !closing.peer_closed {
+            if closing.deadline.as_mut().poll(cx).is_ready() {
+                closing.peer_closed = true;
+                break;
+            }
+            match ready!(Pin::new(&mut *framed).poll_next(cx)) {
+                Some(Ok(Packet::StreamEnd)) => {
+                    closing.peer_closed = true;
+                    self.received.push_back(Packet::StreamEnd);
+                }
+                Some(Ok(packet)) => self.received.push_back(packet),
+                Some(Err(_)) | None => closing.peer_closed = true,
+            }
+        }
+        Poll::Ready(())
+    }
+
     /// Send a `<stream:stream>` start tag
     pub async fn start<'a>(stream: S, jid: Jid, ns: String) -> Result<Self, Error> {
+        Self::start_with_lang(stream, jid, ns, None).await
+    }
+
+    /// Send a `<stream:stream>` start tag whose `xml:lang` is `lang`,
+    /// the language the server should use for the text it generates,
+    /// such as that of stream errors
+    pub async fn start_with_lang(
+        stream: S,
+        jid: Jid,
+        ns: String,
+        lang: Option<String>,
+    ) -> Result<Self, Error> {
         let xmpp_stream = Framed::new(stream, XMPPCodec::new());
-        stream_start::start(xmpp_stream, jid, ns).await
+        stream_start::start(xmpp_stream, jid, ns, lang).await
     }
 
     /// Unwraps the inner stream
@@ -64,6 +293,20 @@ impl<S: AsyncRead + AsyncWrite + Unpin> XMPPStream<S> {
         self.stream.into_inner().unwrap().into_inner()
     }
 
+    /// Takes the stanzas which were sent but never written, because
+    /// flushing failed or the connection got closed before
+    ///
+    /// A stanza partially written is not one of them, as the server may
+    /// have received it anyway.
+    pub fn take_unwritten(&mut self) -> Vec<Element> {
+        self.queue.take_unwritten()
+    }
+
+    /// The codec, unless a panic happened while it was in use
+    fn framed(&mut self) -> Option<&mut Framed<S, XMPPCodec>> {
+        self.stream.get_mut().ok()
+    }
+
     /// Re-run `start()`
     pub async fn restart<'a>(self) -> Result<Self, Error> {
         let stream = self.stream.into_inner().unwrap().into_inner();
@@ -76,6 +319,32 @@ impl<S: AsyncRead + AsyncWrite + Unpin> XMPPStream<S> {
     pub fn send_stanza<E: Into<Element>>(&mut self, e: E) -> Send<Self, Packet> {
         self.send(Packet::Stanza(e.into()))
     }
+
+    /// Send all stanzas in order, flushing only once at the end
+    pub async fn send_all<I: IntoIterator<Item = Element>>(
+        &mut self,
+        stanzas: I,
+    ) -> Result<(), Error> {
+        send_all(self, stanzas.into_iter().map(Packet::Stanza)).await
+    }
+}
+
+/// Write all items to the sink before flushing it once
+///
+/// If an item fails, the ones before it still get flushed, and the
+/// returned `Error::PartialSend` tells how many of them there were.
+pub(crate) async fn send_all<T, Si, I>(sink: &mut Si, items: I) -> Result<(), Error>
+where
+    Si: Sink<T, Error = Error> + Unpin,
+    I: IntoIterator<Item = T>,
+{
+    for (sent, item) in items.into_iter().enumerate() {
+        if let Err(e) = sink.feed(item).await {
+            sink.flush().await?;
+            return Err(Error::PartialSend(sent, Box::new(e)));
+        }
+    }
+    sink.flush().await
 }
 
 /// Proxy to self.stream
@@ -88,29 +357,76 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Sink<Packet> for XMPPStream<S> {
         Poll::Ready(Ok(()))
     }
 
-    fn start_send(
-        #[cfg_attr(rustc_least_1_46, allow(unused_mut))] mut self: Pin<&mut Self>,
-        item: Packet,
-    ) -> Result<(), Self::Error> {
-        Pin::new(&mut self.stream.lock().unwrap().deref_mut())
-            .start_send(item)
-            .map_err(|e| e.into())
+    /// Once `</stream:stream>` got sent, nothing else can be, failing
+    /// with `Error::Closing`
+    fn start_send(mut self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
+        if self.closing.is_some() {
+            return Err(Error::Closing);
+        }
+        let ending = matches!(item, Packet::StreamEnd);
+        let urgent = write_queue::is_urgent(&item);
+        let order = write_queue::order(&item);
+        let stanza = match item {
+            Packet::Stanza(ref stanza) => Some(stanza.clone()),
+            _ => None,
+        };
+        let codec = self.framed().ok_or(Error::InvalidState)?.codec_mut();
+        let mut encoded = codec.pool().take();
+        if let Err(e) = codec.encode(item, &mut encoded) {
+            codec.pool().put(encoded);
+            return Err(e.into());
+        }
+        // Whitespace counts as traffic, but isn't a stanza to resend.
+        if let Some((interval, ref mut timer)) = self.keepalive {
+            timer.as_mut().reset(Instant::now() + interval);
+        }
+        self.queue.push(urgent, order, encoded, stanza);
+        if ending {
+            self.closing = Some(Closing {
+                deadline: Box::pin(tokio::time::sleep(self.close_timeout)),
+                peer_closed: false,
+            });
+        }
+        Ok(())
     }
 
-    fn poll_flush(
-        #[cfg_attr(rustc_least_1_46, allow(unused_mut))] mut self: Pin<&mut Self>,
-        cx: &mut Context,
-    ) -> Poll<Result<(), Self::Error>> {
-        Pin::new(&mut self.stream.lock().unwrap().deref_mut())
-            .poll_flush(cx)
-            .map_err(|e| e.into())
+    /// Writes what got sent one chunk at a time, yielding between
+    /// chunks, see [`XMPPStream::set_write_chunk_size`]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = &mut *self;
+        let framed = this.stream.get_mut().map_err(|_| Error::InvalidState)?;
+        while let Some(chunk) = this.queue.next_chunk() {
+            let written = ready!(Pin::new(framed.get_mut()).poll_write(cx, chunk))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write the stream",
+                )
+                .into()));
+            }
+            if this.queue.advance(written) && !this.queue.is_empty() {
+                // Let the rest run, and maybe queue urgent packets, before
+                // the next chunk.
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+        }
+        Pin::new(framed).poll_flush(cx).map_err(|e| e.into())
     }
 
-    fn poll_close(
-        #[cfg_attr(rustc_least_1_46, allow(unused_mut))] mut self: Pin<&mut Self>,
-        cx: &mut Context,
-    ) -> Poll<Result<(), Self::Error>> {
-        Pin::new(&mut self.stream.lock().unwrap().deref_mut())
+    /// Closes the stream the way RFC 6120 wants it: sends
+    /// `</stream:stream>` unless already done, then keeps reading until
+    /// the peer closes its side or the close timeout passes, and only
+    /// then shuts the connection down
+    ///
+    /// What got read meanwhile is still returned by `poll_next()`.
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        if self.closing.is_none() {
+            self.as_mut().start_send(Packet::StreamEnd)?;
+        }
+        ready!(self.as_mut().poll_flush(cx))?;
+        ready!(self.poll_peer_closed(cx));
+        Pin::new(self.framed().ok_or(Error::InvalidState)?)
             .poll_close(cx)
             .map_err(|e| e.into())
     }
@@ -120,12 +436,317 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Sink<Packet> for XMPPStream<S> {
 impl<S: AsyncRead + AsyncWrite + Unpin> Stream for XMPPStream<S> {
     type Item = Result<Packet, crate::Error>;
 
-    fn poll_next(
-        #[cfg_attr(rustc_least_1_46, allow(unused_mut))] mut self: Pin<&mut Self>,
-        cx: &mut Context,
-    ) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.stream.lock().unwrap().deref_mut())
-            .poll_next(cx)
-            .map(|result| result.map(|result| result.map_err(|e| e.into())))
+    /// Once we closed our side, this ends when the peer closes its own,
+    /// or when the close timeout passes
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if let Some(packet) = self.received.pop_front() {
+            return Poll::Ready(Some(Ok(packet)));
+        }
+        if let Some(ref mut closing) = self.closing {
+            if closing.peer_closed || closing.deadline.as_mut().poll(cx).is_ready() {
+                closing.peer_closed = true;
+                return Poll::Ready(None);
+            }
+        } else if let Some(e) = self.poll_keepalive(cx) {
+            return Poll::Ready(Some(Err(e)));
+        }
+        let framed = match self.framed() {
+            Some(framed) => framed,
+            None => return Poll::Ready(Some(Err(Error::InvalidState))),
+        };
+        let result = Pin::new(framed).poll_next(cx);
+        // Only once everything read got decoded, so that a stanza
+        // completing meanwhile isn't late, and none already decoded gets
+        // lost.
+        if let Some(e) = self.poll_stanza_deadline(cx, result.is_pending()) {
+            return Poll::Ready(Some(Err(e)));
+        }
+        let result = ready!(result);
+        if let Some(ref mut closing) = self.closing {
+            match result {
+                Some(Ok(Packet::StreamEnd)) | Some(Err(_)) | None => closing.peer_closed = true,
+                _ => (),
+            }
+        }
+        Poll::Ready(result.map(|result| result.map_err(|e| e.into())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::StreamExt;
+    use std::str::FromStr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_send_all_keeps_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let mut stream = XMPPStream::new(
+            Jid::from_str("test.example").unwrap(),
+            Framed::new(client.unwrap(), XMPPCodec::new()),
+            String::from("jabber:component:accept"),
+            String::from("id"),
+            Element::builder("features", "http://etherx.jabber.org/streams").build(),
+        );
+        let mut server = Framed::new(server.unwrap().0, XMPPCodec::new());
+        let header = StreamHeader {
+            ns: Some(String::from("jabber:component:accept")),
+            ..StreamHeader::default()
+        };
+        stream.send(Packet::StreamStart(header)).await.unwrap();
+
+        let stanzas = (0..50).map(|i| {
+            Element::builder("message", "jabber:component:accept")
+                .attr("id", i.to_string())
+                .build()
+        });
+        stream.send_all(stanzas).await.unwrap();
+
+        let mut ids = Vec::new();
+        while ids.len() < 50 {
+            if let Packet::Stanza(stanza) = server.next().await.unwrap().unwrap() {
+                ids.push(stanza.attr("id").unwrap().to_owned());
+            }
+        }
+        let expected: Vec<String> = (0..50).map(|i| i.to_string()).collect();
+        assert_eq!(ids, expected);
+    }
+
+    #[tokio::test]
+    async fn test_unwritten_after_disconnection() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut stream = XMPPStream::new(
+            Jid::from_str("test.example").unwrap(),
+            Framed::new(client, XMPPCodec::new()),
+            String::from("jabber:component:accept"),
+            String::from("id"),
+            Element::builder("features", "http://etherx.jabber.org/streams").build(),
+        );
+        let stanza = |i: usize| {
+            Element::builder("message", "jabber:component:accept")
+                .attr("to", "juliet@capulet.example")
+                .attr("id", format!("{:03}", i))
+                .build()
+        };
+        let size = String::from(&stanza(0)).len();
+
+        // The server goes away after reading part of the burst.
+        let server = async move {
+            let mut buf = vec![0; 1500];
+            server.read_exact(&mut buf).await.unwrap();
+        };
+        let (result, ()) = tokio::join!(stream.send_all((0..100).map(stanza)), server);
+        match result {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe),
+            result => panic!("{:?}", result),
+        }
+
+        // What the server read got written, and maybe some more which was
+        // still in the pipe.
+        let unwritten = stream.take_unwritten();
+        let first = 100 - unwritten.len();
+        assert!(first * size >= 1500);
+        assert!(first * size < 1500 + 1024 + size);
+        let expected: Vec<Element> = (first..100).map(stanza).collect();
+        assert_eq!(unwritten, expected);
+        assert!(stream.take_unwritten().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_urgent_ahead_of_bulk() {
+        // The server reads slowly, through a small pipe.
+        let (client, mut server) = tokio::io::duplex(4096);
+        let mut stream = XMPPStream::new(
+            Jid::from_str("test.example").unwrap(),
+            Framed::new(client, XMPPCodec::new()),
+            String::from("jabber:component:accept"),
+            String::from("id"),
+            Element::builder("features", "http://etherx.jabber.org/streams").build(),
+        );
+        let body = "x".repeat(2000);
+        let message = |i: usize| {
+            Element::builder("message", "jabber:component:accept")
+                .attr("to", "juliet@capulet.example")
+                .attr("id", format!("{:03}", i))
+                .append(Element::builder("body", "jabber:component:accept").append(body.as_str()))
+                .build()
+        };
+        let size = String::from(&message(0)).len();
+        for i in 0..100 {
+            stream.feed(Packet::Stanza(message(i))).await.unwrap();
+        }
+
+        // A reply to someone else gets sent while the first chunk of the
+        // bulk is being written.
+        assert!(futures::poll!(stream.flush()).is_pending());
+        let pong = Element::builder("iq", "jabber:component:accept")
+            .attr("type", "result")
+            .attr("to", "romeo@montague.example")
+            .attr("id", "pong")
+            .build();
+        let pong_size = String::from(&pong).len();
+        stream.feed(Packet::Stanza(pong)).await.unwrap();
+
+        let server = async move {
+            let mut buf = vec![0; 100 * size + pong_size];
+            server.read_exact(&mut buf).await.unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+        let (result, received) = tokio::join!(stream.flush(), server);
+        result.unwrap();
+
+        // It went out right after the message being written at the end of
+        // the first chunk, long before the rest of the bulk.
+        let pong_at = received.find("<iq").unwrap();
+        assert!(pong_at < write_queue::DEFAULT_CHUNK_SIZE + size);
+        assert_eq!(pong_at % size, 0);
+        let ids: Vec<&str> = received
+            .match_indices(" id=\"")
+            .map(|(at, _)| &received[at + 5..at + 8])
+            .filter(|id| *id != "pon")
+            .collect();
+        let expected: Vec<String> = (0..100).map(|i| format!("{:03}", i)).collect();
+        assert_eq!(ids, expected);
+    }
+
+    /// What the server reads next, while the client reads the stream
+    async fn next_write(
+        stream: &mut XMPPStream<DuplexStream>,
+        server: &mut DuplexStream,
+    ) -> Vec<u8> {
+        let mut buf = [0; 1024];
+        tokio::select! {
+            read = server.read(&mut buf) => buf[..read.unwrap()].to_vec(),
+            packet = stream.next() => panic!("unexpected packet: {:?}", packet),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_whitespace_keepalive() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut stream = XMPPStream::new(
+            Jid::from_str("test.example").unwrap(),
+            Framed::new(client, XMPPCodec::new()),
+            String::from("jabber:component:accept"),
+            String::from("id"),
+            Element::builder("features", "http://etherx.jabber.org/streams").build(),
+        );
+        stream.set_keepalive(Some(Duration::from_secs(30)));
+        let start = Instant::now();
+
+        assert_eq!(next_write(&mut stream, &mut server).await, b" ");
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+        // It isn't a stanza, so it is never to be sent again.
+        assert!(stream.queue.is_empty());
+        assert_eq!(next_write(&mut stream, &mut server).await, b" ");
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+
+        // Any other write postpones the next keepalive.
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let message = Element::builder("message", "jabber:component:accept").build();
+        stream.send_stanza(message).await.unwrap();
+        assert_eq!(
+            next_write(&mut stream, &mut server).await,
+            b"<message xmlns=\"jabber:component:accept\"/>"
+        );
+        assert_eq!(next_write(&mut stream, &mut server).await, b" ");
+        assert_eq!(start.elapsed(), Duration::from_secs(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_close_timeout() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut stream = XMPPStream::new(
+            Jid::from_str("test.example").unwrap(),
+            Framed::new(client, XMPPCodec::new()),
+            String::from("jabber:component:accept"),
+            String::from("id"),
+            Element::builder("features", "http://etherx.jabber.org/streams").build(),
+        );
+        stream.set_close_timeout(Duration::from_secs(5));
+        stream.set_keepalive(Some(Duration::from_secs(1)));
+        let start = Instant::now();
+
+        // The server reads our </stream:stream>, but never closes its side.
+        let server = async move {
+            let mut buf = [0; 1024];
+            let read = server.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..read], b"</stream:stream>\n");
+            server
+        };
+        let (result, _server) = tokio::join!(stream.close(), server);
+        result.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+        // No keepalive got sent meanwhile, and nothing more can be.
+        assert!(stream.next().await.is_none());
+        let message = Element::builder("message", "jabber:component:accept").build();
+        match stream.send_stanza(message).await {
+            Err(Error::Closing) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stanza_timeout() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut stream = XMPPStream::new(
+            Jid::from_str("test.example").unwrap(),
+            Framed::new(client, XMPPCodec::new()),
+            String::from("jabber:component:accept"),
+            String::from("id"),
+            Element::builder("features", "http://etherx.jabber.org/streams").build(),
+        );
+        stream.set_stanza_timeout(Some(Duration::from_secs(60)));
+        let start = Instant::now();
+
+        // What the server writes, and when, in seconds.
+        let mut script: Vec<(u64, Vec<u8>)> = vec![
+            (0, b"<stream:stream xmlns:stream='http://etherx.jabber.org/streams' xmlns='jabber:component:accept'>".to_vec()),
+            (0, b"<message id='1'/>".to_vec()),
+            // Silence and whitespace between stanzas don't count.
+            (70, b"\n".to_vec()),
+            (140, b"<message id='2'><body>".to_vec()),
+        ];
+        // A large stanza, slow to come but complete in time.
+        script.extend((141..190).map(|at| (at, vec![b'x'; 1000])));
+        script.push((199, b"</body></message>".to_vec()));
+        // One never complete, which keeps coming slower and slower.
+        script.push((200, b"<message id='3' to='".to_vec()));
+        script.extend((0..10).map(|i| (210 + i * i, b"a".to_vec())));
+        let server = tokio::spawn(async move {
+            for (at, bytes) in script {
+                tokio::time::sleep_until(start + Duration::from_secs(at)).await;
+                server.write_all(&bytes).await.unwrap();
+            }
+            server
+        });
+
+        macro_rules! next {
+            () => {
+                (stream.next().await.unwrap(), start.elapsed().as_secs())
+            };
+        }
+        assert!(matches!(next!(), (Ok(Packet::StreamStart(_)), 0)));
+        match next!() {
+            (Ok(Packet::Stanza(message)), 0) => assert_eq!(message.attr("id"), Some("1")),
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+        assert!(matches!(next!(), (Ok(Packet::Text(_)), 70)));
+        match next!() {
+            (Ok(Packet::Stanza(message)), 199) => {
+                let body = message.get_child("body", "jabber:component:accept");
+                assert_eq!(body.unwrap().text().len(), 49 * 1000);
+            }
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+        match next!() {
+            (Err(Error::Protocol(ProtocolError::StanzaTimeout)), 260) => (),
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+        server.abort();
     }
 }
@@ -0,0 +1,103 @@
+//! Clients talking to each other through the mini server, over TCP on
+//! localhost
+//!
+//! Ignored by default since they listen on a port, run them with
+//! `cargo test --features mini-server -- --ignored`.
+
+use futures::{Future, StreamExt};
+use std::str::FromStr;
+use tokio_xmpp::mini_server::MiniServer;
+use tokio_xmpp::{AsyncClient as Client, AsyncConfig as Config, AsyncServerConfig as ServerConfig};
+use xmpp_parsers::{ns, Element, Jid};
+
+fn client(jid: &str, password: &str, port: u16) -> Client {
+    Client::new_with_config(Config::new(
+        Jid::from_str(jid).unwrap(),
+        password,
+        ServerConfig::Loopback { port },
+    ))
+}
+
+/// Waits for `client` to be online, returning its bound JID
+async fn online(client: &mut Client) -> Jid {
+    let event = client.next().await.unwrap();
+    assert!(event.is_online(), "Unexpected event {:?}", event);
+    event.get_jid().unwrap().clone()
+}
+
+async fn next_stanza(client: &mut Client) -> Element {
+    let event = client.next().await.unwrap();
+    match event.into_stanza() {
+        Some(stanza) => stanza,
+        None => panic!("Not a stanza"),
+    }
+}
+
+/// Runs `test` against a server for `bot@localhost` and
+/// `user@localhost`, hosting `lounge@conference.localhost`
+async fn with_server<F, Fut>(test: F)
+where
+    F: FnOnce(u16) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let server = MiniServer::new("localhost")
+        .user("bot", "bot")
+        .user("user", "user")
+        .room("lounge");
+    let (port, server) = server.listen(0).await.unwrap();
+    tokio::select! {
+        result = server => panic!("Server stopped: {:?}", result),
+        () = test(port) => (),
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_message() {
+    with_server(|port| async move {
+        let mut bot = client("bot@localhost/echo", "bot", port);
+        let mut user = client("user@localhost", "user", port);
+        assert_eq!(online(&mut bot).await, Jid::from_str("bot@localhost/echo").unwrap());
+        online(&mut user).await;
+
+        let message: Element = "<message xmlns='jabber:client' to='bot@localhost/echo' type='chat'><body>Hello</body></message>".parse().unwrap();
+        user.send_stanza(message).await.unwrap();
+        let message = next_stanza(&mut bot).await;
+        assert!(message.attr("from").unwrap().starts_with("user@localhost/"));
+        assert_eq!(message.get_child("body", ns::JABBER_CLIENT).unwrap().text(), "Hello");
+    })
+    .await;
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_ping_and_disco() {
+    with_server(|port| async move {
+        let mut user = client("user@localhost", "user", port);
+        online(&mut user).await;
+
+        let ping: Element = "<iq xmlns='jabber:client' type='get' id='ping' to='localhost'><ping xmlns='urn:xmpp:ping'/></iq>".parse().unwrap();
+        user.send_stanza(ping).await.unwrap();
+        let pong = next_stanza(&mut user).await;
+        assert_eq!(pong.attr("type"), Some("result"));
+
+        let disco: Element = "<iq xmlns='jabber:client' type='get' id='disco' to='lounge@conference.localhost'><query xmlns='http://jabber.org/protocol/disco#info'/></iq>".parse().unwrap();
+        user.send_stanza(disco).await.unwrap();
+        let result = next_stanza(&mut user).await;
+        let query = result.get_child("query", ns::DISCO_INFO).unwrap();
+        let identity = query.get_child("identity", ns::DISCO_INFO).unwrap();
+        assert_eq!(identity.attr("category"), Some("conference"));
+    })
+    .await;
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_wrong_password() {
+    with_server(|port| async move {
+        let mut user = client("user@localhost", "bot", port);
+        let event = user.next().await.unwrap();
+        assert!(!event.is_online());
+    })
+    .await;
+}
@@ -0,0 +1,70 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A bot accepting everyone as a contact, greeting each of them once, and forgetting those who
+//! revoke its subscription.
+
+use std::env::args;
+use xmpp::{AutoAcceptPolicy, ClientBuilder, ClientType, Event, FileStorage};
+use xmpp_parsers::{message::MessageType, Jid};
+
+#[tokio::main]
+async fn main() -> Result<(), Option<()>> {
+    env_logger::init();
+
+    let args: Vec<String> = args().collect();
+    if args.len() != 4 {
+        println!("Usage: {} <jid> <password> <storage directory>", args[0]);
+        return Err(None);
+    }
+    let jid = &args[1];
+    let password = &args[2];
+    // Remembers who got greeted already, across restarts.
+    let storage = FileStorage::open(&args[3]).map_err(|err| {
+        println!("Can’t open the storage: {}", err);
+        None
+    })?;
+
+    let mut client = ClientBuilder::new(jid, password)
+        .set_client(ClientType::Bot, "xmpp-rs")
+        .set_website("https://gitlab.com/xmpp-rs/xmpp-rs")
+        .with_contact_list()
+        .set_auto_accept(AutoAcceptPolicy::AcceptAll)
+        .set_storage(storage)
+        .build()
+        .unwrap();
+
+    while let Some(events) = client.wait_for_events().await {
+        for event in events {
+            match event {
+                Event::Online => {
+                    println!("Online.");
+                }
+                Event::Disconnected => {
+                    println!("Disconnected");
+                    return Err(None);
+                }
+                Event::NewConversationPartner(jid) => {
+                    println!("Greeting {}.", jid);
+                    client
+                        .send_message(
+                            Jid::Bare(jid),
+                            MessageType::Chat,
+                            "en",
+                            "Hello, nice to meet you!",
+                        )
+                        .await;
+                }
+                Event::SubscriptionChanged { jid, new, .. } => {
+                    println!("Subscription with {} is now {:?}.", jid, new);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    Ok(())
+}
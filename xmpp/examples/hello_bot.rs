@@ -6,7 +6,7 @@
 
 use env_logger;
 use std::env::args;
-use xmpp::{ClientBuilder, ClientFeature, ClientType, Event};
+use xmpp::{AvatarConfig, ClientBuilder, ClientType, Event, MucConfig};
 use xmpp_parsers::{message::MessageType, Jid};
 
 #[tokio::main]
@@ -25,10 +25,12 @@ async fn main() -> Result<(), Option<()>> {
     let mut client = ClientBuilder::new(jid, password)
         .set_client(ClientType::Bot, "xmpp-rs")
         .set_website("https://gitlab.com/xmpp-rs/xmpp-rs")
-        .set_default_nick("bot")
-        .enable_feature(ClientFeature::Avatars)
-        .enable_feature(ClientFeature::ContactList)
-        .enable_feature(ClientFeature::JoinRooms)
+        .with_avatars(AvatarConfig::default())
+        .with_contact_list()
+        .with_muc(MucConfig {
+            default_nick: String::from("bot"),
+            ..MucConfig::default()
+        })
         .build()
         .unwrap();
 
@@ -51,7 +53,20 @@ async fn main() -> Result<(), Option<()>> {
                 Event::ContactChanged(contact) => {
                     println!("Contact {} changed.", contact.jid);
                 }
-                Event::ChatMessage(jid, body) => {
+                Event::SubscriptionChanged { jid, new, .. } => {
+                    println!("Subscription with {} is now {:?}.", jid, new);
+                }
+                Event::NewConversationPartner(jid) => {
+                    println!("{} is a new conversation partner.", jid);
+                }
+                Event::ContactPresence(jid, presence) => {
+                    if presence.is_online() {
+                        println!("Contact {} is online ({:?}).", jid, presence.best_show);
+                    } else {
+                        println!("Contact {} is offline.", jid);
+                    }
+                }
+                Event::ChatMessage(jid, body, _info) => {
                     println!("Message from {}: {}", jid, body.0);
                 }
                 Event::JoinRoom(jid, conference) => {
@@ -72,8 +87,8 @@ async fn main() -> Result<(), Option<()>> {
                 Event::LeaveAllRooms => {
                     println!("Leaving all rooms…");
                 }
-                Event::RoomJoined(jid) => {
-                    println!("Joined room {}.", jid);
+                Event::RoomJoined(jid, nick) => {
+                    println!("Joined room {} as {}.", jid, nick);
                     client
                         .send_message(Jid::Bare(jid), MessageType::Groupchat, "en", "Hello world!")
                         .await;
@@ -81,12 +96,66 @@ async fn main() -> Result<(), Option<()>> {
                 Event::RoomLeft(jid) => {
                     println!("Left room {}.", jid);
                 }
-                Event::RoomMessage(jid, nick, body) => {
+                Event::RoomMessage(jid, nick, body, _info) => {
                     println!("Message in room {} from {}: {}", jid, nick, body.0);
                 }
-                Event::AvatarRetrieved(jid, path) => {
+                Event::RoomSubject(jid, subject) => match subject {
+                    Some(subject) => println!("Subject of room {}: {}", jid, subject),
+                    None => println!("Room {} has no subject.", jid),
+                },
+                Event::RoomInvitation {
+                    room,
+                    from,
+                    password,
+                    ..
+                } => {
+                    println!("Invited to room {} by {}, joining.", room, from);
+                    client
+                        .join_room(room, None, password, "en", "Yet another bot!")
+                        .await;
+                }
+                Event::AvatarRetrieved(jid, path, _variants) => {
                     println!("Received avatar for {} in {}.", jid, path);
                 }
+                Event::AttentionRequested(jid) => {
+                    println!("{} is trying to get our attention.", jid);
+                }
+                Event::ContactLocation(jid, Some(location)) => {
+                    println!("{} is at {:?}, {:?}.", jid, location.lat, location.lon);
+                }
+                Event::ContactLocation(jid, None) => {
+                    println!("{} stopped sharing their location.", jid);
+                }
+                Event::MessageExpired { id, peer } => {
+                    println!("Message {} from {} expired.", id, peer);
+                }
+                Event::MessageBounced { to, id, error, .. } => {
+                    println!(
+                        "Message {} to {} bounced: {:?}",
+                        id, to, error.defined_condition
+                    );
+                }
+                Event::MessageError { from, error, .. } => {
+                    println!("Error from {}: {:?}", from, error.defined_condition);
+                }
+                Event::PresenceError(from, error) => {
+                    println!(
+                        "Presence error from {}: {:?}",
+                        from, error.defined_condition
+                    );
+                }
+                Event::PubSubNodeDeleted { from, node, .. } => {
+                    println!("Node {} of {} got deleted", node.0, from);
+                }
+                Event::PubSubItemsPublished { .. }
+                | Event::PubSubItemsRetracted { .. }
+                | Event::PubSubNodePurged { .. }
+                | Event::PubSubNodeConfigured { .. }
+                | Event::PubSubSubscriptionChanged { .. }
+                | Event::MessageSentEcho { .. }
+                | Event::JingleCallSessionInitiate { .. }
+                | Event::JingleCallIceCandidate { .. }
+                | Event::JingleCallEnding { .. } => (),
             }
         }
     }
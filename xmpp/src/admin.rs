@@ -0,0 +1,305 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::messages::{self, Messages};
+use crate::{Agent, Error};
+use std::convert::TryFrom;
+use xmpp_parsers::{
+    adhoc::{Command, Status},
+    admin::{self, OnlineUsers, OnlineUsersCount},
+    data_forms::DataForm,
+    disco::{DiscoItemsQuery, DiscoItemsResult},
+    iq::Iq,
+    ns,
+    stanza_error::{DefinedCondition, ErrorType},
+    BareJid, Element, Jid,
+};
+
+/// Runs the service administration commands (XEP-0133) of our server, see [`Agent::admin`].
+pub struct AdminClient<'a> {
+    agent: &'a mut Agent,
+    server: BareJid,
+    /// The nodes of the commands our account may run.
+    nodes: Vec<String>,
+}
+
+impl<'a> AdminClient<'a> {
+    pub(crate) async fn new(agent: &'a mut Agent) -> Result<AdminClient<'a>, Error> {
+        let server = BareJid::domain(agent.own_jid.domain.clone());
+        let jid = Jid::Bare(server.clone());
+        let iq = Iq::from_get(
+            "admin-commands",
+            DiscoItemsQuery {
+                node: Some(String::from(ns::COMMANDS)),
+            },
+        )
+        .with_to(jid.clone());
+        let nodes = agent
+            .query(iq, |agent, elem| commands_response(agent, &jid, elem))
+            .await?;
+        if !nodes.iter().any(|node| node.starts_with(ns::ADMIN)) {
+            let text = agent.messages.no_admin_command();
+            return Err(forbidden(&*agent.messages, text));
+        }
+        Ok(AdminClient {
+            agent,
+            server,
+            nodes,
+        })
+    }
+
+    /// Whether the server lists the command at `node` as allowed to us.
+    pub fn allows(&self, node: &str) -> bool {
+        self.nodes.iter().any(|allowed| allowed == node)
+    }
+
+    /// Create the account `jid`, with `password`.
+    pub async fn add_user(&mut self, jid: &BareJid, password: &str) -> Result<(), Error> {
+        self.run(admin::ADD_USER, Some(admin::add_user_form(jid, password)))
+            .await
+            .map(|_| ())
+    }
+
+    /// Delete the accounts of `jids`.
+    pub async fn delete_users(&mut self, jids: &[BareJid]) -> Result<(), Error> {
+        self.run(admin::DELETE_USER, Some(admin::delete_user_form(jids)))
+            .await
+            .map(|_| ())
+    }
+
+    /// Disconnect every resource of `jids`.
+    pub async fn end_user_sessions(&mut self, jids: &[BareJid]) -> Result<(), Error> {
+        self.run(
+            admin::END_USER_SESSION,
+            Some(admin::end_user_session_form(jids)),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// How many users are online.
+    pub async fn online_users_count(&mut self) -> Result<u64, Error> {
+        let command = self.run(admin::GET_ONLINE_USERS_NUM, None).await?;
+        OnlineUsersCount::try_from(command)
+            .map(|OnlineUsersCount(count)| count)
+            .map_err(|e| Error::Protocol(e.into()))
+    }
+
+    /// The users online, at most `max` of them if given.
+    pub async fn online_users(&mut self, max: Option<u32>) -> Result<Vec<Jid>, Error> {
+        let form = admin::get_online_users_list_form(max);
+        let command = self.run(admin::GET_ONLINE_USERS_LIST, Some(form)).await?;
+        OnlineUsers::try_from(command)
+            .map(|OnlineUsers(jids)| jids)
+            .map_err(|e| Error::Protocol(e.into()))
+    }
+
+    /// Execute the command at `node`, then submit `form` if the server asks for one, and
+    /// return the completed command.
+    async fn run(&mut self, node: &str, form: Option<DataForm>) -> Result<Command, Error> {
+        if !self.allows(node) {
+            let text = self.agent.messages.admin_command_forbidden(node);
+            return Err(forbidden(&*self.agent.messages, text));
+        }
+        let server = Jid::Bare(self.server.clone());
+        let iq = admin::execute("admin-execute", self.server.clone(), node);
+        let mut command = self
+            .agent
+            .query(iq, |agent, elem| {
+                command_response(agent, &server, "admin-execute", elem)
+            })
+            .await?;
+        if let (Some(Status::Executing), Some(form)) = (&command.status, form) {
+            let iq = admin::submit("admin-submit", self.server.clone(), &command, form);
+            command = self
+                .agent
+                .query(iq, |agent, elem| {
+                    command_response(agent, &server, "admin-submit", elem)
+                })
+                .await?;
+        }
+        match command.status {
+            Some(Status::Completed) => Ok(command),
+            _ => Err(Error::Protocol(
+                xmpp_parsers::Error::ParseError("Command not completed.").into(),
+            )),
+        }
+    }
+}
+
+fn forbidden(messages: &dyn Messages, text: String) -> Error {
+    Error::Stanza(messages::stanza_error(
+        messages,
+        ErrorType::Auth,
+        DefinedCondition::Forbidden,
+        text,
+    ))
+}
+
+/// Whether `elem` answers our query for the commands of `server`, and with their nodes.
+fn commands_response(
+    agent: &Agent,
+    server: &Jid,
+    elem: &Element,
+) -> Option<Result<Vec<String>, Error>> {
+    Some(match agent.iq_result(elem, "admin-commands", server)? {
+        Ok(Some(payload)) => DiscoItemsResult::try_from(payload)
+            .map(|result| {
+                result
+                    .items
+                    .into_iter()
+                    .filter_map(|item| item.node)
+                    .collect()
+            })
+            .map_err(|e| Error::Protocol(e.into())),
+        Ok(None) => Err(Error::Protocol(
+            xmpp_parsers::Error::ParseError("Missing commands list.").into(),
+        )),
+        // Servers hide their commands from those who can’t run them.
+        Err(Error::Stanza(ref error))
+            if error.defined_condition == DefinedCondition::ItemNotFound =>
+        {
+            Ok(Vec::new())
+        }
+        Err(err) => Err(err),
+    })
+}
+
+/// Whether `elem` is the answer with this `id` from `server` to a command, and which.
+fn command_response(
+    agent: &Agent,
+    server: &Jid,
+    id: &str,
+    elem: &Element,
+) -> Option<Result<Command, Error>> {
+    Some(match agent.iq_result(elem, id, server)? {
+        Ok(Some(payload)) => Command::try_from(payload).map_err(|e| Error::Protocol(e.into())),
+        Ok(None) => Err(Error::Protocol(
+            xmpp_parsers::Error::ParseError("Missing command.").into(),
+        )),
+        Err(err) => Err(err),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClientBuilder;
+    use std::str::FromStr;
+    use tokio_xmpp::AsyncClient as TokioXmppClient;
+
+    fn agent() -> Agent {
+        let client = TokioXmppClient::new("bard@shakespeare.lit", "meh").unwrap();
+        ClientBuilder::new("bard@shakespeare.lit", "meh")
+            .build_impl(client)
+            .unwrap()
+    }
+
+    fn server() -> Jid {
+        Jid::from_str("shakespeare.lit").unwrap()
+    }
+
+    #[test]
+    fn test_commands() {
+        let agent = agent();
+        let response = |xml: &str| commands_response(&agent, &server(), &xml.parse().unwrap());
+
+        let nodes = response(
+            "<iq xmlns='jabber:client' type='result' id='admin-commands' from='shakespeare.lit'>
+                <query xmlns='http://jabber.org/protocol/disco#items' node='http://jabber.org/protocol/commands'>
+                    <item jid='shakespeare.lit' node='http://jabber.org/protocol/admin#add-user' name='Add a User'/>
+                    <item jid='shakespeare.lit' node='http://jabber.org/protocol/admin#get-online-users-num' name='Get Number of Online Users'/>
+                </query>
+            </iq>",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(nodes, [admin::ADD_USER, admin::GET_ONLINE_USERS_NUM]);
+
+        // Not being allowed any command isn’t an error of the query itself.
+        let nodes = response(
+            "<iq xmlns='jabber:client' type='error' id='admin-commands' from='shakespeare.lit'>
+                <error type='cancel'>
+                    <item-not-found xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>
+                </error>
+            </iq>",
+        );
+        assert_eq!(nodes.unwrap().unwrap(), Vec::<String>::new());
+
+        assert!(response(
+            "<iq xmlns='jabber:client' type='result' id='admin-commands' from='evil@shakespeare.lit'/>"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_command_sessions() {
+        let agent = agent();
+        let response =
+            |id: &str, xml: &str| command_response(&agent, &server(), id, &xml.parse().unwrap());
+
+        // The first stage of add-user asks for a form, the second completes it.
+        let stage = response(
+            "admin-execute",
+            "<iq xmlns='jabber:client' from='shakespeare.lit' id='admin-execute' to='bard@shakespeare.lit/globe' type='result'>
+                <command xmlns='http://jabber.org/protocol/commands' node='http://jabber.org/protocol/admin#add-user' sessionid='add-user:20040408T0337Z' status='executing'>
+                    <x xmlns='jabber:x:data' type='form'>
+                        <field type='hidden' var='FORM_TYPE'><value>http://jabber.org/protocol/admin</value></field>
+                        <field type='jid-single' var='accountjid'><required/></field>
+                    </x>
+                </command>
+            </iq>",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(stage.session_id.as_deref(), Some("add-user:20040408T0337Z"));
+        assert_eq!(stage.status, Some(Status::Executing));
+        let done = response(
+            "admin-submit",
+            "<iq xmlns='jabber:client' from='shakespeare.lit' id='admin-submit' type='result'>
+                <command xmlns='http://jabber.org/protocol/commands' node='http://jabber.org/protocol/admin#add-user' sessionid='add-user:20040408T0337Z' status='completed'/>
+            </iq>",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(done.status, Some(Status::Completed));
+
+        // A refused deletion.
+        match response(
+            "admin-submit",
+            "<iq xmlns='jabber:client' from='shakespeare.lit' id='admin-submit' type='error'>
+                <error type='auth'>
+                    <forbidden xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>
+                </error>
+            </iq>",
+        )
+        .unwrap()
+        {
+            Err(Error::Stanza(error)) => {
+                assert_eq!(error.defined_condition, DefinedCondition::Forbidden)
+            }
+            result => panic!("{:?}", result),
+        }
+
+        let command = response(
+            "admin-execute",
+            "<iq xmlns='jabber:client' from='shakespeare.lit' id='admin-execute' type='result'>
+                <command xmlns='http://jabber.org/protocol/commands' node='http://jabber.org/protocol/admin#get-online-users-num' status='completed'>
+                    <x xmlns='jabber:x:data' type='result'>
+                        <field type='hidden' var='FORM_TYPE'><value>http://jabber.org/protocol/admin</value></field>
+                        <field var='onlineusersnum'><value>75</value></field>
+                    </x>
+                </command>
+            </iq>",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            OnlineUsersCount::try_from(command).unwrap(),
+            OnlineUsersCount(75)
+        );
+    }
+}
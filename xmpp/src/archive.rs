@@ -0,0 +1,515 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{Agent, Error, Step};
+use std::convert::TryFrom;
+use xmpp_parsers::{
+    disco::{DiscoInfoQuery, DiscoInfoResult, Feature},
+    iq::Iq,
+    mam::{Complete, End, Fin, MetadataQuery, MetadataResult, Query, QueryId, Result_, Start},
+    mam_prefs::Prefs,
+    ns,
+    rsm::SetQuery,
+    Element, Jid,
+};
+
+/// The query id of our archive queries, only one of which runs at a time.
+const QUERY_ID: &str = "archive";
+
+/// A page of messages from an archive (XEP-0313), see [`Agent::archive_page`].
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ArchivePage {
+    /// The messages, oldest first.
+    pub messages: Vec<Result_>,
+    /// Whether there is no older message left in the archive.
+    pub complete: bool,
+}
+
+/// Where an [`ArchiveQuery`] is at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// Asking the archive whether it supports metadata queries.
+    Features,
+    /// Asking the archive for its metadata.
+    Metadata,
+    /// Fetching a page of messages.
+    Page,
+}
+
+/// The exchange behind [`Agent::archive_metadata`] and [`Agent::archive_page`].
+///
+/// The metadata of the archive tell whether it is empty, and where it starts, so that syncing
+/// it from its newest message knows when to stop.  Archives which don’t advertise metadata
+/// queries get a page of their newest messages fetched instead.
+pub(crate) struct ArchiveQuery {
+    archive: Jid,
+    state: State,
+    /// How many messages to fetch, none but to find out the metadata if `None`.
+    max: Option<usize>,
+    /// The metadata, once known.
+    metadata: Option<MetadataResult>,
+    messages: Vec<Result_>,
+}
+
+/// What [`ArchiveQuery`] ends with.
+pub(crate) enum Outcome {
+    Metadata(MetadataResult),
+    Page(ArchivePage),
+}
+
+impl ArchiveQuery {
+    /// Find out the metadata of `archive`, returning the first iq to send for it.
+    pub(crate) fn metadata(archive: Jid) -> (ArchiveQuery, Iq) {
+        ArchiveQuery::new(archive, None)
+    }
+
+    /// Fetch the newest `max` messages of `archive`, returning the first iq to send for it.
+    pub(crate) fn newest(archive: Jid, max: usize) -> (ArchiveQuery, Iq) {
+        ArchiveQuery::new(archive, Some(max))
+    }
+
+    /// Fetch the `max` messages of `archive` before the one with the id `before`, returning
+    /// the iq to send for it.
+    pub(crate) fn before(archive: Jid, before: String, max: usize) -> (ArchiveQuery, Iq) {
+        let iq = page_request(&archive, before, max);
+        let query = ArchiveQuery {
+            archive,
+            state: State::Page,
+            max: Some(max),
+            metadata: None,
+            messages: Vec::new(),
+        };
+        (query, iq)
+    }
+
+    fn new(archive: Jid, max: Option<usize>) -> (ArchiveQuery, Iq) {
+        let iq = Iq::from_get("archive-features", DiscoInfoQuery { node: None })
+            .with_to(archive.clone());
+        let query = ArchiveQuery {
+            archive,
+            state: State::Features,
+            max,
+            metadata: None,
+            messages: Vec::new(),
+        };
+        (query, iq)
+    }
+
+    /// Handle `elem` if it belongs to this exchange.
+    pub(crate) fn handle(&mut self, agent: &Agent, elem: &Element) -> Option<Step<Outcome>> {
+        if let Some(result) = self.archived_message(agent, elem) {
+            // Results of an earlier, abandoned query are dropped as well.
+            if self.state == State::Page {
+                self.messages.extend(result);
+            }
+            return Some(Step::Consumed);
+        }
+        match self.state {
+            State::Features => {
+                let supported = match agent.iq_result(elem, "archive-features", &self.archive)? {
+                    Ok(Some(payload)) => DiscoInfoResult::try_from(payload)
+                        .map(|disco| disco.features.contains(&Feature::new(ns::MAM_EXTENDED)))
+                        .unwrap_or(false),
+                    // Not knowing is the same as it not being supported.
+                    Ok(None) | Err(_) => false,
+                };
+                if supported {
+                    self.state = State::Metadata;
+                    let iq = Iq::from_get("archive-metadata", MetadataQuery)
+                        .with_to(self.archive.clone());
+                    Some(Step::Send(iq))
+                } else {
+                    debug!("{} doesn’t support metadata queries", self.archive);
+                    Some(self.fetch_newest(self.max.unwrap_or(1)))
+                }
+            }
+            State::Metadata => {
+                let result = match agent.iq_result(elem, "archive-metadata", &self.archive)? {
+                    Ok(Some(payload)) => {
+                        MetadataResult::try_from(payload).map_err(|e| Error::Protocol(e.into()))
+                    }
+                    Ok(None) => Err(Error::Protocol(
+                        xmpp_parsers::Error::ParseError("Missing archive metadata.").into(),
+                    )),
+                    Err(err) => Err(err),
+                };
+                let metadata = match result {
+                    Ok(metadata) => metadata,
+                    Err(err) => return Some(Step::Done(Err(err))),
+                };
+                match self.max {
+                    None => Some(Step::Done(Ok(Outcome::Metadata(metadata)))),
+                    // Nothing to fetch from an empty archive.
+                    Some(_) if metadata.end.is_none() => {
+                        Some(Step::Done(Ok(Outcome::Page(ArchivePage {
+                            messages: Vec::new(),
+                            complete: true,
+                        }))))
+                    }
+                    Some(max) => {
+                        self.metadata = Some(metadata);
+                        Some(self.fetch_newest(max))
+                    }
+                }
+            }
+            State::Page => {
+                let result = match agent.iq_result(elem, "archive-page", &self.archive)? {
+                    Ok(Some(payload)) => {
+                        Fin::try_from(payload).map_err(|e| Error::Protocol(e.into()))
+                    }
+                    Ok(None) => Err(Error::Protocol(
+                        xmpp_parsers::Error::ParseError("Missing end of archive query.").into(),
+                    )),
+                    Err(err) => Err(err),
+                };
+                Some(Step::Done(result.map(|fin| self.finish(fin))))
+            }
+        }
+    }
+
+    fn fetch_newest(&mut self, max: usize) -> Step<Outcome> {
+        self.state = State::Page;
+        // An empty before asks for the last page.
+        Step::Send(page_request(&self.archive, String::new(), max))
+    }
+
+    fn finish(&mut self, fin: Fin) -> Outcome {
+        let messages = std::mem::take(&mut self.messages);
+        let start = self
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.start.as_ref());
+        let complete = fin.complete == Complete::True
+            || match (start, messages.first()) {
+                (Some(start), Some(oldest)) => start.id == oldest.id,
+                _ => false,
+            };
+        match self.max {
+            Some(_) => Outcome::Page(ArchivePage { messages, complete }),
+            None => Outcome::Metadata(metadata_from_page(&messages, complete)),
+        }
+    }
+
+    /// Whether `elem` is a message forwarded from the archive for our query, and the result
+    /// it contains if it is valid.
+    fn archived_message(&self, agent: &Agent, elem: &Element) -> Option<Option<Result_>> {
+        if !elem.is("message", ns::JABBER_CLIENT) {
+            return None;
+        }
+        let from_archive = match elem.attr("from") {
+            Some(from) => from.parse::<Jid>().ok()? == self.archive,
+            None => self.archive == Jid::Bare(agent.own_jid.clone()),
+        };
+        if !from_archive {
+            return None;
+        }
+        let result = elem.get_child("result", ns::MAM)?;
+        if result.attr("queryid") != Some(QUERY_ID) {
+            return None;
+        }
+        Some(Result_::try_from(result.clone()).ok())
+    }
+}
+
+fn page_request(archive: &Jid, before: String, max: usize) -> Iq {
+    let query = Query {
+        queryid: Some(QueryId(String::from(QUERY_ID))),
+        node: None,
+        form: None,
+        set: Some(SetQuery {
+            max: Some(max),
+            after: None,
+            before: Some(before),
+            index: None,
+        }),
+    };
+    Iq::from_set("archive-page", query).with_to(archive.clone())
+}
+
+/// The metadata of an archive guessed from a page of its newest `messages`, knowing where it
+/// starts only if this page is `complete`.
+fn metadata_from_page(messages: &[Result_], complete: bool) -> MetadataResult {
+    let bound = |message: &Result_| {
+        let delay = message.forwarded.delay.as_ref()?;
+        Some((message.id.clone(), delay.stamp))
+    };
+    let start = messages
+        .first()
+        .filter(|_| complete)
+        .and_then(bound)
+        .map(|(id, timestamp)| Start { id, timestamp });
+    let end = messages
+        .last()
+        .and_then(bound)
+        .map(|(id, timestamp)| End { id, timestamp });
+    MetadataResult { start, end }
+}
+
+/// Whether `elem` answers our request with this `id` for our archiving preferences, and with
+/// what.  A server may answer a change without repeating the preferences, then `sent`.
+pub(crate) fn prefs_response(
+    agent: &Agent,
+    id: &str,
+    sent: Option<&Prefs>,
+    elem: &Element,
+) -> Option<Result<Prefs, Error>> {
+    let own_jid = Jid::Bare(agent.own_jid.clone());
+    Some(match agent.iq_result(elem, id, &own_jid)? {
+        Ok(Some(payload)) => Prefs::try_from(payload).map_err(|e| Error::Protocol(e.into())),
+        Ok(None) => sent.cloned().ok_or_else(|| {
+            Error::Protocol(
+                xmpp_parsers::Error::ParseError("Missing archiving preferences.").into(),
+            )
+        }),
+        Err(err) => Err(err),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClientBuilder;
+    use tokio_xmpp::AsyncClient as TokioXmppClient;
+    use xmpp_parsers::{date::DateTime, mam_prefs::DefaultPrefs, BareJid};
+
+    fn agent() -> Agent {
+        let client = TokioXmppClient::new("juliet@capulet.lit", "meh").unwrap();
+        ClientBuilder::new("juliet@capulet.lit", "meh")
+            .build_impl(client)
+            .unwrap()
+    }
+
+    fn own_archive() -> Jid {
+        Jid::Bare(BareJid::new("juliet", "capulet.lit"))
+    }
+
+    fn parse(xml: &str) -> Element {
+        xml.parse().unwrap()
+    }
+
+    /// Play `script` against `query`, the server sending each element and expecting the iq
+    /// `query` sends next if there is one, returning the outcome.  Elements which aren’t part
+    /// of the exchange must be left alone.
+    fn play(agent: &Agent, query: &mut ArchiveQuery, script: &[(&str, Option<&str>)]) -> Outcome {
+        for (received, expected) in script {
+            match (query.handle(agent, &parse(received)), expected) {
+                (Some(Step::Send(iq)), Some(expected)) => {
+                    assert_eq!(Element::from(iq), parse(expected))
+                }
+                (Some(Step::Consumed), None) | (None, None) => (),
+                (Some(Step::Done(outcome)), None) => return outcome.unwrap(),
+                (_, expected) => panic!("Unexpected step, expected {:?}", expected),
+            }
+        }
+        panic!("The script ended before the query.");
+    }
+
+    fn message(id: &str, stamp: &str, body: &str) -> String {
+        format!(
+            "<message xmlns='jabber:client' to='juliet@capulet.lit/balcony'>
+                <result xmlns='urn:xmpp:mam:2' queryid='archive' id='{}'>
+                    <forwarded xmlns='urn:xmpp:forward:0'>
+                        <delay xmlns='urn:xmpp:delay' stamp='{}'/>
+                        <message xmlns='jabber:client' from='romeo@montague.lit/orchard' to='juliet@capulet.lit/balcony' type='chat'><body>{}</body></message>
+                    </forwarded>
+                </result>
+            </message>",
+            id, stamp, body
+        )
+    }
+
+    const LAST_PAGE: &str = "<iq xmlns='jabber:client' type='set' id='archive-page' to='juliet@capulet.lit'><query xmlns='urn:xmpp:mam:2' queryid='archive'><set xmlns='http://jabber.org/protocol/rsm'><max>2</max><before/></set></query></iq>";
+
+    #[test]
+    fn test_metadata() {
+        let agent = agent();
+        let (mut query, iq) = ArchiveQuery::metadata(own_archive());
+        assert_eq!(
+            Element::from(iq),
+            parse("<iq xmlns='jabber:client' type='get' id='archive-features' to='juliet@capulet.lit'><query xmlns='http://jabber.org/protocol/disco#info'/></iq>")
+        );
+        let outcome = play(
+            &agent,
+            &mut query,
+            &[
+                (
+                    "<iq xmlns='jabber:client' type='result' id='archive-features'><query xmlns='http://jabber.org/protocol/disco#info'><identity category='account' type='registered'/><feature var='http://jabber.org/protocol/disco#info'/><feature var='urn:xmpp:mam:2'/><feature var='urn:xmpp:mam:2#extended'/></query></iq>",
+                    Some("<iq xmlns='jabber:client' type='get' id='archive-metadata' to='juliet@capulet.lit'><metadata xmlns='urn:xmpp:mam:2'/></iq>"),
+                ),
+                (
+                    "<iq xmlns='jabber:client' type='result' id='archive-metadata'><metadata xmlns='urn:xmpp:mam:2'><start id='YWxwaGEg' timestamp='2008-08-22T21:09:04Z'/><end id='b21lZ2Eg' timestamp='2020-04-20T14:34:21Z'/></metadata></iq>",
+                    None,
+                ),
+            ],
+        );
+        match outcome {
+            Outcome::Metadata(metadata) => {
+                assert_eq!(metadata.start.unwrap().id, "YWxwaGEg");
+                assert_eq!(metadata.end.unwrap().id, "b21lZ2Eg");
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_metadata_unsupported() {
+        // Without metadata queries, the newest message tells where the archive ends.
+        let agent = agent();
+        let (mut query, _) = ArchiveQuery::metadata(own_archive());
+        let newest = message("b21lZ2Eg", "2020-04-20T14:34:21Z", "Goodnight!");
+        let outcome = play(
+            &agent,
+            &mut query,
+            &[
+                (
+                    "<iq xmlns='jabber:client' type='result' id='archive-features'><query xmlns='http://jabber.org/protocol/disco#info'><identity category='account' type='registered'/><feature var='http://jabber.org/protocol/disco#info'/><feature var='urn:xmpp:mam:2'/></query></iq>",
+                    Some("<iq xmlns='jabber:client' type='set' id='archive-page' to='juliet@capulet.lit'><query xmlns='urn:xmpp:mam:2' queryid='archive'><set xmlns='http://jabber.org/protocol/rsm'><max>1</max><before/></set></query></iq>"),
+                ),
+                (&newest, None),
+                (
+                    "<iq xmlns='jabber:client' type='result' id='archive-page'><fin xmlns='urn:xmpp:mam:2'><set xmlns='http://jabber.org/protocol/rsm'><first index='41'>b21lZ2Eg</first><last>b21lZ2Eg</last><count>42</count></set></fin></iq>",
+                    None,
+                ),
+            ],
+        );
+        match outcome {
+            Outcome::Metadata(metadata) => {
+                // There are older messages, so where it starts is unknown.
+                assert_eq!(metadata.start, None);
+                let end = metadata.end.unwrap();
+                assert_eq!(end.id, "b21lZ2Eg");
+                assert_eq!(
+                    end.timestamp,
+                    "2020-04-20T14:34:21Z".parse::<DateTime>().unwrap()
+                );
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_sync() {
+        let agent = agent();
+        let (mut query, _) = ArchiveQuery::newest(own_archive(), 2);
+        let outcome = play(
+            &agent,
+            &mut query,
+            &[
+                (
+                    "<iq xmlns='jabber:client' type='result' id='archive-features'><query xmlns='http://jabber.org/protocol/disco#info'><identity category='account' type='registered'/><feature var='http://jabber.org/protocol/disco#info'/><feature var='urn:xmpp:mam:2#extended'/></query></iq>",
+                    Some("<iq xmlns='jabber:client' type='get' id='archive-metadata' to='juliet@capulet.lit'><metadata xmlns='urn:xmpp:mam:2'/></iq>"),
+                ),
+                (
+                    "<iq xmlns='jabber:client' type='result' id='archive-metadata'><metadata xmlns='urn:xmpp:mam:2'><start id='1' timestamp='2020-04-20T14:30:00Z'/><end id='2' timestamp='2020-04-20T14:34:21Z'/></metadata></iq>",
+                    Some(LAST_PAGE),
+                ),
+                (&message("1", "2020-04-20T14:30:00Z", "Hi!"), None),
+                (&message("2", "2020-04-20T14:34:21Z", "Goodnight!"), None),
+                (
+                    "<iq xmlns='jabber:client' type='result' id='archive-page'><fin xmlns='urn:xmpp:mam:2'><set xmlns='http://jabber.org/protocol/rsm'><first index='0'>1</first><last>2</last></set></fin></iq>",
+                    None,
+                ),
+            ],
+        );
+        match outcome {
+            Outcome::Page(page) => {
+                let ids: Vec<_> = page.messages.iter().map(|m| m.id.as_str()).collect();
+                assert_eq!(ids, ["1", "2"]);
+                // The oldest message is the start of the archive, even if the server didn’t
+                // say the query was complete.
+                assert!(page.complete);
+            }
+            _ => panic!(),
+        }
+
+        // An empty archive doesn’t get queried.
+        let (mut query, _) = ArchiveQuery::newest(own_archive(), 2);
+        let outcome = play(
+            &agent,
+            &mut query,
+            &[
+                (
+                    "<iq xmlns='jabber:client' type='result' id='archive-features'><query xmlns='http://jabber.org/protocol/disco#info'><identity category='account' type='registered'/><feature var='http://jabber.org/protocol/disco#info'/><feature var='urn:xmpp:mam:2#extended'/></query></iq>",
+                    Some("<iq xmlns='jabber:client' type='get' id='archive-metadata' to='juliet@capulet.lit'><metadata xmlns='urn:xmpp:mam:2'/></iq>"),
+                ),
+                (
+                    "<iq xmlns='jabber:client' type='result' id='archive-metadata'><metadata xmlns='urn:xmpp:mam:2'/></iq>",
+                    None,
+                ),
+            ],
+        );
+        assert_eq!(
+            match outcome {
+                Outcome::Page(page) => page,
+                Outcome::Metadata(_) => panic!(),
+            },
+            ArchivePage {
+                messages: Vec::new(),
+                complete: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_sync_unsupported() {
+        // A failed disco query falls back to a bounded page as well.
+        let agent = agent();
+        let room = Jid::Bare(BareJid::new("coven", "chat.shakespeare.lit"));
+        let (mut query, _) = ArchiveQuery::newest(room, 2);
+        let outcome = play(
+            &agent,
+            &mut query,
+            &[
+                (
+                    "<iq xmlns='jabber:client' type='error' id='archive-features' from='coven@chat.shakespeare.lit'><error type='cancel'><service-unavailable xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/></error></iq>",
+                    Some("<iq xmlns='jabber:client' type='set' id='archive-page' to='coven@chat.shakespeare.lit'><query xmlns='urn:xmpp:mam:2' queryid='archive'><set xmlns='http://jabber.org/protocol/rsm'><max>2</max><before/></set></query></iq>"),
+                ),
+                // Messages from elsewhere aren’t part of the page.
+                (&message("x", "2020-04-20T14:30:00Z", "Hi!"), None),
+                (
+                    "<iq xmlns='jabber:client' type='result' id='archive-page' from='coven@chat.shakespeare.lit'><fin xmlns='urn:xmpp:mam:2'><set xmlns='http://jabber.org/protocol/rsm'><count>0</count></set></fin></iq>",
+                    None,
+                ),
+            ],
+        );
+        match outcome {
+            Outcome::Page(page) => {
+                assert!(page.messages.is_empty());
+                assert!(!page.complete);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_prefs() {
+        let agent = agent();
+        let prefs = prefs_response(
+            &agent,
+            "mam-prefs",
+            None,
+            &parse("<iq xmlns='jabber:client' type='result' id='mam-prefs'><prefs xmlns='urn:xmpp:mam:2' default='roster'><always><jid>romeo@montague.lit</jid></always></prefs></iq>"),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(prefs.default_, DefaultPrefs::Roster);
+        assert_eq!(
+            prefs.always,
+            [Jid::Bare(BareJid::new("romeo", "montague.lit"))]
+        );
+
+        // An empty result applied what we sent.
+        let sent = Prefs {
+            default_: DefaultPrefs::Never,
+            always: Vec::new(),
+            never: Vec::new(),
+        };
+        let empty = parse("<iq xmlns='jabber:client' type='result' id='mam-prefs-set'/>");
+        let prefs = prefs_response(&agent, "mam-prefs-set", Some(&sent), &empty);
+        assert_eq!(prefs.unwrap().unwrap(), sent);
+        assert!(prefs_response(&agent, "mam-prefs", None, &empty).is_none());
+    }
+}
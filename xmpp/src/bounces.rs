@@ -0,0 +1,141 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The messages we recently sent, to tell which one an error coming back is about.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use xmpp_parsers::{BareJid, Jid};
+
+/// How many messages to remember by default, the oldest ones being forgotten first.
+pub(crate) const DEFAULT_WINDOW: usize = 128;
+
+/// How long to remember a message by default.
+pub(crate) const DEFAULT_RETENTION: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SentMessage {
+    pub(crate) id: String,
+    pub(crate) to: Jid,
+    pub(crate) body: String,
+    sent_at: Instant,
+}
+
+#[derive(Debug)]
+pub(crate) struct SentMessages {
+    window: usize,
+    retention: Duration,
+    /// Oldest first.
+    sent: VecDeque<SentMessage>,
+    /// Used to give an id to the messages which don’t have one.
+    counter: u64,
+}
+
+impl SentMessages {
+    pub(crate) fn new(window: usize, retention: Duration) -> Self {
+        SentMessages {
+            window,
+            retention,
+            sent: VecDeque::new(),
+            counter: 0,
+        }
+    }
+
+    /// A new id, unique for the lifetime of this Agent.
+    pub(crate) fn next_id(&mut self) -> String {
+        self.counter += 1;
+        format!("xmpp-rs-{}", self.counter)
+    }
+
+    /// A message got sent to `to`.
+    pub(crate) fn record(&mut self, id: String, to: Jid, body: String, now: Instant) {
+        self.expire(now);
+        if self.window == 0 {
+            return;
+        }
+        if self.sent.len() >= self.window {
+            self.sent.pop_front();
+        }
+        self.sent.push_back(SentMessage {
+            id,
+            to,
+            body,
+            sent_at: now,
+        });
+    }
+
+    /// An error with this `id` came back from `from`, which must be either the recipient of the
+    /// message, or its server bouncing it.
+    pub(crate) fn bounced(&mut self, id: &str, from: &Jid, now: Instant) -> Option<SentMessage> {
+        self.expire(now);
+        let from_bare = BareJid::from(from.clone());
+        let position = self.sent.iter().position(|sent| {
+            let to = BareJid::from(sent.to.clone());
+            sent.id == id
+                && (to == from_bare || (from_bare.node.is_none() && from_bare.domain == to.domain))
+        })?;
+        self.sent.remove(position)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(sent) = self.sent.front() {
+            if now.saturating_duration_since(sent.sent_at) < self.retention {
+                break;
+            }
+            self.sent.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_bounced() {
+        let start = Instant::now();
+        let jid = |jid: &str| Jid::from_str(jid).unwrap();
+        let mut sent = SentMessages::new(2, Duration::from_secs(60));
+        sent.record(
+            String::from("a"),
+            jid("coucou@bar/phone"),
+            String::from("Hi"),
+            start,
+        );
+        sent.record(
+            String::from("b"),
+            jid("other@baz"),
+            String::from("Yo"),
+            start,
+        );
+
+        // Nobody else can claim a bounce.
+        assert_eq!(sent.bounced("a", &jid("other@baz"), start), None);
+        assert_eq!(sent.bounced("a", &jid("coucou@baz"), start), None);
+        let bounced = sent.bounced("a", &jid("coucou@bar"), start).unwrap();
+        assert_eq!(bounced.body, "Hi");
+        // Only once.
+        assert_eq!(sent.bounced("a", &jid("coucou@bar"), start), None);
+        // The server of the recipient can.
+        assert!(sent.bounced("b", &jid("baz"), start).is_some());
+    }
+
+    #[test]
+    fn test_window() {
+        let start = Instant::now();
+        let jid = Jid::from_str("coucou@bar").unwrap();
+        let mut sent = SentMessages::new(2, Duration::from_secs(60));
+        for id in &["a", "b", "c"] {
+            sent.record(String::from(*id), jid.clone(), String::new(), start);
+        }
+        assert_eq!(sent.bounced("a", &jid, start), None);
+        assert!(sent.bounced("b", &jid, start).is_some());
+
+        let later = start + Duration::from_secs(60);
+        assert_eq!(sent.bounced("c", &jid, later), None);
+    }
+}
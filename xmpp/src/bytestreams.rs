@@ -0,0 +1,202 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! SOCKS5 bytestream proxies (XEP-0065), see [`Agent::discover_streamhosts`].
+
+use crate::{Agent, Error, Step};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use xmpp_parsers::{
+    bytestreams::{Query, StreamHost},
+    disco::{DiscoInfoQuery, DiscoInfoResult, DiscoItemsQuery, DiscoItemsResult},
+    iq::Iq,
+    Element, Jid,
+};
+
+/// What a [`Discovery`] is waiting for.
+#[derive(Clone)]
+enum Asking {
+    /// The items of our server.
+    Items,
+    /// The identities of this item.
+    Info(Jid),
+    /// The network address of this proxy.
+    Address(Jid),
+}
+
+/// The exchange behind [`Agent::discover_streamhosts`]: the items of our server, then the
+/// identities of each of them in turn, then the network address of each proxy among them.
+pub(crate) struct Discovery {
+    server: Jid,
+    asking: Asking,
+    /// The items left to ask for their identities.
+    items: VecDeque<Jid>,
+    /// The proxies left to ask for their network address.
+    proxies: VecDeque<Jid>,
+    found: Vec<StreamHost>,
+}
+
+impl Discovery {
+    /// Discover the proxies of `server`, returning the first iq to send for it.
+    pub(crate) fn new(server: Jid) -> (Discovery, Iq) {
+        let iq =
+            Iq::from_get("proxy-items", DiscoItemsQuery { node: None }).with_to(server.clone());
+        let discovery = Discovery {
+            server,
+            asking: Asking::Items,
+            items: VecDeque::new(),
+            proxies: VecDeque::new(),
+            found: Vec::new(),
+        };
+        (discovery, iq)
+    }
+
+    /// Handle `elem` if it belongs to this exchange.
+    pub(crate) fn handle(
+        &mut self,
+        agent: &Agent,
+        elem: &Element,
+    ) -> Option<Step<Vec<StreamHost>>> {
+        match self.asking.clone() {
+            Asking::Items => {
+                let items = match agent.iq_result(elem, "proxy-items", &self.server)? {
+                    Ok(Some(payload)) => match DiscoItemsResult::try_from(payload) {
+                        Ok(result) => result.items,
+                        Err(e) => return Some(Step::Done(Err(Error::Protocol(e.into())))),
+                    },
+                    Ok(None) => {
+                        return Some(Step::Done(Err(Error::Protocol(
+                            xmpp_parsers::Error::ParseError("Missing disco#items.").into(),
+                        ))))
+                    }
+                    Err(err) => return Some(Step::Done(Err(err))),
+                };
+                for item in items {
+                    if item.node.is_none() && !self.items.contains(&item.jid) {
+                        self.items.push_back(item.jid);
+                    }
+                }
+            }
+            Asking::Info(item) => {
+                // An item which can’t tell what it is isn’t usable as a proxy.
+                if let Ok(Some(payload)) = agent.iq_result(elem, "proxy-info", &item)? {
+                    if let Ok(info) = DiscoInfoResult::try_from(payload) {
+                        if info.identities.iter().any(|identity| {
+                            identity.category == "proxy" && identity.type_ == "bytestreams"
+                        }) {
+                            self.proxies.push_back(item);
+                        }
+                    }
+                }
+            }
+            Asking::Address(proxy) => {
+                // Proxies may only relay for some accounts, those refusing us are skipped.
+                if let Ok(Some(payload)) = agent.iq_result(elem, "proxy-address", &proxy)? {
+                    if let Ok(query) = Query::try_from(payload) {
+                        self.found.extend(query.streamhosts);
+                    }
+                }
+            }
+        }
+        self.next()
+    }
+
+    /// Ask the next item for its identities, then the next proxy for its address, or end with
+    /// the streamhosts found.
+    fn next(&mut self) -> Option<Step<Vec<StreamHost>>> {
+        if let Some(item) = self.items.pop_front() {
+            let iq =
+                Iq::from_get("proxy-info", DiscoInfoQuery { node: None }).with_to(item.clone());
+            self.asking = Asking::Info(item);
+            Some(Step::Send(iq))
+        } else if let Some(proxy) = self.proxies.pop_front() {
+            let iq = Iq::from_get("proxy-address", Query::discover()).with_to(proxy.clone());
+            self.asking = Asking::Address(proxy);
+            Some(Step::Send(iq))
+        } else {
+            Some(Step::Done(Ok(std::mem::take(&mut self.found))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClientBuilder;
+    use tokio_xmpp::AsyncClient as TokioXmppClient;
+
+    fn parse(xml: &str) -> Element {
+        xml.parse().unwrap()
+    }
+
+    fn jid(jid: &str) -> Jid {
+        jid.parse().unwrap()
+    }
+
+    #[test]
+    fn test_discover() {
+        let client = TokioXmppClient::new("juliet@capulet.lit", "meh").unwrap();
+        let agent = ClientBuilder::new("juliet@capulet.lit", "meh")
+            .build_impl(client)
+            .unwrap();
+        let (mut discovery, iq) = Discovery::new(jid("capulet.lit"));
+        assert_eq!(
+            Element::from(iq),
+            parse("<iq xmlns='jabber:client' type='get' id='proxy-items' to='capulet.lit'><query xmlns='http://jabber.org/protocol/disco#items'/></iq>")
+        );
+        let script = [
+            (
+                "<iq xmlns='jabber:client' type='result' id='proxy-items' from='capulet.lit'><query xmlns='http://jabber.org/protocol/disco#items'><item jid='muc.capulet.lit'/><item jid='proxy.capulet.lit'/><item jid='proxy2.capulet.lit'/></query></iq>",
+                Some("<iq xmlns='jabber:client' type='get' id='proxy-info' to='muc.capulet.lit'><query xmlns='http://jabber.org/protocol/disco#info'/></iq>"),
+            ),
+            (
+                "<iq xmlns='jabber:client' type='result' id='proxy-info' from='muc.capulet.lit'><query xmlns='http://jabber.org/protocol/disco#info'><identity category='conference' type='text'/></query></iq>",
+                Some("<iq xmlns='jabber:client' type='get' id='proxy-info' to='proxy.capulet.lit'><query xmlns='http://jabber.org/protocol/disco#info'/></iq>"),
+            ),
+            (
+                "<iq xmlns='jabber:client' type='result' id='proxy-info' from='proxy.capulet.lit'><query xmlns='http://jabber.org/protocol/disco#info'><identity category='proxy' type='bytestreams' name='SOCKS5 Bytestreams Service'/><feature var='http://jabber.org/protocol/bytestreams'/></query></iq>",
+                Some("<iq xmlns='jabber:client' type='get' id='proxy-info' to='proxy2.capulet.lit'><query xmlns='http://jabber.org/protocol/disco#info'/></iq>"),
+            ),
+            (
+                "<iq xmlns='jabber:client' type='result' id='proxy-info' from='proxy2.capulet.lit'><query xmlns='http://jabber.org/protocol/disco#info'><identity category='proxy' type='bytestreams'/></query></iq>",
+                Some("<iq xmlns='jabber:client' type='get' id='proxy-address' to='proxy.capulet.lit'><query xmlns='http://jabber.org/protocol/bytestreams'/></iq>"),
+            ),
+            // Some other answer, left alone.
+            (
+                "<iq xmlns='jabber:client' type='result' id='proxy-address' from='proxy2.capulet.lit'/>",
+                None,
+            ),
+            (
+                "<iq xmlns='jabber:client' type='result' id='proxy-address' from='proxy.capulet.lit'><query xmlns='http://jabber.org/protocol/bytestreams'><streamhost jid='proxy.capulet.lit' host='24.24.24.1' port='7777'/></query></iq>",
+                Some("<iq xmlns='jabber:client' type='get' id='proxy-address' to='proxy2.capulet.lit'><query xmlns='http://jabber.org/protocol/bytestreams'/></iq>"),
+            ),
+            // A proxy refusing us doesn’t end the discovery.
+            (
+                "<iq xmlns='jabber:client' type='error' id='proxy-address' from='proxy2.capulet.lit'><error type='auth'><forbidden xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/></error></iq>",
+                None,
+            ),
+        ];
+        let mut found = None;
+        for (received, expected) in script.iter() {
+            match (discovery.handle(&agent, &parse(received)), expected) {
+                (Some(Step::Send(iq)), Some(expected)) => {
+                    assert_eq!(Element::from(iq), parse(expected))
+                }
+                (None, None) => (),
+                (Some(Step::Done(streamhosts)), None) => found = Some(streamhosts.unwrap()),
+                (_, expected) => panic!("Unexpected step, expected {:?}", expected),
+            }
+        }
+        assert_eq!(
+            found.unwrap(),
+            [StreamHost::new(
+                jid("proxy.capulet.lit"),
+                "24.24.24.1",
+                7777
+            )]
+        );
+    }
+}
@@ -0,0 +1,398 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The Jingle calls (XEP-0166, XEP-0167) contacts started with us, from their session-initiate
+//! until either side terminates them.
+
+use crate::messages::{self, Messages};
+use crate::Event;
+use std::collections::HashMap;
+use xmpp_parsers::{
+    iq::Iq,
+    jingle::{
+        Action, Content, ContentId, Creator, Description, Jingle, Reason, ReasonElement, SessionId,
+        Transport,
+    },
+    jingle_ice_udp::Transport as IceUdpTransport,
+    ns,
+    stanza_error::{DefinedCondition, ErrorType, StanzaError},
+    BareJid, Element, Jid,
+};
+
+/// How many calls a contact can start with us before we answer any of them, further ones
+/// being refused.
+const MAX_PENDING_PER_PEER: usize = 4;
+
+#[derive(Debug)]
+struct Call {
+    /// Who created each content, which has to be repeated when referring to it.
+    creators: HashMap<ContentId, Creator>,
+    /// Whether we sent our session-accept.
+    accepted: bool,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Calls {
+    /// Keyed by peer too, session ids being only unique to their initiator.
+    calls: HashMap<(Jid, SessionId), Call>,
+}
+
+impl Calls {
+    /// Handle a Jingle iq from `from`, returning the events it causes, or why it has to be
+    /// refused.
+    pub(crate) fn handle(&mut self, from: Jid, jingle: Jingle) -> Result<Vec<Event>, Refusal> {
+        if jingle.action == Action::SessionInitiate {
+            return self.initiate(from, jingle);
+        }
+        let key = (from, jingle.sid);
+        let call = match self.calls.get(&key) {
+            Some(call) => call,
+            None => return Err(Refusal::UnknownSession),
+        };
+        let (from, sid) = key;
+        match jingle.action {
+            Action::TransportInfo => Ok(jingle
+                .contents
+                .into_iter()
+                .filter(|content| call.creators.contains_key(&content.name))
+                .filter_map(|content| match content.transport {
+                    Some(Transport::IceUdp(transport)) => Some(Event::JingleCallIceCandidate {
+                        from: from.clone(),
+                        sid: sid.clone(),
+                        content: content.name,
+                        transport,
+                    }),
+                    _ => None,
+                })
+                .collect()),
+            Action::SessionTerminate => {
+                self.calls.remove(&(from.clone(), sid.clone()));
+                Ok(vec![Event::JingleCallEnding {
+                    from,
+                    sid,
+                    reason: jingle.reason,
+                }])
+            }
+            // Ringing and the like, which can be ignored.
+            Action::SessionInfo => Ok(Vec::new()),
+            _ => Err(Refusal::UnsupportedAction),
+        }
+    }
+
+    fn initiate(&mut self, from: Jid, jingle: Jingle) -> Result<Vec<Event>, Refusal> {
+        let key = (from, jingle.sid);
+        if self.calls.contains_key(&key) {
+            return Err(Refusal::Conflict);
+        }
+        let (from, sid) = key;
+        let peer = BareJid::from(from.clone());
+        let pending = self
+            .calls
+            .iter()
+            .filter(|((jid, _), call)| !call.accepted && same_account(jid, &peer))
+            .count();
+        if pending >= MAX_PENDING_PER_PEER {
+            return Err(Refusal::TooManyCalls);
+        }
+        let rtp = |content: &Content| matches!(content.description, Some(Description::Rtp(_)));
+        if jingle.contents.is_empty() || !jingle.contents.iter().all(rtp) {
+            return Err(Refusal::NotRtp);
+        }
+        let creators = jingle
+            .contents
+            .iter()
+            .map(|content| (content.name.clone(), content.creator.clone()))
+            .collect();
+        self.calls.insert(
+            (from.clone(), sid.clone()),
+            Call {
+                creators,
+                accepted: false,
+            },
+        );
+        Ok(vec![Event::JingleCallSessionInitiate {
+            from,
+            sid,
+            contents: jingle.contents,
+        }])
+    }
+
+    /// The session-accept of the call `sid` with `peer` with our `contents`, if it is still
+    /// going on.
+    pub(crate) fn accept(
+        &mut self,
+        peer: &Jid,
+        sid: &SessionId,
+        responder: Option<Jid>,
+        contents: Vec<Content>,
+    ) -> Option<Iq> {
+        let call = self.calls.get_mut(&(peer.clone(), sid.clone()))?;
+        call.accepted = true;
+        let mut jingle = Jingle::new(Action::SessionAccept, sid.clone());
+        jingle.responder = responder;
+        jingle.contents = contents;
+        Some(Iq::from_set("jingle-accept", jingle).with_to(peer.clone()))
+    }
+
+    /// The session-terminate of the call `sid` with `peer`, forgotten from now on, if it was
+    /// still going on.
+    pub(crate) fn terminate(&mut self, peer: &Jid, sid: &SessionId, reason: Reason) -> Option<Iq> {
+        self.calls.remove(&(peer.clone(), sid.clone()))?;
+        let jingle = Jingle::new(Action::SessionTerminate, sid.clone()).set_reason(ReasonElement {
+            reason,
+            texts: Default::default(),
+        });
+        Some(Iq::from_set("jingle-terminate", jingle).with_to(peer.clone()))
+    }
+
+    /// The transport-info giving ICE candidates for `content` of the call `sid` with `peer`,
+    /// if both exist.
+    pub(crate) fn transport_info(
+        &self,
+        peer: &Jid,
+        sid: &SessionId,
+        content: ContentId,
+        transport: IceUdpTransport,
+    ) -> Option<Iq> {
+        let call = self.calls.get(&(peer.clone(), sid.clone()))?;
+        let creator = call.creators.get(&content)?.clone();
+        let jingle = Jingle::new(Action::TransportInfo, sid.clone())
+            .add_content(Content::new(creator, content).with_transport(transport));
+        Some(Iq::from_set("jingle-transport-info", jingle).with_to(peer.clone()))
+    }
+
+    /// Forget every call, the connection they were negotiated on being gone.
+    pub(crate) fn clear(&mut self) {
+        self.calls.clear();
+    }
+}
+
+/// Whether `jid` is `account` or one of its resources.
+fn same_account(jid: &Jid, account: &BareJid) -> bool {
+    match jid {
+        Jid::Bare(bare) => bare == account,
+        Jid::Full(full) => full.node == account.node && full.domain == account.domain,
+    }
+}
+
+/// Why a Jingle iq got refused.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Refusal {
+    /// The session doesn’t exist, or is with someone else.
+    UnknownSession,
+    /// The session already exists.
+    Conflict,
+    /// The peer started too many calls we didn’t answer yet.
+    TooManyCalls,
+    /// The session isn’t a call.
+    NotRtp,
+    /// Contents can’t be added, removed or modified during a call.
+    UnsupportedAction,
+}
+
+impl Refusal {
+    /// The error answering the refused iq, its text written by `messages`.
+    pub(crate) fn into_error(self, messages: &dyn Messages) -> StanzaError {
+        let (type_, condition, text) = match self {
+            Refusal::UnknownSession => (
+                ErrorType::Cancel,
+                DefinedCondition::ItemNotFound,
+                messages.unknown_jingle_session(),
+            ),
+            Refusal::Conflict => (
+                ErrorType::Cancel,
+                DefinedCondition::Conflict,
+                messages.jingle_session_conflict(),
+            ),
+            Refusal::TooManyCalls => (
+                ErrorType::Wait,
+                DefinedCondition::ResourceConstraint,
+                messages.too_many_jingle_sessions(),
+            ),
+            Refusal::NotRtp => (
+                ErrorType::Cancel,
+                DefinedCondition::FeatureNotImplemented,
+                messages.jingle_not_rtp(),
+            ),
+            Refusal::UnsupportedAction => (
+                ErrorType::Cancel,
+                DefinedCondition::FeatureNotImplemented,
+                messages.jingle_unsupported_action(),
+            ),
+        };
+        let mut error = messages::stanza_error(messages, type_, condition, text);
+        if self == Refusal::UnknownSession {
+            error.other = Some(Element::builder("unknown-session", ns::JINGLE_ERRORS).build());
+        }
+        error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+    use xmpp_parsers::iq::IqType;
+
+    fn jingle(xml: &str) -> Jingle {
+        let elem: Element = xml.parse().unwrap();
+        Jingle::try_from(elem).unwrap()
+    }
+
+    const INITIATE: &str = "<jingle xmlns='urn:xmpp:jingle:1' action='session-initiate' sid='a73sjjvkla37jfea'><content creator='initiator' name='voice'><description xmlns='urn:xmpp:jingle:apps:rtp:1' media='audio'><payload-type id='96' name='speex' clockrate='16000'/></description><transport xmlns='urn:xmpp:jingle:transports:ice-udp:1' pwd='asd88fgpdd777uzjYhagZg' ufrag='8hhy'/></content></jingle>";
+
+    const CANDIDATE: &str = "<jingle xmlns='urn:xmpp:jingle:1' action='transport-info' sid='a73sjjvkla37jfea'><content creator='initiator' name='voice'><transport xmlns='urn:xmpp:jingle:transports:ice-udp:1' pwd='asd88fgpdd777uzjYhagZg' ufrag='8hhy'><candidate component='1' foundation='1' generation='0' id='el0747fg11' ip='10.0.1.1' network='1' port='8998' priority='2130706431' protocol='udp' type='host'/></transport></content><content creator='initiator' name='unknown'><transport xmlns='urn:xmpp:jingle:transports:ice-udp:1'/></content></jingle>";
+
+    #[test]
+    fn test_call() {
+        let romeo = Jid::from_str("romeo@montague.lit/orchard").unwrap();
+        let sid = SessionId(String::from("a73sjjvkla37jfea"));
+        let mut calls = Calls::default();
+        match &calls.handle(romeo.clone(), jingle(INITIATE)).unwrap()[..] {
+            [Event::JingleCallSessionInitiate {
+                from,
+                sid: call,
+                contents,
+            }] => {
+                assert_eq!((from, call), (&romeo, &sid));
+                assert_eq!(contents.len(), 1);
+            }
+            events => panic!("Unexpected events {:?}", events),
+        }
+        // The same session can’t be initiated twice.
+        let refusal = calls.handle(romeo.clone(), jingle(INITIATE)).unwrap_err();
+        assert_eq!(refusal, Refusal::Conflict);
+
+        // Only candidates for the contents of the call are reported.
+        match &calls.handle(romeo.clone(), jingle(CANDIDATE)).unwrap()[..] {
+            [Event::JingleCallIceCandidate {
+                content, transport, ..
+            }] => {
+                assert_eq!(content, &ContentId(String::from("voice")));
+                assert_eq!(transport.candidates.len(), 1);
+            }
+            events => panic!("Unexpected events {:?}", events),
+        }
+
+        let iq = calls
+            .transport_info(
+                &romeo,
+                &sid,
+                ContentId(String::from("voice")),
+                IceUdpTransport::new(),
+            )
+            .unwrap();
+        assert_eq!(iq.to, Some(romeo.clone()));
+        match iq.payload {
+            IqType::Set(payload) => {
+                let jingle = Jingle::try_from(payload).unwrap();
+                assert_eq!(jingle.action, Action::TransportInfo);
+                assert_eq!(jingle.contents[0].creator, Creator::Initiator);
+            }
+            payload => panic!("Unexpected payload {:?}", payload),
+        }
+        assert!(calls
+            .transport_info(
+                &romeo,
+                &sid,
+                ContentId(String::from("video")),
+                IceUdpTransport::new()
+            )
+            .is_none());
+        assert!(calls.accept(&romeo, &sid, None, Vec::new()).is_some());
+
+        // Hanging up forgets the call.
+        let iq = calls.terminate(&romeo, &sid, Reason::Success).unwrap();
+        let expected: Element = "<iq xmlns='jabber:client' type='set' id='jingle-terminate' to='romeo@montague.lit/orchard'><jingle xmlns='urn:xmpp:jingle:1' action='session-terminate' sid='a73sjjvkla37jfea'><reason><success/></reason></jingle></iq>".parse().unwrap();
+        assert_eq!(Element::from(iq), expected);
+        assert!(calls.terminate(&romeo, &sid, Reason::Success).is_none());
+        assert!(calls.accept(&romeo, &sid, None, Vec::new()).is_none());
+    }
+
+    #[test]
+    fn test_terminated_by_peer() {
+        let romeo = Jid::from_str("romeo@montague.lit/orchard").unwrap();
+        let mut calls = Calls::default();
+        calls.handle(romeo.clone(), jingle(INITIATE)).unwrap();
+
+        // Someone else can’t act on the call.
+        let terminate = "<jingle xmlns='urn:xmpp:jingle:1' action='session-terminate' sid='a73sjjvkla37jfea'><reason><decline/></reason></jingle>";
+        let tybalt = Jid::from_str("tybalt@capulet.lit/street").unwrap();
+        let refusal = calls.handle(tybalt, jingle(terminate)).unwrap_err();
+        let error = refusal.into_error(&messages::English);
+        assert_eq!(error.defined_condition, DefinedCondition::ItemNotFound);
+        assert!(error
+            .other
+            .unwrap()
+            .is("unknown-session", ns::JINGLE_ERRORS));
+
+        match &calls.handle(romeo.clone(), jingle(terminate)).unwrap()[..] {
+            [Event::JingleCallEnding {
+                reason: Some(reason),
+                ..
+            }] => assert_eq!(reason.reason, Reason::Decline),
+            events => panic!("Unexpected events {:?}", events),
+        }
+        let refusal = calls.handle(romeo, jingle(CANDIDATE)).unwrap_err();
+        assert_eq!(refusal, Refusal::UnknownSession);
+    }
+
+    #[test]
+    fn test_same_sid_from_other_peer() {
+        let romeo = Jid::from_str("romeo@montague.lit/orchard").unwrap();
+        let tybalt = Jid::from_str("tybalt@capulet.lit/street").unwrap();
+        let sid = SessionId(String::from("a73sjjvkla37jfea"));
+        let mut calls = Calls::default();
+        calls.handle(romeo.clone(), jingle(INITIATE)).unwrap();
+        // Session ids are only unique to their initiator, this is another call.
+        calls.handle(tybalt.clone(), jingle(INITIATE)).unwrap();
+        assert_eq!(calls.calls.len(), 2);
+
+        let iq = calls.terminate(&tybalt, &sid, Reason::Busy).unwrap();
+        assert_eq!(iq.to, Some(tybalt));
+        assert!(calls.accept(&romeo, &sid, None, Vec::new()).is_some());
+    }
+
+    #[test]
+    fn test_pending_calls_cap() {
+        let romeo =
+            |resource: usize| Jid::from_str(&format!("romeo@montague.lit/{}", resource)).unwrap();
+        let sid = SessionId(String::from("a73sjjvkla37jfea"));
+        let mut calls = Calls::default();
+        for i in 0..MAX_PENDING_PER_PEER {
+            calls.handle(romeo(i), jingle(INITIATE)).unwrap();
+        }
+        // Other resources of the same contact count as the same peer.
+        let refusal = calls
+            .handle(romeo(MAX_PENDING_PER_PEER), jingle(INITIATE))
+            .unwrap_err();
+        assert_eq!(refusal, Refusal::TooManyCalls);
+        let error = refusal.into_error(&messages::English);
+        assert_eq!(
+            error.defined_condition,
+            DefinedCondition::ResourceConstraint
+        );
+        let juliet = Jid::from_str("juliet@capulet.lit/balcony").unwrap();
+        calls.handle(juliet, jingle(INITIATE)).unwrap();
+
+        // Answered calls aren’t pending any more.
+        calls.accept(&romeo(0), &sid, None, Vec::new()).unwrap();
+        calls
+            .handle(romeo(MAX_PENDING_PER_PEER), jingle(INITIATE))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_not_rtp() {
+        let file_transfer = "<jingle xmlns='urn:xmpp:jingle:1' action='session-initiate' sid='851ba2'><content creator='initiator' name='a-file-offer'><description xmlns='urn:xmpp:jingle:apps:file-transfer:5'/></content></jingle>";
+        let mut calls = Calls::default();
+        let romeo = Jid::from_str("romeo@montague.lit/orchard").unwrap();
+        let refusal = calls.handle(romeo, jingle(file_transfer)).unwrap_err();
+        assert_eq!(refusal, Refusal::NotRtp);
+        assert!(calls.calls.is_empty());
+    }
+}
@@ -0,0 +1,101 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Cache of the features advertised with entity capabilities (XEP-0115).
+//!
+//! Entries are keyed by their verification string, and only ever added once the disco#info we
+//! received hashes back to it, so that a contact can’t poison the entry of another client.
+
+use std::collections::HashMap;
+use tokio::sync::broadcast;
+use xmpp_parsers::{
+    caps::{compute_disco, hash_caps, Caps},
+    disco::DiscoInfoResult,
+    hashes::Hash,
+};
+
+/// New entry of the cache, see [`crate::Agent::caps_updates`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapsUpdate {
+    /// The verification string of the entity capabilities.
+    pub hash: Hash,
+    /// The features it stands for.
+    pub features: Vec<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct CapsCache {
+    /// Features of every verified hash, keyed by its base64 value.
+    features: HashMap<String, Vec<String>>,
+    /// Hashes we asked the disco#info of, keyed by the node we asked it for.
+    pending: HashMap<String, Hash>,
+    updates: broadcast::Sender<CapsUpdate>,
+}
+
+impl Default for CapsCache {
+    fn default() -> Self {
+        CapsCache {
+            features: HashMap::new(),
+            pending: HashMap::new(),
+            updates: broadcast::channel(crate::UPDATES_CAPACITY).0,
+        }
+    }
+}
+
+impl CapsCache {
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<CapsUpdate> {
+        self.updates.subscribe()
+    }
+
+    #[cfg(feature = "test-internals")]
+    pub(crate) fn updates(&self) -> &broadcast::Sender<CapsUpdate> {
+        &self.updates
+    }
+
+    /// The features advertised by `caps`, or `None` if we don’t know them yet.
+    pub(crate) fn features(&self, caps: &Caps) -> Option<&[String]> {
+        self.features.get(&caps.hash.to_base64()).map(Vec::as_slice)
+    }
+
+    /// Remember that we are about to ask for the disco#info behind `caps`, returns the node to
+    /// ask it for, or `None` if it is already known or has already been asked for.
+    pub(crate) fn request(&mut self, caps: &Caps) -> Option<String> {
+        let ver = caps.hash.to_base64();
+        let node = format!("{}#{}", caps.node, ver);
+        if self.features.contains_key(&ver) || self.pending.contains_key(&node) {
+            return None;
+        }
+        self.pending.insert(node.clone(), caps.hash.clone());
+        Some(node)
+    }
+
+    /// Handle a disco#info result, returns whether it answered one of our requests and matched
+    /// its hash.
+    pub(crate) fn insert(&mut self, disco: &DiscoInfoResult) -> bool {
+        let hash = match disco
+            .node
+            .as_ref()
+            .and_then(|node| self.pending.remove(node))
+        {
+            Some(hash) => hash,
+            None => return false,
+        };
+        match hash_caps(&compute_disco(disco), hash.algo.clone()) {
+            Ok(computed) if computed == hash => {
+                let features: Vec<String> = disco
+                    .features
+                    .iter()
+                    .map(|feature| feature.var.clone())
+                    .collect();
+                self.features.insert(hash.to_base64(), features.clone());
+                // Nobody listening isn’t an error.
+                let _ = self.updates.send(CapsUpdate { hash, features });
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The one-to-one conversations which recently saw a message, to tell their peers we are gone
+//! (XEP-0085) when going offline.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use xmpp_parsers::{BareJid, Jid};
+
+/// How long after the last message a conversation is still considered open.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How many conversations to remember, the oldest ones being forgotten first.
+const MAX_SESSIONS: usize = 64;
+
+#[derive(Debug, Default)]
+pub(crate) struct ChatSessions {
+    /// The address last used with each contact, and when.
+    sessions: HashMap<BareJid, (Jid, Instant)>,
+}
+
+impl ChatSessions {
+    /// A chat message got exchanged with `peer`.
+    pub(crate) fn touch(&mut self, peer: Jid, now: Instant) {
+        let bare = BareJid::from(peer.clone());
+        if !self.sessions.contains_key(&bare) && self.sessions.len() >= MAX_SESSIONS {
+            let oldest = self
+                .sessions
+                .iter()
+                .min_by_key(|(_, (_, last))| *last)
+                .map(|(jid, _)| jid.clone());
+            if let Some(oldest) = oldest {
+                self.sessions.remove(&oldest);
+            }
+        }
+        self.sessions.insert(bare, (peer, now));
+    }
+
+    /// Forget every conversation, returning the peers of those still open at `now`.
+    pub(crate) fn close_all(&mut self, now: Instant) -> Vec<Jid> {
+        self.sessions
+            .drain()
+            .filter(|(_, (_, last))| now.saturating_duration_since(*last) < SESSION_TIMEOUT)
+            .map(|(_, (peer, _))| peer)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_sessions() {
+        let start = Instant::now();
+        let jid = |jid: &str| Jid::from_str(jid).unwrap();
+        let mut sessions = ChatSessions::default();
+        sessions.touch(jid("old@bar"), start);
+        sessions.touch(jid("contact@bar"), start);
+        // Only the latest address of a contact is kept.
+        sessions.touch(
+            jid("contact@bar/phone"),
+            start + Duration::from_secs(5 * 60),
+        );
+
+        let peers = sessions.close_all(start + Duration::from_secs(11 * 60));
+        assert_eq!(peers, [jid("contact@bar/phone")]);
+        assert!(sessions.close_all(start).is_empty());
+    }
+
+    #[test]
+    fn test_oldest_forgotten() {
+        let start = Instant::now();
+        let mut sessions = ChatSessions::default();
+        for i in 0..=MAX_SESSIONS {
+            let peer = Jid::from_str(&format!("contact{}@bar", i)).unwrap();
+            sessions.touch(peer, start + Duration::from_secs(i as u64));
+        }
+        let peers = sessions.close_all(start);
+        assert_eq!(peers.len(), MAX_SESSIONS);
+        assert!(!peers.contains(&Jid::from_str("contact0@bar").unwrap()));
+    }
+}
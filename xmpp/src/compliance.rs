@@ -0,0 +1,112 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Which protocols the features we advertise belong to, see [`crate::Agent::compliance_report`].
+
+use xmpp_parsers::{disco::Feature, ns::Support, supported_protocols};
+
+/// A protocol xmpp-parsers implements, and whether we advertise it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtocolReport {
+    /// The specification, such as `XEP-0030: Service Discovery`.
+    pub spec: &'static str,
+    /// The number of the XEP, `None` for an RFC.
+    pub xep: Option<u16>,
+    /// How much of it xmpp-parsers implements.
+    pub support: Support,
+    /// The features of our disco#info belonging to it, empty if we don’t advertise it.
+    pub advertised: Vec<String>,
+}
+
+/// What the Agent implements, as a list of protocols, see [`crate::Agent::compliance_report`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ComplianceReport {
+    /// Every protocol xmpp-parsers implements, in the order of
+    /// [`xmpp_parsers::supported_protocols`].
+    pub protocols: Vec<ProtocolReport>,
+    /// The features of our disco#info no protocol of xmpp-parsers defines, such as those the
+    /// application added with [`crate::Agent::advertise_feature`].
+    pub unknown: Vec<String>,
+}
+
+impl ComplianceReport {
+    /// The protocols we advertise.
+    pub fn advertised(&self) -> impl Iterator<Item = &ProtocolReport> {
+        self.protocols
+            .iter()
+            .filter(|protocol| !protocol.advertised.is_empty())
+    }
+}
+
+/// Cross-references `features` with the protocols of xmpp-parsers.
+pub(crate) fn report(features: &[Feature]) -> ComplianceReport {
+    let mut report = ComplianceReport::default();
+    for info in supported_protocols() {
+        if !report
+            .protocols
+            .iter()
+            .any(|protocol| protocol.spec == info.spec())
+        {
+            report.protocols.push(ProtocolReport {
+                spec: info.spec(),
+                xep: info.xep(),
+                support: info.support,
+                advertised: Vec::new(),
+            });
+        }
+    }
+    for feature in features {
+        // Interest in PEP notifications (XEP-0163) is advertised with this suffix.
+        let ns = feature.var.trim_end_matches("+notify");
+        let spec = supported_protocols()
+            .iter()
+            .find(|info| info.ns == ns)
+            .map(|info| info.spec());
+        let protocol = report
+            .protocols
+            .iter_mut()
+            .find(|protocol| Some(protocol.spec) == spec);
+        match protocol {
+            Some(protocol) => protocol.advertised.push(feature.var.clone()),
+            None => report.unknown.push(feature.var.clone()),
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xmpp_parsers::ns;
+
+    #[test]
+    fn test_report() {
+        let features = [
+            Feature::new(ns::DISCO_INFO),
+            Feature::new(format!("{}+notify", ns::GEOLOC)),
+            Feature::new(ns::JINGLE_RTP),
+            Feature::new(ns::JINGLE_RTP_AUDIO),
+            Feature::new("urn:example:app"),
+        ];
+        let report = report(&features);
+        let advertised: Vec<_> = report
+            .advertised()
+            .map(|protocol| (protocol.xep, protocol.advertised.len()))
+            .collect();
+        assert_eq!(advertised, [(Some(30), 1), (Some(80), 1), (Some(167), 2)]);
+        assert_eq!(report.unknown, ["urn:example:app"]);
+
+        // Every specification is there once, advertised or not.
+        let pubsub: Vec<_> = report
+            .protocols
+            .iter()
+            .filter(|protocol| protocol.spec == "XEP-0060: Publish-Subscribe")
+            .collect();
+        assert_eq!(pubsub.len(), 1);
+        assert_eq!(pubsub[0].support, Support::Partial);
+        assert!(pubsub[0].advertised.is_empty());
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Local bookkeeping of messages which have to be deleted after some time.
+//!
+//! This only lives in memory: every pending deadline is lost when the process exits, so an
+//! application which needs to enforce retention across restarts has to store the deadlines of
+//! the messages it persisted itself.
+
+use crate::Event;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use xmpp_parsers::Jid;
+
+/// The current time as tokio sees it, so that expiries follow its clock when a test pauses it.
+pub(crate) fn now() -> Instant {
+    tokio::time::Instant::now().into_std()
+}
+
+/// Pending expiries, ordered by deadline.
+#[derive(Debug, Default)]
+pub(crate) struct ExpiryTimers {
+    deadlines: BTreeMap<Instant, Vec<(String, Jid)>>,
+}
+
+impl ExpiryTimers {
+    /// Schedule the message `id` from `peer` to expire `after` the given `now`.
+    ///
+    /// Scheduling an id which is already pending for this peer replaces its previous deadline,
+    /// ids being only unique per sender.
+    pub(crate) fn schedule(&mut self, now: Instant, after: Duration, id: String, peer: Jid) {
+        self.cancel(&id, &peer);
+        self.deadlines
+            .entry(now + after)
+            .or_default()
+            .push((id, peer));
+    }
+
+    /// Forget about the message `id` from `peer`, returns whether it was pending.
+    pub(crate) fn cancel(&mut self, id: &str, peer: &Jid) -> bool {
+        let mut found = None;
+        for (deadline, entries) in self.deadlines.iter_mut() {
+            if let Some(index) = entries
+                .iter()
+                .position(|(other, other_peer)| other == id && other_peer == peer)
+            {
+                entries.remove(index);
+                found = Some((*deadline, entries.is_empty()));
+                break;
+            }
+        }
+        match found {
+            Some((deadline, true)) => {
+                self.deadlines.remove(&deadline);
+                true
+            }
+            Some((_, false)) => true,
+            None => false,
+        }
+    }
+
+    /// The earliest deadline still pending, if any.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.keys().next().copied()
+    }
+
+    /// Every deadline still pending, in order, along with how many messages expire then.
+    #[cfg(feature = "test-internals")]
+    pub(crate) fn deadlines(&self) -> impl Iterator<Item = (Instant, usize)> + '_ {
+        self.deadlines
+            .iter()
+            .map(|(deadline, entries)| (*deadline, entries.len()))
+    }
+
+    /// Remove every message whose deadline is at or before `now`, in deadline order.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<Event> {
+        let mut events = vec![];
+        while let Some(deadline) = self.next_deadline() {
+            if deadline > now {
+                break;
+            }
+            for (id, peer) in self.deadlines.remove(&deadline).unwrap() {
+                events.push(Event::MessageExpired { id, peer });
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ExpiryTimers;
+    use crate::Event;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+    use xmpp_parsers::Jid;
+
+    fn expired_ids(events: Vec<Event>) -> Vec<String> {
+        events
+            .into_iter()
+            .map(|event| match event {
+                Event::MessageExpired { id, .. } => id,
+                event => panic!("Unexpected event {:?}", event),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_expire_in_order() {
+        let start = Instant::now();
+        let peer = Jid::from_str("contact@bar/res").unwrap();
+        let mut timers = ExpiryTimers::default();
+        timers.schedule(start, Duration::from_secs(60), "b".into(), peer.clone());
+        timers.schedule(start, Duration::from_secs(10), "a".into(), peer.clone());
+        timers.schedule(start, Duration::from_secs(60), "c".into(), peer.clone());
+        assert_eq!(
+            timers.next_deadline(),
+            Some(start + Duration::from_secs(10))
+        );
+
+        assert!(timers.expire(start + Duration::from_secs(9)).is_empty());
+        assert_eq!(
+            expired_ids(timers.expire(start + Duration::from_secs(10))),
+            ["a"]
+        );
+        assert_eq!(
+            expired_ids(timers.expire(start + Duration::from_secs(3600))),
+            ["b", "c"]
+        );
+        assert_eq!(timers.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_reschedule_and_cancel() {
+        let start = Instant::now();
+        let peer = Jid::from_str("contact@bar").unwrap();
+        let mut timers = ExpiryTimers::default();
+        timers.schedule(start, Duration::from_secs(10), "a".into(), peer.clone());
+        timers.schedule(start, Duration::from_secs(20), "a".into(), peer.clone());
+        timers.schedule(start, Duration::from_secs(20), "b".into(), peer.clone());
+        assert_eq!(
+            timers.next_deadline(),
+            Some(start + Duration::from_secs(20))
+        );
+
+        assert!(timers.cancel("b", &peer));
+        assert!(!timers.cancel("b", &peer));
+        match timers.expire(start + Duration::from_secs(20)).pop() {
+            Some(Event::MessageExpired { id, peer: expired }) => {
+                assert_eq!(id, "a");
+                assert_eq!(expired, peer);
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+        assert_eq!(timers.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_same_id_from_other_peer() {
+        let start = Instant::now();
+        let first = Jid::from_str("contact@bar").unwrap();
+        let second = Jid::from_str("other@bar").unwrap();
+        let mut timers = ExpiryTimers::default();
+        timers.schedule(start, Duration::from_secs(10), "1".into(), first.clone());
+        timers.schedule(start, Duration::from_secs(20), "1".into(), second.clone());
+        assert!(!timers.cancel("1", &Jid::from_str("third@bar").unwrap()));
+
+        let peers: Vec<Jid> = timers
+            .expire(start + Duration::from_secs(20))
+            .into_iter()
+            .map(|event| match event {
+                Event::MessageExpired { id, peer } => {
+                    assert_eq!(id, "1");
+                    peer
+                }
+                event => panic!("Unexpected event {:?}", event),
+            })
+            .collect();
+        assert_eq!(peers, [first, second]);
+    }
+}
@@ -0,0 +1,380 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The optional features of the Agent, enabled on the [`crate::ClientBuilder`] along with their
+//! configuration.
+
+#[cfg(feature = "avatars")]
+use crate::HttpFetcher;
+#[cfg(feature = "avatars")]
+use std::future::Future;
+#[cfg(feature = "avatars")]
+use std::io;
+#[cfg(feature = "avatars")]
+use std::path::PathBuf;
+#[cfg(feature = "avatars")]
+use std::rc::Rc;
+use xmpp_parsers::{disco::Feature, muc::muc::History, ns};
+
+/// The features the Agent can be built with.
+#[deprecated(note = "use the with_* methods of ClientBuilder, which take a configuration")]
+#[derive(Debug, PartialEq)]
+pub enum ClientFeature {
+    /// Emit [`crate::Event::AttentionRequested`] when a contact tries to get our attention
+    /// (XEP-0224).
+    Attention,
+    #[cfg(feature = "avatars")]
+    Avatars,
+    ContactList,
+    /// Schedule a [`crate::Event::MessageExpired`] for every received message carrying an
+    /// ephemeral hint (XEP-0466).
+    EphemeralMessages,
+    /// Handle the calls contacts start with us (XEP-0166, XEP-0167), see
+    /// [`crate::ClientBuilder::with_jingle`].
+    Jingle,
+    JoinRooms,
+}
+
+/// How avatars (XEP-0084) get retrieved, see [`crate::ClientBuilder::with_avatars`].
+#[cfg(feature = "avatars")]
+#[derive(Clone, Default)]
+pub struct AvatarConfig {
+    /// Save the avatars in this directory rather than in one of the temporary directory of the
+    /// system private to the current user, the storage only keeping track of them.  It gets
+    /// refused if it is a symlink, or if someone else owns it or everyone can write in it.
+    pub cache_dir: Option<PathBuf>,
+    /// Download the biggest variant of an avatar whose width and height fit in this many
+    /// pixels, or the smallest one if none does.  By default, the one stored in PEP gets
+    /// downloaded.
+    pub max_dimension: Option<u16>,
+    /// Ignore the variants bigger than this many bytes.
+    pub max_bytes: Option<u32>,
+    /// Downloads the variants only available over HTTP, which are otherwise ignored, see
+    /// [`AvatarConfig::with_http_fetcher`].
+    pub http_fetcher: Option<HttpFetcher>,
+}
+
+#[cfg(feature = "avatars")]
+impl AvatarConfig {
+    /// Use `fetcher` to download the variants only available over HTTP.
+    ///
+    /// The downloads run in the background, and get cancelled by [`crate::Agent::disconnect`].
+    pub fn with_http_fetcher<F, Fut>(mut self, fetcher: F) -> Self
+    where
+        F: Fn(String) -> Fut + 'static,
+        Fut: Future<Output = io::Result<Vec<u8>>> + 'static,
+    {
+        self.http_fetcher = Some(Rc::new(move |url| Box::pin(fetcher(url))));
+        self
+    }
+}
+
+/// How rooms (XEP-0045) get joined, see [`crate::ClientBuilder::with_muc`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct MucConfig {
+    /// The nick used when joining a room, if neither [`crate::Agent::join_room`] nor its
+    /// bookmark give one.
+    pub default_nick: String,
+    /// How much history to ask for when joining a room, the room deciding if `None`.
+    pub history: Option<History>,
+    /// Join the rooms bookmarked (XEP-0402) with autojoin, and follow the changes of the
+    /// bookmarks.
+    pub autojoin_bookmarks: bool,
+    /// Drop the groupchat messages and subject changes coming from rooms we aren’t in, instead
+    /// of emitting them with [`crate::MessageInfo::unjoined_room`] set.
+    pub drop_unjoined_messages: bool,
+    /// How to pick another nick when the one a room got joined with is already taken.
+    pub nick_conflict: NickConflict,
+    /// How many other nicks to try before giving up on joining a room.
+    pub max_nick_retries: u8,
+}
+
+impl Default for MucConfig {
+    fn default() -> Self {
+        MucConfig {
+            default_nick: String::from("xmpp-rs"),
+            history: None,
+            autojoin_bookmarks: true,
+            drop_unjoined_messages: false,
+            nick_conflict: NickConflict::AppendSuffix(String::from("_")),
+            max_nick_retries: 3,
+        }
+    }
+}
+
+/// What to do when the nick a room got joined with is already taken, see
+/// [`MucConfig::nick_conflict`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum NickConflict {
+    /// Give up right away, emitting [`crate::Event::PresenceError`] with the conflict.
+    Fail,
+    /// Append this suffix once more on each retry: “bot_”, then “bot__”…
+    AppendSuffix(String),
+    /// Append the number of the attempt: “bot2”, then “bot3”…
+    AppendNumber,
+}
+
+impl NickConflict {
+    /// The nick to use on the `retry`th retry, the first one being 1, `None` meaning to give
+    /// up.
+    pub(crate) fn nick(&self, nick: &str, retry: u8) -> Option<String> {
+        match self {
+            NickConflict::Fail => None,
+            NickConflict::AppendSuffix(suffix) => {
+                Some(format!("{}{}", nick, suffix.repeat(usize::from(retry))))
+            }
+            NickConflict::AppendNumber => Some(format!("{}{}", nick, u16::from(retry) + 1)),
+        }
+    }
+}
+
+/// Who may see the location we publish (XEP-0080), see [`crate::ClientBuilder::with_locations`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LocationAccess {
+    /// Whoever is subscribed to our presence, the usual access model of PEP.
+    #[default]
+    Presence,
+    /// Only ourselves, and whoever we add to the whitelist of the node.
+    Whitelist,
+}
+
+impl LocationAccess {
+    /// The value of the pubsub#access_model field.
+    pub(crate) fn access_model(self) -> &'static str {
+        match self {
+            LocationAccess::Presence => "presence",
+            LocationAccess::Whitelist => "whitelist",
+        }
+    }
+}
+
+/// Everything enabled on the builder, from which the Agent and its disco#info are derived.
+#[derive(Clone)]
+pub(crate) struct Features {
+    #[cfg(feature = "avatars")]
+    pub(crate) avatars: Option<AvatarConfig>,
+    /// Rooms can be joined by hand even without [`crate::ClientBuilder::with_muc`], so this
+    /// is always there.
+    pub(crate) muc: MucConfig,
+    pub(crate) contact_list: bool,
+    pub(crate) ephemeral_messages: bool,
+    pub(crate) attention: bool,
+    pub(crate) jingle: bool,
+    pub(crate) locations: Option<LocationAccess>,
+    /// Namespaces of the payloads the application handles, whose fallback bodies get removed.
+    pub(crate) understood_payloads: Vec<String>,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            #[cfg(feature = "avatars")]
+            avatars: None,
+            muc: MucConfig {
+                autojoin_bookmarks: false,
+                ..MucConfig::default()
+            },
+            contact_list: false,
+            ephemeral_messages: false,
+            attention: false,
+            jingle: false,
+            locations: None,
+            understood_payloads: Vec::new(),
+        }
+    }
+}
+
+impl Features {
+    /// The features we advertise in disco#info, and thus in our caps.
+    pub(crate) fn disco_features(&self) -> Vec<Feature> {
+        // Invitations to rooms always get reported as events.
+        let mut features = vec![Feature::new(ns::DISCO_INFO), Feature::new(ns::DIRECT_MUC)];
+        #[cfg(feature = "avatars")]
+        {
+            if self.avatars.is_some() {
+                features.push(Feature::new(format!("{}+notify", ns::AVATAR_METADATA)));
+            }
+        }
+        if self.muc.autojoin_bookmarks {
+            features.push(Feature::new(format!("{}+notify", ns::BOOKMARKS2)));
+        }
+        if self.ephemeral_messages {
+            features.push(Feature::new(ns::EPHEMERAL));
+        }
+        if self.attention {
+            features.push(Feature::new(ns::ATTENTION));
+        }
+        if self.locations.is_some() {
+            features.push(Feature::new(format!("{}+notify", ns::GEOLOC)));
+        }
+        if self.jingle {
+            features.extend(
+                [
+                    ns::JINGLE,
+                    ns::JINGLE_RTP,
+                    ns::JINGLE_RTP_AUDIO,
+                    ns::JINGLE_RTP_VIDEO,
+                    ns::JINGLE_ICE_UDP,
+                    ns::JINGLE_DTLS,
+                ]
+                .iter()
+                .map(|feature| Feature::new(*feature)),
+            );
+        }
+        features
+    }
+
+    /// Check that the configurations make sense, alone and together, explaining why otherwise.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.muc.default_nick.is_empty() {
+            return Err(String::from(
+                "The default nick of MucConfig can’t be empty.",
+            ));
+        }
+        if self.muc.nick_conflict == NickConflict::AppendSuffix(String::new()) {
+            return Err(String::from("The nick suffix of MucConfig can’t be empty."));
+        }
+        #[cfg(feature = "avatars")]
+        {
+            if let Some(avatars) = &self.avatars {
+                if avatars.max_dimension == Some(0) || avatars.max_bytes == Some(0) {
+                    return Err(String::from(
+                        "The limits of AvatarConfig must be at least one.",
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientBuilder, Error, MemoryStorage};
+    use tokio_xmpp::AsyncClient as TokioXmppClient;
+    use xmpp_parsers::caps::compute_disco;
+
+    fn build(builder: ClientBuilder) -> crate::Agent {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        builder.build_impl(client).unwrap()
+    }
+
+    fn config_error(builder: ClientBuilder) -> String {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        match builder.build_impl(client) {
+            Err(Error::Config(message)) => message,
+            Err(err) => panic!("Unexpected error {}", err),
+            Ok(_) => panic!("Invalid configuration accepted"),
+        }
+    }
+
+    #[cfg(feature = "avatars")]
+    #[test]
+    #[allow(deprecated)]
+    fn test_same_disco() {
+        let old = build(
+            ClientBuilder::new("foo@bar", "meh")
+                .set_default_nick("bot")
+                .enable_feature(ClientFeature::Avatars)
+                .enable_feature(ClientFeature::ContactList)
+                .enable_feature(ClientFeature::JoinRooms)
+                .enable_feature(ClientFeature::EphemeralMessages)
+                .enable_feature(ClientFeature::Attention)
+                .enable_feature(ClientFeature::Jingle),
+        );
+        let new = build(
+            ClientBuilder::new("foo@bar", "meh")
+                .with_avatars(AvatarConfig::default())
+                .with_contact_list()
+                .with_muc(MucConfig {
+                    default_nick: String::from("bot"),
+                    ..MucConfig::default()
+                })
+                .with_ephemeral_messages()
+                .with_attention()
+                .with_jingle(),
+        );
+        let disco = |agent: &crate::Agent| compute_disco(&agent.disco);
+        assert_eq!(disco(&old), disco(&new));
+        assert!(new
+            .disco
+            .features
+            .contains(&Feature::new(ns::JINGLE_ICE_UDP)));
+        assert_eq!(*old.default_nick.borrow(), *new.default_nick.borrow());
+
+        // Only a nick given the old way doesn’t autojoin bookmarks.
+        let nick_only = build(ClientBuilder::new("foo@bar", "meh").set_default_nick("bot"));
+        let manual_muc = build(ClientBuilder::new("foo@bar", "meh").with_muc(MucConfig {
+            default_nick: String::from("bot"),
+            autojoin_bookmarks: false,
+            ..MucConfig::default()
+        }));
+        assert_eq!(disco(&nick_only), disco(&manual_muc));
+        assert_eq!(
+            nick_only.disco.features,
+            [Feature::new(ns::DISCO_INFO), Feature::new(ns::DIRECT_MUC)]
+        );
+    }
+
+    #[test]
+    fn test_invalid_configs() {
+        let empty_nick = ClientBuilder::new("foo@bar", "meh").with_muc(MucConfig {
+            default_nick: String::new(),
+            ..MucConfig::default()
+        });
+        assert_eq!(
+            config_error(empty_nick),
+            "The default nick of MucConfig can’t be empty."
+        );
+
+        let empty_suffix = ClientBuilder::new("foo@bar", "meh").with_muc(MucConfig {
+            nick_conflict: NickConflict::AppendSuffix(String::new()),
+            ..MucConfig::default()
+        });
+        assert_eq!(
+            config_error(empty_suffix),
+            "The nick suffix of MucConfig can’t be empty."
+        );
+
+        #[cfg(feature = "avatars")]
+        {
+            let no_bytes = ClientBuilder::new("foo@bar", "meh").with_avatars(AvatarConfig {
+                max_bytes: Some(0),
+                ..AvatarConfig::default()
+            });
+            assert_eq!(
+                config_error(no_bytes),
+                "The limits of AvatarConfig must be at least one."
+            );
+
+            // The storage only indexes them, so both can be chosen.
+            let cache_dir = ClientBuilder::new("foo@bar", "meh")
+                .set_storage(MemoryStorage::default())
+                .with_avatars(AvatarConfig {
+                    cache_dir: Some(PathBuf::from("avatars")),
+                    ..AvatarConfig::default()
+                });
+            build(cache_dir);
+        }
+    }
+
+    #[test]
+    fn test_nick_conflict() {
+        let suffix = NickConflict::AppendSuffix(String::from("_"));
+        assert_eq!(suffix.nick("bot", 1).as_deref(), Some("bot_"));
+        assert_eq!(suffix.nick("bot", 3).as_deref(), Some("bot___"));
+        assert_eq!(
+            NickConflict::AppendNumber.nick("bot", 1).as_deref(),
+            Some("bot2")
+        );
+        assert_eq!(
+            NickConflict::AppendNumber.nick("bot", 255).as_deref(),
+            Some("bot256")
+        );
+        assert_eq!(NickConflict::Fail.nick("bot", 1), None);
+    }
+}
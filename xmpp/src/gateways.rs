@@ -0,0 +1,505 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Gateways to legacy services (XEP-0100), see [`Agent::gateways`].
+
+use crate::{Agent, Error, Step};
+use std::collections::{HashSet, VecDeque};
+use std::convert::TryFrom;
+use xmpp_parsers::{
+    data_forms::DataForm,
+    disco::{DiscoInfoQuery, DiscoInfoResult, DiscoItemsQuery, DiscoItemsResult},
+    gateway::{Prompt, Translated},
+    ibr::Query as RegisterQuery,
+    iq::Iq,
+    presence::Type as PresenceType,
+    roster::{Item as RosterItem, Roster, Subscription},
+    BareJid, Element, Jid,
+};
+
+/// A gateway to a legacy service our server hosts, see [`GatewayClient::discover_gateways`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct GatewayInfo {
+    /// Where to register with it.
+    pub jid: Jid,
+    /// The legacy service it connects to, the type of its gateway identity such as `irc` or
+    /// `sms`.
+    pub type_: String,
+    /// Its name, if it gave one.
+    pub name: Option<String>,
+}
+
+/// Registers with the gateways to legacy services (XEP-0100) of our server, and addresses their
+/// users, see [`Agent::gateways`].
+pub struct GatewayClient<'a> {
+    agent: &'a mut Agent,
+}
+
+impl<'a> GatewayClient<'a> {
+    pub(crate) fn new(agent: &'a mut Agent) -> GatewayClient<'a> {
+        GatewayClient { agent }
+    }
+
+    /// The gateways among the items of our server, those with a gateway identity.
+    pub async fn discover_gateways(&mut self) -> Result<Vec<GatewayInfo>, Error> {
+        let server = Jid::Bare(BareJid::domain(self.agent.own_jid.domain.clone()));
+        let (mut discovery, iq) = Discovery::new(server);
+        self.agent
+            .exchange(iq, |agent, elem| discovery.handle(agent, elem))
+            .await
+    }
+
+    /// The registration form of `gateway`, telling whether we are registered already.
+    pub async fn registration_form(&mut self, gateway: &Jid) -> Result<RegisterQuery, Error> {
+        let iq = Iq::from_get("gateway-form", empty_register_query()).with_to(gateway.clone());
+        self.agent
+            .query(iq, |agent, elem| form_response(agent, gateway, elem))
+            .await
+    }
+
+    /// Register with `gateway`, submitting `form` once filled with our credentials for the
+    /// legacy service.
+    ///
+    /// The gateway then asks to receive our presence, to log in to the legacy service whenever
+    /// we are online.  With [`crate::ClientBuilder::set_accept_gateway_subscriptions`], that
+    /// request gets approved, and we subscribe to the presence of the gateway in turn.
+    pub async fn register_with_gateway(
+        &mut self,
+        gateway: &Jid,
+        form: DataForm,
+    ) -> Result<(), Error> {
+        let query = RegisterQuery {
+            form: Some(form),
+            ..empty_register_query()
+        };
+        let iq = Iq::from_set("gateway-register", query).with_to(gateway.clone());
+        self.agent
+            .query(iq, |agent, elem| {
+                done_response(agent, gateway, "gateway-register", elem)
+            })
+            .await?;
+        self.agent
+            .gateways
+            .registered(BareJid::from(gateway.clone()));
+        Ok(())
+    }
+
+    /// Cancel our registration with `gateway`, then remove it from our roster along with the
+    /// contacts we had through it.
+    pub async fn unregister(&mut self, gateway: &Jid) -> Result<(), Error> {
+        let query = RegisterQuery {
+            remove: true,
+            ..empty_register_query()
+        };
+        let iq = Iq::from_set("gateway-unregister", query).with_to(gateway.clone());
+        self.agent
+            .query(iq, |agent, elem| {
+                done_response(agent, gateway, "gateway-unregister", elem)
+            })
+            .await?;
+        let gateway = BareJid::from(gateway.clone());
+        let removals = self
+            .agent
+            .gateways
+            .unregistered(&gateway, self.agent.roster.items());
+        for iq in removals {
+            self.agent.send_stanza(iq.into()).await?;
+        }
+        Ok(())
+    }
+
+    /// The JID of the user at `legacy_id` on the legacy service of `gateway`, such as a phone
+    /// number for an SMS gateway.
+    pub async fn translate_contact(
+        &mut self,
+        gateway: &Jid,
+        legacy_id: &str,
+    ) -> Result<Jid, Error> {
+        let iq = Iq::from_set("gateway-translate", Prompt::translate(legacy_id))
+            .with_to(gateway.clone());
+        self.agent
+            .query(iq, |agent, elem| translate_response(agent, gateway, elem))
+            .await
+    }
+}
+
+fn empty_register_query() -> RegisterQuery {
+    RegisterQuery {
+        fields: Default::default(),
+        registered: false,
+        remove: false,
+        form: None,
+    }
+}
+
+fn missing(what: &'static str) -> Error {
+    Error::Protocol(xmpp_parsers::Error::ParseError(what).into())
+}
+
+/// Whether `elem` answers our request for the registration form of `gateway`, and with what.
+fn form_response(
+    agent: &Agent,
+    gateway: &Jid,
+    elem: &Element,
+) -> Option<Result<RegisterQuery, Error>> {
+    Some(match agent.iq_result(elem, "gateway-form", gateway)? {
+        Ok(Some(payload)) => {
+            RegisterQuery::try_from(payload).map_err(|e| Error::Protocol(e.into()))
+        }
+        Ok(None) => Err(missing("Missing registration form.")),
+        Err(err) => Err(err),
+    })
+}
+
+/// Whether `elem` answers the request with this `id` sent to `gateway`, and whether it
+/// succeeded.
+fn done_response(
+    agent: &Agent,
+    gateway: &Jid,
+    id: &str,
+    elem: &Element,
+) -> Option<Result<(), Error>> {
+    Some(agent.iq_result(elem, id, gateway)?.map(|_| ()))
+}
+
+/// Whether `elem` answers our request to translate an address into a JID, and which.
+fn translate_response(agent: &Agent, gateway: &Jid, elem: &Element) -> Option<Result<Jid, Error>> {
+    Some(match agent.iq_result(elem, "gateway-translate", gateway)? {
+        Ok(Some(payload)) => Translated::try_from(payload)
+            .map(|translated| translated.jid)
+            .map_err(|e| Error::Protocol(e.into())),
+        Ok(None) => Err(missing("Missing translated JID.")),
+        Err(err) => Err(err),
+    })
+}
+
+/// The exchange behind [`GatewayClient::discover_gateways`]: the items of our server, then the
+/// identities of each of them in turn.
+pub(crate) struct Discovery {
+    server: Jid,
+    /// The items left to ask for their identities.
+    items: VecDeque<Jid>,
+    /// The item being asked, once the items are known.
+    current: Option<Jid>,
+    found: Vec<GatewayInfo>,
+}
+
+impl Discovery {
+    /// Discover the gateways of `server`, returning the first iq to send for it.
+    pub(crate) fn new(server: Jid) -> (Discovery, Iq) {
+        let iq =
+            Iq::from_get("gateway-items", DiscoItemsQuery { node: None }).with_to(server.clone());
+        let discovery = Discovery {
+            server,
+            items: VecDeque::new(),
+            current: None,
+            found: Vec::new(),
+        };
+        (discovery, iq)
+    }
+
+    /// Handle `elem` if it belongs to this exchange.
+    pub(crate) fn handle(
+        &mut self,
+        agent: &Agent,
+        elem: &Element,
+    ) -> Option<Step<Vec<GatewayInfo>>> {
+        match self.current.clone() {
+            None => {
+                let items = match agent.iq_result(elem, "gateway-items", &self.server)? {
+                    Ok(Some(payload)) => match DiscoItemsResult::try_from(payload) {
+                        Ok(result) => result.items,
+                        Err(e) => return Some(Step::Done(Err(Error::Protocol(e.into())))),
+                    },
+                    Ok(None) => return Some(Step::Done(Err(missing("Missing disco#items.")))),
+                    Err(err) => return Some(Step::Done(Err(err))),
+                };
+                for item in items {
+                    // Nodes are parts of an entity, gateways are entities of their own.
+                    if item.node.is_none() && !self.items.contains(&item.jid) {
+                        self.items.push_back(item.jid);
+                    }
+                }
+            }
+            Some(item) => {
+                // An item which can’t tell what it is isn’t usable as a gateway.
+                if let Ok(Some(payload)) = agent.iq_result(elem, "gateway-info", &item)? {
+                    if let Ok(info) = DiscoInfoResult::try_from(payload) {
+                        self.found.extend(
+                            info.identities
+                                .into_iter()
+                                .find(|identity| identity.category == "gateway")
+                                .map(|identity| GatewayInfo {
+                                    jid: item,
+                                    type_: identity.type_,
+                                    name: identity.name,
+                                }),
+                        );
+                    }
+                }
+            }
+        }
+        self.next()
+    }
+
+    /// Ask the next item for its identities, or end with the gateways found.
+    fn next(&mut self) -> Option<Step<Vec<GatewayInfo>>> {
+        match self.items.pop_front() {
+            Some(item) => {
+                let iq = Iq::from_get("gateway-info", DiscoInfoQuery { node: None })
+                    .with_to(item.clone());
+                self.current = Some(item);
+                Some(Step::Send(iq))
+            }
+            None => Some(Step::Done(Ok(std::mem::take(&mut self.found)))),
+        }
+    }
+}
+
+/// What we follow of the gateways we registered with.
+#[derive(Debug)]
+pub(crate) struct Gateways {
+    /// Approve the subscription requests of the gateways we registered with.
+    accept_subscriptions: bool,
+    /// The gateways we registered with, whose subscription request hasn’t come yet.
+    awaiting: HashSet<BareJid>,
+}
+
+impl Gateways {
+    pub(crate) fn new(accept_subscriptions: bool) -> Gateways {
+        Gateways {
+            accept_subscriptions,
+            awaiting: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn registered(&mut self, gateway: BareJid) {
+        if self.accept_subscriptions {
+            self.awaiting.insert(gateway);
+        }
+    }
+
+    /// The presences answering the subscription request of `from`, if it is a gateway we just
+    /// registered with: approving it, then asking for its presence in turn.
+    pub(crate) fn subscription_request(&mut self, from: &BareJid) -> Vec<PresenceType> {
+        if self.awaiting.remove(from) {
+            vec![PresenceType::Subscribed, PresenceType::Subscribe]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// The roster sets removing `gateway`, and the contacts of `roster` we had through it, one
+    /// item per set as RFC 6121 §2.1.5 requires.
+    pub(crate) fn unregistered(&mut self, gateway: &BareJid, roster: &[RosterItem]) -> Vec<Iq> {
+        self.awaiting.remove(gateway);
+        let mut jids = vec![gateway.clone()];
+        jids.extend(
+            roster
+                .iter()
+                .filter(|item| item.jid.domain == gateway.domain && item.jid.node.is_some())
+                .map(|item| item.jid.clone()),
+        );
+        jids.into_iter()
+            .enumerate()
+            .map(|(i, jid)| {
+                let removal = Roster {
+                    ver: None,
+                    items: vec![RosterItem {
+                        jid,
+                        name: None,
+                        subscription: Subscription::Remove,
+                        ask: Default::default(),
+                        groups: Vec::new(),
+                    }],
+                };
+                Iq::from_set(format!("gateway-roster-remove-{}", i), removal)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClientBuilder;
+    use tokio_xmpp::AsyncClient as TokioXmppClient;
+    use xmpp_parsers::roster::Ask;
+
+    fn agent(accept_subscriptions: bool) -> Agent {
+        let client = TokioXmppClient::new("juliet@capulet.lit", "meh").unwrap();
+        ClientBuilder::new("juliet@capulet.lit", "meh")
+            .set_accept_gateway_subscriptions(accept_subscriptions)
+            .build_impl(client)
+            .unwrap()
+    }
+
+    fn parse(xml: &str) -> Element {
+        xml.parse().unwrap()
+    }
+
+    fn jid(jid: &str) -> Jid {
+        jid.parse().unwrap()
+    }
+
+    fn contact(jid: &str) -> RosterItem {
+        RosterItem {
+            jid: jid.parse().unwrap(),
+            name: None,
+            subscription: Subscription::Both,
+            ask: Ask::None,
+            groups: Vec::new(),
+        }
+    }
+
+    /// Play `script` against `discovery`, the server sending each element and expecting the iq
+    /// `discovery` sends next if there is one, returning the gateways found.
+    fn play(
+        agent: &Agent,
+        discovery: &mut Discovery,
+        script: &[(&str, Option<&str>)],
+    ) -> Vec<GatewayInfo> {
+        for (received, expected) in script {
+            match (discovery.handle(agent, &parse(received)), expected) {
+                (Some(Step::Send(iq)), Some(expected)) => {
+                    assert_eq!(Element::from(iq), parse(expected))
+                }
+                (Some(Step::Consumed), None) | (None, None) => (),
+                (Some(Step::Done(found)), None) => return found.unwrap(),
+                (_, expected) => panic!("Unexpected step, expected {:?}", expected),
+            }
+        }
+        panic!("The script ended before the discovery.");
+    }
+
+    #[test]
+    fn test_discover() {
+        let agent = agent(false);
+        let (mut discovery, iq) = Discovery::new(jid("capulet.lit"));
+        assert_eq!(
+            Element::from(iq),
+            parse("<iq xmlns='jabber:client' type='get' id='gateway-items' to='capulet.lit'><query xmlns='http://jabber.org/protocol/disco#items'/></iq>")
+        );
+        let found = play(
+            &agent,
+            &mut discovery,
+            &[
+                (
+                    "<iq xmlns='jabber:client' type='result' id='gateway-items' from='capulet.lit'><query xmlns='http://jabber.org/protocol/disco#items'><item jid='muc.capulet.lit'/><item jid='irc.capulet.lit'/><item jid='capulet.lit' node='announce'/><item jid='sms.capulet.lit'/></query></iq>",
+                    Some("<iq xmlns='jabber:client' type='get' id='gateway-info' to='muc.capulet.lit'><query xmlns='http://jabber.org/protocol/disco#info'/></iq>"),
+                ),
+                // Some other answer, left alone.
+                (
+                    "<iq xmlns='jabber:client' type='result' id='gateway-info' from='irc.capulet.lit'/>",
+                    None,
+                ),
+                (
+                    "<iq xmlns='jabber:client' type='result' id='gateway-info' from='muc.capulet.lit'><query xmlns='http://jabber.org/protocol/disco#info'><identity category='conference' type='text' name='Chatrooms'/><feature var='http://jabber.org/protocol/disco#info'/><feature var='http://jabber.org/protocol/muc'/></query></iq>",
+                    Some("<iq xmlns='jabber:client' type='get' id='gateway-info' to='irc.capulet.lit'><query xmlns='http://jabber.org/protocol/disco#info'/></iq>"),
+                ),
+                (
+                    "<iq xmlns='jabber:client' type='result' id='gateway-info' from='irc.capulet.lit'><query xmlns='http://jabber.org/protocol/disco#info'><identity category='gateway' type='irc' name='IRC'/><feature var='http://jabber.org/protocol/disco#info'/><feature var='jabber:iq:register'/><feature var='jabber:iq:gateway'/></query></iq>",
+                    Some("<iq xmlns='jabber:client' type='get' id='gateway-info' to='sms.capulet.lit'><query xmlns='http://jabber.org/protocol/disco#info'/></iq>"),
+                ),
+                // A broken item doesn’t end the discovery.
+                (
+                    "<iq xmlns='jabber:client' type='error' id='gateway-info' from='sms.capulet.lit'><error type='cancel'><service-unavailable xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/></error></iq>",
+                    None,
+                ),
+            ],
+        );
+        assert_eq!(
+            found,
+            [GatewayInfo {
+                jid: jid("irc.capulet.lit"),
+                type_: String::from("irc"),
+                name: Some(String::from("IRC")),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_register_then_subscribe() {
+        let mut agent = agent(true);
+        let gateway = jid("sms.capulet.lit");
+
+        // The registration gets answered…
+        let result = parse("<iq xmlns='jabber:client' type='result' id='gateway-register' from='sms.capulet.lit'/>");
+        assert!(
+            done_response(&agent, &jid("irc.capulet.lit"), "gateway-register", &result).is_none()
+        );
+        done_response(&agent, &gateway, "gateway-register", &result)
+            .unwrap()
+            .unwrap();
+        agent.gateways.registered(BareJid::from(gateway.clone()));
+
+        // …then the gateway asks for our presence, which gets approved and asked in turn, once.
+        let other = BareJid::domain("irc.capulet.lit");
+        assert!(agent.gateways.subscription_request(&other).is_empty());
+        let gateway = BareJid::from(gateway);
+        assert_eq!(
+            agent.gateways.subscription_request(&gateway),
+            [PresenceType::Subscribed, PresenceType::Subscribe]
+        );
+        assert!(agent.gateways.subscription_request(&gateway).is_empty());
+    }
+
+    #[test]
+    fn test_subscriptions_not_accepted() {
+        let mut agent = agent(false);
+        let gateway = BareJid::domain("sms.capulet.lit");
+        agent.gateways.registered(gateway.clone());
+        assert!(agent.gateways.subscription_request(&gateway).is_empty());
+    }
+
+    #[test]
+    fn test_unregister() {
+        let mut gateways = Gateways::new(true);
+        let gateway = BareJid::domain("sms.capulet.lit");
+        gateways.registered(gateway.clone());
+        let roster = [
+            contact("romeo@montague.lit"),
+            contact("sms.capulet.lit"),
+            contact("+13158675309@sms.capulet.lit"),
+            contact("+15551234567@sms.capulet.lit"),
+            contact("nurse@capulet.lit"),
+        ];
+        let removals: Vec<_> = gateways
+            .unregistered(&gateway, &roster)
+            .into_iter()
+            .map(Element::from)
+            .collect();
+        assert_eq!(
+            removals,
+            [
+                parse("<iq xmlns='jabber:client' type='set' id='gateway-roster-remove-0'><query xmlns='jabber:iq:roster'><item jid='sms.capulet.lit' subscription='remove'/></query></iq>"),
+                parse("<iq xmlns='jabber:client' type='set' id='gateway-roster-remove-1'><query xmlns='jabber:iq:roster'><item jid='+13158675309@sms.capulet.lit' subscription='remove'/></query></iq>"),
+                parse("<iq xmlns='jabber:client' type='set' id='gateway-roster-remove-2'><query xmlns='jabber:iq:roster'><item jid='+15551234567@sms.capulet.lit' subscription='remove'/></query></iq>"),
+            ]
+        );
+        // A subscription request coming after that isn’t from a gateway we use anymore.
+        assert!(gateways.subscription_request(&gateway).is_empty());
+    }
+
+    #[test]
+    fn test_translate() {
+        let agent = agent(false);
+        let gateway = jid("sms.capulet.lit");
+        let iq = Iq::from_set("gateway-translate", Prompt::translate("+13158675309"))
+            .with_to(gateway.clone());
+        assert_eq!(
+            Element::from(iq),
+            parse("<iq xmlns='jabber:client' type='set' id='gateway-translate' to='sms.capulet.lit'><query xmlns='jabber:iq:gateway'><prompt>+13158675309</prompt></query></iq>")
+        );
+        let result = parse("<iq xmlns='jabber:client' type='result' id='gateway-translate' from='sms.capulet.lit'><query xmlns='jabber:iq:gateway'><jid>+13158675309@sms.capulet.lit</jid></query></iq>");
+        assert_eq!(
+            translate_response(&agent, &gateway, &result)
+                .unwrap()
+                .unwrap(),
+            jid("+13158675309@sms.capulet.lit")
+        );
+    }
+}
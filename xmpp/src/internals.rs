@@ -0,0 +1,267 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Introspection of the timers and queues of the Agent, and a way to drive it by hand, so that
+//! tests running it under tokio’s paused time can assert on them deterministically.
+//!
+//! Only built with the `test-internals` feature.
+
+use crate::{ephemeral, Agent, Event};
+use futures::{FutureExt, StreamExt};
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use tokio_xmpp::internals as client;
+
+/// What a timer of the Agent is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerKind {
+    /// A timer of the underlying client.
+    Client(client::TimerKind),
+    /// Emitting [`Event::MessageExpired`] for that many messages.
+    MessageExpiry(usize),
+    /// Broadcasting our entity capabilities again, see [`Agent::advertise_feature`].
+    CapsBroadcast,
+}
+
+/// A pending timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timer {
+    /// What it is for.
+    pub kind: TimerKind,
+    /// When it fires.
+    pub deadline: Instant,
+}
+
+/// The queue of one kind of updates, shared by all of its receivers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubscriberQueue {
+    /// How many receivers are subscribed.
+    pub subscribers: usize,
+    /// How many updates at most are waiting for the slowest receiver.
+    pub queued: usize,
+}
+
+impl<T> From<&broadcast::Sender<T>> for SubscriberQueue {
+    fn from(sender: &broadcast::Sender<T>) -> SubscriberQueue {
+        SubscriberQueue {
+            subscribers: sender.receiver_count(),
+            queued: sender.len(),
+        }
+    }
+}
+
+/// How much is waiting in the queues of the Agent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueueDepths {
+    /// The queues of the underlying client.
+    pub client: client::QueueDepths,
+    /// Events to be returned by the next call to [`Agent::wait_for_events`].
+    pub queued_events: usize,
+    /// Background tasks still running.
+    pub tasks: usize,
+    /// Receivers of [`Agent::roster_updates`].
+    pub roster_updates: SubscriberQueue,
+    /// Receivers of [`Agent::presence_updates`].
+    pub presence_updates: SubscriberQueue,
+    /// Receivers of [`Agent::caps_updates`].
+    pub caps_updates: SubscriberQueue,
+}
+
+impl Agent {
+    /// Every timer pending, ordered by deadline.
+    pub fn timers(&self) -> Vec<Timer> {
+        let mut timers: Vec<Timer> = self
+            .client
+            .timers()
+            .into_iter()
+            .map(|timer| Timer {
+                kind: TimerKind::Client(timer.kind),
+                deadline: timer.deadline,
+            })
+            .collect();
+        timers.extend(self.expiry.deadlines().map(|(deadline, count)| Timer {
+            kind: TimerKind::MessageExpiry(count),
+            deadline: Instant::from_std(deadline),
+        }));
+        if let Some(deadline) = self.own_caps.next_deadline() {
+            timers.push(Timer {
+                kind: TimerKind::CapsBroadcast,
+                deadline: Instant::from_std(deadline),
+            });
+        }
+        timers.sort_by_key(|timer| timer.deadline);
+        timers
+    }
+
+    /// How much is waiting in every queue.
+    pub fn queue_depths(&self) -> QueueDepths {
+        QueueDepths {
+            client: self.client.queue_depths(),
+            queued_events: self.queued_events.len(),
+            tasks: self.tasks.len(),
+            roster_updates: self.roster.updates().into(),
+            presence_updates: self.resources.updates().into(),
+            caps_updates: self.caps.updates().into(),
+        }
+    }
+
+    /// Do everything which is due without waiting, and return the events it caused.
+    ///
+    /// This is what [`Agent::wait_for_events`] does, except that it never sleeps: timers only
+    /// fire once their deadline is reached, so a test advances tokio’s paused clock and then
+    /// calls this, instead of racing the Agent against a timeout.
+    pub async fn tick(&mut self) -> Vec<Event> {
+        let mut events: Vec<Event> = self.queued_events.drain(..).collect();
+        if self.shut_down {
+            return events;
+        }
+        events.extend(self.expiry.expire(ephemeral::now()));
+        self.broadcast_caps().await;
+        loop {
+            if let Some(Some(event)) = self.client.next().now_or_never() {
+                let new_events = self.handle_client_event(event).await;
+                events.extend(new_events);
+            } else if let Some(Some(new_events)) = self.tasks.next().now_or_never() {
+                events.extend(new_events);
+            } else {
+                break;
+            }
+        }
+        events.append(&mut self.queued_events);
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SubscriberQueue, Timer, TimerKind};
+    use crate::{Agent, ClientBuilder, Event};
+    use std::str::FromStr;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::time::Instant;
+    use tokio_xmpp::internals as client;
+    use tokio_xmpp::{
+        AsyncClient as TokioXmppClient, AsyncConfig, AsyncServerConfig, ReconnectPolicy,
+    };
+    use xmpp_parsers::Jid;
+
+    /// An Agent whose every connection attempt fails right away, nothing listening on its port.
+    async fn unreachable_agent(reconnect: bool) -> Agent {
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let mut client = TokioXmppClient::new_with_config(AsyncConfig::new(
+            Jid::from_str("foo@127.0.0.1").unwrap(),
+            "meh",
+            AsyncServerConfig::Manual {
+                host: String::from("127.0.0.1"),
+                port,
+            },
+        ));
+        client
+            .set_reconnect(reconnect)
+            .set_reconnect_policy(ReconnectPolicy {
+                initial_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(8),
+                jitter: false,
+                max_failures: None,
+                ..Default::default()
+            });
+        ClientBuilder::new("foo@127.0.0.1", "meh")
+            .build_impl(client)
+            .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnect_backoff() {
+        let mut agent = unreachable_agent(true).await;
+        let events = agent.wait_for_events().await.unwrap();
+        assert!(matches!(events[..], [Event::Disconnected]));
+
+        for delay in &[2, 4, 8, 8] {
+            // Nothing is due before the backoff elapsed.
+            assert!(agent.tick().await.is_empty());
+            let deadline = Instant::now() + Duration::from_secs(*delay);
+            assert_eq!(
+                agent.timers(),
+                [Timer {
+                    kind: TimerKind::Client(client::TimerKind::Reconnect),
+                    deadline,
+                }]
+            );
+            tokio::time::advance(Duration::from_secs(*delay - 1)).await;
+            assert!(agent.tick().await.is_empty());
+            assert_eq!(agent.timers()[0].deadline, deadline);
+
+            tokio::time::advance(Duration::from_secs(1)).await;
+            let events = agent.wait_for_events().await.unwrap();
+            assert!(matches!(events[..], [Event::Disconnected]));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_message_expiry() {
+        let mut agent = unreachable_agent(false).await;
+        let events = agent.wait_for_events().await.unwrap();
+        assert!(matches!(events[..], [Event::Disconnected]));
+
+        let peer = Jid::from_str("contact@bar/res").unwrap();
+        let start = Instant::now();
+        agent.schedule_expiry(String::from("a"), peer.clone(), Duration::from_secs(60));
+        agent.schedule_expiry(String::from("b"), peer.clone(), Duration::from_secs(60));
+        agent.schedule_expiry(String::from("c"), peer, Duration::from_secs(30));
+        assert_eq!(
+            agent.timers(),
+            [
+                Timer {
+                    kind: TimerKind::MessageExpiry(1),
+                    deadline: start + Duration::from_secs(30),
+                },
+                Timer {
+                    kind: TimerKind::MessageExpiry(2),
+                    deadline: start + Duration::from_secs(60),
+                },
+            ]
+        );
+
+        let expired = |events: Vec<Event>| -> Vec<String> {
+            events
+                .into_iter()
+                .map(|event| match event {
+                    Event::MessageExpired { id, .. } => id,
+                    event => panic!("Unexpected event {:?}", event),
+                })
+                .collect()
+        };
+        tokio::time::advance(Duration::from_secs(29)).await;
+        assert!(agent.tick().await.is_empty());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(expired(agent.tick().await), ["c"]);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(expired(agent.tick().await), ["a", "b"]);
+        assert!(agent.timers().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_queue_depths() {
+        let agent = unreachable_agent(false).await;
+        let depths = agent.queue_depths();
+        assert_eq!(depths.client, client::QueueDepths::default());
+        assert_eq!(depths.roster_updates, SubscriberQueue::default());
+
+        // Updates only get queued for receivers which exist.
+        let _presence = agent.presence_updates();
+        let _roster = agent.roster_updates();
+        let _also_roster = agent.roster_updates();
+        let depths = agent.queue_depths();
+        assert_eq!(depths.roster_updates.subscribers, 2);
+        assert_eq!(depths.presence_updates.subscribers, 1);
+        assert_eq!(depths.caps_updates.subscribers, 0);
+        assert_eq!((depths.queued_events, depths.tasks), (0, 0));
+    }
+}
@@ -8,34 +8,119 @@
 
 use futures::stream::StreamExt;
 use std::cell::RefCell;
+use std::collections::HashMap;
+#[cfg(feature = "avatars")]
+use std::collections::VecDeque;
 use std::convert::TryFrom;
+#[cfg(feature = "avatars")]
+use std::future::Future;
+#[cfg(feature = "avatars")]
+use std::io;
+#[cfg(feature = "avatars")]
+use std::path::PathBuf;
+#[cfg(feature = "avatars")]
+use std::pin::Pin;
 use std::rc::Rc;
-use tokio_xmpp::{AsyncClient as TokioXmppClient, Event as TokioXmppEvent};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio_xmpp::{
+    AsyncClient as TokioXmppClient, AsyncConfig, AsyncServerConfig, Event as TokioXmppEvent,
+};
 use xmpp_parsers::{
-    bookmarks2::Conference,
+    attention::Attention,
+    bookmarks2::{Autojoin, Conference},
+    bytestreams::StreamHost,
     caps::{compute_disco, hash_caps, Caps},
+    carbons,
+    chatstates::ChatState,
+    data_forms::{DataForm, DataFormType},
     disco::{DiscoInfoQuery, DiscoInfoResult, Feature, Identity},
+    ephemeral::Ephemeral,
+    fallback::{self, Fallback},
+    geoloc::Geoloc,
     hashes::Algo,
+    idle::Idle,
     iq::{Iq, IqType},
+    jingle::{Content as JingleContent, ContentId, Jingle, Reason, ReasonElement, SessionId},
+    jingle_ice_udp::Transport as IceUdpTransport,
+    last::{LastActivityQuery, LastActivityResult},
+    mam::MetadataResult,
+    mam_prefs::{Prefs, PrefsQuery},
     message::{Body, Message, MessageType},
+    message_styling::{self, Span},
     muc::{
+        muc::History,
         user::{MucUser, Status},
-        Muc,
+        Muc, MucOwner,
     },
     ns,
-    presence::{Presence, Type as PresenceType},
-    pubsub::pubsub::{Items, PubSub},
-    roster::{Item as RosterItem, Roster},
+    presence::{Presence, Show as PresenceShow, Type as PresenceType},
+    pubsub::{
+        pubsub::{Items, PubSub},
+        reference::Reference,
+        Item as PubSubItem, ItemId, NodeName, Subscription as PubSubSubscription,
+    },
+    roster::{Item as RosterItem, Roster, Subscription},
+    sec_label::{Catalog, CatalogQuery, SecurityLabel},
     stanza_error::{DefinedCondition, ErrorType, StanzaError},
-    BareJid, FullJid, Jid,
+    stanza_id::{OriginId, StanzaId},
+    BareJid, Element, FullJid, Jid,
 };
 #[macro_use]
 extern crate log;
 
+mod admin;
+mod archive;
+mod bounces;
+mod bytestreams;
+mod calls;
+mod caps;
+mod chat_sessions;
+mod compliance;
+mod ephemeral;
+mod features;
+mod gateways;
+#[cfg(feature = "test-internals")]
+pub mod internals;
+mod messages;
+mod muc;
+mod outbox;
+mod own_caps;
 mod pubsub;
+mod rate_limit;
+mod resources;
+mod roster;
+mod storage;
+mod subscriptions;
+mod tasks;
+pub use admin::AdminClient;
+pub use archive::ArchivePage;
+pub use caps::CapsUpdate;
+pub use compliance::{ComplianceReport, ProtocolReport};
+#[cfg(feature = "avatars")]
+pub use features::AvatarConfig;
+#[allow(deprecated)]
+pub use features::ClientFeature;
+pub use features::{LocationAccess, MucConfig, NickConflict};
+pub use gateways::{GatewayClient, GatewayInfo};
+pub use messages::{English, Messages};
+pub use muc::InvitationKind;
+pub use outbox::{MessageStatus, PendingMessage};
+pub use pubsub::comments::CommentsPage;
+pub use resources::{AggregatedPresence, PresenceUpdate, Resource};
+pub use roster::RosterUpdate;
+pub use storage::{migrate, Change, FileStorage, MemoryStorage, ResumeState, Storage, VERSION_KEY};
+pub use subscriptions::{AutoAcceptPolicy, SubscriptionState};
 
 pub type Error = tokio_xmpp::Error;
 
+/// How many changes the receivers of [`Agent::roster_updates`], [`Agent::presence_updates`] and
+/// [`Agent::caps_updates`] can lag behind before missing some.
+pub const UPDATES_CAPACITY: usize = 256;
+pub use tokio_xmpp::InboundPolicy;
+pub use tokio_xmpp::Socks5Stream;
+
 #[derive(Debug)]
 pub enum ClientType {
     Bot,
@@ -57,15 +142,107 @@ impl ToString for ClientType {
     }
 }
 
-#[derive(PartialEq)]
-pub enum ClientFeature {
-    #[cfg(feature = "avatars")]
-    Avatars,
-    ContactList,
-    JoinRooms,
+pub type RoomNick = String;
+
+/// Downloads the given HTTP URL, see [`AvatarConfig::with_http_fetcher`].
+#[cfg(feature = "avatars")]
+pub type HttpFetcher = Rc<dyn Fn(String) -> Pin<Box<dyn Future<Output = io::Result<Vec<u8>>>>>>;
+
+/// Metadata of a message, besides its body.
+#[derive(Debug, Clone, Default)]
+pub struct MessageInfo {
+    /// The id the sender gave to this message, if any.
+    pub id: Option<String>,
+    /// The id the sending client gave to this message (XEP-0359), which rooms and carbons keep
+    /// as is, unlike `id`.
+    pub origin_id: Option<String>,
+    /// How long the sender asked us to keep this message (XEP-0466).
+    pub ephemeral: Option<Duration>,
+    /// Whether this groupchat message comes from a room we aren’t in, anyone being able to
+    /// send those and pretend to be an occupant.  See [`MucConfig::drop_unjoined_messages`].
+    pub unjoined_room: bool,
+    /// The security label of this message (XEP-0258), for the application to enforce.
+    pub security_label: Option<SecurityLabel>,
+    /// Whether the sender asked for the body not to be styled (XEP-0393).
+    pub unstyled: bool,
+    /// The namespaces of the payloads whose fallback text got removed from the body (XEP-0428),
+    /// see [`ClientBuilder::with_understood_payloads`].
+    pub fallbacks: Vec<String>,
 }
 
-pub type RoomNick = String;
+impl MessageInfo {
+    /// The spans to style `body` with (XEP-0393), none if the sender asked for it to stay
+    /// plain.
+    pub fn styling(&self, body: &Body) -> Vec<Span> {
+        if self.unstyled {
+            return Vec::new();
+        }
+        message_styling::parse(&body.0)
+    }
+}
+
+/// `body` without the text only there for clients not understanding the payloads of
+/// `fallbacks` (XEP-0428).
+fn without_fallbacks(body: &Body, fallbacks: &[Fallback]) -> Body {
+    if fallbacks.is_empty() {
+        return body.clone();
+    }
+    let ranges: Vec<_> = fallbacks
+        .iter()
+        .flat_map(|fallback| fallback.body_ranges(&body.0))
+        .collect();
+    Body(fallback::strip(&body.0, &ranges))
+}
+
+/// Additional elements to attach to a message sent with [`Agent::send_message_with_options`].
+#[derive(Debug, Clone, Default)]
+pub struct MessageOptions {
+    /// Ask the recipients to only keep this message for that long (XEP-0466).
+    pub ephemeral: Option<Duration>,
+    /// The id to give to this message, to recognise it in [`Event::MessageBounced`].  A unique
+    /// one gets generated otherwise.
+    pub id: Option<String>,
+    /// The security label to attach to this message (XEP-0258), usually picked from
+    /// [`Agent::fetch_label_catalog`].
+    pub security_label: Option<SecurityLabel>,
+}
+
+/// How to join a room with [`Agent::join_rooms`], `nick` and `password` defaulting like for
+/// [`Agent::join_room`].
+#[derive(Debug, Clone, Default)]
+pub struct JoinOptions {
+    pub nick: Option<String>,
+    pub password: Option<String>,
+    pub lang: String,
+    pub status: String,
+}
+
+/// What we know about our own server.
+#[derive(Debug, Clone, Default)]
+pub struct ServerInfo {
+    /// The server mirrors PEP avatars into vcard-temp and back (XEP-0398).
+    pub pep_vcard_conversion: bool,
+}
+
+/// What to do with a stanza received during an exchange of several iqs with an entity.
+pub(crate) enum Step<T> {
+    /// It is part of the exchange, which goes on.
+    Consumed,
+    /// It is part of the exchange, which goes on with this iq.
+    Send(Iq),
+    /// It ends the exchange.
+    Done(Result<T, Error>),
+}
+
+/// The answer to [`Agent::last_activity`] (XEP-0012).
+#[derive(Debug, Clone, PartialEq)]
+pub struct LastActivity {
+    /// For a server, how long it has been running.  For an account, how long ago it went
+    /// offline, or how long its user has been idle if it is online.
+    pub elapsed: Duration,
+    /// The status an offline account left with, if any.
+    pub status: Option<String>,
+}
 
 #[derive(Debug)]
 pub enum Event {
@@ -74,26 +251,171 @@ pub enum Event {
     ContactAdded(RosterItem),
     ContactRemoved(RosterItem),
     ContactChanged(RosterItem),
+    /// The avatar of a contact got saved where the given string says, usually a path, after
+    /// checking its hash, see [`ClientBuilder::set_storage`].  Every
+    /// variant it is available in (XEP-0084) comes along, the saved one being picked according
+    /// to [`AvatarConfig::max_dimension`].
     #[cfg(feature = "avatars")]
-    AvatarRetrieved(Jid, String),
-    ChatMessage(BareJid, Body),
+    AvatarRetrieved(Jid, String, Vec<xmpp_parsers::avatar::Info>),
+    ChatMessage(BareJid, Body, MessageInfo),
     JoinRoom(BareJid, Conference),
     LeaveRoom(BareJid),
     LeaveAllRooms,
-    RoomJoined(BareJid),
+    /// We joined a room with this nick, which differs from the one asked for if it was taken,
+    /// see [`MucConfig::nick_conflict`].
+    RoomJoined(BareJid, RoomNick),
     RoomLeft(BareJid),
-    RoomMessage(BareJid, RoomNick, Body),
+    RoomMessage(BareJid, RoomNick, Body, MessageInfo),
+    /// The subject of a room changed, either when joining it or later on, `None` meaning it got
+    /// removed.
+    RoomSubject(BareJid, Option<String>),
+    /// We got invited to join a room, either directly by `from` or through the room, along
+    /// with the password to join it if it needs one.
+    RoomInvitation {
+        room: BareJid,
+        from: Jid,
+        reason: Option<String>,
+        password: Option<String>,
+        kind: InvitationKind,
+    },
+    /// The presence subscription state with a contact changed.
+    SubscriptionChanged {
+        jid: BareJid,
+        old: SubscriptionState,
+        new: SubscriptionState,
+    },
+    /// A contact became a conversation partner, its subscription request having been approved
+    /// by the [`AutoAcceptPolicy`] for the first time, even across restarts with a persistent
+    /// [`Storage`].  This is the time to greet it.
+    NewConversationPartner(BareJid),
+    /// The availability of a contact changed, either because one of its resources came online,
+    /// went offline, or changed its show or status message.
+    ContactPresence(BareJid, AggregatedPresence),
+    /// Someone is trying to get our attention (XEP-0224), which the application may highlight.
+    AttentionRequested(Jid),
+    /// A contact published its location (XEP-0080), or stopped publishing it if `None`.
+    ContactLocation(Jid, Option<Geoloc>),
+    /// The retention time of a message has elapsed, and the application should now delete it.
+    MessageExpired {
+        id: String,
+        peer: Jid,
+    },
+    /// A message we recently sent couldn’t be delivered, see
+    /// [`ClientBuilder::set_bounce_window`].
+    MessageBounced {
+        to: Jid,
+        id: String,
+        body: String,
+        error: StanzaError,
+    },
+    /// A message we sent, so that the application can store it along with the received ones,
+    /// see [`ClientBuilder::set_sent_echo`].  It is built from the message as sent, with its
+    /// generated ids.
+    ///
+    /// `carbon` is set when another of our resources sent it (XEP-0280); copies of our own
+    /// messages coming back that way, or reflected by a room, have the same
+    /// [`MessageInfo::origin_id`].
+    MessageSentEcho {
+        to: Jid,
+        body: Body,
+        info: MessageInfo,
+        carbon: bool,
+    },
+    /// An error came back for a message we don’t know about, or sent too long ago.
+    MessageError {
+        from: Jid,
+        id: Option<String>,
+        error: StanzaError,
+    },
+    /// A presence we sent got refused, for instance when joining a room.
+    PresenceError(Jid, StanzaError),
+    /// Items got published on a PubSub node the Agent doesn’t handle itself (XEP-0060).
+    PubSubItemsPublished {
+        from: Jid,
+        node: NodeName,
+        items: Vec<PubSubItem>,
+    },
+    /// Items got retracted from a PubSub node the Agent doesn’t handle itself.
+    PubSubItemsRetracted {
+        from: Jid,
+        node: NodeName,
+        items: Vec<ItemId>,
+    },
+    /// All items of a PubSub node got removed at once.
+    PubSubNodePurged {
+        from: Jid,
+        node: NodeName,
+    },
+    /// A PubSub node got deleted, possibly replaced by the node at the `redirect` xmpp: URI.
+    PubSubNodeDeleted {
+        from: Jid,
+        node: NodeName,
+        redirect: Option<String>,
+    },
+    /// The configuration of a PubSub node changed, the new one coming along if the service
+    /// includes it.
+    PubSubNodeConfigured {
+        from: Jid,
+        node: NodeName,
+        form: Option<DataForm>,
+    },
+    /// The state of the subscription of `jid` to a PubSub node changed.
+    PubSubSubscriptionChanged {
+        from: Jid,
+        node: NodeName,
+        jid: Option<Jid>,
+        subscription: Option<PubSubSubscription>,
+    },
+    /// A contact calls us (XEP-0166, XEP-0167), proposing the media and transports described
+    /// in `contents`.  The call gets answered with [`Agent::accept_call`] or
+    /// [`Agent::reject_call`], given both `from` and `sid`, see [`ClientBuilder::with_jingle`].
+    JingleCallSessionInitiate {
+        from: Jid,
+        sid: SessionId,
+        contents: Vec<JingleContent>,
+    },
+    /// The peer of a call sent ICE candidates for one of its contents (XEP-0176).
+    JingleCallIceCandidate {
+        from: Jid,
+        sid: SessionId,
+        content: ContentId,
+        transport: IceUdpTransport,
+    },
+    /// The peer of a call hung up, or cancelled it before we answered.  Calls are also
+    /// forgotten without this event on [`Event::Disconnected`].
+    JingleCallEnding {
+        from: Jid,
+        sid: SessionId,
+        reason: Option<ReasonElement>,
+    },
 }
 
+/// How long requests wait for their answer, unless set otherwise.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Default)]
 pub struct ClientBuilder<'a> {
     jid: &'a str,
     password: &'a str,
     website: String,
-    default_nick: String,
     lang: Vec<String>,
     disco: (ClientType, String),
-    features: Vec<ClientFeature>,
+    features: features::Features,
+    rate_limit: Option<(u32, u32)>,
+    inbound_policy: Option<InboundPolicy>,
+    bounce_window: (usize, Duration),
+    sent_echo: bool,
+    accept_gateway_subscriptions: bool,
+    auto_accept: Option<AutoAcceptPolicy>,
+    shutdown_grace_period: Duration,
+    request_timeout: Duration,
+    storage: Option<Rc<RefCell<dyn Storage>>>,
+    messages: Option<Rc<dyn Messages>>,
+    /// Set by the deprecated setters, applying whatever [`ClientBuilder::with_avatars`] got.
+    #[cfg(feature = "avatars")]
+    avatar_max_dimension: Option<u16>,
+    #[cfg(feature = "avatars")]
+    http_fetcher: Option<HttpFetcher>,
 }
 
 impl ClientBuilder<'_> {
@@ -102,10 +424,23 @@ impl ClientBuilder<'_> {
             jid,
             password,
             website: String::from("https://gitlab.com/xmpp-rs/tokio-xmpp"),
-            default_nick: String::from("xmpp-rs"),
             lang: vec![String::from("en")],
             disco: (ClientType::default(), String::from("tokio-xmpp")),
-            features: vec![],
+            features: features::Features::default(),
+            rate_limit: None,
+            inbound_policy: None,
+            bounce_window: (bounces::DEFAULT_WINDOW, bounces::DEFAULT_RETENTION),
+            sent_echo: true,
+            accept_gateway_subscriptions: false,
+            auto_accept: None,
+            shutdown_grace_period: tasks::DEFAULT_GRACE_PERIOD,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            storage: None,
+            messages: None,
+            #[cfg(feature = "avatars")]
+            avatar_max_dimension: None,
+            #[cfg(feature = "avatars")]
+            http_fetcher: None,
         }
     }
 
@@ -119,18 +454,214 @@ impl ClientBuilder<'_> {
         self
     }
 
+    #[deprecated(note = "use with_muc() and MucConfig::default_nick")]
     pub fn set_default_nick(mut self, nick: &str) -> Self {
-        self.default_nick = String::from(nick);
+        self.features.muc.default_nick = String::from(nick);
         self
     }
 
+    /// Set the languages to pick message bodies in, by order of preference, the first one also
+    /// being the one the server should write its errors in.
     pub fn set_lang(mut self, lang: Vec<String>) -> Self {
         self.lang = lang;
         self
     }
 
+    /// Enable `feature` with its default configuration.
+    #[deprecated(note = "use the with_* methods, which take a configuration")]
+    #[allow(deprecated)]
     pub fn enable_feature(mut self, feature: ClientFeature) -> Self {
-        self.features.push(feature);
+        match feature {
+            ClientFeature::Attention => self.features.attention = true,
+            #[cfg(feature = "avatars")]
+            ClientFeature::Avatars => {
+                self.features.avatars.get_or_insert_with(Default::default);
+            }
+            ClientFeature::ContactList => self.features.contact_list = true,
+            ClientFeature::EphemeralMessages => self.features.ephemeral_messages = true,
+            ClientFeature::Jingle => self.features.jingle = true,
+            ClientFeature::JoinRooms => self.features.muc.autojoin_bookmarks = true,
+        }
+        self
+    }
+
+    /// Retrieve the avatars of contacts (XEP-0084), as configured by `config`.
+    #[cfg(feature = "avatars")]
+    pub fn with_avatars(mut self, config: AvatarConfig) -> Self {
+        self.features.avatars = Some(config);
+        self
+    }
+
+    /// Join rooms (XEP-0045) as configured by `config`, by default also the ones bookmarked
+    /// with autojoin (XEP-0402).
+    pub fn with_muc(mut self, config: MucConfig) -> Self {
+        self.features.muc = config;
+        self
+    }
+
+    /// Fetch the roster once online.
+    pub fn with_contact_list(mut self) -> Self {
+        self.features.contact_list = true;
+        self
+    }
+
+    /// Schedule a [`Event::MessageExpired`] for every received message carrying an ephemeral
+    /// hint (XEP-0466).
+    pub fn with_ephemeral_messages(mut self) -> Self {
+        self.features.ephemeral_messages = true;
+        self
+    }
+
+    /// Emit [`Event::AttentionRequested`] when a contact tries to get our attention (XEP-0224).
+    pub fn with_attention(mut self) -> Self {
+        self.features.attention = true;
+        self
+    }
+
+    /// Handle the calls contacts start with us (XEP-0166, XEP-0167), reported as
+    /// [`Event::JingleCallSessionInitiate`] and answered with [`Agent::accept_call`] or
+    /// [`Agent::reject_call`], over ICE-UDP (XEP-0176) secured with DTLS-SRTP (XEP-0320).
+    pub fn with_jingle(mut self) -> Self {
+        self.features.jingle = true;
+        self
+    }
+
+    /// Receive the locations of contacts as [`Event::ContactLocation`] (XEP-0080), and make
+    /// ours visible as `access` says once published with [`Agent::publish_location`].
+    pub fn with_locations(mut self, access: LocationAccess) -> Self {
+        self.features.locations = Some(access);
+        self
+    }
+
+    /// Tell that the application handles the payloads of namespace `ns`, such as an encryption
+    /// layer decrypting OMEMO ones, so that the text of their bodies only there for clients not
+    /// understanding them gets removed from message events (XEP-0428).  Bodies whose fallbacks
+    /// are for other payloads are left as they are.
+    pub fn with_understood_payloads(mut self, ns: &str) -> Self {
+        self.features.understood_payloads.push(ns.to_owned());
+        self
+    }
+
+    /// Limit outgoing stanzas to `stanzas_per_second`, allowing bursts of up to `burst` stanzas
+    /// after being idle.  Once the limit is reached, sending waits until the stanza can go out.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `stanzas_per_second` is zero.
+    pub fn set_rate_limit(mut self, stanzas_per_second: u32, burst: u32) -> Self {
+        assert!(
+            stanzas_per_second > 0,
+            "The rate limit must be at least one stanza per second."
+        );
+        self.rate_limit = Some((stanzas_per_second, burst));
+        self
+    }
+
+    /// Set how fast incoming stanzas are read, see [`InboundPolicy`].  By default, the Agent
+    /// only lets other tasks run from time to time when stanzas keep coming.
+    pub fn set_inbound_policy(mut self, policy: InboundPolicy) -> Self {
+        self.inbound_policy = Some(policy);
+        self
+    }
+
+    /// Remember the last `messages` sent for up to `retention`, so that an error coming back for
+    /// one of them gets emitted as [`Event::MessageBounced`] along with its body, instead of
+    /// [`Event::MessageError`].  Defaults to 128 messages for five minutes.
+    pub fn set_bounce_window(mut self, messages: usize, retention: Duration) -> Self {
+        self.bounce_window = (messages, retention);
+        self
+    }
+
+    /// Emit [`Event::MessageSentEcho`] for every message we send, or that another of our
+    /// resources sent.  Enabled by default.
+    pub fn set_sent_echo(mut self, echo: bool) -> Self {
+        self.sent_echo = echo;
+        self
+    }
+
+    /// Approve the presence subscription request of a gateway (XEP-0100) right after
+    /// [`GatewayClient::register_with_gateway`] succeeded, and subscribe to its presence in turn,
+    /// so that it logs us in to its legacy service whenever we are online.  Disabled by default,
+    /// leaving the request to [`Agent::approve_subscription`].
+    pub fn set_accept_gateway_subscriptions(mut self, accept: bool) -> Self {
+        self.accept_gateway_subscriptions = accept;
+        self
+    }
+
+    /// Approve the presence subscription requests `policy` accepts, and subscribe to the
+    /// presence of these contacts in turn, emitting [`Event::NewConversationPartner`] the first
+    /// time.  These contacts then get removed from the roster once they revoke our
+    /// subscription, unlike the ones added otherwise.  Disabled by default, leaving every
+    /// request to [`Agent::approve_subscription`].
+    pub fn set_auto_accept(mut self, policy: AutoAcceptPolicy) -> Self {
+        self.auto_accept = Some(policy);
+        self
+    }
+
+    /// Set how long [`Agent::disconnect`] waits for the background activities of the Agent to
+    /// finish, and then for the stream to close.  Defaults to five seconds.
+    pub fn set_shutdown_grace_period(mut self, grace_period: Duration) -> Self {
+        self.shutdown_grace_period = grace_period;
+        self
+    }
+
+    /// Set how long the requests made on behalf of the application, such as
+    /// [`Agent::last_activity`], wait for their answer before failing with
+    /// [`Error::RequestTimeout`].  Defaults to thirty seconds.
+    pub fn set_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Drop the groupchat messages and subject changes coming from rooms we aren’t in, instead
+    /// of emitting them with [`MessageInfo::unjoined_room`] set.
+    #[deprecated(note = "use with_muc() and MucConfig::drop_unjoined_messages")]
+    pub fn set_drop_unjoined_room_messages(mut self, drop: bool) -> Self {
+        self.features.muc.drop_unjoined_messages = drop;
+        self
+    }
+
+    /// Keep the roster, bookmarks, index of the avatars, pending subscription requests and ids
+    /// of the messages handled in `storage`, so that they survive restarts, for instance in a
+    /// [`FileStorage`].  By default, a [`MemoryStorage`] is used.
+    ///
+    /// What it contains gets loaded when building the Agent, after bringing it to the current
+    /// schema with [`migrate`].  If it was written by a newer version of this crate, it is left
+    /// untouched and a [`MemoryStorage`] is used instead.
+    pub fn set_storage<S: Storage + 'static>(mut self, storage: S) -> Self {
+        self.storage = Some(Rc::new(RefCell::new(storage)));
+        self
+    }
+
+    /// Write the texts the Agent sends on its own, such as those of the errors it answers iqs
+    /// with, with `messages` and in its language, instead of in [`English`].
+    pub fn set_messages<M: Messages + 'static>(mut self, messages: M) -> Self {
+        self.messages = Some(Rc::new(messages));
+        self
+    }
+
+    /// Download the biggest variant of an avatar whose width and height fit in `dimension`
+    /// pixels, or the smallest one if none does.  By default, the one stored in PEP gets
+    /// downloaded.
+    #[cfg(feature = "avatars")]
+    #[deprecated(note = "use with_avatars() and AvatarConfig::max_dimension")]
+    pub fn set_avatar_max_dimension(mut self, dimension: u16) -> Self {
+        self.avatar_max_dimension = Some(dimension);
+        self
+    }
+
+    /// Use `fetcher` to download the avatar variants only available over HTTP, which are
+    /// otherwise ignored.
+    ///
+    /// The downloads run in the background, and get cancelled by [`Agent::disconnect`].
+    #[cfg(feature = "avatars")]
+    #[deprecated(note = "use with_avatars() and AvatarConfig::with_http_fetcher()")]
+    pub fn set_http_fetcher<F, Fut>(mut self, fetcher: F) -> Self
+    where
+        F: Fn(String) -> Fut + 'static,
+        Fut: Future<Output = io::Result<Vec<u8>>> + 'static,
+    {
+        self.http_fetcher = Some(Rc::new(move |url| Box::pin(fetcher(url))));
         self
     }
 
@@ -141,40 +672,139 @@ impl ClientBuilder<'_> {
             "en",
             self.disco.1.to_string(),
         )];
-        let mut features = vec![Feature::new(ns::DISCO_INFO)];
-        #[cfg(feature = "avatars")]
-        {
-            if self.features.contains(&ClientFeature::Avatars) {
-                features.push(Feature::new(format!("{}+notify", ns::AVATAR_METADATA)));
-            }
-        }
-        if self.features.contains(&ClientFeature::JoinRooms) {
-            features.push(Feature::new(format!("{}+notify", ns::BOOKMARKS2)));
-        }
         DiscoInfoResult {
             node: None,
             identities,
-            features,
+            features: self.features.disco_features(),
             extensions: vec![],
+            unknown: vec![],
         }
     }
 
     pub fn build(self) -> Result<Agent, Error> {
-        let client = TokioXmppClient::new(self.jid, self.password)?;
+        let mut config = AsyncConfig::new(
+            Jid::from_str(self.jid)?,
+            self.password,
+            AsyncServerConfig::UseSrv,
+        );
+        if let Some(lang) = self.lang.first() {
+            config = config.stream_lang(lang);
+        }
+        let mut client = TokioXmppClient::new_with_config(config);
+        if let Some(policy) = self.inbound_policy.clone() {
+            client.set_inbound_policy(policy);
+        }
         Ok(self.build_impl(client)?)
     }
 
     // This function is meant to be used for testing build
     pub(crate) fn build_impl(self, client: TokioXmppClient) -> Result<Agent, Error> {
+        self.features.validate().map_err(Error::Config)?;
         let disco = self.make_disco();
         let node = self.website;
+        let features = self.features;
+        #[cfg(feature = "avatars")]
+        let avatars = features.avatars.unwrap_or_default();
+        let own_jid = BareJid::from_str(self.jid)?;
+        let storage = match self.storage {
+            Some(storage) => {
+                let migrated = storage::migrate_all(&mut *storage.borrow_mut());
+                match migrated {
+                    Ok(()) => storage,
+                    Err(err) => {
+                        warn!("Not using the storage: {}", err);
+                        Rc::new(RefCell::new(MemoryStorage::default()))
+                    }
+                }
+            }
+            None => Rc::new(RefCell::new(MemoryStorage::default())),
+        };
+        let stored = storage.borrow();
+        let bookmarks = storage::load_bookmarks(&*stored).unwrap_or_else(|err| {
+            warn!("Couldn’t load the bookmarks: {}", err);
+            HashMap::new()
+        });
+        let mut subscriptions = subscriptions::Subscriptions::default();
+        match storage::load_pending_subscriptions(&*stored) {
+            Ok(pending) => subscriptions.pending_in(pending),
+            Err(err) => warn!("Couldn’t load the pending subscriptions: {}", err),
+        }
+        let mut roster = roster::RosterCache::default();
+        match storage::load_roster(&*stored) {
+            Ok(Some(stored)) => {
+                subscriptions.roster(&stored.items);
+                roster.replace(stored.items);
+            }
+            Ok(None) => (),
+            Err(err) => warn!("Couldn’t load the roster: {}", err),
+        }
+        let seen_stanza_ids = storage::SeenStanzaIds::load(&*stored).unwrap_or_else(|err| {
+            warn!("Couldn’t load the ids of the messages handled: {}", err);
+            Default::default()
+        });
+        drop(stored);
 
         let agent = Agent {
             client,
-            default_nick: Rc::new(RefCell::new(self.default_nick)),
+            own_jid,
+            server_info: ServerInfo::default(),
+            #[cfg(feature = "avatars")]
+            published_avatars: VecDeque::new(),
+            #[cfg(feature = "avatars")]
+            avatar_max_dimension: avatars.max_dimension.or(self.avatar_max_dimension),
+            #[cfg(feature = "avatars")]
+            avatar_max_bytes: avatars.max_bytes,
+            #[cfg(feature = "avatars")]
+            http_fetcher: avatars.http_fetcher.or(self.http_fetcher),
+            #[cfg(feature = "avatars")]
+            avatar_variants: HashMap::new(),
+            #[cfg(feature = "avatars")]
+            avatar_directory: avatars
+                .cache_dir
+                .unwrap_or_else(storage::default_avatar_directory),
+            default_nick: Rc::new(RefCell::new(features.muc.default_nick)),
+            muc_history: features.muc.history,
             lang: Rc::new(self.lang),
             disco,
             node,
+            own_caps: Default::default(),
+            rooms_joined: HashMap::new(),
+            pending_joins: muc::PendingJoins::new(
+                features.muc.nick_conflict,
+                features.muc.max_nick_retries,
+            ),
+            bookmarks,
+            roster,
+            resources: Default::default(),
+            caps: Default::default(),
+            subscriptions,
+            queued_events: vec![],
+            enforce_ephemeral: features.ephemeral_messages,
+            attention: features.attention,
+            jingle: features.jingle,
+            calls: Default::default(),
+            understood_payloads: features.understood_payloads,
+            location_access: features.locations.unwrap_or_default(),
+            chat_sessions: Default::default(),
+            sent_messages: bounces::SentMessages::new(self.bounce_window.0, self.bounce_window.1),
+            outbox: outbox::Outbox::new(self.bounce_window.0),
+            connected: false,
+            sent_echo: self.sent_echo,
+            gateways: gateways::Gateways::new(self.accept_gateway_subscriptions),
+            streamhosts: Vec::new(),
+            auto_accept: self.auto_accept,
+            expiry: Default::default(),
+            tasks: Default::default(),
+            shutdown_grace_period: self.shutdown_grace_period,
+            request_timeout: self.request_timeout,
+            shut_down: false,
+            drop_unjoined_room_messages: features.muc.drop_unjoined_messages,
+            storage,
+            messages: self.messages.unwrap_or_else(|| Rc::new(English)),
+            seen_stanza_ids,
+            rate_limit: self.rate_limit.map(|(stanzas_per_second, burst)| {
+                rate_limit::TokenBucket::new(stanzas_per_second, burst, Instant::now())
+            }),
         };
 
         Ok(agent)
@@ -183,13 +813,83 @@ impl ClientBuilder<'_> {
 
 pub struct Agent {
     client: TokioXmppClient,
+    own_jid: BareJid,
+    server_info: ServerInfo,
+    #[cfg(feature = "avatars")]
+    published_avatars: VecDeque<String>,
+    #[cfg(feature = "avatars")]
+    avatar_max_dimension: Option<u16>,
+    #[cfg(feature = "avatars")]
+    avatar_max_bytes: Option<u32>,
+    #[cfg(feature = "avatars")]
+    http_fetcher: Option<HttpFetcher>,
+    /// Every variant of the last avatar announced by each contact.
+    #[cfg(feature = "avatars")]
+    avatar_variants: HashMap<Jid, Vec<xmpp_parsers::avatar::Info>>,
+    /// Where avatars get saved, the storage only indexing them.
+    #[cfg(feature = "avatars")]
+    avatar_directory: PathBuf,
     default_nick: Rc<RefCell<String>>,
+    /// The history asked for when joining a room.
+    muc_history: Option<History>,
     lang: Rc<Vec<String>>,
     disco: DiscoInfoResult,
     node: String,
+    own_caps: own_caps::OwnCaps,
+    rooms_joined: HashMap<BareJid, RoomNick>,
+    /// The rooms we sent a join presence to, until we are in.
+    pending_joins: muc::PendingJoins,
+    /// Our bookmarks (XEP-0402), as last received from the server or published by us.
+    bookmarks: HashMap<BareJid, Conference>,
+    roster: roster::RosterCache,
+    resources: resources::Resources,
+    caps: caps::CapsCache,
+    subscriptions: subscriptions::Subscriptions,
+    /// Events caused by our own actions, returned by the next call to wait_for_events().
+    queued_events: Vec<Event>,
+    enforce_ephemeral: bool,
+    attention: bool,
+    jingle: bool,
+    calls: calls::Calls,
+    understood_payloads: Vec<String>,
+    location_access: LocationAccess,
+    chat_sessions: chat_sessions::ChatSessions,
+    sent_messages: bounces::SentMessages,
+    /// The messages of [`Agent::send_message_queued`], waiting for the connection or sent.
+    outbox: outbox::Outbox,
+    /// Whether the client is online, messages can only be sent then.
+    connected: bool,
+    /// Whether to emit [`Event::MessageSentEcho`].
+    sent_echo: bool,
+    gateways: gateways::Gateways,
+    /// The SOCKS5 bytestream proxies of our server, see [`Agent::discover_streamhosts`].
+    streamhosts: Vec<StreamHost>,
+    /// Which subscription requests to approve, and whose contacts to remove once they
+    /// unsubscribe.
+    auto_accept: Option<AutoAcceptPolicy>,
+    expiry: ephemeral::ExpiryTimers,
+    /// Every background activity of the Agent has to be spawned there, so that
+    /// [`Agent::disconnect`] can stop it.
+    tasks: tasks::Tasks,
+    shutdown_grace_period: Duration,
+    /// How long requests wait for each answer, see [`ClientBuilder::set_request_timeout`].
+    request_timeout: Duration,
+    /// Set by [`Agent::disconnect`], after which no event gets emitted anymore.
+    shut_down: bool,
+    drop_unjoined_room_messages: bool,
+    /// Shared with the background tasks saving avatars.
+    storage: Rc<RefCell<dyn Storage>>,
+    /// The texts we send on our own.
+    messages: Rc<dyn Messages>,
+    /// The last stanza-ids (XEP-0359) our server gave to messages, to drop those delivered
+    /// again.
+    seen_stanza_ids: storage::SeenStanzaIds,
+    rate_limit: Option<rate_limit::TokenBucket>,
 }
 
 impl Agent {
+    /// Join a room, `nick` and `password` defaulting to the ones from its bookmark if there is
+    /// one, and the nick then to [`MucConfig::default_nick`].
     pub async fn join_room(
         &mut self,
         room: BareJid,
@@ -198,17 +898,74 @@ impl Agent {
         lang: &str,
         status: &str,
     ) {
+        let presence = self.join_presence(room, nick, password, lang, status);
+        self.pending_joins.joining(&presence);
+        let _ = self.send_stanza(presence.into()).await;
+    }
+
+    /// Join every room in `rooms`, for instance those bookmarked with autojoin, returning
+    /// whether the presence for each of them could be sent.
+    ///
+    /// The presences go through the rate limit set with [`ClientBuilder::set_rate_limit`], or
+    /// are paced to five per second after the first ten when there is none, so that restoring
+    /// lots of rooms doesn’t flood the server.
+    pub async fn join_rooms(
+        &mut self,
+        rooms: Vec<(BareJid, JoinOptions)>,
+    ) -> Vec<(BareJid, Result<(), Error>)> {
+        let mut pacing = match self.rate_limit {
+            Some(_) => None,
+            None => Some(rate_limit::TokenBucket::new(5, 10, Instant::now())),
+        };
+        let mut results = Vec::with_capacity(rooms.len());
+        for (room, options) in rooms {
+            if let Some(bucket) = &mut pacing {
+                let delay = bucket.take(Instant::now());
+                if delay > Duration::from_secs(0) {
+                    tokio::time::sleep(delay).await;
+                }
+            }
+            let presence = self.join_presence(
+                room.clone(),
+                options.nick,
+                options.password,
+                &options.lang,
+                &options.status,
+            );
+            self.pending_joins.joining(&presence);
+            let result = self.send_stanza(presence.into()).await;
+            results.push((room, result));
+        }
+        results
+    }
+
+    fn join_presence(
+        &self,
+        room: BareJid,
+        nick: Option<String>,
+        password: Option<String>,
+        lang: &str,
+        status: &str,
+    ) -> Presence {
+        let bookmark = self.bookmarks.get(&room);
+        let nick = nick.or_else(|| bookmark.and_then(|conference| conference.nick.clone()));
+        let password =
+            password.or_else(|| bookmark.and_then(|conference| conference.password.clone()));
+
         let mut muc = Muc::new();
         if let Some(password) = password {
             muc = muc.with_password(password);
         }
+        if let Some(history) = self.muc_history.clone() {
+            muc = muc.with_history(history);
+        }
 
         let nick = nick.unwrap_or_else(|| self.default_nick.borrow().clone());
         let room_jid = room.with_resource(nick);
         let mut presence = Presence::new(PresenceType::None).with_to(Jid::Full(room_jid));
         presence.add_payload(muc);
         presence.set_status(String::from(lang), String::from(status));
-        let _ = self.client.send_stanza(presence.into()).await;
+        presence
     }
 
     pub async fn send_message(
@@ -218,119 +975,1366 @@ impl Agent {
         lang: &str,
         text: &str,
     ) {
-        let mut message = Message::new(Some(recipient));
+        self.send_message_with_options(recipient, type_, lang, text, MessageOptions::default())
+            .await
+    }
+
+    pub async fn send_message_with_options(
+        &mut self,
+        recipient: Jid,
+        type_: MessageType,
+        lang: &str,
+        text: &str,
+        options: MessageOptions,
+    ) {
+        let message = self.outgoing_message(recipient, type_, lang, text, options, Instant::now());
+        let echo = self.sent_echo(&message, false);
+        if self.send_stanza(message.into()).await.is_ok() {
+            self.queued_events.extend(echo);
+        }
+    }
+
+    /// Send a message like [`Agent::send_message_with_options`], or queue it until we are
+    /// online, returning a handle to follow what becomes of it or to cancel it meanwhile.
+    ///
+    /// The messages queued get sent once connected, or connected again, in the order they were
+    /// queued, after any message queued before them for the same recipient.
+    pub async fn send_message_queued(
+        &mut self,
+        recipient: Jid,
+        type_: MessageType,
+        lang: &str,
+        text: &str,
+        mut options: MessageOptions,
+    ) -> PendingMessage {
+        let id = match options.id {
+            Some(ref id) => id.clone(),
+            None => options.id.insert(self.sent_messages.next_id()).clone(),
+        };
+        let handle = self.outbox.push(id, recipient, type_, lang, text, options);
+        self.flush_outbox().await;
+        handle
+    }
+
+    /// The messages to `peer` of [`Agent::send_message_queued`] still waiting for the
+    /// connection, in the order they will be sent.
+    pub fn queued_messages(&self, peer: &BareJid) -> Vec<PendingMessage> {
+        self.outbox.conversation(peer)
+    }
+
+    /// Send the messages queued, if online, until one fails to be.
+    async fn flush_outbox(&mut self) {
+        if !self.connected {
+            return;
+        }
+        while let Some(queued) = self.outbox.next() {
+            let message = self.outgoing_message(
+                queued.handle.to().clone(),
+                queued.type_.clone(),
+                &queued.lang,
+                &queued.text,
+                queued.options.clone(),
+                Instant::now(),
+            );
+            let echo = self.sent_echo(&message, false);
+            if self.send_stanza(message.into()).await.is_err() {
+                self.outbox.retry(queued);
+                break;
+            }
+            self.queued_events.extend(echo);
+            self.outbox.sent(queued, Instant::now());
+        }
+    }
+
+    fn outgoing_message(
+        &mut self,
+        recipient: Jid,
+        type_: MessageType,
+        lang: &str,
+        text: &str,
+        options: MessageOptions,
+        now: Instant,
+    ) -> Message {
+        let id = options.id.unwrap_or_else(|| self.sent_messages.next_id());
+        let mut message = Message::new(Some(recipient.clone()));
+        message.id = Some(id.clone());
         message.type_ = type_;
+        message.payloads.push(OriginId { id: id.clone() }.into());
         message
             .bodies
             .insert(String::from(lang), Body(String::from(text)));
-        let _ = self.client.send_stanza(message.into()).await;
+        if let Some(timer) = options.ephemeral {
+            message.payloads.push(Ephemeral::new(timer).into());
+        }
+        if let Some(label) = options.security_label {
+            message.payloads.push(label.into());
+        }
+        if message.type_ == MessageType::Chat {
+            self.chat_sessions.touch(recipient.clone(), now);
+        }
+        self.sent_messages
+            .record(id, recipient, String::from(text), now);
+        message
     }
 
-    fn make_initial_presence(disco: &DiscoInfoResult, node: &str) -> Presence {
-        let caps_data = compute_disco(disco);
-        let hash = hash_caps(&caps_data, Algo::Sha_1).unwrap();
-        let caps = Caps::new(node, hash);
-
-        let mut presence = Presence::new(PresenceType::None);
-        presence.add_payload(caps);
-        presence
+    /// Try to get the attention of `recipient` (XEP-0224), optionally telling why.
+    ///
+    /// Without a body this is a headline, which isn’t stored for offline contacts since it
+    /// would be stale by the time they come back, with one it is a normal chat message.
+    pub async fn send_attention(&mut self, recipient: Jid, body: Option<&str>) {
+        let message = Self::attention_message(recipient, body);
+        let _ = self.send_stanza(message.into()).await;
     }
 
-    async fn handle_iq(&mut self, iq: Iq) -> Vec<Event> {
-        let mut events = vec![];
-        let from = iq
-            .from
-            .clone()
-            .unwrap_or_else(|| self.client.bound_jid().unwrap().clone());
-        if let IqType::Get(payload) = iq.payload {
-            if payload.is("query", ns::DISCO_INFO) {
-                let query = DiscoInfoQuery::try_from(payload);
-                match query {
-                    Ok(query) => {
-                        let mut disco_info = self.disco.clone();
-                        disco_info.node = query.node;
-                        let iq = Iq::from_result(iq.id, Some(disco_info))
-                            .with_to(iq.from.unwrap())
-                            .into();
-                        let _ = self.client.send_stanza(iq).await;
-                    }
-                    Err(err) => {
-                        let error = StanzaError::new(
-                            ErrorType::Modify,
-                            DefinedCondition::BadRequest,
-                            "en",
-                            &format!("{}", err),
-                        );
-                        let iq = Iq::from_error(iq.id, error)
-                            .with_to(iq.from.unwrap())
-                            .into();
-                        let _ = self.client.send_stanza(iq).await;
-                    }
-                }
-            } else {
-                // We MUST answer unhandled get iqs with a service-unavailable error.
-                let error = StanzaError::new(
-                    ErrorType::Cancel,
-                    DefinedCondition::ServiceUnavailable,
-                    "en",
-                    "No handler defined for this kind of iq.",
-                );
-                let iq = Iq::from_error(iq.id, error)
-                    .with_to(iq.from.unwrap())
-                    .into();
-                let _ = self.client.send_stanza(iq).await;
+    fn attention_message(recipient: Jid, body: Option<&str>) -> Message {
+        let mut message = Message::new(Some(recipient));
+        message.type_ = match body {
+            Some(body) => {
+                message
+                    .bodies
+                    .insert(String::new(), Body(String::from(body)));
+                MessageType::Chat
             }
-        } else if let IqType::Result(Some(payload)) = iq.payload {
-            // TODO: move private iqs like this one somewhere else, for
-            // security reasons.
-            if payload.is("query", ns::ROSTER) && iq.from.is_none() {
-                let roster = Roster::try_from(payload).unwrap();
-                for item in roster.items.into_iter() {
-                    events.push(Event::ContactAdded(item));
-                }
-            } else if payload.is("pubsub", ns::PUBSUB) {
-                let new_events = pubsub::handle_iq_result(&from, payload);
-                events.extend(new_events);
+            None => MessageType::Headline,
+        };
+        message.payloads.push(Attention.into());
+        message
+    }
+
+    /// Close the connection, after telling the contacts we recently chatted with that we are
+    /// gone (XEP-0085).
+    ///
+    /// The background activities of the Agent get cancelled first, and are given the grace
+    /// period set with [`ClientBuilder::set_shutdown_grace_period`] to finish; the stream then
+    /// gets closed, waiting as long at most.  The events still pending are then returned by
+    /// [`Agent::wait_for_events`], the last one being [`Event::Disconnected`], after which it
+    /// only returns `None`.
+    pub async fn disconnect(&mut self) {
+        if self.shut_down {
+            return;
+        }
+        let grace_period = self.shutdown_grace_period;
+        let events = self.tasks.shutdown(grace_period).await;
+        self.queued_events.extend(events);
+        let close = async {
+            for message in self.gone_messages(Instant::now()) {
+                let _ = self.send_stanza(message.into()).await;
             }
-        } else if let IqType::Set(_) = iq.payload {
-            // We MUST answer unhandled set iqs with a service-unavailable error.
-            let error = StanzaError::new(
-                ErrorType::Cancel,
-                DefinedCondition::ServiceUnavailable,
-                "en",
-                "No handler defined for this kind of iq.",
-            );
-            let iq = Iq::from_error(iq.id, error)
-                .with_to(iq.from.unwrap())
-                .into();
-            let _ = self.client.send_stanza(iq).await;
+            let _ = self.client.send_end().await;
+        };
+        if tokio::time::timeout(grace_period, close).await.is_err() {
+            warn!("The stream didn’t close within {:?}", grace_period);
         }
+        self.queued_events.push(Event::Disconnected);
+        self.shut_down = true;
+    }
 
-        events
+    fn gone_messages(&mut self, now: Instant) -> Vec<Message> {
+        self.chat_sessions
+            .close_all(now)
+            .into_iter()
+            .map(|peer| {
+                let mut message = Message::new(Some(peer));
+                message.type_ = MessageType::Chat;
+                message.payloads.push(ChatState::Gone.into());
+                message
+            })
+            .collect()
     }
 
-    async fn handle_message(&mut self, message: Message) -> Vec<Event> {
-        let mut events = vec![];
+    /// What was discovered about our server since we got online.
+    pub fn server_info(&self) -> &ServerInfo {
+        &self.server_info
+    }
+
+    /// Publish a new avatar (XEP-0084), `type_` being its IANA media type.
+    ///
+    /// This only ever publishes via PEP: servers implementing XEP-0398 take care of mirroring
+    /// it into vcard-temp for older clients, and the resulting presence echo is ignored.
+    #[cfg(feature = "avatars")]
+    pub async fn publish_avatar(&mut self, data: Vec<u8>, type_: &str) {
+        pubsub::avatar::publish_avatar(self, data, type_).await
+    }
+
+    /// Publish our location (XEP-0080), or stop publishing it with `None`.
+    ///
+    /// It is visible to whoever [`ClientBuilder::with_locations`] allows, only our contacts
+    /// otherwise.
+    pub async fn publish_location(&mut self, location: Option<Geoloc>) {
+        let iq = pubsub::publish_location(location.unwrap_or_default(), self.location_access);
+        let _ = self.send_stanza(iq.into()).await;
+    }
+
+    /// Emit [`Event::MessageExpired`] for the message `id` from `peer` once `after` has elapsed.
+    ///
+    /// With [`ClientFeature::EphemeralMessages`] this is done automatically for every received
+    /// message with an id and an ephemeral hint, this can be used for any other message the
+    /// application wants to delete later.  Pending expiries survive reconnections but are only
+    /// kept in memory, they are all lost when the Agent is dropped.
+    pub fn schedule_expiry(&mut self, id: String, peer: Jid, after: Duration) {
+        self.expiry.schedule(ephemeral::now(), after, id, peer);
+    }
+
+    /// Cancel a pending expiry, returns whether the message `id` from `peer` was scheduled.
+    pub fn cancel_expiry(&mut self, id: &str, peer: &Jid) -> bool {
+        self.expiry.cancel(id, peer)
+    }
+
+    /// The presence subscription state with `jid`, which is [`SubscriptionState::default`] for
+    /// unknown contacts.
+    pub fn subscription_state(&self, jid: &BareJid) -> SubscriptionState {
+        self.subscriptions.get(jid)
+    }
+
+    /// Everyone who asked to receive our presence and didn’t get an answer yet, in no
+    /// particular order.
+    ///
+    /// These are kept in the storage, and the server delivers pending requests again on every
+    /// login anyway (RFC 6121 §3.1.3).
+    pub fn pending_inbound_subscriptions(&self) -> Vec<BareJid> {
+        self.subscriptions.pending_inbound()
+    }
+
+    /// Ask `jid` to receive its presence.
+    pub async fn request_subscription(&mut self, jid: BareJid) {
+        self.send_subscription(jid, PresenceType::Subscribe).await
+    }
+
+    /// Allow `jid` to receive our presence, usually to answer its request.
+    pub async fn approve_subscription(&mut self, jid: BareJid) {
+        self.send_subscription(jid, PresenceType::Subscribed).await
+    }
+
+    /// Deny the request of `jid` to receive our presence, or stop sending it if it already was.
+    pub async fn deny_subscription(&mut self, jid: BareJid) {
+        self.send_subscription(jid, PresenceType::Unsubscribed)
+            .await
+    }
+
+    /// Stop receiving the presence of `jid`, or cancel our request to.
+    pub async fn cancel_subscription(&mut self, jid: BareJid) {
+        self.send_subscription(jid, PresenceType::Unsubscribe).await
+    }
+
+    async fn send_subscription(&mut self, jid: BareJid, type_: PresenceType) {
+        let presence = Presence::new(type_.clone()).with_to(Jid::Bare(jid.clone()));
+        if self.send_stanza(presence.into()).await.is_ok() {
+            let events: Vec<Event> = self
+                .subscriptions
+                .outbound(jid, &type_)
+                .into_iter()
+                .collect();
+            self.save_pending_subscriptions(&events);
+            self.queued_events.extend(events);
+        }
+    }
+
+    /// Answer the call `sid` from `peer` with the media and transports we agree to, returning
+    /// whether it is still going on, nothing being sent otherwise.
+    pub async fn accept_call(
+        &mut self,
+        peer: &Jid,
+        sid: &SessionId,
+        contents: Vec<JingleContent>,
+    ) -> bool {
+        let responder = self.client.bound_jid().cloned();
+        match self.calls.accept(peer, sid, responder, contents) {
+            Some(iq) => {
+                let _ = self.send_stanza(iq.into()).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Turn down the call `sid` from `peer`, or hang up once it got accepted, usually with
+    /// [`Reason::Decline`], [`Reason::Busy`] or [`Reason::Success`].  Returns whether it was
+    /// still going on, nothing being sent otherwise.
+    pub async fn reject_call(&mut self, peer: &Jid, sid: &SessionId, reason: Reason) -> bool {
+        match self.calls.terminate(peer, sid, reason) {
+            Some(iq) => {
+                let _ = self.send_stanza(iq.into()).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Send our ICE candidates for `content` of the call `sid` with `peer` (XEP-0176), returning
+    /// whether both exist, nothing being sent otherwise.
+    pub async fn send_ice_candidate(
+        &mut self,
+        peer: &Jid,
+        sid: &SessionId,
+        content: ContentId,
+        transport: IceUdpTransport,
+    ) -> bool {
+        match self.calls.transport_info(peer, sid, content, transport) {
+            Some(iq) => {
+                let _ = self.send_stanza(iq.into()).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Request the configuration form of a room we own (XEP-0045).
+    ///
+    /// This waits for the answer, the events received meanwhile are returned by the next call
+    /// to [`Agent::wait_for_events`].
+    pub async fn get_room_config(&mut self, room: BareJid) -> Result<DataForm, Error> {
+        let room = Jid::Bare(room);
+        let iq = Iq::from_get("room-config", MucOwner::new()).with_to(room.clone());
+        self.query(iq, |agent, elem| agent.room_config_response(&room, elem))
+            .await
+    }
+
+    /// Whether `elem` answers the query sent by [`Agent::get_room_config`] to `room`, and with
+    /// what.
+    fn room_config_response(&self, room: &Jid, elem: &Element) -> Option<Result<DataForm, Error>> {
+        Some(match self.iq_result(elem, "room-config", room)? {
+            Ok(Some(payload)) => match MucOwner::try_from(payload) {
+                Ok(MucOwner { form: Some(form) }) => Ok(form),
+                Ok(MucOwner { form: None }) => Err(Error::Protocol(
+                    xmpp_parsers::Error::ParseError("Missing room configuration form.").into(),
+                )),
+                Err(e) => Err(Error::Protocol(e.into())),
+            },
+            Ok(None) => Err(Error::Protocol(
+                xmpp_parsers::Error::ParseError("Missing room configuration.").into(),
+            )),
+            Err(err) => Err(err),
+        })
+    }
+
+    /// Change the configuration of a room we own, `form` being usually the one returned by
+    /// [`Agent::get_room_config`] with the values of its fields modified.
+    ///
+    /// This waits for the room to accept it, the events received meanwhile are returned by the
+    /// next call to [`Agent::wait_for_events`].
+    pub async fn set_room_config(
+        &mut self,
+        room: BareJid,
+        mut form: DataForm,
+    ) -> Result<(), Error> {
+        form.type_ = DataFormType::Submit;
+        let room = Jid::Bare(room);
+        let iq = Iq::from_set("room-config", MucOwner::with_form(form)).with_to(room.clone());
+        self.query(iq, |agent, elem| {
+            agent
+                .iq_result(elem, "room-config", &room)
+                .map(|result| result.map(|_| ()))
+        })
+        .await
+    }
+
+    /// Ask `jid` for its last activity (XEP-0012): the uptime of a server, the idle time of a
+    /// resource or room occupant, or how long ago an account went offline.
+    ///
+    /// This waits for the answer, the events received meanwhile are returned by the next call
+    /// to [`Agent::wait_for_events`].
+    pub async fn last_activity(&mut self, jid: Jid) -> Result<LastActivity, Error> {
+        let iq = Iq::from_get("last-activity", LastActivityQuery).with_to(jid.clone());
+        self.query(iq, |agent, elem| agent.last_activity_response(&jid, elem))
+            .await
+    }
+
+    /// Whether `elem` answers the query sent by [`Agent::last_activity`] to `jid`, and with
+    /// what.
+    fn last_activity_response(
+        &self,
+        jid: &Jid,
+        elem: &Element,
+    ) -> Option<Result<LastActivity, Error>> {
+        Some(match self.iq_result(elem, "last-activity", jid)? {
+            Ok(Some(payload)) => LastActivityResult::try_from(payload)
+                .map(|result| LastActivity {
+                    elapsed: Duration::from_secs(result.seconds),
+                    status: result.status,
+                })
+                .map_err(|e| Error::Protocol(e.into())),
+            Ok(None) => Err(Error::Protocol(
+                xmpp_parsers::Error::ParseError("Missing last activity result.").into(),
+            )),
+            Err(err) => Err(err),
+        })
+    }
+
+    /// Ask our server for the security labels (XEP-0258) we can attach to the messages we send
+    /// to `to`, with [`MessageOptions::security_label`].
+    ///
+    /// This waits for the answer, the events received meanwhile are returned by the next call
+    /// to [`Agent::wait_for_events`].
+    pub async fn fetch_label_catalog(&mut self, to: Jid) -> Result<Catalog, Error> {
+        let server = Jid::Bare(BareJid::domain(self.own_jid.domain.clone()));
+        let iq =
+            Iq::from_get("label-catalog", CatalogQuery { to: Some(to) }).with_to(server.clone());
+        self.query(iq, |agent, elem| {
+            agent.label_catalog_response(&server, elem)
+        })
+        .await
+    }
+
+    /// Whether `elem` answers the query sent by [`Agent::fetch_label_catalog`] to `server`, and
+    /// with what.
+    fn label_catalog_response(
+        &self,
+        server: &Jid,
+        elem: &Element,
+    ) -> Option<Result<Catalog, Error>> {
+        Some(match self.iq_result(elem, "label-catalog", server)? {
+            Ok(Some(payload)) => Catalog::try_from(payload).map_err(|e| Error::Protocol(e.into())),
+            Ok(None) => Err(Error::Protocol(
+                xmpp_parsers::Error::ParseError("Missing security label catalog.").into(),
+            )),
+            Err(err) => Err(err),
+        })
+    }
+
+    /// Administrate our server (XEP-0133) with the commands it allows to our account.
+    ///
+    /// The server gets asked for these commands first, failing with a `forbidden`
+    /// [`Error::Stanza`] if there is none.  The events received while waiting for answers are
+    /// returned by the next call to [`Agent::wait_for_events`].
+    pub async fn admin(&mut self) -> Result<AdminClient<'_>, Error> {
+        AdminClient::new(self).await
+    }
+
+    /// Interact with the gateways to legacy services (XEP-0100) our server hosts.
+    ///
+    /// Each of its methods waits for the answers, the events received meanwhile are returned by
+    /// the next call to [`Agent::wait_for_events`].
+    pub fn gateways(&mut self) -> GatewayClient<'_> {
+        GatewayClient::new(self)
+    }
+
+    /// Discover the SOCKS5 bytestream proxies (XEP-0065) among the items of our server, and
+    /// the network address of each of them, which then become our [`Agent::streamhosts`].
+    ///
+    /// This waits for the answers, the events received meanwhile are returned by the next call
+    /// to [`Agent::wait_for_events`].
+    pub async fn discover_streamhosts(&mut self) -> Result<Vec<StreamHost>, Error> {
+        let server = Jid::Bare(BareJid::domain(self.own_jid.domain.clone()));
+        let (mut discovery, iq) = bytestreams::Discovery::new(server);
+        let streamhosts = self
+            .exchange(iq, |agent, elem| discovery.handle(agent, elem))
+            .await?;
+        self.streamhosts = streamhosts.clone();
+        Ok(streamhosts)
+    }
+
+    /// The proxies found by the last [`Agent::discover_streamhosts`], to offer along with our
+    /// own streamhosts when initiating a SOCKS5 bytestream, then to connect to with
+    /// [`Socks5Stream::connect`].
+    pub fn streamhosts(&self) -> &[StreamHost] {
+        &self.streamhosts
+    }
+
+    /// Our archiving preferences (XEP-0441): which messages our server stores in our archive.
+    ///
+    /// This waits for the answer, the events received meanwhile are returned by the next call
+    /// to [`Agent::wait_for_events`].
+    pub async fn mam_preferences(&mut self) -> Result<Prefs, Error> {
+        let iq = Iq::from_get("mam-prefs", PrefsQuery);
+        self.query(iq, |agent, elem| {
+            archive::prefs_response(agent, "mam-prefs", None, elem)
+        })
+        .await
+    }
+
+    /// Change our archiving preferences (XEP-0441), returning them as our server applied them.
+    ///
+    /// This waits for the answer, the events received meanwhile are returned by the next call
+    /// to [`Agent::wait_for_events`].
+    pub async fn set_mam_preferences(&mut self, prefs: Prefs) -> Result<Prefs, Error> {
+        let iq = Iq::from_set("mam-prefs-set", prefs.clone());
+        self.query(iq, |agent, elem| {
+            archive::prefs_response(agent, "mam-prefs-set", Some(&prefs), elem)
+        })
+        .await
+    }
+
+    /// The ids and timestamps of the oldest and newest messages of the archive (XEP-0313) of
+    /// `archive`, a room, or of our own account if `None`.  Neither is known if it is empty.
+    ///
+    /// An archive which doesn’t advertise metadata queries gets asked for its newest message
+    /// instead, then where it starts is only known if that is its only message.  This waits for
+    /// the answer, the events received meanwhile are returned by the next call to
+    /// [`Agent::wait_for_events`].
+    pub async fn archive_metadata(
+        &mut self,
+        archive: Option<BareJid>,
+    ) -> Result<MetadataResult, Error> {
+        let archive = Jid::Bare(archive.unwrap_or_else(|| self.own_jid.clone()));
+        let (mut query, iq) = archive::ArchiveQuery::metadata(archive);
+        match self
+            .exchange(iq, |agent, elem| query.handle(agent, elem))
+            .await?
+        {
+            archive::Outcome::Metadata(metadata) => Ok(metadata),
+            archive::Outcome::Page(_) => unreachable!(),
+        }
+    }
+
+    /// Fetch at most `max` messages from the archive (XEP-0313) of `archive`, a room, or of
+    /// our own account if `None`: the newest ones, or those right before the message with the
+    /// id `before`, to page backwards through it.
+    ///
+    /// To sync from the newest message, the [`Agent::archive_metadata`] get checked first when
+    /// the archive supports them, so that an empty archive doesn’t get queried, and the page
+    /// holding its oldest message is known to be complete.  This waits for the answer, the
+    /// events received meanwhile are returned by the next call to
+    /// [`Agent::wait_for_events`].
+    pub async fn archive_page(
+        &mut self,
+        archive: Option<BareJid>,
+        before: Option<String>,
+        max: usize,
+    ) -> Result<ArchivePage, Error> {
+        let archive = Jid::Bare(archive.unwrap_or_else(|| self.own_jid.clone()));
+        let (mut query, iq) = match before {
+            None => archive::ArchiveQuery::newest(archive, max),
+            Some(before) => archive::ArchiveQuery::before(archive, before, max),
+        };
+        match self
+            .exchange(iq, |agent, elem| query.handle(agent, elem))
+            .await?
+        {
+            archive::Outcome::Page(page) => Ok(page),
+            archive::Outcome::Metadata(_) => unreachable!(),
+        }
+    }
+
+    /// The node of the comments on the item `item` of `node` on `service`, as its payload
+    /// references it the way social feeds do (XEP-0277, XEP-0472), `None` if it doesn’t.
+    ///
+    /// This waits for the answer, the events received meanwhile are returned by the next call
+    /// to [`Agent::wait_for_events`].
+    pub async fn comments_node_for(
+        &mut self,
+        service: Jid,
+        node: NodeName,
+        item: ItemId,
+    ) -> Result<Option<Reference>, Error> {
+        let iq = pubsub::comments::item_request(&service, &node, &item);
+        self.query(iq, |agent, elem| {
+            pubsub::comments::reference_response(agent, &service, &item, elem)
+        })
+        .await
+    }
+
+    /// Create the node for the comments on the item `item`, on `service`, with the name social
+    /// feeds use by convention, open for anyone to read and publish comments to.
+    ///
+    /// The returned reference is the one to add to the payload of the item, converted into an
+    /// [`Element`], the service being free to create the node under another name.  This waits
+    /// for the answer, the events received meanwhile are returned by the next call to
+    /// [`Agent::wait_for_events`].
+    pub async fn create_comments_node(
+        &mut self,
+        service: Jid,
+        item: ItemId,
+    ) -> Result<Reference, Error> {
+        let comments = Reference::for_item(service, &item);
+        let iq = pubsub::comments::create_request(&comments);
+        self.query(iq, |agent, elem| {
+            pubsub::comments::create_response(agent, &comments, elem)
+        })
+        .await
+    }
+
+    /// Publish `entry`, usually an Atom entry, as a comment to the node of `comments`,
+    /// returning the id the service gave it.
+    ///
+    /// This waits for the answer, the events received meanwhile are returned by the next call
+    /// to [`Agent::wait_for_events`].
+    pub async fn publish_comment(
+        &mut self,
+        comments: &Reference,
+        entry: Element,
+    ) -> Result<ItemId, Error> {
+        let iq = pubsub::comments::publish_request(comments, entry);
+        self.query(iq, |agent, elem| {
+            pubsub::comments::publish_response(agent, comments, elem)
+        })
+        .await
+    }
+
+    /// Fetch at most `max` comments from the node of `comments`: the first ones, or those after
+    /// the [`CommentsPage::next`] of the previous page, using Result Set Management (XEP-0059).
+    ///
+    /// A service which doesn’t page the items of its nodes returns all of them at once.  This
+    /// waits for the answer, the events received meanwhile are returned by the next call to
+    /// [`Agent::wait_for_events`].
+    pub async fn fetch_comments(
+        &mut self,
+        comments: &Reference,
+        after: Option<String>,
+        max: usize,
+    ) -> Result<CommentsPage, Error> {
+        let iq = pubsub::comments::page_request(comments, after, max);
+        self.query(iq, |agent, elem| {
+            pubsub::comments::page_response(agent, comments, max, elem)
+        })
+        .await
+    }
+
+    /// Send `iq`, then handle the stanzas received until `response` recognises its answer.
+    async fn query<T, F>(&mut self, iq: Iq, response: F) -> Result<T, Error>
+    where
+        F: Fn(&Self, &Element) -> Option<Result<T, Error>>,
+    {
+        self.exchange(iq, |agent, elem| response(agent, elem).map(Step::Done))
+            .await
+    }
+
+    /// Send `iq`, then let `step` go through the stanzas received, those it doesn’t take part
+    /// being handled as usual, until it is done.  Each request sent fails with
+    /// [`Error::RequestTimeout`] if it isn’t answered in time.
+    async fn exchange<T, F>(&mut self, iq: Iq, mut step: F) -> Result<T, Error>
+    where
+        F: FnMut(&Self, &Element) -> Option<Step<T>>,
+    {
+        self.send_stanza(iq.into()).await?;
+        let mut deadline = tokio::time::Instant::now() + self.request_timeout;
+        loop {
+            let event = tokio::time::timeout_at(deadline, self.client.next())
+                .await
+                .map_err(|_| Error::RequestTimeout)?
+                .ok_or(Error::Disconnected)?;
+            if let TokioXmppEvent::Stanza(ref elem) = event {
+                match step(self, elem) {
+                    Some(Step::Consumed) => continue,
+                    Some(Step::Send(iq)) => {
+                        self.send_stanza(iq.into()).await?;
+                        deadline = tokio::time::Instant::now() + self.request_timeout;
+                        continue;
+                    }
+                    Some(Step::Done(result)) => return result,
+                    None => (),
+                }
+            }
+            let disconnected = matches!(event, TokioXmppEvent::Disconnected(_));
+            let events = self.handle_client_event(event).await;
+            self.queued_events.extend(events);
+            if disconnected {
+                return Err(Error::Disconnected);
+            }
+        }
+    }
+
+    /// Whether `elem` is the result or error iq with the given `id` coming from `jid`, and its
+    /// payload if it is.
+    fn iq_result(
+        &self,
+        elem: &Element,
+        id: &str,
+        jid: &Jid,
+    ) -> Option<Result<Option<Element>, Error>> {
+        if !elem.is("iq", ns::JABBER_CLIENT) || elem.attr("id") != Some(id) {
+            return None;
+        }
+        let iq = Iq::try_from(elem.clone()).ok()?;
+        let expected = match iq.from {
+            Some(from) => from == *jid,
+            // Our own server answers for itself or for our bare JID without a from.
+            None => match jid {
+                Jid::Bare(bare) => {
+                    *bare == self.own_jid || *bare == BareJid::domain(self.own_jid.domain.clone())
+                }
+                Jid::Full(_) => false,
+            },
+        };
+        if !expected {
+            return None;
+        }
+        match iq.payload {
+            IqType::Result(payload) => Some(Ok(payload)),
+            IqType::Error(error) => Some(Err(Error::Stanza(error))),
+            IqType::Get(_) | IqType::Set(_) => None,
+        }
+    }
+
+    /// The online resource of `jid` to address directly, for protocols which need a full JID
+    /// such as Jingle.
+    ///
+    /// This is the one with the highest priority, then the most available one, among those
+    /// which advertised `required_feature` in their entity capabilities (XEP-0115).  Resources
+    /// whose capabilities we haven’t been able to verify yet are never picked when a feature is
+    /// required, and `None` is returned if no resource is known to support it.
+    pub fn best_resource_for(
+        &self,
+        jid: BareJid,
+        required_feature: Option<&str>,
+    ) -> Option<FullJid> {
+        self.resources
+            .best(&jid, |resource| match required_feature {
+                None => true,
+                Some(required) => resource
+                    .caps
+                    .as_ref()
+                    .and_then(|caps| self.caps.features(caps))
+                    .into_iter()
+                    .flatten()
+                    .any(|feature| feature == required),
+            })
+    }
+
+    /// Our roster, as last received from the server and updated by its pushes, or as loaded from
+    /// the storage until then.
+    pub fn roster(&self) -> &[RosterItem] {
+        self.roster.items()
+    }
+
+    /// Receive every change of [`Agent::roster`] from now on, typically to update the contact
+    /// list of a user interface without having to reconcile the events itself.
+    ///
+    /// The changes are broadcast to every receiver without waiting for them: one which falls
+    /// behind by more than [`UPDATES_CAPACITY`] changes misses the oldest ones and gets
+    /// [`RecvError::Lagged`](tokio::sync::broadcast::error::RecvError::Lagged) with how many,
+    /// after which it should read the whole roster again.
+    pub fn roster_updates(&self) -> broadcast::Receiver<RosterUpdate> {
+        self.roster.subscribe()
+    }
+
+    /// Receive every change of the online resources of our contacts from now on, the same way
+    /// as [`Agent::roster_updates`].
+    ///
+    /// Every resource goes offline when we get disconnected, and comes back online once we
+    /// reconnected and received its presence again.
+    pub fn presence_updates(&self) -> broadcast::Receiver<PresenceUpdate> {
+        self.resources.subscribe()
+    }
+
+    /// Receive the features of every entity capabilities (XEP-0115) we verified from now on,
+    /// the same way as [`Agent::roster_updates`].
+    pub fn caps_updates(&self) -> broadcast::Receiver<CapsUpdate> {
+        self.caps.subscribe()
+    }
+
+    /// The bookmark of `room` (XEP-0402), if we have one.
+    pub fn bookmark(&self, room: &BareJid) -> Option<&Conference> {
+        self.bookmarks.get(room)
+    }
+
+    /// Add or replace the bookmark of `room` (XEP-0402).
+    ///
+    /// Its extensions are written back as they are, so a bookmark obtained from
+    /// [`Agent::bookmark`] or [`Event::JoinRoom`] keeps whatever other clients stored in it.
+    pub async fn set_bookmark(&mut self, room: BareJid, conference: Conference) {
+        let iq = pubsub::publish_bookmark(&room, conference.clone());
+        if self.send_stanza(iq.into()).await.is_ok() {
+            self.bookmarks.insert(room, conference);
+            self.save_bookmarks();
+        }
+    }
+
+    /// Write our bookmarks to the storage, after they changed.
+    fn save_bookmarks(&self) {
+        if let Err(err) = storage::save_bookmarks(&mut *self.storage.borrow_mut(), &self.bookmarks)
+        {
+            warn!("Couldn’t save the bookmarks: {}", err);
+        }
+    }
+
+    /// Write the roster to the storage, after receiving it or a push updating it.
+    fn save_roster(&self, roster: Roster, push: bool) {
+        let mut storage = self.storage.borrow_mut();
+        let saved = if push {
+            storage::save_roster_push(&mut *storage, &roster)
+        } else {
+            storage::save_roster(&mut *storage, &roster)
+        };
+        if let Err(err) = saved {
+            warn!("Couldn’t save the roster: {}", err);
+        }
+    }
+
+    /// The presences answering the subscription request of `from` as the [`AutoAcceptPolicy`]
+    /// says, along with [`Event::NewConversationPartner`] if it got accepted for the first time.
+    fn auto_accept(&self, from: &BareJid) -> (Vec<PresenceType>, Option<Event>) {
+        let policy = match &self.auto_accept {
+            Some(policy) => policy,
+            None => return (Vec::new(), None),
+        };
+        let in_roster = self.roster.items().iter().any(|item| item.jid == *from);
+        let answers = policy.answers(from, self.subscriptions.get(from), in_roster);
+        if answers.is_empty() {
+            return (answers, None);
+        }
+        let greeting = match storage::greet(&mut *self.storage.borrow_mut(), from) {
+            Ok(true) => Some(Event::NewConversationPartner(from.clone())),
+            Ok(false) => None,
+            Err(err) => {
+                warn!("Couldn’t save the greeting of {}: {}", from, err);
+                None
+            }
+        };
+        (answers, greeting)
+    }
+
+    /// The roster set removing `from` once it revoked our subscription, if the
+    /// [`AutoAcceptPolicy`] accepted it, the contacts added otherwise being left alone.
+    fn auto_remove(&self, from: &BareJid, type_: &PresenceType) -> Option<Iq> {
+        if self.auto_accept.is_none()
+            || *type_ != PresenceType::Unsubscribed
+            || !self.roster.items().iter().any(|item| item.jid == *from)
+        {
+            return None;
+        }
+        match storage::greeted(&*self.storage.borrow(), from) {
+            Ok(true) => (),
+            Ok(false) => return None,
+            Err(err) => {
+                warn!("Couldn’t read the greeting of {}: {}", from, err);
+                return None;
+            }
+        }
+        let removal = Roster {
+            ver: None,
+            items: vec![RosterItem {
+                jid: from.clone(),
+                name: None,
+                subscription: Subscription::Remove,
+                ask: Default::default(),
+                groups: Vec::new(),
+            }],
+        };
+        Some(Iq::from_set("auto-roster-remove", removal))
+    }
+
+    /// Write the subscription requests which became pending or got answered, from the events
+    /// of [`Subscriptions`](subscriptions::Subscriptions).
+    fn save_pending_subscriptions(&self, events: &[Event]) {
+        let changes: Vec<(BareJid, bool)> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::SubscriptionChanged { jid, old, new }
+                    if old.pending_in != new.pending_in =>
+                {
+                    Some((jid.clone(), new.pending_in))
+                }
+                _ => None,
+            })
+            .collect();
+        if changes.is_empty() {
+            return;
+        }
+        let mut storage = self.storage.borrow_mut();
+        if let Err(err) = storage::save_pending_subscriptions(&mut *storage, &changes) {
+            warn!("Couldn’t save the pending subscriptions: {}", err);
+        }
+    }
+
+    /// Change whether `room` should be joined automatically, keeping the rest of its bookmark,
+    /// or bookmarking it if it wasn’t already.
+    pub async fn set_bookmark_autojoin(&mut self, room: BareJid, autojoin: bool) {
+        let conference = self.bookmark_with_autojoin(&room, autojoin);
+        self.set_bookmark(room, conference).await
+    }
+
+    fn bookmark_with_autojoin(&self, room: &BareJid, autojoin: bool) -> Conference {
+        let mut conference = self.bookmarks.get(room).cloned().unwrap_or_default();
+        conference.autojoin = if autojoin {
+            Autojoin::True
+        } else {
+            Autojoin::False
+        };
+        conference
+    }
+
+    /// Send a chat state notification (XEP-0085) to a contact or to a room.
+    ///
+    /// If `recipient` is a room we are currently in, the notification is sent to the room as a
+    /// groupchat message, otherwise it is sent as a chat message to `recipient` directly, which
+    /// is also what a private conversation with a room occupant expects.
+    pub async fn send_chat_state(&mut self, recipient: Jid, state: ChatState) {
+        let (to, type_) = self.route_message(recipient);
+        let mut message = Message::new(Some(to));
+        message.type_ = type_;
+        message.payloads.push(state.into());
+        let _ = self.send_stanza(message.into()).await;
+    }
+
+    /// Send a directed presence to a contact or to a room.
+    ///
+    /// Rooms we are currently in only accept presence sent to our own occupant JID, so the
+    /// presence is addressed to it, every other recipient gets it unchanged.
+    pub async fn send_presence(
+        &mut self,
+        recipient: Jid,
+        show: Option<PresenceShow>,
+        lang: &str,
+        status: &str,
+    ) {
+        let mut presence =
+            Presence::new(PresenceType::None).with_to(self.route_presence(recipient));
+        presence.show = show;
+        presence.set_status(String::from(lang), String::from(status));
+        let _ = self.send_stanza(presence.into()).await;
+    }
+
+    /// Send our own presence, with our entity capabilities, directly to `to`, such as a contact
+    /// we don't share our presence with, or a service which needs it.
+    ///
+    /// Rooms we are in get it on our occupant JID, like with [`Agent::send_presence`].  Clients
+    /// often answer with their own presence, but nothing requires them to, and only servers
+    /// may probe the presence of a contact.
+    pub async fn send_directed_presence(&mut self, to: Jid) {
+        let presence = self.directed_presence(to);
+        let _ = self.send_stanza(presence.into()).await;
+    }
+
+    fn directed_presence(&self, to: Jid) -> Presence {
+        Self::make_initial_presence(&self.disco, &self.node, &*self.messages)
+            .with_to(self.route_presence(to))
+    }
+
+    /// Send a stanza once the rate limit allows it, every stanza the Agent sends goes through
+    /// here.
+    async fn send_stanza(&mut self, stanza: Element) -> Result<(), Error> {
+        if let Some(bucket) = &mut self.rate_limit {
+            let delay = bucket.take(Instant::now());
+            if delay > Duration::from_secs(0) {
+                tokio::time::sleep(delay).await;
+            }
+        }
+        self.client.send_stanza(stanza).await
+    }
+
+    fn route_message(&self, recipient: Jid) -> (Jid, MessageType) {
+        match recipient {
+            Jid::Bare(room) if self.rooms_joined.contains_key(&room) => {
+                (Jid::Bare(room), MessageType::Groupchat)
+            }
+            Jid::Full(occupant) => {
+                let room = BareJid::from(occupant.clone());
+                match self.rooms_joined.get(&room) {
+                    // Our own occupant JID, this is meant for the whole room.
+                    Some(nick) if *nick == occupant.resource => {
+                        (Jid::Bare(room), MessageType::Groupchat)
+                    }
+                    _ => (Jid::Full(occupant), MessageType::Chat),
+                }
+            }
+            recipient => (recipient, MessageType::Chat),
+        }
+    }
+
+    fn route_presence(&self, recipient: Jid) -> Jid {
+        match recipient {
+            Jid::Bare(room) => match self.rooms_joined.get(&room) {
+                Some(nick) => Jid::Full(room.with_resource(nick.clone())),
+                None => Jid::Bare(room),
+            },
+            recipient => recipient,
+        }
+    }
+
+    /// Advertise `var` in our disco#info, such as the namespace of a payload the application
+    /// starts handling, returning whether it wasn’t already.
+    ///
+    /// Our entity capabilities get broadcast again once no other feature changed for a
+    /// second, and the disco#info others saw before keeps being answered for five minutes.
+    pub fn advertise_feature(&mut self, var: &str) -> bool {
+        if self.disco.features.iter().any(|feature| feature.var == var) {
+            return false;
+        }
+        self.own_caps.changed(&self.disco, ephemeral::now());
+        self.disco.features.push(Feature::new(var));
+        true
+    }
+
+    /// Stop advertising `var` in our disco#info, returning whether it was, see
+    /// [`Agent::advertise_feature`].
+    pub fn withdraw_feature(&mut self, var: &str) -> bool {
+        let position = match self.disco.features.iter().position(|f| f.var == var) {
+            Some(position) => position,
+            None => return false,
+        };
+        self.own_caps.changed(&self.disco, ephemeral::now());
+        self.disco.features.remove(position);
+        true
+    }
+
+    /// Which protocols we implement according to our disco#info, for operators to check what
+    /// is enabled.  A feature we advertise but xmpp-parsers doesn’t know about ends up in
+    /// [`ComplianceReport::unknown`].
+    pub fn compliance_report(&self) -> ComplianceReport {
+        compliance::report(&self.disco.features)
+    }
+
+    /// Our disco#info for `node`, the one we advertised before if it is for its verification
+    /// string, so that whoever only saw our previous presence gets what it stands for.
+    fn disco_info(&mut self, node: Option<String>) -> DiscoInfoResult {
+        let previous = node
+            .as_deref()
+            .and_then(|node| node.strip_prefix(self.node.as_str()))
+            .and_then(|ver| ver.strip_prefix('#'))
+            .and_then(|ver| self.own_caps.previous(ver, ephemeral::now()));
+        let mut disco_info = match previous {
+            Some(previous) => previous.clone(),
+            None => self.disco.clone(),
+        };
+        disco_info.node = node;
+        disco_info
+    }
+
+    /// Broadcast our entity capabilities again if they changed and no other change happened
+    /// for a while, to the server and to the rooms we are in.
+    async fn broadcast_caps(&mut self) {
+        if !self.own_caps.broadcast_due(&self.disco, ephemeral::now()) {
+            return;
+        }
+        // Our initial presence will carry them once online again.
+        if self.client.bound_jid().is_none() {
+            return;
+        }
+        let mut presences = vec![Self::make_initial_presence(
+            &self.disco,
+            &self.node,
+            &*self.messages,
+        )];
+        for room in self.rooms_joined.keys() {
+            presences.push(self.directed_presence(Jid::Bare(room.clone())));
+        }
+        for presence in presences {
+            let _ = self.send_stanza(presence.into()).await;
+        }
+    }
+
+    fn make_initial_presence(
+        disco: &DiscoInfoResult,
+        node: &str,
+        messages: &dyn Messages,
+    ) -> Presence {
+        let caps_data = compute_disco(disco);
+        let hash = hash_caps(&caps_data, Algo::Sha_1).unwrap();
+        let caps = Caps::new(node, hash);
+
+        let mut presence = Presence::new(PresenceType::None);
+        if let Some(status) = messages.initial_status() {
+            presence.set_status(String::from(messages.lang()), status);
+        }
+        presence.add_payload(caps);
+        presence
+    }
+
+    async fn handle_iq(&mut self, iq: Iq) -> Vec<Event> {
+        let mut events = vec![];
+        let from = iq
+            .from
+            .clone()
+            .unwrap_or_else(|| self.client.bound_jid().unwrap().clone());
+        if let IqType::Get(payload) = iq.payload {
+            if payload.is("query", ns::DISCO_INFO) {
+                let query = DiscoInfoQuery::try_from(payload);
+                match query {
+                    Ok(query) => {
+                        let disco_info = self.disco_info(query.node);
+                        let iq = Iq::from_result(iq.id, Some(disco_info))
+                            .with_to(iq.from.unwrap())
+                            .into();
+                        let _ = self.send_stanza(iq).await;
+                    }
+                    Err(err) => {
+                        let error = messages::stanza_error(
+                            &*self.messages,
+                            ErrorType::Modify,
+                            DefinedCondition::BadRequest,
+                            self.messages.invalid_payload(&err),
+                        );
+                        let iq = Iq::from_error(iq.id, error)
+                            .with_to(iq.from.unwrap())
+                            .into();
+                        let _ = self.send_stanza(iq).await;
+                    }
+                }
+            } else {
+                // We MUST answer unhandled get iqs with a service-unavailable error.
+                let error = messages::stanza_error(
+                    &*self.messages,
+                    ErrorType::Cancel,
+                    DefinedCondition::ServiceUnavailable,
+                    self.messages.unhandled_iq(),
+                );
+                let iq = Iq::from_error(iq.id, error)
+                    .with_to(iq.from.unwrap())
+                    .into();
+                let _ = self.send_stanza(iq).await;
+            }
+        } else if let IqType::Result(Some(payload)) = iq.payload {
+            // TODO: move private iqs like this one somewhere else, for
+            // security reasons.
+            if payload.is("query", ns::ROSTER) && iq.from.is_none() {
+                let roster = Roster::try_from(payload).unwrap();
+                let changes = self.subscriptions.roster(&roster.items);
+                self.save_pending_subscriptions(&changes);
+                events.extend(changes);
+                self.roster.replace(roster.items.clone());
+                self.save_roster(roster.clone(), false);
+                for item in roster.items.into_iter() {
+                    events.push(Event::ContactAdded(item));
+                }
+            } else if payload.is("query", ns::DISCO_INFO)
+                && from == Jid::Bare(BareJid::domain(self.own_jid.domain.clone()))
+            {
+                if let Ok(disco) = DiscoInfoResult::try_from(payload) {
+                    self.server_info.pep_vcard_conversion = disco
+                        .features
+                        .contains(&Feature::new(ns::PEP_VCARD_CONVERSION));
+                }
+            } else if payload.is("query", ns::DISCO_INFO) {
+                if let Ok(disco) = DiscoInfoResult::try_from(payload) {
+                    self.caps.insert(&disco);
+                }
+            } else if payload.is("pubsub", ns::PUBSUB) {
+                let new_events = pubsub::handle_iq_result(&from, payload, self);
+                events.extend(new_events);
+            }
+        } else if let IqType::Set(payload) = iq.payload {
+            let from_own_account = match iq.from {
+                None => true,
+                Some(Jid::Bare(ref jid)) => *jid == self.own_jid,
+                Some(Jid::Full(_)) => false,
+            };
+            if payload.is("query", ns::ROSTER) && from_own_account {
+                // Roster push (RFC 6121 §2.1.6).
+                if let Ok(roster) = Roster::try_from(payload) {
+                    let mut changes = vec![];
+                    for item in roster.items.iter() {
+                        changes.extend(self.subscriptions.roster_item(item));
+                        self.roster.push(item.clone());
+                    }
+                    self.save_pending_subscriptions(&changes);
+                    events.extend(changes);
+                    self.save_roster(roster, true);
+                }
+                let mut result = Iq::from_result(iq.id, None::<Roster>);
+                result.to = iq.from;
+                let _ = self.send_stanza(result.into()).await;
+                return events;
+            }
+            if self.jingle && payload.is("jingle", ns::JINGLE) {
+                let handled = match Jingle::try_from(payload) {
+                    Ok(jingle) => self
+                        .calls
+                        .handle(from.clone(), jingle)
+                        .map_err(|refusal| refusal.into_error(&*self.messages)),
+                    Err(err) => Err(messages::stanza_error(
+                        &*self.messages,
+                        ErrorType::Modify,
+                        DefinedCondition::BadRequest,
+                        self.messages.invalid_payload(&err),
+                    )),
+                };
+                let reply = match handled {
+                    Ok(new_events) => {
+                        events.extend(new_events);
+                        Iq {
+                            from: None,
+                            to: Some(from),
+                            id: iq.id,
+                            payload: IqType::Result(None),
+                        }
+                    }
+                    Err(error) => Iq::from_error(iq.id, error).with_to(from),
+                };
+                let _ = self.send_stanza(reply.into()).await;
+                return events;
+            }
+            // We MUST answer unhandled set iqs with a service-unavailable error.
+            let error = messages::stanza_error(
+                &*self.messages,
+                ErrorType::Cancel,
+                DefinedCondition::ServiceUnavailable,
+                self.messages.unhandled_iq(),
+            );
+            let iq = Iq::from_error(iq.id, error)
+                .with_to(iq.from.unwrap())
+                .into();
+            let _ = self.send_stanza(iq).await;
+        }
+
+        events
+    }
+
+    /// The fallbacks of `message` for payloads we understand (XEP-0428).  Bodies stay intact
+    /// for clients not understanding the payloads they are a fallback for.
+    fn understood_fallbacks(&self, message: &Message) -> Vec<Fallback> {
+        message
+            .payloads
+            .iter()
+            .filter(|child| child.is("fallback", ns::FALLBACK))
+            .filter_map(|child| Fallback::try_from(child.clone()).ok())
+            .filter(|fallback| self.understood_payloads.contains(&fallback.for_))
+            .collect()
+    }
+
+    /// Whether `message` wasn’t handled before, going by the stanza-id our server stamped on it
+    /// (XEP-0359), which is remembered from now on.
+    fn first_delivery(&mut self, message: &Message) -> bool {
+        let own_jid = Jid::Bare(self.own_jid.clone());
+        let stanza_id = message
+            .payloads
+            .iter()
+            .filter(|child| child.is("stanza-id", ns::SID))
+            .filter_map(|child| StanzaId::try_from(child.clone()).ok())
+            .find(|stanza_id| stanza_id.by == own_jid);
+        let stanza_id = match stanza_id {
+            Some(stanza_id) => stanza_id,
+            None => return true,
+        };
+        let mut storage = self.storage.borrow_mut();
+        match self.seen_stanza_ids.insert(&mut *storage, &stanza_id.id) {
+            Ok(new) => new,
+            Err(err) => {
+                warn!("Couldn’t save the stanza-id of a message: {}", err);
+                true
+            }
+        }
+    }
+
+    fn message_info(message: &Message, unjoined_room: bool, fallbacks: &[Fallback]) -> MessageInfo {
+        MessageInfo {
+            id: message.id.clone(),
+            origin_id: message
+                .payloads
+                .iter()
+                .find(|child| child.is("origin-id", ns::SID))
+                .and_then(|child| OriginId::try_from(child.clone()).ok())
+                .map(|origin_id| origin_id.id),
+            ephemeral: message
+                .payloads
+                .iter()
+                .find(|child| child.is("ephemeral", ns::EPHEMERAL))
+                .and_then(|child| Ephemeral::try_from(child.clone()).ok())
+                .map(|ephemeral| ephemeral.timer),
+            unjoined_room,
+            security_label: message
+                .payloads
+                .iter()
+                .find(|child| child.is("securitylabel", ns::SEC_LABEL))
+                .and_then(|child| SecurityLabel::try_from(child.clone()).ok()),
+            unstyled: message_styling::is_unstyled(message),
+            fallbacks: fallbacks
+                .iter()
+                .map(|fallback| fallback.for_.clone())
+                .collect(),
+        }
+    }
+
+    /// The message another of our resources sent, if `message` is a carbon copy of it
+    /// (XEP-0280).  Only our own account may send those.
+    fn sent_carbon(&self, from: &Jid, message: &Message) -> Option<Message> {
+        if *from != Jid::Bare(self.own_jid.clone()) {
+            return None;
+        }
+        let sent = message
+            .payloads
+            .iter()
+            .find(|child| child.is("sent", ns::CARBONS))?;
+        carbons::Sent::try_from(sent.clone()).ok()?.forwarded.stanza
+    }
+
+    /// The [`Event::MessageSentEcho`] for `message`, as sent by us or by another of our
+    /// resources if `carbon`, unless disabled or it has no body.
+    fn sent_echo(&self, message: &Message, carbon: bool) -> Option<Event> {
+        if !self.sent_echo {
+            return None;
+        }
+        let to = message.to.clone()?;
+        let langs: Vec<&str> = self.lang.iter().map(String::as_str).collect();
+        let fallbacks = self.understood_fallbacks(message);
+        let (_lang, body) = message.get_best_body(langs)?;
+        Some(Event::MessageSentEcho {
+            to,
+            body: without_fallbacks(body, &fallbacks),
+            info: Self::message_info(message, false, &fallbacks),
+            carbon,
+        })
+    }
+
+    async fn handle_message(&mut self, message: Message) -> Vec<Event> {
+        let mut events = vec![];
         let from = message.from.clone().unwrap();
+        if message.type_ == MessageType::Error {
+            events.extend(self.message_error(from, message));
+            return events;
+        }
+        if let Some(sent) = self.sent_carbon(&from, &message) {
+            events.extend(self.sent_echo(&sent, true));
+            return events;
+        }
+        if !self.first_delivery(&message) {
+            info!("Dropping a message from {} delivered again", from);
+            return events;
+        }
+        let unjoined_room = message.type_ == MessageType::Groupchat
+            && !self.rooms_joined.contains_key(&BareJid::from(from.clone()));
+        if unjoined_room {
+            warn!("Groupchat message from {}, a room we aren’t in", from);
+            if self.drop_unjoined_room_messages {
+                return events;
+            }
+        }
         let langs: Vec<&str> = self.lang.iter().map(String::as_str).collect();
-        match message.get_best_body(langs) {
-            Some((_lang, body)) => match message.type_ {
+        let fallbacks = self.understood_fallbacks(&message);
+        let info = Self::message_info(&message, unjoined_room, &fallbacks);
+        // The body of an invitation is only there for clients not understanding it.
+        let invitation = muc::invitation(&message);
+        let body = message
+            .get_best_body(langs.clone())
+            .map(|(_lang, body)| without_fallbacks(body, &fallbacks));
+        match body.as_ref() {
+            Some(body) => match message.type_ {
                 MessageType::Groupchat => {
                     let event = Event::RoomMessage(
                         from.clone().into(),
                         FullJid::try_from(from.clone()).unwrap().resource,
                         body.clone(),
+                        info.clone(),
                     );
                     events.push(event)
                 }
                 MessageType::Chat | MessageType::Normal => {
-                    let event = Event::ChatMessage(from.clone().into(), body.clone());
-                    events.push(event)
+                    if message.type_ == MessageType::Chat {
+                        self.chat_sessions.touch(from.clone(), Instant::now());
+                    }
+                    if invitation.is_none() {
+                        let event =
+                            Event::ChatMessage(from.clone().into(), body.clone(), info.clone());
+                        events.push(event)
+                    }
                 }
                 _ => (),
             },
+            // A subject without a body changes the subject of the room (XEP-0045 §8.1), an
+            // empty one removing it.
+            None if message.type_ == MessageType::Groupchat && !message.subjects.is_empty() => {
+                let subject = message
+                    .get_best_subject(langs)
+                    .map(|(_lang, subject)| subject.0.clone())
+                    .filter(|subject| !subject.is_empty());
+                events.push(Event::RoomSubject(from.clone().into(), subject));
+            }
             None => (),
         }
+        events.extend(invitation);
+        // Rooms aren’t allowed to bother everyone at once.
+        if self.attention
+            && message.type_ != MessageType::Groupchat
+            && message
+                .payloads
+                .iter()
+                .any(|child| child.is("attention", ns::ATTENTION))
+        {
+            events.push(Event::AttentionRequested(from.clone()));
+        }
+        if let (true, Some(id), Some(timer)) = (self.enforce_ephemeral, info.id, info.ephemeral) {
+            self.expiry
+                .schedule(ephemeral::now(), timer, id, from.clone());
+        }
         for child in message.payloads {
             if child.is("event", ns::PUBSUB_EVENT) {
                 let new_events = pubsub::handle_event(&from, child, self).await;
@@ -338,113 +2342,2112 @@ impl Agent {
             }
         }
 
-        events
+        events
+    }
+
+    fn message_error(&mut self, from: Jid, message: Message) -> Option<Event> {
+        let error = message
+            .payloads
+            .into_iter()
+            .find_map(|child| StanzaError::try_from(child).ok())?;
+        let sent = message
+            .id
+            .as_ref()
+            .and_then(|id| self.sent_messages.bounced(id, &from, Instant::now()));
+        Some(match sent {
+            Some(sent) => {
+                self.outbox.bounced(&sent.id, &error);
+                Event::MessageBounced {
+                    to: sent.to,
+                    id: sent.id,
+                    body: sent.body,
+                    error,
+                }
+            }
+            None => Event::MessageError {
+                from,
+                id: message.id,
+                error,
+            },
+        })
+    }
+
+    async fn handle_presence(&mut self, presence: Presence) -> Vec<Event> {
+        let mut events = vec![];
+        if presence.type_ == PresenceType::Error {
+            let from = presence.from.unwrap();
+            let error = presence
+                .payloads
+                .into_iter()
+                .find_map(|child| StanzaError::try_from(child).ok());
+            let room = BareJid::from(from.clone());
+            let conflict = matches!(&error, Some(error) if error.defined_condition == DefinedCondition::Conflict);
+            if conflict {
+                if let Some(retry) = self.pending_joins.conflict(&room) {
+                    let _ = self.send_stanza(retry.into()).await;
+                    return events;
+                }
+            }
+            self.pending_joins.done(&room);
+            events.extend(error.map(|error| Event::PresenceError(from, error)));
+            return events;
+        }
+        let (from, nick): (BareJid, Option<RoomNick>) = match presence.from.clone().unwrap() {
+            Jid::Full(FullJid {
+                node,
+                domain,
+                resource,
+            }) => (BareJid { node, domain }, Some(resource)),
+            Jid::Bare(bare) => (bare, None),
+        };
+        match presence.type_ {
+            PresenceType::Subscribe
+            | PresenceType::Subscribed
+            | PresenceType::Unsubscribe
+            | PresenceType::Unsubscribed => {
+                let mut answers = match presence.type_ {
+                    PresenceType::Subscribe => self.gateways.subscription_request(&from),
+                    _ => Vec::new(),
+                };
+                let changes: Vec<Event> = self
+                    .subscriptions
+                    .inbound(from.clone(), &presence.type_)
+                    .into_iter()
+                    .collect();
+                self.save_pending_subscriptions(&changes);
+                events.extend(changes);
+                if answers.is_empty() && presence.type_ == PresenceType::Subscribe {
+                    let (auto_answers, greeting) = self.auto_accept(&from);
+                    answers = auto_answers;
+                    events.extend(greeting);
+                }
+                for type_ in answers {
+                    self.send_subscription(from.clone(), type_).await;
+                }
+                if let Some(removal) = self.auto_remove(&from, &presence.type_) {
+                    let _ = self.send_stanza(removal.into()).await;
+                }
+                return events;
+            }
+            _ => (),
+        }
+        events.extend(self.track_resource(&presence).await);
+        for payload in presence.payloads.into_iter() {
+            #[cfg(feature = "avatars")]
+            {
+                if payload.is("x", ns::VCARD_UPDATE) {
+                    let new_events =
+                        pubsub::avatar::handle_vcard_update(&from, self, payload).await;
+                    events.extend(new_events);
+                    continue;
+                }
+            }
+            let muc_user = match MucUser::try_from(payload) {
+                Ok(muc_user) => muc_user,
+                _ => continue,
+            };
+            if !muc_user.status.iter().any(Status::is_self_presence) {
+                continue;
+            }
+            if presence.type_ == PresenceType::Unavailable {
+                if let Some(reason) = muc_user.status.iter().find_map(Status::removal_reason) {
+                    info!("Removed from room {}: {:?}", from, reason);
+                }
+                self.rooms_joined.remove(&from);
+                events.push(Event::RoomLeft(from.clone()));
+            } else if let Some(nick) = nick.clone() {
+                self.pending_joins.done(&from);
+                self.rooms_joined.insert(from.clone(), nick.clone());
+                events.push(Event::RoomJoined(from.clone(), nick));
+            }
+        }
+
+        events
+    }
+
+    /// Keep track of the online resources of our contacts, and ask for the features of those
+    /// advertising capabilities we don’t know yet.  Returns the new availability of the contact
+    /// if it changed.
+    async fn track_resource(&mut self, presence: &Presence) -> Option<Event> {
+        let from = presence.from.clone()?;
+        // Room occupants aren’t resources of the room, and we already know about ourselves.
+        if presence
+            .payloads
+            .iter()
+            .any(|payload| payload.is("x", ns::MUC_USER))
+            || self.client.bound_jid() == Some(&from)
+        {
+            return None;
+        }
+        let contact = BareJid::from(from.clone());
+        let old = self.resources.aggregate(&contact);
+        match (from, &presence.type_) {
+            (Jid::Full(jid), PresenceType::None) => {
+                let caps = presence
+                    .payloads
+                    .iter()
+                    .find(|payload| payload.is("c", ns::CAPS))
+                    .and_then(|payload| Caps::try_from(payload.clone()).ok());
+                let node = caps.as_ref().and_then(|caps| self.caps.request(caps));
+                let status = self
+                    .lang
+                    .iter()
+                    .map(String::as_str)
+                    .chain(Some(""))
+                    .find_map(|lang| presence.statuses.get(lang))
+                    .or_else(|| presence.statuses.values().next())
+                    .cloned();
+                let idle_since = presence
+                    .payloads
+                    .iter()
+                    .find(|payload| payload.is("idle", ns::IDLE))
+                    .and_then(|payload| Idle::try_from(payload.clone()).ok())
+                    .map(|idle| idle.since);
+                let resource = resources::Resource {
+                    priority: presence.priority,
+                    show: presence.show.clone(),
+                    status,
+                    caps,
+                    idle_since,
+                };
+                self.resources.available(jid.clone(), resource);
+                if let Some(node) = node {
+                    let iq = Iq::from_get("caps-disco", DiscoInfoQuery { node: Some(node) })
+                        .with_to(Jid::Full(jid))
+                        .into();
+                    let _ = self.send_stanza(iq).await;
+                }
+            }
+            (Jid::Full(jid), PresenceType::Unavailable) => self.resources.unavailable(&jid),
+            (Jid::Bare(jid), PresenceType::Unavailable) => self.resources.all_unavailable(&jid),
+            _ => return None,
+        }
+        let new = self.resources.aggregate(&contact);
+        // Our other resources are tracked too, but we aren’t one of our contacts.
+        if new == old || contact == self.own_jid {
+            None
+        } else {
+            Some(Event::ContactPresence(contact, new))
+        }
+    }
+
+    pub async fn wait_for_events(&mut self) -> Option<Vec<Event>> {
+        if !self.queued_events.is_empty() {
+            return Some(self.queued_events.drain(..).collect());
+        }
+        if self.shut_down {
+            return None;
+        }
+        let expired = self.expiry.expire(ephemeral::now());
+        if !expired.is_empty() {
+            return Some(expired);
+        }
+        let deadline = self.expiry.next_deadline();
+        let caps_deadline = self.own_caps.next_deadline();
+        let event = tokio::select! {
+            event = self.client.next() => event,
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(ephemeral::now).into()), if deadline.is_some() => {
+                return Some(self.expiry.expire(ephemeral::now()));
+            }
+            _ = tokio::time::sleep_until(caps_deadline.unwrap_or_else(ephemeral::now).into()), if caps_deadline.is_some() => {
+                self.broadcast_caps().await;
+                return Some(Vec::new());
+            }
+            Some(events) = self.tasks.next(), if !self.tasks.is_empty() => {
+                return Some(events);
+            }
+        };
+        match event {
+            Some(event) => Some(self.handle_client_event(event).await),
+            None => None,
+        }
+    }
+
+    async fn handle_client_event(&mut self, event: TokioXmppEvent) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        match event {
+            TokioXmppEvent::Online {
+                bound_jid,
+                resumed: false,
+            } => {
+                // The server may have assigned us another node, such as after SASL ANONYMOUS.
+                self.own_jid = BareJid::from(bound_jid);
+                self.own_caps.broadcast(&self.disco, ephemeral::now());
+                let presence =
+                    Self::make_initial_presence(&self.disco, &self.node, &*self.messages).into();
+                let _ = self.send_stanza(presence).await;
+                events.push(Event::Online);
+                // TODO: only send this when the ContactList feature is enabled.
+                let iq = Iq::from_get(
+                    "roster",
+                    Roster {
+                        ver: None,
+                        items: vec![],
+                    },
+                )
+                .into();
+                let _ = self.send_stanza(iq).await;
+                // TODO: only send this when the JoinRooms feature is enabled.
+                let iq =
+                    Iq::from_get("bookmarks", PubSub::Items(Items::new(ns::BOOKMARKS2))).into();
+                let _ = self.send_stanza(iq).await;
+                let server = BareJid::domain(self.own_jid.domain.clone());
+                let iq = Iq::from_get("server-disco", DiscoInfoQuery { node: None })
+                    .with_to(Jid::Bare(server))
+                    .into();
+                let _ = self.send_stanza(iq).await;
+                self.connected = true;
+                self.flush_outbox().await;
+            }
+            TokioXmppEvent::Online { resumed: true, .. } => {
+                self.connected = true;
+                self.flush_outbox().await;
+            }
+            TokioXmppEvent::ReconnectionSuspended { until, error } => {
+                warn!(
+                    "Reconnection suspended for {:?} after: {}",
+                    until.saturating_duration_since(Instant::now()),
+                    error
+                );
+            }
+            TokioXmppEvent::ResumptionExpired {
+                window,
+                offline_for,
+            } => {
+                warn!(
+                    "Session not resumed, offline for {:?} while the server keeps it for {:?}",
+                    offline_for, window
+                );
+            }
+            TokioXmppEvent::Disconnected(_) => {
+                self.connected = false;
+                self.rooms_joined.clear();
+                self.pending_joins.clear();
+                self.resources.clear();
+                self.calls.clear();
+                self.server_info = ServerInfo::default();
+                events.push(Event::Disconnected);
+            }
+            TokioXmppEvent::Stanza(elem) => {
+                if elem.is("iq", "jabber:client") {
+                    let iq = Iq::try_from(elem).unwrap();
+                    let new_events = self.handle_iq(iq).await;
+                    events.extend(new_events);
+                } else if elem.is("message", "jabber:client") {
+                    let message = Message::try_from(elem).unwrap();
+                    let new_events = self.handle_message(message).await;
+                    events.extend(new_events);
+                } else if elem.is("presence", "jabber:client") {
+                    let presence = Presence::try_from(elem).unwrap();
+                    let new_events = self.handle_presence(presence).await;
+                    events.extend(new_events);
+                } else if elem.is("error", "http://etherx.jabber.org/streams") {
+                    println!("Received a fatal stream error: {}", String::from(&elem));
+                } else {
+                    panic!("Unknown stanza: {}", String::from(&elem));
+                }
+            }
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ephemeral, own_caps, pubsub, storage, Agent, AutoAcceptPolicy, AvatarConfig, ClientBuilder,
+        ClientType, Event, FileStorage, InvitationKind, LocationAccess, MemoryStorage,
+        MessageOptions, MucConfig, NickConflict, SubscriptionState,
+    };
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+    use tokio_xmpp::{AsyncClient as TokioXmppClient, Event as TokioXmppEvent};
+    use xmpp_parsers::{
+        bookmarks2::{Autojoin, Conference},
+        disco::Feature,
+        geoloc::Geoloc,
+        iq::Iq,
+        message::{Message, MessageType},
+        muc::muc::History,
+        ns,
+        presence::{Presence, Show as PresenceShow, Type as PresenceType},
+        pubsub::pubsub::PubSub,
+        roster::{Ask, Item as RosterItem, Roster, Subscription},
+        stanza_error::DefinedCondition,
+        BareJid, Element, FullJid, Jid,
+    };
+
+    #[tokio::test]
+    async fn test_simple() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+
+        // Client instance
+        let client_builder = ClientBuilder::new("foo@bar", "meh")
+            .set_client(ClientType::Bot, "xmpp-rs")
+            .set_website("https://gitlab.com/xmpp-rs/xmpp-rs")
+            .with_muc(MucConfig {
+                default_nick: String::from("bot"),
+                autojoin_bookmarks: false,
+                ..MucConfig::default()
+            })
+            .with_avatars(AvatarConfig::default())
+            .with_contact_list();
+
+        let mut agent: Agent = client_builder.build_impl(client).unwrap();
+
+        while let Some(events) = agent.wait_for_events().await {
+            assert!(match events[0] {
+                Event::Disconnected => true,
+                _ => false,
+            });
+            assert_eq!(events.len(), 1);
+            break;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_assigned_jid() {
+        use futures::FutureExt;
+
+        let client = TokioXmppClient::new("bar", "").unwrap();
+        let mut agent: Agent = ClientBuilder::new("bar", "").build_impl(client).unwrap();
+        let online = TokioXmppEvent::Online {
+            bound_jid: Jid::from_str("a1b2c3@bar/d4e5f6").unwrap(),
+            resumed: false,
+        };
+        // Sending our initial presence waits for a connection, the JID got adopted before.
+        assert!(agent.handle_client_event(online).now_or_never().is_none());
+        assert_eq!(agent.own_jid, BareJid::from_str("a1b2c3@bar").unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_muc_routing() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        let room = BareJid::from_str("room@muc.bar").unwrap();
+        agent.rooms_joined.insert(room.clone(), String::from("bot"));
+
+        // The room itself gets groupchat messages, and presence to our occupant JID.
+        let (to, type_) = agent.route_message(Jid::Bare(room.clone()));
+        assert_eq!(to, Jid::Bare(room.clone()));
+        assert_eq!(type_, MessageType::Groupchat);
+        assert_eq!(
+            agent.route_presence(Jid::Bare(room.clone())),
+            Jid::from_str("room@muc.bar/bot").unwrap()
+        );
+
+        // Our own occupant JID is an alias for the room.
+        let (to, type_) = agent.route_message(Jid::from_str("room@muc.bar/bot").unwrap());
+        assert_eq!(to, Jid::Bare(room.clone()));
+        assert_eq!(type_, MessageType::Groupchat);
+
+        // Other occupants are private conversations.
+        let occupant = Jid::from_str("room@muc.bar/someone").unwrap();
+        let (to, type_) = agent.route_message(occupant.clone());
+        assert_eq!(to, occupant);
+        assert_eq!(type_, MessageType::Chat);
+
+        // Contacts and rooms we are not in are left alone.
+        let contact = Jid::from_str("contact@bar").unwrap();
+        let (to, type_) = agent.route_message(contact.clone());
+        assert_eq!(to, contact);
+        assert_eq!(type_, MessageType::Chat);
+        let other_room = Jid::from_str("other@muc.bar").unwrap();
+        assert_eq!(agent.route_presence(other_room.clone()), other_room);
+    }
+
+    #[test]
+    fn test_directed_presence() {
+        use xmpp_parsers::presence::Type as PresenceType;
+
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        let contact = Jid::from_str("contact@bar").unwrap();
+        let presence = agent.directed_presence(contact.clone());
+        assert_eq!(presence.to, Some(contact));
+        assert_eq!(presence.type_, PresenceType::None);
+        // The same capabilities as our initial presence, for the contact to learn about them.
+        let initial = Agent::make_initial_presence(&agent.disco, &agent.node, &*agent.messages);
+        assert_eq!(presence.payloads, initial.payloads);
+        assert!(presence.payloads[0].is("c", ns::CAPS));
+
+        let room = BareJid::from_str("room@muc.bar").unwrap();
+        agent.rooms_joined.insert(room.clone(), String::from("bot"));
+        let presence = agent.directed_presence(Jid::Bare(room));
+        assert_eq!(
+            presence.to,
+            Some(Jid::from_str("room@muc.bar/bot").unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_room_subject() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .set_lang(vec![String::from("fr")])
+            .build_impl(client)
+            .unwrap();
+        let room = BareJid::from_str("room@muc.bar").unwrap();
+        let message = |xml: &str| Message::try_from(Element::from_str(xml).unwrap()).unwrap();
+
+        let mut events = agent
+            .handle_message(message(
+                "<message xmlns='jabber:client' from='room@muc.bar/alice' type='groupchat'>
+                    <subject>Cooking</subject>
+                    <subject xml:lang='fr'>Cuisine</subject>
+                </message>",
+            ))
+            .await;
+        match events.pop() {
+            Some(Event::RoomSubject(jid, subject)) => {
+                assert_eq!(jid, room);
+                assert_eq!(subject.as_deref(), Some("Cuisine"));
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        let mut events = agent
+            .handle_message(message(
+                "<message xmlns='jabber:client' from='room@muc.bar/alice' type='groupchat'>
+                    <subject/>
+                </message>",
+            ))
+            .await;
+        match events.pop() {
+            Some(Event::RoomSubject(jid, None)) => assert_eq!(jid, room),
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        // With a body, this is a normal message which happens to have a subject.
+        let mut events = agent
+            .handle_message(message(
+                "<message xmlns='jabber:client' from='room@muc.bar/alice' type='groupchat'>
+                    <subject>Cooking</subject>
+                    <body>Anyone has a recipe?</body>
+                </message>",
+            ))
+            .await;
+        match events.pop() {
+            Some(Event::RoomMessage(jid, nick, body, _info)) => {
+                assert_eq!(jid, room);
+                assert_eq!(nick, "alice");
+                assert_eq!(body.0, "Anyone has a recipe?");
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unjoined_room() {
+        let message = || {
+            let elem: Element = "<message xmlns='jabber:client' from='room@muc.bar/alice' type='groupchat'><body>Hi</body></message>".parse().unwrap();
+            Message::try_from(elem).unwrap()
+        };
+        let room = BareJid::from_str("room@muc.bar").unwrap();
+        let unjoined_room = |events: Vec<Event>| match &events[..] {
+            [Event::RoomMessage(_, _, _, info)] => info.unjoined_room,
+            events => panic!("Unexpected events {:?}", events),
+        };
+
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        assert!(unjoined_room(agent.handle_message(message()).await));
+        agent.rooms_joined.insert(room.clone(), String::from("bot"));
+        assert!(!unjoined_room(agent.handle_message(message()).await));
+
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .with_muc(MucConfig {
+                autojoin_bookmarks: false,
+                drop_unjoined_messages: true,
+                ..MucConfig::default()
+            })
+            .build_impl(client)
+            .unwrap();
+        assert!(agent.handle_message(message()).await.is_empty());
+        agent.rooms_joined.insert(room, String::from("bot"));
+        assert!(!unjoined_room(agent.handle_message(message()).await));
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_message() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .with_ephemeral_messages()
+            .build_impl(client)
+            .unwrap();
+        let message: Element = "<message xmlns='jabber:client' from='contact@bar/res' id='m1' type='chat'><body>Burn after reading</body><ephemeral xmlns='urn:xmpp:ephemeral:0' timer='60'/></message>"
+            .parse()
+            .unwrap();
+        let mut events = agent
+            .handle_message(Message::try_from(message).unwrap())
+            .await;
+        assert_eq!(events.len(), 1);
+        match events.pop() {
+            Some(Event::ChatMessage(jid, body, info)) => {
+                assert_eq!(jid, BareJid::from_str("contact@bar").unwrap());
+                assert_eq!(body.0, "Burn after reading");
+                assert_eq!(info.id.as_deref(), Some("m1"));
+                assert_eq!(info.ephemeral, Some(Duration::from_secs(60)));
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        // Drive the timers with a fake clock rather than waiting for a minute.
+        let now = Instant::now();
+        assert!(agent.expiry.expire(now).is_empty());
+        match agent.expiry.expire(now + Duration::from_secs(61)).pop() {
+            Some(Event::MessageExpired { id, peer }) => {
+                assert_eq!(id, "m1");
+                assert_eq!(peer, Jid::from_str("contact@bar/res").unwrap());
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+        assert_eq!(agent.expiry.next_deadline(), None);
+    }
+
+    #[tokio::test]
+    async fn test_server_disco() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        assert!(!agent.server_info().pep_vcard_conversion);
+
+        // Only our own server is trusted about that.
+        let result = "<iq xmlns='jabber:client' type='result' id='server-disco' from='{}'><query xmlns='http://jabber.org/protocol/disco#info'><identity category='server' type='im'/><feature var='http://jabber.org/protocol/disco#info'/><feature var='urn:xmpp:pep-vcard-conversion:0'/></query></iq>";
+        let iq: Element = result.replace("{}", "evil.example").parse().unwrap();
+        agent.handle_iq(Iq::try_from(iq).unwrap()).await;
+        assert!(!agent.server_info().pep_vcard_conversion);
+
+        let iq: Element = result.replace("{}", "bar").parse().unwrap();
+        agent.handle_iq(Iq::try_from(iq).unwrap()).await;
+        assert!(agent.server_info().pep_vcard_conversion);
+    }
+
+    #[tokio::test]
+    async fn test_inbound_subscription() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        let contact = BareJid::from_str("contact@bar").unwrap();
+        let presence: Element =
+            "<presence xmlns='jabber:client' from='contact@bar' type='subscribe'/>"
+                .parse()
+                .unwrap();
+        let mut events = agent
+            .handle_presence(Presence::try_from(presence).unwrap())
+            .await;
+        match events.pop() {
+            Some(Event::SubscriptionChanged { jid, old, new }) => {
+                assert_eq!(jid, contact);
+                assert_eq!(old, SubscriptionState::default());
+                assert!(new.pending_in);
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+        assert!(agent.subscription_state(&contact).pending_in);
+        assert_eq!(agent.pending_inbound_subscriptions(), [contact]);
+
+        // The same request delivered again on the next login changes nothing.
+        let presence: Element =
+            "<presence xmlns='jabber:client' from='contact@bar/res' type='subscribe'/>"
+                .parse()
+                .unwrap();
+        assert!(agent
+            .handle_presence(Presence::try_from(presence).unwrap())
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_auto_accept() {
+        let directory = std::env::temp_dir().join("xmpp-rs-test-auto-accept");
+        let _ = std::fs::remove_dir_all(&directory);
+        let build = || {
+            let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+            ClientBuilder::new("foo@bar", "meh")
+                .set_auto_accept(AutoAcceptPolicy::AcceptAll)
+                .set_storage(FileStorage::open(&directory).unwrap())
+                .build_impl(client)
+                .unwrap()
+        };
+        let presence = |xml: &str| Presence::try_from(Element::from_str(xml).unwrap()).unwrap();
+        let subscribe =
+            || presence("<presence xmlns='jabber:client' from='contact@bar' type='subscribe'/>");
+        let greetings = |events: &[Event]| {
+            events
+                .iter()
+                .filter(|event| matches!(event, Event::NewConversationPartner(_)))
+                .count()
+        };
+        let contact = BareJid::from_str("contact@bar").unwrap();
+
+        // Both sides asked at the same time, our request still pending.
+        let roster: Element = "<query xmlns='jabber:iq:roster' ver='1'><item jid='contact@bar' subscription='none' ask='subscribe'/><item jid='friend@bar' subscription='both'/></query>"
+            .parse()
+            .unwrap();
+        build().save_roster(Roster::try_from(roster).unwrap(), false);
+        let mut agent: Agent = build();
+        let events = agent.handle_presence(subscribe()).await;
+        match &events[..] {
+            [Event::SubscriptionChanged { new, .. }, Event::NewConversationPartner(jid)] => {
+                assert!(new.pending_in && new.pending_out);
+                assert_eq!(*jid, contact);
+            }
+            events => panic!("Unexpected events {:?}", events),
+        }
+        // The request delivered again isn’t a new partner.
+        assert_eq!(greetings(&agent.handle_presence(subscribe()).await), 0);
+        drop(agent);
+
+        // Nor after a restart, even if the contact unsubscribed and asked again meanwhile.
+        let mut agent: Agent = build();
+        agent
+            .handle_presence(presence(
+                "<presence xmlns='jabber:client' from='contact@bar' type='unsubscribe'/>",
+            ))
+            .await;
+        assert_eq!(greetings(&agent.handle_presence(subscribe()).await), 0);
+
+        // Someone else still is.
+        let events = agent
+            .handle_presence(presence(
+                "<presence xmlns='jabber:client' from='other@bar/res' type='subscribe'/>",
+            ))
+            .await;
+        assert_eq!(greetings(&events), 1);
+
+        // Only the contacts accepted get removed, once they revoke our subscription.
+        assert!(agent
+            .auto_remove(&contact, &PresenceType::Unsubscribe)
+            .is_none());
+        assert!(agent
+            .auto_remove(&contact, &PresenceType::Unsubscribed)
+            .is_some());
+        let friend = BareJid::from_str("friend@bar").unwrap();
+        assert!(agent
+            .auto_remove(&friend, &PresenceType::Unsubscribed)
+            .is_none());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_auto_accept_roster_only() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .set_auto_accept(AutoAcceptPolicy::AcceptRosterOnly)
+            .build_impl(client)
+            .unwrap();
+        let presence: Element =
+            "<presence xmlns='jabber:client' from='stranger@bar' type='subscribe'/>"
+                .parse()
+                .unwrap();
+        let events = agent
+            .handle_presence(Presence::try_from(presence).unwrap())
+            .await;
+        assert!(matches!(events[..], [Event::SubscriptionChanged { .. }]));
+        // Left to the application.
+        assert_eq!(
+            agent.pending_inbound_subscriptions(),
+            [BareJid::from_str("stranger@bar").unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_room_config_response() {
+        use super::Error;
+
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        let room = Jid::from_str("room@muc.bar").unwrap();
+        let response = |xml: &str| agent.room_config_response(&room, &xml.parse().unwrap());
+
+        let form = response("<iq xmlns='jabber:client' type='result' id='room-config' from='room@muc.bar'><query xmlns='http://jabber.org/protocol/muc#owner'><x xmlns='jabber:x:data' type='form'><field var='FORM_TYPE' type='hidden'><value>http://jabber.org/protocol/muc#roomconfig</value></field><field var='muc#roomconfig_persistentroom' type='boolean'><value>0</value></field></x></query></iq>")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            form.form_type.as_deref(),
+            Some("http://jabber.org/protocol/muc#roomconfig")
+        );
+        assert_eq!(form.fields[0].var, "muc#roomconfig_persistentroom");
+
+        // Unsolicited forms, from other rooms, are left to the rest of the agent.
+        assert!(response("<iq xmlns='jabber:client' type='result' id='room-config' from='other@muc.bar'><query xmlns='http://jabber.org/protocol/muc#owner'/></iq>").is_none());
+
+        match response(
+            "<iq xmlns='jabber:client' type='result' id='room-config' from='room@muc.bar'/>",
+        ) {
+            Some(Err(Error::Protocol(_))) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        match response("<iq xmlns='jabber:client' type='error' id='room-config' from='room@muc.bar'><error type='auth'><forbidden xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/></error></iq>") {
+            Some(Err(Error::Stanza(error))) => {
+                assert_eq!(error.defined_condition, DefinedCondition::Forbidden)
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_resource() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        let contact = BareJid::from_str("contact@bar").unwrap();
+        let presence = |xml: &str| Presence::try_from(Element::from_str(xml).unwrap()).unwrap();
+
+        agent
+            .handle_presence(presence(
+                "<presence xmlns='jabber:client' from='contact@bar/desktop'>
+                    <idle xmlns='urn:xmpp:idle:1' since='2021-06-01T10:00:00Z'/>
+                </presence>",
+            ))
+            .await;
+        agent
+            .handle_presence(presence(
+                "<presence xmlns='jabber:client' from='contact@bar/phone'/>",
+            ))
+            .await;
+        assert_eq!(
+            agent.best_resource_for(contact.clone(), None),
+            Some(contact.clone().with_resource("phone"))
+        );
+
+        // The phone went idle earlier than the desktop, once in the same timezone.
+        agent
+            .handle_presence(presence(
+                "<presence xmlns='jabber:client' from='contact@bar/phone'>
+                    <idle xmlns='urn:xmpp:idle:1' since='2021-06-01T11:00:00+02:00'/>
+                </presence>",
+            ))
+            .await;
+        assert_eq!(
+            agent.best_resource_for(contact.clone(), None),
+            Some(contact.clone().with_resource("desktop"))
+        );
+    }
+
+    #[test]
+    fn test_last_activity_response() {
+        use super::{Error, LastActivity};
+
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        let server = Jid::from_str("bar").unwrap();
+        let response = |xml: &str| agent.last_activity_response(&server, &xml.parse().unwrap());
+
+        let uptime = response(
+            "<iq xmlns='jabber:client' type='result' id='last-activity' from='bar'>
+                <query xmlns='jabber:iq:last' seconds='86400'/>
+            </iq>",
+        );
+        assert_eq!(
+            uptime.unwrap().unwrap(),
+            LastActivity {
+                elapsed: Duration::from_secs(86400),
+                status: None,
+            }
+        );
+
+        // Answers to other queries, or from other entities, are left to the rest of the agent.
+        assert!(
+            response("<iq xmlns='jabber:client' type='result' id='roster' from='bar'/>").is_none()
+        );
+        assert!(response(
+            "<iq xmlns='jabber:client' type='result' id='last-activity' from='evil@bar'>
+                <query xmlns='jabber:iq:last' seconds='0'/>
+            </iq>"
+        )
+        .is_none());
+
+        let error = response(
+            "<iq xmlns='jabber:client' type='error' id='last-activity' from='bar'>
+                <error type='cancel'>
+                    <service-unavailable xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>
+                </error>
+            </iq>",
+        );
+        match error {
+            Some(Err(Error::Stanza(_))) => (),
+            error => panic!("{:?}", error),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        use super::Error;
+        use futures::StreamExt;
+        use tokio_xmpp::{mini_server::MiniServer, AsyncConfig, AsyncServerConfig};
+
+        fn client(jid: &str, password: &str, port: u16) -> TokioXmppClient {
+            TokioXmppClient::new_with_config(AsyncConfig::new(
+                Jid::from_str(jid).unwrap(),
+                password,
+                AsyncServerConfig::Loopback { port },
+            ))
+        }
+
+        async fn run(port: u16) {
+            // Bob gets the query, and never answers it.
+            let mut bob = client("bob@localhost", "bob", port);
+            let bob_jid = match bob.next().await {
+                Some(TokioXmppEvent::Online { bound_jid, .. }) => bound_jid,
+                event => panic!("Unexpected event {:?}", event),
+            };
+            let mut agent: Agent = ClientBuilder::new("foo@localhost", "foo")
+                .set_request_timeout(Duration::from_millis(200))
+                .build_impl(client("foo@localhost", "foo", port))
+                .unwrap();
+            loop {
+                let events = agent.wait_for_events().await.unwrap();
+                if events.iter().any(|event| matches!(event, Event::Online)) {
+                    break;
+                }
+            }
+            let start = Instant::now();
+            match agent.last_activity(bob_jid).await {
+                Err(Error::RequestTimeout) => (),
+                result => panic!("Unexpected result {:?}", result),
+            }
+            assert!(start.elapsed() >= Duration::from_millis(200));
+        }
+
+        let server = MiniServer::new("localhost")
+            .user("foo", "foo")
+            .user("bob", "bob");
+        let (port, server) = server.listen(0).await.unwrap();
+        tokio::select! {
+            result = server => panic!("Server stopped: {:?}", result),
+            () = run(port) => (),
+        }
     }
 
-    async fn handle_presence(&mut self, presence: Presence) -> Vec<Event> {
-        let mut events = vec![];
-        let from: BareJid = match presence.from.clone().unwrap() {
-            Jid::Full(FullJid { node, domain, .. }) => BareJid { node, domain },
-            Jid::Bare(bare) => bare,
+    #[tokio::test]
+    async fn test_best_resource() {
+        use tokio::time::timeout;
+        use xmpp_parsers::{
+            caps::{compute_disco, hash_caps, Caps},
+            disco::{DiscoInfoResult, Feature, Identity},
+            hashes::Algo,
+            presence::Type as PresenceType,
         };
-        for payload in presence.payloads.into_iter() {
-            let muc_user = match MucUser::try_from(payload) {
-                Ok(muc_user) => muc_user,
-                _ => continue,
+
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        let contact = BareJid::from_str("contact@bar").unwrap();
+        let phone = contact.clone().with_resource("phone");
+        let desktop = contact.clone().with_resource("desktop");
+
+        // Learn the features of both clients, as if they had answered our disco#info queries.
+        let mut known_caps = |features: &[&str]| {
+            let mut disco = DiscoInfoResult {
+                node: None,
+                identities: vec![Identity::new("client", "pc", "en", "Client")],
+                features: features.iter().map(|var| Feature::new(*var)).collect(),
+                extensions: vec![],
+                unknown: vec![],
             };
-            for status in muc_user.status.into_iter() {
-                if status == Status::SelfPresence {
-                    events.push(Event::RoomJoined(from.clone()));
-                    break;
+            let hash = hash_caps(&compute_disco(&disco), Algo::Sha_1).unwrap();
+            let caps = Caps::new("https://client.example", hash);
+            disco.node = agent.caps.request(&caps);
+            assert!(agent.caps.insert(&disco));
+            caps
+        };
+        let phone_caps = known_caps(&[ns::DISCO_INFO]);
+        let desktop_caps = known_caps(&[ns::DISCO_INFO, ns::JINGLE, ns::JINGLE_RTP]);
+
+        let presence = |from: &FullJid, priority: i8, caps: &Caps| {
+            let mut presence = Presence::new(PresenceType::None);
+            presence.from = Some(Jid::Full(from.clone()));
+            presence.priority = priority;
+            presence.add_payload(caps.clone());
+            presence
+        };
+        // Both are already known, so nothing gets sent.
+        let wait = Duration::from_millis(100);
+        timeout(
+            wait,
+            agent.handle_presence(presence(&phone, 10, &phone_caps)),
+        )
+        .await
+        .unwrap();
+        timeout(
+            wait,
+            agent.handle_presence(presence(&desktop, 0, &desktop_caps)),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            agent.best_resource_for(contact.clone(), None),
+            Some(phone.clone())
+        );
+        assert_eq!(
+            agent.best_resource_for(contact.clone(), Some(ns::JINGLE_RTP)),
+            Some(desktop.clone())
+        );
+        assert_eq!(
+            agent.best_resource_for(contact.clone(), Some(ns::JINGLE_FT)),
+            None
+        );
+
+        // Negative priorities don’t prevent picking a resource.
+        let mut update = presence(&desktop, -1, &desktop_caps);
+        update.show = Some(PresenceShow::Away);
+        agent.handle_presence(update).await;
+        assert_eq!(
+            agent.best_resource_for(contact.clone(), Some(ns::JINGLE_RTP)),
+            Some(desktop.clone())
+        );
+
+        // A new version of the desktop client isn’t trusted before we could verify it.
+        let unknown = Caps::new(
+            "https://client.example",
+            hash_caps(b"something else", Algo::Sha_1).unwrap(),
+        );
+        timeout(wait, agent.handle_presence(presence(&desktop, 0, &unknown)))
+            .await
+            .expect_err("no disco#info query was sent");
+        assert_eq!(
+            agent.best_resource_for(contact.clone(), Some(ns::JINGLE_RTP)),
+            None
+        );
+        assert_eq!(agent.best_resource_for(contact, None), Some(phone));
+    }
+
+    #[tokio::test]
+    async fn test_contact_presence() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        let contact = BareJid::from_str("contact@bar").unwrap();
+        let presence = |xml: &str| Presence::try_from(xml.parse::<Element>().unwrap()).unwrap();
+
+        let mut events = agent
+            .handle_presence(presence("<presence xmlns='jabber:client' from='contact@bar/phone'><show>away</show><status xml:lang='fr'>Absent</status><status xml:lang='en'>Away</status></presence>"))
+            .await;
+        match events.pop() {
+            Some(Event::ContactPresence(jid, presence)) => {
+                assert_eq!(jid, contact);
+                assert_eq!(presence.best_show, Some(PresenceShow::Away));
+                assert_eq!(
+                    presence.statuses_by_resource.get("phone"),
+                    Some(&Some(String::from("Away")))
+                );
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        // Nothing changed, so nothing to report.
+        assert!(agent
+            .handle_presence(presence("<presence xmlns='jabber:client' from='contact@bar/phone'><show>away</show><status xml:lang='en'>Away</status></presence>"))
+            .await
+            .is_empty());
+
+        // Room occupants aren’t contacts.
+        assert!(agent
+            .handle_presence(presence("<presence xmlns='jabber:client' from='room@muc.bar/someone'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='none' role='participant'/></x></presence>"))
+            .await
+            .is_empty());
+
+        let mut events = agent
+            .handle_presence(presence(
+                "<presence xmlns='jabber:client' from='contact@bar/phone' type='unavailable'/>",
+            ))
+            .await;
+        match events.pop() {
+            Some(Event::ContactPresence(jid, presence)) => {
+                assert_eq!(jid, contact);
+                assert!(!presence.is_online());
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    /// Replays the capture in `XMPP_CAPTURE`, or the one of tokio-xmpp, through the Agent
+    #[tokio::test]
+    async fn test_replay_capture() {
+        use futures::stream::StreamExt;
+        use tokio_xmpp::{capture, Event as TokioXmppEvent, Packet};
+
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        let path = std::env::var_os("XMPP_CAPTURE").unwrap_or_else(|| {
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/../tokio-xmpp/tests/captures/login.xmppcap"
+            )
+            .into()
+        });
+        let mut events = Vec::new();
+        for connection in capture::read_capture_file(path).unwrap() {
+            let mut packets = capture::packets(connection);
+            // What comes before binding gets handled by tokio-xmpp.
+            let mut online = false;
+            while let Some(packet) = packets.next().await {
+                match packet.unwrap() {
+                    Packet::Stanza(stanza) if !online => {
+                        online = stanza.is("iq", ns::JABBER_CLIENT)
+                            && stanza.has_child("bind", ns::BIND);
+                    }
+                    Packet::Stanza(stanza) if stanza.ns() == ns::JABBER_CLIENT => {
+                        let event = TokioXmppEvent::Stanza(stanza);
+                        events.extend(agent.handle_client_event(event).await);
+                    }
+                    _ => (),
                 }
             }
         }
+        if std::env::var_os("XMPP_CAPTURE").is_none() {
+            assert!(events
+                .iter()
+                .any(|event| matches!(event, Event::ChatMessage(..))));
+        }
+    }
 
-        events
+    #[tokio::test]
+    async fn test_location() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .with_locations(LocationAccess::Whitelist)
+            .build_impl(client)
+            .unwrap();
+        let notify = Feature::new(format!("{}+notify", ns::GEOLOC));
+        assert!(agent.disco.features.contains(&notify));
+
+        // Stopping publishing is an empty geoloc, and the node stays restricted.
+        let published = Element::from(pubsub::publish_location(
+            Geoloc::default(),
+            agent.location_access,
+        ));
+        let pubsub =
+            PubSub::try_from(published.get_child("pubsub", ns::PUBSUB).unwrap().clone()).unwrap();
+        let (mut items, form) = match pubsub {
+            PubSub::Publish {
+                publish,
+                publish_options: Some(options),
+            } => (publish.items, options.form.unwrap()),
+            pubsub => panic!("Unexpected pubsub {:?}", pubsub),
+        };
+        let access_model = form
+            .fields
+            .iter()
+            .find(|field| field.var == "pubsub#access_model")
+            .unwrap();
+        assert_eq!(access_model.values, ["whitelist"]);
+        let payload = items.pop().unwrap().0.payload.unwrap();
+        assert_eq!(
+            payload,
+            "<geoloc xmlns='http://jabber.org/protocol/geoloc'/>"
+                .parse::<Element>()
+                .unwrap()
+        );
+
+        let notification = |geoloc: &str| {
+            let message: Element = format!(
+                "<message xmlns='jabber:client' from='juliet@capulet.lit'><event xmlns='http://jabber.org/protocol/pubsub#event'><items node='http://jabber.org/protocol/geoloc'><item id='current'>{}</item></items></event></message>",
+                geoloc
+            )
+            .parse()
+            .unwrap();
+            Message::try_from(message).unwrap()
+        };
+        let juliet = Jid::from_str("juliet@capulet.lit").unwrap();
+        let mut events = agent
+            .handle_message(notification(
+                "<geoloc xmlns='http://jabber.org/protocol/geoloc'><lat>45.44</lat><lon>12.33</lon><locality>Venice</locality></geoloc>",
+            ))
+            .await;
+        match events.pop() {
+            Some(Event::ContactLocation(jid, Some(geoloc))) => {
+                assert_eq!(jid, juliet);
+                assert_eq!(geoloc.lat, Some(45.44));
+                assert_eq!(geoloc.lon, Some(12.33));
+                assert_eq!(geoloc.locality.as_deref(), Some("Venice"));
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+        let mut events = agent
+            .handle_message(notification(
+                "<geoloc xmlns='http://jabber.org/protocol/geoloc'/>",
+            ))
+            .await;
+        match events.pop() {
+            Some(Event::ContactLocation(jid, None)) => assert_eq!(jid, juliet),
+            event => panic!("Unexpected event {:?}", event),
+        }
     }
 
-    pub async fn wait_for_events(&mut self) -> Option<Vec<Event>> {
-        if let Some(event) = self.client.next().await {
-            let mut events = Vec::new();
-
-            match event {
-                TokioXmppEvent::Online { resumed: false, .. } => {
-                    let presence = Self::make_initial_presence(&self.disco, &self.node).into();
-                    let _ = self.client.send_stanza(presence).await;
-                    events.push(Event::Online);
-                    // TODO: only send this when the ContactList feature is enabled.
-                    let iq = Iq::from_get(
-                        "roster",
-                        Roster {
-                            ver: None,
-                            items: vec![],
-                        },
-                    )
-                    .into();
-                    let _ = self.client.send_stanza(iq).await;
-                    // TODO: only send this when the JoinRooms feature is enabled.
-                    let iq =
-                        Iq::from_get("bookmarks", PubSub::Items(Items::new(ns::BOOKMARKS2))).into();
-                    let _ = self.client.send_stanza(iq).await;
+    #[tokio::test]
+    async fn test_bookmark_round_trip() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .with_muc(MucConfig::default())
+            .build_impl(client)
+            .unwrap();
+        let room = BareJid::from_str("room@muc.bar").unwrap();
+        let extension: Element =
+            "<state xmlns='http://myclient.example/bookmark/state' minimized='true'><pinned>1</pinned></state>"
+                .parse()
+                .unwrap();
+        let conference = Conference {
+            autojoin: Autojoin::True,
+            name: Some(String::from("Room")),
+            nick: Some(String::from("bot")),
+            password: Some(String::from("secret")),
+            extensions: vec![extension.clone()],
+        };
+
+        // The notification of our own publication, as the server would send it back.
+        let published = Element::from(pubsub::publish_bookmark(&room, conference));
+        let item = published
+            .get_child("pubsub", ns::PUBSUB)
+            .and_then(|pubsub| pubsub.get_child("publish", ns::PUBSUB))
+            .and_then(|publish| publish.get_child("item", ns::PUBSUB))
+            .unwrap();
+        assert_eq!(item.attr("id"), Some("room@muc.bar"));
+        let message: Element = format!(
+            "<message xmlns='jabber:client' from='foo@bar'><event xmlns='http://jabber.org/protocol/pubsub#event'><items node='urn:xmpp:bookmarks:1'><item id='room@muc.bar'>{}</item></items></event></message>",
+            String::from(item.get_child("conference", ns::BOOKMARKS2).unwrap())
+        )
+        .parse()
+        .unwrap();
+        let mut events = agent
+            .handle_message(Message::try_from(message).unwrap())
+            .await;
+        match events.pop() {
+            Some(Event::JoinRoom(jid, conference)) => {
+                assert_eq!(jid, room);
+                assert_eq!(conference.nick.as_deref(), Some("bot"));
+                assert_eq!(conference.password.as_deref(), Some("secret"));
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        // Disabling autojoin writes the extension back untouched.
+        let updated = agent.bookmark_with_autojoin(&room, false);
+        let published = Element::from(pubsub::publish_bookmark(&room, updated));
+        let pubsub =
+            PubSub::try_from(published.get_child("pubsub", ns::PUBSUB).unwrap().clone()).unwrap();
+        let mut items = match pubsub {
+            PubSub::Publish {
+                publish,
+                publish_options: Some(_),
+            } => publish.items,
+            pubsub => panic!("Unexpected pubsub {:?}", pubsub),
+        };
+        let conference = Conference::try_from(items.pop().unwrap().0.payload.unwrap()).unwrap();
+        assert_eq!(conference.autojoin, Autojoin::False);
+        assert_eq!(conference.name.as_deref(), Some("Room"));
+        assert_eq!(conference.nick.as_deref(), Some("bot"));
+        assert_eq!(conference.extensions, [extension]);
+    }
+
+    #[cfg(feature = "avatars")]
+    #[tokio::test]
+    async fn test_vcard_update_echo() {
+        use futures::StreamExt;
+        use tokio_xmpp::{mini_server::MiniServer, AsyncConfig, AsyncServerConfig};
+        use xmpp_parsers::iq::IqType;
+
+        /// The iqs the server sent back to the agent, up to the answer to a ping sent after
+        /// everything the agent wrote so far, which the server handles in order.
+        async fn replies_until_ping(agent: &mut Agent, id: &str) -> Vec<Iq> {
+            let ping: Element = format!(
+                "<iq xmlns='jabber:client' type='get' id='{}'><ping xmlns='urn:xmpp:ping'/></iq>",
+                id
+            )
+            .parse()
+            .unwrap();
+            agent.client.send_stanza(ping).await.unwrap();
+            let mut replies = Vec::new();
+            loop {
+                let elem = match agent.client.next().await.unwrap().into_stanza() {
+                    Some(elem) if elem.is("iq", ns::JABBER_CLIENT) => elem,
+                    _ => continue,
+                };
+                let iq = Iq::try_from(elem).unwrap();
+                if iq.id == id {
+                    return replies;
                 }
-                TokioXmppEvent::Online { resumed: true, .. } => {}
-                TokioXmppEvent::Disconnected(_) => {
-                    events.push(Event::Disconnected);
+                replies.push(iq);
+            }
+        }
+
+        async fn run(port: u16) {
+            let client = TokioXmppClient::new_with_config(AsyncConfig::new(
+                Jid::from_str("foo@localhost").unwrap(),
+                "foo",
+                AsyncServerConfig::Loopback { port },
+            ));
+            let mut agent: Agent = ClientBuilder::new("foo@localhost", "foo")
+                .with_avatars(AvatarConfig::default())
+                .build_impl(client)
+                .unwrap();
+            loop {
+                let events = agent.wait_for_events().await.unwrap();
+                if events.iter().any(|event| matches!(event, Event::Online)) {
+                    break;
                 }
-                TokioXmppEvent::Stanza(elem) => {
-                    if elem.is("iq", "jabber:client") {
-                        let iq = Iq::try_from(elem).unwrap();
-                        let new_events = self.handle_iq(iq).await;
-                        events.extend(new_events);
-                    } else if elem.is("message", "jabber:client") {
-                        let message = Message::try_from(elem).unwrap();
-                        let new_events = self.handle_message(message).await;
-                        events.extend(new_events);
-                    } else if elem.is("presence", "jabber:client") {
-                        let presence = Presence::try_from(elem).unwrap();
-                        let new_events = self.handle_presence(presence).await;
-                        events.extend(new_events);
-                    } else if elem.is("error", "http://etherx.jabber.org/streams") {
-                        println!("Received a fatal stream error: {}", String::from(&elem));
-                    } else {
-                        panic!("Unknown stanza: {}", String::from(&elem));
-                    }
+            }
+            agent.server_info.pep_vcard_conversion = true;
+            let hash = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+            agent.published_avatars.push_back(String::from(hash));
+            let presence = |from: &str| {
+                let elem: Element = format!("<presence xmlns='jabber:client' from='{}'><x xmlns='vcard-temp:x:update'><photo>{}</photo></x></presence>", from, hash).parse().unwrap();
+                Presence::try_from(elem).unwrap()
+            };
+            // Only the PEP fetch goes to a contact, the server refuses it as it has no PEP.
+            let is_fetch = |iq: &Iq| {
+                iq.id == "coucou"
+                    && iq.from == Some(Jid::from_str("contact@localhost").unwrap())
+                    && matches!(iq.payload, IqType::Error(_))
+            };
+
+            // The mirrored echo of the avatar we just published is ignored.
+            let events = agent.handle_presence(presence("foo@localhost/other")).await;
+            assert!(events.is_empty());
+            let replies = replies_until_ping(&mut agent, "echo").await;
+            assert!(!replies.iter().any(|iq| iq.id == "coucou"));
+
+            // A contact’s new avatar gets fetched from PEP, which is authoritative.
+            let events = agent
+                .handle_presence(presence("contact@localhost/res"))
+                .await;
+            assert!(events.is_empty());
+            let replies = replies_until_ping(&mut agent, "contact").await;
+            assert_eq!(replies.iter().filter(|iq| is_fetch(iq)).count(), 1);
+
+            // Without conversion we have nowhere to fetch it from.
+            agent.server_info.pep_vcard_conversion = false;
+            let events = agent
+                .handle_presence(presence("contact@localhost/res"))
+                .await;
+            assert!(events.is_empty());
+            let replies = replies_until_ping(&mut agent, "unconverted").await;
+            assert!(!replies.iter().any(|iq| iq.id == "coucou"));
+        }
+
+        let server = MiniServer::new("localhost").user("foo", "foo");
+        let (port, server) = server.listen(0).await.unwrap();
+        tokio::select! {
+            result = server => panic!("Server stopped: {:?}", result),
+            () = run(port) => (),
+        }
+    }
+
+    #[cfg(feature = "avatars")]
+    #[tokio::test]
+    async fn test_avatar_http_fetcher() {
+        use std::cell::RefCell;
+        use std::fs;
+        use std::rc::Rc;
+        use xmpp_parsers::{caps::hash_caps, hashes::Algo};
+
+        let data = b"not really a png".to_vec();
+        let hash = hash_caps(&data, Algo::Sha_1).unwrap().to_hex();
+        let urls = Rc::new(RefCell::new(Vec::new()));
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let fetched = urls.clone();
+        let served = data.clone();
+        let directory = std::env::temp_dir().join("xmpp-rs-test-http-fetcher");
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .with_avatars(
+                AvatarConfig {
+                    cache_dir: Some(directory.clone()),
+                    max_dimension: Some(256),
+                    ..AvatarConfig::default()
                 }
+                .with_http_fetcher(move |url| {
+                    fetched.borrow_mut().push(url);
+                    let served = served.clone();
+                    async move { Ok(served) }
+                }),
+            )
+            .build_impl(client)
+            .unwrap();
+        let metadata = |from: &str, id: &str| {
+            let elem: Element = format!(
+                "<message xmlns='jabber:client' from='{from}'>
+                    <event xmlns='http://jabber.org/protocol/pubsub#event'>
+                        <items node='urn:xmpp:avatar:metadata'>
+                            <item id='{id}'>
+                                <metadata xmlns='urn:xmpp:avatar:metadata'>
+                                    <info bytes='{bytes}' id='{id}' type='image/png' width='32' height='32'/>
+                                    <info bytes='{bytes}' id='{id}' type='image/png' width='128' height='128' url='https://example.org/{from}.png'/>
+                                </metadata>
+                            </item>
+                        </items>
+                    </event>
+                </message>",
+                from = from,
+                id = id,
+                bytes = data.len(),
+            )
+            .parse()
+            .unwrap();
+            Message::try_from(elem).unwrap()
+        };
+
+        let jid = "http-avatar@bar";
+        let events = agent.handle_message(metadata(jid, &hash)).await;
+        assert!(events.is_empty());
+        assert_eq!(*urls.borrow(), [format!("https://example.org/{}.png", jid)]);
+        let events = agent.tasks.next().await.unwrap();
+        let path = directory.join(jid).join(&hash).display().to_string();
+        match &events[..] {
+            [Event::AvatarRetrieved(from, saved, variants)] => {
+                assert_eq!(from, &Jid::from_str(jid).unwrap());
+                assert_eq!(saved, &path);
+                assert_eq!(variants.len(), 2);
             }
+            events => panic!("Unexpected events {:?}", events),
+        }
+        assert_eq!(fs::read(&path).unwrap(), data);
+        // Known from now on, so it doesn’t get downloaded again.
+        let events = agent.handle_message(metadata(jid, &hash)).await;
+        assert_eq!(urls.borrow().len(), 1);
+        assert!(matches!(&events[..], [Event::AvatarRetrieved(_, saved, _)] if *saved == path));
 
-            Some(events)
-        } else {
-            None
+        // Data not matching its hash gets thrown away.
+        let jid = "http-avatar-mismatch@bar";
+        let wrong = hash_caps(b"something else", Algo::Sha_1).unwrap().to_hex();
+        let events = agent.handle_message(metadata(jid, &wrong)).await;
+        assert_eq!(urls.borrow().len(), 2);
+        assert!(events.is_empty());
+        assert!(agent.tasks.next().await.unwrap().is_empty());
+        assert!(fs::metadata(directory.join(jid)).is_err());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_storage() {
+        let room = BareJid::from_str("room@muc.bar").unwrap();
+        let contact = BareJid::from_str("contact@bar").unwrap();
+        let mut storage = MemoryStorage::new();
+        let conference = Conference {
+            autojoin: Autojoin::True,
+            nick: Some(String::from("bot")),
+            ..Conference::default()
+        };
+        let bookmarks = vec![(room.clone(), conference)].into_iter().collect();
+        storage::save_bookmarks(&mut storage, &bookmarks).unwrap();
+        let roster = Roster {
+            ver: None,
+            items: vec![RosterItem {
+                jid: contact.clone(),
+                name: None,
+                subscription: Subscription::Both,
+                ask: Ask::None,
+                groups: vec![],
+            }],
+        };
+        storage::save_roster(&mut storage, &roster).unwrap();
+
+        // What was stored is known right away.
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .set_storage(storage)
+            .build_impl(client)
+            .unwrap();
+        assert_eq!(
+            agent
+                .bookmark(&room)
+                .and_then(|conference| conference.nick.clone()),
+            Some(String::from("bot"))
+        );
+        let state = agent.subscription_state(&contact);
+        assert!(state.to && state.from);
+        assert_eq!(agent.roster(), &roster.items[..]);
+
+        // And what changes gets stored.
+        let elem: Element = "<message xmlns='jabber:client' from='foo@bar'><event xmlns='http://jabber.org/protocol/pubsub#event'><items node='urn:xmpp:bookmarks:1'><retract id='room@muc.bar'/></items></event></message>"
+            .parse()
+            .unwrap();
+        agent.handle_message(Message::try_from(elem).unwrap()).await;
+        assert!(storage::load_bookmarks(&*agent.storage.borrow())
+            .unwrap()
+            .is_empty());
+        let roster = |xml: &str| Roster::try_from(Element::from_str(xml).unwrap()).unwrap();
+        agent.save_roster(
+            roster("<query xmlns='jabber:iq:roster' ver='2'><item jid='other@bar' subscription='to'/></query>"),
+            false,
+        );
+        agent.save_roster(
+            roster("<query xmlns='jabber:iq:roster' ver='3'><item jid='contact@bar' subscription='from'/></query>"),
+            true,
+        );
+        let stored = storage::load_roster(&*agent.storage.borrow())
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.ver.as_deref(), Some("3"));
+        assert_eq!(stored.items.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_restart() {
+        let directory = std::env::temp_dir().join("xmpp-rs-test-restart");
+        let _ = std::fs::remove_dir_all(&directory);
+        let build = || {
+            let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+            ClientBuilder::new("foo@bar", "meh")
+                .set_storage(FileStorage::open(&directory).unwrap())
+                .build_impl(client)
+                .unwrap()
+        };
+        let contact = BareJid::from_str("contact@bar").unwrap();
+        let message = || {
+            let elem: Element = "<message xmlns='jabber:client' from='friend@bar/res' type='chat'><body>Hi</body><stanza-id xmlns='urn:xmpp:sid:0' id='abc' by='foo@bar'/></message>"
+                .parse()
+                .unwrap();
+            Message::try_from(elem).unwrap()
+        };
+
+        let mut agent: Agent = build();
+        let roster: Element = "<query xmlns='jabber:iq:roster' ver='ver7'><item jid='friend@bar' subscription='both'/></query>"
+            .parse()
+            .unwrap();
+        agent.save_roster(Roster::try_from(roster).unwrap(), false);
+        let presence: Element =
+            "<presence xmlns='jabber:client' from='contact@bar' type='subscribe'/>"
+                .parse()
+                .unwrap();
+        agent
+            .handle_presence(Presence::try_from(presence).unwrap())
+            .await;
+        assert_eq!(agent.handle_message(message()).await.len(), 1);
+        // Delivered again, for instance because it wasn’t acknowledged.
+        assert!(agent.handle_message(message()).await.is_empty());
+        drop(agent);
+
+        let mut agent: Agent = build();
+        let stored = storage::load_roster(&*agent.storage.borrow())
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.ver.as_deref(), Some("ver7"));
+        assert_eq!(agent.roster().len(), 1);
+        assert!(agent.subscription_state(&stored.items[0].jid).to);
+        assert_eq!(agent.pending_inbound_subscriptions(), [contact]);
+        assert!(agent.handle_message(message()).await.is_empty());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pubsub_events() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        let message = |payload: &str| {
+            let elem: Element = format!("<message xmlns='jabber:client' from='pubsub.shakespeare.lit' to='foo@bar'><event xmlns='http://jabber.org/protocol/pubsub#event'>{}</event></message>", payload).parse().unwrap();
+            Message::try_from(elem).unwrap()
+        };
+        let service = Jid::from_str("pubsub.shakespeare.lit").unwrap();
+
+        let deleted = message("<delete node='princely_musings'><redirect uri='xmpp:hamlet@denmark.lit?;node=blog'/></delete>");
+        match &agent.handle_message(deleted).await[..] {
+            [Event::PubSubNodeDeleted {
+                from,
+                node,
+                redirect,
+            }] => {
+                assert_eq!(from, &service);
+                assert_eq!(node.0, "princely_musings");
+                assert_eq!(
+                    redirect.as_deref(),
+                    Some("xmpp:hamlet@denmark.lit?;node=blog")
+                );
+            }
+            events => panic!("Unexpected events {:?}", events),
+        }
+
+        let retracted = message("<items node='princely_musings'><retract id='ae890ac52d0df67ed7cfdf51b644e901'/></items>");
+        match &agent.handle_message(retracted).await[..] {
+            [Event::PubSubItemsRetracted { node, items, .. }] => {
+                assert_eq!(node.0, "princely_musings");
+                assert_eq!(items[0].0, "ae890ac52d0df67ed7cfdf51b644e901");
+            }
+            events => panic!("Unexpected events {:?}", events),
+        }
+
+        // The bookmarks node is still handled by the Agent itself, for our own account only.
+        let forged = message("<delete node='urn:xmpp:bookmarks:1'/>");
+        assert!(agent.handle_message(forged).await.is_empty());
+        let elem: Element = "<message xmlns='jabber:client' from='foo@bar'><event xmlns='http://jabber.org/protocol/pubsub#event'><delete node='urn:xmpp:bookmarks:1'/></event></message>".parse().unwrap();
+        let purged = Message::try_from(elem).unwrap();
+        match &agent.handle_message(purged).await[..] {
+            [Event::LeaveAllRooms] => (),
+            events => panic!("Unexpected events {:?}", events),
         }
+        // Malformed bookmarks are ignored.
+        for items in &[
+            "<item><conference xmlns='urn:xmpp:bookmarks:1'/></item>",
+            "<item id='@muc.bar'><conference xmlns='urn:xmpp:bookmarks:1'/></item>",
+            "<item id='room@muc.bar'/>",
+            "<retract id='one@muc.bar'/><retract id='two@muc.bar'/>",
+        ] {
+            let elem: Element = format!("<message xmlns='jabber:client' from='foo@bar'><event xmlns='http://jabber.org/protocol/pubsub#event'><items node='urn:xmpp:bookmarks:1'>{}</items></event></message>", items).parse().unwrap();
+            let message = Message::try_from(elem).unwrap();
+            assert!(agent.handle_message(message).await.is_empty());
+        }
+        assert!(agent.bookmarks.is_empty());
+        assert!(agent.handle_message(message("<coucou/>")).await.is_empty());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{Agent, ClientBuilder, ClientFeature, ClientType, Event};
-    use tokio_xmpp::AsyncClient as TokioXmppClient;
+    #[tokio::test(start_paused = true)]
+    async fn test_feature_changes() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        let node_ver = |agent: &Agent| format!("{}#{}", agent.node, own_caps::ver(&agent.disco));
+        let old = node_ver(&agent);
+        let old_features = agent.disco.features.clone();
+
+        // Ten features added in a row get broadcast at once, a second after the last one.
+        for i in 0..10 {
+            assert!(agent.advertise_feature(&format!("urn:example:{}", i)));
+            tokio::time::advance(Duration::from_millis(100)).await;
+        }
+        assert!(!agent.advertise_feature("urn:example:0"));
+        assert_eq!(
+            agent.own_caps.next_deadline(),
+            Some(ephemeral::now() + Duration::from_millis(900))
+        );
+        let new = node_ver(&agent);
+        assert_eq!(agent.disco_info(Some(old.clone())).features, old_features);
+        let disco = agent.disco_info(Some(new.clone()));
+        assert_eq!(disco.features.len(), old_features.len() + 10);
+        assert_eq!(disco.node, Some(new));
+
+        // Offline, our next initial presence will carry them instead.
+        tokio::time::advance(Duration::from_millis(900)).await;
+        agent.broadcast_caps().await;
+        assert_eq!(agent.own_caps.next_deadline(), None);
+        assert_eq!(agent.disco_info(Some(old.clone())).features, old_features);
+
+        // Until the grace period is over.
+        tokio::time::advance(Duration::from_secs(5 * 60)).await;
+        let disco = agent.disco_info(Some(old));
+        assert_eq!(disco.features.len(), old_features.len() + 10);
+        assert!(agent.withdraw_feature("urn:example:3"));
+        assert!(!agent.withdraw_feature("urn:example:3"));
+        assert!(agent.own_caps.next_deadline().is_some());
+    }
 
     #[tokio::test]
-    async fn test_simple() {
+    async fn test_attention() {
         let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .with_attention()
+            .build_impl(client)
+            .unwrap();
+        assert!(agent.disco.features.contains(&Feature::new(ns::ATTENTION)));
 
-        // Client instance
-        let client_builder = ClientBuilder::new("foo@bar", "meh")
-            .set_client(ClientType::Bot, "xmpp-rs")
-            .set_website("https://gitlab.com/xmpp-rs/xmpp-rs")
-            .set_default_nick("bot")
-            .enable_feature(ClientFeature::Avatars)
-            .enable_feature(ClientFeature::ContactList);
+        let message = |type_: &str| {
+            let elem: Element = format!("<message xmlns='jabber:client' from='coucou@bar/phone' type='{}'><attention xmlns='urn:xmpp:attention:0'/></message>", type_).parse().unwrap();
+            Message::try_from(elem).unwrap()
+        };
+        match &agent.handle_message(message("headline")).await[..] {
+            [Event::AttentionRequested(jid)] => {
+                assert_eq!(jid, &Jid::from_str("coucou@bar/phone").unwrap())
+            }
+            events => panic!("Unexpected events {:?}", events),
+        }
+        assert!(agent.handle_message(message("groupchat")).await.is_empty());
 
-        let mut agent: Agent = client_builder.build_impl(client).unwrap();
+        let nudge = Agent::attention_message(Jid::from_str("coucou@bar").unwrap(), None);
+        let expected: Element = "<message xmlns='jabber:client' to='coucou@bar' type='headline'><attention xmlns='urn:xmpp:attention:0'/></message>".parse().unwrap();
+        assert_eq!(Element::from(nudge), expected);
+        let nudge = Agent::attention_message(Jid::from_str("coucou@bar").unwrap(), Some("Ping?"));
+        let expected: Element = "<message xmlns='jabber:client' to='coucou@bar' type='chat'><body>Ping?</body><attention xmlns='urn:xmpp:attention:0'/></message>".parse().unwrap();
+        assert_eq!(Element::from(nudge), expected);
+    }
 
-        while let Some(events) = agent.wait_for_events().await {
-            assert!(match events[0] {
-                Event::Disconnected => true,
-                _ => false,
-            });
-            assert_eq!(events.len(), 1);
-            break;
+    #[tokio::test]
+    async fn test_fallbacks() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .with_understood_payloads("urn:xmpp:reply:0")
+            .build_impl(client)
+            .unwrap();
+        let message = |payloads: &str| {
+            let elem: Element = format!("<message xmlns='jabber:client' from='coucou@bar/phone' type='chat'><body>&gt; Éléphant 🐘\nOui 🐘</body>{}</message>", payloads).parse().unwrap();
+            Message::try_from(elem).unwrap()
+        };
+        let body = |events: Vec<Event>| match &events[..] {
+            [Event::ChatMessage(_, body, info)] => (body.0.clone(), info.fallbacks.clone()),
+            events => panic!("Unexpected events {:?}", events),
+        };
+
+        // Code points are counted, not bytes.
+        let reply = message("<reply xmlns='urn:xmpp:reply:0' to='coucou@bar/phone' id='1'/><fallback xmlns='urn:xmpp:fallback:0' for='urn:xmpp:reply:0'><body start='0' end='13'/></fallback>");
+        assert_eq!(
+            body(agent.handle_message(reply).await),
+            (
+                String::from("Oui 🐘"),
+                vec![String::from("urn:xmpp:reply:0")]
+            )
+        );
+
+        // Nobody handles OMEMO, so its fallback is all there is to show.
+        let encrypted = message("<encrypted xmlns='eu.siacs.conversations.axolotl'/><fallback xmlns='urn:xmpp:fallback:0' for='eu.siacs.conversations.axolotl'/>");
+        assert_eq!(
+            body(agent.handle_message(encrypted).await),
+            (String::from("> Éléphant 🐘\nOui 🐘"), vec![])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_room_invitation() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        assert!(agent.disco.features.contains(&Feature::new(ns::DIRECT_MUC)));
+
+        // The fallback body doesn’t also come as a chat message.
+        let elem: Element = "<message xmlns='jabber:client' from='coucou@bar/phone'><body>Join room@muc!</body><x xmlns='jabber:x:conference' jid='room@muc'/></message>".parse().unwrap();
+        match &agent.handle_message(Message::try_from(elem).unwrap()).await[..] {
+            [Event::RoomInvitation {
+                room,
+                from,
+                kind: InvitationKind::Direct,
+                ..
+            }] => {
+                assert_eq!(room, &BareJid::from_str("room@muc").unwrap());
+                assert_eq!(from, &Jid::from_str("coucou@bar/phone").unwrap());
+            }
+            events => panic!("Unexpected events {:?}", events),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gone_on_disconnect() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        let message = |from: &str, type_: &str| {
+            let elem: Element = format!(
+                "<message xmlns='jabber:client' from='{}' type='{}'><body>Hi</body></message>",
+                from, type_
+            )
+            .parse()
+            .unwrap();
+            Message::try_from(elem).unwrap()
+        };
+        agent
+            .handle_message(message("coucou@bar/phone", "chat"))
+            .await;
+        // Neither rooms nor normal messages are chat sessions.
+        agent
+            .handle_message(message("room@muc/nick", "groupchat"))
+            .await;
+        agent
+            .handle_message(message("other@bar/phone", "normal"))
+            .await;
+
+        let gone = agent.gone_messages(Instant::now());
+        let expected: Element = "<message xmlns='jabber:client' to='coucou@bar/phone' type='chat'><gone xmlns='http://jabber.org/protocol/chatstates'/></message>".parse().unwrap();
+        let gone: Vec<Element> = gone.into_iter().map(Element::from).collect();
+        assert_eq!(gone, [expected]);
+        assert!(agent.gone_messages(Instant::now()).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_disconnect_ordering() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use tokio::time::Instant;
+
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .set_shutdown_grace_period(Duration::from_secs(2))
+            .build_impl(client)
+            .unwrap();
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let slow = order.clone();
+        agent.tasks.spawn(|_| async move {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            slow.borrow_mut().push("slow");
+            vec![]
+        });
+        let quick = order.clone();
+        agent.tasks.spawn(|cancel| async move {
+            cancel.cancelled().await;
+            quick.borrow_mut().push("cancelled");
+            vec![Event::AttentionRequested(
+                Jid::from_str("coucou@bar").unwrap(),
+            )]
+        });
+
+        // The client never connected, so closing the stream times out too.
+        let start = Instant::now();
+        agent.disconnect().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(4));
+        assert_eq!(*order.borrow(), ["cancelled"]);
+
+        let events = agent.wait_for_events().await.unwrap();
+        match &events[..] {
+            [Event::AttentionRequested(_), Event::Disconnected] => (),
+            events => panic!("Unexpected events {:?}", events),
+        }
+        assert!(agent.wait_for_events().await.is_none());
+        agent.disconnect().await;
+        assert!(agent.wait_for_events().await.is_none());
+        assert_eq!(*order.borrow(), ["cancelled"]);
+    }
+
+    #[tokio::test]
+    async fn test_message_bounced() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        let message = agent.outgoing_message(
+            Jid::from_str("coucou@baz").unwrap(),
+            MessageType::Chat,
+            "en",
+            "Hello",
+            MessageOptions::default(),
+            Instant::now(),
+        );
+        let id = message.id.clone().unwrap();
+
+        let elem: Element = format!("<message xmlns='jabber:client' from='coucou@baz' id='{}' type='error'><error type='cancel'><service-unavailable xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/></error></message>", id).parse().unwrap();
+        match &agent.handle_message(Message::try_from(elem).unwrap()).await[..] {
+            [Event::MessageBounced {
+                to,
+                id: bounced,
+                body,
+                error,
+            }] => {
+                assert_eq!(to, &Jid::from_str("coucou@baz").unwrap());
+                assert_eq!(bounced, &id);
+                assert_eq!(body, "Hello");
+                assert_eq!(
+                    error.defined_condition,
+                    DefinedCondition::ServiceUnavailable
+                );
+            }
+            events => panic!("Unexpected events {:?}", events),
+        }
+    }
+
+    #[test]
+    fn test_compliance_report() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let builder = ClientBuilder::new("foo@bar", "meh")
+            .with_contact_list()
+            .with_muc(MucConfig::default())
+            .with_ephemeral_messages()
+            .with_attention()
+            .with_jingle()
+            .with_locations(LocationAccess::default());
+        #[cfg(feature = "avatars")]
+        let builder = builder.with_avatars(AvatarConfig::default());
+        let mut agent = builder.build_impl(client).unwrap();
+        // Everything we advertise has to be known to xmpp-parsers.
+        let report = agent.compliance_report();
+        assert!(report.unknown.is_empty(), "{:?}", report.unknown);
+        let advertised: usize = report
+            .advertised()
+            .map(|protocol| protocol.advertised.len())
+            .sum();
+        assert_eq!(advertised, agent.disco.features.len());
+        assert!(report
+            .advertised()
+            .any(|protocol| protocol.xep == Some(176)));
+
+        agent.advertise_feature("urn:example:app");
+        assert_eq!(agent.compliance_report().unknown, ["urn:example:app"]);
+    }
+
+    #[tokio::test]
+    async fn test_sent_echo() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        let message = agent.outgoing_message(
+            Jid::from_str("coucou@baz").unwrap(),
+            MessageType::Chat,
+            "en",
+            "Hello",
+            MessageOptions {
+                ephemeral: Some(Duration::from_secs(60)),
+                ..MessageOptions::default()
+            },
+            Instant::now(),
+        );
+        let id = message.id.clone();
+        // Built from the message as sent, with what got added to it.
+        match agent.sent_echo(&message, false) {
+            Some(Event::MessageSentEcho {
+                to,
+                body,
+                info,
+                carbon: false,
+            }) => {
+                assert_eq!(to, Jid::from_str("coucou@baz").unwrap());
+                assert_eq!(body.0, "Hello");
+                assert_eq!(info.id, id);
+                assert_eq!(info.origin_id, id);
+                assert_eq!(info.ephemeral, Some(Duration::from_secs(60)));
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        // The same message, sent by another of our resources.
+        let carbon = |from: &str| {
+            let elem: Element = format!(
+                "<message xmlns='jabber:client' from='{}' to='foo@bar/res'><sent xmlns='urn:xmpp:carbons:2'><forwarded xmlns='urn:xmpp:forward:0'>{}</forwarded></sent></message>",
+                from,
+                String::from(&Element::from(message.clone()))
+            )
+            .parse()
+            .unwrap();
+            Message::try_from(elem).unwrap()
+        };
+        match &agent.handle_message(carbon("foo@bar")).await[..] {
+            [Event::MessageSentEcho {
+                info, carbon: true, ..
+            }] => assert_eq!(info.origin_id, id),
+            events => panic!("Unexpected events {:?}", events),
+        }
+        // Nobody else can pretend we sent something.
+        assert!(agent.handle_message(carbon("coucou@baz")).await.is_empty());
+
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .set_sent_echo(false)
+            .build_impl(client)
+            .unwrap();
+        assert!(agent.sent_echo(&message, false).is_none());
+        assert!(agent.handle_message(carbon("foo@bar")).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_security_label() {
+        use xmpp_parsers::sec_label::SecurityLabel;
+
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        let server = Jid::from_str("bar").unwrap();
+        let catalog = agent
+            .label_catalog_response(
+                &server,
+                &"<iq xmlns='jabber:client' type='result' id='label-catalog' from='bar'>
+                    <catalog xmlns='urn:xmpp:sec-label:catalog:2' to='coucou@baz'>
+                        <item selector='SECRET'><securitylabel xmlns='urn:xmpp:sec-label:0'><displaymarking fgcolor='black' bgcolor='red'>SECRET</displaymarking><label><esssecuritylabel xmlns='urn:xmpp:sec-label:ess:0'>MQYCAQQGASk=</esssecuritylabel></label></securitylabel></item>
+                        <item selector='UNCLASSIFIED' default='true'/>
+                    </catalog>
+                </iq>"
+                    .parse()
+                    .unwrap(),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(catalog.items.len(), 2);
+        // Without a from, the answer comes from our server too.
+        assert!(agent
+            .label_catalog_response(
+                &server,
+                &"<iq xmlns='jabber:client' type='result' id='label-catalog'/>"
+                    .parse()
+                    .unwrap(),
+            )
+            .unwrap()
+            .is_err());
+
+        let label = catalog.items[0].label.clone().unwrap();
+        let message = agent.outgoing_message(
+            Jid::from_str("coucou@baz").unwrap(),
+            MessageType::Chat,
+            "en",
+            "Hello",
+            MessageOptions {
+                security_label: Some(label.clone()),
+                ..MessageOptions::default()
+            },
+            Instant::now(),
+        );
+        let attached = message
+            .payloads
+            .iter()
+            .find(|payload| payload.is("securitylabel", ns::SEC_LABEL))
+            .unwrap();
+        assert_eq!(SecurityLabel::try_from(attached.clone()).unwrap(), label);
+
+        let mut received = message;
+        received.from = Some(Jid::from_str("coucou@baz/res").unwrap());
+        received.to = None;
+        match &agent.handle_message(received).await[..] {
+            [Event::ChatMessage(_, _, info)] => {
+                assert_eq!(info.security_label.as_ref(), Some(&label));
+            }
+            events => panic!("Unexpected events {:?}", events),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_styling() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+        for (unstyled, spans) in &[("", 1), ("<unstyled xmlns='urn:xmpp:styling:0'/>", 0)] {
+            let message: Element = format!(
+                "<message xmlns='jabber:client' from='coucou@baz/res' type='chat'><body>*Hi*</body>{}</message>",
+                unstyled
+            )
+            .parse()
+            .unwrap();
+            match &agent
+                .handle_message(Message::try_from(message).unwrap())
+                .await[..]
+            {
+                [Event::ChatMessage(_, body, info)] => {
+                    assert_eq!(info.styling(body).len(), *spans);
+                }
+                events => panic!("Unexpected events {:?}", events),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stanza_errors() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .build_impl(client)
+            .unwrap();
+
+        let elem: Element = "<message xmlns='jabber:client' from='coucou@baz' id='unknown' type='error'><error type='cancel'><item-not-found xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/></error></message>".parse().unwrap();
+        match &agent.handle_message(Message::try_from(elem).unwrap()).await[..] {
+            [Event::MessageError { from, id, error }] => {
+                assert_eq!(from, &Jid::from_str("coucou@baz").unwrap());
+                assert_eq!(id.as_deref(), Some("unknown"));
+                assert_eq!(error.defined_condition, DefinedCondition::ItemNotFound);
+            }
+            events => panic!("Unexpected events {:?}", events),
+        }
+
+        let elem: Element = "<presence xmlns='jabber:client' from='room@muc/nick' type='error'><error type='cancel'><conflict xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/></error></presence>".parse().unwrap();
+        match &agent
+            .handle_presence(Presence::try_from(elem).unwrap())
+            .await[..]
+        {
+            [Event::PresenceError(from, error)] => {
+                assert_eq!(from, &Jid::from_str("room@muc/nick").unwrap());
+                assert_eq!(error.defined_condition, DefinedCondition::Conflict);
+            }
+            events => panic!("Unexpected events {:?}", events),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nick_conflict() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        // Retrying sends a presence, which would wait for the connection forever.
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .with_muc(MucConfig {
+                default_nick: String::from("bot"),
+                max_nick_retries: 0,
+                ..MucConfig::default()
+            })
+            .build_impl(client)
+            .unwrap();
+        let room = BareJid::from_str("room@muc").unwrap();
+        let conflict = |nick: &str| {
+            let elem: Element = format!("<presence xmlns='jabber:client' from='room@muc/{}' type='error'><error type='cancel'><conflict xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/></error></presence>", nick).parse().unwrap();
+            Presence::try_from(elem).unwrap()
+        };
+        let joined = |nick: &str| {
+            let elem: Element = format!("<presence xmlns='jabber:client' from='room@muc/{}'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='none' role='participant'/><status code='110'/></x></presence>", nick).parse().unwrap();
+            Presence::try_from(elem).unwrap()
+        };
+
+        // Out of retries, the conflict gets reported.
+        let presence = agent.join_presence(room.clone(), None, None, "en", "");
+        agent.pending_joins.joining(&presence);
+        match &agent.handle_presence(conflict("bot")).await[..] {
+            [Event::PresenceError(from, error)] => {
+                assert_eq!(from, &Jid::from_str("room@muc/bot").unwrap());
+                assert_eq!(error.defined_condition, DefinedCondition::Conflict);
+            }
+            events => panic!("Unexpected events {:?}", events),
+        }
+
+        // The nick the room got joined with comes along.
+        agent.pending_joins.joining(&presence);
+        match &agent.handle_presence(joined("bot_")).await[..] {
+            [Event::RoomJoined(jid, nick)] => {
+                assert_eq!(jid, &room);
+                assert_eq!(nick, "bot_");
+            }
+            events => panic!("Unexpected events {:?}", events),
         }
+        assert_eq!(agent.rooms_joined.get(&room).unwrap(), "bot_");
+    }
+
+    #[tokio::test]
+    async fn test_join_presence() {
+        let client = TokioXmppClient::new("foo@bar", "meh").unwrap();
+        let mut agent: Agent = ClientBuilder::new("foo@bar", "meh")
+            .with_muc(MucConfig {
+                default_nick: String::from("bot"),
+                history: Some(History::new().with_maxstanzas(20)),
+                autojoin_bookmarks: false,
+                drop_unjoined_messages: false,
+                nick_conflict: NickConflict::Fail,
+                max_nick_retries: 0,
+            })
+            .build_impl(client)
+            .unwrap();
+        let room = BareJid::from_str("room@muc").unwrap();
+        let join = |agent: &Agent, nick: Option<&str>| {
+            let presence =
+                agent.join_presence(room.clone(), nick.map(String::from), None, "en", "Hi");
+            Element::from(presence)
+        };
+
+        let expected: Element = "<presence xmlns='jabber:client' to='room@muc/bot'><status xml:lang='en'>Hi</status><x xmlns='http://jabber.org/protocol/muc'><history maxstanzas='20'/></x></presence>".parse().unwrap();
+        assert_eq!(join(&agent, None), expected);
+
+        // The bookmark wins over the default nick, but not over the given one.
+        let conference = Conference {
+            nick: Some(String::from("marked")),
+            password: Some(String::from("secret")),
+            ..Conference::new()
+        };
+        agent.bookmarks.insert(room.clone(), conference);
+        let expected: Element = "<presence xmlns='jabber:client' to='room@muc/marked'><status xml:lang='en'>Hi</status><x xmlns='http://jabber.org/protocol/muc'><password>secret</password><history maxstanzas='20'/></x></presence>".parse().unwrap();
+        assert_eq!(join(&agent, None), expected);
+        let expected: Element = "<presence xmlns='jabber:client' to='room@muc/given'><status xml:lang='en'>Hi</status><x xmlns='http://jabber.org/protocol/muc'><password>secret</password><history maxstanzas='20'/></x></presence>".parse().unwrap();
+        assert_eq!(join(&agent, Some("given")), expected);
     }
 }
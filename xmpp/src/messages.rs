@@ -0,0 +1,269 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The human-readable texts the Agent writes on its own, see [`crate::ClientBuilder::set_messages`].
+
+use xmpp_parsers::{
+    stanza_error::{DefinedCondition, ErrorType, StanzaError},
+    Error as ParsersError,
+};
+
+/// Every human-readable text the Agent writes on its own, such as those of the errors it answers
+/// iqs with, all in a single language, to translate or rebrand them.
+///
+/// [`English`] is the default.  Only [`Messages::lang`] is required, every text defaulting to
+/// the English one, so that implementations only override those they translate.  New texts get
+/// added here along with the replies using them.
+pub trait Messages {
+    /// The language of these texts, as the `xml:lang` they get sent with.
+    fn lang(&self) -> &str;
+
+    /// The text of our initial presence, if any.
+    fn initial_status(&self) -> Option<String> {
+        English.initial_status()
+    }
+
+    /// Why we answer an iq with `service-unavailable`: nothing handles it.
+    fn unhandled_iq(&self) -> String {
+        English.unhandled_iq()
+    }
+
+    /// Why we answer an iq with `bad-request`: its payload is invalid, for this `error`.
+    fn invalid_payload(&self, error: &ParsersError) -> String {
+        English.invalid_payload(error)
+    }
+
+    /// Why we refuse a Jingle action: it refers to a session we don’t know.
+    fn unknown_jingle_session(&self) -> String {
+        English.unknown_jingle_session()
+    }
+
+    /// Why we refuse a Jingle session: one with the same id exists already.
+    fn jingle_session_conflict(&self) -> String {
+        English.jingle_session_conflict()
+    }
+
+    /// Why we refuse a Jingle session: its initiator has too many we didn’t answer yet.
+    fn too_many_jingle_sessions(&self) -> String {
+        English.too_many_jingle_sessions()
+    }
+
+    /// Why we refuse a Jingle session: it isn’t an RTP one (XEP-0167).
+    fn jingle_not_rtp(&self) -> String {
+        English.jingle_not_rtp()
+    }
+
+    /// Why we refuse a Jingle action: it adds or removes contents.
+    fn jingle_unsupported_action(&self) -> String {
+        English.jingle_unsupported_action()
+    }
+
+    /// Why [`crate::Agent::admin`] fails: our server allows no administration command to us.
+    fn no_admin_command(&self) -> String {
+        English.no_admin_command()
+    }
+
+    /// Why an administration command fails: our server doesn’t allow this `node` to us.
+    fn admin_command_forbidden(&self, node: &str) -> String {
+        English.admin_command_forbidden(node)
+    }
+}
+
+/// The default [`Messages`], in English.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct English;
+
+impl Messages for English {
+    fn lang(&self) -> &str {
+        "en"
+    }
+
+    fn initial_status(&self) -> Option<String> {
+        None
+    }
+
+    fn unhandled_iq(&self) -> String {
+        String::from("No handler defined for this kind of iq.")
+    }
+
+    fn invalid_payload(&self, error: &ParsersError) -> String {
+        format!("{}", error)
+    }
+
+    fn unknown_jingle_session(&self) -> String {
+        String::from("Unknown session.")
+    }
+
+    fn jingle_session_conflict(&self) -> String {
+        String::from("This session already exists.")
+    }
+
+    fn too_many_jingle_sessions(&self) -> String {
+        String::from("Too many calls are waiting for an answer already.")
+    }
+
+    fn jingle_not_rtp(&self) -> String {
+        String::from("Only RTP sessions are supported.")
+    }
+
+    fn jingle_unsupported_action(&self) -> String {
+        String::from("Only calls with a fixed set of contents are supported.")
+    }
+
+    fn no_admin_command(&self) -> String {
+        String::from("No administration command is allowed to this account.")
+    }
+
+    fn admin_command_forbidden(&self, node: &str) -> String {
+        format!("Not allowed to run {}.", node)
+    }
+}
+
+/// A stanza error of ours, with `text` written in the language of `messages`.
+pub(crate) fn stanza_error(
+    messages: &dyn Messages,
+    type_: ErrorType,
+    condition: DefinedCondition,
+    text: String,
+) -> StanzaError {
+    StanzaError::new(type_, condition, messages.lang(), text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientBuilder, Event};
+    use futures::StreamExt;
+    use std::str::FromStr;
+    use tokio_xmpp::mini_server::MiniServer;
+    use tokio_xmpp::{AsyncClient, AsyncConfig, AsyncServerConfig};
+    use xmpp_parsers::{ns, Element, Jid};
+
+    struct German;
+
+    impl Messages for German {
+        fn lang(&self) -> &str {
+            "de"
+        }
+
+        fn initial_status(&self) -> Option<String> {
+            Some(String::from("Bin da"))
+        }
+
+        fn unhandled_iq(&self) -> String {
+            String::from("Für diese Art von iq gibt es keinen Handler.")
+        }
+
+        fn jingle_not_rtp(&self) -> String {
+            String::from("Nur RTP-Sitzungen werden unterstützt.")
+        }
+
+        // The other texts aren’t translated yet.
+    }
+
+    fn client(jid: &str, password: &str, port: u16) -> AsyncClient {
+        AsyncClient::new_with_config(AsyncConfig::new(
+            Jid::from_str(jid).unwrap(),
+            password,
+            AsyncServerConfig::Loopback { port },
+        ))
+    }
+
+    /// The error bob gets back for an iq nothing handles, from an Agent built by `builder`.
+    async fn unhandled_iq_error(port: u16, builder: ClientBuilder<'_>) -> Element {
+        let mut bob = client("bob@localhost", "bob", port);
+        assert!(bob.next().await.unwrap().is_online());
+        let mut agent = builder
+            .build_impl(client("foo@localhost", "foo", port))
+            .unwrap();
+        loop {
+            let events = agent.wait_for_events().await.unwrap();
+            if events.iter().any(|event| matches!(event, Event::Online)) {
+                break;
+            }
+        }
+
+        let to = agent.client.bound_jid().unwrap().clone();
+        let iq: Element = format!(
+            "<iq xmlns='jabber:client' type='get' id='unhandled' to='{}'><query xmlns='urn:example:unhandled'/></iq>",
+            to
+        )
+        .parse()
+        .unwrap();
+        bob.send_stanza(iq).await.unwrap();
+        let answer = async {
+            loop {
+                match bob.next().await.unwrap().into_stanza() {
+                    Some(elem) if elem.is("iq", ns::JABBER_CLIENT) => return elem,
+                    _ => continue,
+                }
+            }
+        };
+        let agent_runs = async {
+            loop {
+                agent.wait_for_events().await;
+            }
+        };
+        tokio::select! {
+            elem = answer => {
+                assert_eq!(elem.attr("id"), Some("unhandled"));
+                assert_eq!(elem.attr("type"), Some("error"));
+                elem.get_child("error", ns::JABBER_CLIENT).unwrap().clone()
+            },
+            () = agent_runs => unreachable!(),
+        }
+    }
+
+    async fn unhandled_iq_errors(port: u16) {
+        let error = unhandled_iq_error(
+            port,
+            ClientBuilder::new("foo@localhost", "foo").set_messages(German),
+        )
+        .await;
+        let expected: Element = "<error xmlns='jabber:client' type='cancel'><service-unavailable xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/><text xmlns='urn:ietf:params:xml:ns:xmpp-stanzas' xml:lang='de'>Für diese Art von iq gibt es keinen Handler.</text></error>".parse().unwrap();
+        assert_eq!(error, expected);
+
+        // Unchanged by default.
+        let error = unhandled_iq_error(port, ClientBuilder::new("foo@localhost", "foo")).await;
+        let expected: Element = "<error xmlns='jabber:client' type='cancel'><service-unavailable xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/><text xmlns='urn:ietf:params:xml:ns:xmpp-stanzas' xml:lang='en'>No handler defined for this kind of iq.</text></error>".parse().unwrap();
+        assert_eq!(error, expected);
+    }
+
+    #[tokio::test]
+    async fn test_unhandled_iq() {
+        let server = MiniServer::new("localhost")
+            .user("foo", "foo")
+            .user("bob", "bob");
+        let (port, server) = server.listen(0).await.unwrap();
+        tokio::select! {
+            result = server => panic!("Server stopped: {:?}", result),
+            () = unhandled_iq_errors(port) => (),
+        }
+    }
+
+    #[test]
+    fn test_translated_texts() {
+        let error = crate::calls::Refusal::NotRtp.into_error(&German);
+        assert_eq!(
+            error.texts.get("de").map(String::as_str),
+            Some("Nur RTP-Sitzungen werden unterstützt.")
+        );
+        assert_eq!(error.texts.len(), 1);
+        // Texts left untranslated are the English ones.
+        assert_eq!(German.no_admin_command(), English.no_admin_command());
+
+        let client = AsyncClient::new("foo@bar", "meh").unwrap();
+        let agent = ClientBuilder::new("foo@bar", "meh")
+            .set_messages(German)
+            .build_impl(client)
+            .unwrap();
+        let presence = agent.directed_presence(Jid::from_str("contact@bar").unwrap());
+        assert_eq!(
+            presence.statuses.get("de").map(String::as_str),
+            Some("Bin da")
+        );
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The rooms we are joining, to try other nicks when ours is already taken, and the
+//! invitations to join others.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use xmpp_parsers::{
+    direct_muc::Invitation,
+    message::{Message, MessageType},
+    muc::MucUser,
+    ns,
+    presence::Presence,
+    BareJid, Jid,
+};
+
+use crate::features::NickConflict;
+use crate::Event;
+
+/// How an invitation to a room reached us, see [`Event::RoomInvitation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvitationKind {
+    /// Sent to us by whoever invites us (XEP-0249).
+    Direct,
+    /// Relayed by the room on behalf of whoever invites us (XEP-0045 §7.8.2).
+    Mediated,
+}
+
+/// The invitation to a room `message` carries, if any.
+pub(crate) fn invitation(message: &Message) -> Option<Event> {
+    if matches!(message.type_, MessageType::Error | MessageType::Groupchat) {
+        return None;
+    }
+    let from = message.from.clone()?;
+    let direct = message
+        .payloads
+        .iter()
+        .find(|payload| payload.is("x", ns::DIRECT_MUC))
+        .and_then(|payload| Invitation::try_from(payload.clone()).ok());
+    if let Some(invitation) = direct {
+        return Some(Event::RoomInvitation {
+            room: invitation.jid,
+            from,
+            reason: invitation.reason,
+            password: invitation.password,
+            kind: InvitationKind::Direct,
+        });
+    }
+    let muc_user = message
+        .payloads
+        .iter()
+        .find(|payload| payload.is("x", ns::MUC_USER))
+        .and_then(|payload| MucUser::try_from(payload.clone()).ok())?;
+    // The room relays the invitation from the bare room JID, saying who it comes from.
+    let invite = muc_user.invites.into_iter().next()?;
+    Some(Event::RoomInvitation {
+        room: BareJid::from(from),
+        from: invite.from?,
+        reason: invite.reason.map(|reason| reason.0),
+        password: muc_user.password,
+        kind: InvitationKind::Mediated,
+    })
+}
+
+#[derive(Debug)]
+struct PendingJoin {
+    /// The presence which joined, sent again with another nick on conflict.
+    presence: Presence,
+    /// The nick first asked for, which the other ones derive from.
+    nick: String,
+    retries: u8,
+}
+
+#[derive(Debug)]
+pub(crate) struct PendingJoins {
+    joins: HashMap<BareJid, PendingJoin>,
+    strategy: NickConflict,
+    max_retries: u8,
+}
+
+impl PendingJoins {
+    pub(crate) fn new(strategy: NickConflict, max_retries: u8) -> Self {
+        PendingJoins {
+            joins: HashMap::new(),
+            strategy,
+            max_retries,
+        }
+    }
+
+    /// A join `presence` got sent, to a room JID with our nick.
+    pub(crate) fn joining(&mut self, presence: &Presence) {
+        if let Some(Jid::Full(occupant)) = &presence.to {
+            let join = PendingJoin {
+                presence: presence.clone(),
+                nick: occupant.resource.clone(),
+                retries: 0,
+            };
+            self.joins.insert(BareJid::from(occupant.clone()), join);
+        }
+    }
+
+    /// The nick we were joining `room` with is taken, returns the presence to try the next one
+    /// with, or `None` to give up.
+    pub(crate) fn conflict(&mut self, room: &BareJid) -> Option<Presence> {
+        let join = self.joins.get_mut(room)?;
+        let nick = if join.retries < self.max_retries {
+            self.strategy.nick(&join.nick, join.retries + 1)
+        } else {
+            None
+        };
+        let nick = match nick {
+            Some(nick) => nick,
+            None => {
+                self.joins.remove(room);
+                return None;
+            }
+        };
+        join.retries += 1;
+        let occupant = room.clone().with_resource(nick);
+        Some(join.presence.clone().with_to(Jid::Full(occupant)))
+    }
+
+    /// Joining `room` is over, having either worked or failed.
+    pub(crate) fn done(&mut self, room: &BareJid) {
+        self.joins.remove(room);
+    }
+
+    /// The connection got lost, with every join still pending.
+    pub(crate) fn clear(&mut self) {
+        self.joins.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use xmpp_parsers::presence::Type as PresenceType;
+
+    fn join(to: &str) -> Presence {
+        Presence::new(PresenceType::None).with_to(Jid::from_str(to).unwrap())
+    }
+
+    fn message(xml: &str) -> Message {
+        Message::try_from(xml.parse::<xmpp_parsers::Element>().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_invitations() {
+        let direct = message(
+            "<message xmlns='jabber:client' from='crone1@shakespeare.lit/desktop' to='hecate@shakespeare.lit'>
+                <x xmlns='jabber:x:conference' jid='darkcave@macbeth.shakespeare.lit' password='cauldronburn' reason='Hey Hecate'/>
+            </message>",
+        );
+        match invitation(&direct) {
+            Some(Event::RoomInvitation {
+                room,
+                from,
+                reason,
+                password,
+                kind,
+            }) => {
+                assert_eq!(
+                    room,
+                    BareJid::from_str("darkcave@macbeth.shakespeare.lit").unwrap()
+                );
+                assert_eq!(
+                    from,
+                    Jid::from_str("crone1@shakespeare.lit/desktop").unwrap()
+                );
+                assert_eq!(reason.as_deref(), Some("Hey Hecate"));
+                assert_eq!(password.as_deref(), Some("cauldronburn"));
+                assert_eq!(kind, InvitationKind::Direct);
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        let mediated = message(
+            "<message xmlns='jabber:client' from='coven@chat.shakespeare.lit' to='hecate@shakespeare.lit'>
+                <body>crone1@shakespeare.lit invites you to coven@chat.shakespeare.lit</body>
+                <x xmlns='http://jabber.org/protocol/muc#user'>
+                    <invite from='crone1@shakespeare.lit/desktop'>
+                        <reason>Hey Hecate</reason>
+                    </invite>
+                    <password>cauldronburn</password>
+                </x>
+            </message>",
+        );
+        match invitation(&mediated) {
+            Some(Event::RoomInvitation {
+                room,
+                from,
+                reason,
+                password,
+                kind,
+            }) => {
+                assert_eq!(
+                    room,
+                    BareJid::from_str("coven@chat.shakespeare.lit").unwrap()
+                );
+                assert_eq!(
+                    from,
+                    Jid::from_str("crone1@shakespeare.lit/desktop").unwrap()
+                );
+                assert_eq!(reason.as_deref(), Some("Hey Hecate"));
+                assert_eq!(password.as_deref(), Some("cauldronburn"));
+                assert_eq!(kind, InvitationKind::Mediated);
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        // Occupants’ presences and room messages aren’t invitations.
+        let groupchat = message(
+            "<message xmlns='jabber:client' from='coven@chat.shakespeare.lit/crone1' type='groupchat'>
+                <x xmlns='jabber:x:conference' jid='darkcave@macbeth.shakespeare.lit'/>
+            </message>",
+        );
+        assert!(invitation(&groupchat).is_none());
+        let status = message(
+            "<message xmlns='jabber:client' from='coven@chat.shakespeare.lit'>
+                <x xmlns='http://jabber.org/protocol/muc#user'><status code='104'/></x>
+            </message>",
+        );
+        assert!(invitation(&status).is_none());
+    }
+
+    #[test]
+    fn test_retries() {
+        let room = BareJid::from_str("room@muc").unwrap();
+        let mut joins = PendingJoins::new(NickConflict::AppendSuffix(String::from("_")), 2);
+        joins.joining(&join("room@muc/bot"));
+        let to = |presence: Option<Presence>| presence.and_then(|presence| presence.to);
+        assert_eq!(
+            to(joins.conflict(&room)),
+            Some(Jid::from_str("room@muc/bot_").unwrap())
+        );
+        assert_eq!(
+            to(joins.conflict(&room)),
+            Some(Jid::from_str("room@muc/bot__").unwrap())
+        );
+        // Out of retries, the join gets forgotten.
+        assert_eq!(to(joins.conflict(&room)), None);
+        assert!(joins.joins.is_empty());
+
+        // Rooms we aren’t joining, or are joining as a whole, get no retry.
+        joins.joining(&join("other@muc"));
+        assert_eq!(to(joins.conflict(&room)), None);
+        assert!(joins.joins.is_empty());
+
+        let mut joins = PendingJoins::new(NickConflict::Fail, 2);
+        joins.joining(&join("room@muc/bot"));
+        assert_eq!(to(joins.conflict(&room)), None);
+    }
+}
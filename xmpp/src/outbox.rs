@@ -0,0 +1,402 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The messages sent with [`crate::Agent::send_message_queued`], waiting for the connection, then
+//! followed until they bounce or get forgotten.
+
+use crate::MessageOptions;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::{Rc, Weak};
+use std::time::Instant;
+use tokio::sync::broadcast;
+use xmpp_parsers::{message::MessageType, stanza_error::StanzaError, BareJid, Jid};
+
+/// How many changes of status a receiver of [`PendingMessage::updates`] may fall behind by, more
+/// than a message usually goes through.
+const UPDATES_CAPACITY: usize = 8;
+
+/// What became of a message sent with [`crate::Agent::send_message_queued`].
+#[derive(Debug, Clone)]
+pub enum MessageStatus {
+    /// Waiting for the connection, until then it can be cancelled.
+    Queued,
+    /// Being written to the connection.
+    Sending,
+    /// Written to the connection at `at`, it can still bounce.
+    Sent { at: Instant },
+    /// It bounced, see [`crate::Event::MessageBounced`].
+    Failed { error: Box<StanzaError> },
+    /// [`PendingMessage::cancel`] got called while it was queued, it will never be sent.
+    Cancelled,
+}
+
+#[derive(Debug)]
+struct State {
+    status: MessageStatus,
+    updates: broadcast::Sender<MessageStatus>,
+}
+
+impl State {
+    fn set(&mut self, status: MessageStatus) {
+        let _ = self.updates.send(status.clone());
+        self.status = status;
+    }
+}
+
+/// A message sent with [`crate::Agent::send_message_queued`], to follow what becomes of it.
+///
+/// Its clones all follow the same message.
+#[derive(Debug, Clone)]
+pub struct PendingMessage {
+    id: String,
+    to: Jid,
+    state: Rc<RefCell<State>>,
+}
+
+impl PendingMessage {
+    fn new(id: String, to: Jid) -> PendingMessage {
+        PendingMessage {
+            id,
+            to,
+            state: Rc::new(RefCell::new(State {
+                status: MessageStatus::Queued,
+                updates: broadcast::channel(UPDATES_CAPACITY).0,
+            })),
+        }
+    }
+
+    /// The id of the message, the same as in [`crate::Event::MessageSentEcho`] and
+    /// [`crate::Event::MessageBounced`].
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The recipient of the message.
+    pub fn to(&self) -> &Jid {
+        &self.to
+    }
+
+    /// Where the message is at.
+    pub fn status(&self) -> MessageStatus {
+        self.state.borrow().status.clone()
+    }
+
+    /// Receive every change of [`PendingMessage::status`] from now on, such as to update the
+    /// message in a user interface.
+    pub fn updates(&self) -> broadcast::Receiver<MessageStatus> {
+        self.state.borrow().updates.subscribe()
+    }
+
+    /// Never send the message, returning whether that was still possible, which is only the case
+    /// while it is [`MessageStatus::Queued`].
+    pub fn cancel(&self) -> bool {
+        let queued = matches!(self.state.borrow().status, MessageStatus::Queued);
+        if queued {
+            self.set_status(MessageStatus::Cancelled);
+        }
+        queued
+    }
+
+    fn set_status(&self, status: MessageStatus) {
+        self.state.borrow_mut().set(status);
+    }
+}
+
+/// A message waiting for the connection, with what it gets built from once sent.
+#[derive(Debug)]
+pub(crate) struct Queued {
+    pub(crate) handle: PendingMessage,
+    pub(crate) type_: MessageType,
+    pub(crate) lang: String,
+    pub(crate) text: String,
+    pub(crate) options: MessageOptions,
+    /// The conversation it belongs to.
+    peer: BareJid,
+}
+
+#[derive(Debug)]
+pub(crate) struct Outbox {
+    /// Oldest first, so that the messages of each conversation get sent in order.
+    queued: VecDeque<Queued>,
+    /// The messages sent which may still bounce, oldest first, as many at most as
+    /// [`crate::bounces::SentMessages`] remembers.
+    sent: VecDeque<(String, Weak<RefCell<State>>)>,
+    window: usize,
+}
+
+impl Outbox {
+    pub(crate) fn new(window: usize) -> Outbox {
+        Outbox {
+            queued: VecDeque::new(),
+            sent: VecDeque::new(),
+            window,
+        }
+    }
+
+    /// Queue a message to `to`, whose id got generated already.
+    pub(crate) fn push(
+        &mut self,
+        id: String,
+        to: Jid,
+        type_: MessageType,
+        lang: &str,
+        text: &str,
+        options: MessageOptions,
+    ) -> PendingMessage {
+        let peer = BareJid::from(to.clone());
+        let handle = PendingMessage::new(id, to);
+        self.queued.push_back(Queued {
+            handle: handle.clone(),
+            type_,
+            lang: String::from(lang),
+            text: String::from(text),
+            options,
+            peer,
+        });
+        handle
+    }
+
+    /// The next message to send, now [`MessageStatus::Sending`], the cancelled ones being
+    /// dropped.
+    pub(crate) fn next(&mut self) -> Option<Queued> {
+        while let Some(queued) = self.queued.pop_front() {
+            if let MessageStatus::Queued = queued.handle.status() {
+                queued.handle.set_status(MessageStatus::Sending);
+                return Some(queued);
+            }
+        }
+        None
+    }
+
+    /// `queued` couldn’t be sent, to be tried again first on the next connection.
+    pub(crate) fn retry(&mut self, queued: Queued) {
+        queued.handle.set_status(MessageStatus::Queued);
+        self.queued.push_front(queued);
+    }
+
+    /// `queued` got sent at `now`.
+    pub(crate) fn sent(&mut self, queued: Queued, now: Instant) {
+        let handle = queued.handle;
+        handle.set_status(MessageStatus::Sent { at: now });
+        self.sent.retain(|(_, state)| state.strong_count() > 0);
+        if self.window == 0 {
+            return;
+        }
+        if self.sent.len() >= self.window {
+            self.sent.pop_front();
+        }
+        self.sent
+            .push_back((handle.id, Rc::downgrade(&handle.state)));
+    }
+
+    /// The message `id` bounced with `error`, which got correlated to one we sent already.
+    pub(crate) fn bounced(&mut self, id: &str, error: &StanzaError) {
+        let position = match self.sent.iter().position(|(sent, _)| sent == id) {
+            Some(position) => position,
+            None => return,
+        };
+        let (_, state) = self.sent.remove(position).unwrap();
+        if let Some(state) = state.upgrade() {
+            state.borrow_mut().set(MessageStatus::Failed {
+                error: Box::new(error.clone()),
+            });
+        }
+    }
+
+    /// The messages queued in the conversation with `peer`, in the order they will be sent.
+    pub(crate) fn conversation(&self, peer: &BareJid) -> Vec<PendingMessage> {
+        self.queued
+            .iter()
+            .filter(|queued| queued.peer == *peer)
+            .filter(|queued| matches!(queued.handle.status(), MessageStatus::Queued))
+            .map(|queued| queued.handle.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Agent, ClientBuilder, Event};
+    use futures::StreamExt;
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+    use tokio::sync::broadcast::Receiver;
+    use tokio_xmpp::mini_server::MiniServer;
+    use tokio_xmpp::{AsyncClient, AsyncConfig, AsyncServerConfig};
+    use xmpp_parsers::{message::Message, stanza_error::DefinedCondition, stanza_error::ErrorType};
+
+    fn statuses(updates: &mut Receiver<MessageStatus>) -> Vec<MessageStatus> {
+        let mut statuses = Vec::new();
+        while let Ok(status) = updates.try_recv() {
+            statuses.push(status);
+        }
+        statuses
+    }
+
+    #[test]
+    fn test_outbox() {
+        let jid = |jid: &str| Jid::from_str(jid).unwrap();
+        let mut outbox = Outbox::new(2);
+        let mut push = |id: &str, to: &str| {
+            let options = MessageOptions::default();
+            outbox.push(
+                String::from(id),
+                jid(to),
+                MessageType::Chat,
+                "en",
+                "Hi",
+                options,
+            )
+        };
+        let a = push("a", "coucou@bar/phone");
+        let b = push("b", "other@baz");
+        let c = push("c", "coucou@bar");
+        let mut updates = a.updates();
+        assert!(b.cancel());
+        let coucou: Vec<_> = outbox
+            .conversation(&BareJid::from_str("coucou@bar").unwrap())
+            .iter()
+            .map(|pending| String::from(pending.id()))
+            .collect();
+        assert_eq!(coucou, ["a", "c"]);
+
+        // A message which failed to be sent goes first again, the cancelled one never does.
+        let queued = outbox.next().unwrap();
+        assert_eq!(queued.handle.id(), "a");
+        assert!(!a.cancel());
+        outbox.retry(queued);
+        let queued = outbox.next().unwrap();
+        let start = Instant::now();
+        outbox.sent(queued, start);
+        assert_eq!(outbox.next().unwrap().handle.id(), "c");
+        assert!(outbox.next().is_none());
+        assert!(matches!(
+            statuses(&mut updates)[..],
+            [
+                MessageStatus::Sending,
+                MessageStatus::Queued,
+                MessageStatus::Sending,
+                MessageStatus::Sent { at },
+            ] if at == start
+        ));
+
+        // Only what got sent can bounce.
+        let error = StanzaError::new(
+            ErrorType::Cancel,
+            DefinedCondition::ServiceUnavailable,
+            "en",
+            "",
+        );
+        outbox.bounced("c", &error);
+        assert!(matches!(c.status(), MessageStatus::Sending));
+        outbox.bounced("a", &error);
+        assert!(matches!(a.status(), MessageStatus::Failed { .. }));
+        assert!(matches!(b.status(), MessageStatus::Cancelled));
+    }
+
+    fn client(jid: &str, password: &str, port: u16) -> AsyncClient {
+        AsyncClient::new_with_config(AsyncConfig::new(
+            Jid::from_str(jid).unwrap(),
+            password,
+            AsyncServerConfig::Loopback { port },
+        ))
+    }
+
+    /// The bodies of the next `count` messages `client` receives.
+    async fn bodies(client: &mut AsyncClient, count: usize) -> Vec<String> {
+        let mut bodies = Vec::new();
+        while bodies.len() < count {
+            let event = client.next().await.unwrap();
+            let message = match event.into_stanza().map(Message::try_from) {
+                Some(Ok(message)) => message,
+                _ => continue,
+            };
+            bodies.extend(message.bodies.values().map(|body| body.0.clone()));
+        }
+        bodies
+    }
+
+    async fn send_after_connect(port: u16) {
+        let mut alice = client("alice@localhost", "alice", port);
+        let mut bob = client("bob@localhost", "bob", port);
+        for peer in [&mut alice, &mut bob] {
+            assert!(peer.next().await.unwrap().is_online());
+        }
+        let mut agent: Agent = ClientBuilder::new("foo@localhost", "foo")
+            .build_impl(client("foo@localhost", "foo", port))
+            .unwrap();
+
+        // Not connected yet.
+        let mut queue = Vec::new();
+        for (to, text) in [
+            ("alice@localhost", "one"),
+            ("bob@localhost", "two"),
+            ("alice@localhost", "three"),
+        ] {
+            let to = Jid::from_str(to).unwrap();
+            let options = MessageOptions::default();
+            let pending = agent
+                .send_message_queued(to, MessageType::Chat, "en", text, options)
+                .await;
+            assert!(matches!(pending.status(), MessageStatus::Queued));
+            queue.push((pending.updates(), pending));
+        }
+        assert!(queue[1].1.cancel());
+        let alice_jid = BareJid::from_str("alice@localhost").unwrap();
+        let queued = agent.queued_messages(&alice_jid);
+        assert_eq!(queued.len(), 2);
+        assert_eq!(queued[1].id(), queue[2].1.id());
+
+        loop {
+            let events = agent.wait_for_events().await.unwrap();
+            if events.iter().any(|event| matches!(event, Event::Online)) {
+                break;
+            }
+        }
+        assert!(agent.queued_messages(&alice_jid).is_empty());
+        for (i, (updates, _)) in queue.iter_mut().enumerate() {
+            let statuses = statuses(updates);
+            if i == 1 {
+                assert!(matches!(statuses[..], [MessageStatus::Cancelled]));
+            } else {
+                assert!(matches!(
+                    statuses[..],
+                    [MessageStatus::Sending, MessageStatus::Sent { .. }]
+                ));
+            }
+        }
+        assert_eq!(bodies(&mut alice, 2).await, ["one", "three"]);
+
+        // Once online, messages go out right away, the cancelled one never does.
+        let to = Jid::from_str("bob@localhost").unwrap();
+        let pending = agent
+            .send_message_queued(
+                to,
+                MessageType::Chat,
+                "en",
+                "four",
+                MessageOptions::default(),
+            )
+            .await;
+        assert!(matches!(pending.status(), MessageStatus::Sent { .. }));
+        assert_eq!(bodies(&mut bob, 1).await, ["four"]);
+    }
+
+    #[tokio::test]
+    async fn test_send_after_connect() {
+        let server = MiniServer::new("localhost")
+            .user("foo", "foo")
+            .user("alice", "alice")
+            .user("bob", "bob");
+        let (port, server) = server.listen(0).await.unwrap();
+        tokio::select! {
+            result = server => panic!("Server stopped: {:?}", result),
+            () = send_after_connect(port) => (),
+        }
+    }
+}
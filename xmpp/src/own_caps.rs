@@ -0,0 +1,168 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Our own entity capabilities (XEP-0115), broadcast again when our disco#info changes.
+//!
+//! Others only learn about the change from our next presence, and may still query the
+//! disco#info of the verification string they saw before, so the previous one keeps being
+//! answered for a while after the new one got broadcast.
+
+use std::time::{Duration, Instant};
+use xmpp_parsers::{
+    caps::{compute_disco, hash_caps},
+    disco::DiscoInfoResult,
+    hashes::Algo,
+};
+
+/// How long to wait for other changes before broadcasting, so that a burst of them results in a
+/// single presence.
+pub(crate) const BROADCAST_DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// How long the previous disco#info is still answered once the new one got broadcast.
+pub(crate) const PREVIOUS_GRACE: Duration = Duration::from_secs(5 * 60);
+
+/// The verification string of `disco`.
+pub(crate) fn ver(disco: &DiscoInfoResult) -> String {
+    hash_caps(&compute_disco(disco), Algo::Sha_1)
+        .unwrap()
+        .to_base64()
+}
+
+#[derive(Debug)]
+struct Previous {
+    ver: String,
+    disco: DiscoInfoResult,
+    /// When to stop answering it, unset until the new one got broadcast.
+    until: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct OwnCaps {
+    /// The disco#info last broadcast, if it changed since.
+    previous: Option<Previous>,
+    /// When to broadcast the current disco#info.
+    broadcast_at: Option<Instant>,
+}
+
+impl OwnCaps {
+    /// Our disco#info changed from `old` at `now`, to be broadcast once no other change
+    /// happened for [`BROADCAST_DEBOUNCE`].
+    pub(crate) fn changed(&mut self, old: &DiscoInfoResult, now: Instant) {
+        // Nobody saw the intermediate ones, only the one broadcast last is worth answering.
+        if self.broadcast_at.is_none() {
+            self.previous = Some(Previous {
+                ver: ver(old),
+                disco: old.clone(),
+                until: None,
+            });
+        }
+        self.broadcast_at = Some(now + BROADCAST_DEBOUNCE);
+    }
+
+    /// When the pending broadcast is due, if any.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.broadcast_at
+    }
+
+    /// Whether `current` has to be broadcast at `now`, which is no longer the case once this
+    /// returned true.  It isn’t if the changes cancelled each other out.
+    pub(crate) fn broadcast_due(&mut self, current: &DiscoInfoResult, now: Instant) -> bool {
+        match self.broadcast_at {
+            Some(deadline) if deadline <= now => self.broadcast(current, now),
+            _ => false,
+        }
+    }
+
+    /// `current` gets broadcast at `now`, such as with our initial presence, returning
+    /// whether it differs from the one broadcast before.
+    pub(crate) fn broadcast(&mut self, current: &DiscoInfoResult, now: Instant) -> bool {
+        self.broadcast_at = None;
+        match self.previous {
+            Some(ref previous) if previous.ver == ver(current) => {
+                self.previous = None;
+                false
+            }
+            Some(ref mut previous) => {
+                previous.until = Some(now + PREVIOUS_GRACE);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Our disco#info before the last change, if `ver` is its verification string and it is
+    /// still answered at `now`.
+    pub(crate) fn previous(&mut self, ver: &str, now: Instant) -> Option<&DiscoInfoResult> {
+        if let Some(Previous {
+            until: Some(until), ..
+        }) = self.previous
+        {
+            if until <= now {
+                self.previous = None;
+            }
+        }
+        self.previous
+            .as_ref()
+            .filter(|previous| previous.ver == ver)
+            .map(|previous| &previous.disco)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xmpp_parsers::disco::Feature;
+
+    fn disco(features: &[&str]) -> DiscoInfoResult {
+        DiscoInfoResult {
+            node: None,
+            identities: vec![],
+            features: features.iter().map(|var| Feature::new(*var)).collect(),
+            extensions: vec![],
+            unknown: vec![],
+        }
+    }
+
+    #[test]
+    fn test_debounce() {
+        let start = Instant::now();
+        let mut caps = OwnCaps::default();
+        let mut current = disco(&["a"]);
+        let first = ver(&current);
+        for (i, var) in ["b", "c", "d"].iter().enumerate() {
+            let now = start + Duration::from_millis(i as u64 * 500);
+            caps.changed(&current, now);
+            current.features.push(Feature::new(*var));
+            assert!(!caps.broadcast_due(&current, now));
+        }
+        // A second after the last change, not the first one.
+        let last = start + Duration::from_millis(1000);
+        assert_eq!(caps.next_deadline(), Some(last + BROADCAST_DEBOUNCE));
+        assert!(!caps.broadcast_due(&current, last + Duration::from_millis(999)));
+        assert!(caps.broadcast_due(&current, last + BROADCAST_DEBOUNCE));
+        assert!(!caps.broadcast_due(&current, last + BROADCAST_DEBOUNCE));
+        assert_eq!(caps.next_deadline(), None);
+
+        // The one broadcast before is still answered, not the intermediate ones.
+        let now = last + BROADCAST_DEBOUNCE;
+        let previous = caps.previous(&first, now).unwrap();
+        assert_eq!(previous.features, [Feature::new("a")]);
+        assert!(caps.previous(&ver(&disco(&["a", "b"])), now).is_none());
+        assert!(caps.previous(&first, now + PREVIOUS_GRACE).is_none());
+    }
+
+    #[test]
+    fn test_reverted() {
+        let start = Instant::now();
+        let mut caps = OwnCaps::default();
+        let old = disco(&["a"]);
+        caps.changed(&old, start);
+        caps.changed(&disco(&["a", "b"]), start);
+        // Nothing to tell if we went back to what others already know.
+        assert!(!caps.broadcast_due(&old, start + BROADCAST_DEBOUNCE));
+        assert!(caps.previous(&ver(&old), start).is_none());
+    }
+}
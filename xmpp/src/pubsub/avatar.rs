@@ -4,13 +4,15 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use super::Agent;
-use crate::Event;
+use super::{publish_item, Agent, ItemId, PubSubItem, RawItem};
+use crate::{storage, Event, Storage};
+use std::cell::RefCell;
 use std::convert::TryFrom;
-use std::fs::{self, File};
-use std::io::{self, Write};
+use std::path::Path;
 use xmpp_parsers::{
-    avatar::{Data, Metadata},
+    avatar::{Data, Info, Metadata},
+    caps::hash_caps,
+    hashes::{Algo, Sha1HexAttribute},
     iq::Iq,
     ns,
     pubsub::{
@@ -18,9 +20,86 @@ use xmpp_parsers::{
         pubsub::{Items, PubSub},
         NodeName,
     },
-    Jid,
+    vcard_update::{Photo, VCardUpdate},
+    BareJid, Element, Jid,
 };
 
+/// How many of our own avatar hashes to remember, to recognise their echoes.
+const RECENTLY_PUBLISHED: usize = 8;
+
+pub(crate) async fn publish_avatar(agent: &mut Agent, data: Vec<u8>, type_: &str) {
+    let bytes = match u32::try_from(data.len()) {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            warn!("Avatar too big to be published: {} bytes", data.len());
+            return;
+        }
+    };
+    let hash = hash_caps(&data, Algo::Sha_1).unwrap();
+    let id = hash.to_hex();
+
+    // Keep a copy, so that the notification of our own metadata doesn’t trigger a download.
+    let own_jid = Jid::Bare(agent.own_jid.clone());
+    let saved = storage::save_avatar(
+        &mut *agent.storage.borrow_mut(),
+        &agent.avatar_directory,
+        &own_jid,
+        &id,
+        &data,
+    );
+    if let Err(err) = saved {
+        warn!("Couldn’t save our own avatar: {}", err);
+    }
+    agent.published_avatars.push_back(id.clone());
+    if agent.published_avatars.len() > RECENTLY_PUBLISHED {
+        agent.published_avatars.pop_front();
+    }
+
+    let iq = publish_item("avatar-data", ns::AVATAR_DATA, &id, Data { data }, None);
+    let _ = agent.send_stanza(iq.into()).await;
+    let metadata = Metadata {
+        infos: vec![Info {
+            bytes,
+            width: None,
+            height: None,
+            id: id.parse::<Sha1HexAttribute>().unwrap(),
+            type_: String::from(type_),
+            url: None,
+        }],
+    };
+    let iq = publish_item("avatar-metadata", ns::AVATAR_METADATA, &id, metadata, None);
+    let _ = agent.send_stanza(iq.into()).await;
+}
+
+pub(crate) async fn handle_vcard_update(
+    from: &BareJid,
+    agent: &mut Agent,
+    payload: Element,
+) -> Vec<Event> {
+    let id = match VCardUpdate::try_from(payload) {
+        Ok(VCardUpdate {
+            photo: Some(Photo { data: Some(id) }),
+        }) => id,
+        _ => return vec![],
+    };
+    if *from == agent.own_jid && agent.published_avatars.contains(&id) {
+        // The server mirrored the avatar we just published, there is nothing new here.
+        return vec![];
+    }
+    let from = Jid::Bare(from.clone());
+    if storage::avatar(&*agent.storage.borrow(), &from, &id).is_some() {
+        return vec![];
+    }
+    if agent.server_info.pep_vcard_conversion {
+        // PEP is authoritative on such servers, so fetch it from there rather than vcard-temp.
+        let iq = download_latest_avatar(&from);
+        let _ = agent.send_stanza(iq.into()).await;
+    } else {
+        debug!("Ignoring vcard-temp avatar {} of {}", id, from);
+    }
+    vec![]
+}
+
 pub(crate) async fn handle_metadata_pubsub_event(
     from: &Jid,
     agent: &mut Agent,
@@ -28,29 +107,111 @@ pub(crate) async fn handle_metadata_pubsub_event(
 ) -> Vec<Event> {
     let mut events = Vec::new();
     for item in items {
-        let payload = item.payload.clone().unwrap();
-        if payload.is("metadata", ns::AVATAR_METADATA) {
-            let metadata = Metadata::try_from(payload).unwrap();
-            for info in metadata.infos {
-                let filename = format!("data/{}/{}", from, &*info.id.to_hex());
-                let file_length = match fs::metadata(filename.clone()) {
-                    Ok(metadata) => metadata.len(),
-                    Err(_) => 0,
-                };
-                // TODO: Also check the hash.
-                if info.bytes as u64 == file_length {
-                    events.push(Event::AvatarRetrieved(from.clone(), filename));
-                } else {
-                    let iq = download_avatar(from);
-                    let _ = agent.client.send_stanza(iq.into()).await;
-                }
+        let metadata = match item.payload.clone().map(Metadata::try_from) {
+            Some(Ok(metadata)) => metadata,
+            _ => continue,
+        };
+        let variants = metadata.infos;
+        let info = match select_variant(
+            &variants,
+            agent.avatar_max_dimension,
+            agent.avatar_max_bytes,
+            agent.http_fetcher.is_some(),
+        ) {
+            Some(info) => info.clone(),
+            // No avatar, or none we can download.
+            None => continue,
+        };
+        agent.avatar_variants.insert(from.clone(), variants.clone());
+        let id = info.id.to_hex();
+        // Only verified avatars get saved, so this one can be trusted.
+        let saved = storage::avatar(&*agent.storage.borrow(), from, &id);
+        if let Some(saved) = saved {
+            events.push(Event::AvatarRetrieved(from.clone(), saved, variants));
+            continue;
+        }
+        match (info.url, agent.http_fetcher.clone()) {
+            (Some(url), Some(fetch)) => {
+                // Downloading can take a while, so don’t hold the other events back meanwhile.
+                let download = fetch(url.clone());
+                let from = from.clone();
+                let storage = agent.storage.clone();
+                let directory = agent.avatar_directory.clone();
+                agent.tasks.spawn(move |cancel| async move {
+                    let data = tokio::select! {
+                        result = download => match result {
+                            Ok(data) => data,
+                            Err(err) => {
+                                warn!(
+                                    "Couldn’t download the avatar of {} from {}: {}",
+                                    from, url, err
+                                );
+                                return vec![];
+                            }
+                        },
+                        _ = cancel.cancelled() => return vec![],
+                    };
+                    match verify_and_save(&storage, &directory, &from, &id, &data) {
+                        Some(filename) => vec![Event::AvatarRetrieved(from, filename, variants)],
+                        None => vec![],
+                    }
+                });
+            }
+            _ => {
+                let iq = download_avatar(from, &id);
+                let _ = agent.send_stanza(iq.into()).await;
             }
         }
     }
     events
 }
 
-fn download_avatar(from: &Jid) -> Iq {
+/// Pick the variant of an avatar to download: the largest one fitting in `max_dimension`, or
+/// else the smallest one, preferring the one hosted in PEP when two have the same size.
+/// Without `max_dimension`, the first one hosted in PEP is picked.
+///
+/// Variants hosted over HTTP are only considered when we can download them.
+pub(crate) fn select_variant(
+    variants: &[Info],
+    max_dimension: Option<u16>,
+    max_bytes: Option<u32>,
+    can_fetch_http: bool,
+) -> Option<&Info> {
+    let usable = variants
+        .iter()
+        .filter(|info| info.url.is_none() || can_fetch_http)
+        .filter(|info| info.bytes <= max_bytes.unwrap_or(u32::MAX));
+    let max_dimension = match max_dimension {
+        Some(max_dimension) => i32::from(max_dimension),
+        None => return usable.min_by_key(|info| info.url.is_some()),
+    };
+    usable.max_by_key(|info| {
+        let dimension = i32::from(info.width.max(info.height).unwrap_or(0));
+        let fits = dimension <= max_dimension;
+        // Bigger is better when it fits, smaller otherwise.
+        let closeness = if fits { dimension } else { -dimension };
+        (fits, closeness, info.url.is_none())
+    })
+}
+
+/// Ask for the data of the avatar `id` of `from`.
+fn download_avatar(from: &Jid, id: &str) -> Iq {
+    let item = RawItem::new(Some(ItemId(String::from(id))), None, None::<Data>);
+    Iq::from_get(
+        "coucou",
+        PubSub::Items(Items {
+            max_items: None,
+            node: NodeName(String::from(ns::AVATAR_DATA)),
+            subid: None,
+            items: vec![PubSubItem(item)],
+            unknown: vec![],
+        }),
+    )
+    .with_to(from.clone())
+}
+
+/// Ask for the latest avatar of `from`, whatever its id.
+fn download_latest_avatar(from: &Jid) -> Iq {
     Iq::from_get(
         "coucou",
         PubSub::Items(Items {
@@ -58,36 +219,104 @@ fn download_avatar(from: &Jid) -> Iq {
             node: NodeName(String::from(ns::AVATAR_DATA)),
             subid: None,
             items: Vec::new(),
+            unknown: Vec::new(),
         }),
     )
     .with_to(from.clone())
 }
 
-// The return value of this function will be simply pushed to a Vec in the caller function,
-// so it makes no sense to allocate a Vec here - we're lazy instead
-pub(crate) fn handle_data_pubsub_iq<'a>(
-    from: &'a Jid,
-    items: &'a Items,
-) -> impl IntoIterator<Item = Event> + 'a {
-    let from = from.clone();
+pub(crate) fn handle_data_pubsub_iq(from: &Jid, items: &Items, agent: &Agent) -> Vec<Event> {
     items
         .items
         .iter()
-        .filter_map(move |item| match (&item.id, &item.payload) {
+        .filter_map(|item| match (&item.id, &item.payload) {
             (Some(id), Some(payload)) => {
-                let data = Data::try_from(payload.clone()).unwrap();
-                let filename = save_avatar(&from, id.0.clone(), &data.data).unwrap();
-                Some(Event::AvatarRetrieved(from.clone(), filename))
+                let data = Data::try_from(payload.clone()).ok()?;
+                let filename = verify_and_save(
+                    &agent.storage,
+                    &agent.avatar_directory,
+                    from,
+                    &id.0,
+                    &data.data,
+                )?;
+                let variants = agent.avatar_variants.get(from).cloned().unwrap_or_default();
+                Some(Event::AvatarRetrieved(from.clone(), filename, variants))
             }
             _ => None,
         })
+        .collect()
+}
+
+/// Save the avatar of `from` if `data` matches its SHA-1 `id`, returning where.
+fn verify_and_save(
+    storage: &RefCell<dyn Storage>,
+    directory: &Path,
+    from: &Jid,
+    id: &str,
+    data: &[u8],
+) -> Option<String> {
+    let hash = hash_caps(data, Algo::Sha_1).ok()?;
+    if hash.to_hex() != id {
+        warn!(
+            "Avatar of {} doesn’t match its hash {}, ignoring it",
+            from, id
+        );
+        return None;
+    }
+    match storage::save_avatar(&mut *storage.borrow_mut(), directory, from, id, data) {
+        Ok(filename) => Some(filename),
+        Err(err) => {
+            warn!("Couldn’t save the avatar of {}: {}", from, err);
+            None
+        }
+    }
 }
 
-fn save_avatar(from: &Jid, id: String, data: &[u8]) -> io::Result<String> {
-    let directory = format!("data/{}", from);
-    let filename = format!("data/{}/{}", from, id);
-    fs::create_dir_all(directory)?;
-    let mut file = File::create(&filename)?;
-    file.write_all(data)?;
-    Ok(filename)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(size: u16, url: Option<&str>) -> Info {
+        Info {
+            bytes: u32::from(size) * 100,
+            width: Some(size),
+            height: Some(size),
+            id: "111f4b3c50d7b0df729d299bc6f8e9ef9066971f".parse().unwrap(),
+            type_: String::from("image/png"),
+            url: url.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_select_variant() {
+        let variants = [
+            info(64, None),
+            info(128, Some("https://example.org/128.png")),
+            info(512, Some("https://example.org/512.png")),
+        ];
+        let selected =
+            |max, http| select_variant(&variants, max, None, http).map(|info| info.width);
+
+        assert_eq!(selected(Some(256), true), Some(Some(128)));
+        assert_eq!(selected(Some(1024), true), Some(Some(512)));
+        // Nothing fits, so the smallest one.
+        assert_eq!(selected(Some(32), true), Some(Some(64)));
+        // Only PEP is usable without a way to fetch over HTTP.
+        assert_eq!(selected(Some(256), false), Some(Some(64)));
+        assert_eq!(selected(None, true), Some(Some(64)));
+        // Too heavy variants are ignored, even when nothing else is left.
+        let light = |max_bytes| {
+            select_variant(&variants, Some(1024), Some(max_bytes), true).map(|info| info.width)
+        };
+        assert_eq!(light(12_800), Some(Some(128)));
+        assert_eq!(light(6_399), None);
+
+        // PEP wins for the same size.
+        let variants = [info(64, Some("https://example.org/64.png")), info(64, None)];
+        assert_eq!(
+            select_variant(&variants, Some(64), None, true),
+            Some(&variants[1])
+        );
+        assert_eq!(select_variant(&[], Some(64), None, true), None);
+    }
 }
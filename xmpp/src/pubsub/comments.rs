@@ -0,0 +1,362 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The exchanges behind [`Agent::comments_node_for`], [`Agent::create_comments_node`],
+//! [`Agent::publish_comment`] and [`Agent::fetch_comments`].
+
+use crate::{Agent, Error};
+use std::convert::TryFrom;
+use xmpp_parsers::{
+    iq::Iq,
+    pubsub::pubsub::{Configure, Create, Item as PubSubItem, Items, PubSub, Publish},
+    pubsub::reference::{comments_node_config, Reference},
+    pubsub::{Item, ItemId, NodeName},
+    rsm::SetQuery,
+    Element, Jid,
+};
+
+/// A page of the comments on an item, see [`Agent::fetch_comments`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommentsPage {
+    /// The comments, in the order of the node, usually oldest first.
+    pub comments: Vec<Item>,
+    /// What to pass as `after` to fetch the next page, `None` if this one is the last.
+    pub next: Option<String>,
+    /// How many comments there are in all, if the service tells.
+    pub count: Option<usize>,
+}
+
+fn missing(what: &'static str) -> Error {
+    Error::Protocol(xmpp_parsers::Error::ParseError(what).into())
+}
+
+/// The PubSub payload of `elem` if it answers our request with this `id` to `service`.
+fn pubsub_result(
+    agent: &Agent,
+    elem: &Element,
+    id: &str,
+    service: &Jid,
+) -> Option<Result<Option<PubSub>, Error>> {
+    Some(match agent.iq_result(elem, id, service)? {
+        Ok(Some(payload)) => PubSub::try_from(payload)
+            .map(Some)
+            .map_err(|e| Error::Protocol(e.into())),
+        Ok(None) => Ok(None),
+        Err(err) => Err(err),
+    })
+}
+
+/// Fetch the item `item` of `node` on `service`.
+pub(crate) fn item_request(service: &Jid, node: &NodeName, item: &ItemId) -> Iq {
+    let mut items = Items::new(&node.0);
+    items.items.push(PubSubItem(Item {
+        id: Some(item.clone()),
+        publisher: None,
+        payload: None,
+    }));
+    Iq::from_get("comments-item", PubSub::Items(items)).with_to(service.clone())
+}
+
+/// Whether `elem` answers [`item_request`] for `item`, and the reference to the comments on it
+/// if there is one.
+pub(crate) fn reference_response(
+    agent: &Agent,
+    service: &Jid,
+    item: &ItemId,
+    elem: &Element,
+) -> Option<Result<Option<Reference>, Error>> {
+    Some(
+        match pubsub_result(agent, elem, "comments-item", service)? {
+            Ok(Some(PubSub::Items(items))) => Ok(items
+                .items
+                .into_iter()
+                .map(|item| item.0)
+                .filter(|found| found.id.as_ref() == Some(item))
+                .find_map(|found| found.payload.as_ref().and_then(Reference::find))),
+            Ok(_) => Err(missing("Missing items in PubSub result.")),
+            Err(err) => Err(err),
+        },
+    )
+}
+
+/// Create the node of `comments`, open to anyone.
+pub(crate) fn create_request(comments: &Reference) -> Iq {
+    let pubsub = PubSub::Create {
+        create: Create {
+            node: Some(comments.node.clone()),
+        },
+        configure: Some(Configure {
+            form: Some(comments_node_config()),
+        }),
+    };
+    Iq::from_set("comments-create", pubsub).with_to(comments.service.clone())
+}
+
+/// Whether `elem` answers [`create_request`], and where the node got created, the service being
+/// free to pick another name than the one asked for.
+pub(crate) fn create_response(
+    agent: &Agent,
+    comments: &Reference,
+    elem: &Element,
+) -> Option<Result<Reference, Error>> {
+    Some(
+        match pubsub_result(agent, elem, "comments-create", &comments.service)? {
+            Ok(Some(PubSub::Create { create, .. })) => Ok(Reference {
+                service: comments.service.clone(),
+                node: create.node.unwrap_or_else(|| comments.node.clone()),
+            }),
+            Ok(None) => Ok(comments.clone()),
+            Ok(Some(_)) => Err(missing("Missing created node in PubSub result.")),
+            Err(err) => Err(err),
+        },
+    )
+}
+
+/// Publish `entry` to the node of `comments`, letting the service pick its id.
+pub(crate) fn publish_request(comments: &Reference, entry: Element) -> Iq {
+    let item = Item {
+        id: None,
+        publisher: None,
+        payload: Some(entry),
+    };
+    let pubsub = PubSub::Publish {
+        publish: Publish {
+            node: comments.node.clone(),
+            items: vec![PubSubItem(item)],
+            unknown: vec![],
+        },
+        publish_options: None,
+    };
+    Iq::from_set("comments-publish", pubsub).with_to(comments.service.clone())
+}
+
+/// Whether `elem` answers [`publish_request`], and the id the comment got.
+pub(crate) fn publish_response(
+    agent: &Agent,
+    comments: &Reference,
+    elem: &Element,
+) -> Option<Result<ItemId, Error>> {
+    Some(
+        match pubsub_result(agent, elem, "comments-publish", &comments.service)? {
+            Ok(Some(PubSub::Publish { publish, .. })) => publish
+                .items
+                .into_iter()
+                .find_map(|item| item.0.id)
+                .ok_or_else(|| missing("Missing id of the published comment.")),
+            Ok(_) => Err(missing("Missing id of the published comment.")),
+            Err(err) => Err(err),
+        },
+    )
+}
+
+/// Fetch at most `max` comments from the node of `comments`, after the one with the id `after`
+/// or from the first one.
+pub(crate) fn page_request(comments: &Reference, after: Option<String>, max: usize) -> Iq {
+    let pubsub = PubSub::ItemsQuery {
+        items: Items::new(&comments.node.0),
+        set: SetQuery {
+            max: Some(max),
+            after,
+            before: None,
+            index: None,
+        },
+    };
+    Iq::from_get("comments-page", pubsub).with_to(comments.service.clone())
+}
+
+/// Whether `elem` answers [`page_request`] for `max` comments, and with which.
+pub(crate) fn page_response(
+    agent: &Agent,
+    comments: &Reference,
+    max: usize,
+    elem: &Element,
+) -> Option<Result<CommentsPage, Error>> {
+    Some(
+        match pubsub_result(agent, elem, "comments-page", &comments.service)? {
+            Ok(Some(PubSub::ItemsPage { items, set })) => {
+                let comments: Vec<Item> = items.items.into_iter().map(|item| item.0).collect();
+                // A short page is the last one, as is the one reaching the count.
+                let end = set.first_index.unwrap_or(0) + comments.len();
+                let last = comments.len() < max || set.count.is_some_and(|count| end >= count);
+                Ok(CommentsPage {
+                    next: set.last.filter(|_| !last),
+                    count: set.count,
+                    comments,
+                })
+            }
+            // A service without paging returns every comment at once.
+            Ok(Some(PubSub::Items(items))) => Ok(CommentsPage {
+                count: Some(items.items.len()),
+                comments: items.items.into_iter().map(|item| item.0).collect(),
+                next: None,
+            }),
+            Ok(_) => Err(missing("Missing comments in PubSub result.")),
+            Err(err) => Err(err),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClientBuilder;
+    use tokio_xmpp::AsyncClient as TokioXmppClient;
+
+    fn agent() -> Agent {
+        let client = TokioXmppClient::new("juliet@capulet.lit", "meh").unwrap();
+        ClientBuilder::new("juliet@capulet.lit", "meh")
+            .build_impl(client)
+            .unwrap()
+    }
+
+    fn parse(xml: &str) -> Element {
+        xml.parse().unwrap()
+    }
+
+    fn service() -> Jid {
+        "pubsub.capulet.lit".parse().unwrap()
+    }
+
+    fn ids(page: &CommentsPage) -> Vec<&str> {
+        page.comments
+            .iter()
+            .map(|comment| comment.id.as_ref().unwrap().0.as_str())
+            .collect()
+    }
+
+    fn comment(id: &str, text: &str) -> String {
+        format!(
+            "<item id='{}'><entry xmlns='http://www.w3.org/2005/Atom'><title>{}</title></entry></item>",
+            id, text
+        )
+    }
+
+    #[test]
+    fn test_comments() {
+        let agent = agent();
+        let post = ItemId(String::from("balcony"));
+
+        // The post points at its comments node.
+        let iq = item_request(
+            &service(),
+            &NodeName(String::from("urn:xmpp:microblog:0")),
+            &post,
+        );
+        assert_eq!(
+            Element::from(iq),
+            parse("<iq xmlns='jabber:client' type='get' id='comments-item' to='pubsub.capulet.lit'><pubsub xmlns='http://jabber.org/protocol/pubsub'><items node='urn:xmpp:microblog:0'><item id='balcony'/></items></pubsub></iq>")
+        );
+        let found = reference_response(
+            &agent,
+            &service(),
+            &post,
+            &parse("<iq xmlns='jabber:client' type='result' id='comments-item' from='pubsub.capulet.lit'><pubsub xmlns='http://jabber.org/protocol/pubsub'><items node='urn:xmpp:microblog:0'><item id='balcony'><entry xmlns='http://www.w3.org/2005/Atom'><title>Balcony</title><link rel='replies' title='comments' href='xmpp:pubsub.capulet.lit?;node=urn%3Axmpp%3Amicroblog%3A0%3Acomments%2Fbalcony'/></entry></item></items></pubsub></iq>"),
+        );
+        let comments = found.unwrap().unwrap().unwrap();
+        assert_eq!(comments, Reference::for_item(service(), &post));
+
+        // Someone else’s answer is left alone.
+        let other = parse("<iq xmlns='jabber:client' type='result' id='comments-item' from='pubsub.montague.lit'/>");
+        assert!(reference_response(&agent, &service(), &post, &other).is_none());
+
+        let iq = create_request(&comments);
+        assert_eq!(
+            Element::from(iq),
+            parse("<iq xmlns='jabber:client' type='set' id='comments-create' to='pubsub.capulet.lit'><pubsub xmlns='http://jabber.org/protocol/pubsub'><create node='urn:xmpp:microblog:0:comments/balcony'/><configure><x xmlns='jabber:x:data' type='submit'><field var='FORM_TYPE' type='hidden'><value>http://jabber.org/protocol/pubsub#node_config</value></field><field var='pubsub#access_model'><value>open</value></field><field var='pubsub#publish_model'><value>open</value></field><field var='pubsub#persist_items' type='boolean'><value>true</value></field><field var='pubsub#max_items'><value>max</value></field><field var='pubsub#notify_retract' type='boolean'><value>true</value></field></x></configure></pubsub></iq>")
+        );
+        let created = create_response(
+            &agent,
+            &comments,
+            &parse("<iq xmlns='jabber:client' type='result' id='comments-create' from='pubsub.capulet.lit'/>"),
+        );
+        assert_eq!(created.unwrap().unwrap(), comments);
+
+        let entry =
+            parse("<entry xmlns='http://www.w3.org/2005/Atom'><title>Wherefore?</title></entry>");
+        let iq = publish_request(&comments, entry);
+        assert_eq!(
+            Element::from(iq),
+            parse("<iq xmlns='jabber:client' type='set' id='comments-publish' to='pubsub.capulet.lit'><pubsub xmlns='http://jabber.org/protocol/pubsub'><publish node='urn:xmpp:microblog:0:comments/balcony'><item><entry xmlns='http://www.w3.org/2005/Atom'><title>Wherefore?</title></entry></item></publish></pubsub></iq>")
+        );
+        let published = publish_response(
+            &agent,
+            &comments,
+            &parse("<iq xmlns='jabber:client' type='result' id='comments-publish' from='pubsub.capulet.lit'><pubsub xmlns='http://jabber.org/protocol/pubsub'><publish node='urn:xmpp:microblog:0:comments/balcony'><item id='c3'/></publish></pubsub></iq>"),
+        );
+        assert_eq!(published.unwrap().unwrap(), ItemId(String::from("c3")));
+
+        // Then the comments get fetched two at a time.
+        let iq = page_request(&comments, None, 2);
+        assert_eq!(
+            Element::from(iq),
+            parse("<iq xmlns='jabber:client' type='get' id='comments-page' to='pubsub.capulet.lit'><pubsub xmlns='http://jabber.org/protocol/pubsub'><items node='urn:xmpp:microblog:0:comments/balcony'/><set xmlns='http://jabber.org/protocol/rsm'><max>2</max></set></pubsub></iq>")
+        );
+        let result = format!(
+            "<iq xmlns='jabber:client' type='result' id='comments-page' from='pubsub.capulet.lit'><pubsub xmlns='http://jabber.org/protocol/pubsub'><items node='urn:xmpp:microblog:0:comments/balcony'>{}{}</items><set xmlns='http://jabber.org/protocol/rsm'><first index='0'>c1</first><last>c2</last><count>3</count></set></pubsub></iq>",
+            comment("c1", "Hi!"),
+            comment("c2", "Hello")
+        );
+        let page = page_response(&agent, &comments, 2, &parse(&result))
+            .unwrap()
+            .unwrap();
+        assert_eq!(ids(&page), ["c1", "c2"]);
+        assert_eq!(page.count, Some(3));
+        assert_eq!(page.next.as_deref(), Some("c2"));
+
+        let iq = page_request(&comments, page.next, 2);
+        assert_eq!(
+            Element::from(iq),
+            parse("<iq xmlns='jabber:client' type='get' id='comments-page' to='pubsub.capulet.lit'><pubsub xmlns='http://jabber.org/protocol/pubsub'><items node='urn:xmpp:microblog:0:comments/balcony'/><set xmlns='http://jabber.org/protocol/rsm'><max>2</max><after>c2</after></set></pubsub></iq>")
+        );
+        let result = format!(
+            "<iq xmlns='jabber:client' type='result' id='comments-page' from='pubsub.capulet.lit'><pubsub xmlns='http://jabber.org/protocol/pubsub'><items node='urn:xmpp:microblog:0:comments/balcony'>{}</items><set xmlns='http://jabber.org/protocol/rsm'><first index='2'>c3</first><last>c3</last><count>3</count></set></pubsub></iq>",
+            comment("c3", "Wherefore?")
+        );
+        let page = page_response(&agent, &comments, 2, &parse(&result))
+            .unwrap()
+            .unwrap();
+        assert_eq!(ids(&page), ["c3"]);
+        assert_eq!(page.next, None);
+    }
+
+    #[test]
+    fn test_comments_without_paging() {
+        let agent = agent();
+        let comments = Reference::for_item(service(), &ItemId(String::from("balcony")));
+
+        // The service answers with a full page which isn’t the last, without a count.
+        let result = format!(
+            "<iq xmlns='jabber:client' type='result' id='comments-page' from='pubsub.capulet.lit'><pubsub xmlns='http://jabber.org/protocol/pubsub'><items node='urn:xmpp:microblog:0:comments/balcony'>{}</items><set xmlns='http://jabber.org/protocol/rsm'><first>c1</first><last>c1</last></set></pubsub></iq>",
+            comment("c1", "Hi!")
+        );
+        let page = page_response(&agent, &comments, 1, &parse(&result))
+            .unwrap()
+            .unwrap();
+        assert_eq!(page.next.as_deref(), Some("c1"));
+
+        // Or ignores paging and returns everything.
+        let result = format!(
+            "<iq xmlns='jabber:client' type='result' id='comments-page' from='pubsub.capulet.lit'><pubsub xmlns='http://jabber.org/protocol/pubsub'><items node='urn:xmpp:microblog:0:comments/balcony'>{}{}</items></pubsub></iq>",
+            comment("c1", "Hi!"),
+            comment("c2", "Hello")
+        );
+        let page = page_response(&agent, &comments, 1, &parse(&result))
+            .unwrap()
+            .unwrap();
+        assert_eq!(ids(&page), ["c1", "c2"]);
+        assert_eq!(page.next, None);
+
+        // A post without comments.
+        let post = ItemId(String::from("balcony"));
+        let found = reference_response(
+            &agent,
+            &service(),
+            &post,
+            &parse("<iq xmlns='jabber:client' type='result' id='comments-item' from='pubsub.capulet.lit'><pubsub xmlns='http://jabber.org/protocol/pubsub'><items node='urn:xmpp:microblog:0'><item id='balcony'><entry xmlns='http://www.w3.org/2005/Atom'><title>Balcony</title></entry></item></items></pubsub></iq>"),
+        );
+        assert_eq!(found.unwrap().unwrap(), None);
+    }
+}
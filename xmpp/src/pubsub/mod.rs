@@ -5,80 +5,165 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use super::Agent;
-use crate::Event;
+use crate::{Event, LocationAccess};
 use std::convert::TryFrom;
 use std::str::FromStr;
 use xmpp_parsers::{
     bookmarks2::{Autojoin, Conference},
+    data_forms::{DataForm, DataFormType, Field, FieldType},
+    geoloc::Geoloc,
+    iq::Iq,
     ns,
     pubsub::event::PubSubEvent,
-    pubsub::pubsub::PubSub,
+    pubsub::pubsub::{Item as PubSubItem, PubSub, Publish, PublishOptions},
+    pubsub::{Item as RawItem, ItemId, NodeName, PubSubPayload},
     BareJid, Element, Jid,
 };
 
 #[cfg(feature = "avatars")]
 pub(crate) mod avatar;
+pub(crate) mod comments;
 
 pub(crate) async fn handle_event(from: &Jid, elem: Element, agent: &mut Agent) -> Vec<Event> {
-    let mut events = Vec::new();
-    let event = PubSubEvent::try_from(elem);
+    let event = match PubSubEvent::try_from(elem) {
+        Ok(event) => event,
+        Err(err) => {
+            warn!("Invalid PubSub event from {}: {}", from, err);
+            return vec![];
+        }
+    };
     trace!("PubSub event: {:#?}", event);
-    match event {
-        Ok(PubSubEvent::PublishedItems { node, items }) => {
-            match node.0 {
-                #[cfg(feature = "avatars")]
-                ref node if node == ns::AVATAR_METADATA => {
-                    let new_events =
-                        avatar::handle_metadata_pubsub_event(&from, agent, items).await;
-                    events.extend(new_events);
-                }
-                ref node if node == ns::BOOKMARKS2 => {
-                    // TODO: Check that our bare JID is the sender.
-                    assert_eq!(items.len(), 1);
-                    let item = items.clone().pop().unwrap();
-                    let jid = BareJid::from_str(&item.id.clone().unwrap().0).unwrap();
-                    let payload = item.payload.clone().unwrap();
-                    match Conference::try_from(payload) {
-                        Ok(conference) => {
-                            if conference.autojoin == Autojoin::True {
-                                events.push(Event::JoinRoom(jid, conference));
-                            } else {
-                                events.push(Event::LeaveRoom(jid));
-                            }
-                        }
-                        Err(err) => println!("not bookmark: {}", err),
+    let node = event_node(&event).0.clone();
+    // The nodes we know about get handled here, every other one is left to the application.
+    match (node.as_str(), event) {
+        #[cfg(feature = "avatars")]
+        (ns::AVATAR_METADATA, PubSubEvent::PublishedItems { items, .. }) => {
+            avatar::handle_metadata_pubsub_event(&from, agent, items).await
+        }
+        (ns::BOOKMARKS2, _) if *from != agent.own_jid => {
+            // Only our own account has a say in our bookmarks.
+            warn!("Ignoring bookmarks event from {}", from);
+            vec![]
+        }
+        (ns::BOOKMARKS2, PubSubEvent::PublishedItems { items, .. }) => {
+            let (jid, conference) = match items.as_slice() {
+                [item] => match bookmark(&item.0) {
+                    Ok(bookmark) => bookmark,
+                    Err(err) => {
+                        warn!("Invalid bookmark: {}", err);
+                        return vec![];
                     }
+                },
+                _ => {
+                    warn!("Expected a single bookmark, got {}", items.len());
+                    return vec![];
                 }
-                ref node => unimplemented!("node {}", node),
+            };
+            agent.bookmarks.insert(jid.clone(), conference.clone());
+            agent.save_bookmarks();
+            if conference.autojoin == Autojoin::True {
+                vec![Event::JoinRoom(jid, conference)]
+            } else {
+                vec![Event::LeaveRoom(jid)]
             }
         }
-        Ok(PubSubEvent::RetractedItems { node, items }) => {
-            match node.0 {
-                ref node if node == ns::BOOKMARKS2 => {
-                    // TODO: Check that our bare JID is the sender.
-                    assert_eq!(items.len(), 1);
-                    let item = items.clone().pop().unwrap();
-                    let jid = BareJid::from_str(&item.0).unwrap();
-                    events.push(Event::LeaveRoom(jid));
+        (ns::BOOKMARKS2, PubSubEvent::RetractedItems { items, .. }) => {
+            let jid = match items.as_slice() {
+                [item] => match BareJid::from_str(&item.0) {
+                    Ok(jid) => jid,
+                    Err(err) => {
+                        warn!("Invalid retracted bookmark {}: {}", item.0, err);
+                        return vec![];
+                    }
+                },
+                _ => {
+                    warn!("Expected a single retracted bookmark, got {}", items.len());
+                    return vec![];
                 }
-                ref node => unimplemented!("node {}", node),
-            }
+            };
+            agent.bookmarks.remove(&jid);
+            agent.save_bookmarks();
+            vec![Event::LeaveRoom(jid)]
         }
-        Ok(PubSubEvent::Purge { node }) => {
-            match node.0 {
-                ref node if node == ns::BOOKMARKS2 => {
-                    // TODO: Check that our bare JID is the sender.
-                    events.push(Event::LeaveAllRooms);
+        (ns::BOOKMARKS2, PubSubEvent::Purge { .. })
+        | (ns::BOOKMARKS2, PubSubEvent::Delete { .. }) => {
+            agent.bookmarks.clear();
+            agent.save_bookmarks();
+            vec![Event::LeaveAllRooms]
+        }
+        (ns::GEOLOC, PubSubEvent::PublishedItems { items, .. }) => {
+            // Only the latest item is the current location.
+            let payload = match items.into_iter().last().and_then(|item| item.0.payload) {
+                Some(payload) => payload,
+                None => return vec![],
+            };
+            match Geoloc::try_from(payload) {
+                Ok(geoloc) if geoloc.is_empty() => vec![Event::ContactLocation(from.clone(), None)],
+                Ok(geoloc) => vec![Event::ContactLocation(from.clone(), Some(geoloc))],
+                Err(err) => {
+                    warn!("Invalid location from {}: {}", from, err);
+                    vec![]
                 }
-                ref node => unimplemented!("node {}", node),
             }
         }
-        _ => unimplemented!(),
+        (ns::GEOLOC, PubSubEvent::RetractedItems { .. })
+        | (ns::GEOLOC, PubSubEvent::Purge { .. })
+        | (ns::GEOLOC, PubSubEvent::Delete { .. }) => {
+            vec![Event::ContactLocation(from.clone(), None)]
+        }
+        (_, event) => vec![generic_event(from.clone(), event)],
+    }
+}
+
+fn event_node(event: &PubSubEvent) -> &NodeName {
+    match event {
+        PubSubEvent::Configuration { node, .. }
+        | PubSubEvent::Delete { node, .. }
+        | PubSubEvent::PublishedItems { node, .. }
+        | PubSubEvent::RetractedItems { node, .. }
+        | PubSubEvent::Purge { node }
+        | PubSubEvent::Subscription { node, .. } => node,
     }
-    events
 }
 
-pub(crate) fn handle_iq_result(from: &Jid, elem: Element) -> impl IntoIterator<Item = Event> {
+fn generic_event(from: Jid, event: PubSubEvent) -> Event {
+    match event {
+        PubSubEvent::Configuration { node, form } => {
+            Event::PubSubNodeConfigured { from, node, form }
+        }
+        PubSubEvent::Delete { node, redirect } => Event::PubSubNodeDeleted {
+            from,
+            node,
+            redirect,
+        },
+        PubSubEvent::PublishedItems { node, items } => Event::PubSubItemsPublished {
+            from,
+            node,
+            items: items.into_iter().map(|item| item.0).collect(),
+        },
+        PubSubEvent::RetractedItems { node, items } => {
+            Event::PubSubItemsRetracted { from, node, items }
+        }
+        PubSubEvent::Purge { node } => Event::PubSubNodePurged { from, node },
+        PubSubEvent::Subscription {
+            node,
+            jid,
+            subscription,
+            ..
+        } => Event::PubSubSubscriptionChanged {
+            from,
+            node,
+            jid,
+            subscription,
+        },
+    }
+}
+
+pub(crate) fn handle_iq_result(
+    from: &Jid,
+    elem: Element,
+    agent: &mut Agent,
+) -> impl IntoIterator<Item = Event> {
     let mut events = Vec::new();
     let pubsub = PubSub::try_from(elem).unwrap();
     trace!("PubSub: {:#?}", pubsub);
@@ -86,27 +171,112 @@ pub(crate) fn handle_iq_result(from: &Jid, elem: Element) -> impl IntoIterator<I
         match items.node.0.clone() {
             #[cfg(feature = "avatars")]
             ref node if node == ns::AVATAR_DATA => {
-                let new_events = avatar::handle_data_pubsub_iq(&from, &items);
+                let new_events = avatar::handle_data_pubsub_iq(&from, &items, agent);
                 events.extend(new_events);
             }
+            // Only our own account has a say in our bookmarks.
+            ref node if node == ns::BOOKMARKS2 && !own_account_result(from, agent) => {
+                warn!("Ignoring bookmarks from {}", from);
+            }
             ref node if node == ns::BOOKMARKS2 => {
                 events.push(Event::LeaveAllRooms);
+                agent.bookmarks.clear();
                 for item in items.items {
-                    let item = item.0;
-                    let jid = BareJid::from_str(&item.id.clone().unwrap().0).unwrap();
-                    let payload = item.payload.clone().unwrap();
-                    match Conference::try_from(payload) {
-                        Ok(conference) => {
-                            if let Autojoin::True = conference.autojoin {
-                                events.push(Event::JoinRoom(jid, conference));
-                            }
+                    let (jid, conference) = match bookmark(&item.0) {
+                        Ok(bookmark) => bookmark,
+                        Err(err) => {
+                            warn!("Invalid bookmark: {}", err);
+                            continue;
                         }
-                        Err(err) => panic!("Wrong payload type in bookmarks 2 item: {}", err),
+                    };
+                    agent.bookmarks.insert(jid.clone(), conference.clone());
+                    if let Autojoin::True = conference.autojoin {
+                        events.push(Event::JoinRoom(jid, conference));
                     }
                 }
+                agent.save_bookmarks();
             }
             _ => unimplemented!(),
         }
     }
     events
 }
+
+/// The room and conference of a bookmarks2 item, or why it isn’t one.
+fn bookmark(item: &RawItem) -> Result<(BareJid, Conference), String> {
+    let id = item.id.as_ref().ok_or("no room")?;
+    let jid = BareJid::from_str(&id.0).map_err(|err| format!("{}: {}", id.0, err))?;
+    let payload = item
+        .payload
+        .clone()
+        .ok_or_else(|| format!("{}: no conference", jid))?;
+    let conference = Conference::try_from(payload).map_err(|err| format!("{}: {}", jid, err))?;
+    Ok((jid, conference))
+}
+
+/// Whether an iq result from `from` comes from our own account, those without a from being
+/// given our bound JID.
+fn own_account_result(from: &Jid, agent: &Agent) -> bool {
+    *from == agent.own_jid || agent.client.bound_jid() == Some(from)
+}
+
+pub(crate) fn publish_item<P: PubSubPayload>(
+    iq_id: &str,
+    node: &str,
+    id: &str,
+    payload: P,
+    publish_options: Option<PublishOptions>,
+) -> Iq {
+    let item = RawItem::new(Some(ItemId(String::from(id))), None, Some(payload));
+    Iq::from_set(
+        iq_id,
+        PubSub::Publish {
+            publish: Publish {
+                node: NodeName(String::from(node)),
+                items: vec![PubSubItem(item)],
+                unknown: vec![],
+            },
+            publish_options,
+        },
+    )
+}
+
+/// Add or replace the bookmark of `room`, with the node configuration XEP-0402 requires.
+pub(crate) fn publish_bookmark(room: &BareJid, conference: Conference) -> Iq {
+    let form = DataForm::new(
+        DataFormType::Submit,
+        &format!("{}#publish-options", ns::PUBSUB),
+        vec![
+            Field::new("pubsub#persist_items", FieldType::Boolean).with_value("true"),
+            Field::text_single("pubsub#max_items", "max"),
+            Field::text_single("pubsub#send_last_published_item", "never"),
+            Field::text_single("pubsub#access_model", "whitelist"),
+        ],
+    );
+    publish_item(
+        "bookmark",
+        ns::BOOKMARKS2,
+        &room.to_string(),
+        conference,
+        Some(PublishOptions { form: Some(form) }),
+    )
+}
+
+/// Publish our location, or the empty one to stop publishing it, restricting who may see it.
+pub(crate) fn publish_location(location: Geoloc, access: LocationAccess) -> Iq {
+    let form = DataForm::new(
+        DataFormType::Submit,
+        &format!("{}#publish-options", ns::PUBSUB),
+        vec![Field::text_single(
+            "pubsub#access_model",
+            access.access_model(),
+        )],
+    );
+    publish_item(
+        "location",
+        ns::GEOLOC,
+        "current",
+        location,
+        Some(PublishOptions { form: Some(form) }),
+    )
+}
@@ -0,0 +1,87 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::time::{Duration, Instant};
+
+/// Token bucket limiting how many stanzas we send, to avoid tripping the server’s flood
+/// control.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    /// Tokens regained per second.
+    rate: f64,
+    /// Maximum amount of tokens, that is how many stanzas can be sent at once after being idle.
+    burst: f64,
+    /// Can be negative, when stanzas are already waiting for their turn.
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(stanzas_per_second: u32, burst: u32, now: Instant) -> TokenBucket {
+        let burst = f64::from(burst.max(1));
+        TokenBucket {
+            rate: f64::from(stanzas_per_second),
+            burst,
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    /// Take a token, and return how long to wait before the stanza can be sent.
+    ///
+    /// The token is taken even if it isn’t available yet, so that concurrent senders queue up
+    /// behind each other instead of all waking up at the same time.
+    pub(crate) fn take(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last);
+        if now > self.last {
+            self.last = now;
+        }
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        self.tokens -= 1.;
+        if self.tokens >= 0. {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenBucket;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_burst_then_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, 3, start);
+
+        // The burst goes out immediately.
+        for _ in 0..3 {
+            assert_eq!(bucket.take(start), Duration::from_secs(0));
+        }
+        // Then each stanza has to wait for its own token.
+        assert_eq!(bucket.take(start), Duration::from_millis(500));
+        assert_eq!(bucket.take(start), Duration::from_millis(1000));
+
+        // Once those have been sent, the bucket is empty again.
+        let later = start + Duration::from_secs(1);
+        assert_eq!(bucket.take(later), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_refill_is_capped() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10, 2, start);
+        assert_eq!(bucket.take(start), Duration::from_secs(0));
+
+        // Being idle for a long time doesn’t allow more than the burst.
+        let later = start + Duration::from_secs(3600);
+        assert_eq!(bucket.take(later), Duration::from_secs(0));
+        assert_eq!(bucket.take(later), Duration::from_secs(0));
+        assert_eq!(bucket.take(later), Duration::from_millis(100));
+    }
+}
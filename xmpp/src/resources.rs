@@ -0,0 +1,335 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The online resources of our contacts, as announced by their presence.
+
+use std::collections::{BTreeMap, HashMap};
+use tokio::sync::broadcast;
+use xmpp_parsers::{caps::Caps, date::DateTime, presence::Show, BareJid, FullJid};
+
+/// The availability of a contact, across all of its online resources.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AggregatedPresence {
+    /// The most available show among the online resources, `None` meaning either plainly
+    /// available or offline.
+    pub best_show: Option<Show>,
+    /// Every online resource, with its status message if it has one.  The contact is offline
+    /// when this is empty.
+    pub statuses_by_resource: BTreeMap<String, Option<String>>,
+}
+
+impl AggregatedPresence {
+    /// Whether at least one resource of this contact is online.
+    pub fn is_online(&self) -> bool {
+        !self.statuses_by_resource.is_empty()
+    }
+}
+
+/// What we know about a single online resource, from its last presence.
+#[derive(Debug, Clone)]
+pub struct Resource {
+    pub priority: i8,
+    pub show: Option<Show>,
+    /// The status message, in the preferred language if there are several.
+    pub status: Option<String>,
+    /// The entity capabilities (XEP-0115) of its last presence, if any.
+    pub caps: Option<Caps>,
+    /// Since when its user has been idle (XEP-0319), `None` if they aren’t.
+    pub idle_since: Option<DateTime>,
+}
+
+/// A change of the online resources of a contact, see [`crate::Agent::presence_updates`].
+#[derive(Debug, Clone)]
+pub enum PresenceUpdate {
+    /// A resource came online.
+    Online(FullJid, Resource),
+    /// A resource went offline, or we got disconnected.
+    Offline(FullJid),
+    /// A resource sent a new presence.
+    Updated {
+        jid: FullJid,
+        old: Resource,
+        new: Resource,
+    },
+}
+
+impl Resource {
+    /// How likely the user is to be in front of this resource, higher is better.
+    fn availability(&self) -> u8 {
+        match self.show {
+            Some(Show::Chat) => 4,
+            None => 3,
+            Some(Show::Away) => 2,
+            Some(Show::Xa) => 1,
+            Some(Show::Dnd) => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Resources {
+    contacts: HashMap<BareJid, BTreeMap<String, Resource>>,
+    updates: broadcast::Sender<PresenceUpdate>,
+}
+
+impl Default for Resources {
+    fn default() -> Self {
+        Resources {
+            contacts: HashMap::new(),
+            updates: broadcast::channel(crate::UPDATES_CAPACITY).0,
+        }
+    }
+}
+
+impl Resources {
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<PresenceUpdate> {
+        self.updates.subscribe()
+    }
+
+    #[cfg(feature = "test-internals")]
+    pub(crate) fn updates(&self) -> &broadcast::Sender<PresenceUpdate> {
+        &self.updates
+    }
+
+    /// `jid` sent an available presence, replacing whatever it announced before.
+    pub(crate) fn available(&mut self, jid: FullJid, resource: Resource) {
+        let old = self
+            .contacts
+            .entry(BareJid::from(jid.clone()))
+            .or_default()
+            .insert(jid.resource.clone(), resource.clone());
+        self.notify(match old {
+            Some(old) => PresenceUpdate::Updated {
+                jid,
+                old,
+                new: resource,
+            },
+            None => PresenceUpdate::Online(jid, resource),
+        });
+    }
+
+    /// `jid` went offline.
+    pub(crate) fn unavailable(&mut self, jid: &FullJid) {
+        let bare = BareJid::from(jid.clone());
+        let resources = match self.contacts.get_mut(&bare) {
+            Some(resources) => resources,
+            None => return,
+        };
+        let removed = resources.remove(&jid.resource).is_some();
+        if resources.is_empty() {
+            self.contacts.remove(&bare);
+        }
+        if removed {
+            self.notify(PresenceUpdate::Offline(jid.clone()));
+        }
+    }
+
+    /// `jid` went offline on every resource at once.
+    pub(crate) fn all_unavailable(&mut self, jid: &BareJid) {
+        if let Some(resources) = self.contacts.remove(jid) {
+            for name in resources.into_keys() {
+                self.notify(PresenceUpdate::Offline(jid.clone().with_resource(name)));
+            }
+        }
+    }
+
+    fn notify(&self, update: PresenceUpdate) {
+        // Nobody listening isn’t an error.
+        let _ = self.updates.send(update);
+    }
+
+    /// The availability of `jid`, which is offline if we never got its presence.
+    pub(crate) fn aggregate(&self, jid: &BareJid) -> AggregatedPresence {
+        let resources = match self.contacts.get(jid) {
+            Some(resources) => resources,
+            None => return AggregatedPresence::default(),
+        };
+        AggregatedPresence {
+            best_show: resources
+                .values()
+                .max_by_key(|resource| resource.availability())
+                .and_then(|resource| resource.show.clone()),
+            statuses_by_resource: resources
+                .iter()
+                .map(|(name, resource)| (name.clone(), resource.status.clone()))
+                .collect(),
+        }
+    }
+
+    /// Forget every resource, presences are sent again after we reconnect.
+    pub(crate) fn clear(&mut self) {
+        for (jid, resources) in std::mem::take(&mut self.contacts) {
+            for name in resources.into_keys() {
+                self.notify(PresenceUpdate::Offline(jid.clone().with_resource(name)));
+            }
+        }
+    }
+
+    /// The online resource of `jid` with the highest priority, then the most available one,
+    /// then the least idle one, among those accepted by `filter`.
+    ///
+    /// Resources with a negative priority are considered too: they never get messages sent to
+    /// the bare JID, so addressing them directly is the only way to reach them.
+    pub(crate) fn best<F: Fn(&Resource) -> bool>(
+        &self,
+        jid: &BareJid,
+        filter: F,
+    ) -> Option<FullJid> {
+        self.contacts
+            .get(jid)?
+            .iter()
+            .filter(|(_, resource)| filter(resource))
+            .max_by_key(|(_, resource)| {
+                (
+                    resource.priority,
+                    resource.availability(),
+                    resource.idle_since.is_none(),
+                    resource.idle_since,
+                )
+            })
+            .map(|(name, _)| jid.clone().with_resource(name.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn resource(priority: i8, show: Option<Show>) -> Resource {
+        Resource {
+            priority,
+            show,
+            status: None,
+            caps: None,
+            idle_since: None,
+        }
+    }
+
+    #[test]
+    fn test_ranking() {
+        let contact = BareJid::from_str("contact@bar").unwrap();
+        let full = |name: &str| contact.clone().with_resource(name);
+        let mut resources = Resources::default();
+        assert_eq!(resources.best(&contact, |_| true), None);
+
+        resources.available(full("phone"), resource(0, Some(Show::Away)));
+        resources.available(full("desktop"), resource(0, None));
+        assert_eq!(resources.best(&contact, |_| true), Some(full("desktop")));
+
+        // Priority wins over availability.
+        resources.available(full("phone"), resource(5, Some(Show::Away)));
+        assert_eq!(resources.best(&contact, |_| true), Some(full("phone")));
+
+        resources.unavailable(&full("phone"));
+        assert_eq!(resources.best(&contact, |_| true), Some(full("desktop")));
+        resources.unavailable(&full("desktop"));
+        assert_eq!(resources.best(&contact, |_| true), None);
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let contact = BareJid::from_str("contact@bar").unwrap();
+        let full = |name: &str| contact.clone().with_resource(name);
+        let mut resources = Resources::default();
+        assert!(!resources.aggregate(&contact).is_online());
+
+        resources.available(full("phone"), resource(10, Some(Show::Xa)));
+        let mut desktop = resource(0, Some(Show::Away));
+        desktop.status = Some(String::from("Lunch"));
+        resources.available(full("desktop"), desktop);
+        let aggregate = resources.aggregate(&contact);
+        assert!(aggregate.is_online());
+        assert_eq!(aggregate.best_show, Some(Show::Away));
+        assert_eq!(
+            aggregate
+                .statuses_by_resource
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [
+                (String::from("desktop"), Some(String::from("Lunch"))),
+                (String::from("phone"), None),
+            ]
+        );
+
+        resources.available(full("laptop"), resource(0, None));
+        assert_eq!(resources.aggregate(&contact).best_show, None);
+        resources.all_unavailable(&contact);
+        assert_eq!(resources.aggregate(&contact), AggregatedPresence::default());
+    }
+
+    #[test]
+    fn test_idle() {
+        let contact = BareJid::from_str("contact@bar").unwrap();
+        let full = |name: &str| contact.clone().with_resource(name);
+        let idle = |since: &str| {
+            let mut resource = resource(0, None);
+            resource.idle_since = Some(DateTime::from_str(since).unwrap());
+            resource
+        };
+        let mut resources = Resources::default();
+        resources.available(full("desktop"), idle("2021-06-01T10:00:00Z"));
+        resources.available(full("laptop"), idle("2021-06-01T12:00:00Z"));
+        // The one whose user left the most recently.
+        assert_eq!(resources.best(&contact, |_| true), Some(full("laptop")));
+        resources.available(full("phone"), resource(0, None));
+        assert_eq!(resources.best(&contact, |_| true), Some(full("phone")));
+    }
+
+    #[test]
+    fn test_negative_priority() {
+        let contact = BareJid::from_str("contact@bar").unwrap();
+        let full = |name: &str| contact.clone().with_resource(name);
+        let mut resources = Resources::default();
+        resources.available(full("a"), resource(-1, None));
+        resources.available(full("b"), resource(-5, Some(Show::Chat)));
+        assert_eq!(resources.best(&contact, |_| true), Some(full("a")));
+        assert_eq!(
+            resources.best(&contact, |resource| resource.show.is_some()),
+            Some(full("b"))
+        );
+        assert_eq!(
+            resources.best(&contact, |resource| resource.priority >= 0),
+            None
+        );
+    }
+
+    #[test]
+    fn test_updates() {
+        let contact = BareJid::from_str("contact@bar").unwrap();
+        let full = |name: &str| contact.clone().with_resource(name);
+        let mut resources = Resources::default();
+        let mut updates = resources.subscribe();
+        resources.available(full("phone"), resource(0, None));
+        resources.available(full("phone"), resource(0, Some(Show::Away)));
+        resources.available(full("desktop"), resource(0, None));
+        resources.unavailable(&full("phone"));
+        // Already offline.
+        resources.unavailable(&full("phone"));
+        resources.clear();
+
+        let mut received = vec![];
+        while let Ok(update) = updates.try_recv() {
+            received.push(match update {
+                PresenceUpdate::Online(jid, _) => format!("online {}", jid),
+                PresenceUpdate::Offline(jid) => format!("offline {}", jid),
+                PresenceUpdate::Updated { jid, old, new } => {
+                    format!("updated {} {:?} {:?}", jid, old.show, new.show)
+                }
+            });
+        }
+        assert_eq!(
+            received,
+            [
+                "online contact@bar/phone",
+                "updated contact@bar/phone None Some(Away)",
+                "online contact@bar/desktop",
+                "offline contact@bar/phone",
+                "offline contact@bar/desktop",
+            ]
+        );
+    }
+}
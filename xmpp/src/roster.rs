@@ -0,0 +1,187 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Our roster, as last received from the server and updated by its pushes.
+
+use tokio::sync::broadcast;
+use xmpp_parsers::roster::{Item as RosterItem, Subscription};
+
+/// A change of the roster, see [`crate::Agent::roster_updates`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum RosterUpdate {
+    /// A contact got added to the roster.
+    Added(RosterItem),
+    /// A contact got removed from the roster.
+    Removed(RosterItem),
+    /// The name, groups or subscription of a contact changed.
+    Updated { old: RosterItem, new: RosterItem },
+}
+
+#[derive(Debug)]
+pub(crate) struct RosterCache {
+    items: Vec<RosterItem>,
+    updates: broadcast::Sender<RosterUpdate>,
+}
+
+impl Default for RosterCache {
+    fn default() -> Self {
+        RosterCache {
+            items: Vec::new(),
+            updates: broadcast::channel(crate::UPDATES_CAPACITY).0,
+        }
+    }
+}
+
+impl RosterCache {
+    pub(crate) fn items(&self) -> &[RosterItem] {
+        &self.items
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<RosterUpdate> {
+        self.updates.subscribe()
+    }
+
+    #[cfg(feature = "test-internals")]
+    pub(crate) fn updates(&self) -> &broadcast::Sender<RosterUpdate> {
+        &self.updates
+    }
+
+    /// Replace the whole roster, when receiving it after connecting.
+    pub(crate) fn replace(&mut self, items: Vec<RosterItem>) {
+        let old = std::mem::take(&mut self.items);
+        for item in old.iter() {
+            if !items.iter().any(|new| new.jid == item.jid) {
+                self.notify(RosterUpdate::Removed(item.clone()));
+            }
+        }
+        for item in items {
+            let update = match old.iter().find(|old| old.jid == item.jid) {
+                None => Some(RosterUpdate::Added(item.clone())),
+                Some(old) if *old != item => Some(RosterUpdate::Updated {
+                    old: old.clone(),
+                    new: item.clone(),
+                }),
+                Some(_) => None,
+            };
+            self.notify_all(update);
+            self.items.push(item);
+        }
+    }
+
+    /// Apply an item of a roster push (RFC 6121 §2.1.6).
+    pub(crate) fn push(&mut self, item: RosterItem) {
+        let position = self.items.iter().position(|old| old.jid == item.jid);
+        let update = match position {
+            Some(position) if item.subscription == Subscription::Remove => {
+                Some(RosterUpdate::Removed(self.items.remove(position)))
+            }
+            None if item.subscription == Subscription::Remove => None,
+            Some(position) if self.items[position] == item => None,
+            Some(position) => Some(RosterUpdate::Updated {
+                old: std::mem::replace(&mut self.items[position], item.clone()),
+                new: item,
+            }),
+            None => {
+                self.items.push(item.clone());
+                Some(RosterUpdate::Added(item))
+            }
+        };
+        self.notify_all(update);
+    }
+
+    fn notify_all(&self, update: Option<RosterUpdate>) {
+        if let Some(update) = update {
+            self.notify(update);
+        }
+    }
+
+    fn notify(&self, update: RosterUpdate) {
+        // Nobody listening isn’t an error.
+        let _ = self.updates.send(update);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use tokio::sync::broadcast::error::TryRecvError;
+    use xmpp_parsers::{roster::Ask, BareJid};
+
+    fn item(jid: &str, subscription: Subscription) -> RosterItem {
+        RosterItem {
+            jid: BareJid::from_str(jid).unwrap(),
+            name: None,
+            subscription,
+            ask: Ask::None,
+            groups: vec![],
+        }
+    }
+
+    #[test]
+    fn test_changes() {
+        let mut roster = RosterCache::default();
+        let mut updates = roster.subscribe();
+        roster.replace(vec![
+            item("romeo@example.net", Subscription::Both),
+            item("mercutio@example.com", Subscription::From),
+        ]);
+        roster.push(item("mercutio@example.com", Subscription::Both));
+        // Nothing changes, nothing gets notified.
+        roster.push(item("mercutio@example.com", Subscription::Both));
+        roster.push(item("romeo@example.net", Subscription::Remove));
+        roster.replace(vec![item("benvolio@example.net", Subscription::None)]);
+
+        let mut received = vec![];
+        while let Ok(update) = updates.try_recv() {
+            received.push(update);
+        }
+        assert_eq!(
+            received,
+            [
+                RosterUpdate::Added(item("romeo@example.net", Subscription::Both)),
+                RosterUpdate::Added(item("mercutio@example.com", Subscription::From)),
+                RosterUpdate::Updated {
+                    old: item("mercutio@example.com", Subscription::From),
+                    new: item("mercutio@example.com", Subscription::Both),
+                },
+                RosterUpdate::Removed(item("romeo@example.net", Subscription::Both)),
+                RosterUpdate::Removed(item("mercutio@example.com", Subscription::Both)),
+                RosterUpdate::Added(item("benvolio@example.net", Subscription::None)),
+            ]
+        );
+        assert_eq!(
+            roster.items(),
+            [item("benvolio@example.net", Subscription::None)]
+        );
+    }
+
+    #[test]
+    fn test_slow_subscriber() {
+        fn assert_send<T: Send>(_: &T) {}
+
+        let mut roster = RosterCache::default();
+        let mut fast = roster.subscribe();
+        let mut slow = roster.subscribe();
+        assert_send(&slow);
+        let contacts = crate::UPDATES_CAPACITY + 10;
+        for i in 0..contacts {
+            let contact = item(&format!("contact{}@bar", i), Subscription::Both);
+            roster.push(contact.clone());
+            assert_eq!(fast.try_recv(), Ok(RosterUpdate::Added(contact)));
+        }
+        assert_eq!(fast.try_recv(), Err(TryRecvError::Empty));
+
+        // The oldest changes got dropped rather than blocking the roster, and the slow
+        // subscriber gets told how many it missed before receiving the most recent ones.
+        assert_eq!(slow.try_recv(), Err(TryRecvError::Lagged(10)));
+        for i in 10..contacts {
+            let contact = item(&format!("contact{}@bar", i), Subscription::Both);
+            assert_eq!(slow.try_recv(), Ok(RosterUpdate::Added(contact)));
+        }
+        assert_eq!(slow.try_recv(), Err(TryRecvError::Empty));
+    }
+}
@@ -0,0 +1,993 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! What the Agent keeps across restarts, and where.
+//!
+//! Everything goes through the [`Storage`] trait, a key-value store split in namespaces, one
+//! for each kind of state: the roster, the bookmarks, the index of the avatars saved, the
+//! pending subscription requests, the contacts already greeted, the ids of the stanzas already
+//! handled, and what is needed to resume the session.  Applications wanting to persist it
+//! elsewhere, for instance in a database, implement it and pass it to
+//! [`crate::ClientBuilder::set_storage`].
+//!
+//! Every namespace carries its schema version under [`VERSION_KEY`], upgraded by [`migrate`].
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use xmpp_parsers::{
+    bookmarks2::Conference,
+    roster::{Item as RosterItem, Roster, Subscription},
+    BareJid, Element, Jid,
+};
+
+/// The key of the schema version of every namespace, reserved in all of them.
+pub const VERSION_KEY: &str = "version";
+
+/// The version of the schema of every namespace the Agent uses.
+const SCHEMA_VERSION: u32 = 1;
+
+const ROSTER: &str = "roster";
+const BOOKMARKS: &str = "bookmarks";
+const AVATARS: &str = "avatars";
+const RESUME: &str = "resume";
+const SUBSCRIPTIONS: &str = "subscriptions";
+const GREETED: &str = "greeted";
+const STANZA_IDS: &str = "stanza-ids";
+
+/// How many ids of handled stanzas to remember.
+const STANZA_IDS_CAPACITY: usize = 1024;
+
+/// The namespace of the files of [`FileStorage`].
+const FILE_NS: &str = "urn:xmpp-rs:storage";
+
+/// A change to a namespace, setting `key` to a value or deleting it when `None`.
+pub type Change<'a> = (&'a str, Option<&'a str>);
+
+/// A key-value store split in namespaces, where the Agent saves what it learns as soon as it
+/// changes.
+///
+/// Failures only get logged, the Agent keeping on with what it has in memory.
+pub trait Storage {
+    /// The value of `key` in `namespace`, if any.
+    fn get(&self, namespace: &str, key: &str) -> io::Result<Option<String>>;
+
+    /// Every key of `namespace` with its value, sorted by key.
+    fn scan(&self, namespace: &str) -> io::Result<Vec<(String, String)>>;
+
+    /// Apply all of `changes` to `namespace`, or none of them if it fails, even if the process
+    /// gets killed in the middle.
+    fn write(&mut self, namespace: &str, changes: &[Change]) -> io::Result<()>;
+
+    /// Set `key` to `value` in `namespace`.
+    fn put(&mut self, namespace: &str, key: &str, value: &str) -> io::Result<()> {
+        self.write(namespace, &[(key, Some(value))])
+    }
+
+    /// Remove `key` from `namespace`.
+    fn delete(&mut self, namespace: &str, key: &str) -> io::Result<()> {
+        self.write(namespace, &[(key, None)])
+    }
+}
+
+/// Bring `namespace` to schema `version`, calling `upgrade` with each older version found and
+/// the entries of the namespace, for the changes to the next version.
+///
+/// Each step is written along with its version, so an interrupted migration resumes where it
+/// stopped.  A namespace without any entry is new and gets `version` right away, while one
+/// written by a newer schema is an error, as this one wouldn’t understand it.
+pub fn migrate<F>(
+    storage: &mut dyn Storage,
+    namespace: &str,
+    version: u32,
+    mut upgrade: F,
+) -> io::Result<()>
+where
+    F: FnMut(u32, Vec<(String, String)>) -> io::Result<Vec<(String, Option<String>)>>,
+{
+    let entries = storage.scan(namespace)?;
+    let mut current = match storage.get(namespace, VERSION_KEY)? {
+        Some(stored) => stored
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid schema version."))?,
+        None if entries.is_empty() => {
+            return storage.put(namespace, VERSION_KEY, &version.to_string());
+        }
+        None => 0,
+    };
+    if current > version {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "The “{}” namespace has schema version {}, newer than {}.",
+                namespace, current, version
+            ),
+        ));
+    }
+    while current < version {
+        let entries = storage
+            .scan(namespace)?
+            .into_iter()
+            .filter(|(key, _)| key != VERSION_KEY)
+            .collect();
+        let changes = upgrade(current, entries)?;
+        current += 1;
+        let next = current.to_string();
+        let mut batch: Vec<Change> = changes
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_deref()))
+            .collect();
+        batch.push((VERSION_KEY, Some(next.as_str())));
+        storage.write(namespace, &batch)?;
+    }
+    Ok(())
+}
+
+/// The default [`Storage`], keeping everything in memory.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    namespaces: HashMap<String, BTreeMap<String, String>>,
+}
+
+impl MemoryStorage {
+    /// An empty storage.
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, namespace: &str, key: &str) -> io::Result<Option<String>> {
+        Ok(self
+            .namespaces
+            .get(namespace)
+            .and_then(|entries| entries.get(key))
+            .cloned())
+    }
+
+    fn scan(&self, namespace: &str) -> io::Result<Vec<(String, String)>> {
+        Ok(self
+            .namespaces
+            .get(namespace)
+            .map(|entries| entries.clone().into_iter().collect())
+            .unwrap_or_default())
+    }
+
+    fn write(&mut self, namespace: &str, changes: &[Change]) -> io::Result<()> {
+        apply(
+            self.namespaces.entry(namespace.to_owned()).or_default(),
+            changes,
+        );
+        Ok(())
+    }
+}
+
+fn apply(entries: &mut BTreeMap<String, String>, changes: &[Change]) {
+    for (key, value) in changes {
+        match value {
+            Some(value) => entries.insert(String::from(*key), String::from(*value)),
+            None => entries.remove(*key),
+        };
+    }
+}
+
+/// A [`Storage`] in a directory, with an XML file for each namespace.
+///
+/// Every write replaces the whole file, written to a temporary file first and then renamed, so
+/// that a namespace is either as before or as after even if the process dies in between.
+#[derive(Debug)]
+pub struct FileStorage {
+    directory: PathBuf,
+    namespaces: HashMap<String, BTreeMap<String, String>>,
+    /// How many more bytes may get written before simulating a crash.
+    #[cfg(test)]
+    crash_after: Option<usize>,
+}
+
+impl FileStorage {
+    /// Open the storage in `directory`, creating it if needed.
+    pub fn open<P: Into<PathBuf>>(directory: P) -> io::Result<Self> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        let mut namespaces = HashMap::new();
+        for entry in fs::read_dir(&directory)? {
+            let path = entry?.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("xml") => (),
+                // Left behind by a write which didn’t complete.
+                Some("part") => {
+                    fs::remove_file(&path)?;
+                    continue;
+                }
+                _ => continue,
+            }
+            let namespace = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(namespace) if valid_namespace(namespace) => namespace.to_owned(),
+                _ => continue,
+            };
+            namespaces.insert(namespace, read_file(&path)?);
+        }
+        Ok(FileStorage {
+            directory,
+            namespaces,
+            #[cfg(test)]
+            crash_after: None,
+        })
+    }
+
+    fn write_file(&mut self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let partial = path.with_extension("part");
+        let mut file = File::create(&partial)?;
+        #[cfg(test)]
+        {
+            if let Some(budget) = self.crash_after {
+                if budget <= contents.len() {
+                    file.write_all(&contents[..budget])?;
+                    return Err(io::Error::other("Simulated crash."));
+                }
+            }
+        }
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&partial, path)
+    }
+}
+
+impl Storage for FileStorage {
+    fn get(&self, namespace: &str, key: &str) -> io::Result<Option<String>> {
+        Ok(self
+            .namespaces
+            .get(namespace)
+            .and_then(|entries| entries.get(key))
+            .cloned())
+    }
+
+    fn scan(&self, namespace: &str) -> io::Result<Vec<(String, String)>> {
+        Ok(self
+            .namespaces
+            .get(namespace)
+            .map(|entries| entries.clone().into_iter().collect())
+            .unwrap_or_default())
+    }
+
+    fn write(&mut self, namespace: &str, changes: &[Change]) -> io::Result<()> {
+        if !valid_namespace(namespace) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid namespace “{}”.", namespace),
+            ));
+        }
+        let mut entries = self.namespaces.get(namespace).cloned().unwrap_or_default();
+        apply(&mut entries, changes);
+        let file = Element::builder("storage", FILE_NS)
+            .append_all(entries.iter().map(|(key, value)| {
+                Element::builder("entry", FILE_NS)
+                    .attr("key", key.as_str())
+                    .append(value.as_str())
+            }))
+            .build();
+        let path = self.directory.join(format!("{}.xml", namespace));
+        self.write_file(&path, String::from(&file).as_bytes())?;
+        // Only once it is on disk, so that memory never is ahead of it.
+        self.namespaces.insert(namespace.to_owned(), entries);
+        Ok(())
+    }
+}
+
+/// Namespaces become file names, so they are restricted to what is safe in those.
+fn valid_namespace(namespace: &str) -> bool {
+    !namespace.is_empty()
+        && namespace
+            .bytes()
+            .all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'-')
+}
+
+fn read_file(path: &Path) -> io::Result<BTreeMap<String, String>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let root = Element::from_str(&fs::read_to_string(path)?)
+        .map_err(|err| invalid(format!("{}: {}", path.display(), err)))?;
+    if !root.is("storage", FILE_NS) {
+        return Err(invalid(format!("{}: not a storage file", path.display())));
+    }
+    Ok(root
+        .children()
+        .filter(|child| child.is("entry", FILE_NS))
+        .filter_map(|child| Some((child.attr("key")?.to_owned(), child.text())))
+        .collect())
+}
+
+/// Bring every namespace of the Agent to the current schema.
+pub(crate) fn migrate_all(storage: &mut dyn Storage) -> io::Result<()> {
+    for namespace in [
+        ROSTER,
+        BOOKMARKS,
+        AVATARS,
+        RESUME,
+        SUBSCRIPTIONS,
+        GREETED,
+        STANZA_IDS,
+    ] {
+        // Version 0 is whatever was there before versioning, which never was anything.
+        migrate(storage, namespace, SCHEMA_VERSION, |_, _| Ok(Vec::new()))?;
+    }
+    Ok(())
+}
+
+fn invalid_entry(namespace: &str, key: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid entry “{}” in the “{}” namespace.", key, namespace),
+    )
+}
+
+fn roster_key(jid: &BareJid) -> String {
+    format!("item:{}", jid)
+}
+
+/// The roster last saved, if any.
+///
+/// Its version is kept under `ver`, empty when the server didn’t give any, and its items each
+/// under `item:` followed by their JID.
+pub(crate) fn load_roster(storage: &dyn Storage) -> io::Result<Option<Roster>> {
+    let ver = match storage.get(ROSTER, "ver")? {
+        Some(ver) => ver,
+        None => return Ok(None),
+    };
+    let mut items = vec![];
+    for (key, value) in storage.scan(ROSTER)? {
+        if key.starts_with("item:") {
+            let item = Element::from_str(&value)
+                .ok()
+                .and_then(|elem| RosterItem::try_from(elem).ok())
+                .ok_or_else(|| invalid_entry(ROSTER, &key))?;
+            items.push(item);
+        }
+    }
+    Ok(Some(Roster {
+        ver: Some(ver).filter(|ver| !ver.is_empty()),
+        items,
+    }))
+}
+
+/// Replace the stored roster.
+pub(crate) fn save_roster(storage: &mut dyn Storage, roster: &Roster) -> io::Result<()> {
+    let items: Vec<(String, String)> = roster
+        .items
+        .iter()
+        .map(|item| {
+            (
+                roster_key(&item.jid),
+                String::from(&Element::from(item.clone())),
+            )
+        })
+        .collect();
+    let stale: Vec<String> = storage
+        .scan(ROSTER)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .filter(|key| key.starts_with("item:") && !items.iter().any(|(item, _)| item == key))
+        .collect();
+    let ver = roster.ver.as_deref().unwrap_or("");
+    let mut changes: Vec<Change> = vec![("ver", Some(ver))];
+    changes.extend(stale.iter().map(|key| (key.as_str(), None)));
+    changes.extend(
+        items
+            .iter()
+            .map(|(key, value)| (key.as_str(), Some(value.as_str()))),
+    );
+    storage.write(ROSTER, &changes)
+}
+
+/// Apply a roster push (RFC 6121 §2.1.6) to the stored roster, unless there isn’t any yet.
+pub(crate) fn save_roster_push(storage: &mut dyn Storage, push: &Roster) -> io::Result<()> {
+    if storage.get(ROSTER, "ver")?.is_none() {
+        return Ok(());
+    }
+    let items: Vec<(String, Option<String>)> = push
+        .items
+        .iter()
+        .map(|item| {
+            let value = if item.subscription == Subscription::Remove {
+                None
+            } else {
+                Some(String::from(&Element::from(item.clone())))
+            };
+            (roster_key(&item.jid), value)
+        })
+        .collect();
+    let mut changes: Vec<Change> = items
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_deref()))
+        .collect();
+    if let Some(ver) = push.ver.as_deref() {
+        changes.push(("ver", Some(ver)));
+    }
+    storage.write(ROSTER, &changes)
+}
+
+/// The bookmarks last saved (XEP-0402).
+pub(crate) fn load_bookmarks(storage: &dyn Storage) -> io::Result<HashMap<BareJid, Conference>> {
+    let mut bookmarks = HashMap::new();
+    for (key, value) in storage.scan(BOOKMARKS)? {
+        if key == VERSION_KEY {
+            continue;
+        }
+        let jid = BareJid::from_str(&key).map_err(|_| invalid_entry(BOOKMARKS, &key))?;
+        let conference = Element::from_str(&value)
+            .ok()
+            .and_then(|elem| Conference::try_from(elem).ok())
+            .ok_or_else(|| invalid_entry(BOOKMARKS, &key))?;
+        bookmarks.insert(jid, conference);
+    }
+    Ok(bookmarks)
+}
+
+/// Replace the stored bookmarks, keyed by the JID of their room.
+pub(crate) fn save_bookmarks(
+    storage: &mut dyn Storage,
+    bookmarks: &HashMap<BareJid, Conference>,
+) -> io::Result<()> {
+    let entries: Vec<(String, String)> = bookmarks
+        .iter()
+        .map(|(jid, conference)| {
+            (
+                jid.to_string(),
+                String::from(&Element::from(conference.clone())),
+            )
+        })
+        .collect();
+    let stale: Vec<String> = storage
+        .scan(BOOKMARKS)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .filter(|key| key != VERSION_KEY && !entries.iter().any(|(jid, _)| jid == key))
+        .collect();
+    let mut changes: Vec<Change> = stale.iter().map(|key| (key.as_str(), None)).collect();
+    changes.extend(
+        entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), Some(value.as_str()))),
+    );
+    storage.write(BOOKMARKS, &changes)
+}
+
+#[cfg(feature = "avatars")]
+/// Where avatars get saved when [`crate::AvatarConfig::cache_dir`] doesn’t say: a directory of
+/// the temporary one for the current user alone.
+pub(crate) fn default_avatar_directory() -> PathBuf {
+    #[cfg(unix)]
+    let name = format!("xmpp-rs-{}", unsafe { libc::geteuid() });
+    #[cfg(not(unix))]
+    let name = String::from("xmpp-rs");
+    env::temp_dir().join(name)
+}
+
+#[cfg(feature = "avatars")]
+/// Create `directory` for the current user alone if it is missing, and refuse it if it is a
+/// symlink, or if someone else owns it or everyone can write in it: in a shared directory such
+/// as the temporary one, they could then make us overwrite their files.
+fn private_directory(directory: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(directory)?;
+        let metadata = fs::symlink_metadata(directory)?;
+        if !metadata.is_dir()
+            || metadata.uid() != unsafe { libc::geteuid() }
+            || metadata.mode() & 0o002 != 0
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} isn’t private to us", directory.display()),
+            ));
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    fs::create_dir_all(directory)
+}
+
+#[cfg(feature = "avatars")]
+fn avatar_key(jid: &Jid, id: &str) -> String {
+    format!("{} {}", jid, id)
+}
+
+#[cfg(feature = "avatars")]
+/// Save the avatar `id` of `jid`, whose hash already got checked, in a subdirectory of
+/// `directory` for each contact, named after its escaped bare JID, returning where it can be
+/// found.
+///
+/// The file is written first, the storage only indexing it, and to a temporary file so that an
+/// avatar is either complete or missing.
+pub(crate) fn save_avatar(
+    storage: &mut dyn Storage,
+    directory: &Path,
+    jid: &Jid,
+    id: &str,
+    data: &[u8],
+) -> io::Result<String> {
+    private_directory(directory)?;
+    let contact_directory = directory.join(contact_directory_name(&BareJid::from(jid.clone())));
+    fs::create_dir_all(&contact_directory)?;
+    let path = contact_directory.join(id);
+    let partial = path.with_extension("part");
+    let mut file = File::create(&partial)?;
+    file.write_all(data)?;
+    fs::rename(&partial, &path)?;
+    let path = path.display().to_string();
+    storage.put(AVATARS, &avatar_key(jid, id), &path)?;
+    Ok(path)
+}
+
+#[cfg(feature = "avatars")]
+/// `jid` as a single path component: anything but ASCII letters, digits and `@._-` gets
+/// percent-encoded, as does a leading dot, so that it can’t escape its parent directory.
+fn contact_directory_name(jid: &BareJid) -> String {
+    let mut name = String::new();
+    for (i, byte) in jid.to_string().bytes().enumerate() {
+        match byte {
+            b'.' if i == 0 => name.push_str("%2E"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'@' | b'.' | b'-' | b'_' => {
+                name.push(char::from(byte))
+            }
+            _ => name.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    name
+}
+
+#[cfg(feature = "avatars")]
+/// Where the avatar `id` of `jid` got saved, if it was and is still there.
+pub(crate) fn avatar(storage: &dyn Storage, jid: &Jid, id: &str) -> Option<String> {
+    storage
+        .get(AVATARS, &avatar_key(jid, id))
+        .ok()
+        .flatten()
+        .filter(|path| Path::new(path).is_file())
+}
+
+/// Everyone whose subscription request was pending when last saved.
+pub(crate) fn load_pending_subscriptions(storage: &dyn Storage) -> io::Result<Vec<BareJid>> {
+    storage
+        .scan(SUBSCRIPTIONS)?
+        .into_iter()
+        .filter(|(key, _)| key != VERSION_KEY)
+        .map(|(key, _)| BareJid::from_str(&key).map_err(|_| invalid_entry(SUBSCRIPTIONS, &key)))
+        .collect()
+}
+
+/// Add and remove subscription requests from those pending, in one go.
+pub(crate) fn save_pending_subscriptions(
+    storage: &mut dyn Storage,
+    changes: &[(BareJid, bool)],
+) -> io::Result<()> {
+    let keys: Vec<String> = changes.iter().map(|(jid, _)| jid.to_string()).collect();
+    let changes: Vec<Change> = keys
+        .iter()
+        .zip(changes)
+        .map(|(key, (_, pending))| (key.as_str(), if *pending { Some("") } else { None }))
+        .collect();
+    storage.write(SUBSCRIPTIONS, &changes)
+}
+
+/// Remember that `jid` became a conversation partner, returning whether it is the first time.
+pub(crate) fn greet(storage: &mut dyn Storage, jid: &BareJid) -> io::Result<bool> {
+    let key = jid.to_string();
+    if storage.get(GREETED, &key)?.is_some() {
+        return Ok(false);
+    }
+    storage.put(GREETED, &key, "")?;
+    Ok(true)
+}
+
+/// Whether `jid` already became a conversation partner.
+pub(crate) fn greeted(storage: &dyn Storage, jid: &BareJid) -> io::Result<bool> {
+    Ok(storage.get(GREETED, &jid.to_string())?.is_some())
+}
+
+/// The ids of the last stanzas handled, to recognise the ones delivered again, for instance
+/// when the server resends what it didn’t get acknowledged.
+///
+/// Each is saved under a counter, zero-padded so that they scan from the oldest.
+#[derive(Debug, Default)]
+pub(crate) struct SeenStanzaIds {
+    order: VecDeque<(u64, String)>,
+    ids: HashSet<String>,
+    next: u64,
+}
+
+impl SeenStanzaIds {
+    pub(crate) fn load(storage: &dyn Storage) -> io::Result<SeenStanzaIds> {
+        let mut seen = SeenStanzaIds::default();
+        for (key, id) in storage.scan(STANZA_IDS)? {
+            if key == VERSION_KEY {
+                continue;
+            }
+            let counter = key
+                .parse::<u64>()
+                .map_err(|_| invalid_entry(STANZA_IDS, &key))?;
+            seen.ids.insert(id.clone());
+            seen.order.push_back((counter, id));
+            seen.next = counter + 1;
+        }
+        Ok(seen)
+    }
+
+    pub(crate) fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Remember `id`, forgetting the oldest one when full, returns whether it was new.
+    pub(crate) fn insert(&mut self, storage: &mut dyn Storage, id: &str) -> io::Result<bool> {
+        if self.contains(id) {
+            return Ok(false);
+        }
+        let key = format!("{:020}", self.next);
+        let mut changes: Vec<Change> = vec![(key.as_str(), Some(id))];
+        let evicted = if self.order.len() >= STANZA_IDS_CAPACITY {
+            self.order
+                .front()
+                .map(|(counter, _)| format!("{:020}", counter))
+        } else {
+            None
+        };
+        if let Some(evicted) = evicted.as_deref() {
+            changes.push((evicted, None));
+        }
+        storage.write(STANZA_IDS, &changes)?;
+        if evicted.is_some() {
+            if let Some((_, id)) = self.order.pop_front() {
+                self.ids.remove(&id);
+            }
+        }
+        self.order.push_back((self.next, id.to_owned()));
+        self.ids.insert(id.to_owned());
+        self.next += 1;
+        Ok(true)
+    }
+}
+
+/// What is needed to resume a stream management session (XEP-0198).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumeState {
+    /// The id the server gave to the session.
+    pub id: String,
+    /// Where the server asked us to reconnect to resume it, if anywhere in particular.
+    pub location: Option<String>,
+    /// How many stanzas we handled.
+    pub handled: u32,
+}
+
+impl ResumeState {
+    /// The state last saved to resume the session, if any.
+    ///
+    /// The Agent doesn’t resume sessions yet, this is for applications doing it themselves.
+    pub fn load(storage: &dyn Storage) -> io::Result<Option<ResumeState>> {
+        let id = match storage.get(RESUME, "id")? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let handled = storage
+            .get(RESUME, "handled")?
+            .and_then(|handled| handled.parse().ok())
+            .ok_or_else(|| invalid_entry(RESUME, "handled"))?;
+        Ok(Some(ResumeState {
+            id,
+            location: storage.get(RESUME, "location")?,
+            handled,
+        }))
+    }
+
+    /// Replace the state saved to resume the session.
+    pub fn save(&self, storage: &mut dyn Storage) -> io::Result<()> {
+        let handled = self.handled.to_string();
+        storage.write(
+            RESUME,
+            &[
+                ("id", Some(self.id.as_str())),
+                ("location", self.location.as_deref()),
+                ("handled", Some(handled.as_str())),
+            ],
+        )
+    }
+
+    /// Remove the state saved, once the session can’t be resumed anymore.
+    pub fn clear(storage: &mut dyn Storage) -> io::Result<()> {
+        storage.write(
+            RESUME,
+            &[("id", None), ("location", None), ("handled", None)],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roster(xml: &str) -> Roster {
+        Roster::try_from(Element::from_str(xml).unwrap()).unwrap()
+    }
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    #[test]
+    fn test_roster_push() {
+        let mut storage = MemoryStorage::new();
+        save_roster_push(
+            &mut storage,
+            &roster("<query xmlns='jabber:iq:roster'><item jid='romeo@example.net'/></query>"),
+        )
+        .unwrap();
+        // A push before the roster itself, there is nothing to update.
+        assert_eq!(load_roster(&storage).unwrap(), None);
+
+        save_roster(
+            &mut storage,
+            &roster(
+                "<query xmlns='jabber:iq:roster' ver='1'>
+                    <item jid='romeo@example.net' subscription='both'/>
+                    <item jid='mercutio@example.com' subscription='from'/>
+                </query>",
+            ),
+        )
+        .unwrap();
+        save_roster_push(
+            &mut storage,
+            &roster(
+                "<query xmlns='jabber:iq:roster' ver='2'>
+                    <item jid='mercutio@example.com' subscription='both'/>
+                </query>",
+            ),
+        )
+        .unwrap();
+        save_roster_push(
+            &mut storage,
+            &roster(
+                "<query xmlns='jabber:iq:roster'>
+                    <item jid='romeo@example.net' subscription='remove'/>
+                    <item jid='benvolio@example.net' subscription='none'/>
+                </query>",
+            ),
+        )
+        .unwrap();
+        let stored = load_roster(&storage).unwrap().unwrap();
+        assert_eq!(stored.ver.as_deref(), Some("2"));
+        let items: Vec<_> = stored
+            .items
+            .iter()
+            .map(|item| (item.jid.to_string(), item.subscription.clone()))
+            .collect();
+        assert_eq!(
+            items,
+            [
+                (String::from("benvolio@example.net"), Subscription::None),
+                (String::from("mercutio@example.com"), Subscription::Both),
+            ]
+        );
+    }
+
+    #[cfg(feature = "avatars")]
+    #[test]
+    fn test_avatars() {
+        let directory = directory("xmpp-rs-test-storage");
+        let mut storage = MemoryStorage::new();
+        let jid = Jid::from_str("juliet@capulet.lit").unwrap();
+        assert_eq!(avatar(&storage, &jid, "abc"), None);
+
+        let path = save_avatar(&mut storage, &directory, &jid, "abc", b"avatar").unwrap();
+        assert_eq!(avatar(&storage, &jid, "abc").as_ref(), Some(&path));
+        assert_eq!(fs::read(&path).unwrap(), b"avatar");
+        // Nothing is left behind but the avatar itself.
+        assert_eq!(
+            fs::read_dir(directory.join("juliet@capulet.lit"))
+                .unwrap()
+                .count(),
+            1
+        );
+        // An avatar removed from the disk is as good as unknown.
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(avatar(&storage, &jid, "abc"), None);
+
+        // Contacts only get a directory per bare JID, which stays inside.
+        let jid = Jid::from_str("juliet@capulet.lit/../../escaped").unwrap();
+        let path = save_avatar(&mut storage, &directory, &jid, "abc", b"avatar").unwrap();
+        assert_eq!(
+            Path::new(&path),
+            directory.join("juliet@capulet.lit").join("abc")
+        );
+        let jid = Jid::from_str("..").unwrap();
+        let path = save_avatar(&mut storage, &directory, &jid, "abc", b"avatar").unwrap();
+        assert_eq!(Path::new(&path), directory.join("%2E.").join("abc"));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[cfg(all(feature = "avatars", unix))]
+    #[test]
+    fn test_avatar_directory_private() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let jid = Jid::from_str("juliet@capulet.lit").unwrap();
+        let mut storage = MemoryStorage::new();
+        let directory = directory("xmpp-rs-test-private");
+        save_avatar(&mut storage, &directory, &jid, "abc", b"avatar").unwrap();
+        let mode = fs::metadata(&directory).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        // Others could plant symlinks in there.
+        fs::set_permissions(&directory, fs::Permissions::from_mode(0o777)).unwrap();
+        let error = save_avatar(&mut storage, &directory, &jid, "def", b"avatar").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        // Or have the directory itself point elsewhere.
+        let link = directory.with_extension("link");
+        let _ = fs::remove_file(&link);
+        fs::set_permissions(&directory, fs::Permissions::from_mode(0o700)).unwrap();
+        symlink(&directory, &link).unwrap();
+        let error = save_avatar(&mut storage, &link, &jid, "def", b"avatar").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        fs::remove_file(link).unwrap();
+        fs::remove_dir_all(directory).unwrap();
+
+        assert_ne!(default_avatar_directory(), env::temp_dir().join("xmpp-rs"));
+    }
+
+    #[test]
+    fn test_migrate() {
+        let mut storage = MemoryStorage::new();
+        // Fresh namespaces start at the current version.
+        migrate(&mut storage, "fresh", 3, |_, _| unreachable!()).unwrap();
+        assert_eq!(
+            storage.get("fresh", VERSION_KEY).unwrap().as_deref(),
+            Some("3")
+        );
+
+        // Unversioned data goes through every step, each seeing the result of the previous.
+        storage.put("old", "name", "romeo").unwrap();
+        let mut steps = vec![];
+        migrate(&mut storage, "old", 2, |from, entries| {
+            steps.push((from, entries.clone()));
+            let (key, value) = &entries[0];
+            Ok(vec![
+                (key.clone(), None),
+                (format!("{}:{}", from, key), Some(value.to_uppercase())),
+            ])
+        })
+        .unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(
+            steps[1].1,
+            [(String::from("0:name"), String::from("ROMEO"))]
+        );
+        assert_eq!(
+            storage.scan("old").unwrap(),
+            [
+                (String::from("1:0:name"), String::from("ROMEO")),
+                (String::from(VERSION_KEY), String::from("2")),
+            ]
+        );
+        // Nothing left to do.
+        migrate(&mut storage, "old", 2, |_, _| unreachable!()).unwrap();
+
+        // A newer schema isn’t touched.
+        let err = migrate(&mut storage, "old", 1, |_, _| unreachable!()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            storage.get("old", VERSION_KEY).unwrap().as_deref(),
+            Some("2")
+        );
+    }
+
+    #[test]
+    fn test_file_storage() {
+        let directory = directory("xmpp-rs-test-file-storage");
+        let mut storage = FileStorage::open(&directory).unwrap();
+        storage
+            .write(
+                "notes",
+                &[("b", Some("<not> & an element")), ("a", Some("first"))],
+            )
+            .unwrap();
+        storage.delete("notes", "a").unwrap();
+        assert!(storage.put("../escape", "a", "b").is_err());
+
+        let storage = FileStorage::open(&directory).unwrap();
+        assert_eq!(
+            storage.scan("notes").unwrap(),
+            [(String::from("b"), String::from("<not> & an element"))]
+        );
+        assert_eq!(storage.get("notes", "a").unwrap(), None);
+        assert_eq!(storage.get("missing", "a").unwrap(), None);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_crash_consistency() {
+        let directory = directory("xmpp-rs-test-crash");
+        let before = [
+            (String::from("kept"), String::from("1")),
+            (String::from("removed"), String::from("2")),
+        ];
+        let after = [
+            (String::from("added"), String::from("3")),
+            (String::from("kept"), String::from("4")),
+        ];
+        let changes = [("removed", None), ("added", Some("3")), ("kept", Some("4"))];
+
+        // Whatever byte the process dies at, the namespace is either before or after.
+        let mut cut = 0;
+        loop {
+            let mut storage = FileStorage::open(&directory).unwrap();
+            storage
+                .write("ns", &[("kept", Some("1")), ("removed", Some("2"))])
+                .unwrap();
+            storage.crash_after = Some(cut);
+            let crashed = storage.write("ns", &changes).is_err();
+            if crashed {
+                // Nothing changed in memory either.
+                assert_eq!(storage.scan("ns").unwrap(), before);
+            }
+
+            let reopened = FileStorage::open(&directory).unwrap();
+            if crashed {
+                assert_eq!(reopened.scan("ns").unwrap(), before);
+            } else {
+                assert_eq!(reopened.scan("ns").unwrap(), after);
+                break;
+            }
+            // Whatever got left behind is gone.
+            assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+            fs::remove_dir_all(&directory).unwrap();
+            cut += 1;
+        }
+        assert!(cut > 50);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_stanza_ids() {
+        let mut storage = MemoryStorage::new();
+        let mut seen = SeenStanzaIds::default();
+        for i in 0..STANZA_IDS_CAPACITY + 2 {
+            assert!(seen.insert(&mut storage, &format!("id-{}", i)).unwrap());
+        }
+        assert!(!seen.insert(&mut storage, "id-2").unwrap());
+
+        let seen = SeenStanzaIds::load(&storage).unwrap();
+        assert!(!seen.contains("id-0"));
+        assert!(!seen.contains("id-1"));
+        assert!(seen.contains("id-2"));
+        assert!(seen.contains(&format!("id-{}", STANZA_IDS_CAPACITY + 1)));
+        assert_eq!(seen.order.len(), STANZA_IDS_CAPACITY);
+        assert_eq!(seen.next, STANZA_IDS_CAPACITY as u64 + 2);
+    }
+
+    #[test]
+    fn test_resume_state() {
+        let mut storage = MemoryStorage::new();
+        assert_eq!(ResumeState::load(&storage).unwrap(), None);
+        let state = ResumeState {
+            id: String::from("some-id"),
+            location: None,
+            handled: 42,
+        };
+        state.save(&mut storage).unwrap();
+        assert_eq!(ResumeState::load(&storage).unwrap(), Some(state));
+        ResumeState::clear(&mut storage).unwrap();
+        assert_eq!(ResumeState::load(&storage).unwrap(), None);
+    }
+}
@@ -0,0 +1,444 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Presence subscription states, following the state charts of RFC 6121 appendix A.
+//!
+//! The roster is authoritative for everything but the “Pending In” sub-state, which the server
+//! only conveys by (re)delivering the subscription request, so that one comes from presences.
+
+use crate::Event;
+use std::collections::HashMap;
+use std::rc::Rc;
+use xmpp_parsers::{
+    presence::Type as PresenceType,
+    roster::{Ask, Item as RosterItem, Subscription},
+    BareJid,
+};
+
+/// Presence subscription state with a contact (RFC 6121 §2.1.2.5).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SubscriptionState {
+    /// We receive the presence of the contact.
+    pub to: bool,
+    /// The contact receives our presence.
+    pub from: bool,
+    /// We asked to receive the presence of the contact, and it hasn’t answered yet.
+    pub pending_out: bool,
+    /// The contact asked to receive our presence, and we haven’t answered yet.
+    pub pending_in: bool,
+}
+
+impl SubscriptionState {
+    /// A request can only be pending in a direction without a subscription.
+    fn normalize(mut self) -> Self {
+        self.pending_out &= !self.to;
+        self.pending_in &= !self.from;
+        self
+    }
+}
+
+/// Which presence subscription requests get approved without asking the application, see
+/// [`crate::ClientBuilder::set_auto_accept`].
+#[derive(Clone)]
+pub enum AutoAcceptPolicy {
+    /// Everyone’s.
+    AcceptAll,
+    /// Only those of the contacts already in our roster, for instance because we added them
+    /// first.
+    AcceptRosterOnly,
+    /// Those for which this returns true.
+    Decide(Rc<dyn Fn(&BareJid) -> bool>),
+}
+
+impl AutoAcceptPolicy {
+    /// Approve the requests for which `decide` returns true.
+    pub fn decide<F: Fn(&BareJid) -> bool + 'static>(decide: F) -> Self {
+        AutoAcceptPolicy::Decide(Rc::new(decide))
+    }
+
+    /// The presences answering the subscription request of `jid`, whose state with us is now
+    /// `state`: approving it if the policy accepts it, then asking for its presence in turn
+    /// unless we already receive it or asked for it, for instance because both sides asked at
+    /// the same time.
+    pub(crate) fn answers(
+        &self,
+        jid: &BareJid,
+        state: SubscriptionState,
+        in_roster: bool,
+    ) -> Vec<PresenceType> {
+        let accepted = match self {
+            AutoAcceptPolicy::AcceptAll => true,
+            AutoAcceptPolicy::AcceptRosterOnly => in_roster,
+            AutoAcceptPolicy::Decide(decide) => decide(jid),
+        };
+        if !accepted {
+            return Vec::new();
+        }
+        let mut answers = vec![PresenceType::Subscribed];
+        if !state.to && !state.pending_out {
+            answers.push(PresenceType::Subscribe);
+        }
+        answers
+    }
+}
+
+/// The subscription state of every contact we know of, in the roster or not.
+#[derive(Debug, Default)]
+pub(crate) struct Subscriptions {
+    states: HashMap<BareJid, SubscriptionState>,
+}
+
+impl Subscriptions {
+    pub(crate) fn get(&self, jid: &BareJid) -> SubscriptionState {
+        self.states.get(jid).copied().unwrap_or_default()
+    }
+
+    pub(crate) fn pending_inbound(&self) -> Vec<BareJid> {
+        self.states
+            .iter()
+            .filter(|(_, state)| state.pending_in)
+            .map(|(jid, _)| jid.clone())
+            .collect()
+    }
+
+    /// Restore the requests which were pending when last saved, before the roster.
+    pub(crate) fn pending_in(&mut self, jids: Vec<BareJid>) {
+        for jid in jids {
+            self.update(jid, |state| SubscriptionState {
+                pending_in: true,
+                ..state
+            });
+        }
+    }
+
+    /// Apply a whole roster, contacts missing from it are no longer subscribed either way.
+    pub(crate) fn roster(&mut self, items: &[RosterItem]) -> Vec<Event> {
+        let mut events = vec![];
+        let missing: Vec<BareJid> = self
+            .states
+            .keys()
+            .filter(|jid| !items.iter().any(|item| item.jid == **jid))
+            .cloned()
+            .collect();
+        for jid in missing {
+            events.extend(self.update(jid, |state| SubscriptionState {
+                pending_in: state.pending_in,
+                ..Default::default()
+            }));
+        }
+        for item in items {
+            events.extend(self.roster_item(item));
+        }
+        events
+    }
+
+    /// Apply a roster item, either from the initial roster or from a roster push.
+    pub(crate) fn roster_item(&mut self, item: &RosterItem) -> Option<Event> {
+        if item.subscription == Subscription::Remove {
+            // The server cancels and denies everything when removing a contact.
+            return self.update(item.jid.clone(), |_| SubscriptionState::default());
+        }
+        let to = matches!(item.subscription, Subscription::To | Subscription::Both);
+        let from = matches!(item.subscription, Subscription::From | Subscription::Both);
+        let pending_out = item.ask == Ask::Subscribe;
+        self.update(item.jid.clone(), |state| SubscriptionState {
+            to,
+            from,
+            pending_out,
+            pending_in: state.pending_in,
+        })
+    }
+
+    /// Apply a subscription related presence received from `from`.
+    pub(crate) fn inbound(&mut self, from: BareJid, type_: &PresenceType) -> Option<Event> {
+        self.update(from, |state| match type_ {
+            PresenceType::Subscribe => SubscriptionState {
+                pending_in: true,
+                ..state
+            },
+            PresenceType::Subscribed if state.pending_out => {
+                SubscriptionState { to: true, ..state }
+            }
+            PresenceType::Unsubscribe => SubscriptionState {
+                from: false,
+                pending_in: false,
+                ..state
+            },
+            PresenceType::Unsubscribed => SubscriptionState {
+                to: false,
+                pending_out: false,
+                ..state
+            },
+            _ => state,
+        })
+    }
+
+    /// Apply a subscription related presence we sent to `to`.
+    pub(crate) fn outbound(&mut self, to: BareJid, type_: &PresenceType) -> Option<Event> {
+        self.update(to, |state| match type_ {
+            PresenceType::Subscribe => SubscriptionState {
+                pending_out: true,
+                ..state
+            },
+            // Without a pending request this is a pre-approval, which changes nothing yet.
+            PresenceType::Subscribed if state.pending_in => SubscriptionState {
+                from: true,
+                ..state
+            },
+            PresenceType::Unsubscribe => SubscriptionState {
+                to: false,
+                pending_out: false,
+                ..state
+            },
+            PresenceType::Unsubscribed => SubscriptionState {
+                from: false,
+                pending_in: false,
+                ..state
+            },
+            _ => state,
+        })
+    }
+
+    fn update<F: FnOnce(SubscriptionState) -> SubscriptionState>(
+        &mut self,
+        jid: BareJid,
+        f: F,
+    ) -> Option<Event> {
+        let old = self.get(&jid);
+        let new = f(old).normalize();
+        if new == old {
+            return None;
+        }
+        if new == SubscriptionState::default() {
+            self.states.remove(&jid);
+        } else {
+            self.states.insert(jid.clone(), new);
+        }
+        Some(Event::SubscriptionChanged { jid, old, new })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    // The states of RFC 6121 appendix A.1, with their abbreviations.
+    const NONE: SubscriptionState = state(false, false, false, false);
+    const NONE_PO: SubscriptionState = state(false, false, true, false);
+    const NONE_PI: SubscriptionState = state(false, false, false, true);
+    const NONE_PO_PI: SubscriptionState = state(false, false, true, true);
+    const TO: SubscriptionState = state(true, false, false, false);
+    const TO_PI: SubscriptionState = state(true, false, false, true);
+    const FROM: SubscriptionState = state(false, true, false, false);
+    const FROM_PO: SubscriptionState = state(false, true, true, false);
+    const BOTH: SubscriptionState = state(true, true, false, false);
+
+    const ALL: [SubscriptionState; 9] = [
+        NONE, NONE_PO, NONE_PI, NONE_PO_PI, TO, TO_PI, FROM, FROM_PO, BOTH,
+    ];
+
+    const fn state(to: bool, from: bool, pending_out: bool, pending_in: bool) -> SubscriptionState {
+        SubscriptionState {
+            to,
+            from,
+            pending_out,
+            pending_in,
+        }
+    }
+
+    fn contact() -> BareJid {
+        BareJid::from_str("contact@bar").unwrap()
+    }
+
+    fn starting_from(initial: SubscriptionState) -> Subscriptions {
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.update(contact(), |_| initial);
+        subscriptions
+    }
+
+    /// Runs `apply` from every state, and checks the resulting state and event against the
+    /// expected one for each state, in the order of `ALL`.
+    fn check<F: Fn(&mut Subscriptions) -> Option<Event>>(
+        apply: F,
+        expected: [SubscriptionState; 9],
+    ) {
+        for (initial, expected) in ALL.iter().zip(expected.iter()) {
+            let mut subscriptions = starting_from(*initial);
+            let event = apply(&mut subscriptions);
+            assert_eq!(
+                subscriptions.get(&contact()),
+                *expected,
+                "from {:?}",
+                initial
+            );
+            match event {
+                Some(Event::SubscriptionChanged { jid, old, new }) => {
+                    assert_eq!(jid, contact());
+                    assert_eq!(old, *initial);
+                    assert_eq!(new, *expected);
+                }
+                None => assert_eq!(initial, expected),
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+    }
+
+    #[test]
+    fn test_inbound() {
+        // Appendix A.2.2 and A.3.2, from the point of view of the contact’s client.
+        let inbound = |type_| move |s: &mut Subscriptions| s.inbound(contact(), &type_);
+        check(
+            inbound(PresenceType::Subscribe),
+            [
+                NONE_PI, NONE_PO_PI, NONE_PI, NONE_PO_PI, TO_PI, TO_PI, FROM, FROM_PO, BOTH,
+            ],
+        );
+        check(
+            inbound(PresenceType::Subscribed),
+            [NONE, TO, NONE_PI, TO_PI, TO, TO_PI, FROM, BOTH, BOTH],
+        );
+        check(
+            inbound(PresenceType::Unsubscribe),
+            [NONE, NONE_PO, NONE, NONE_PO, TO, TO, NONE, NONE_PO, TO],
+        );
+        check(
+            inbound(PresenceType::Unsubscribed),
+            [
+                NONE, NONE, NONE_PI, NONE_PI, NONE, NONE_PI, FROM, FROM, FROM,
+            ],
+        );
+        check(inbound(PresenceType::Unavailable), ALL);
+    }
+
+    #[test]
+    fn test_outbound() {
+        // Appendix A.2.1 and A.3.1.
+        let outbound = |type_| move |s: &mut Subscriptions| s.outbound(contact(), &type_);
+        check(
+            outbound(PresenceType::Subscribe),
+            [
+                NONE_PO, NONE_PO, NONE_PO_PI, NONE_PO_PI, TO, TO_PI, FROM_PO, FROM_PO, BOTH,
+            ],
+        );
+        check(
+            outbound(PresenceType::Subscribed),
+            [NONE, NONE_PO, FROM, FROM_PO, TO, BOTH, FROM, FROM_PO, BOTH],
+        );
+        check(
+            outbound(PresenceType::Unsubscribe),
+            [
+                NONE, NONE, NONE_PI, NONE_PI, NONE, NONE_PI, FROM, FROM, FROM,
+            ],
+        );
+        check(
+            outbound(PresenceType::Unsubscribed),
+            [NONE, NONE_PO, NONE, NONE_PO, TO, TO, NONE, NONE_PO, TO],
+        );
+    }
+
+    #[test]
+    fn test_roster_push() {
+        let item = |subscription, ask| RosterItem {
+            jid: contact(),
+            name: None,
+            subscription,
+            ask,
+            groups: vec![],
+        };
+        let push = |subscription, ask| {
+            let item = item(subscription, ask);
+            move |s: &mut Subscriptions| s.roster_item(&item)
+        };
+
+        // The roster overrides everything but the pending in sub-state.
+        check(
+            push(Subscription::None, Ask::None),
+            [
+                NONE, NONE, NONE_PI, NONE_PI, NONE, NONE_PI, NONE, NONE, NONE,
+            ],
+        );
+        check(
+            push(Subscription::None, Ask::Subscribe),
+            [
+                NONE_PO, NONE_PO, NONE_PO_PI, NONE_PO_PI, NONE_PO, NONE_PO_PI, NONE_PO, NONE_PO,
+                NONE_PO,
+            ],
+        );
+        check(
+            push(Subscription::To, Ask::None),
+            [TO, TO, TO_PI, TO_PI, TO, TO_PI, TO, TO, TO],
+        );
+        check(push(Subscription::From, Ask::Subscribe), [FROM_PO; 9]);
+        check(push(Subscription::Both, Ask::None), [BOTH; 9]);
+        check(push(Subscription::Remove, Ask::None), [NONE; 9]);
+    }
+
+    #[test]
+    fn test_roster_then_presence() {
+        // The server pushes the roster change before delivering the subscribed presence, which
+        // then doesn’t change anything anymore.
+        let mut subscriptions = starting_from(NONE_PO);
+        assert!(subscriptions
+            .roster_item(&RosterItem {
+                jid: contact(),
+                name: None,
+                subscription: Subscription::To,
+                ask: Ask::None,
+                groups: vec![],
+            })
+            .is_some());
+        assert!(subscriptions
+            .inbound(contact(), &PresenceType::Subscribed)
+            .is_none());
+        assert_eq!(subscriptions.get(&contact()), TO);
+    }
+
+    #[test]
+    fn test_auto_accept() {
+        let answers = |policy: &AutoAcceptPolicy, state, in_roster| {
+            policy.answers(&contact(), state, in_roster)
+        };
+        let mutual = [PresenceType::Subscribed, PresenceType::Subscribe];
+        let all = AutoAcceptPolicy::AcceptAll;
+        assert_eq!(answers(&all, NONE_PI, false), mutual);
+        // Both sides asked at the same time, ours is still pending.
+        assert_eq!(answers(&all, NONE_PO_PI, true), [PresenceType::Subscribed]);
+        assert_eq!(answers(&all, TO_PI, true), [PresenceType::Subscribed]);
+
+        let roster_only = AutoAcceptPolicy::AcceptRosterOnly;
+        assert!(answers(&roster_only, NONE_PI, false).is_empty());
+        assert_eq!(answers(&roster_only, NONE_PI, true), mutual);
+
+        let decide = AutoAcceptPolicy::decide(|jid| jid.domain == "bar");
+        assert_eq!(answers(&decide, NONE_PI, false), mutual);
+        let stranger = BareJid::from_str("stranger@baz").unwrap();
+        assert!(decide.answers(&stranger, NONE_PI, false).is_empty());
+    }
+
+    #[test]
+    fn test_full_roster() {
+        let other = BareJid::from_str("other@bar").unwrap();
+        let mut subscriptions = starting_from(BOTH);
+        subscriptions.update(other.clone(), |_| NONE_PI);
+        let stranger = BareJid::from_str("stranger@bar").unwrap();
+        subscriptions.update(stranger.clone(), |_| TO_PI);
+
+        // Contacts missing from a fresh roster lose their subscriptions, not their requests.
+        let events = subscriptions.roster(&[RosterItem {
+            jid: other.clone(),
+            name: None,
+            subscription: Subscription::From,
+            ask: Ask::None,
+            groups: vec![],
+        }]);
+        assert_eq!(events.len(), 3);
+        assert_eq!(subscriptions.get(&contact()), NONE);
+        assert_eq!(subscriptions.get(&other), FROM);
+        assert_eq!(subscriptions.get(&stranger), NONE_PI);
+        assert_eq!(subscriptions.pending_inbound(), [stranger]);
+    }
+}
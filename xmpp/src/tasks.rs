@@ -0,0 +1,188 @@
+// Copyright (c) 2021 Emmanuel Gil Peyrot <linkmauve@linkmauve.fr>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The background activities of the Agent, so that they can all be stopped before it disconnects.
+//!
+//! They run on the task calling [`crate::Agent::wait_for_events`], which polls them along with the
+//! connection, so whatever they hold doesn’t have to be `Send`.
+
+use crate::Event;
+use futures::future::LocalBoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// How long [`Tasks::shutdown`] waits by default for the tasks to finish once cancelled.
+pub(crate) const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Tells a task that the Agent is shutting down, and that it should finish as soon as possible.
+#[derive(Debug, Clone)]
+pub(crate) struct CancellationToken(watch::Receiver<bool>);
+
+impl CancellationToken {
+    pub(crate) fn is_cancelled(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Completes once cancelled.
+    pub(crate) async fn cancelled(&self) {
+        let mut receiver = self.0.clone();
+        while !*receiver.borrow() {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Every task spawned by the Agent, along with the token cancelling them.
+pub(crate) struct Tasks {
+    cancel: watch::Sender<bool>,
+    token: CancellationToken,
+    running: FuturesUnordered<LocalBoxFuture<'static, Vec<Event>>>,
+}
+
+impl Default for Tasks {
+    fn default() -> Self {
+        let (cancel, receiver) = watch::channel(false);
+        Tasks {
+            cancel,
+            token: CancellationToken(receiver),
+            running: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl Tasks {
+    /// Run `task` in the background, the events it returns being emitted once it completes.
+    ///
+    /// Once shut down, `task` doesn’t even get started.
+    pub(crate) fn spawn<F, Fut>(&mut self, task: F)
+    where
+        F: FnOnce(CancellationToken) -> Fut,
+        Fut: Future<Output = Vec<Event>> + 'static,
+    {
+        if self.token.is_cancelled() {
+            return;
+        }
+        self.running.push(Box::pin(task(self.token.clone())));
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    #[cfg(feature = "test-internals")]
+    pub(crate) fn len(&self) -> usize {
+        self.running.len()
+    }
+
+    /// The events of the next task to complete, `None` if none is running.
+    pub(crate) async fn next(&mut self) -> Option<Vec<Event>> {
+        self.running.next().await
+    }
+
+    /// Cancel every task, then wait up to `grace` for them to finish, returning their events.
+    ///
+    /// The tasks still running after that get dropped.
+    pub(crate) async fn shutdown(&mut self, grace: Duration) -> Vec<Event> {
+        let _ = self.cancel.send(true);
+        let mut events = Vec::new();
+        let running = &mut self.running;
+        let drain = async {
+            while let Some(new_events) = running.next().await {
+                events.extend(new_events);
+            }
+        };
+        if tokio::time::timeout(grace, drain).await.is_err() {
+            warn!(
+                "Dropping {} background tasks still running {:?} after shutting down",
+                self.running.len(),
+                grace
+            );
+            self.running = FuturesUnordered::new();
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::str::FromStr;
+    use tokio::time::{sleep, Instant};
+    use xmpp_parsers::Jid;
+
+    fn expired(id: &str) -> Vec<Event> {
+        vec![Event::MessageExpired {
+            id: String::from(id),
+            peer: Jid::from_str("coucou@bar").unwrap(),
+        }]
+    }
+
+    fn ids(events: &[Event]) -> Vec<&str> {
+        events
+            .iter()
+            .map(|event| match event {
+                Event::MessageExpired { id, .. } => id.as_str(),
+                event => panic!("Unexpected event {:?}", event),
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancelled_tasks_finish() {
+        let mut tasks = Tasks::default();
+        tasks.spawn(|cancel| async move {
+            cancel.cancelled().await;
+            expired("cooperative")
+        });
+        let start = Instant::now();
+        let events = tasks.shutdown(Duration::from_secs(5)).await;
+        assert_eq!(ids(&events), ["cooperative"]);
+        assert_eq!(start.elapsed(), Duration::from_secs(0));
+        assert!(tasks.is_empty());
+
+        // Nothing starts anymore once shut down.
+        let started = Rc::new(Cell::new(false));
+        let flag = started.clone();
+        tasks.spawn(move |_| {
+            flag.set(true);
+            async { vec![] }
+        });
+        assert!(!started.get());
+        assert!(tasks.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_grace_period() {
+        let mut tasks = Tasks::default();
+        let completed = Rc::new(Cell::new(false));
+        let flag = completed.clone();
+        tasks.spawn(|_| async move {
+            // Ignores the cancellation.
+            sleep(Duration::from_secs(60)).await;
+            flag.set(true);
+            expired("slow")
+        });
+        tasks.spawn(|_| async move {
+            sleep(Duration::from_secs(1)).await;
+            expired("quick")
+        });
+        let start = Instant::now();
+        let events = tasks.shutdown(Duration::from_secs(5)).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+        assert_eq!(ids(&events), ["quick"]);
+        assert!(tasks.is_empty());
+
+        // The slow one never gets to complete.
+        sleep(Duration::from_secs(60)).await;
+        assert!(!completed.get());
+    }
+}